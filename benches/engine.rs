@@ -0,0 +1,231 @@
+#![warn(clippy::all, clippy::pedantic, future_incompatible)]
+
+//! Throughput of `set` and `get` across workloads, sync policies, index modes and engines, and
+//! memory taken by key indexes
+//!
+//! ```sh
+//! cargo bench --bench engine
+//! ```
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use kvs::{KeyIndex, KvStore, KvsEngine, MemEngine, OpenOptions};
+use std::{fmt::Debug, hint::black_box, time::Duration};
+use tempfile::TempDir;
+
+/// Number of keys written or read per iteration
+const KEYS: usize = 1000;
+
+/// Value sizes in bytes benchmarked for each workload
+const VALUE_SIZES: [usize; 3] = [16, 256, 4096];
+
+/// Deterministic xorshift generator, so runs are comparable
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// Returns keys in sequential or shuffled order
+fn keys(random: bool) -> Vec<String> {
+    let mut keys: Vec<_> = (0..KEYS).map(|i| format!("key{i:08}")).collect();
+    if random {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        for i in (1..keys.len()).rev() {
+            let j = usize::try_from(rng.next() % (i as u64 + 1)).unwrap();
+            keys.swap(i, j);
+        }
+    }
+    keys
+}
+
+/// Returns a printable value of the given size
+fn value(size: usize) -> String {
+    "v".repeat(size)
+}
+
+/// Opens a store in a fresh directory, kept alive alongside it
+fn open(options: &OpenOptions) -> (TempDir, KvStore) {
+    let dir = TempDir::new().expect("unable to create temporary directory");
+    let store = options.open(dir.path()).expect("unable to open store");
+    (dir, store)
+}
+
+/// Index modes compared by each benchmark
+fn modes() -> [(&'static str, OpenOptions); 2] {
+    let mut offset_index = OpenOptions::new();
+    offset_index.offset_index(true);
+    [
+        ("memory", OpenOptions::new()),
+        ("offset_index", offset_index),
+    ]
+}
+
+fn set(c: &mut Criterion) {
+    for (order, random) in [("sequential", false), ("random", true)] {
+        let mut group = c.benchmark_group(format!("set_{order}"));
+        group.throughput(Throughput::Elements(KEYS as u64));
+        let keys = keys(random);
+        for (mode, options) in modes() {
+            for size in VALUE_SIZES {
+                let value = value(size);
+                group.bench_function(BenchmarkId::new(mode, size), |b| {
+                    b.iter_batched(
+                        || open(&options),
+                        |(_dir, store)| {
+                            for key in &keys {
+                                store.set(key.clone(), value.clone()).unwrap();
+                            }
+                        },
+                        BatchSize::PerIteration,
+                    );
+                });
+            }
+        }
+        group.finish();
+    }
+}
+
+fn get(c: &mut Criterion) {
+    for (order, random) in [("sequential", false), ("random", true)] {
+        let mut group = c.benchmark_group(format!("get_{order}"));
+        group.throughput(Throughput::Elements(KEYS as u64));
+        let keys = keys(random);
+        for (mode, options) in modes() {
+            for size in VALUE_SIZES {
+                let (_dir, store) = open(&options);
+                for key in &keys {
+                    store.set(key.clone(), value(size)).unwrap();
+                }
+                group.bench_function(BenchmarkId::new(mode, size), |b| {
+                    b.iter(|| {
+                        for key in &keys {
+                            black_box(store.get(key.clone()).unwrap());
+                        }
+                    });
+                });
+            }
+        }
+        group.finish();
+    }
+}
+
+fn sync_policy(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sync_policy");
+    group.throughput(Throughput::Elements(KEYS as u64));
+    group.sample_size(10);
+    let keys = keys(false);
+    let value = value(256);
+
+    let mut group_commit = OpenOptions::new();
+    group_commit.group_commit(Duration::from_millis(1));
+    let mut preallocated = OpenOptions::new();
+    preallocated
+        .group_commit(Duration::from_millis(1))
+        .preallocate_segments(true);
+    let mut write_through = OpenOptions::new();
+    write_through.write_through(true);
+    let mut write_through_group = OpenOptions::new();
+    write_through_group
+        .write_through(true)
+        .group_commit(Duration::from_millis(1));
+    let mut coalesce = OpenOptions::new();
+    coalesce.coalesce_window(Duration::from_millis(1));
+    for (policy, options) in [
+        ("unsynced", OpenOptions::new()),
+        ("group_commit", group_commit),
+        ("group_commit_preallocated", preallocated),
+        ("write_through", write_through),
+        ("write_through_group_commit", write_through_group),
+        ("coalesce", coalesce),
+    ] {
+        group.bench_function(policy, |b| {
+            b.iter_batched(
+                || open(&options),
+                |(_dir, store)| {
+                    for key in &keys {
+                        store.set(key.clone(), value.clone()).unwrap();
+                    }
+                },
+                BatchSize::PerIteration,
+            );
+        });
+    }
+    group.finish();
+}
+
+/// Sets every key, then gets each, through the engine interface
+fn set_get<E: KvsEngine>(engine: &mut E, keys: &[String], value: &str)
+where
+    E::Error: Debug,
+{
+    for key in keys {
+        engine.set(key.clone(), value.to_owned()).unwrap();
+    }
+    for key in keys {
+        black_box(engine.get(key.clone()).unwrap());
+    }
+}
+
+/// The same `set` and `get` workload on each engine, through [`KvsEngine`]
+fn engines(c: &mut Criterion) {
+    let mut group = c.benchmark_group("engines");
+    group.throughput(Throughput::Elements(2 * KEYS as u64));
+    let keys = keys(true);
+    for size in VALUE_SIZES {
+        let value = value(size);
+        group.bench_function(BenchmarkId::new("mem", size), |b| {
+            b.iter_batched(
+                MemEngine::new,
+                |mut engine| set_get(&mut engine, &keys, &value),
+                BatchSize::PerIteration,
+            );
+        });
+        group.bench_function(BenchmarkId::new("kv_store", size), |b| {
+            b.iter_batched(
+                || open(&OpenOptions::new()),
+                |(_dir, mut store)| set_get(&mut store, &keys, &value),
+                BatchSize::PerIteration,
+            );
+        });
+    }
+    group.finish();
+}
+
+/// Lookups of keys sharing long prefixes by key index, in offset-index mode, printing the
+/// memory each index takes as reported by the store
+fn key_index(c: &mut Criterion) {
+    let mut group = c.benchmark_group("key_index");
+    group.throughput(Throughput::Elements(KEYS as u64));
+    let keys: Vec<_> = keys(true)
+        .iter()
+        .map(|key| format!("tenant/acme-corporation/users/{key}/profile"))
+        .collect();
+    for (name, key_index) in [("hashed", KeyIndex::Hashed), ("radix", KeyIndex::Radix)] {
+        let mut options = OpenOptions::new();
+        options.offset_index(true).key_index(key_index);
+        let (_dir, store) = open(&options);
+        for key in &keys {
+            store.set(key.clone(), value(16)).unwrap();
+        }
+        println!(
+            "key_index/{name}: {} bytes of memory for {KEYS} keys",
+            store.stats().memory_bytes
+        );
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for key in &keys {
+                    black_box(store.get(key.clone()).unwrap());
+                }
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, set, get, sync_policy, engines, key_index);
+criterion_main!(benches);
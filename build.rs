@@ -0,0 +1,12 @@
+//! Generates gRPC bindings from protobuf definitions when the `grpc` feature is enabled
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/kvs.proto");
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+        tonic_prost_build::compile_protos("proto/kvs.proto")?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,72 @@
+#![warn(clippy::all, clippy::pedantic, future_incompatible)]
+
+//! Persistent read-through cache in front of a slow computation
+//!
+//! ```sh
+//! cargo run --example cache
+//! ```
+
+use kvs::{KvStore, Result};
+use std::{thread, time::Duration};
+use tempfile::TempDir;
+
+/// Read-through cache backed by a KV store
+struct Cache<'a> {
+    store: &'a KvStore,
+    hits: u32,
+    misses: u32,
+}
+
+impl<'a> Cache<'a> {
+    fn new(store: &'a KvStore) -> Self {
+        Self {
+            store,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Returns cached value for key, computing and storing it on a miss
+    fn get_or_insert_with(&mut self, key: &str, f: impl FnOnce() -> String) -> Result<String> {
+        if let Some(value) = self.store.get(key)? {
+            self.hits += 1;
+            return Ok(value);
+        }
+
+        self.misses += 1;
+        let value = f();
+        self.store.set(key.to_owned(), value.clone())?;
+        Ok(value)
+    }
+}
+
+/// Stand-in for an expensive lookup, e.g. a remote API call
+fn slow_square(n: u64) -> String {
+    thread::sleep(Duration::from_millis(10));
+    (n * n).to_string()
+}
+
+fn main() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary directory");
+
+    {
+        let store = KvStore::open(dir.path())?;
+        let mut cache = Cache::new(&store);
+        for n in [1, 2, 3, 2, 1] {
+            let value = cache.get_or_insert_with(&format!("square:{n}"), || slow_square(n))?;
+            println!("{n}^2 = {value}");
+        }
+        println!("hits: {}, misses: {}", cache.hits, cache.misses);
+    }
+
+    // Cached values survive a restart
+    let store = KvStore::open(dir.path())?;
+    let mut cache = Cache::new(&store);
+    cache.get_or_insert_with("square:3", || slow_square(3))?;
+    println!(
+        "after reopen, hits: {}, misses: {}",
+        cache.hits, cache.misses
+    );
+
+    Ok(())
+}
@@ -0,0 +1,38 @@
+#![warn(clippy::all, clippy::pedantic, future_incompatible)]
+
+//! Word count written against a `HashMap<String, String>`, persisted by swapping in a store
+//! through [`MapFacade`]
+//!
+//! ```sh
+//! cargo run --example map
+//! ```
+
+use kvs::{KvStore, MapFacade, Result};
+use tempfile::TempDir;
+
+/// Counts the words of text in counts, as the code did with a `HashMap<String, String>`
+fn count_words(counts: &mut MapFacade, text: &str) {
+    for word in text.split_whitespace() {
+        let count = counts
+            .get(word)
+            .map_or(0, |count| count.parse::<u64>().unwrap_or(0));
+        counts.insert(word.to_owned(), (count + 1).to_string());
+    }
+}
+
+fn main() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary directory");
+
+    let mut counts = MapFacade::new(KvStore::open(dir.path())?);
+    count_words(&mut counts, "the quick brown fox jumps over the lazy dog");
+    drop(counts);
+
+    // Counts survive reopening the store
+    let mut counts = MapFacade::new(KvStore::open(dir.path())?);
+    count_words(&mut counts, "the end");
+    for (word, count) in &counts {
+        println!("{word}: {count}");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,66 @@
+#![warn(clippy::all, clippy::pedantic, future_incompatible)]
+
+//! Primary/replica pair replicating commands over a channel
+//!
+//! The primary applies each command locally and ships it to a replica thread, which applies
+//! the same command to its own store.
+//!
+//! ```sh
+//! cargo run --example replication
+//! ```
+
+use kvs::{Command, KvStore, Result};
+use std::{sync::mpsc, thread};
+use tempfile::TempDir;
+
+fn main() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary directory");
+
+    let primary = KvStore::open(primary_dir.path())?;
+    let replica = KvStore::open(replica_dir.path())?;
+
+    let (tx, rx) = mpsc::channel::<Command>();
+    let replica = thread::spawn(move || -> Result<KvStore> {
+        for cmd in rx {
+            replica.execute(cmd)?;
+        }
+        Ok(replica)
+    });
+
+    let commands = [
+        Command::Set {
+            key: "user:1".to_owned(),
+            value: "ada".to_owned(),
+            path: None,
+            nx: false,
+            xx: false,
+        },
+        Command::Set {
+            key: "user:2".to_owned(),
+            value: "grace".to_owned(),
+            path: None,
+            nx: false,
+            xx: false,
+        },
+        Command::Rm {
+            key: "user:1".to_owned(),
+            prefix: None,
+        },
+    ];
+    for cmd in commands {
+        // Only ship commands the primary applied successfully
+        primary.execute(cmd.clone())?;
+        tx.send(cmd).expect("replica hung up");
+    }
+    drop(tx);
+
+    let replica = replica.join().expect("replica thread panicked")?;
+    for key in ["user:1", "user:2"] {
+        let (p, r) = (primary.get(key)?, replica.get(key)?);
+        println!("{key}: primary={p:?} replica={r:?}");
+        assert_eq!(p, r);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,86 @@
+#![warn(clippy::all, clippy::pedantic, future_incompatible)]
+
+//! Axum web app keeping per-visitor sessions in a KV store
+//!
+//! ```sh
+//! cargo run --example sessions -- /tmp/kvs-sessions
+//! curl -c jar -b jar localhost:3000
+//! ```
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use kvs::{KvStore, KvStoreError};
+use std::{env, error::Error, fs, sync::Arc};
+
+/// Session cookie name
+const COOKIE: &str = "kvs_session";
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let dir = env::args()
+        .nth(1)
+        .map_or_else(|| env::temp_dir().join("kvs-sessions"), Into::into);
+    fs::create_dir_all(&dir)?;
+    let store = Arc::new(KvStore::open(dir)?);
+
+    let app = Router::new().route("/", get(visit)).with_state(store);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000").await?;
+    println!("Listening on http://{}", listener.local_addr()?);
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
+
+/// Counts visits per session, starting a new session for unknown visitors
+async fn visit(
+    State(store): State<Arc<KvStore>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    let session = match session_id(&headers) {
+        Some(id) if store.get(format!("session:{id}"))?.is_some() => id,
+        _ => store.next_id("session")?.to_string(),
+    };
+
+    let key = format!("session:{session}");
+    let visits = store
+        .get(key.as_str())?
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or_default()
+        + 1;
+    store.set(key, visits.to_string())?;
+
+    Ok((
+        [(header::SET_COOKIE, format!("{COOKIE}={session}; HttpOnly"))],
+        format!("Session {session}: visit #{visits}\n"),
+    ))
+}
+
+fn session_id(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|c| c.trim().strip_prefix(COOKIE)?.strip_prefix('='))
+        .map(str::to_owned)
+}
+
+/// Maps store errors to HTTP 500 responses
+struct AppError(KvStoreError);
+
+impl From<KvStoreError> for AppError {
+    fn from(e: KvStoreError) -> Self {
+        Self(e)
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        (StatusCode::INTERNAL_SERVER_ERROR, self.0.to_string()).into_response()
+    }
+}
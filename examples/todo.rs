@@ -0,0 +1,77 @@
+#![warn(clippy::all, clippy::pedantic, future_incompatible)]
+
+//! CLI todo app keeping its items in a KV store in the current directory
+//!
+//! ```sh
+//! cargo run --example todo -- add milk
+//! cargo run --example todo -- list
+//! cargo run --example todo -- done 1
+//! ```
+
+use clap::{Parser, Subcommand};
+use kvs::{KvStore, Result};
+use std::env;
+
+/// Key holding comma-separated IDs of open items
+const INDEX: &str = "todo:index";
+
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Todo,
+}
+
+#[derive(Subcommand)]
+enum Todo {
+    /// Add an item
+    Add { text: String },
+    /// List open items
+    List,
+    /// Mark an item as done
+    Done { id: u64 },
+}
+
+fn main() -> Result<()> {
+    let store = KvStore::open(env::current_dir().map_err(kvs::KvStoreError::UnknownCwd)?)?;
+
+    match Cli::parse().command {
+        Todo::Add { text } => {
+            let id = store.next_id("todo")?;
+            store.set(format!("todo:{id}"), text)?;
+            let mut ids = index(&store)?;
+            ids.push(id);
+            save_index(&store, &ids)?;
+            println!("Added #{id}");
+        }
+        Todo::List => {
+            for id in index(&store)? {
+                if let Some(text) = store.get(format!("todo:{id}"))? {
+                    println!("#{id} {text}");
+                }
+            }
+        }
+        Todo::Done { id } => {
+            let mut ids = index(&store)?;
+            ids.retain(|&i| i != id);
+            save_index(&store, &ids)?;
+            store.remove(format!("todo:{id}"))?;
+            println!("Done #{id}");
+        }
+    }
+
+    Ok(())
+}
+
+fn index(store: &KvStore) -> Result<Vec<u64>> {
+    Ok(store
+        .get(INDEX)?
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|id| id.parse().ok())
+        .collect())
+}
+
+fn save_index(store: &KvStore, ids: &[u64]) -> Result<()> {
+    let ids: Vec<_> = ids.iter().map(u64::to_string).collect();
+    store.set(INDEX.to_owned(), ids.join(",")).map(drop)
+}
@@ -0,0 +1,29 @@
+#![no_main]
+
+//! Reads arbitrary bytes as a stream of wire protocol frames
+//!
+//! ```sh
+//! cargo +nightly fuzz run protocol
+//! ```
+
+use kvs::protocol::{read_frame, write_frame, Envelope, Request, Response};
+use libfuzzer_sys::fuzz_target;
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Debug;
+
+/// Reads frames of messages until the bytes run out or do not hold one
+///
+/// Whatever reads must survive a round-trip through a frame unchanged.
+fn read_all<T: DeserializeOwned + Serialize + PartialEq + Debug>(mut data: &[u8]) {
+    while let Ok(Some(message)) = read_frame::<T>(&mut data) {
+        let mut frame = Vec::new();
+        write_frame(&mut frame, &message).unwrap();
+        assert_eq!(read_frame::<T>(&mut frame.as_slice()).unwrap(), Some(message));
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    read_all::<Envelope<serde_json::Value>>(data);
+    read_all::<Envelope<Request>>(data);
+    read_all::<Envelope<Response>>(data);
+});
@@ -0,0 +1,27 @@
+#![no_main]
+
+//! Replays arbitrary bytes as the WAL of a fresh store
+//!
+//! ```sh
+//! cargo +nightly fuzz run wal_replay
+//! ```
+
+use kvs::OpenOptions;
+use libfuzzer_sys::fuzz_target;
+use tempfile::TempDir;
+
+fuzz_target!(|wal: &[u8]| {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(temp_dir.path().join("wa.log"), wal).unwrap();
+
+    // Malformed records must fail the open with an error, not a panic, and leave the WAL as is
+    match OpenOptions::new().panic_free(false).open(temp_dir.path()) {
+        Ok(store) => {
+            assert_eq!(store.poisoned(), None);
+            store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        }
+        Err(_) => {
+            assert_eq!(std::fs::read(temp_dir.path().join("wa.log")).unwrap(), wal);
+        }
+    }
+});
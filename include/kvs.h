@@ -0,0 +1,119 @@
+#ifndef KVS_H
+#define KVS_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Returned on success
+ */
+#define KVS_OK 0
+
+/**
+ * Returned for an invalid argument, see [`ErrorKind::Usage`](crate::ErrorKind::Usage)
+ */
+#define KVS_USAGE 1
+
+/**
+ * Returned for a missing key, see [`ErrorKind::KeyNotFound`](crate::ErrorKind::KeyNotFound)
+ */
+#define KVS_KEY_NOT_FOUND 2
+
+/**
+ * Returned if reading or writing files fails, see [`ErrorKind::Io`](crate::ErrorKind::Io)
+ */
+#define KVS_IO 3
+
+/**
+ * Returned for damaged files, see [`ErrorKind::Corrupted`](crate::ErrorKind::Corrupted)
+ */
+#define KVS_CORRUPTED 4
+
+/**
+ * Returned for a conflicting write, see [`ErrorKind::Conflict`](crate::ErrorKind::Conflict)
+ */
+#define KVS_CONFLICT 5
+
+/**
+ * Returned if unavailable, see [`ErrorKind::Unavailable`](crate::ErrorKind::Unavailable)
+ */
+#define KVS_UNAVAILABLE 6
+
+/**
+ * Returned if the call is not allowed, see [`ErrorKind::Denied`](crate::ErrorKind::Denied)
+ */
+#define KVS_DENIED 7
+
+/**
+ * Returned for errors of a remote store, see [`ErrorKind::Remote`](crate::ErrorKind::Remote)
+ */
+#define KVS_REMOTE 8
+
+typedef struct KvStore KvStore;
+
+#ifdef __cplusplus
+extern "C" {
+#endif  // __cplusplus
+
+/**
+ * Opens the store at path, storing a handle to it in store, to be closed with [`kvs_close`]
+ *
+ * # Safety
+ * path must be a NUL-terminated string, and store a valid pointer to write the handle to.
+ */
+int kvs_open(const char *path, KvStore **store);
+
+/**
+ * Stores the value of key in value, or NULL if missing, to be freed with [`kvs_free_string`]
+ *
+ * Returns [`KVS_USAGE`] if the value holds a NUL byte.
+ *
+ * # Safety
+ * store must be a handle from [`kvs_open`] not yet closed, key a NUL-terminated string, and
+ * value a valid pointer to write the value to.
+ */
+int kvs_get(const KvStore *store, const char *key, char **value);
+
+/**
+ * Sets the value of key
+ *
+ * # Safety
+ * store must be a handle from [`kvs_open`] not yet closed, and key and value NUL-terminated
+ * strings.
+ */
+int kvs_set(const KvStore *store, const char *key, const char *value);
+
+/**
+ * Removes key, returning [`KVS_KEY_NOT_FOUND`] if missing
+ *
+ * # Safety
+ * store must be a handle from [`kvs_open`] not yet closed, and key a NUL-terminated string.
+ */
+int kvs_remove(const KvStore *store, const char *key);
+
+/**
+ * Closes a handle from [`kvs_open`], doing nothing if NULL
+ *
+ * # Safety
+ * store must be NULL or a handle from [`kvs_open`] not yet closed, which it may no longer be
+ * used after.
+ */
+void kvs_close(KvStore *store);
+
+/**
+ * Frees a value from [`kvs_get`], doing nothing if NULL
+ *
+ * # Safety
+ * value must be NULL or a value from [`kvs_get`] not yet freed.
+ */
+void kvs_free_string(char *value);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* KVS_H */
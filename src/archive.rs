@@ -0,0 +1,636 @@
+//! Archival of log segments to S3-compatible object storage, and point-in-time restore from the
+//! archive
+//!
+//! An [`Archive`] is a bucket and key prefix, e.g. `s3://kvs-backups/node1`, holding a copy of
+//! each segment a store sealed or compacted, named after its file, and a `CATALOG` object
+//! listing them in log order with when they were sealed, in milliseconds since the Unix epoch:
+//!
+//! ```text
+//! codec text
+//! segment 3 1718000000000
+//! base 4 1718000060000
+//! ```
+//!
+//! [`spawn`] uploads the segments of a running store as they are sealed, as `kvs-server` does
+//! with an `archive` table in its [config file](crate::config). The store keeps them until
+//! uploaded, see [`OpenOptions::archive_segments`](crate::OpenOptions::archive_segments).
+//! Writes still in the active segment are not archived yet, so the archive lags behind the
+//! store by up to a segment.
+//!
+//! [`restore`] fetches the archive into a directory holding no store, as `kvs restore --from`
+//! does, optionally as of a point in time: it starts from the latest base segment compacted by
+//! then and drops the records committed after it.
+//!
+//! Requests address buckets path-style, as S3 and compatible stores such as `MinIO` accept,
+//! and are signed with AWS Signature Version 4 unless no credentials are set.
+
+use crate::{
+    manifest::{self, Manifest, FORMAT_VERSION},
+    segment,
+    server::Shutdown,
+    wal, KvStore, KvStoreError, Result, WalFormat,
+};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::{
+    env, fmt,
+    fmt::Write as _,
+    fs, io,
+    path::Path,
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, info, warn};
+
+/// Object listing the archived segments
+const CATALOG: &str = "CATALOG";
+
+/// Subdirectory of a store holding the segments to upload, see
+/// [`OpenOptions::archive_segments`](crate::OpenOptions::archive_segments)
+pub(crate) const PENDING: &str = "archive";
+
+/// Interval at which the archiver looks for newly sealed segments
+const ARCHIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Interval at which an idle archiver checks for shutdown
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Time a request may take, including the transfer of a segment
+const REQUEST_TIMEOUT: Duration = Duration::from_mins(5);
+
+/// Region of requests unless set or given by `AWS_REGION`
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// Bucket and key prefix of S3-compatible object storage to archive segments to, with the
+/// credentials to access it
+#[derive(Clone)]
+pub struct Archive {
+    bucket: String,
+    prefix: String,
+    endpoint: Option<String>,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+    agent: ureq::Agent,
+}
+
+impl Archive {
+    /// Returns the archive at location `s3://BUCKET[/PREFIX]`
+    ///
+    /// Credentials, region and endpoint are taken from the `AWS_ACCESS_KEY_ID`,
+    /// `AWS_SECRET_ACCESS_KEY`, `AWS_REGION` and `AWS_ENDPOINT_URL` environment variables if
+    /// set, by default no credentials, region `us-east-1`, and the AWS endpoint of the region.
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::InvalidArchive`] if location is not an `s3://` URL with a bucket
+    pub fn new(location: &str) -> Result<Self> {
+        let (bucket, prefix) = location
+            .strip_prefix("s3://")
+            .map(|path| path.split_once('/').unwrap_or((path, "")))
+            .filter(|(bucket, _)| !bucket.is_empty())
+            .ok_or_else(|| {
+                KvStoreError::InvalidArchive(format!("{location} is not an s3://BUCKET URL"))
+            })?;
+        let var = |name| env::var(name).ok().filter(|value| !value.is_empty());
+        let agent = ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .timeout_global(Some(REQUEST_TIMEOUT))
+            .build()
+            .into();
+        Ok(Self {
+            bucket: bucket.to_owned(),
+            prefix: prefix.trim_matches('/').to_owned(),
+            endpoint: var("AWS_ENDPOINT_URL"),
+            region: var("AWS_REGION").unwrap_or_else(|| DEFAULT_REGION.to_owned()),
+            access_key_id: var("AWS_ACCESS_KEY_ID").unwrap_or_default(),
+            secret_access_key: var("AWS_SECRET_ACCESS_KEY").unwrap_or_default(),
+            agent,
+        })
+    }
+
+    /// Sets the URL of the object storage, e.g. `http://127.0.0.1:9000`
+    pub fn endpoint(&mut self, url: impl Into<String>) -> &mut Self {
+        self.endpoint = Some(url.into().trim_end_matches('/').to_owned());
+        self
+    }
+
+    /// Sets the region requests are signed for
+    pub fn region(&mut self, region: impl Into<String>) -> &mut Self {
+        self.region = region.into();
+        self
+    }
+
+    /// Sets the access key to sign requests with
+    pub fn credentials(
+        &mut self,
+        access_key_id: impl Into<String>,
+        secret_access_key: impl Into<String>,
+    ) -> &mut Self {
+        self.access_key_id = access_key_id.into();
+        self.secret_access_key = secret_access_key.into();
+        self
+    }
+
+    /// Returns the catalog, or `None` if nothing was archived yet
+    fn catalog(&self) -> Result<Option<Catalog>> {
+        self.get(CATALOG)?
+            .map(|bytes| {
+                let contents = String::from_utf8(bytes).map_err(|_| {
+                    KvStoreError::InvalidArchive(format!("{self}/{CATALOG} is not UTF-8"))
+                })?;
+                contents.parse()
+            })
+            .transpose()
+    }
+
+    /// Uploads the segments the store in dir kept for archival, records them in catalog, and
+    /// drops them once recorded
+    fn upload(&self, dir: &Path, catalog: &mut Catalog) -> Result<()> {
+        let pending = match segment::segment_files(&dir.join(PENDING)) {
+            Ok(pending) => pending,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(KvStoreError::FailedArchive(e)),
+        };
+        for file in pending {
+            // Segments up to the last recorded one were uploaded before a crash
+            if catalog.segments.last().is_none_or(|last| file.id > last.id) {
+                let modified = fs::metadata(&file.path)
+                    .and_then(|metadata| metadata.modified())
+                    .map_err(KvStoreError::FailedArchive)?;
+                let bytes = fs::read(&file.path).map_err(KvStoreError::FailedArchive)?;
+                self.put(&object_name(file.id, file.base), bytes)?;
+                catalog.segments.push(Archived {
+                    id: file.id,
+                    base: file.base,
+                    sealed_at: millis(modified),
+                });
+                self.put(CATALOG, catalog.to_string().into_bytes())?;
+                info!(segment = file.id, archive = %self, "Archived segment");
+            }
+            fs::remove_file(&file.path).map_err(KvStoreError::FailedArchive)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the object with the given name under the prefix, or `None` if there is none
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let (url, path) = self.url(name);
+        let headers = self.headers("GET", &path, &hex(&Sha256::digest(b"")));
+        let mut request = self.agent.get(&url);
+        for (header, value) in headers {
+            request = request.header(header, value);
+        }
+        let response = request.call();
+        match self.response("GET", name, response)? {
+            (404, _) => Ok(None),
+            (_, body) => Ok(Some(body)),
+        }
+    }
+
+    /// Stores bytes as the object with the given name under the prefix
+    fn put(&self, name: &str, bytes: Vec<u8>) -> Result<()> {
+        let (url, path) = self.url(name);
+        let headers = self.headers("PUT", &path, &hex(&Sha256::digest(&bytes)));
+        let mut request = self.agent.put(&url);
+        for (header, value) in headers {
+            request = request.header(header, value);
+        }
+        let response = request.send(bytes);
+        self.response("PUT", name, response).map(drop)
+    }
+
+    /// Returns the status and body of a response, failing unless it is a success or not found
+    fn response(
+        &self,
+        method: &str,
+        name: &str,
+        response: std::result::Result<ureq::http::Response<ureq::Body>, ureq::Error>,
+    ) -> Result<(u16, Vec<u8>)> {
+        let failed = |reason: String| {
+            KvStoreError::FailedArchive(io::Error::other(format!(
+                "{method} {self}/{name}: {reason}"
+            )))
+        };
+        let mut response = response.map_err(|e| failed(e.to_string()))?;
+        let status = response.status().as_u16();
+        let body = response
+            .body_mut()
+            .with_config()
+            .limit(u64::MAX)
+            .read_to_vec()
+            .map_err(|e| failed(e.to_string()))?;
+        debug!(method, name, status, "Archive request");
+        match status {
+            200..=299 | 404 => Ok((status, body)),
+            _ => Err(failed(format!(
+                "status {status}: {}",
+                String::from_utf8_lossy(&body)
+            ))),
+        }
+    }
+
+    /// Returns the URL of the object with the given name, and its path
+    fn url(&self, name: &str) -> (String, String) {
+        let key = if self.prefix.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{}/{name}", self.prefix)
+        };
+        let path = format!("/{}/{}", uri_encode(&self.bucket), uri_encode(&key));
+        let endpoint = self
+            .endpoint
+            .clone()
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", self.region));
+        (format!("{endpoint}{path}"), path)
+    }
+
+    /// Returns the headers of a request signed with AWS Signature Version 4, or unsigned if
+    /// there are no credentials
+    fn headers(&self, method: &str, path: &str, payload_hash: &str) -> Vec<(String, String)> {
+        let date = amz_date(SystemTime::now());
+        let mut headers = vec![
+            ("x-amz-content-sha256".to_owned(), payload_hash.to_owned()),
+            ("x-amz-date".to_owned(), date.clone()),
+        ];
+        if self.access_key_id.is_empty() {
+            return headers;
+        }
+
+        let (url, _) = self.url("");
+        let host = url
+            .split_once("://")
+            .map_or(url.as_str(), |(_, rest)| rest)
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .to_owned();
+        let mut signed = headers.clone();
+        signed.push(("host".to_owned(), host));
+        let authorization = self.authorization(method, path, &signed, payload_hash, &date);
+        headers.push(("authorization".to_owned(), authorization));
+        headers
+    }
+
+    /// Returns the `Authorization` header signing a request with the given lowercase headers
+    /// at date, formatted as in `x-amz-date`
+    fn authorization(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(String, String)],
+        payload_hash: &str,
+        date: &str,
+    ) -> String {
+        let mut headers = headers.to_vec();
+        headers.sort();
+        let names = headers
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers = headers.iter().fold(String::new(), |mut s, (name, value)| {
+            let _ = writeln!(s, "{name}:{}", value.trim());
+            s
+        });
+        let canonical_request =
+            format!("{method}\n{path}\n\n{canonical_headers}\n{names}\n{payload_hash}");
+
+        let day = &date[..8];
+        let scope = format!("{day}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{date}\n{scope}\n{}",
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = [&self.region, "s3", "aws4_request"].iter().fold(
+            hmac(
+                format!("AWS4{}", self.secret_access_key).as_bytes(),
+                day.as_bytes(),
+            ),
+            |key, part| hmac(&key, part.as_bytes()),
+        );
+        let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={names}, Signature={signature}",
+            self.access_key_id
+        )
+    }
+}
+
+/// Formats the location of the archive, e.g. `s3://kvs-backups/node1`
+impl fmt::Display for Archive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "s3://{}", self.bucket)?;
+        if !self.prefix.is_empty() {
+            write!(f, "/{}", self.prefix)?;
+        }
+        Ok(())
+    }
+}
+
+/// Leaves the secret access key out
+impl fmt::Debug for Archive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Archive")
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .field("endpoint", &self.endpoint)
+            .field("region", &self.region)
+            .field("access_key_id", &self.access_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Archived segments, in log order
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Catalog {
+    /// Format of the records of all segments
+    format: WalFormat,
+    segments: Vec<Archived>,
+}
+
+/// Segment recorded in the catalog
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Archived {
+    id: u64,
+    /// Whether the segment was written by compaction
+    base: bool,
+    /// When the segment was sealed or compacted, in milliseconds since the Unix epoch
+    sealed_at: u64,
+}
+
+impl std::str::FromStr for Catalog {
+    type Err = KvStoreError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid =
+            |line: &str| KvStoreError::InvalidArchive(format!("malformed catalog: {line}"));
+        let mut catalog = Self::default();
+        for line in s.lines() {
+            match line.split(' ').collect::<Vec<_>>()[..] {
+                ["codec", format] => catalog.format = format.parse().map_err(|_| invalid(line))?,
+                [kind @ ("segment" | "base"), id, sealed_at] => catalog.segments.push(Archived {
+                    id: id.parse().map_err(|_| invalid(line))?,
+                    base: kind == "base",
+                    sealed_at: sealed_at.parse().map_err(|_| invalid(line))?,
+                }),
+                _ => return Err(invalid(line)),
+            }
+        }
+        Ok(catalog)
+    }
+}
+
+impl fmt::Display for Catalog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "codec {}", self.format)?;
+        self.segments.iter().try_for_each(|s| {
+            let kind = if s.base { "base" } else { "segment" };
+            writeln!(f, "{kind} {} {}", s.id, s.sealed_at)
+        })
+    }
+}
+
+/// Outcome of a [`restore`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Restored {
+    /// Number of segments fetched
+    pub segments: usize,
+    /// Number of records restored, some of which may be replayed as duplicates
+    pub records: usize,
+}
+
+impl fmt::Display for Restored {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Restored {} records from {} segments",
+            self.records, self.segments
+        )
+    }
+}
+
+/// Uploads the segments of store to archive as they are sealed, until shut down
+///
+/// Runs on a new thread, checking for sealed segments every second and once more when shut
+/// down. Uploads that fail are retried on the next check.
+///
+/// # Errors
+/// Returns `Err` if store was opened without
+/// [`OpenOptions::archive_segments`](crate::OpenOptions::archive_segments), the catalog cannot
+/// be fetched or is malformed, the archive holds records of another format, or the thread
+/// cannot be spawned
+pub fn spawn(store: &KvStore, archive: Archive, shutdown: &Shutdown) -> Result<JoinHandle<()>> {
+    if !store.inner.options.archive_segments {
+        return Err(KvStoreError::InvalidConfig(
+            "archiving requires a store opened with OpenOptions::archive_segments".to_owned(),
+        ));
+    }
+    let format = store.inner.wal.format();
+    let mut catalog = archive.catalog()?.unwrap_or(Catalog {
+        format,
+        segments: Vec::new(),
+    });
+    if catalog.format != format {
+        return Err(KvStoreError::InvalidArchive(format!(
+            "{archive} holds {} records, not {format}",
+            catalog.format
+        )));
+    }
+
+    let dir = store.inner.dirs.store.clone();
+    let shutdown = shutdown.clone();
+    thread::Builder::new()
+        .name("kvs-archive".to_owned())
+        .spawn(move || loop {
+            let stopping = shutdown.is_triggered();
+            if let Err(e) = archive.upload(&dir, &mut catalog) {
+                warn!(archive = %archive, "Failed to archive segments: {e}");
+            }
+            if stopping {
+                return;
+            }
+            for _ in 0..ARCHIVE_INTERVAL.as_millis() / POLL_INTERVAL.as_millis() {
+                if shutdown.is_triggered() {
+                    break;
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        })
+        .map_err(KvStoreError::FailedArchive)
+}
+
+/// Fetches the segments of archive into dir, which must not hold a store yet, as of until if
+/// given, in milliseconds since the Unix epoch
+///
+/// The store opens from the latest base segment compacted by until, followed by the records
+/// of the segments after it committed by then. Records the store had replayed into new
+/// segments when reopened are restored again, and skipped as duplicates on open.
+///
+/// # Errors
+/// Returns `Err` if dir already holds a store, the archive holds no records to restore, a
+/// request fails, or the files cannot be written
+pub fn restore(dir: impl AsRef<Path>, archive: &Archive, until: Option<u64>) -> Result<Restored> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir).map_err(KvStoreError::FailedArchive)?;
+    if !manifest::check(dir)? {
+        return Err(KvStoreError::FailedArchive(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already holds a store", dir.display()),
+        )));
+    }
+    let catalog = archive
+        .catalog()?
+        .ok_or_else(|| KvStoreError::InvalidArchive(format!("{archive} holds no catalog")))?;
+
+    let until = until.unwrap_or(u64::MAX);
+    let start = catalog
+        .segments
+        .iter()
+        .rposition(|s| s.base && s.sealed_at <= until)
+        .unwrap_or(0);
+    let mut tracked = Manifest {
+        format: catalog.format,
+        ..Manifest::default()
+    };
+    let mut restored = Restored::default();
+    for (i, archived) in catalog.segments[start..].iter().enumerate() {
+        // Base segments only copy records of the segments before them, unless restored from
+        if archived.base && (i > 0 || archived.sealed_at > until) {
+            continue;
+        }
+        let name = object_name(archived.id, archived.base);
+        let bytes = archive
+            .get(&name)?
+            .ok_or_else(|| KvStoreError::InvalidArchive(format!("{archive} lacks {name}")))?;
+        let (bytes, records) = if archived.base {
+            let records = segment::base_records(&bytes)
+                .map_err(|reason| KvStoreError::InvalidArchive(format!("{name}: {reason}")))?;
+            let count = records
+                .split(|&b| b == b'\n')
+                .filter(|r| !r.is_empty())
+                .count();
+            (bytes, count)
+        } else {
+            committed_by(catalog.format, &bytes, until)
+        };
+        if records == 0 {
+            continue;
+        }
+
+        let id = tracked.next_segment;
+        fs::write(segment_path(dir, id, archived.base), bytes)
+            .map_err(KvStoreError::FailedArchive)?;
+        tracked.segments.push((id, archived.base));
+        tracked.next_segment += 1;
+        restored.segments += 1;
+        restored.records += records;
+    }
+
+    if restored.records == 0 {
+        return Err(KvStoreError::InvalidArchive(format!(
+            "{archive} holds no records to restore"
+        )));
+    }
+    manifest::write(dir, FORMAT_VERSION, &tracked).map_err(KvStoreError::FailedArchive)?;
+    info!(archive = %archive, %restored, "Restored archive");
+    Ok(restored)
+}
+
+/// Returns the records of a sealed segment committed by until, and how many there are
+///
+/// Records that do not decode are kept, for the store to report on open.
+fn committed_by(format: WalFormat, bytes: &[u8], until: u64) -> (Vec<u8>, usize) {
+    let mut kept = Vec::with_capacity(bytes.len());
+    let mut records = 0;
+    for frame in bytes.split(|&b| b == b'\n').filter(|f| !f.is_empty()) {
+        let committed = wal::decode(format, frame).map_or(true, |(stamp, _)| {
+            stamp.timestamp.is_none_or(|t| t <= until)
+        });
+        if committed {
+            kept.extend_from_slice(frame);
+            kept.push(b'\n');
+            records += 1;
+        }
+    }
+    (kept, records)
+}
+
+/// Returns the path of the segment with the given ID in dir
+fn segment_path(dir: &Path, id: u64, base: bool) -> std::path::PathBuf {
+    if base {
+        segment::base_path(dir, id)
+    } else {
+        segment::sealed_path(dir, id)
+    }
+}
+
+/// Returns the name of the object of a segment, that of its file
+fn object_name(id: u64, base: bool) -> String {
+    segment_path(Path::new(""), id, base).display().to_string()
+}
+
+/// Returns the time in milliseconds since the Unix epoch
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX))
+}
+
+/// Formats time as in the `x-amz-date` header, e.g. `20240131T235959Z`
+fn amz_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (year, month, day) = civil(secs / 86_400);
+    let secs = secs % 86_400;
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Returns the year, month and day of the given number of days since the Unix epoch
+///
+/// See <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    (era * 400 + year_of_era + u64::from(month <= 2), month, day)
+}
+
+/// Percent-encodes the characters of an object key other than unreserved ones and `/`
+fn uri_encode(key: &str) -> String {
+    key.bytes().fold(String::new(), |mut s, b| {
+        if b.is_ascii_alphanumeric() || b"-._~/".contains(&b) {
+            s.push(char::from(b));
+        } else {
+            let _ = write!(s, "%{b:02X}");
+        }
+        s
+    })
+}
+
+/// Returns the HMAC-SHA256 of data with key
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Returns bytes as lowercase hexadecimal
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
@@ -0,0 +1,190 @@
+//! Users of the TCP [`server`](crate::server) and their permissions on key prefixes
+//!
+//! Users are loaded from a JSON config file, e.g.
+//!
+//! ```json
+//! {
+//!   "users": [
+//!     { "name": "ada", "password": "secret", "permissions": { "": "read", "ada/": "write" } },
+//!     { "name": "ops", "token": "t0k3n", "permissions": { "": "admin" } }
+//!   ]
+//! }
+//! ```
+//!
+//! A user has the access of the longest prefix of a key among its permissions, and none to
+//! keys matching no prefix. Secrets are stored as is, so keep the file readable by the server
+//! only.
+
+use crate::{glob, protocol::Request, KvStoreError, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, fs, path::Path, str::FromStr, sync::Arc};
+use strum::Display;
+
+/// Access granted to a user on the keys starting with a prefix, each level including the ones
+/// before it
+#[derive(Clone, Copy, Debug, Deserialize, Display, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Access {
+    /// Get and scan keys
+    Read,
+    /// Also set and remove keys
+    Write,
+    /// Also manage the server: replication, the Raft cluster, and admin commands such as
+    /// compaction, which require admin access to all keys
+    Admin,
+}
+
+/// Secret a client authenticates with, sent in [`Request::Auth`]
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Credentials {
+    /// Name and password of a user
+    Password {
+        /// User name
+        user: String,
+        /// Password
+        password: String,
+    },
+    /// Token of a user
+    Token(String),
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Password { user, .. } => f
+                .debug_struct("Password")
+                .field("user", user)
+                .finish_non_exhaustive(),
+            Self::Token(_) => f.write_str("Token(..)"),
+        }
+    }
+}
+
+/// Users allowed to connect to a server, and their permissions
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    users: Vec<Arc<User>>,
+}
+
+/// Contents of the config file of an [`Acl`]
+#[derive(Deserialize)]
+struct Config {
+    users: Vec<User>,
+}
+
+/// User of a server
+#[derive(Deserialize)]
+pub(crate) struct User {
+    pub(crate) name: String,
+    password: Option<String>,
+    token: Option<String>,
+    /// Access by key prefix
+    permissions: BTreeMap<String, Access>,
+}
+
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("User")
+            .field("name", &self.name)
+            .field("permissions", &self.permissions)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Acl {
+    /// Loads users from the JSON config file at path
+    ///
+    /// # Errors
+    /// Returns `Err` if the file cannot be read or is malformed
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        fs::read_to_string(path)
+            .map_err(KvStoreError::FailedAcl)?
+            .parse()
+    }
+
+    /// Returns the user with credentials, if any
+    pub(crate) fn authenticate(&self, credentials: &Credentials) -> Option<Arc<User>> {
+        self.users
+            .iter()
+            .find(|user| match credentials {
+                Credentials::Password {
+                    user: name,
+                    password,
+                } => {
+                    user.name == *name && user.password.as_ref().is_some_and(|p| same(p, password))
+                }
+                Credentials::Token(token) => user.token.as_ref().is_some_and(|t| same(t, token)),
+            })
+            .cloned()
+    }
+
+    /// Returns the user named name, if any
+    pub(crate) fn user(&self, name: &str) -> Option<Arc<User>> {
+        self.users.iter().find(|user| user.name == name).cloned()
+    }
+}
+
+impl FromStr for Acl {
+    type Err = KvStoreError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let config: Config =
+            serde_json::from_str(s).map_err(|e| KvStoreError::InvalidAcl(e.to_string()))?;
+        let users = config.users.into_iter().map(Arc::new).collect();
+        Ok(Self { users })
+    }
+}
+
+impl User {
+    /// Returns the access of the user to key, or to all keys starting with it
+    fn access(&self, key: &str) -> Option<Access> {
+        self.permissions
+            .iter()
+            .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, &access)| access)
+    }
+
+    /// Fails unless the user may make request
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::PermissionDenied`] if the user lacks the access required
+    pub(crate) fn authorize(&self, request: &Request) -> Result<()> {
+        let prefix;
+        let (required, key) = match request {
+            Request::Get { key, .. } => (Access::Read, key.as_str()),
+            Request::Scan { prefix } => (Access::Read, prefix.as_str()),
+            Request::ScanCursor { pattern, .. } => {
+                // Every key matching the pattern starts with its literal prefix
+                prefix = glob::literal_prefix(pattern.as_deref().unwrap_or_default());
+                (Access::Read, prefix.as_str())
+            }
+            Request::Set { key, .. }
+            | Request::Rm { key }
+            | Request::Lock { key, .. }
+            | Request::Unlock { key, .. } => (Access::Write, key.as_str()),
+            Request::Auth(_) | Request::Ping | Request::Sequence | Request::WaitFor { .. } => {
+                return Ok(())
+            }
+            _ => (Access::Admin, ""),
+        };
+        if self.access(key).is_some_and(|access| access >= required) {
+            return Ok(());
+        }
+        Err(KvStoreError::PermissionDenied(format!(
+            "user {} lacks {required} access to {key:?}",
+            self.name
+        )))
+    }
+}
+
+/// Compares secrets in time independent of where they differ
+fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
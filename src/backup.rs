@@ -0,0 +1,117 @@
+//! Continuous incremental backups: a copy of a store kept up to date with the records of its
+//! WAL, as streamed by [`KvStore::tail`](crate::KvStore::tail) or
+//! [`KvsClient::tail`](crate::client::KvsClient::tail)
+//!
+//! A backup directory holds the records received as its active segment `wa.log`, along with a
+//! manifest, so that a store can be opened from it once copying stops. `kvs backup --dir DIR`
+//! keeps one up to date with a server.
+
+use crate::{
+    lock::DirLock,
+    manifest::{self, Manifest, FORMAT_VERSION},
+    wal, KvStoreError, Result, Shipment, WalFormat, WAL,
+};
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+    time::Duration,
+};
+use tracing::{info, warn};
+
+/// Backup directory being written, locked against stores and other backups
+#[derive(Debug)]
+pub struct Backup {
+    file: File,
+    /// Sequence number of the last record copied
+    sequence: u64,
+    _lock: DirLock,
+}
+
+impl Backup {
+    /// Opens the backup in dir, creating it if needed
+    ///
+    /// A record left incomplete by an interrupted write is dropped.
+    ///
+    /// # Errors
+    /// Returns `Err` if dir cannot be created or locked, holds a store with segments of its
+    /// own, or its records cannot be read
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).map_err(KvStoreError::FailedBackup)?;
+        let lock = DirLock::acquire(dir, Duration::ZERO)?;
+        if manifest::check(dir)? {
+            manifest::write(dir, FORMAT_VERSION, &Manifest::default())
+                .map_err(KvStoreError::FailedBackup)?;
+        } else if manifest::read(dir)? != Manifest::default() {
+            return Err(KvStoreError::FailedBackup(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} holds a store with sealed segments", dir.display()),
+            )));
+        }
+
+        let path = dir.join(WAL);
+        let mut records = fs::read(&path).or_else(|e| match e.kind() {
+            io::ErrorKind::NotFound => Ok(Vec::new()),
+            _ => Err(KvStoreError::FailedBackup(e)),
+        })?;
+        let complete = records
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |n| n + 1);
+        if complete < records.len() {
+            warn!(
+                bytes = records.len() - complete,
+                "Dropped incomplete record of backup"
+            );
+            records.truncate(complete);
+        }
+        let sequence = match records.split(|&b| b == b'\n').rfind(|f| !f.is_empty()) {
+            Some(frame) => wal::decode(WalFormat::Text, frame)?.0.sequence.unwrap_or(0),
+            None => 0,
+        };
+
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(KvStoreError::FailedBackup)?;
+        file.set_len(complete as u64)
+            .map_err(KvStoreError::FailedBackup)?;
+        Ok(Self {
+            file,
+            sequence,
+            _lock: lock,
+        })
+    }
+
+    /// Returns the sequence number of the last record copied, or 0 if none was
+    ///
+    /// Copying resumes from the next one.
+    #[must_use]
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Appends the records of shipment, or replaces the copied records by those of a snapshot,
+    /// then syncs them to disk
+    ///
+    /// # Errors
+    /// Returns `Err` if writing or syncing fails
+    pub fn write(&mut self, shipment: &Shipment) -> Result<()> {
+        let (records, sequence) = match shipment {
+            Shipment::Committed(records, sequence) => (records, *sequence),
+            Shipment::Snapshot(records, sequence) => {
+                info!(sequence, "Starting backup over from a snapshot");
+                self.file.set_len(0).map_err(KvStoreError::FailedBackup)?;
+                (records, *sequence)
+            }
+        };
+        self.file
+            .write_all(records.as_bytes())
+            .and_then(|()| self.file.sync_data())
+            .map_err(KvStoreError::FailedBackup)?;
+        self.sequence = sequence;
+        Ok(())
+    }
+}
@@ -0,0 +1,439 @@
+#![deny(missing_docs)]
+#![warn(clippy::all, clippy::pedantic, future_incompatible)]
+
+//! Key-value (KV) store server
+
+use clap::{Parser, Subcommand, ValueEnum};
+use kvs::{
+    config::{Engine, ServerConfig, SyncPolicy},
+    server::{Scope, ServerOptions, Shutdown},
+    thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool},
+    transport::ServerAddr,
+    KvStore, KvStoreError, Result, WalFormat,
+};
+use std::{io, net::SocketAddr, panic, path::PathBuf, sync::Arc, thread};
+use tracing::{info, warn};
+use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*, reload, Registry};
+
+fn main() -> Result<()> {
+    let cli = Arc::new(Cli::parse());
+    let config = cli.config()?;
+    let (log_level, log_level_handle) = reload::Layer::new(config.log_level());
+    tracing_subscriber::registry()
+        .with(log_level)
+        .with(fmt::layer().with_writer(io::stderr))
+        .init();
+
+    if let Some(ServerCommand::CheckConfig) = cli.command {
+        config.check()?;
+        println!("Config OK");
+        return Ok(());
+    }
+
+    let mut options = config.open_options()?;
+    options.wal_format(cli.wal_format);
+    if let Some(primary) = cli.replica_of {
+        options.replica_of(primary);
+    }
+    #[cfg(feature = "raft")]
+    if !cli.cluster_member.is_empty() {
+        options.cluster(config.addr(), &cli.cluster_member);
+    }
+    if cli.verbose {
+        options.on_progress(|progress| {
+            info!(
+                segments = progress.segments_done,
+                of = progress.segments_total,
+                records = progress.records,
+                eta_secs = progress.eta.map(|eta| eta.as_secs()),
+                "Loading store"
+            );
+        });
+    }
+    let store = options.open(config.data_dir()?)?;
+
+    #[cfg(any(feature = "http", feature = "grpc"))]
+    let _runtime = spawn_async_servers(&cli, &store)?;
+
+    let mut server_options = config.server_options()?;
+    server_options.on_reload({
+        let (cli, store) = (Arc::clone(&cli), store.clone());
+        move |options| reload(&cli, &store, options, &log_level_handle)
+    });
+    let shutdown = Shutdown::new();
+    handle_signals(&shutdown, &server_options)?;
+
+    let replicators = cli
+        .replicate_to
+        .iter()
+        .map(|&replica| kvs::replication::replicate_to(&store, replica, &shutdown))
+        .collect::<Result<Vec<_>>>()?;
+    let triggers = config
+        .triggers()
+        .into_iter()
+        .map(|trigger| kvs::trigger::spawn(&store, trigger, &shutdown))
+        .collect::<Result<Vec<_>>>()?;
+    #[cfg(feature = "archive")]
+    let archiver = config
+        .archive()?
+        .map(|archive| kvs::archive::spawn(&store, archive, &shutdown))
+        .transpose()?;
+    #[cfg(feature = "raft")]
+    let raft = (!cli.cluster_member.is_empty())
+        .then(|| kvs::raft::run(&store, &shutdown))
+        .transpose()?;
+
+    let mut admin_options = server_options.clone();
+    if config.admin_listen.is_some() {
+        server_options.scope(Scope::Data);
+        admin_options.scope(Scope::Admin);
+    }
+    let threads = cli
+        .threads
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, usize::from));
+    // Either server failing stops the other
+    let serve = |options: &ServerOptions, listen: &ServerAddr| {
+        serve(cli.pool, options, listen, &store, threads, &shutdown)
+            .inspect_err(|_| shutdown.trigger())
+    };
+    thread::scope(|s| {
+        let admin = config
+            .admin_listen
+            .as_ref()
+            .map(|listen| s.spawn(|| serve(&admin_options, listen)));
+        let result = serve(&server_options, &config.listen());
+        let admin = admin.map_or(Ok(()), |admin| {
+            admin.join().unwrap_or_else(|e| panic::resume_unwind(e))
+        });
+        result.and(admin)
+    })?;
+
+    store.sync()?;
+    info!("WAL synced, exiting");
+    for thread in replicators.into_iter().chain(triggers) {
+        let _ = thread.join();
+    }
+    #[cfg(feature = "archive")]
+    if let Some(archiver) = archiver {
+        let _ = archiver.join();
+    }
+    #[cfg(feature = "raft")]
+    if let Some(raft) = raft {
+        let _ = raft.join();
+    }
+
+    Ok(())
+}
+
+/// Serves the TCP protocol with connections handled on a pool of the given kind
+fn serve(
+    pool: Pool,
+    options: &ServerOptions,
+    listen: &ServerAddr,
+    store: &KvStore,
+    threads: usize,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    match pool {
+        Pool::Naive => serve_on::<NaiveThreadPool>(options, listen, store, threads, shutdown),
+        Pool::SharedQueue => {
+            serve_on::<SharedQueueThreadPool>(options, listen, store, threads, shutdown)
+        }
+        Pool::Rayon => serve_on::<RayonThreadPool>(options, listen, store, threads, shutdown),
+    }
+}
+
+/// Serves the TCP protocol with connections handled on a new pool of type `P`
+fn serve_on<P: ThreadPool>(
+    options: &ServerOptions,
+    listen: &ServerAddr,
+    store: &KvStore,
+    threads: usize,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    let pool = P::new(threads)?;
+    match listen {
+        ServerAddr::Tcp(addrs) => options.serve(store, &addrs[..], &pool, shutdown),
+        #[cfg(unix)]
+        ServerAddr::Unix(path) => options.serve_unix(store, path, &pool, shutdown),
+    }
+}
+
+/// Reads the config file again and applies the settings that can change while serving
+fn reload(
+    cli: &Cli,
+    store: &KvStore,
+    options: &ServerOptions,
+    log_level: &reload::Handle<LevelFilter, Registry>,
+) -> Result<()> {
+    let config = cli.config()?;
+    config.apply(store, options)?;
+    if let Err(e) = log_level.reload(config.log_level()) {
+        warn!("Failed to set log level: {e}");
+    }
+    Ok(())
+}
+
+/// Stops the servers on SIGINT and SIGTERM, returning once connections are drained, and
+/// reloads settings on SIGHUP
+#[cfg(unix)]
+fn handle_signals(shutdown: &Shutdown, options: &ServerOptions) -> Result<()> {
+    use signal_hook::{
+        consts::{SIGHUP, SIGINT, SIGTERM},
+        iterator::Signals,
+    };
+
+    let mut signals = Signals::new([SIGHUP, SIGINT, SIGTERM]).map_err(KvStoreError::FailedServe)?;
+    let (shutdown, options) = (shutdown.clone(), options.clone());
+    thread::Builder::new()
+        .name("kvs-signals".to_owned())
+        .spawn(move || {
+            for signal in &mut signals {
+                if signal != SIGHUP {
+                    shutdown.trigger();
+                } else if let Err(e) = options.reload() {
+                    tracing::error!("Failed to reload settings: {e}");
+                }
+            }
+        })
+        .map_err(KvStoreError::FailedServe)?;
+    Ok(())
+}
+
+/// Stops the servers on Ctrl-C, returning once connections are drained
+#[cfg(not(unix))]
+fn handle_signals(shutdown: &Shutdown, _options: &ServerOptions) -> Result<()> {
+    let handler = shutdown.clone();
+    ctrlc::set_handler(move || handler.trigger())
+        .map_err(|e| KvStoreError::FailedServe(io::Error::other(e)))
+}
+
+/// Starts the optional HTTP and gRPC servers on a Tokio runtime
+///
+/// The servers run for as long as the returned runtime is kept alive.
+#[cfg(any(feature = "http", feature = "grpc"))]
+fn spawn_async_servers(cli: &Cli, store: &KvStore) -> Result<tokio::runtime::Runtime> {
+    let runtime = tokio::runtime::Runtime::new().map_err(KvStoreError::FailedServe)?;
+
+    #[cfg(feature = "http")]
+    if let Some(addr) = cli.http_addr {
+        let store = store.clone();
+        runtime.spawn(async move {
+            if let Err(e) = kvs::http::serve(store, addr).await {
+                tracing::error!("HTTP server failed: {e}");
+            }
+        });
+    }
+
+    #[cfg(feature = "grpc")]
+    if let Some(addr) = cli.grpc_addr {
+        let store = store.clone();
+        runtime.spawn(async move {
+            if let Err(e) = kvs::grpc::serve(store, addr).await {
+                tracing::error!("gRPC server failed: {e}");
+            }
+        });
+    }
+
+    Ok(runtime)
+}
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<ServerCommand>,
+
+    /// TOML file of settings, see `kvs::config`; the flags below override them, and SIGHUP
+    /// reloads those that can change while serving
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Maximum level of log events written to stderr, default info
+    #[arg(long)]
+    log_level: Option<LevelFilter>,
+
+    /// Log the progress of loading the store on startup: log files loaded, records replayed
+    /// and the time left
+    #[arg(long)]
+    verbose: bool,
+
+    /// Directory of the store, default the working directory
+    #[arg(long, value_name = "PATH")]
+    data_dir: Option<PathBuf>,
+
+    /// Address to serve the TCP protocol on, default 127.0.0.1:4000
+    #[arg(long)]
+    addr: Option<SocketAddr>,
+
+    /// Address to serve the TCP protocol on instead of --addr, which still identifies the
+    /// server to Raft cluster members: a TCP address, or `unix:PATH` for a Unix domain socket
+    /// that only local clients allowed by its file permissions can connect to
+    #[arg(long, value_name = "ADDR")]
+    listen: Option<ServerAddr>,
+
+    /// Address to serve admin requests on, such as `compact` or `reload`, which are then
+    /// refused on the main address; e.g. a loopback or Unix domain socket address
+    #[arg(long, value_name = "ADDR")]
+    admin_listen: Option<ServerAddr>,
+
+    /// Where values are kept, default memory
+    #[arg(long, value_enum)]
+    engine: Option<Engine>,
+
+    /// When writes are synced to disk, default never
+    #[arg(long, value_enum)]
+    sync: Option<SyncPolicy>,
+
+    /// Group commit window in milliseconds with --sync always, default 0
+    #[arg(long, value_name = "MS")]
+    group_commit_ms: Option<u64>,
+
+    /// Log size in bytes below which it is never compacted, default 1 MiB
+    #[arg(long, value_name = "BYTES")]
+    compaction_threshold: Option<u64>,
+
+    /// Capacity in bytes of the value cache with --engine offset-index, default none
+    #[arg(long, value_name = "BYTES")]
+    cache_size: Option<usize>,
+
+    /// Thread pool handling client connections
+    #[arg(long, value_enum, default_value_t = Pool::SharedQueue)]
+    pool: Pool,
+
+    /// Number of pool threads, default one per CPU
+    #[arg(long)]
+    threads: Option<usize>,
+
+    /// Format of log records when creating a new store; existing stores keep theirs
+    #[arg(long, value_enum, default_value_t = WalFormat::Text)]
+    wal_format: WalFormat,
+
+    /// Address of a replica server to stream committed records to, repeatable
+    #[arg(long, value_name = "ADDR")]
+    replicate_to: Vec<SocketAddr>,
+
+    /// Serve as a read-only replica of the primary at address, until promoted
+    #[arg(long, value_name = "ADDR")]
+    replica_of: Option<SocketAddr>,
+
+    /// Member of a Raft cluster, repeatable: the initial members including this server, or the
+    /// current members when joining a cluster; the server is identified by its address
+    #[cfg(feature = "raft")]
+    #[arg(long, value_name = "ADDR")]
+    cluster_member: Vec<SocketAddr>,
+
+    /// JSON file of the users clients authenticate as and their permissions, see `kvs::auth`;
+    /// applies to the TCP protocol only
+    #[arg(long, value_name = "PATH")]
+    auth_config: Option<PathBuf>,
+
+    /// Log requests taking at least this many milliseconds to answer as warnings, and keep the
+    /// last ones for `kvs admin slowlog`
+    #[arg(long, value_name = "MS")]
+    slow_query_ms: Option<u64>,
+
+    /// Connections open at once on each address beyond which new ones are refused as busy,
+    /// default unlimited
+    #[arg(long, value_name = "N")]
+    max_connections: Option<usize>,
+
+    /// Requests per second answered across all connections, the others being refused as busy;
+    /// default unlimited
+    #[arg(long, value_name = "N")]
+    rate_limit: Option<u32>,
+
+    /// Requests per second answered on each connection, default unlimited
+    #[arg(long, value_name = "N")]
+    connection_rate_limit: Option<u32>,
+
+    /// PEM file of the certificate chain to serve the TCP protocol over TLS with; replication
+    /// and Raft connections to the server stay unencrypted, so do not combine them
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PATH", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file of the private key of the TLS certificate
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// PEM file of the certificate authority clients must present a certificate signed by
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+
+    /// Address to also serve the HTTP API on
+    #[cfg(feature = "http")]
+    #[arg(long)]
+    http_addr: Option<SocketAddr>,
+
+    /// Address to also serve the gRPC interface on
+    #[cfg(feature = "grpc")]
+    #[arg(long)]
+    grpc_addr: Option<SocketAddr>,
+}
+
+impl Cli {
+    /// Returns the settings of the config file, if any, overridden by the flags
+    fn config(&self) -> Result<ServerConfig> {
+        let file = match &self.config {
+            Some(path) => ServerConfig::load(path)?,
+            None => ServerConfig::default(),
+        };
+        #[cfg(feature = "tls")]
+        let tls = self
+            .tls_cert
+            .clone()
+            .zip(self.tls_key.clone())
+            .map(|(cert, key)| kvs::config::TlsConfig {
+                cert,
+                key,
+                client_ca: self.tls_client_ca.clone(),
+            });
+        #[cfg(not(feature = "tls"))]
+        let tls = None;
+        Ok(file.merge(ServerConfig {
+            log_level: self.log_level,
+            data_dir: self.data_dir.clone(),
+            addr: self.addr,
+            listen: self.listen.clone(),
+            admin_listen: self.admin_listen.clone(),
+            engine: self.engine,
+            sync: self.sync,
+            group_commit_ms: self.group_commit_ms,
+            compaction_threshold: self.compaction_threshold,
+            cache_size: self.cache_size,
+            auth_config: self.auth_config.clone(),
+            slow_query_ms: self.slow_query_ms,
+            max_connections: self.max_connections,
+            rate_limit: self.rate_limit,
+            connection_rate_limit: self.connection_rate_limit,
+            tls,
+            // Set in the file only, being lists of commands
+            triggers: Vec::new(),
+            // Set in the file only, being a table
+            eviction: None,
+            // Set in the file only, along with its credentials
+            archive: None,
+        }))
+    }
+}
+
+/// Commands other than serving
+#[derive(Subcommand)]
+enum ServerCommand {
+    /// Check the config file and flags, including the files they refer to, without serving
+    CheckConfig,
+}
+
+/// Thread pool implementation
+#[derive(Clone, Copy, ValueEnum)]
+enum Pool {
+    /// New thread per connection
+    Naive,
+    /// Fixed threads sharing a job queue
+    SharedQueue,
+    /// Rayon work-stealing pool
+    Rayon,
+}
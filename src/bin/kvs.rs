@@ -3,30 +3,568 @@
 
 //! Key-value (KV) store CLI client
 
-use clap::Parser;
-use kvs::{Command, KvStoreError, Result};
-use std::env;
+use clap::{CommandFactory, Parser, ValueEnum};
+use kvs::{
+    auth::Credentials,
+    backup::Backup,
+    client::{ClientOptions, KvsClient},
+    dump::{Entry, LogCommand},
+    server::AdminCommand,
+    Bucket, Command, ErrorKind, KvStoreError, Result, Shipment, SlowQuery,
+};
+use serde::Serialize;
+use std::{
+    env,
+    ffi::OsString,
+    io::{self, Write},
+    iter,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
+use tracing::warn;
+use tracing_subscriber::filter::LevelFilter;
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
+    let cli = match Cli::try_parse_from(legacy_args(env::args_os().collect())) {
+        Ok(cli) => cli,
+        Err(e) => return usage(&e),
+    };
+    init_logging(cli.log_level, cli.log_format);
+    let format = cli.output;
+    report(format, run(cli))
+}
+
+/// Runs the command of cli, returning its output
+#[allow(clippy::too_many_lines)] // Dispatches every command
+fn run(cli: Cli) -> Result<Output> {
+    if let Command::Completions { shell } = cli.command {
+        // Generating panics on write errors, so write separately
+        let mut script = Vec::new();
+        clap_complete::generate(shell, &mut Cli::command(), "kvs", &mut script);
+        io::stdout()
+            .write_all(&script)
+            .map_err(KvStoreError::FailedDocs)?;
+        return Ok(Output::Printed);
+    }
+    if let Command::Man { dir } = &cli.command {
+        return man(dir.as_deref());
+    }
     let current_dir = env::current_dir().map_err(KvStoreError::UnknownCwd)?;
-    let store = kvs::KvStore::open(current_dir)?;
+    if let Command::Doctor { repair } = cli.command {
+        return doctor(current_dir, repair, cli.output);
+    }
+    if let Command::Migrate = cli.command {
+        return Ok(Output::Text(
+            kvs::migrate::migrate(current_dir)?.to_string(),
+        ));
+    }
+    if let Command::Log { command } = cli.command {
+        return log(current_dir, &command);
+    }
+    #[cfg(feature = "archive")]
+    if let Command::Restore {
+        from,
+        until,
+        endpoint,
+    } = &cli.command
+    {
+        let mut archive = kvs::archive::Archive::new(from)?;
+        if let Some(url) = endpoint {
+            archive.endpoint(url.as_str());
+        }
+        let restored = kvs::archive::restore(current_dir, &archive, *until)?;
+        return Ok(Output::Text(restored.to_string()));
+    }
+    if let Command::Ping {
+        server,
+        json,
+        max_lag_ms,
+    } = &cli.command
+    {
+        let client = client_options(&cli)?.connect_to(server.parse()?)?;
+        let max_lag = max_lag_ms.map(Duration::from_millis);
+        return ping(&client, *json, max_lag, cli.output);
+    }
+    if let Command::Backup {
+        server,
+        from_seq,
+        follow,
+        dir,
+    } = &cli.command
+    {
+        let client = client_options(&cli)?.connect_to(server.parse()?)?;
+        return backup(&client, *from_seq, *follow, dir.as_deref());
+    }
+    if let Command::Admin { command, server } = &cli.command {
+        let client = client_options(&cli)?.connect_to(server.parse()?)?;
+        return admin(&client, command);
+    }
+    #[cfg(feature = "raft")]
+    if let Command::Cluster { command, server } = &cli.command {
+        let client = client_options(&cli)?.connect_to(server.parse()?)?;
+        return cluster(&client, command);
+    }
+    let store = if let Command::History { .. } = cli.command {
+        // History starts over on open, so keep all of it while replaying
+        kvs::OpenOptions::new()
+            .history_retention(u64::MAX)
+            .open(current_dir)?
+    } else {
+        kvs::KvStore::open(current_dir)?
+    };
+
+    match cli.bucket {
+        Some(bucket) => execute_in(&store.bucket(bucket), cli.command),
+        None => match cli.command {
+            cmd @ Command::Get { .. } => store.execute(cmd).map(Output::Value),
+            Command::Exists { key } => Ok(Output::Exists(store.contains_key(&key))),
+            // Previous values are for clients; the CLI prints nothing on success
+            cmd @ (Command::Set {
+                nx: false,
+                xx: false,
+                ..
+            }
+            | Command::Rm { prefix: None, .. }) => {
+                store.execute(cmd).map(|_| Output::Text(String::new()))
+            }
+            cmd => store
+                .execute(cmd)
+                .map(|output| Output::Text(output.unwrap_or_default())),
+        },
+    }
+}
+
+/// Output of a command
+enum Output {
+    /// Text printed as is, or as the value of the JSON result if not empty
+    Text(String),
+    /// Value of a key, or `None` if not found
+    Value(Option<String>),
+    /// Whether a key exists, printed as `true` or `false`
+    Exists(bool),
+    /// Previous value of a key written, only printed as the value of the JSON result
+    Previous(Option<String>),
+    /// Already printed while running, e.g. streamed records
+    Printed,
+}
+
+/// Prints the output of a command or its error in format, returning the exit code
+///
+/// Errors are also written to stderr in the text format.
+fn report(format: OutputFormat, result: Result<Output>) -> ExitCode {
+    let code = match &result {
+        Ok(Output::Exists(false)) => ErrorKind::KeyNotFound.exit_code(),
+        Ok(_) => 0,
+        Err(e) => e.kind().exit_code(),
+    };
+    match (format, result) {
+        (OutputFormat::Text, Ok(Output::Text(text))) => println!("{text}"),
+        (OutputFormat::Text, Ok(Output::Exists(exists))) => println!("{exists}"),
+        (OutputFormat::Text, Ok(Output::Previous(_))) => println!(),
+        (OutputFormat::Text, Ok(Output::Value(value))) => {
+            println!("{}", value.as_deref().unwrap_or("Key not found"));
+        }
+        (_, Ok(Output::Printed)) => {}
+        (OutputFormat::Text, Err(e)) => {
+            println!("{}", e.report());
+            eprintln!("Error: {e:?}");
+        }
+        (OutputFormat::Json, Ok(Output::Text(text))) => {
+            let value = (!text.is_empty()).then_some(text.as_str());
+            print_json(&JsonResult::Ok { ok: true, value });
+        }
+        (OutputFormat::Json, Ok(Output::Value(value) | Output::Previous(value))) => {
+            let value = value.as_deref();
+            print_json(&JsonResult::Ok { ok: true, value });
+        }
+        (OutputFormat::Json, Ok(Output::Exists(exists))) => {
+            let value = Some(if exists { "true" } else { "false" });
+            print_json(&JsonResult::Ok { ok: true, value });
+        }
+        (OutputFormat::Json, Err(e)) => print_json(&JsonResult::error(e.kind(), &e.report())),
+    }
+    ExitCode::from(code)
+}
 
-    let cmd = Cli::parse().command;
-    match store.execute(cmd) {
-        Err(e) => {
-            println!("{e}");
-            Err(e)
+/// Result of a command in the JSON output format
+#[derive(Serialize)]
+#[serde(untagged)]
+enum JsonResult<'a> {
+    Ok {
+        ok: bool,
+        value: Option<&'a str>,
+    },
+    Err {
+        ok: bool,
+        code: ErrorKind,
+        exit_code: u8,
+        message: &'a str,
+    },
+}
+
+impl<'a> JsonResult<'a> {
+    fn error(kind: ErrorKind, message: &'a str) -> Self {
+        Self::Err {
+            ok: false,
+            code: kind,
+            exit_code: kind.exit_code(),
+            message,
         }
-        Ok(s) => {
-            println!("{s}");
-            Ok(())
+    }
+}
+
+fn print_json(result: &JsonResult<'_>) {
+    // Serializing strings and numbers cannot fail
+    println!("{}", serde_json::to_string(result).unwrap_or_default());
+}
+
+/// Rewrites `--output DIR` of `kvs backup` and `kvs man` to `--dir DIR`, their directory flag
+/// before `--output` became the global output format flag
+///
+/// Only values that are not output formats are taken as directories.
+fn legacy_args(mut args: Vec<OsString>) -> Vec<OsString> {
+    let cli = Cli::command();
+    let is_format = |value: &str| OutputFormat::from_str(value, false).is_ok();
+    // The subcommand is the first argument that is neither an option nor the value of one
+    let mut at = 1;
+    while let Some(long) = args
+        .get(at)
+        .and_then(|arg| arg.to_str())
+        .and_then(|arg| arg.strip_prefix("--"))
+    {
+        let takes_value = !long.contains('=')
+            && cli
+                .get_arguments()
+                .any(|arg| arg.get_long() == Some(long) && arg.get_action().takes_values());
+        at += if takes_value { 2 } else { 1 };
+    }
+    if !matches!(
+        args.get(at).and_then(|arg| arg.to_str()),
+        Some("backup" | "man")
+    ) {
+        return args;
+    }
+    for at in at + 1..args.len() {
+        let dir = match args[at].to_str() {
+            Some("--") => break,
+            Some("--output") => args
+                .get(at + 1)
+                .and_then(|value| value.to_str())
+                .is_none_or(|value| !is_format(value))
+                .then(|| "--dir".into()),
+            Some(arg) => arg
+                .strip_prefix("--output=")
+                .filter(|value| !is_format(value))
+                .map(|value| format!("--dir={value}").into()),
+            None => None,
+        };
+        if let Some(dir) = dir {
+            args[at] = dir;
+        }
+    }
+    args
+}
+
+/// Prints a command line parse error, or the help or version asked for, returning the exit
+/// code
+fn usage(e: &clap::Error) -> ExitCode {
+    if !e.use_stderr() {
+        // Help and version are not errors
+        let _ = e.print();
+        return ExitCode::SUCCESS;
+    }
+    // The output format is not parsed along with the error
+    let args: Vec<_> = env::args().collect();
+    let json = args.iter().any(|arg| arg == "--output=json")
+        || args
+            .windows(2)
+            .any(|pair| pair[0] == "--output" && pair[1] == "json");
+    if json {
+        print_json(&JsonResult::error(
+            ErrorKind::Usage,
+            e.to_string().trim_end(),
+        ));
+    } else {
+        let _ = e.print();
+    }
+    ExitCode::from(ErrorKind::Usage.exit_code())
+}
+
+/// Prints the man page of the CLI, or writes it and those of the subcommands to dir
+fn man(dir: Option<&Path>) -> Result<Output> {
+    let cli = Cli::command();
+    match dir {
+        Some(dir) => clap_mangen::generate_to(cli, dir).map(|()| Output::Text(String::new())),
+        None => clap_mangen::Man::new(cli)
+            .render(&mut io::stdout())
+            .map(|()| Output::Printed),
+    }
+    .map_err(KvStoreError::FailedDocs)
+}
+
+/// Checks the store in dir, failing if issues are left unresolved, after printing the report
+/// in the text format
+fn doctor(dir: PathBuf, repair: bool, format: OutputFormat) -> Result<Output> {
+    let report = kvs::doctor::check(dir, repair)?;
+    match report.unresolved() {
+        0 => Ok(Output::Text(report.to_string())),
+        n => {
+            if format == OutputFormat::Text {
+                println!("{report}");
+            }
+            Err(KvStoreError::Unhealthy(n))
         }
     }
 }
 
+/// Runs a log command on the store in dir
+fn log(dir: PathBuf, command: &LogCommand) -> Result<Output> {
+    let LogCommand::Dump {
+        segment,
+        since_seq,
+        json,
+    } = *command;
+    let entries = kvs::dump::dump(dir, segment, since_seq)?;
+    let lines = if json {
+        entries
+            .iter()
+            .map(|entry| serde_json::to_string(entry).map_err(KvStoreError::SerializeOutput))
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        let entries = entries.iter().map(ToString::to_string);
+        iter::once(Entry::header()).chain(entries).collect()
+    };
+    Ok(Output::Text(lines.join("\n")))
+}
+
+/// Returns the options of connections to servers, authenticating with the credentials given,
+/// and over TLS if a certificate authority is given
+#[cfg_attr(not(feature = "tls"), allow(clippy::unnecessary_wraps))] // TLS files may not load
+fn client_options(cli: &Cli) -> Result<ClientOptions> {
+    let mut options = ClientOptions::new();
+    if let Some(token) = &cli.token {
+        options.credentials(Credentials::Token(token.clone()));
+    } else if let Some((user, password)) = cli.user.clone().zip(cli.password.clone()) {
+        options.credentials(Credentials::Password { user, password });
+    }
+    #[cfg(feature = "tls")]
+    if let Some(ca) = &cli.tls_ca {
+        let identity = cli.tls_cert.as_deref().zip(cli.tls_key.as_deref());
+        options.tls(kvs::tls::client_config(ca, identity)?);
+    }
+    Ok(options)
+}
+
+/// Sends an admin command to a server
+fn admin(client: &KvsClient, command: &AdminCommand) -> Result<Output> {
+    let output = match command {
+        AdminCommand::Flush => client.flush().map(|()| String::new())?,
+        AdminCommand::Compact => client.compact().map(|()| String::new())?,
+        AdminCommand::Snapshot => client.snapshot().map(|()| String::new())?,
+        AdminCommand::Stats { json: false } => client.stats()?.to_string(),
+        AdminCommand::Stats { json: true } => {
+            serde_json::to_string(&client.stats()?).map_err(KvStoreError::SerializeOutput)?
+        }
+        AdminCommand::SlowLog { json: false } => {
+            let queries = client.slow_log()?;
+            let queries = queries.iter().map(ToString::to_string);
+            let lines: Vec<_> = iter::once(SlowQuery::header()).chain(queries).collect();
+            lines.join("\n")
+        }
+        AdminCommand::SlowLog { json: true } => client
+            .slow_log()?
+            .iter()
+            .map(|query| serde_json::to_string(query).map_err(KvStoreError::SerializeOutput))
+            .collect::<Result<Vec<_>>>()?
+            .join("\n"),
+        AdminCommand::Freeze => client.freeze_writes().map(|()| String::new())?,
+        AdminCommand::Thaw => client.thaw().map(|()| String::new())?,
+        AdminCommand::Reload => client.reload().map(|()| String::new())?,
+    };
+    Ok(Output::Text(output))
+}
+
+/// Returns the health of a server, failing if it is not ready after printing its health in the
+/// text format
+fn ping(
+    client: &KvsClient,
+    json: bool,
+    max_lag: Option<Duration>,
+    format: OutputFormat,
+) -> Result<Output> {
+    let health = client.ping()?;
+    let output = if json {
+        serde_json::to_string(&health).map_err(KvStoreError::SerializeOutput)?
+    } else {
+        health.to_string()
+    };
+    match health.problem(max_lag) {
+        None => Ok(Output::Text(output)),
+        Some(problem) => {
+            if format == OutputFormat::Text {
+                println!("{output}");
+            }
+            Err(KvStoreError::NotReady(problem))
+        }
+    }
+}
+
+/// Copies the WAL records of a server to a backup directory, or prints those numbered from
+/// `from_seq` on, until caught up unless following
+fn backup(
+    client: &KvsClient,
+    from_seq: Option<u64>,
+    follow: bool,
+    dir: Option<&Path>,
+) -> Result<Output> {
+    let mut backup = dir.map(Backup::open).transpose()?;
+    let from_seq = match &backup {
+        Some(backup) => backup.sequence() + 1,
+        None => from_seq.unwrap_or(1),
+    };
+
+    for shipment in client.tail(from_seq)? {
+        let shipment = shipment?;
+        if let Some(backup) = &mut backup {
+            backup.write(&shipment)?;
+        } else {
+            let records = match &shipment {
+                Shipment::Committed(records, _) => records,
+                Shipment::Snapshot(records, _) => {
+                    warn!(
+                        from_seq,
+                        "Records were compacted, printing a snapshot instead"
+                    );
+                    records
+                }
+            };
+            let mut stdout = io::stdout().lock();
+            stdout
+                .write_all(records.as_bytes())
+                .and_then(|()| stdout.flush())
+                .map_err(KvStoreError::FailedBackup)?;
+        }
+        if !follow {
+            break;
+        }
+    }
+
+    Ok(backup.map_or(Output::Printed, |backup| {
+        Output::Text(format!(
+            "Copied records through sequence number {}",
+            backup.sequence()
+        ))
+    }))
+}
+
+/// Sends a cluster command to a server
+#[cfg(feature = "raft")]
+fn cluster(client: &KvsClient, command: &kvs::raft::ClusterCommand) -> Result<Output> {
+    match command {
+        kvs::raft::ClusterCommand::AddNode { node } => client
+            .cluster_add_node(*node)
+            .map(|()| Output::Text(String::new())),
+        kvs::raft::ClusterCommand::Status => Ok(Output::Text(client.cluster_status()?.to_string())),
+    }
+}
+
+/// Executes a command on a bucket, which only supports key-value commands
+fn execute_in(bucket: &Bucket, cmd: Command) -> Result<Output> {
+    match cmd {
+        Command::Get { key, path: None } => bucket.get(key).map(Output::Value),
+        Command::Set {
+            key,
+            value,
+            path: None,
+            nx: false,
+            xx: false,
+        } => bucket.set(key, value).map(Output::Previous),
+        Command::Rm { key, prefix: None } => bucket.remove(key).map(Output::Previous),
+        cmd => Err(KvStoreError::InvalidCommand(format!(
+            "{cmd} is not supported in buckets"
+        ))),
+    }
+}
+
+/// Installs a global subscriber writing log events to stderr
+fn init_logging(level: LevelFilter, format: LogFormat) {
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(io::stderr);
+
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Command,
+
+    /// Bucket to run the command in, default the store's own key space
+    #[arg(long, global = true)]
+    bucket: Option<String>,
+
+    /// Format of command results printed to stdout
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Maximum level of log events written to stderr
+    #[arg(long, global = true, default_value_t = LevelFilter::WARN)]
+    log_level: LevelFilter,
+
+    /// Format of log events
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// User to authenticate as to the server, for servers with an ACL
+    #[arg(long, global = true, requires = "password", conflicts_with = "token")]
+    user: Option<String>,
+
+    /// Password of the user
+    #[arg(long, global = true, requires = "user")]
+    password: Option<String>,
+
+    /// Token to authenticate with to the server, for servers with an ACL
+    #[arg(long, global = true)]
+    token: Option<String>,
+
+    /// PEM file of the certificate authority of the server, to send admin and cluster commands
+    /// over TLS
+    #[cfg(feature = "tls")]
+    #[arg(long, global = true, value_name = "PATH")]
+    tls_ca: Option<PathBuf>,
+
+    /// PEM file of the client certificate chain, for servers requiring one
+    #[cfg(feature = "tls")]
+    #[arg(long, global = true, value_name = "PATH", requires_all = ["tls_ca", "tls_key"])]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file of the private key of the client certificate
+    #[cfg(feature = "tls")]
+    #[arg(long, global = true, value_name = "PATH", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+}
+
+/// Command result output format
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Output of the command as is
+    Text,
+    /// JSON object per command, with `ok`, and the output as `value`, or the error as `code`,
+    /// `exit_code` and `message`
+    Json,
+}
+
+/// Log event output format
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// JSON object per line
+    Json,
 }
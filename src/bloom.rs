@@ -0,0 +1,165 @@
+//! Bloom filters of the keys whose values a segment holds, so that reads skip segments that
+//! cannot hold a key
+//!
+//! Each sealed and base segment gets a filter file next to it, `wa.<id>.bloom` or
+//! `wa.<id>.base.bloom`, written when the segment is sealed or compacted into. After a header
+//! line, the file holds the bits of the filter as little-endian 64-bit words:
+//!
+//! ```text
+//! kvs-bloom <version> <hashes> <checksum> <len>
+//! ```
+//!
+//! with the number of hash functions, and the checksum and length of the bits. Filter files
+//! that cannot be used are ignored, so that the segment is read as if it had no filter.
+
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+};
+
+/// Magic starting the header of filter files
+const BLOOM_MAGIC: &str = "kvs-bloom";
+
+/// Format version of filter files written by this version
+const BLOOM_VERSION: u32 = 1;
+
+/// Bits per key, for about 1% false positives with [`HASHES`] hash functions
+const BITS_PER_KEY: usize = 10;
+
+/// Hash functions per key
+const HASHES: u32 = 7;
+
+/// Set of keys answering whether it may hold a key, never missing one it holds
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Bloom {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl Bloom {
+    /// Returns an empty filter sized for keys keys
+    pub(crate) fn new(keys: usize) -> Self {
+        let words = keys.saturating_mul(BITS_PER_KEY).div_ceil(64).max(1);
+        Self {
+            bits: vec![0; words],
+            hashes: HASHES,
+        }
+    }
+
+    /// Returns a filter holding keys
+    pub(crate) fn of(keys: &[impl AsRef<str>]) -> Self {
+        let mut bloom = Self::new(keys.len());
+        for key in keys {
+            bloom.insert(key.as_ref());
+        }
+        bloom
+    }
+
+    pub(crate) fn insert(&mut self, key: &str) {
+        for bit in self.bits_of(key) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Returns whether key may be in the set, `false` meaning it is not
+    pub(crate) fn may_contain(&self, key: &str) -> bool {
+        self.bits_of(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Returns the positions of the bits of key, derived from the halves of a single hash
+    #[allow(clippy::cast_possible_truncation)] // Below the number of bits, which fit in memory
+    fn bits_of(&self, key: &str) -> impl Iterator<Item = usize> {
+        let hash = fnv1a(key.as_bytes());
+        let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        let len = self.bits.len() as u64 * 64;
+        (0..u64::from(self.hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    /// Writes the filter to a file at path and syncs it
+    ///
+    /// # Errors
+    /// Returns `Err` if writing or syncing fails
+    pub(crate) fn write(&self, path: &Path) -> io::Result<()> {
+        let bytes: Vec<u8> = self.bits.iter().flat_map(|w| w.to_le_bytes()).collect();
+        let mut file = File::create(path)?;
+        writeln!(
+            file,
+            "{BLOOM_MAGIC} {BLOOM_VERSION} {} {:08x} {:016x}",
+            self.hashes,
+            crc32fast::hash(&bytes),
+            bytes.len()
+        )?;
+        file.write_all(&bytes)?;
+        file.sync_all()
+    }
+
+    /// Reads the filter file at path
+    ///
+    /// # Errors
+    /// Returns why the filter file cannot be used: missing, unreadable, malformed, of an
+    /// unsupported format version, or corrupted
+    pub(crate) fn read(path: &Path) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|e| e.to_string())?;
+        let end = bytes
+            .iter()
+            .take(64)
+            .position(|&b| b == b'\n')
+            .ok_or("missing header")?;
+        let header = std::str::from_utf8(&bytes[..end]).map_err(|_| "malformed header")?;
+        let words = &bytes[end + 1..];
+        let malformed = || "malformed header".to_owned();
+        let [magic, version, hashes, checksum, len] = header.split(' ').collect::<Vec<_>>()[..]
+        else {
+            return Err(malformed());
+        };
+        if magic != BLOOM_MAGIC {
+            return Err("missing header".to_owned());
+        }
+        match version.parse::<u32>() {
+            Ok(BLOOM_VERSION) => {}
+            Ok(version) => return Err(format!("unsupported format version {version}")),
+            Err(_) => return Err(malformed()),
+        }
+        let (Ok(hashes @ 1..=HASHES), Ok(checksum), Ok(len)) = (
+            hashes.parse(),
+            u32::from_str_radix(checksum, 16),
+            u64::from_str_radix(len, 16),
+        ) else {
+            return Err(malformed());
+        };
+        if words.len() as u64 != len || words.is_empty() || len % 8 != 0 {
+            return Err(format!(
+                "expected {len} bytes of bits, found {}",
+                words.len()
+            ));
+        }
+        if crc32fast::hash(words) != checksum {
+            return Err("checksum mismatch".to_owned());
+        }
+        let bits = words
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap_or_default()))
+            .collect();
+        Ok(Self { bits, hashes })
+    }
+}
+
+/// Returns the 64-bit FNV-1a hash of bytes, which unlike the hasher of the standard library is
+/// the same in every build, as persisted filters require
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Returns the key whose value a record with the given fields writes, if any
+pub(crate) fn valued_key(fields: &[String]) -> Option<&str> {
+    match fields {
+        [tag, key, ..] if matches!(tag.as_str(), "set" | "vset" | "xset" | "append") => Some(key),
+        [tag, _, key, _] if matches!(tag.as_str(), "mv" | "bset") => Some(key),
+        _ => None,
+    }
+}
@@ -0,0 +1,141 @@
+//! Named buckets: separate key spaces sharing one store and WAL
+
+use crate::{wal::Record, KvStore, KvStoreError, Result};
+use tracing::debug;
+
+/// Handle to a named bucket of a [`KvStore`], with its own key space
+///
+/// Keys of a bucket never collide with those of other buckets or of the store itself, which
+/// is the default key space. Bucket writes are logged as they happen, bypassing write
+/// coalescing, and are not delivered to watchers.
+pub struct Bucket<'a> {
+    store: &'a KvStore,
+    name: String,
+}
+
+impl<'a> Bucket<'a> {
+    pub(crate) fn new(store: &'a KvStore, name: String) -> Self {
+        Self { store, name }
+    }
+
+    /// Returns the bucket name
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Inserts key-value pair into bucket, returning the previous value of key if present
+    ///
+    /// # Errors
+    /// Returns `Err` if key or value exceeds its size limit, or on-disk WAL write fails
+    pub fn set(&self, key: String, value: String) -> Result<Option<String>> {
+        let store = self.store;
+        store.guard_write("set", || {
+            store.check_len(&key, &value)?;
+            store.check_memory()?;
+            if store.inner.options.offset_index {
+                let previous = store.inner.wal.get(Some(&self.name), &key)?;
+                store.inner.wal.write(Record::BucketSet {
+                    bucket: self.name.clone(),
+                    key,
+                    value,
+                })?;
+                return Ok(previous);
+            }
+
+            let (entry, previous) =
+                match store.inner.buckets.entry((self.name.clone(), key.clone())) {
+                    dashmap::Entry::Occupied(mut entry) => {
+                        let previous = entry.insert(value.clone());
+                        (entry.into_ref(), Some(previous))
+                    }
+                    dashmap::Entry::Vacant(entry) => (entry.insert(value.clone()), None),
+                };
+            let pending = store.inner.wal.append(Record::BucketSet {
+                bucket: self.name.clone(),
+                key,
+                value,
+            });
+            drop(entry);
+            store.logged(pending).map(|()| previous)
+        })
+    }
+
+    /// Returns value for given key from bucket if present
+    ///
+    /// # Errors
+    /// Returns `Err` if KV store read fails
+    pub fn get(&self, key: impl Into<String>) -> Result<Option<String>> {
+        let key = key.into();
+        let store = self.store;
+        store.guard("get", || {
+            let value = if store.inner.options.offset_index {
+                store.inner.wal.get(Some(&self.name), &key)?
+            } else {
+                store
+                    .inner
+                    .buckets
+                    .get(&(self.name.clone(), key.clone()))
+                    .map(|v| v.value().to_owned())
+            };
+
+            if value.is_none() {
+                debug!(bucket = self.name, key, "Key not found");
+            }
+            Ok(value)
+        })
+    }
+
+    /// Returns key-value pairs of bucket whose keys start with prefix, sorted by key
+    ///
+    /// # Errors
+    /// Returns `Err` if KV store read fails
+    pub fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let store = self.store;
+        store.guard("scan", || {
+            if store.inner.options.offset_index {
+                return store.inner.wal.scan(Some(&self.name), prefix);
+            }
+
+            let mut entries: Vec<_> = store
+                .inner
+                .buckets
+                .iter()
+                .filter(|e| e.key().0 == self.name && e.key().1.starts_with(prefix))
+                .map(|e| (e.key().1.clone(), e.value().clone()))
+                .collect();
+            entries.sort_unstable();
+            Ok(entries)
+        })
+    }
+
+    /// Removes key-value pair from bucket for given key, returning the removed value
+    ///
+    /// # Errors
+    /// Returns `Err` if key is not found or on-disk WAL write fails
+    pub fn remove(&self, key: String) -> Result<Option<String>> {
+        let store = self.store;
+        store.guard_write("rm", || {
+            if store.inner.options.offset_index {
+                let removed = store.inner.wal.get(Some(&self.name), &key)?;
+                store.inner.wal.write(Record::BucketRm {
+                    bucket: self.name.clone(),
+                    key,
+                })?;
+                return Ok(removed);
+            }
+
+            let dashmap::Entry::Occupied(entry) =
+                store.inner.buckets.entry((self.name.clone(), key.clone()))
+            else {
+                return Err(KvStoreError::FailedRm(key));
+            };
+            let pending = store.inner.wal.append(Record::BucketRm {
+                bucket: self.name.clone(),
+                key,
+            });
+            let removed = entry.remove();
+            store.logged(pending).map(|()| Some(removed))
+        })
+    }
+}
@@ -0,0 +1,149 @@
+//! Least-recently-used (LRU) cache of values read from disk
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+/// Configuration of the value cache
+#[derive(Clone, Copy, Debug)]
+pub struct CacheConfig {
+    /// Maximum total bytes of cached keys and values
+    pub capacity_bytes: usize,
+}
+
+/// Thread-safe LRU cache bounded by the bytes of its keys and values
+///
+/// Stores of a runtime share one LRU, each through a view of its own keys, see
+/// [`ValueCache::view`].
+#[derive(Debug)]
+pub(crate) struct ValueCache {
+    inner: Arc<Mutex<Lru>>,
+    /// ID of the store whose keys the view holds
+    store: u64,
+}
+
+/// Key of a store in the LRU, by ID of the store
+type Key = (u64, String);
+
+#[derive(Debug, Default)]
+struct Lru {
+    /// Maximum total bytes of keys and values
+    capacity: usize,
+    entries: HashMap<Key, Entry>,
+    /// Keys by last use, oldest first
+    order: BTreeMap<u64, Key>,
+    tick: u64,
+    size: usize,
+    /// Bumped by every invalidation
+    epoch: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: Arc<str>,
+    used: u64,
+}
+
+impl ValueCache {
+    pub(crate) fn new(config: CacheConfig) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Lru {
+                capacity: config.capacity_bytes,
+                ..Lru::default()
+            })),
+            store: 0,
+        }
+    }
+
+    /// Returns a view of the same LRU holding the keys of the store with the given ID
+    pub(crate) fn view(&self, store: u64) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            store,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Lru> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the cached value of key, marking it as recently used
+    pub(crate) fn get(&self, key: &str) -> Option<Arc<str>> {
+        let key = (self.store, key.to_owned());
+        let mut lru = self.lock();
+        lru.tick += 1;
+        let tick = lru.tick;
+        let entry = lru.entries.get_mut(&key)?;
+        let used = std::mem::replace(&mut entry.used, tick);
+        let value = Arc::clone(&entry.value);
+        lru.order.remove(&used);
+        lru.order.insert(tick, key);
+
+        Some(value)
+    }
+
+    /// Returns the current epoch, to pass to [`ValueCache::insert`] after reading a value
+    pub(crate) fn epoch(&self) -> u64 {
+        self.lock().epoch
+    }
+
+    /// Caches a value read while the cache was at the given epoch, evicting the least recently
+    /// used values as needed
+    ///
+    /// The value is dropped if any key was invalidated since, as it may be stale.
+    pub(crate) fn insert(&self, key: String, value: Arc<str>, epoch: u64) {
+        let size = key.len() + value.len();
+        let mut lru = self.lock();
+        if epoch != lru.epoch || size > lru.capacity {
+            return;
+        }
+
+        let key = (self.store, key);
+        lru.remove(&key);
+        let room = lru.capacity - size;
+        lru.evict(room);
+
+        lru.tick += 1;
+        let used = lru.tick;
+        lru.order.insert(used, key.clone());
+        lru.entries.insert(key, Entry { value, used });
+        lru.size += size;
+    }
+
+    /// Drops the cached value of key, if any
+    pub(crate) fn invalidate(&self, key: &str) {
+        let mut lru = self.lock();
+        lru.epoch += 1;
+        lru.remove(&(self.store, key.to_owned()));
+    }
+
+    /// Sets the maximum total bytes of cached keys and values, evicting the least recently used
+    /// values beyond it, of every store sharing the LRU
+    pub(crate) fn resize(&self, capacity: usize) {
+        let mut lru = self.lock();
+        lru.capacity = capacity;
+        lru.evict(capacity);
+    }
+}
+
+impl Lru {
+    /// Evicts the least recently used values until at most size bytes are cached
+    fn evict(&mut self, size: usize) {
+        while self.size > size {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.size -= oldest.1.len() + entry.value.len();
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.used);
+            self.size -= key.1.len() + entry.value.len();
+        }
+    }
+}
@@ -0,0 +1,1059 @@
+//! Client for the TCP [`server`](crate::server), with connection pooling and retries
+
+use crate::{
+    auth::Credentials,
+    protocol::{read_frame, write_frame, Envelope, Request, Response, Tailing, REFUSED},
+    transport::{ServerAddr, Stream},
+    Health, KvStoreError, Result, Shipment, SlowQuery, StoreStats,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::{self, prelude::*, BufReader, BufWriter},
+    net::{SocketAddr, ToSocketAddrs},
+    slice,
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+/// Options to configure how a client talks to a server
+///
+/// Mirrors [`OpenOptions`](crate::OpenOptions): chain setters on [`ClientOptions::new`],
+/// then call [`ClientOptions::connect`] or [`ClientOptions::connect_to`].
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    pool_size: usize,
+    timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    retries: u32,
+    backoff: Duration,
+    virtual_nodes: usize,
+    credentials: Option<Credentials>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ClientConfig>>,
+    #[cfg(feature = "tls")]
+    tls_server_name: Option<String>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            pool_size: 4,
+            timeout: Some(Duration::from_secs(5)),
+            request_timeout: None,
+            retries: 3,
+            backoff: Duration::from_millis(50),
+            virtual_nodes: 128,
+            credentials: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            tls_server_name: None,
+        }
+    }
+}
+
+impl ClientOptions {
+    /// Returns default options
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum number of idle connections kept for reuse, default 4
+    ///
+    /// More connections are opened as needed by concurrent callers; those beyond the limit are
+    /// closed once their request completes.
+    pub fn pool_size(&mut self, pool_size: usize) -> &mut Self {
+        self.pool_size = pool_size;
+        self
+    }
+
+    /// Sets the connect, read, and write timeout of each connection, default 5 seconds
+    ///
+    /// `None` waits indefinitely.
+    pub fn timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the deadline of each request, retries included, default none
+    ///
+    /// A request not answered in time fails with [`KvStoreError::Timeout`] and its connection
+    /// is closed, so a late response cannot be taken for that of a later request. The server
+    /// may still execute the request. See also [`KvsClient::pipeline_timeout`].
+    pub fn request_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Sets how many times a request failing with a network error is retried, default 3
+    ///
+    /// Retries use a fresh connection. A retried request may have been executed by the
+    /// server already, e.g. a `rm` can then report the key as not found.
+    pub fn retries(&mut self, retries: u32) -> &mut Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Sets the delay before the first retry, doubled for each further one, default 50 ms
+    pub fn backoff(&mut self, backoff: Duration) -> &mut Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Sets the number of points each server of a [`ShardedClient`] takes on its hash ring,
+    /// default 128
+    ///
+    /// More points spread keys more evenly across servers. Clients sharing servers must use the
+    /// same number to map keys alike.
+    pub fn virtual_nodes(&mut self, virtual_nodes: usize) -> &mut Self {
+        self.virtual_nodes = virtual_nodes;
+        self
+    }
+
+    /// Authenticates each connection with credentials, for servers with an
+    /// [ACL](crate::auth::Acl)
+    pub fn credentials(&mut self, credentials: Credentials) -> &mut Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Connects over TLS with config, see [`tls::client_config`](crate::tls::client_config)
+    #[cfg(feature = "tls")]
+    pub fn tls(&mut self, config: Arc<rustls::ClientConfig>) -> &mut Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Sets the name the certificate of the server must be valid for, default the IP address
+    /// connected to
+    ///
+    /// Required over Unix domain sockets, which have no IP address.
+    #[cfg(feature = "tls")]
+    pub fn tls_server_name(&mut self, server_name: impl Into<String>) -> &mut Self {
+        self.tls_server_name = Some(server_name.into());
+        self
+    }
+
+    /// Returns a client spreading keys across the servers at addresses with these options
+    ///
+    /// Connections to each server are opened on first use.
+    #[must_use]
+    pub fn sharded(&self, addrs: &[SocketAddr]) -> ShardedClient {
+        let mut client = ShardedClient {
+            ring: BTreeMap::new(),
+            shards: HashMap::new(),
+            options: self.clone(),
+        };
+        for &addr in addrs {
+            client.add_shard(addr);
+        }
+        client
+    }
+
+    /// Connects to the server at address with these options
+    ///
+    /// # Errors
+    /// Returns `Err` if address does not resolve or no connection can be opened
+    pub fn connect(&self, addr: impl ToSocketAddrs) -> Result<KvsClient> {
+        let addrs = addr
+            .to_socket_addrs()
+            .map_err(KvStoreError::FailedConnect)?
+            .collect();
+        self.connect_to(ServerAddr::Tcp(addrs))
+    }
+
+    /// Connects to the server at address, over TCP or a Unix domain socket, with these
+    /// options
+    ///
+    /// Parse the address from a string such as `unix:///run/kvs.sock` with
+    /// [`str::parse`].
+    ///
+    /// # Errors
+    /// Returns `Err` if no connection can be opened
+    pub fn connect_to(&self, addr: ServerAddr) -> Result<KvsClient> {
+        let client = KvsClient {
+            addr,
+            options: self.clone(),
+            idle: Mutex::new(Vec::new()),
+        };
+
+        let connection = client.with_retries(|| client.open())?;
+        client.checkin(connection);
+
+        Ok(client)
+    }
+}
+
+/// Client of a KV store server
+///
+/// Safe to share between threads: each call borrows a pooled connection for its duration.
+pub struct KvsClient {
+    addr: ServerAddr,
+    options: ClientOptions,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl fmt::Debug for KvsClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KvsClient")
+            .field("addr", &self.addr)
+            .field("options", &self.options)
+            .finish_non_exhaustive()
+    }
+}
+
+impl KvsClient {
+    /// Connects to the server at address with default [`ClientOptions`]
+    ///
+    /// # Errors
+    /// Returns `Err` if address does not resolve or no connection can be opened
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        ClientOptions::new().connect(addr)
+    }
+
+    /// Returns value for given key if present
+    ///
+    /// # Errors
+    /// Returns `Err` if the request fails
+    pub fn get(&self, key: impl Into<String>) -> Result<Option<String>> {
+        match self.call(&Request::Get {
+            key: key.into(),
+            path: None,
+        })? {
+            Response::Ok(value) => Ok(value),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    /// Returns the field at path of the JSON value of key as JSON, if present, see
+    /// [`KvStore::get_path`](crate::KvStore::get_path)
+    ///
+    /// # Errors
+    /// Returns `Err` if the path or the value is not valid, or the request fails
+    pub fn get_path(
+        &self,
+        key: impl Into<String>,
+        path: impl Into<String>,
+    ) -> Result<Option<String>> {
+        match self.call(&Request::Get {
+            key: key.into(),
+            path: Some(path.into()),
+        })? {
+            Response::Ok(value) => Ok(value),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    /// Inserts key-value pair, returning the previous value of key if present
+    ///
+    /// # Errors
+    /// Returns `Err` if the request fails
+    pub fn set(&self, key: impl Into<String>, value: impl Into<String>) -> Result<Option<String>> {
+        match self.call(&Request::Set {
+            key: key.into(),
+            value: value.into(),
+            path: None,
+        })? {
+            Response::Ok(previous) => Ok(previous),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    /// Sets the field at path of the JSON value of key to value, given as JSON, see
+    /// [`KvStore::set_path`](crate::KvStore::set_path)
+    ///
+    /// # Errors
+    /// Returns `Err` if the path or either value is not valid, or the request fails
+    pub fn set_path(
+        &self,
+        key: impl Into<String>,
+        path: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<()> {
+        self.call(&Request::Set {
+            key: key.into(),
+            value: value.into(),
+            path: Some(path.into()),
+        })
+        .map(drop)
+    }
+
+    /// Removes key-value pair for given key, returning the removed value
+    ///
+    /// Servers of earlier versions do not return the removed value, so it is `None` from them.
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::FailedRm`] if the key was not found, or `Err` if the request fails
+    pub fn remove(&self, key: impl Into<String>) -> Result<Option<String>> {
+        match self.call(&Request::Rm { key: key.into() })? {
+            Response::Ok(removed) => Ok(removed),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    /// Returns key-value pairs whose keys start with prefix, sorted by key
+    ///
+    /// # Errors
+    /// Returns `Err` if the request fails
+    pub fn scan(&self, prefix: impl Into<String>) -> Result<Vec<(String, String)>> {
+        match self.call(&Request::Scan {
+            prefix: prefix.into(),
+        })? {
+            Response::Entries(entries) => Ok(entries),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    /// Returns a page of the keys matching a glob-style pattern, all if `None`, among the
+    /// count keys after cursor, along with the cursor of the next page, see
+    /// [`Request::ScanCursor`]
+    ///
+    /// Start a scan with cursor `None`, and continue it until the cursor returned is `None`.
+    ///
+    /// # Errors
+    /// Returns `Err` if the request fails
+    pub fn scan_cursor(
+        &self,
+        cursor: Option<String>,
+        pattern: Option<&str>,
+        count: Option<usize>,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        let request = Request::ScanCursor {
+            cursor,
+            pattern: pattern.map(str::to_owned),
+            count,
+        };
+        match self.call(&request)? {
+            Response::Page { keys, cursor } => Ok((keys, cursor)),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    /// Takes the lock under key for ttl unless held, returning the token of its lease, see
+    /// [`KvStore::lock`](crate::KvStore::lock)
+    ///
+    /// # Errors
+    /// Returns `Err` if the lock is held or the request fails
+    pub fn lock(&self, key: impl Into<String>, ttl: Duration) -> Result<u64> {
+        let request = Request::Lock {
+            key: key.into(),
+            ttl_ms: u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX),
+        };
+        match self.call(&request)? {
+            Response::Ok(Some(token)) => token
+                .parse()
+                .map_err(|_| KvStoreError::Remote(format!("Unexpected lease token: {token}"))),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    /// Releases the lock under key held with the lease of token
+    ///
+    /// # Errors
+    /// Returns `Err` if the lock is not held with token or the request fails
+    pub fn unlock(&self, key: impl Into<String>, token: u64) -> Result<()> {
+        self.call(&Request::Unlock {
+            key: key.into(),
+            token,
+        })
+        .map(drop)
+    }
+
+    /// Inserts key-value pair and returns a session token for [`KvsClient::get_after`]
+    ///
+    /// # Errors
+    /// Returns `Err` if the request fails
+    pub fn set_with_token(&self, key: impl Into<String>, value: impl Into<String>) -> Result<u64> {
+        let set = Request::Set {
+            key: key.into(),
+            value: value.into(),
+            path: None,
+        };
+        self.with_token(&set)
+    }
+
+    /// Removes key-value pair for given key and returns a session token for
+    /// [`KvsClient::get_after`]
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::FailedRm`] if the key was not found, or `Err` if the request fails
+    pub fn remove_with_token(&self, key: impl Into<String>) -> Result<u64> {
+        self.with_token(&Request::Rm { key: key.into() })
+    }
+
+    /// Returns value for given key if present, observing the writes up to a session token
+    ///
+    /// A replica waits until it has applied the writes; if it lags too far behind, it
+    /// redirects the read to its primary.
+    ///
+    /// # Errors
+    /// Returns `Err` if the request fails
+    pub fn get_after(&self, key: impl Into<String>, token: u64) -> Result<Option<String>> {
+        // Pipelined requests may run in any order, so the read waits for the answer
+        match self.send(&Request::WaitFor { sequence: token })? {
+            Response::Redirect(primary) => {
+                debug!(%primary, "Redirected to primary");
+                self.options.connect(primary)?.get(key)
+            }
+            Response::Ok(_) => self.get(key),
+            Response::PermissionDenied(e) => Err(KvStoreError::PermissionDenied(e)),
+            Response::Busy(e) => Err(KvStoreError::Busy(e)),
+            Response::Err(e) => Err(KvStoreError::Remote(e)),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    /// Sends a write, then a sequence request once it is done, returning the sequence
+    fn with_token(&self, write: &Request) -> Result<u64> {
+        match self.send(write)? {
+            Response::KeyNotFound(key) => return Err(KvStoreError::FailedRm(key)),
+            Response::PermissionDenied(e) => return Err(KvStoreError::PermissionDenied(e)),
+            Response::Busy(e) => return Err(KvStoreError::Busy(e)),
+            Response::ReadOnly => return Err(KvStoreError::ReadOnly),
+            Response::Err(e) => return Err(KvStoreError::Remote(e)),
+            _ => {}
+        }
+        match self.send(&Request::Sequence)? {
+            Response::Sequence(sequence) => Ok(sequence),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    /// Promotes the server from replica to primary, accepting writes from clients
+    ///
+    /// # Errors
+    /// Returns `Err` if the server is not a replica or the request fails
+    pub fn promote(&self) -> Result<()> {
+        self.call(&Request::Promote).map(drop)
+    }
+
+    /// Makes the server reload its settings, e.g. `kvs-server` its config file
+    ///
+    /// # Errors
+    /// Returns `Err` if the server cannot reload or the request fails
+    pub fn reload(&self) -> Result<()> {
+        self.call(&Request::Reload).map(drop)
+    }
+
+    /// Makes the server log writes held back by coalescing and sync its WAL to disk, see
+    /// [`KvStore::sync`](crate::KvStore::sync)
+    ///
+    /// # Errors
+    /// Returns `Err` if the server fails to sync or the request fails
+    pub fn flush(&self) -> Result<()> {
+        self.call(&Request::Flush).map(drop)
+    }
+
+    /// Makes the server compact its WAL now, see [`KvStore::compact`](crate::KvStore::compact)
+    ///
+    /// # Errors
+    /// Returns `Err` if compaction fails or the request fails
+    pub fn compact(&self) -> Result<()> {
+        self.call(&Request::Compact).map(drop)
+    }
+
+    /// Makes the server rewrite its WAL as a new base segment, see
+    /// [`KvStore::snapshot`](crate::KvStore::snapshot)
+    ///
+    /// # Errors
+    /// Returns `Err` if rewriting fails or the request fails
+    pub fn snapshot(&self) -> Result<()> {
+        self.call(&Request::Snapshot).map(drop)
+    }
+
+    /// Makes the server reject writes until thawed, see
+    /// [`KvStore::freeze_writes`](crate::KvStore::freeze_writes)
+    ///
+    /// # Errors
+    /// Returns `Err` if the server fails to sync or the request fails
+    pub fn freeze_writes(&self) -> Result<()> {
+        self.call(&Request::Freeze).map(drop)
+    }
+
+    /// Makes the server accept writes again after [`KvsClient::freeze_writes`]
+    ///
+    /// # Errors
+    /// Returns `Err` if the request fails
+    pub fn thaw(&self) -> Result<()> {
+        self.call(&Request::Thaw).map(drop)
+    }
+
+    /// Returns statistics of the store of the server
+    ///
+    /// # Errors
+    /// Returns `Err` if the request fails
+    pub fn stats(&self) -> Result<StoreStats> {
+        match self.call(&Request::Stats)? {
+            Response::Stats(stats) => Ok(stats),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    /// Checks that the server is alive and returns the health of its store
+    ///
+    /// # Errors
+    /// Returns `Err` if the request fails
+    pub fn ping(&self) -> Result<Health> {
+        match self.call(&Request::Ping)? {
+            Response::Health(health) => Ok(health),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    /// Returns the requests the server recently took long to answer, oldest first
+    ///
+    /// # Errors
+    /// Returns `Err` if the server keeps no slow query log or the request fails
+    pub fn slow_log(&self) -> Result<Vec<SlowQuery>> {
+        match self.call(&Request::SlowLog)? {
+            Response::SlowQueries(queries) => Ok(queries),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    /// Streams the WAL records of the server numbered from sequence number `from_seq` on, then
+    /// those it commits, see [`KvStore::tail`](crate::KvStore::tail)
+    ///
+    /// The stream holds a connection of its own, closed when dropped.
+    ///
+    /// # Errors
+    /// Returns `Err` if no connection can be opened or the request fails
+    pub fn tail(&self, from_seq: u64) -> Result<Tail> {
+        let mut connection = self.with_retries(|| self.open())?;
+        let request = Request::Tail { from_seq };
+        let response = connection
+            .roundtrip(
+                slice::from_ref(&request),
+                self.options.request_timeout.map(|t| Instant::now() + t),
+            )
+            .map_err(KvStoreError::FailedRequest)?
+            .pop();
+        match response {
+            Some(Response::Ok(_)) => Ok(Tail {
+                connection,
+                ended: false,
+            }),
+            Some(Response::PermissionDenied(e)) => Err(KvStoreError::PermissionDenied(e)),
+            Some(Response::Busy(e)) => Err(KvStoreError::Busy(e)),
+            Some(Response::Err(e)) => Err(KvStoreError::Remote(e)),
+            Some(response) => Err(unexpected(&response)),
+            None => Err(KvStoreError::FailedRequest(
+                io::ErrorKind::UnexpectedEof.into(),
+            )),
+        }
+    }
+
+    /// Adds a member to the Raft cluster of the server
+    ///
+    /// # Errors
+    /// Returns `Err` if the server is not a cluster member, another member is still being
+    /// added, or the request fails
+    #[cfg(feature = "raft")]
+    pub fn cluster_add_node(&self, node: SocketAddr) -> Result<()> {
+        self.call(&Request::ClusterAddNode { node }).map(drop)
+    }
+
+    /// Returns the Raft cluster state seen by the server
+    ///
+    /// # Errors
+    /// Returns `Err` if the server is not a cluster member or the request fails
+    #[cfg(feature = "raft")]
+    pub fn cluster_status(&self) -> Result<crate::raft::Status> {
+        match self.call(&Request::ClusterStatus)? {
+            Response::Cluster(status) => Ok(status),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    /// Sends requests in one batch over a single connection and returns their responses, in
+    /// the order of the requests
+    ///
+    /// The server executes the requests concurrently, in no particular order, and answers each
+    /// one, so a failed request does not stop the others; check each response. Send requests
+    /// depending on the outcome of others in later batches.
+    ///
+    /// # Errors
+    /// Returns `Err` if the batch cannot be sent or its responses cannot be read
+    pub fn pipeline(&self, requests: &[Request]) -> Result<Vec<Response>> {
+        self.exchange(requests, self.options.request_timeout)
+    }
+
+    /// Sends requests in one batch like [`KvsClient::pipeline`], giving up on their responses
+    /// after timeout instead of the [request timeout](ClientOptions::request_timeout)
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::Timeout`] if the responses are not all read within timeout, or
+    /// `Err` if the batch cannot be sent or its responses cannot be read
+    pub fn pipeline_timeout(
+        &self,
+        requests: &[Request],
+        timeout: Duration,
+    ) -> Result<Vec<Response>> {
+        self.exchange(requests, Some(timeout))
+    }
+
+    /// Sends requests in one batch, retrying until timeout if any
+    fn exchange(&self, requests: &[Request], timeout: Option<Duration>) -> Result<Vec<Response>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self.with_retries(|| {
+            let mut connection = self.checkout()?;
+            // A failed connection is dropped rather than checked in, since it may be
+            // midway through a frame or still owe responses
+            let responses =
+                connection
+                    .roundtrip(requests, deadline)
+                    .map_err(|e| match timeout {
+                        Some(timeout) if deadline.is_some_and(|d| Instant::now() >= d) => {
+                            KvStoreError::Timeout(timeout)
+                        }
+                        _ => KvStoreError::FailedRequest(e),
+                    })?;
+            if !connection.refused {
+                self.checkin(connection);
+            }
+            Ok(responses)
+        })
+    }
+
+    /// Sends a single request and returns its response
+    fn send(&self, request: &Request) -> Result<Response> {
+        self.pipeline(slice::from_ref(request))?
+            .pop()
+            .ok_or_else(|| KvStoreError::FailedRequest(io::ErrorKind::UnexpectedEof.into()))
+    }
+
+    /// Sends a single request and turns error responses into `Err`, following redirects
+    fn call(&self, request: &Request) -> Result<Response> {
+        match self.send(request)? {
+            Response::KeyNotFound(key) => Err(KvStoreError::FailedRm(key)),
+            Response::PermissionDenied(e) => Err(KvStoreError::PermissionDenied(e)),
+            Response::Busy(e) => Err(KvStoreError::Busy(e)),
+            Response::ReadOnly => Err(KvStoreError::ReadOnly),
+            Response::Err(e) => Err(KvStoreError::Remote(e)),
+            Response::Redirect(addr) => {
+                debug!(%addr, "Redirected");
+                self.options.connect(addr)?.call(request)
+            }
+            response => Ok(response),
+        }
+    }
+
+    /// Runs op, retrying network failures with exponential backoff
+    fn with_retries<T>(&self, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut attempt = 0;
+        loop {
+            match op() {
+                Err(e @ (KvStoreError::FailedConnect(_) | KvStoreError::FailedRequest(_)))
+                    if attempt < self.options.retries =>
+                {
+                    let delay = self.options.backoff.saturating_mul(1 << attempt.min(16));
+                    warn!(attempt, ?delay, "Retrying request: {e}");
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Takes an idle connection from the pool, or opens a new one
+    fn checkout(&self) -> Result<Connection> {
+        let idle = self
+            .idle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop();
+        idle.map_or_else(|| self.open(), Ok)
+    }
+
+    /// Returns a healthy connection to the pool, unless it is full
+    fn checkin(&self, connection: Connection) {
+        let mut idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner);
+        if idle.len() < self.options.pool_size {
+            idle.push(connection);
+        }
+    }
+
+    fn open(&self) -> Result<Connection> {
+        let stream = Stream::connect(&self.addr, self.options.timeout)
+            .map_err(KvStoreError::FailedConnect)?;
+        debug!(peer = ?stream.peer(), "Opened connection");
+
+        let mut connection =
+            Connection::new(stream, &self.options).map_err(KvStoreError::FailedConnect)?;
+        if let Some(credentials) = &self.options.credentials {
+            let auth = Request::Auth(credentials.clone());
+            let response = connection
+                .roundtrip(slice::from_ref(&auth), None)
+                .map_err(KvStoreError::FailedConnect)?
+                .pop();
+            match response {
+                Some(Response::Ok(_)) => {}
+                Some(Response::PermissionDenied(e)) => {
+                    return Err(KvStoreError::PermissionDenied(e));
+                }
+                Some(Response::Busy(e)) => return Err(KvStoreError::Busy(e)),
+                Some(response) => return Err(unexpected(&response)),
+                None => {
+                    return Err(KvStoreError::FailedConnect(
+                        io::ErrorKind::UnexpectedEof.into(),
+                    ))
+                }
+            }
+        }
+        Ok(connection)
+    }
+}
+
+/// Stream of the WAL records of a server, from [`KvsClient::tail`]
+///
+/// Yields each shipment of records as received, skipping heartbeats, until the server closes
+/// the connection or a read fails.
+pub struct Tail {
+    connection: Connection,
+    ended: bool,
+}
+
+impl fmt::Debug for Tail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tail")
+            .field("ended", &self.ended)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Iterator for Tail {
+    type Item = Result<Shipment>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.ended {
+            match read_frame(&mut self.connection.reader) {
+                Ok(Some(Tailing::Shipment(shipment))) => return Some(Ok(shipment)),
+                Ok(Some(Tailing::Heartbeat)) => {}
+                Ok(None) => self.ended = true,
+                Err(e) => {
+                    self.ended = true;
+                    return Some(Err(KvStoreError::FailedRequest(e)));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Client spreading keys across several servers by consistent hashing
+///
+/// Each server takes [virtual nodes](ClientOptions::virtual_nodes) on a hash ring and owns the
+/// keys hashing up to each of them, so adding or removing a server only moves the keys of its
+/// neighbours on the ring (see [`ShardedClient::reshard_from`]). A server failing only fails
+/// the requests for its keys.
+///
+/// Cheap to clone: clones share connections.
+#[derive(Clone)]
+pub struct ShardedClient {
+    ring: BTreeMap<u64, SocketAddr>,
+    shards: HashMap<SocketAddr, Arc<Shard>>,
+    options: ClientOptions,
+}
+
+impl fmt::Debug for ShardedClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShardedClient")
+            .field("shards", &self.shards())
+            .field("options", &self.options)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ShardedClient {
+    /// Returns a client spreading keys across the servers at addresses with default
+    /// [`ClientOptions`]
+    #[must_use]
+    pub fn new(addrs: &[SocketAddr]) -> Self {
+        ClientOptions::new().sharded(addrs)
+    }
+
+    /// Returns the addresses of the servers, sorted
+    #[must_use]
+    pub fn shards(&self) -> Vec<SocketAddr> {
+        let mut shards: Vec<_> = self.shards.keys().copied().collect();
+        shards.sort_unstable();
+        shards
+    }
+
+    /// Returns the address of the server owning key, or `None` without servers
+    #[must_use]
+    pub fn shard_for(&self, key: &str) -> Option<SocketAddr> {
+        let hash = ring_hash(key.as_bytes());
+        self.ring
+            .range(hash..)
+            .chain(&self.ring)
+            .next()
+            .map(|(_, &addr)| addr)
+    }
+
+    /// Adds the server at address, which takes over part of the keys of the others
+    pub fn add_shard(&mut self, addr: SocketAddr) {
+        if self.shards.contains_key(&addr) {
+            return;
+        }
+        for vnode in 0..self.options.virtual_nodes {
+            self.ring
+                .insert(ring_hash(format!("{addr}#{vnode}").as_bytes()), addr);
+        }
+        self.shards.insert(
+            addr,
+            Arc::new(Shard {
+                addr,
+                client: Mutex::new(None),
+            }),
+        );
+    }
+
+    /// Removes the server at address, whose keys go to the others
+    pub fn remove_shard(&mut self, addr: SocketAddr) {
+        self.ring.retain(|_, &mut owner| owner != addr);
+        self.shards.remove(&addr);
+    }
+
+    /// Returns value for given key if present
+    ///
+    /// # Errors
+    /// Returns `Err` if there are no servers or the request to the owner of key fails
+    pub fn get(&self, key: impl Into<String>) -> Result<Option<String>> {
+        let key = key.into();
+        self.client_for(&key)?.get(key)
+    }
+
+    /// Inserts key-value pair, returning the previous value of key if present
+    ///
+    /// # Errors
+    /// Returns `Err` if there are no servers or the request to the owner of key fails
+    pub fn set(&self, key: impl Into<String>, value: impl Into<String>) -> Result<Option<String>> {
+        let key = key.into();
+        self.client_for(&key)?.set(key, value)
+    }
+
+    /// Removes key-value pair for given key, returning the removed value
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::FailedRm`] if the key was not found, or `Err` if there are no
+    /// servers or the request to the owner of key fails
+    pub fn remove(&self, key: impl Into<String>) -> Result<Option<String>> {
+        let key = key.into();
+        self.client_for(&key)?.remove(key)
+    }
+
+    /// Returns key-value pairs whose keys start with prefix across all servers, sorted by key
+    ///
+    /// # Errors
+    /// Returns `Err` if the request to any server fails
+    pub fn scan(&self, prefix: impl Into<String>) -> Result<Vec<(String, String)>> {
+        let prefix = prefix.into();
+        let mut entries = Vec::new();
+        for shard in self.shards.values() {
+            entries.extend(shard.client(&self.options)?.scan(prefix.clone())?);
+        }
+        entries.sort_unstable();
+        Ok(entries)
+    }
+
+    /// Moves the keys whose owner differs from that in a previous version of this client,
+    /// returning how many were moved
+    ///
+    /// Clone the client before adding or removing servers, then call this on the updated one
+    /// with the clone; removed servers must still be reachable. Only the default key space is
+    /// moved, and writes in the meantime may be lost, so pause them while resharding.
+    ///
+    /// # Errors
+    /// Returns `Err` if a request fails, leaving the keys not moved yet on their previous owner
+    pub fn reshard_from(&self, previous: &Self) -> Result<usize> {
+        let mut moved = 0;
+        for (&addr, shard) in &previous.shards {
+            let source = shard.client(&previous.options)?;
+            for (key, value) in source.scan("")? {
+                if self.shard_for(&key) == Some(addr) {
+                    continue;
+                }
+                self.client_for(&key)?.set(key.clone(), value)?;
+                source.remove(key)?;
+                moved += 1;
+            }
+        }
+
+        debug!(moved, "Resharded keys");
+        Ok(moved)
+    }
+
+    /// Returns the client of the server owning key
+    fn client_for(&self, key: &str) -> Result<Arc<KvsClient>> {
+        let shard = self
+            .shard_for(key)
+            .and_then(|addr| self.shards.get(&addr))
+            .ok_or_else(|| KvStoreError::FailedConnect(io::ErrorKind::AddrNotAvailable.into()))?;
+        shard.client(&self.options)
+    }
+}
+
+/// Server of a [`ShardedClient`], connected on first use
+struct Shard {
+    addr: SocketAddr,
+    client: Mutex<Option<Arc<KvsClient>>>,
+}
+
+impl Shard {
+    /// Returns the client of the server, connecting unless already connected
+    fn client(&self, options: &ClientOptions) -> Result<Arc<KvsClient>> {
+        let mut client = self.client.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(client) = &*client {
+            return Ok(Arc::clone(client));
+        }
+        let connected = Arc::new(options.connect(self.addr)?);
+        *client = Some(Arc::clone(&connected));
+        Ok(connected)
+    }
+}
+
+/// Hashes bytes onto the ring of a [`ShardedClient`]
+///
+/// FNV-1a followed by a finalizer spreading similar inputs apart; unlike the hashers of the
+/// standard library, it is stable across processes and versions, so every client maps keys
+/// alike.
+fn ring_hash(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Builds the error for a response not matching its request
+fn unexpected(response: &Response) -> KvStoreError {
+    KvStoreError::Remote(format!("Unexpected response: {response:?}"))
+}
+
+/// Buffered connection to a server
+struct Connection {
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: BufWriter<Box<dyn Write + Send>>,
+    /// Socket under the halves, to bound waits by a deadline
+    stream: Stream,
+    /// Read and write timeout of the socket outside deadlines
+    timeout: Option<Duration>,
+    /// Whether the server refused the connection, closing it
+    refused: bool,
+}
+
+impl Connection {
+    fn new(stream: Stream, options: &ClientOptions) -> io::Result<Self> {
+        stream.configure(options.timeout)?;
+        let socket = stream.try_clone()?;
+
+        #[cfg(feature = "tls")]
+        if let Some(config) = &options.tls {
+            let server_name = options.tls_server_name.as_deref();
+            let (reader, writer) = crate::tls::connect(config, server_name, stream)?;
+            return Ok(Self {
+                reader: BufReader::new(Box::new(reader)),
+                writer: BufWriter::new(Box::new(writer)),
+                stream: socket,
+                timeout: options.timeout,
+                refused: false,
+            });
+        }
+
+        Ok(Self {
+            reader: BufReader::new(Box::new(stream.try_clone()?)),
+            writer: BufWriter::new(Box::new(stream)),
+            stream: socket,
+            timeout: options.timeout,
+            refused: false,
+        })
+    }
+
+    /// Writes all requests, numbered by position, then reads one response per request and
+    /// puts them back in the order of the requests, failing once deadline passes if any
+    ///
+    /// If the server refused the connection, every request gets its refusal.
+    fn roundtrip(
+        &mut self,
+        requests: &[Request],
+        deadline: Option<Instant>,
+    ) -> io::Result<Vec<Response>> {
+        self.arm(deadline)?;
+        let responses = self.exchange(requests, deadline)?;
+        if deadline.is_some() {
+            self.stream.configure(self.timeout)?;
+        }
+        Ok(responses)
+    }
+
+    /// Bounds the socket timeouts by the time left until deadline, failing if it has passed
+    fn arm(&self, deadline: Option<Instant>) -> io::Result<()> {
+        let Some(deadline) = deadline else {
+            return Ok(());
+        };
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream
+            .configure(Some(self.timeout.map_or(left, |timeout| timeout.min(left))))
+    }
+
+    /// Writes the requests and reads their responses for [`Connection::roundtrip`]
+    fn exchange(
+        &mut self,
+        requests: &[Request],
+        deadline: Option<Instant>,
+    ) -> io::Result<Vec<Response>> {
+        for (id, request) in (0..).zip(requests) {
+            // The server may have refused the connection and closed it already
+            if let Err(e) = write_frame(&mut self.writer, &Envelope { id, body: request }) {
+                return self.refusal(requests.len()).ok_or(e);
+            }
+        }
+        if let Err(e) = self.writer.flush() {
+            return self.refusal(requests.len()).ok_or(e);
+        }
+
+        let mut responses = vec![None; requests.len()];
+        for _ in requests {
+            self.arm(deadline)?;
+            let Envelope { id, body } = read_frame(&mut self.reader)?
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+            if id == REFUSED {
+                self.refused = true;
+                return Ok(vec![body; requests.len()]);
+            }
+            let slot = usize::try_from(id)
+                .ok()
+                .and_then(|id| responses.get_mut(id))
+                .filter(|slot| slot.is_none())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unexpected ID"))?;
+            *slot = Some(body);
+        }
+        Ok(responses.into_iter().flatten().collect())
+    }
+
+    /// Reads the refusal of a server that closed the connection, as the response to each of
+    /// count requests
+    fn refusal(&mut self, count: usize) -> Option<Vec<Response>> {
+        match read_frame(&mut self.reader) {
+            Ok(Some(Envelope { id: REFUSED, body })) => {
+                self.refused = true;
+                Some(vec![body; count])
+            }
+            _ => None,
+        }
+    }
+}
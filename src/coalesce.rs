@@ -0,0 +1,84 @@
+//! Write coalescing of rapid overwrites into single WAL records
+
+use crate::wal::Record;
+use std::{
+    collections::BTreeMap,
+    mem,
+    time::{Duration, SystemTime},
+};
+
+/// Latest unlogged write per key, held back for up to a window
+#[derive(Debug, Default)]
+pub(crate) struct Coalescer {
+    opened: Option<SystemTime>,
+    pending: BTreeMap<String, Pending>,
+}
+
+/// Latest unlogged write of a key
+#[derive(Debug)]
+struct Pending {
+    /// New value and version, or `None` for a removal
+    value: Option<(String, u64)>,
+    /// Whether the key had a logged value before the window opened
+    logged: bool,
+}
+
+impl Coalescer {
+    /// Records a write, replacing any pending write of the same key
+    ///
+    /// `existed` tells whether the key had a value before this write.
+    pub(crate) fn push(
+        &mut self,
+        key: String,
+        value: Option<(String, u64)>,
+        existed: bool,
+        now: SystemTime,
+    ) {
+        self.opened.get_or_insert(now);
+        self.pending
+            .entry(key)
+            .and_modify(|p| p.value.clone_from(&value))
+            .or_insert(Pending {
+                value,
+                logged: existed,
+            });
+    }
+
+    /// Returns whether a window is open, i.e. writes are pending
+    pub(crate) fn is_open(&self) -> bool {
+        self.opened.is_some()
+    }
+
+    /// Returns whether the window opened by the oldest pending write has elapsed
+    pub(crate) fn expired(&self, window: Duration, now: SystemTime) -> bool {
+        self.opened
+            .is_some_and(|opened| now.duration_since(opened).unwrap_or_default() >= window)
+    }
+
+    /// Returns the time left until the window opened by the oldest pending write elapses, or
+    /// `None` if no write is pending
+    pub(crate) fn remaining(&self, window: Duration, now: SystemTime) -> Option<Duration> {
+        self.opened
+            .map(|opened| window.saturating_sub(now.duration_since(opened).unwrap_or_default()))
+    }
+
+    /// Takes the records of all pending writes that need logging, closing the window
+    ///
+    /// Removals of keys created within the window cancel out and are dropped. Sets skipping
+    /// versions, or recreating a logged key removed within the window, record their version.
+    pub(crate) fn take(&mut self) -> impl Iterator<Item = Record> {
+        self.opened = None;
+        mem::take(&mut self.pending)
+            .into_iter()
+            .filter(|(_, p)| p.logged || p.value.is_some())
+            .map(|(key, p)| match p.value {
+                Some((value, version)) => Record::Set {
+                    key,
+                    value,
+                    version: (p.logged || version > 1).then_some(version),
+                    expires: None,
+                },
+                None => Record::Rm { key },
+            })
+    }
+}
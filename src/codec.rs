@@ -0,0 +1,387 @@
+//! Encodings of WAL records, selected when creating a store and recorded in its manifest
+//!
+//! Whatever the encoding, a record is a frame ended by a newline, holding the [`Stamp`] of the
+//! record and its fields, the first of which is its tag (see
+//! [`Record::fields`](crate::wal::Record::fields)). No frame holds a newline or carriage return,
+//! binary frames being escaped, which keeps log files splittable into records without decoding
+//! them, even after their line endings were converted to those of Windows.
+//!
+//! Records may be written checked, the frame of their encoding wrapped in a marker, its length
+//! and its CRC, see [`encode_checked`], so that torn or stale records are told from valid ones.
+//! Checked and unchecked frames are told apart by the marker, so a log may hold both.
+//!
+//! Records shipped to replicas and cluster members always use the text encoding.
+
+use clap::ValueEnum;
+use serde::{de, Deserialize, Serialize};
+use std::{borrow::Cow, fmt};
+use strum::{Display, EnumString};
+
+/// Byte escaping newlines, carriage returns and itself in binary frames
+const ESCAPE: u8 = 0xdb;
+/// Escaped newline, after [`ESCAPE`]
+const ESCAPED_NEWLINE: u8 = 0xdc;
+/// Escaped [`ESCAPE`], after [`ESCAPE`]
+const ESCAPED_ESCAPE: u8 = 0xdd;
+/// Escaped carriage return, after [`ESCAPE`]
+const ESCAPED_RETURN: u8 = 0xde;
+/// Start of checked frames: [`ESCAPE`] followed by a byte it never escapes in binary frames,
+/// which text frames never start with either
+const CHECKED: [u8; 2] = [ESCAPE, 0xdf];
+
+/// Encoding of the records of a WAL
+#[derive(Clone, Copy, Debug, Default, Display, EnumString, PartialEq, Eq, ValueEnum)]
+#[strum(serialize_all = "kebab-case")]
+pub enum WalFormat {
+    /// Space-separated fields, quoted as JSON strings where needed
+    #[default]
+    Text,
+    /// JSON object per record, human-readable and easy to process with other tools
+    Json,
+    /// bincode, compact and fast to encode
+    Bincode,
+    /// `MessagePack`, compact and self-describing
+    MessagePack,
+}
+
+impl WalFormat {
+    /// Returns the codec of the format
+    pub(crate) fn codec(self) -> &'static dyn RecordCodec {
+        match self {
+            Self::Text => &Text,
+            Self::Json => &Json,
+            Self::Bincode => &Bincode,
+            Self::MessagePack => &MessagePack,
+        }
+    }
+}
+
+/// Metadata written along with the fields of a record
+///
+/// Records written before records were numbered, and `compacted` markers, have no sequence
+/// number; records written before they were timestamped have no timestamp.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Stamp {
+    /// Sequence number of the record
+    pub(crate) sequence: Option<u64>,
+    /// When the record was first committed, in milliseconds since the Unix epoch
+    pub(crate) timestamp: Option<u64>,
+    /// Client that made the write, if known
+    pub(crate) client: Option<String>,
+}
+
+/// Encoder and decoder of record frames
+pub(crate) trait RecordCodec: Send + Sync {
+    /// Appends the frame of a record to buf, without the newline ending it
+    fn encode(&self, stamp: &Stamp, fields: &[Cow<'_, str>], buf: &mut Vec<u8>);
+
+    /// Decodes a frame, without the newline ending it, into the stamp and fields of its record
+    ///
+    /// # Errors
+    /// Returns `Err` if the frame is malformed
+    fn decode(&self, frame: &[u8]) -> serde_json::Result<(Stamp, Vec<String>)>;
+
+    /// Decodes a frame as [`RecordCodec::decode`] does, checking and unwrapping it first if
+    /// written checked
+    ///
+    /// # Errors
+    /// Returns `Err` if the frame fails its check or is malformed
+    fn decode_checked(&self, frame: &[u8]) -> serde_json::Result<(Stamp, Vec<String>)> {
+        self.decode(unchecked(frame)?)
+    }
+}
+
+/// Appends the frame of a record to buf as [`RecordCodec::encode`] does, checked: the marker
+/// of checked frames, the length of the frame in 8 hex digits, the frame, and its CRC-32 in 8
+/// hex digits
+pub(crate) fn encode_checked(
+    codec: &dyn RecordCodec,
+    stamp: &Stamp,
+    fields: &[Cow<'_, str>],
+    buf: &mut Vec<u8>,
+) {
+    buf.extend_from_slice(&CHECKED);
+    let len_at = buf.len();
+    buf.extend_from_slice(&[b'0'; 8]);
+    let start = buf.len();
+    codec.encode(stamp, fields, buf);
+    let len = format!("{:08x}", buf.len() - start);
+    let checksum = format!("{:08x}", crc32fast::hash(&buf[start..]));
+    buf[len_at..start].copy_from_slice(len.as_bytes());
+    buf.extend_from_slice(checksum.as_bytes());
+}
+
+/// Returns the frame wrapped in a checked frame, verified, or an unchecked frame as is
+///
+/// # Errors
+/// Returns `Err` if a checked frame is truncated, malformed, or fails its checksum
+pub(crate) fn unchecked(frame: &[u8]) -> serde_json::Result<&[u8]> {
+    let Some(rest) = frame.strip_prefix(&CHECKED) else {
+        return Ok(frame);
+    };
+    let hex = |digits: &[u8]| {
+        std::str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| de::Error::custom("malformed checked frame"))
+    };
+    let len = hex(rest
+        .get(..8)
+        .ok_or_else(|| de::Error::custom("truncated frame"))?)? as usize;
+    let rest = &rest[8..];
+    if rest.len() != len + 8 {
+        return Err(de::Error::custom(format!(
+            "expected {len} bytes of frame, found {}",
+            rest.len().saturating_sub(8)
+        )));
+    }
+    let (inner, checksum) = rest.split_at(len);
+    if crc32fast::hash(inner) != hex(checksum)? {
+        return Err(de::Error::custom("checksum mismatch"));
+    }
+    Ok(inner)
+}
+
+/// Sequence number, timestamp and client prefixed with `@`, followed by space-separated
+/// fields, see [`fields`]
+struct Text;
+
+impl RecordCodec for Text {
+    fn encode(&self, stamp: &Stamp, fields: &[Cow<'_, str>], buf: &mut Vec<u8>) {
+        let mut tokens = Vec::new();
+        tokens.extend(stamp.sequence.map(|sequence| sequence.to_string()));
+        tokens.extend(stamp.timestamp.map(|timestamp| timestamp.to_string()));
+        tokens
+            .extend((stamp.client.as_ref()).map(|client| Field(&format!("@{client}")).to_string()));
+        tokens.extend(fields.iter().map(|field| Field(field).to_string()));
+        buf.extend_from_slice(tokens.join(" ").as_bytes());
+    }
+
+    fn decode(&self, frame: &[u8]) -> serde_json::Result<(Stamp, Vec<String>)> {
+        let line = std::str::from_utf8(frame).map_err(de::Error::custom)?;
+        let (sequence, line) = split_number(line);
+        let (timestamp, line) = match sequence {
+            Some(_) => split_number(line),
+            None => (None, line),
+        };
+        let mut fields = fields(line)?;
+        // Tags never start with `@`
+        let client = match fields.first().and_then(|f| f.strip_prefix('@')) {
+            Some(client) => {
+                let client = client.to_owned();
+                fields.remove(0);
+                Some(client)
+            }
+            None => None,
+        };
+
+        let stamp = Stamp {
+            sequence,
+            timestamp,
+            client,
+        };
+        Ok((stamp, fields))
+    }
+}
+
+/// Stamp and fields of a record, as serialized by structured codecs
+///
+/// Fields added since structured codecs were introduced come last, so that bincode frames
+/// without them can be told apart.
+#[derive(Serialize, Deserialize)]
+struct Frame<F> {
+    sequence: Option<u64>,
+    fields: Vec<F>,
+    #[serde(default)]
+    timestamp: Option<u64>,
+    #[serde(default)]
+    client: Option<F>,
+}
+
+/// [`Frame`] of records written before they were timestamped
+#[derive(Deserialize)]
+struct UntimedFrame {
+    sequence: Option<u64>,
+    fields: Vec<String>,
+}
+
+impl<'a> Frame<Cow<'a, str>> {
+    fn new(stamp: &'a Stamp, fields: &'a [Cow<'a, str>]) -> Self {
+        Self {
+            sequence: stamp.sequence,
+            fields: fields.iter().map(|f| Cow::Borrowed(f.as_ref())).collect(),
+            timestamp: stamp.timestamp,
+            client: stamp.client.as_deref().map(Cow::Borrowed),
+        }
+    }
+}
+
+impl Frame<String> {
+    fn into_parts(self) -> (Stamp, Vec<String>) {
+        let stamp = Stamp {
+            sequence: self.sequence,
+            timestamp: self.timestamp,
+            client: self.client,
+        };
+        (stamp, self.fields)
+    }
+}
+
+impl From<UntimedFrame> for Frame<String> {
+    fn from(frame: UntimedFrame) -> Self {
+        Self {
+            sequence: frame.sequence,
+            fields: frame.fields,
+            timestamp: None,
+            client: None,
+        }
+    }
+}
+
+/// JSON object with the members of [`Frame`]
+struct Json;
+
+impl RecordCodec for Json {
+    fn encode(&self, stamp: &Stamp, fields: &[Cow<'_, str>], buf: &mut Vec<u8>) {
+        // Serializing strings to a buffer cannot fail, and escapes newlines
+        let _ = serde_json::to_writer(buf, &Frame::new(stamp, fields));
+    }
+
+    fn decode(&self, frame: &[u8]) -> serde_json::Result<(Stamp, Vec<String>)> {
+        serde_json::from_slice(frame).map(Frame::into_parts)
+    }
+}
+
+/// bincode encoding of the frame, escaped
+struct Bincode;
+
+impl RecordCodec for Bincode {
+    fn encode(&self, stamp: &Stamp, fields: &[Cow<'_, str>], buf: &mut Vec<u8>) {
+        // Serializing strings cannot fail
+        if let Ok(bytes) = bincode::serialize(&Frame::new(stamp, fields)) {
+            escape(&bytes, buf);
+        }
+    }
+
+    fn decode(&self, frame: &[u8]) -> serde_json::Result<(Stamp, Vec<String>)> {
+        // bincode frames hold no field names, so ones without timestamp end early
+        let bytes = unescape(frame)?;
+        bincode::deserialize::<Frame<String>>(&bytes)
+            .or_else(|_| bincode::deserialize::<UntimedFrame>(&bytes).map(Frame::from))
+            .map(Frame::into_parts)
+            .map_err(de::Error::custom)
+    }
+}
+
+/// `MessagePack` encoding of the frame, escaped
+struct MessagePack;
+
+impl RecordCodec for MessagePack {
+    fn encode(&self, stamp: &Stamp, fields: &[Cow<'_, str>], buf: &mut Vec<u8>) {
+        // Serializing strings cannot fail
+        if let Ok(bytes) = rmp_serde::to_vec(&Frame::new(stamp, fields)) {
+            escape(&bytes, buf);
+        }
+    }
+
+    fn decode(&self, frame: &[u8]) -> serde_json::Result<(Stamp, Vec<String>)> {
+        rmp_serde::from_slice(&unescape(frame)?)
+            .map(Frame::into_parts)
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Appends bytes to buf, escaping newlines, carriage returns and escape bytes
+fn escape(bytes: &[u8], buf: &mut Vec<u8>) {
+    for &byte in bytes {
+        match byte {
+            b'\n' => buf.extend_from_slice(&[ESCAPE, ESCAPED_NEWLINE]),
+            b'\r' => buf.extend_from_slice(&[ESCAPE, ESCAPED_RETURN]),
+            ESCAPE => buf.extend_from_slice(&[ESCAPE, ESCAPED_ESCAPE]),
+            byte => buf.push(byte),
+        }
+    }
+}
+
+/// Reverses [`escape`]
+fn unescape(frame: &[u8]) -> serde_json::Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(frame.len());
+    let mut iter = frame.iter();
+    while let Some(&byte) = iter.next() {
+        bytes.push(match byte {
+            ESCAPE => match iter.next() {
+                Some(&ESCAPED_NEWLINE) => b'\n',
+                Some(&ESCAPED_ESCAPE) => ESCAPE,
+                Some(&ESCAPED_RETURN) => b'\r',
+                _ => return Err(de::Error::custom("invalid escape sequence")),
+            },
+            byte => byte,
+        });
+    }
+    Ok(bytes)
+}
+
+/// String field of a text record, written as is unless that would be ambiguous
+///
+/// Empty fields, and those containing spaces or control characters or starting with a quote,
+/// are written as JSON strings instead.
+struct Field<'a>(&'a str);
+
+impl fmt::Display for Field<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let quote = self.0.is_empty()
+            || self.0.starts_with('"')
+            || self.0.contains(|c: char| c == ' ' || c.is_control());
+        if quote {
+            f.write_str(&serde_json::to_string(self.0).map_err(|_| fmt::Error)?)
+        } else {
+            f.write_str(self.0)
+        }
+    }
+}
+
+/// Splits a text record, after its stamp, into its fields, unquoting JSON string
+/// fields
+///
+/// # Errors
+/// Returns `Err` if a quoted field is malformed
+fn fields(line: &str) -> serde_json::Result<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut rest = line;
+    loop {
+        if rest.starts_with('"') {
+            let mut stream = serde_json::Deserializer::from_str(rest).into_iter::<String>();
+            let field = stream
+                .next()
+                .transpose()?
+                .ok_or_else(|| de::Error::custom("missing quoted field"))?;
+            rest = &rest[stream.byte_offset()..];
+            if !(rest.is_empty() || rest.starts_with(' ')) {
+                return Err(de::Error::custom("trailing characters after quoted field"));
+            }
+            fields.push(field);
+        } else {
+            let end = rest.find(' ').unwrap_or(rest.len());
+            fields.push(rest[..end].to_owned());
+            rest = &rest[end..];
+        }
+
+        match rest.strip_prefix(' ') {
+            Some(next) => rest = next,
+            None => return Ok(fields),
+        }
+    }
+}
+
+/// Splits a leading number off a text record, if any
+///
+/// The fields of a record always start with a non-numeric tag, or the `@`-prefixed client.
+fn split_number(line: &str) -> (Option<u64>, &str) {
+    match line.split_once(' ') {
+        Some((sequence, rest)) => match sequence.parse() {
+            Ok(sequence) => (Some(sequence), rest),
+            Err(_) => (None, line),
+        },
+        None => (None, line),
+    }
+}
@@ -0,0 +1,231 @@
+//! Policies choosing when WAL segments are compacted, and pacing and control of compactions
+//! running in the background
+//!
+//! A compaction rewrites the live records of the oldest segments, up to the one a
+//! [`CompactionPolicy`] chooses, as a base segment superseding them. The writer hands it to a
+//! thread of its own, or to the thread pool of its runtime, so that it goes on committing while
+//! live records are copied. Copying is throttled to a number of bytes per second if set, and to
+//! that of the runtime shared by all its stores, and pauses while writes queue up at the
+//! writer, for at most [`MAX_PAUSE`] at a time so that compaction always makes progress under
+//! sustained load.
+
+use crate::{rate_limit::RateLimiter, CompactionProgress};
+use std::{
+    fmt, mem,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Size and age of a WAL segment, as given to a [`CompactionPolicy`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SegmentInfo {
+    /// ID of the segment, increasing in log order
+    pub id: u64,
+    /// Bytes of records still needed to rebuild the current state
+    pub live_bytes: u64,
+    /// Bytes of records superseded by later ones
+    pub dead_bytes: u64,
+    /// When the segment was started, or written for a base segment; no earlier than when the
+    /// store was opened, since opening rewrites the log
+    pub created: SystemTime,
+    /// Whether the segment is a base segment written by an earlier compaction
+    pub base: bool,
+}
+
+/// Decides when the WAL is compacted, and which of its segments, set with
+/// [`OpenOptions::compaction_policy`](crate::OpenOptions::compaction_policy)
+///
+/// The writer asks the policy after each commit, unless a compaction is running, automatic
+/// compactions are stopped, or the log is below
+/// [`OpenOptions::compaction_threshold`](crate::OpenOptions::compaction_threshold).
+/// Compactions requested with [`KvStore::compact`](crate::KvStore::compact) or
+/// [`KvStore::snapshot`](crate::KvStore::snapshot) cover every segment regardless.
+pub trait CompactionPolicy: fmt::Debug + Send + Sync {
+    /// Returns how many of the oldest segments to compact, given every segment in log order,
+    /// the active one last, and the current time; 0 leaves the log as it is
+    ///
+    /// Since a base segment supersedes every segment before it, only the oldest segments can
+    /// be compacted together. A selection covering the active segment seals it first, and one
+    /// ending with a base segment takes the segment after it along.
+    fn select(&self, segments: &[SegmentInfo], now: SystemTime) -> usize;
+}
+
+/// Default policy compacting the longest run of oldest segments more than half of whose bytes
+/// are dead
+///
+/// Segments are written in tiers: every compaction folds the runs worth it into a base segment,
+/// leaving the newer segments, mostly live, be until they pile up dead records in turn.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SizeTiered;
+
+impl CompactionPolicy for SizeTiered {
+    fn select(&self, segments: &[SegmentInfo], _now: SystemTime) -> usize {
+        let (mut live, mut dead) = (0, 0);
+        let mut selected = 0;
+        for (n, segment) in segments.iter().enumerate() {
+            live += segment.live_bytes;
+            dead += segment.dead_bytes;
+            if dead > live {
+                selected = n + 1;
+            }
+        }
+        selected
+    }
+}
+
+/// Policy compacting the oldest segments once older than a maximum age, if they hold dead
+/// records, for workloads whose records mostly expire or get removed after a while
+#[derive(Clone, Copy, Debug)]
+pub struct TimeBased {
+    /// Age from which segments are compacted
+    pub max_age: Duration,
+}
+
+impl CompactionPolicy for TimeBased {
+    fn select(&self, segments: &[SegmentInfo], now: SystemTime) -> usize {
+        let old = segments
+            .iter()
+            .take_while(|s| {
+                now.duration_since(s.created)
+                    .is_ok_and(|age| age >= self.max_age)
+            })
+            .count();
+        if segments[..old].iter().any(|s| s.dead_bytes > 0) {
+            old
+        } else {
+            0
+        }
+    }
+}
+
+/// Longest a compaction pauses at a time for foreground writes
+const MAX_PAUSE: Duration = Duration::from_secs(1);
+
+/// Interval at which a paused compaction checks the load again
+const PAUSE_STEP: Duration = Duration::from_millis(5);
+
+/// Jobs per writer batch from which the writer counts as under heavy load
+const HEAVY_LOAD: usize = 2;
+
+/// Settings and state of background compaction, shared by the store, the writer and the
+/// compaction thread
+#[derive(Debug, Default)]
+pub(crate) struct Control {
+    /// Bytes per second copying is throttled to, if limited
+    throttle: Option<u64>,
+    /// Limiter of the bytes copied by the compactions of every store of a runtime, if any
+    shared_throttle: Option<Arc<RateLimiter>>,
+    /// Whether automatic compactions are stopped
+    stopped: AtomicBool,
+    /// Whether the running compaction is to be abandoned
+    cancelled: AtomicBool,
+    /// Jobs in the latest batch of the writer, 0 once it is idle
+    load: AtomicUsize,
+    /// Progress of the running compaction, if any
+    progress: Mutex<Option<CompactionProgress>>,
+}
+
+impl Control {
+    /// Returns controls throttling copying to the given bytes per second, if any, and to the
+    /// limiter shared with other stores, if any
+    pub(crate) fn new(throttle: Option<u64>, shared_throttle: Option<Arc<RateLimiter>>) -> Self {
+        Self {
+            throttle,
+            shared_throttle,
+            ..Self::default()
+        }
+    }
+
+    /// Stops automatic compactions and abandons the running one, if any
+    pub(crate) fn stop(&self) {
+        self.stopped.store(true, Ordering::Release);
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    /// Lets automatic compactions run again after [`Control::stop`]
+    pub(crate) fn start(&self) {
+        self.stopped.store(false, Ordering::Release);
+    }
+
+    /// Returns whether automatic compactions are stopped
+    pub(crate) fn stopped(&self) -> bool {
+        self.stopped.load(Ordering::Acquire)
+    }
+
+    /// Abandons the running compaction, if any, leaving later ones be
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    /// Returns whether the running compaction is to be abandoned
+    pub(crate) fn cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Records the number of jobs in the latest batch of the writer
+    pub(crate) fn set_load(&self, jobs: usize) {
+        self.load.store(jobs, Ordering::Relaxed);
+    }
+
+    /// Returns the progress of the running compaction, if any
+    pub(crate) fn progress(&self) -> Option<CompactionProgress> {
+        *self.progress.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Marks a compaction copying total bytes as started
+    pub(crate) fn begin(&self, total_bytes: u64) {
+        self.cancelled.store(false, Ordering::Release);
+        *self.progress.lock().unwrap_or_else(PoisonError::into_inner) = Some(CompactionProgress {
+            copied_bytes: 0,
+            total_bytes,
+        });
+    }
+
+    /// Marks the running compaction as over
+    pub(crate) fn end(&self) {
+        *self.progress.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+
+    /// Records copied bytes copied since started, then waits as long as the throttle and the
+    /// load of the writer require
+    pub(crate) fn pace(&self, copied: u64, started: Instant) {
+        let previous = self
+            .progress
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_mut()
+            .map_or(0, |progress| {
+                mem::replace(&mut progress.copied_bytes, copied)
+            });
+        let debt = self
+            .shared_throttle
+            .as_ref()
+            .map_or(Duration::ZERO, |limiter| {
+                limiter.charge(copied.saturating_sub(previous))
+            });
+
+        // Sleeping in steps lets an abandoned compaction stop right away
+        let paused = Instant::now();
+        loop {
+            let throttled = self
+                .throttle
+                .filter(|&per_sec| per_sec > 0)
+                .is_some_and(|per_sec| {
+                    #[allow(clippy::cast_precision_loss)] // Byte counts far below 2^52
+                    let due = Duration::from_secs_f64(copied as f64 / per_sec as f64);
+                    started.elapsed() < due
+                });
+            let throttled = throttled || paused.elapsed() < debt;
+            let loaded =
+                self.load.load(Ordering::Relaxed) >= HEAVY_LOAD && paused.elapsed() < MAX_PAUSE;
+            if !(throttled || loaded) || self.cancelled() {
+                break;
+            }
+            thread::sleep(PAUSE_STEP);
+        }
+    }
+}
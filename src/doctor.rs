@@ -0,0 +1,414 @@
+//! Offline integrity check of a store directory
+//!
+//! Unless written checked, WAL records carry no checksums, so records are checked for being
+//! complete lines that parse and replay, which catches torn writes and most corruption, but not
+//! every flipped bit.
+
+use crate::{
+    codec::WalFormat,
+    lock::DirLock,
+    manifest::{self, Manifest, FORMAT_VERSION},
+    segment::{self, Dirs, SegmentFile},
+    wal, Command, KvStoreError, Result,
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    fs::{self, OpenOptions},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Problem found in a store directory
+#[derive(Clone, Debug, PartialEq)]
+pub enum Issue {
+    /// Record that is truncated or does not parse, at a byte offset of a log file
+    Corrupted {
+        /// Log file
+        path: PathBuf,
+        /// Offset of the record
+        offset: u64,
+        /// Why the record is invalid
+        reason: String,
+    },
+    /// Removal of a key without a value, which replay skips
+    Orphaned {
+        /// Log file
+        path: PathBuf,
+        /// Offset of the record
+        offset: u64,
+        /// Removed key
+        key: String,
+    },
+    /// Several segment files with the same ID
+    DuplicateSegmentId {
+        /// Segment ID
+        id: u64,
+        /// Segment files with that ID
+        paths: Vec<PathBuf>,
+    },
+    /// Segment superseded by a later base segment or not tracked by the manifest, but not
+    /// deleted
+    ObsoleteSegment {
+        /// Segment file
+        path: PathBuf,
+    },
+    /// Segment tracked by the manifest but not found
+    MissingSegment {
+        /// Segment file
+        path: PathBuf,
+    },
+    /// Temporary file of an interrupted compaction
+    Leftover {
+        /// Temporary file
+        path: PathBuf,
+    },
+}
+
+impl Issue {
+    /// Returns whether [`check`] fixes the issue when repairing
+    ///
+    /// Invalid records are fixed by truncating their log file right before them, losing any
+    /// records after them in that file.
+    #[must_use]
+    pub fn repairable(&self) -> bool {
+        !matches!(
+            self,
+            Self::DuplicateSegmentId { .. } | Self::MissingSegment { .. }
+        )
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Corrupted {
+                path,
+                offset,
+                reason,
+            } => write!(
+                f,
+                "{}: corrupted record at byte {offset}: {reason}",
+                path.display()
+            ),
+            Self::Orphaned { path, offset, key } => write!(
+                f,
+                "{}: removal of key without value at byte {offset}: {key}",
+                path.display()
+            ),
+            Self::DuplicateSegmentId { id, paths } => {
+                write!(f, "duplicate segment ID {id}:")?;
+                paths.iter().try_for_each(|p| write!(f, " {}", p.display()))
+            }
+            Self::ObsoleteSegment { path } => {
+                write!(
+                    f,
+                    "{}: segment superseded by a base segment",
+                    path.display()
+                )
+            }
+            Self::MissingSegment { path } => {
+                write!(
+                    f,
+                    "{}: segment tracked by manifest not found",
+                    path.display()
+                )
+            }
+            Self::Leftover { path } => {
+                write!(f, "{}: leftover of interrupted compaction", path.display())
+            }
+        }
+    }
+}
+
+/// Outcome of [`check`]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    /// Log files scanned
+    pub files: usize,
+    /// Valid records found
+    pub records: usize,
+    /// Problems found
+    pub issues: Vec<Issue>,
+    /// Whether repairable issues were fixed
+    pub repaired: bool,
+}
+
+impl Report {
+    /// Returns the number of issues left in place
+    #[must_use]
+    pub fn unresolved(&self) -> usize {
+        self.issues
+            .iter()
+            .filter(|issue| !(self.repaired && issue.repairable()))
+            .count()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for issue in &self.issues {
+            let state = if self.repaired && issue.repairable() {
+                " (repaired)"
+            } else {
+                ""
+            };
+            writeln!(f, "{issue}{state}")?;
+        }
+        write!(
+            f,
+            "Checked {} records in {} files: {} issues, {} unresolved",
+            self.records,
+            self.files,
+            self.issues.len(),
+            self.unresolved()
+        )
+    }
+}
+
+/// Checks the store directory at path, fixing what can be fixed if repair is set
+///
+/// The store must not be open, which the lock of its directory enforces. Log files are scanned in replay order; invalid records are
+/// reported once per file, as records after them are not checked. Segments are checked as
+/// tracked by the manifest, which repairing rewrites to track the segments left in place.
+///
+/// # Errors
+/// Returns `Err` if the store has another format version or is open, or a file cannot be
+/// listed, read, truncated, or removed
+pub fn check(path: impl AsRef<Path>, repair: bool) -> Result<Report> {
+    let dir = path.as_ref();
+    let _lock = DirLock::acquire(dir, Duration::ZERO)?;
+    // Older formats would be misread, e.g. base segments without header as corrupted
+    let fresh = manifest::check(dir)?;
+    let mut tracked = if fresh {
+        Manifest::default()
+    } else {
+        manifest::read(dir)?
+    };
+    let dirs = tracked.dirs(dir);
+    let files = dirs.segment_files().map_err(KvStoreError::FailedCheck)?;
+    let (mut live, missing): (Vec<_>, Vec<_>) = tracked
+        .segment_files(&dirs)
+        .into_iter()
+        .partition(|s| s.path.is_file());
+    let mut report = Report {
+        repaired: repair,
+        ..Report::default()
+    };
+
+    report.issues.extend(duplicate_ids(&files));
+
+    let missing = missing
+        .into_iter()
+        .map(|s| Issue::MissingSegment { path: s.path });
+    report.issues.extend(missing);
+
+    // Like open, set aside invalid base segments and fall back to the segments before them
+    let mut invalid = Vec::new();
+    while let Some(base) = segment::live(&live).first().filter(|s| s.base) {
+        let bytes = fs::read(&base.path).map_err(KvStoreError::FailedCheck)?;
+        let Err(reason) = segment::base_records(&bytes) else {
+            break;
+        };
+        report.files += 1;
+        if repair {
+            segment::set_aside(&base.path).map_err(KvStoreError::FailedCheck)?;
+        }
+        report.issues.push(Issue::Corrupted {
+            path: base.path.clone(),
+            offset: 0,
+            reason,
+        });
+        invalid.push(base.path.clone());
+        let id = base.id;
+        live.retain(|s| !(s.id == id && s.base));
+    }
+
+    let start = live.len() - segment::live(&live).len();
+    live.drain(..start);
+    let obsolete = files
+        .iter()
+        .filter(|f| !invalid.contains(&f.path) && !live.iter().any(|s| s.path == f.path));
+    for obsolete in obsolete {
+        report.issues.push(Issue::ObsoleteSegment {
+            path: obsolete.path.clone(),
+        });
+        if repair {
+            fs::remove_file(&obsolete.path).map_err(KvStoreError::FailedCheck)?;
+        }
+    }
+
+    report.issues.extend(leftover(&dirs, repair)?);
+
+    let active = dirs.active_path();
+    let logs = live
+        .iter()
+        .map(|f| (f.path.clone(), f.base))
+        .chain(active.is_file().then(|| (active.clone(), false)));
+    let format = tracked.format;
+    let mut keys = HashSet::new();
+    for (log, base) in logs {
+        report.files += 1;
+        let bytes = read_log(&log, base, tracked.end.filter(|_| log == active))?;
+        let start = if base {
+            // Verified above
+            bytes.len() - segment::base_records(&bytes).map_or(0, <[u8]>::len)
+        } else {
+            0
+        };
+        let records = &mut report.records;
+        let Some((offset, issue)) = scan(&log, &bytes, start, format, &mut keys, records) else {
+            continue;
+        };
+
+        // Truncating a base segment would break its checksum
+        if repair && base {
+            segment::set_aside(&log).map_err(KvStoreError::FailedCheck)?;
+            invalid.push(log.clone());
+        } else if repair {
+            OpenOptions::new()
+                .write(true)
+                .open(&log)
+                .and_then(|file| file.set_len(offset))
+                .map_err(KvStoreError::FailedCheck)?;
+        }
+        report.issues.push(issue);
+    }
+
+    // Track exactly the segments left in place
+    if repair && !fresh {
+        tracked.segments = live
+            .iter()
+            .filter(|s| !invalid.contains(&s.path))
+            .map(|s| (s.id, s.base))
+            .collect();
+        manifest::write(dir, FORMAT_VERSION, &tracked).map_err(KvStoreError::FailedCheck)?;
+    }
+
+    Ok(report)
+}
+
+/// Reads the log file at path, up to end if recorded for the active segment on close, and
+/// without the zeros of preallocated space unless a base segment
+fn read_log(path: &Path, base: bool, end: Option<u64>) -> Result<Vec<u8>> {
+    let mut bytes = fs::read(path).map_err(KvStoreError::FailedCheck)?;
+    if !base {
+        bytes.truncate(segment::written_len(&bytes));
+    }
+    if let Some(end) = end {
+        bytes.truncate(usize::try_from(end).unwrap_or(usize::MAX));
+    }
+    Ok(bytes)
+}
+
+/// Returns the issue of a compaction output left by a crash, if any, removing it if repair is set
+fn leftover(dirs: &Dirs, repair: bool) -> Result<Option<Issue>> {
+    let compact = dirs.compact_path();
+    if !compact.is_file() {
+        return Ok(None);
+    }
+    if repair {
+        fs::remove_file(&compact).map_err(KvStoreError::FailedCheck)?;
+    }
+    Ok(Some(Issue::Leftover { path: compact }))
+}
+
+/// Returns an issue for each segment ID shared by several files
+fn duplicate_ids(files: &[SegmentFile]) -> Vec<Issue> {
+    let mut by_id = BTreeMap::<u64, Vec<&SegmentFile>>::new();
+    for file in files {
+        by_id.entry(file.id).or_default().push(file);
+    }
+    by_id
+        .into_iter()
+        .filter(|(_, same)| same.len() > 1)
+        .map(|(id, same)| Issue::DuplicateSegmentId {
+            id,
+            paths: same.iter().map(|f| f.path.clone()).collect(),
+        })
+        .collect()
+}
+
+/// Replays the records of a log file in format from offset start against the keys with a
+/// value, counting valid records
+///
+/// Returns the first invalid record, if any.
+fn scan(
+    path: &Path,
+    bytes: &[u8],
+    start: usize,
+    format: WalFormat,
+    keys: &mut HashSet<(Option<String>, String)>,
+    records: &mut usize,
+) -> Option<(u64, Issue)> {
+    let corrupted = |offset: usize, reason: String| {
+        let offset = offset as u64;
+        let path = path.to_owned();
+        Some((
+            offset,
+            Issue::Corrupted {
+                path,
+                offset,
+                reason,
+            },
+        ))
+    };
+
+    let mut offset = start;
+    while offset < bytes.len() {
+        let Some(len) = bytes[offset..].iter().position(|&b| b == b'\n') else {
+            return corrupted(offset, "truncated record".to_owned());
+        };
+        let cmd = match wal::decode(format, &bytes[offset..offset + len]) {
+            Ok((_, cmd)) => cmd,
+            Err(e) => return corrupted(offset, e.to_string()),
+        };
+
+        let removed = match cmd {
+            Command::Set { key, .. }
+            | Command::VersionedSet { key, .. }
+            | Command::ExpiringSet { key, .. } => {
+                keys.insert((None, key));
+                None
+            }
+            Command::BucketSet { bucket, key, .. } => {
+                keys.insert((Some(bucket), key));
+                None
+            }
+            Command::Rm { key, prefix: None } => Some((None, key)),
+            Command::Rm {
+                prefix: Some(prefix),
+                ..
+            } => {
+                keys.retain(|(bucket, key)| bucket.is_some() || !key.starts_with(&prefix));
+                None
+            }
+            // The old key may be compacted away already
+            Command::Renamed { from, to, .. } => {
+                keys.remove(&(None, from));
+                keys.insert((None, to));
+                None
+            }
+            Command::BucketRm { bucket, key } => Some((Some(bucket), key)),
+            _ => None,
+        };
+        if let Some(slot) = removed {
+            if !keys.remove(&slot) {
+                let offset = offset as u64;
+                return Some((
+                    offset,
+                    Issue::Orphaned {
+                        path: path.to_owned(),
+                        offset,
+                        key: slot.1,
+                    },
+                ));
+            }
+        }
+
+        *records += 1;
+        offset += len + 1;
+    }
+
+    None
+}
@@ -0,0 +1,83 @@
+//! Fields of JSON values, at paths written as JSON pointers such as `/a/b` or as `$.a.b`
+
+use crate::{KvStoreError, Result};
+use serde_json::Value;
+
+/// Returns the JSON pointer of path, `None` if it is neither a JSON pointer nor `$` followed by
+/// `.field` for each field; `$` alone is the whole value
+pub(crate) fn pointer(path: &str) -> Option<String> {
+    if path.starts_with('/') {
+        return Some(path.to_owned());
+    }
+    let fields = path.strip_prefix('$')?;
+    if fields.is_empty() {
+        return Some(String::new());
+    }
+    let fields = fields.strip_prefix('.')?;
+    if fields.split('.').any(str::is_empty) {
+        return None;
+    }
+    Some(fields.split('.').fold(String::new(), |pointer, field| {
+        pointer + "/" + &field.replace('~', "~0").replace('/', "~1")
+    }))
+}
+
+/// Returns the field at pointer of value, the value of key, as JSON, or `None` if missing
+///
+/// # Errors
+/// Returns [`KvStoreError::InvalidJson`] if value is not JSON
+pub(crate) fn get(key: &str, value: &str, pointer: &str) -> Result<Option<String>> {
+    Ok(parse(key, value)?.pointer(pointer).map(Value::to_string))
+}
+
+/// Returns value, the value of key if any, with its field at pointer set to field
+///
+/// Objects missing along the pointer are created, as is the whole value if missing. An array
+/// element is set by its index, or appended if the index is its length or `-`.
+///
+/// # Errors
+/// Returns [`KvStoreError::InvalidJson`] if value is not JSON, or the pointer goes through a
+/// value other than an object or array, or past the end of an array
+pub(crate) fn set(key: &str, value: Option<&str>, pointer: &str, field: Value) -> Result<String> {
+    let mut document = value.map_or(Ok(Value::Null), |value| parse(key, value))?;
+    let mut slot = &mut document;
+    // The pointer is empty or starts with `/`, so the first token is empty
+    for token in pointer.split('/').skip(1) {
+        let token = token.replace("~1", "/").replace("~0", "~");
+        if slot.is_null() {
+            *slot = Value::Object(serde_json::Map::new());
+        }
+        slot = match slot {
+            Value::Object(fields) => fields.entry(token).or_insert(Value::Null),
+            Value::Array(elements) => {
+                let len = elements.len();
+                let i = if token == "-" {
+                    len
+                } else {
+                    token.parse().map_err(|_| {
+                        KvStoreError::InvalidJson(format!("{token} of {key} is not an index"))
+                    })?
+                };
+                if i == len {
+                    elements.push(Value::Null);
+                }
+                elements.get_mut(i).ok_or_else(|| {
+                    KvStoreError::InvalidJson(format!("{token} is past the end of {key}"))
+                })?
+            }
+            _ => {
+                return Err(KvStoreError::InvalidJson(format!(
+                    "{token} of {key} is not in an object or array"
+                )))
+            }
+        };
+    }
+    *slot = field;
+    Ok(document.to_string())
+}
+
+/// Parses value, the value of key, as JSON
+fn parse(key: &str, value: &str) -> Result<Value> {
+    serde_json::from_str(value)
+        .map_err(|e| KvStoreError::InvalidJson(format!("value of {key}: {e}")))
+}
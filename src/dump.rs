@@ -0,0 +1,224 @@
+//! Offline listing of the records of a store directory, for debugging and auditing
+
+use crate::{manifest, segment, wal, Command, KvStoreError, Result, WalFormat};
+use clap::Subcommand;
+use serde::Serialize;
+use std::{fmt, fs, path::Path};
+
+/// Commands reading the log files of a store
+#[derive(Clone, Debug, PartialEq, Subcommand)]
+pub enum LogCommand {
+    /// Print every record of the log, in replay order
+    Dump {
+        /// Only print the records of the segment with this ID; the active segment has the ID
+        /// after the last sealed one
+        #[arg(long, value_name = "N")]
+        segment: Option<u64>,
+        /// Only print records numbered from this sequence number on
+        #[arg(long, value_name = "S")]
+        since_seq: Option<u64>,
+        /// Print a JSON object per record instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+// Required by `EnumString` on `Command`
+impl Default for LogCommand {
+    fn default() -> Self {
+        Self::Dump {
+            segment: None,
+            since_seq: None,
+            json: false,
+        }
+    }
+}
+
+/// Record of a log file, as listed by [`dump`]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Entry {
+    /// ID of the segment holding the record
+    pub segment: u64,
+    /// Byte offset of the record in its segment file
+    pub offset: u64,
+    /// Sequence number of the record, if numbered
+    pub sequence: Option<u64>,
+    /// When the record was first committed, in milliseconds since the Unix epoch
+    pub timestamp: Option<u64>,
+    /// Client that made the write, if known
+    pub client: Option<String>,
+    /// Record tag, e.g. `set`, or `invalid` if the record does not parse
+    pub op: String,
+    /// Bucket of the key, if not in the default key space
+    pub bucket: Option<String>,
+    /// Key, ID sequence, timeseries, list or set written
+    pub key: Option<String>,
+    /// Size in bytes of the value written
+    pub value_size: Option<usize>,
+    /// Why the record does not parse
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Entry {
+    /// Returns the header line of the table [`Entry`] formats rows of
+    #[must_use]
+    pub fn header() -> String {
+        format!(
+            "{:>8} {:>10} {:>10} {:>13} {:<16} {:<9} {:>10} KEY",
+            "SEGMENT", "OFFSET", "SEQUENCE", "TIMESTAMP", "CLIENT", "OP", "VALUE_SIZE"
+        )
+    }
+}
+
+/// Formats the entry as a row of the table headed by [`Entry::header`]
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let or_dash = |field: Option<String>| field.unwrap_or_else(|| "-".to_owned());
+        write!(
+            f,
+            "{:>8} {:>10} {:>10} {:>13} {:<16} {:<9} {:>10} ",
+            self.segment,
+            self.offset,
+            or_dash(self.sequence.map(|s| s.to_string())),
+            or_dash(self.timestamp.map(|t| t.to_string())),
+            or_dash(self.client.clone()),
+            self.op,
+            or_dash(self.value_size.map(|s| s.to_string())),
+        )?;
+        match (&self.bucket, &self.key, &self.error) {
+            (_, _, Some(error)) => write!(f, "{error}"),
+            (Some(bucket), Some(key), _) => write!(f, "{bucket}/{key}"),
+            (_, key, _) => write!(f, "{}", or_dash(key.clone())),
+        }
+    }
+}
+
+/// Lists the records of the store directory at path, in replay order
+///
+/// Only the segments replayed on open are read, i.e. the segments tracked by the manifest from
+/// the latest base segment on, then the active segment. Records after one that does not parse
+/// are still listed, as far as they can be told apart.
+///
+/// # Errors
+/// Returns `Err` if the store has another format version, or a log file cannot be read
+pub fn dump(
+    path: impl AsRef<Path>,
+    segment: Option<u64>,
+    since_seq: Option<u64>,
+) -> Result<Vec<Entry>> {
+    let dir = path.as_ref();
+    if manifest::check(dir)? {
+        return Ok(Vec::new());
+    }
+    let mut tracked = manifest::read(dir)?;
+    let dirs = tracked.dirs(dir);
+    let files = tracked.segment_files(&dirs);
+    let logs = segment::live(&files)
+        .iter()
+        .map(|f| (f.id, f.path.clone(), f.base))
+        .chain([(tracked.next_segment, dirs.active_path(), false)])
+        .filter(|(id, path, _)| segment.is_none_or(|s| s == *id) && path.is_file())
+        .collect::<Vec<_>>();
+
+    let mut entries = Vec::new();
+    for (id, path, base) in logs {
+        let mut bytes = fs::read(&path).map_err(KvStoreError::FailedDump)?;
+        if !base {
+            bytes.truncate(segment::written_len(&bytes));
+        }
+        // Bytes past the end recorded on close are not records
+        if let Some(end) = tracked.end.filter(|_| id == tracked.next_segment) {
+            bytes.truncate(usize::try_from(end).unwrap_or(usize::MAX));
+        }
+        // The header of a base segment is a line of its own, verified or not
+        let mut offset = if base {
+            bytes
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(bytes.len(), |n| n + 1)
+        } else {
+            0
+        };
+        while offset < bytes.len() {
+            let len = bytes[offset..]
+                .iter()
+                .position(|&b| b == b'\n')
+                .unwrap_or(bytes.len() - offset);
+            let frame = &bytes[offset..offset + len];
+            let entry = entry(tracked.format, frame, id, offset as u64);
+            let since = since_seq.is_none_or(|since| entry.sequence.is_some_and(|s| s >= since));
+            if since {
+                entries.push(entry);
+            }
+            offset += len + 1;
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Describes the record frame at offset of a segment
+fn entry(format: WalFormat, frame: &[u8], segment: u64, offset: u64) -> Entry {
+    let mut entry = Entry {
+        segment,
+        offset,
+        sequence: None,
+        timestamp: None,
+        client: None,
+        op: "invalid".to_owned(),
+        bucket: None,
+        key: None,
+        value_size: None,
+        error: None,
+    };
+    let (stamp, cmd) = match wal::decode(format, frame) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            entry.error = Some(e.to_string());
+            return entry;
+        }
+    };
+
+    entry.sequence = stamp.sequence;
+    entry.timestamp = stamp.timestamp;
+    entry.client = stamp.client;
+    entry.op = cmd.to_string();
+    match cmd {
+        Command::Set { key, value, .. }
+        | Command::VersionedSet { key, value, .. }
+        | Command::ExpiringSet { key, value, .. }
+        | Command::Append { key, suffix: value }
+        | Command::Lpush { key, value }
+        | Command::Sadd { key, member: value } => {
+            entry.key = Some(key);
+            entry.value_size = Some(value.len());
+        }
+        Command::Renamed { to, value, .. } => {
+            entry.key = Some(to);
+            entry.value_size = Some(value.len());
+        }
+        Command::BucketSet { bucket, key, value } => {
+            entry.bucket = Some(bucket);
+            entry.key = Some(key);
+            entry.value_size = Some(value.len());
+        }
+        Command::Rm {
+            prefix: Some(prefix),
+            ..
+        } => entry.key = Some(prefix),
+        Command::Rm { key, prefix: None }
+        | Command::TsAdd { key, .. }
+        | Command::Rpop { key }
+        | Command::Srem { key, .. }
+        | Command::Leased { key, .. }
+        | Command::Unlock { key, .. } => entry.key = Some(key),
+        Command::BucketRm { bucket, key } => {
+            entry.bucket = Some(bucket);
+            entry.key = Some(key);
+        }
+        Command::ReserveIds { sequence, .. } => entry.key = Some(sequence),
+        _ => {}
+    }
+    entry
+}
@@ -0,0 +1,112 @@
+//! Storage engine interface, and an in-memory engine without the file-backed write-ahead log
+//!
+//! This module uses only `core`, `alloc` and `hashbrown`, not `std`, so that [`MemEngine`]
+//! builds for targets without a file system or threads, e.g. embedded or WASM ones. Code written
+//! against [`KvsEngine`] runs on it as on a [`KvStore`](crate::KvStore).
+
+use alloc::{string::String, vec, vec::Vec};
+use core::convert::Infallible;
+use hashbrown::HashMap;
+
+/// Get, set and remove operations of a KV store, whichever engine keeps the pairs
+pub trait KvsEngine {
+    /// Error of the operations of the engine
+    type Error;
+
+    /// Iterator over the key-value pairs of the engine, see [`KvsEngine::iter`]
+    type Iter: Iterator<Item = (String, String)>;
+
+    /// Returns the value of key, or `None` if missing
+    ///
+    /// # Errors
+    /// Returns `Err` if the engine fails to read the key
+    fn get(&self, key: String) -> Result<Option<String>, Self::Error>;
+
+    /// Sets the value of key, returning its previous value, or `None` if missing
+    ///
+    /// # Errors
+    /// Returns `Err` if the engine fails to write the key
+    fn set(&mut self, key: String, value: String) -> Result<Option<String>, Self::Error>;
+
+    /// Removes key, returning its value, or `None` if missing
+    ///
+    /// # Errors
+    /// Returns `Err` if the engine fails to write the key
+    fn remove(&mut self, key: String) -> Result<Option<String>, Self::Error>;
+
+    /// Returns the number of keys
+    fn len(&self) -> usize;
+
+    /// Returns whether there are no keys
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the key-value pairs in key order, as of a snapshot
+    ///
+    /// # Errors
+    /// Returns `Err` if the engine fails to read the pairs
+    #[allow(clippy::iter_not_returning_iterator)] // Reading the pairs may fail
+    fn iter(&self) -> Result<Self::Iter, Self::Error>;
+}
+
+/// Engine keeping the key-value pairs in a map in memory only, lost once dropped
+#[derive(Clone, Debug, Default)]
+pub struct MemEngine {
+    map: HashMap<String, String>,
+}
+
+impl MemEngine {
+    /// Returns an empty engine
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of keys
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns whether there are no keys
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl KvsEngine for MemEngine {
+    type Error = Infallible;
+    type Iter = vec::IntoIter<(String, String)>;
+
+    fn get(&self, key: String) -> Result<Option<String>, Infallible> {
+        Ok(self.map.get(&key).cloned())
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<Option<String>, Infallible> {
+        Ok(self.map.insert(key, value))
+    }
+
+    fn remove(&mut self, key: String) -> Result<Option<String>, Infallible> {
+        Ok(self.map.remove(&key))
+    }
+
+    fn len(&self) -> usize {
+        Self::len(self)
+    }
+
+    fn is_empty(&self) -> bool {
+        Self::is_empty(self)
+    }
+
+    fn iter(&self) -> Result<Self::Iter, Infallible> {
+        let mut pairs: Vec<_> = self
+            .map
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        pairs.sort_unstable();
+        Ok(pairs.into_iter())
+    }
+}
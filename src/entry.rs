@@ -0,0 +1,151 @@
+//! Entries of the default key space, for atomic read-modify-write
+
+use crate::{current_version, KvStore, Result, ValueRef};
+use dashmap::Entry as VersionEntry;
+use std::sync::{PoisonError, RwLockReadGuard};
+
+/// Version of a key locked for a write, along with writes of several keys
+type Locked<'a> = (RwLockReadGuard<'a, ()>, VersionEntry<'a, String, u64>);
+
+/// Handle to a key of a [`KvStore`], returned by [`KvStore::entry`]
+///
+/// Each method reads the value of the key and writes it, with a single log record if any,
+/// while the key is locked against other writes, so that no write can slip in between as with
+/// a `get` followed by a `set`. The key is locked from [`KvStore::entry`] on until a method
+/// writes it, and again for the duration of each later method.
+///
+/// The lock is that of the shard of the key in the versions of the store: writes to any key of
+/// that shard, and [`KvStore::get_versioned`] of those keys, wait for it, so a thread holding an
+/// entry must not write to the store itself, lest it deadlock. Writes of several keys wait for it too.
+pub struct Entry<'a> {
+    store: &'a KvStore,
+    key: String,
+    /// Version of the key, held until the first write
+    current: Option<Locked<'a>>,
+}
+
+impl<'a> Entry<'a> {
+    pub(crate) fn new(store: &'a KvStore, key: String) -> Self {
+        let current = Some(lock(store, &key));
+        Self {
+            store,
+            key,
+            current,
+        }
+    }
+
+    /// Returns the key of the entry
+    #[must_use]
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Returns the value of the key if present
+    ///
+    /// # Errors
+    /// Returns `Err` if KV store read fails
+    pub fn get(&self) -> Result<Option<String>> {
+        self.store.guard("entry", || self.value())
+    }
+
+    /// Applies f to the value of the key if present, logging the modified value
+    ///
+    /// Returns the entry for chaining, e.g. with [`Entry::or_insert_with`].
+    ///
+    /// # Errors
+    /// Returns `Err` if the modified value exceeds its size limit, or KV store read or on-disk WAL
+    /// write fails
+    pub fn and_modify(mut self, f: impl FnOnce(&mut String)) -> Result<Self> {
+        let store = self.store;
+        store.guard_write("entry", || {
+            let (multi_key, current) = self.lock();
+            let Some(mut value) = self.value()? else {
+                self.current = Some((multi_key, current));
+                return Ok(());
+            };
+
+            f(&mut value);
+            store.check_len(&self.key, &value)?;
+            store.check_memory()?;
+            let version = current_version(&current) + 1;
+            let expires = store.expiry(&self.key);
+            store
+                .apply_set(current, self.key.clone(), value, version, expires)
+                .map(drop)
+        })?;
+        // Unless still holding the lock of the key, having written nothing
+        if self.current.is_none() {
+            store.evict();
+        }
+        Ok(self)
+    }
+
+    /// Returns the value of the key, inserting and logging the value returned by f if missing
+    ///
+    /// # Errors
+    /// Returns `Err` if the inserted value exceeds its size limit, or KV store read or on-disk
+    /// WAL write fails
+    pub fn or_insert_with(mut self, f: impl FnOnce() -> String) -> Result<String> {
+        let store = self.store;
+        store
+            .guard_write("entry", || {
+                let (_multi_key, current) = self.lock();
+                if let Some(value) = self.value()? {
+                    return Ok(value);
+                }
+
+                let value = f();
+                store.check_len(&self.key, &value)?;
+                store.check_memory()?;
+                let version = current_version(&current) + 1;
+                store.apply_set(current, self.key.clone(), value.clone(), version, None)?;
+                Ok(value)
+            })
+            .inspect(|_| store.evict())
+    }
+
+    /// Removes the key if present, logging the removal, and returns its value
+    ///
+    /// # Errors
+    /// Returns `Err` if KV store read or on-disk WAL write fails
+    pub fn remove(mut self) -> Result<Option<String>> {
+        let store = self.store;
+        store.guard_write("entry", || {
+            let (_multi_key, current) = self.lock();
+            let value = self.value()?;
+            if value.is_some() {
+                store.apply_rm(current, self.key.clone())?;
+            }
+            Ok(value)
+        })
+    }
+
+    /// Returns the version of the key, locking it again if already written
+    fn lock(&mut self) -> Locked<'a> {
+        self.current
+            .take()
+            .unwrap_or_else(|| lock(self.store, &self.key))
+    }
+
+    /// Reads the value of the key
+    fn value(&self) -> Result<Option<String>> {
+        let value = if self.store.expired(&self.key) {
+            None
+        } else if self.store.inner.options.offset_index {
+            self.store.disk_get(&self.key)?
+        } else {
+            self.store.inner.store.get(&self.key).map(ValueRef::entry)
+        };
+        Ok(value.map(ValueRef::into_string))
+    }
+}
+
+/// Locks the version of key in store for a write
+fn lock<'a>(store: &'a KvStore, key: &str) -> Locked<'a> {
+    let multi_key = store
+        .inner
+        .multi_key
+        .read()
+        .unwrap_or_else(PoisonError::into_inner);
+    (multi_key, store.inner.versions.entry(key.to_owned()))
+}
@@ -0,0 +1,150 @@
+//! Eviction of keys of a store in cache mode, beyond a key count or byte budget
+
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard, PoisonError,
+    },
+};
+
+/// Which keys are evicted first, see [`EvictionConfig`]
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EvictionPolicy {
+    /// Least recently used
+    #[default]
+    Lru,
+    /// Least frequently used, the least recently used first among equally used keys
+    Lfu,
+}
+
+/// Configuration of cache mode, see [`OpenOptions::eviction`](crate::OpenOptions::eviction)
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct EvictionConfig {
+    /// Which keys are evicted first
+    #[serde(default)]
+    pub policy: EvictionPolicy,
+    /// Maximum number of keys, default unlimited
+    pub max_keys: Option<usize>,
+    /// Maximum total bytes of keys and values, default unlimited
+    pub max_bytes: Option<u64>,
+}
+
+/// Thread-safe tracker of the uses of the keys of a store, picking those to evict
+#[derive(Debug)]
+pub(crate) struct Evictor {
+    inner: Mutex<Tracker>,
+    evicted: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Tracker {
+    config: EvictionConfig,
+    entries: HashMap<String, Use>,
+    /// Keys by rank, first evicted first
+    order: BTreeMap<(u64, u64), String>,
+    tick: u64,
+    /// Total bytes of keys and values
+    bytes: u64,
+}
+
+#[derive(Debug)]
+struct Use {
+    /// Last use for LRU, or number of uses for LFU, then last use
+    rank: (u64, u64),
+    /// Bytes of the key and its value
+    size: u64,
+}
+
+impl Evictor {
+    pub(crate) fn new(config: EvictionConfig) -> Self {
+        Self {
+            inner: Mutex::new(Tracker {
+                config,
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+                bytes: 0,
+            }),
+            evicted: AtomicU64::new(0),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Tracker> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Tracks a write of key with a value, of size bytes along with key, as a use
+    pub(crate) fn set(&self, key: &str, size: usize) {
+        let mut tracker = self.lock();
+        let uses = tracker.forget(key).map_or(0, |used| used.rank.0);
+        tracker.track(key, uses, size as u64);
+    }
+
+    /// Tracks a read of key as a use, if tracked
+    pub(crate) fn touch(&self, key: &str) {
+        let mut tracker = self.lock();
+        if let Some(used) = tracker.forget(key) {
+            tracker.track(key, used.rank.0, used.size);
+        }
+    }
+
+    /// Stops tracking key, removed from the store
+    pub(crate) fn forget(&self, key: &str) {
+        self.lock().forget(key);
+    }
+
+    /// Returns the next key to evict while over budget, and its size along with its value, no
+    /// longer tracking it
+    pub(crate) fn victim(&self) -> Option<(String, usize)> {
+        let mut tracker = self.lock();
+        let config = tracker.config;
+        let over = config
+            .max_keys
+            .is_some_and(|max| tracker.entries.len() > max)
+            || config.max_bytes.is_some_and(|max| tracker.bytes > max);
+        if !over {
+            return None;
+        }
+        let (_, key) = tracker.order.pop_first()?;
+        let size = tracker.entries.remove(&key).map_or(0, |used| {
+            tracker.bytes -= used.size;
+            usize::try_from(used.size).unwrap_or(usize::MAX)
+        });
+        Some((key, size))
+    }
+
+    /// Counts a key as evicted
+    pub(crate) fn evicted(&self) {
+        self.evicted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of keys evicted since the store was opened
+    pub(crate) fn evictions(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+}
+
+impl Tracker {
+    /// Tracks a use of key, used uses times before, of size bytes along with its value
+    fn track(&mut self, key: &str, uses: u64, size: u64) {
+        self.tick += 1;
+        let rank = match self.config.policy {
+            EvictionPolicy::Lru => (self.tick, self.tick),
+            EvictionPolicy::Lfu => (uses + 1, self.tick),
+        };
+        self.order.insert(rank, key.to_owned());
+        self.entries.insert(key.to_owned(), Use { rank, size });
+        self.bytes += size;
+    }
+
+    fn forget(&mut self, key: &str) -> Option<Use> {
+        let used = self.entries.remove(key)?;
+        self.order.remove(&used.rank);
+        self.bytes -= used.size;
+        Some(used)
+    }
+}
@@ -0,0 +1,200 @@
+//! Facade giving a KV store the methods of a `HashMap<String, String>`
+
+use crate::{engine::KvsEngine, KvStore};
+use std::fmt::Display;
+
+/// Wrapper of a [`KvsEngine`] with the methods of a `HashMap<String, String>`, so that code
+/// written against one takes a store with few changes
+///
+/// Values are returned by copy rather than by reference. Each map method has a `try_` variant
+/// returning the error of the engine, such as a key over the size limit of a store; the map
+/// methods themselves cannot fail, so they panic on it, and are meant for engines failing only
+/// when their storage does.
+///
+/// Collecting pairs into a facade creates its engine with [`Default`], e.g. a
+/// [`MemEngine`](crate::MemEngine).
+#[derive(Clone, Debug, Default)]
+pub struct MapFacade<E = KvStore> {
+    engine: E,
+}
+
+impl<E: KvsEngine> MapFacade<E>
+where
+    E::Error: Display,
+{
+    /// Wraps engine
+    pub fn new(engine: E) -> Self {
+        Self { engine }
+    }
+
+    /// Returns the wrapped engine
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+
+    /// Unwraps the engine
+    pub fn into_inner(self) -> E {
+        self.engine
+    }
+
+    /// Returns the value of key, or `None` if missing
+    ///
+    /// # Errors
+    /// Returns the error of the engine if it fails to read the key
+    pub fn try_get(&self, key: &str) -> Result<Option<String>, E::Error> {
+        self.engine.get(key.to_owned())
+    }
+
+    /// Returns the value of key, or `None` if missing
+    ///
+    /// # Panics
+    /// Panics if the engine fails to read the key, e.g. when its storage fails; see
+    /// [`MapFacade::try_get`]
+    pub fn get(&self, key: &str) -> Option<String> {
+        expect(self.try_get(key))
+    }
+
+    /// Returns whether key is present
+    ///
+    /// # Errors
+    /// Returns the error of the engine if it fails to read the key
+    pub fn try_contains_key(&self, key: &str) -> Result<bool, E::Error> {
+        self.try_get(key).map(|value| value.is_some())
+    }
+
+    /// Returns whether key is present
+    ///
+    /// # Panics
+    /// Panics if the engine fails to read the key, e.g. when its storage fails; see
+    /// [`MapFacade::try_contains_key`]
+    pub fn contains_key(&self, key: &str) -> bool {
+        expect(self.try_contains_key(key))
+    }
+
+    /// Sets the value of key, returning its previous value, or `None` if missing
+    ///
+    /// # Errors
+    /// Returns the error of the engine if it rejects the key or value, e.g. over the size
+    /// limits of a store, or fails to write them
+    pub fn try_insert(&mut self, key: String, value: String) -> Result<Option<String>, E::Error> {
+        self.engine.set(key, value)
+    }
+
+    /// Sets the value of key, returning its previous value, or `None` if missing
+    ///
+    /// # Panics
+    /// Panics if the engine rejects the key or value, e.g. over the size limits of a store, or
+    /// fails to write them when its storage fails; see [`MapFacade::try_insert`]
+    pub fn insert(&mut self, key: String, value: String) -> Option<String> {
+        expect(self.try_insert(key, value))
+    }
+
+    /// Removes key, returning its value, or `None` if missing
+    ///
+    /// # Errors
+    /// Returns the error of the engine if it rejects the removal, e.g. of a read-only store,
+    /// or fails to write it
+    pub fn try_remove(&mut self, key: &str) -> Result<Option<String>, E::Error> {
+        self.engine.remove(key.to_owned())
+    }
+
+    /// Removes key, returning its value, or `None` if missing
+    ///
+    /// # Panics
+    /// Panics if the engine rejects the removal, e.g. of a read-only store, or fails to write
+    /// it when its storage fails; see [`MapFacade::try_remove`]
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        expect(self.try_remove(key))
+    }
+
+    /// Returns the key-value pairs in key order, as of a snapshot, see [`KvsEngine::iter`]
+    ///
+    /// # Errors
+    /// Returns the error of the engine if it fails to read the pairs
+    pub fn try_iter(&self) -> Result<E::Iter, E::Error> {
+        self.engine.iter()
+    }
+
+    /// Returns the key-value pairs in key order, as of a snapshot, see [`KvsEngine::iter`]
+    ///
+    /// # Panics
+    /// Panics if the engine fails to read the pairs, e.g. when its storage fails; see
+    /// [`MapFacade::try_iter`]
+    #[must_use]
+    pub fn iter(&self) -> E::Iter {
+        expect(self.try_iter())
+    }
+
+    /// Returns the keys in key order, as of a snapshot
+    ///
+    /// # Panics
+    /// Panics if the engine fails to read the pairs
+    pub fn keys(&self) -> impl Iterator<Item = String> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    /// Returns the values in key order, as of a snapshot
+    ///
+    /// # Panics
+    /// Panics if the engine fails to read the pairs
+    pub fn values(&self) -> impl Iterator<Item = String> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    /// Returns the number of keys
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.engine.len()
+    }
+
+    /// Returns whether there are no keys
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.engine.is_empty()
+    }
+}
+
+impl<E: KvsEngine> Extend<(String, String)> for MapFacade<E>
+where
+    E::Error: Display,
+{
+    fn extend<T: IntoIterator<Item = (String, String)>>(&mut self, pairs: T) {
+        for (key, value) in pairs {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl<E: KvsEngine + Default> FromIterator<(String, String)> for MapFacade<E>
+where
+    E::Error: Display,
+{
+    fn from_iter<T: IntoIterator<Item = (String, String)>>(pairs: T) -> Self {
+        let mut facade = Self::new(E::default());
+        facade.extend(pairs);
+        facade
+    }
+}
+
+impl<E: KvsEngine> IntoIterator for &MapFacade<E>
+where
+    E::Error: Display,
+{
+    type Item = (String, String);
+    type IntoIter = E::Iter;
+
+    fn into_iter(self) -> E::Iter {
+        self.iter()
+    }
+}
+
+impl From<KvStore> for MapFacade {
+    fn from(store: KvStore) -> Self {
+        Self::new(store)
+    }
+}
+
+/// Returns the value of result, panicking with the error of the engine if any
+fn expect<T, E: Display>(result: Result<T, E>) -> T {
+    result.unwrap_or_else(|e| panic!("KV store failed: {e}"))
+}
@@ -0,0 +1,156 @@
+//! C bindings of the store, for C, C++ or Python (`ctypes`) programs embedding it through the
+//! `kvs` shared library, built with
+//! `cargo rustc --lib --release --features ffi --crate-type cdylib`
+//!
+//! Each function returns [`KVS_OK`], or the [exit code](crate::ErrorKind::exit_code) of the
+//! kind of error. The C header `include/kvs.h` is generated with
+//! `cbindgen --config cbindgen.toml --output include/kvs.h`.
+
+use crate::{KvStore, KvStoreError, Result};
+use std::{
+    ffi::{c_char, c_int, CStr, CString},
+    ptr,
+};
+
+/// Returned on success
+pub const KVS_OK: c_int = 0;
+/// Returned for an invalid argument, see [`ErrorKind::Usage`](crate::ErrorKind::Usage)
+pub const KVS_USAGE: c_int = 1;
+/// Returned for a missing key, see [`ErrorKind::KeyNotFound`](crate::ErrorKind::KeyNotFound)
+pub const KVS_KEY_NOT_FOUND: c_int = 2;
+/// Returned if reading or writing files fails, see [`ErrorKind::Io`](crate::ErrorKind::Io)
+pub const KVS_IO: c_int = 3;
+/// Returned for damaged files, see [`ErrorKind::Corrupted`](crate::ErrorKind::Corrupted)
+pub const KVS_CORRUPTED: c_int = 4;
+/// Returned for a conflicting write, see [`ErrorKind::Conflict`](crate::ErrorKind::Conflict)
+pub const KVS_CONFLICT: c_int = 5;
+/// Returned if unavailable, see [`ErrorKind::Unavailable`](crate::ErrorKind::Unavailable)
+pub const KVS_UNAVAILABLE: c_int = 6;
+/// Returned if the call is not allowed, see [`ErrorKind::Denied`](crate::ErrorKind::Denied)
+pub const KVS_DENIED: c_int = 7;
+/// Returned for errors of a remote store, see [`ErrorKind::Remote`](crate::ErrorKind::Remote)
+pub const KVS_REMOTE: c_int = 8;
+
+/// Opens the store at path, storing a handle to it in store, to be closed with [`kvs_close`]
+///
+/// # Safety
+/// path must be a NUL-terminated string, and store a valid pointer to write the handle to.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_open(path: *const c_char, store: *mut *mut KvStore) -> c_int {
+    if store.is_null() {
+        return KVS_USAGE;
+    }
+    code(arg(path, "path").and_then(KvStore::open).map(|opened| {
+        *store = Box::into_raw(Box::new(opened));
+    }))
+}
+
+/// Stores the value of key in value, or NULL if missing, to be freed with [`kvs_free_string`]
+///
+/// Returns [`KVS_USAGE`] if the value holds a NUL byte.
+///
+/// # Safety
+/// store must be a handle from [`kvs_open`] not yet closed, key a NUL-terminated string, and
+/// value a valid pointer to write the value to.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_get(
+    store: *const KvStore,
+    key: *const c_char,
+    value: *mut *mut c_char,
+) -> c_int {
+    let (Some(store), false) = (store.as_ref(), value.is_null()) else {
+        return KVS_USAGE;
+    };
+    code(
+        arg(key, "key")
+            .and_then(|key| store.get(key))
+            .and_then(|found| {
+                *value = match found {
+                    Some(found) => CString::new(found)
+                        .map_err(|e| KvStoreError::InvalidCommand(format!("Invalid value: {e}")))?
+                        .into_raw(),
+                    None => ptr::null_mut(),
+                };
+                Ok(())
+            }),
+    )
+}
+
+/// Sets the value of key
+///
+/// # Safety
+/// store must be a handle from [`kvs_open`] not yet closed, and key and value NUL-terminated
+/// strings.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_set(
+    store: *const KvStore,
+    key: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    let Some(store) = store.as_ref() else {
+        return KVS_USAGE;
+    };
+    code(arg(key, "key").and_then(|key| {
+        store.set(key.to_owned(), arg(value, "value")?.to_owned())?;
+        Ok(())
+    }))
+}
+
+/// Removes key, returning [`KVS_KEY_NOT_FOUND`] if missing
+///
+/// # Safety
+/// store must be a handle from [`kvs_open`] not yet closed, and key a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_remove(store: *const KvStore, key: *const c_char) -> c_int {
+    let Some(store) = store.as_ref() else {
+        return KVS_USAGE;
+    };
+    code(arg(key, "key").and_then(|key| store.remove(key.to_owned()).map(drop)))
+}
+
+/// Closes a handle from [`kvs_open`], doing nothing if NULL
+///
+/// # Safety
+/// store must be NULL or a handle from [`kvs_open`] not yet closed, which it may no longer be
+/// used after.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_close(store: *mut KvStore) {
+    if !store.is_null() {
+        drop(Box::from_raw(store));
+    }
+}
+
+/// Frees a value from [`kvs_get`], doing nothing if NULL
+///
+/// # Safety
+/// value must be NULL or a value from [`kvs_get`] not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_free_string(value: *mut c_char) {
+    if !value.is_null() {
+        drop(CString::from_raw(value));
+    }
+}
+
+/// Returns the string argument named name
+///
+/// # Errors
+/// Returns [`KvStoreError::InvalidCommand`] if s is NULL or not UTF-8
+///
+/// # Safety
+/// s must be NULL or a NUL-terminated string that outlives the returned one.
+unsafe fn arg<'a>(s: *const c_char, name: &str) -> Result<&'a str> {
+    if s.is_null() {
+        return Err(KvStoreError::InvalidCommand(format!("Missing {name}")));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|e| KvStoreError::InvalidCommand(format!("Invalid {name}: {e}")))
+}
+
+/// Returns the code of result
+fn code(result: Result<()>) -> c_int {
+    match result {
+        Ok(()) => KVS_OK,
+        Err(e) => c_int::from(e.kind().exit_code()),
+    }
+}
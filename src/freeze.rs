@@ -0,0 +1,91 @@
+//! Maintenance mode of a KV store, rejecting writes while files are copied or migrated
+
+use crate::{KvStoreError, Result};
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+/// Interval at which freezing checks whether writes in progress are done
+const DRAIN_INTERVAL: Duration = Duration::from_millis(1);
+
+thread_local! {
+    /// Whether the writes of this thread apply entries already committed elsewhere, see
+    /// [`applying`]
+    static APPLYING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs f with the writes it makes on this thread allowed while frozen
+///
+/// For Raft cluster members applying committed entries, which must not be skipped.
+#[cfg(feature = "raft")]
+pub(crate) fn applying<T>(f: impl FnOnce() -> T) -> T {
+    let previous = APPLYING.with(|a| a.replace(true));
+    let result = f();
+    APPLYING.with(|a| a.set(previous));
+    result
+}
+
+/// Whether writes are frozen, and how many are in progress
+#[derive(Debug, Default)]
+pub(crate) struct Freeze {
+    frozen: AtomicBool,
+    writing: AtomicUsize,
+}
+
+impl Freeze {
+    /// Returns whether writes are frozen
+    pub(crate) fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::SeqCst)
+    }
+
+    /// Fails if writes are frozen
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::ReadOnly`] if writes are frozen
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.is_frozen() {
+            return Err(KvStoreError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Counts a write in progress until the returned guard is dropped
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::ReadOnly`] if writes are frozen, unless the thread is
+    /// [applying](applying) committed entries
+    pub(crate) fn enter(&self) -> Result<Writing<'_>> {
+        // Counted before checking, so that freezing either waits for the write or rejects it
+        self.writing.fetch_add(1, Ordering::SeqCst);
+        let writing = Writing(&self.writing);
+        if !APPLYING.with(Cell::get) {
+            self.check()?;
+        }
+        Ok(writing)
+    }
+
+    /// Rejects writes from now on, and waits for those in progress
+    pub(crate) fn freeze(&self) {
+        self.frozen.store(true, Ordering::SeqCst);
+        while self.writing.load(Ordering::SeqCst) > 0 {
+            thread::sleep(DRAIN_INTERVAL);
+        }
+    }
+
+    /// Accepts writes again
+    pub(crate) fn thaw(&self) {
+        self.frozen.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Write in progress, see [`Freeze::enter`]
+pub(crate) struct Writing<'a>(&'a AtomicUsize);
+
+impl Drop for Writing<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,88 @@
+//! Glob-style patterns of keys, as matched by Redis `SCAN MATCH`
+//!
+//! `*` matches any run of characters, `?` any one character, `[abc]` and `[a-z]` one character
+//! of a set, `[^abc]` one character not in it, and `\` escapes the character after it.
+
+/// Returns whether key matches pattern
+pub(crate) fn matches(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    let (mut p, mut k) = (0, 0);
+    // Pattern after the last `*` seen, and the end of the characters it matches so far
+    let mut star = None;
+    while k < key.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            star = Some((p, k));
+            continue;
+        }
+        if let Some(next) = (p < pattern.len())
+            .then(|| match_one(&pattern, p, key[k]))
+            .flatten()
+        {
+            p = next;
+            k += 1;
+            continue;
+        }
+        // Backtrack, letting the last `*` match one more character
+        let Some((after, end)) = star else {
+            return false;
+        };
+        p = after;
+        k = end + 1;
+        star = Some((after, k));
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Returns the position in pattern after the token at p if it matches c
+fn match_one(pattern: &[char], p: usize, c: char) -> Option<usize> {
+    match pattern[p] {
+        '?' => Some(p + 1),
+        '\\' if p + 1 < pattern.len() => (pattern[p + 1] == c).then_some(p + 2),
+        '[' => {
+            let mut i = p + 1;
+            let negated = pattern.get(i) == Some(&'^');
+            if negated {
+                i += 1;
+            }
+            let mut found = false;
+            while i < pattern.len() && pattern[i] != ']' {
+                if pattern[i] == '\\' && i + 1 < pattern.len() {
+                    found |= pattern[i + 1] == c;
+                    i += 2;
+                } else if pattern.get(i + 1) == Some(&'-')
+                    && pattern.get(i + 2).is_some_and(|&end| end != ']')
+                {
+                    let (start, end) = (pattern[i], pattern[i + 2]);
+                    found |= (start.min(end)..=start.max(end)).contains(&c);
+                    i += 3;
+                } else {
+                    found |= pattern[i] == c;
+                    i += 1;
+                }
+            }
+            // An unclosed set runs to the end of the pattern
+            (found != negated).then_some((i + 1).min(pattern.len()))
+        }
+        literal => (literal == c).then_some(p + 1),
+    }
+}
+
+/// Returns the characters every key matching pattern starts with
+pub(crate) fn literal_prefix(pattern: &str) -> String {
+    let mut prefix = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' | '?' | '[' => break,
+            '\\' => match chars.next() {
+                Some(escaped) => prefix.push(escaped),
+                // A trailing `\` is literal
+                None => prefix.push('\\'),
+            },
+            c => prefix.push(c),
+        }
+    }
+    prefix
+}
@@ -0,0 +1,163 @@
+//! gRPC interface of a KV store, as defined in `proto/kvs.proto`
+
+use crate::{Command, KvStore, KvStoreError, WatchEvent};
+use proto::{
+    kvs_server::{Kvs, KvsServer},
+    Change, Entry, GetRequest, GetResponse, RemoveRequest, RemoveResponse, ScanRequest, SetRequest,
+    SetResponse, WatchRequest,
+};
+use std::{net::SocketAddr, panic, pin::Pin, thread};
+use tokio::{sync::mpsc, task};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tonic::{transport::Server, Request, Response, Status};
+
+/// Messages and service stubs generated from `proto/kvs.proto`
+#[allow(clippy::all, clippy::pedantic, missing_docs)]
+pub mod proto {
+    tonic::include_proto!("kvs");
+}
+
+/// gRPC client of a KV store server
+pub type GrpcClient = proto::kvs_client::KvsClient<tonic::transport::Channel>;
+
+/// Number of stream messages buffered per streaming call
+const STREAM_BUFFER: usize = 64;
+
+/// gRPC service backed by a KV store
+#[derive(Clone)]
+pub struct KvsService {
+    store: KvStore,
+}
+
+impl KvsService {
+    /// Returns a service for store
+    #[must_use]
+    pub fn new(store: KvStore) -> KvsServer<Self> {
+        KvsServer::new(Self { store })
+    }
+
+    /// Runs op with the store on the blocking thread pool, since store operations may wait on
+    /// locks and disk I/O, resuming its panic if any
+    async fn blocking<T: Send + 'static>(
+        &self,
+        op: impl FnOnce(&KvStore) -> crate::Result<T> + Send + 'static,
+    ) -> Result<T, Status> {
+        let store = self.store.clone();
+        task::spawn_blocking(move || op(&store))
+            .await
+            .unwrap_or_else(|e| panic::resume_unwind(e.into_panic()))
+            .map_err(Status::from)
+    }
+}
+
+/// Serves the gRPC interface for store on address until the process exits
+///
+/// # Errors
+/// Returns `Err` if binding or serving fails
+pub async fn serve(store: KvStore, addr: SocketAddr) -> Result<(), tonic::transport::Error> {
+    tracing::info!(%addr, "gRPC server listening");
+    Server::builder()
+        .add_service(KvsService::new(store))
+        .serve(addr)
+        .await
+}
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Kvs for KvsService {
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let key = request.into_inner().key;
+        let value = self.blocking(move |store| store.get_hooked(key)).await?;
+        Ok(Response::new(GetResponse { value }))
+    }
+
+    async fn set(&self, request: Request<SetRequest>) -> Result<Response<SetResponse>, Status> {
+        let SetRequest { key, value } = request.into_inner();
+        self.blocking(move |store| {
+            store.check_writable()?;
+            store.execute(Command::Set {
+                key,
+                value,
+                path: None,
+                nx: false,
+                xx: false,
+            })
+        })
+        .await?;
+        Ok(Response::new(SetResponse {}))
+    }
+
+    async fn remove(
+        &self,
+        request: Request<RemoveRequest>,
+    ) -> Result<Response<RemoveResponse>, Status> {
+        let key = request.into_inner().key;
+        self.blocking(move |store| {
+            store.check_writable()?;
+            store.execute(Command::Rm { key, prefix: None })
+        })
+        .await?;
+        Ok(Response::new(RemoveResponse {}))
+    }
+
+    type ScanStream = ResponseStream<Entry>;
+
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> Result<Response<Self::ScanStream>, Status> {
+        let prefix = request.into_inner().prefix;
+        let entries = self.blocking(move |store| store.scan(&prefix)).await?;
+        let stream = tokio_stream::iter(entries).map(|(key, value)| Ok(Entry { key, value }));
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    type WatchStream = ResponseStream<Change>;
+
+    async fn watch(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let events = self.store.watch(request.into_inner().prefix);
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+
+        // Store watchers are blocking receivers, so forward from a dedicated thread; it exits
+        // once the client hangs up and the next change fails to send.
+        thread::Builder::new()
+            .name("kvs-grpc-watch".to_owned())
+            .spawn(move || {
+                for event in events {
+                    let change = match event {
+                        WatchEvent::Set { key, value } => Change {
+                            key,
+                            value: Some(value),
+                        },
+                        WatchEvent::Removed { key } => Change { key, value: None },
+                    };
+                    if tx.blocking_send(Ok(change)).is_err() {
+                        break;
+                    }
+                }
+            })
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+}
+
+impl From<KvStoreError> for Status {
+    fn from(e: KvStoreError) -> Self {
+        match e {
+            KvStoreError::FailedRm(_) => Self::not_found(e.to_string()),
+            KvStoreError::Poisoned(_)
+            | KvStoreError::ReadOnly
+            | KvStoreError::ReadOnlyReplica(_) => Self::failed_precondition(e.to_string()),
+            KvStoreError::KeyTooLarge(..) | KvStoreError::ValueTooLarge(..) => {
+                Self::invalid_argument(e.to_string())
+            }
+            KvStoreError::OutOfMemoryBudget(..) => Self::resource_exhausted(e.to_string()),
+            e => Self::internal(e.report()),
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Hint files: where the records of a base segment sit, so that opening a store rebuilds its
+//! index from them instead of replaying the base segment
+//!
+//! Compaction writes `wa.<id>.base.hint` along with each base segment. After a header line,
+//! each line locates one record of the base segment, in log order, in the text format: the
+//! fields of the record but its value, then the offset and length of the record. The header
+//! ties the hint file to the base segment it describes:
+//!
+//! ```text
+//! kvs-hint <version> <checksum> <len> <base checksum> <base len> <last sequence> <through>
+//! ```
+//!
+//! with the checksum and length of the lines after it, those of the records of the base
+//! segment, as in its header, the sequence number of its last record, and that of its
+//! `compacted` marker. Hint files not matching their base segment are ignored.
+
+use crate::{
+    codec::{Stamp, WalFormat},
+    segment,
+    wal::Record,
+};
+use std::{
+    borrow::Cow,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::Path,
+};
+
+/// Magic starting the header of hint files
+const HINT_MAGIC: &str = "kvs-hint";
+
+/// Format version of hint files written by this version
+const HINT_VERSION: u32 = 1;
+
+/// Tags of the records whose last field is the value of a key, left out of hints; the values of
+/// lists and members of sets are kept, as opening the store rebuilds them from hints alone
+const VALUED: [&str; 6] = ["set", "vset", "xset", "append", "mv", "bset"];
+
+/// Records of a base segment, as located by its hint file
+#[derive(Debug)]
+pub(crate) struct Hints {
+    /// Bytes of records of the base segment, its `compacted` marker included
+    pub(crate) len: u64,
+    /// Sequence number of the last record of the base segment, 0 if none
+    pub(crate) last_sequence: u64,
+    /// Last sequence number covered by the compaction that wrote the base segment
+    pub(crate) through: u64,
+    /// Records of the base segment, without values, with their offset and length
+    pub(crate) records: Vec<(Record, u64, u64)>,
+}
+
+/// Appends the hint line of the record with the given fields, at offset and of length len, to
+/// buf
+pub(crate) fn encode(fields: &[String], offset: u64, len: u64, buf: &mut Vec<u8>) {
+    let valued = fields
+        .first()
+        .is_some_and(|tag| VALUED.contains(&tag.as_str()));
+    let named = &fields[..fields.len() - usize::from(valued)];
+    let fields: Vec<_> = named
+        .iter()
+        .map(|field| Cow::Borrowed(field.as_str()))
+        .chain([offset.to_string().into(), len.to_string().into()])
+        .collect();
+    WalFormat::Text
+        .codec()
+        .encode(&Stamp::default(), &fields, buf);
+    buf.push(b'\n');
+}
+
+/// Writes the hint file at path, holding lines, of a base segment with the given header
+/// fields, and syncs it
+///
+/// # Errors
+/// Returns `Err` if writing or syncing fails
+pub(crate) fn write(
+    path: &Path,
+    lines: &[u8],
+    (base_checksum, base_len): (u32, u64),
+    (last_sequence, through): (u64, u64),
+) -> io::Result<()> {
+    let checksum = crc32fast::hash(lines);
+    let mut file = File::create(path)?;
+    writeln!(
+        file,
+        "{HINT_MAGIC} {HINT_VERSION} {checksum:08x} {:016x} {base_checksum:08x} {base_len:016x} \
+         {last_sequence} {through}",
+        lines.len()
+    )?;
+    file.write_all(lines)?;
+    file.sync_all()
+}
+
+/// Reads the hint file at path of the base segment at `base_path`
+///
+/// # Errors
+/// Returns why the hint file cannot be used: missing, unreadable, malformed, of an unsupported
+/// format version, or not matching the base segment
+pub(crate) fn read(path: &Path, base_path: &Path) -> Result<Hints, String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    let end = bytes
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or("missing header")?;
+    let (header, lines) = (&bytes[..end], &bytes[end + 1..]);
+    let header = std::str::from_utf8(header).map_err(|_| "malformed header")?;
+    let malformed = || "malformed header".to_owned();
+    let [magic, version, checksum, len, base_checksum, base_len, last_sequence, through] =
+        header.split(' ').collect::<Vec<_>>()[..]
+    else {
+        return Err(malformed());
+    };
+    if magic != HINT_MAGIC {
+        return Err("missing header".to_owned());
+    }
+    match version.parse::<u32>() {
+        Ok(HINT_VERSION) => {}
+        Ok(version) => return Err(format!("unsupported format version {version}")),
+        Err(_) => return Err(malformed()),
+    }
+    let (Ok(checksum), Ok(len), Ok(base_checksum), Ok(base_len), Ok(last_sequence), Ok(through)) = (
+        u32::from_str_radix(checksum, 16),
+        u64::from_str_radix(len, 16),
+        u32::from_str_radix(base_checksum, 16),
+        u64::from_str_radix(base_len, 16),
+        last_sequence.parse(),
+        through.parse(),
+    ) else {
+        return Err(malformed());
+    };
+    if lines.len() as u64 != len || crc32fast::hash(lines) != checksum {
+        return Err("truncated or corrupt lines".to_owned());
+    }
+
+    // Only the header of the base segment is read, its records being verified on their own
+    let mut base_header = Vec::new();
+    BufReader::new(File::open(base_path).map_err(|e| e.to_string())?)
+        .take(64)
+        .read_until(b'\n', &mut base_header)
+        .map_err(|e| e.to_string())?;
+    if base_header != segment::base_header(base_checksum, base_len).as_bytes() {
+        return Err("base segment does not match".to_owned());
+    }
+
+    let records = lines
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (_, fields) = WalFormat::Text
+                .codec()
+                .decode(line)
+                .map_err(|e| e.to_string())?;
+            record(fields)
+                .ok_or_else(|| format!("malformed line: {}", String::from_utf8_lossy(line)))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Hints {
+        len: base_len,
+        last_sequence,
+        through,
+        records,
+    })
+}
+
+/// Parses the fields of a hint line into its record, with an empty value, and the offset and
+/// length of the record
+fn record(mut fields: Vec<String>) -> Option<(Record, u64, u64)> {
+    let len = fields.pop()?.parse().ok()?;
+    let offset = fields.pop()?.parse().ok()?;
+    let mut fields = fields.into_iter();
+    let tag = fields.next()?;
+    let mut next = || fields.next();
+    let record = match tag.as_str() {
+        "set" => Record::Set {
+            key: next()?,
+            value: String::new(),
+            version: None,
+            expires: None,
+        },
+        "vset" => Record::Set {
+            key: next()?,
+            version: Some(next()?.parse().ok()?),
+            value: String::new(),
+            expires: None,
+        },
+        "xset" => Record::Set {
+            key: next()?,
+            version: Some(next()?.parse().ok()?),
+            expires: Some(next()?.parse().ok()?),
+            value: String::new(),
+        },
+        "append" => Record::Append {
+            key: next()?,
+            suffix: String::new(),
+        },
+        "mv" => Record::Rename {
+            from: next()?,
+            to: next()?,
+            value: String::new(),
+        },
+        "bset" => Record::BucketSet {
+            bucket: next()?,
+            key: next()?,
+            value: String::new(),
+        },
+        "id" => Record::ReserveIds {
+            sequence: next()?,
+            end: next()?.parse().ok()?,
+        },
+        "ts" => Record::Sample {
+            key: next()?,
+            timestamp: next()?.parse().ok()?,
+            value: next()?.parse().ok()?,
+        },
+        "lpush" => Record::ListPush {
+            key: next()?,
+            value: next()?,
+        },
+        "sadd" => Record::SetAdd {
+            key: next()?,
+            member: next()?,
+        },
+        "lease" => Record::Lease {
+            key: next()?,
+            token: next()?.parse().ok()?,
+            expires: next()?.parse().ok()?,
+        },
+        _ => return None,
+    };
+    Some((record, offset, len))
+}
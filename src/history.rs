@@ -0,0 +1,148 @@
+//! Values of keys as of past log sequence numbers, taken from the records the WAL commits
+
+use crate::{codec::Stamp, wal::Record, KvStoreError, Result};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Value of a key as of the record writing it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Revision {
+    /// Sequence number of the record
+    pub sequence: u64,
+    /// Value written, or `None` if the key was removed
+    pub value: Option<String>,
+    /// When the record was first committed, unless written before records were timestamped
+    pub timestamp: Option<SystemTime>,
+    /// Client that made the write, if known
+    pub client: Option<String>,
+}
+
+/// Revisions of the default key space within a window of the latest sequence numbers
+///
+/// The window of `retention` records ends at the latest sequence number; each key keeps its
+/// revisions in the window, plus the last one before it, which still holds at its start.
+#[derive(Debug, Default)]
+pub(crate) struct History {
+    /// Number of latest records whose revisions are kept, or `None` if disabled
+    retention: Option<u64>,
+    /// Revisions per key, in log order
+    keys: HashMap<String, Vec<Revision>>,
+    /// Keys in log order of their revisions, trimmed once they leave the window
+    written: VecDeque<(u64, String)>,
+}
+
+impl History {
+    pub(crate) fn new(retention: Option<u64>) -> Self {
+        Self {
+            retention,
+            ..Self::default()
+        }
+    }
+
+    /// Records the revision of a numbered record with its stamp, if it writes a key
+    pub(crate) fn record(&mut self, stamp: &Stamp, record: &Record) {
+        let (Some(retention), Some(sequence)) = (self.retention, stamp.sequence) else {
+            return;
+        };
+        let writes: Vec<(String, Option<String>)> = match record {
+            Record::Set { key, value, .. } => vec![(key.clone(), Some(value.clone()))],
+            Record::Rm { key } => vec![(key.clone(), None)],
+            Record::Rename { from, to, value } => {
+                vec![(from.clone(), None), (to.clone(), Some(value.clone()))]
+            }
+            Record::Append { key, suffix } => {
+                let Some(value) = self.keys.get(key).and_then(|r| r.last()?.value.as_ref()) else {
+                    return;
+                };
+                vec![(key.clone(), Some(format!("{value}{suffix}")))]
+            }
+            // Every key present has a revision, the last one before the window at least
+            Record::RmPrefix { prefix } => self
+                .keys
+                .iter()
+                .filter(|(key, revisions)| {
+                    key.starts_with(prefix.as_str())
+                        && revisions.last().is_some_and(|r| r.value.is_some())
+                })
+                .map(|(key, _)| (key.clone(), None))
+                .collect(),
+            _ => return,
+        };
+
+        for (key, value) in writes {
+            self.keys.entry(key.clone()).or_default().push(Revision {
+                sequence,
+                value,
+                timestamp: stamp
+                    .timestamp
+                    .map(|millis| UNIX_EPOCH + Duration::from_millis(millis)),
+                client: stamp.client.clone(),
+            });
+            self.written.push_back((sequence, key));
+        }
+        self.trim(sequence.saturating_sub(retention));
+    }
+
+    /// Drops revisions superseded as of start, the first sequence number of the window
+    fn trim(&mut self, start: u64) {
+        while self
+            .written
+            .front()
+            .is_some_and(|(sequence, _)| *sequence <= start)
+        {
+            let Some((_, key)) = self.written.pop_front() else {
+                break;
+            };
+            let Some(revisions) = self.keys.get_mut(&key) else {
+                continue;
+            };
+
+            let before = revisions.partition_point(|r| r.sequence <= start);
+            let mut superseded = before.saturating_sub(1);
+            // A removal before the window reads the same as no revision
+            if before > 0 && revisions[before - 1].value.is_none() {
+                superseded = before;
+            }
+            revisions.drain(..superseded);
+            if revisions.is_empty() {
+                self.keys.remove(&key);
+            }
+        }
+    }
+
+    /// Returns the sequence numbers as of which values can be read, given the latest one
+    fn window(&self, latest: u64) -> Result<(u64, u64)> {
+        let retention = self.retention.ok_or(KvStoreError::HistoryDisabled)?;
+        Ok((latest.saturating_sub(retention), latest))
+    }
+
+    /// Returns the value of key as of sequence, given the latest sequence number
+    ///
+    /// # Errors
+    /// Returns `Err` if history is disabled or sequence is outside the window
+    pub(crate) fn get_at(&self, key: &str, sequence: u64, latest: u64) -> Result<Option<String>> {
+        let (start, end) = self.window(latest)?;
+        if !(start..=end).contains(&sequence) {
+            return Err(KvStoreError::HistoryUnavailable(sequence, start, end));
+        }
+
+        let Some(revisions) = self.keys.get(key) else {
+            return Ok(None);
+        };
+        let after = revisions.partition_point(|r| r.sequence <= sequence);
+        Ok(after
+            .checked_sub(1)
+            .and_then(|i| revisions[i].value.clone()))
+    }
+
+    /// Returns the revisions of key in the window, given the latest sequence number
+    ///
+    /// # Errors
+    /// Returns `Err` if history is disabled
+    pub(crate) fn revisions(&self, key: &str, latest: u64) -> Result<Vec<Revision>> {
+        self.window(latest)?;
+        Ok(self.keys.get(key).cloned().unwrap_or_default())
+    }
+}
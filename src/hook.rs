@@ -0,0 +1,28 @@
+//! Callbacks intercepting the commands a KV store executes
+
+use crate::{Command, KvStoreError, Result};
+
+/// Callbacks run around each command a store executes, registered with
+/// [`OpenOptions::hook`](crate::OpenOptions::hook), e.g. to validate, audit or measure commands
+///
+/// Hooks see the commands given to [`KvStore::execute`](crate::KvStore::execute), as by the
+/// `kvs` CLI, and the reads and writes of clients of the servers. Records replayed from the log
+/// or applied from a primary or Raft leader are not commands, so hooks do not see them.
+///
+/// Hooks run on the thread executing the command, in the order they were registered.
+pub trait Hook: Send + Sync {
+    /// Runs before cmd executes; returning `Err`, e.g. [`KvStoreError::Vetoed`], vetoes the
+    /// command, which fails with that error without running the hooks after this one
+    ///
+    /// # Errors
+    /// Returns `Err` to veto the command
+    fn before_execute(&self, cmd: &Command) -> Result<()> {
+        let _ = cmd;
+        Ok(())
+    }
+
+    /// Runs after cmd executed, with the error it failed with if any
+    fn after_execute(&self, cmd: &Command, error: Option<&KvStoreError>) {
+        let _ = (cmd, error);
+    }
+}
@@ -0,0 +1,179 @@
+//! HTTP REST API exposing a KV store as JSON resources
+//!
+//! | Method   | Path               | Response                                   |
+//! |----------|--------------------|--------------------------------------------|
+//! | `GET`    | `/keys/{key}`      | `{"key": …, "value": …}` or 404            |
+//! | `PUT`    | `/keys/{key}`      | 204; request body is the value             |
+//! | `DELETE` | `/keys/{key}`      | 204 or 404                                 |
+//! | `GET`    | `/keys?prefix=…`   | `[{"key": …, "value": …}, …]` sorted by key |
+//! | `GET`    | `/healthz`         | [`Health`] as JSON; 503 if not ready        |
+//!
+//! Errors are returned as `{"error": …}`. Handlers run store operations on the blocking thread
+//! pool of the runtime, since they may wait on locks and disk I/O.
+
+use crate::{Command, Health, KvStore, KvStoreError};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{io, panic};
+use tokio::{
+    net::{TcpListener, ToSocketAddrs},
+    task,
+};
+
+/// Key-value pair as returned by the API
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct Entry {
+    /// Key string
+    pub key: String,
+    /// Value string
+    pub value: String,
+}
+
+/// Query parameters of the key listing
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    #[serde(default)]
+    prefix: String,
+}
+
+/// Returns a router serving the API for store
+pub fn router(store: KvStore) -> Router {
+    let router = Router::new()
+        .route("/keys", get(list))
+        .route("/keys/{key}", get(get_key).put(put_key).delete(delete_key))
+        .route("/healthz", get(healthz));
+
+    #[cfg(feature = "metrics")]
+    let router = router.route("/metrics", get(metrics));
+
+    router.with_state(store)
+}
+
+/// Serves the API for store on address until the process exits
+///
+/// # Errors
+/// Returns `Err` if binding or accepting connections fails
+pub async fn serve(store: KvStore, addr: impl ToSocketAddrs) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    tracing::info!(addr = %listener.local_addr()?, "HTTP server listening");
+    axum::serve(listener, router(store)).await
+}
+
+async fn get_key(
+    State(store): State<KvStore>,
+    Path(key): Path<String>,
+) -> Result<Json<Entry>, ApiError> {
+    let lookup = key.clone();
+    match blocking(move || store.get_hooked(lookup)).await? {
+        Some(value) => Ok(Json(Entry { key, value })),
+        None => Err(ApiError::NotFound(key)),
+    }
+}
+
+async fn put_key(
+    State(store): State<KvStore>,
+    Path(key): Path<String>,
+    value: String,
+) -> Result<StatusCode, ApiError> {
+    blocking(move || {
+        store.check_writable()?;
+        store.execute(Command::Set {
+            key,
+            value,
+            path: None,
+            nx: false,
+            xx: false,
+        })
+    })
+    .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_key(
+    State(store): State<KvStore>,
+    Path(key): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    blocking(move || {
+        store.check_writable()?;
+        store.execute(Command::Rm { key, prefix: None })
+    })
+    .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list(
+    State(store): State<KvStore>,
+    Query(query): Query<ListQuery>,
+) -> Result<Json<Vec<Entry>>, ApiError> {
+    Ok(Json(
+        blocking(move || store.scan(&query.prefix))
+            .await?
+            .into_iter()
+            .map(|(key, value)| Entry { key, value })
+            .collect(),
+    ))
+}
+
+async fn healthz(State(store): State<KvStore>) -> (StatusCode, Json<Health>) {
+    let health = blocking(move || store.health()).await;
+    let status = match health.problem(None) {
+        None => StatusCode::OK,
+        Some(_) => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(health))
+}
+
+#[cfg(feature = "metrics")]
+async fn metrics(State(store): State<KvStore>) -> String {
+    store.metrics().render()
+}
+
+/// Runs op on the blocking thread pool, resuming its panic if any
+async fn blocking<T: Send + 'static>(op: impl FnOnce() -> T + Send + 'static) -> T {
+    task::spawn_blocking(op)
+        .await
+        .unwrap_or_else(|e| panic::resume_unwind(e.into_panic()))
+}
+
+/// Error response of the API
+enum ApiError {
+    NotFound(String),
+    TooLarge(KvStoreError),
+    ReadOnly(KvStoreError),
+    OverBudget(KvStoreError),
+    Store(KvStoreError),
+}
+
+impl From<KvStoreError> for ApiError {
+    fn from(e: KvStoreError) -> Self {
+        match e {
+            KvStoreError::FailedRm(key) => Self::NotFound(key),
+            e @ (KvStoreError::KeyTooLarge(..) | KvStoreError::ValueTooLarge(..)) => {
+                Self::TooLarge(e)
+            }
+            e @ (KvStoreError::ReadOnly | KvStoreError::ReadOnlyReplica(_)) => Self::ReadOnly(e),
+            e @ KvStoreError::OutOfMemoryBudget(..) => Self::OverBudget(e),
+            e => Self::Store(e),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            Self::NotFound(key) => (StatusCode::NOT_FOUND, format!("Key not found: {key}")),
+            Self::TooLarge(e) => (StatusCode::PAYLOAD_TOO_LARGE, e.to_string()),
+            Self::ReadOnly(e) => (StatusCode::FORBIDDEN, e.to_string()),
+            Self::OverBudget(e) => (StatusCode::INSUFFICIENT_STORAGE, e.to_string()),
+            Self::Store(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.report()),
+        };
+        (status, Json(json!({ "error": message }))).into_response()
+    }
+}
@@ -0,0 +1,755 @@
+//! Import of the string keys of Redis dumps, to migrate simple string workloads from Redis, and
+//! of SQLite files as written by `kvs export` with the `sqlite` feature
+//!
+//! Both persistence formats of Redis are read: RDB snapshots, and AOF logs of the commands
+//! Redis executed, including those starting with an RDB preamble and the base and incremental
+//! files of a multi-part AOF, given in order. Keys of one Redis database are imported; keys of
+//! other types, and AOF commands on them, are skipped.
+//!
+//! The store has no expiry, so TTLs only serve to skip the keys already expired when imported;
+//! the others are imported for good. Relative TTLs in AOF commands are taken as relative to
+//! the time of the import.
+
+use crate::{KvStore, KvStoreError, Result};
+use clap::ValueEnum;
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use strum::Display;
+use tracing::{info, warn};
+
+/// Magic starting RDB files, and the RDB preamble of AOF files
+const RDB_MAGIC: &[u8] = b"REDIS";
+
+/// Latest RDB format version read
+const RDB_VERSION: u32 = 12;
+
+/// Format of a file to import
+#[derive(Clone, Copy, Debug, Default, Display, PartialEq, Eq, ValueEnum)]
+#[strum(serialize_all = "kebab-case")]
+pub enum ImportFormat {
+    /// Redis RDB snapshot, e.g. `dump.rdb`
+    #[default]
+    RedisRdb,
+    /// Redis AOF log, e.g. `appendonly.aof`
+    RedisAof,
+    /// SQLite database with a `kv` table, see [`crate::sqlite`]
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+/// Outcome of [`import`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Imported {
+    /// String keys imported
+    pub keys: u64,
+    /// Keys imported without the TTL they had
+    pub ttls_dropped: u64,
+    /// Keys skipped as already expired
+    pub expired: u64,
+    /// Keys skipped as not strings
+    pub other_types: u64,
+    /// Keys skipped as their key or value is not valid UTF-8
+    pub not_utf8: u64,
+    /// AOF commands skipped as unsupported
+    pub skipped_commands: u64,
+}
+
+impl fmt::Display for Imported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Imported {} keys", self.keys)?;
+        if self.ttls_dropped > 0 {
+            write!(f, ", dropping {} TTLs", self.ttls_dropped)?;
+        }
+        let skipped: Vec<_> = [
+            (self.expired, "expired keys"),
+            (self.other_types, "keys of other types"),
+            (self.not_utf8, "keys not in UTF-8"),
+            (self.skipped_commands, "unsupported commands"),
+        ]
+        .into_iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, what)| format!("{count} {what}"))
+        .collect();
+        if !skipped.is_empty() {
+            write!(f, "; skipped {}", skipped.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Imports the keys of the files at paths, in order, into store, setting them over those of the
+/// store
+///
+/// Of Redis dumps, only the string keys of database db are imported. They are read whole
+/// before any key is written.
+///
+/// # Errors
+/// Returns `Err` if a file cannot be read or is malformed, or writing a key fails
+pub fn import(
+    store: &KvStore,
+    paths: &[impl AsRef<Path>],
+    format: ImportFormat,
+    db: u64,
+) -> Result<Imported> {
+    match format {
+        ImportFormat::RedisRdb => import_redis(store, paths, false, db),
+        ImportFormat::RedisAof => import_redis(store, paths, true, db),
+        #[cfg(feature = "sqlite")]
+        ImportFormat::Sqlite => {
+            let mut imported = Imported::default();
+            for path in paths {
+                let file = crate::sqlite::import(store, path)?;
+                imported.keys += file.keys;
+                imported.not_utf8 += file.not_utf8;
+            }
+            Ok(imported)
+        }
+    }
+}
+
+/// Imports the string keys of Redis database db from the RDB or AOF files at paths
+fn import_redis(
+    store: &KvStore,
+    paths: &[impl AsRef<Path>],
+    aof: bool,
+    db: u64,
+) -> Result<Imported> {
+    let now = millis(store.clock().now());
+    let mut dataset = Dataset::new(db, now);
+    for path in paths {
+        let path = path.as_ref();
+        let bytes = fs::read(path).map_err(KvStoreError::FailedImport)?;
+        let parsed = if aof {
+            load_aof(&bytes, &mut dataset)
+        } else {
+            Rdb::new(&bytes).load(&mut dataset).map(drop)
+        };
+        parsed.map_err(|e| KvStoreError::InvalidImport(format!("{}: {e}", path.display())))?;
+        info!(path = %path.display(), "Read Redis dump");
+    }
+
+    let mut imported = Imported {
+        skipped_commands: dataset.skipped_commands,
+        ..Imported::default()
+    };
+    for (key, entry) in dataset.keys {
+        if entry.expires_at.is_some_and(|at| at <= now) {
+            imported.expired += 1;
+            continue;
+        }
+        let Some(value) = entry.value else {
+            imported.other_types += 1;
+            continue;
+        };
+        let (Ok(key), Ok(value)) = (String::from_utf8(key), String::from_utf8(value)) else {
+            imported.not_utf8 += 1;
+            continue;
+        };
+        store.set(key, value)?;
+        imported.keys += 1;
+        if entry.expires_at.is_some() {
+            imported.ttls_dropped += 1;
+        }
+    }
+    Ok(imported)
+}
+
+/// Key of a Redis database
+#[derive(Debug, Default)]
+struct Entry {
+    /// Value of a string key, `None` for other types
+    value: Option<Vec<u8>>,
+    /// Expiry in milliseconds since the Unix epoch, if any
+    expires_at: Option<u64>,
+}
+
+/// Keys of the Redis database imported, as of the records read so far
+#[derive(Debug)]
+struct Dataset {
+    db: u64,
+    /// Database the records read apply to
+    selected: u64,
+    keys: HashMap<Vec<u8>, Entry>,
+    /// Import time in milliseconds since the Unix epoch
+    now: u64,
+    skipped_commands: u64,
+}
+
+impl Dataset {
+    fn new(db: u64, now: u64) -> Self {
+        Self {
+            db,
+            selected: 0,
+            keys: HashMap::new(),
+            now,
+            skipped_commands: 0,
+        }
+    }
+
+    /// Returns the keys of the selected database, or `None` if not imported
+    fn selected(&mut self) -> Option<&mut HashMap<Vec<u8>, Entry>> {
+        (self.selected == self.db).then_some(&mut self.keys)
+    }
+}
+
+/// Reader of RDB files, see <https://rdb.fnordig.de/file_format.html>
+struct Rdb<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    version: u32,
+}
+
+/// Length of an RDB string, or how the string is encoded instead
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+impl<'a> Rdb<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            pos: 0,
+            version: 0,
+        }
+    }
+
+    fn error(&self, message: impl fmt::Display) -> String {
+        format!("{message} at byte {}", self.pos)
+    }
+
+    fn take(&mut self, n: usize) -> std::result::Result<&'a [u8], String> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos.saturating_add(n))
+            .ok_or_else(|| self.error("unexpected end of file"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> std::result::Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> std::result::Result<[u8; N], String> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn length_or_encoding(&mut self) -> std::result::Result<Length, String> {
+        let first = self.u8()?;
+        Ok(match first >> 6 {
+            0 => Length::Len(u64::from(first & 0x3f)),
+            1 => Length::Len(u64::from(first & 0x3f) << 8 | u64::from(self.u8()?)),
+            2 if first == 0x80 => Length::Len(u64::from(u32::from_be_bytes(self.array()?))),
+            2 if first == 0x81 => Length::Len(u64::from_be_bytes(self.array()?)),
+            2 => return Err(self.error(format!("invalid length {first:#04x}"))),
+            _ => Length::Encoded(first & 0x3f),
+        })
+    }
+
+    fn length(&mut self) -> std::result::Result<u64, String> {
+        match self.length_or_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => Err(self.error("expected a length")),
+        }
+    }
+
+    fn usize(&mut self) -> std::result::Result<usize, String> {
+        let len = self.length()?;
+        usize::try_from(len).map_err(|_| self.error(format!("length {len} out of range")))
+    }
+
+    fn string(&mut self) -> std::result::Result<Vec<u8>, String> {
+        match self.length_or_encoding()? {
+            Length::Len(len) => {
+                let len = usize::try_from(len)
+                    .map_err(|_| self.error(format!("length {len} out of range")))?;
+                Ok(self.take(len)?.to_vec())
+            }
+            Length::Encoded(0) => Ok(i8::from_le_bytes(self.array()?).to_string().into_bytes()),
+            Length::Encoded(1) => Ok(i16::from_le_bytes(self.array()?).to_string().into_bytes()),
+            Length::Encoded(2) => Ok(i32::from_le_bytes(self.array()?).to_string().into_bytes()),
+            Length::Encoded(3) => {
+                let compressed = self.usize()?;
+                let len = self.usize()?;
+                let input = self.take(compressed)?;
+                lzf_decompress(input, len).ok_or_else(|| self.error("invalid LZF string"))
+            }
+            Length::Encoded(encoding) => {
+                Err(self.error(format!("unknown string encoding {encoding}")))
+            }
+        }
+    }
+
+    /// Skips n strings
+    fn skip_strings(&mut self, n: u64) -> std::result::Result<(), String> {
+        for _ in 0..n {
+            self.string()?;
+        }
+        Ok(())
+    }
+
+    /// Reads the records of the file into dataset, returning the offset of what follows it
+    ///
+    /// AOF files may continue after their RDB preamble.
+    fn load(mut self, dataset: &mut Dataset) -> std::result::Result<usize, String> {
+        if self.take(RDB_MAGIC.len()).ok() != Some(RDB_MAGIC) {
+            return Err("not an RDB file".to_owned());
+        }
+        let version = std::str::from_utf8(self.take(4)?)
+            .ok()
+            .and_then(|version| version.parse().ok())
+            .ok_or_else(|| self.error("malformed RDB version"))?;
+        if version > RDB_VERSION {
+            return Err(format!("unsupported RDB version {version}"));
+        }
+        self.version = version;
+
+        let mut expires_at = None;
+        loop {
+            match self.u8()? {
+                // EOF, followed by a CRC64 checksum from version 5 on
+                0xff => {
+                    if version >= 5 {
+                        self.take(8)?;
+                    }
+                    return Ok(self.pos);
+                }
+                // SELECTDB
+                0xfe => dataset.selected = self.length()?,
+                // EXPIRETIME, in seconds
+                0xfd => {
+                    let secs = u32::from_le_bytes(self.array()?);
+                    expires_at = Some(u64::from(secs) * 1000);
+                }
+                // EXPIRETIME_MS
+                0xfc => expires_at = Some(u64::from_le_bytes(self.array()?)),
+                // RESIZEDB
+                0xfb => {
+                    self.length()?;
+                    self.length()?;
+                }
+                // AUX
+                0xfa => self.skip_strings(2)?,
+                // FREQ
+                0xf9 => {
+                    self.u8()?;
+                }
+                // IDLE
+                0xf8 => {
+                    self.length()?;
+                }
+                // MODULE_AUX
+                0xf7 => return Err(self.error("module data is not supported")),
+                // FUNCTION_PRE_GA
+                0xf6 => return Err(self.error("functions of Redis 7.0 RC are not supported")),
+                // FUNCTION2
+                0xf5 => self.skip_strings(1)?,
+                // SLOT_INFO
+                0xf4 => {
+                    for _ in 0..3 {
+                        self.length()?;
+                    }
+                }
+                value_type => {
+                    let key = self.string()?;
+                    let value = self.value(value_type)?;
+                    let expires_at = expires_at.take();
+                    if let Some(keys) = dataset.selected() {
+                        keys.insert(key, Entry { value, expires_at });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads the value of a key of the given type, returning it if a string
+    fn value(&mut self, value_type: u8) -> std::result::Result<Option<Vec<u8>>, String> {
+        match value_type {
+            // String
+            0 => return self.string().map(Some),
+            // List, set, quicklist of ziplists
+            1 | 2 | 14 => {
+                let n = self.length()?;
+                self.skip_strings(n)?;
+            }
+            // Sorted set, with scores as strings
+            3 => {
+                for _ in 0..self.length()? {
+                    self.string()?;
+                    match self.u8()? {
+                        // NaN and infinities
+                        253..=255 => {}
+                        len => {
+                            self.take(len.into())?;
+                        }
+                    }
+                }
+            }
+            // Hash
+            4 => {
+                let n = self.length()?;
+                self.skip_strings(n.saturating_mul(2))?;
+            }
+            // Sorted set, with binary scores
+            5 => {
+                for _ in 0..self.length()? {
+                    self.string()?;
+                    self.take(8)?;
+                }
+            }
+            // Zipmap, ziplist, intset and listpack encodings
+            9..=13 | 16 | 17 | 20 => {
+                self.string()?;
+            }
+            // Quicklist of listpacks
+            18 => {
+                for _ in 0..self.length()? {
+                    self.length()?;
+                    self.string()?;
+                }
+            }
+            // Stream
+            15 | 19 | 21 => self.skip_stream(value_type)?,
+            6 | 7 => return Err(self.error("module values are not supported")),
+            _ => return Err(self.error(format!("unsupported value type {value_type}"))),
+        }
+        Ok(None)
+    }
+
+    /// Skips a stream value, of type 15, or 19 and 21 for later versions
+    fn skip_stream(&mut self, value_type: u8) -> std::result::Result<(), String> {
+        let nodes = self.length()?;
+        self.skip_strings(nodes.saturating_mul(2))?;
+        // Length, last ID, and from type 19 on first ID, max deleted ID and entries added
+        let lengths = if value_type >= 19 { 8 } else { 3 };
+        for _ in 0..lengths {
+            self.length()?;
+        }
+        for _ in 0..self.length()? {
+            // Name and last delivered ID, and from type 19 on entries read
+            self.string()?;
+            self.length()?;
+            self.length()?;
+            if value_type >= 19 {
+                self.length()?;
+            }
+            // Pending entries: ID, delivery time and count
+            for _ in 0..self.length()? {
+                self.take(24)?;
+                self.length()?;
+            }
+            // Consumers: name, seen time, from type 21 on active time, and pending IDs
+            for _ in 0..self.length()? {
+                self.string()?;
+                self.take(if value_type >= 21 { 16 } else { 8 })?;
+                let pending = self.usize()?;
+                self.take(pending.saturating_mul(16))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decompresses LZF input into len bytes, or `None` if malformed
+///
+/// The length comes from the file, so the output is only reserved up to what input can
+/// decompress to, 264 bytes from each 3-byte back reference.
+fn lzf_decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(len.min(input.len().saturating_mul(88)));
+    let mut i = 0;
+    while i < input.len() {
+        if output.len() > len {
+            return None;
+        }
+        let control = usize::from(input[i]);
+        i += 1;
+        if control < 32 {
+            // Literal run
+            let run = input.get(i..i + control + 1)?;
+            output.extend_from_slice(run);
+            i += control + 1;
+        } else {
+            // Back reference
+            let mut run = control >> 5;
+            if run == 7 {
+                run += usize::from(*input.get(i)?);
+                i += 1;
+            }
+            let offset = ((control & 0x1f) << 8) + usize::from(*input.get(i)?) + 1;
+            i += 1;
+            let start = output.len().checked_sub(offset)?;
+            for k in start..start + run + 2 {
+                output.push(output[k]);
+            }
+        }
+    }
+    (output.len() == len).then_some(output)
+}
+
+/// Applies the commands of an AOF file to dataset, after its RDB preamble if any
+///
+/// A command cut short at the end of the file, as left by a crash of Redis, is ignored.
+fn load_aof(bytes: &[u8], dataset: &mut Dataset) -> std::result::Result<(), String> {
+    let mut pos = 0;
+    if bytes.starts_with(RDB_MAGIC) {
+        pos = Rdb::new(bytes).load(dataset)?;
+    }
+    while pos < bytes.len() {
+        let Some(args) = resp_command(bytes, &mut pos)? else {
+            warn!(
+                bytes = bytes.len() - pos,
+                "Ignored truncated command at the end of AOF"
+            );
+            break;
+        };
+        apply(dataset, &args);
+    }
+    Ok(())
+}
+
+/// Reads a command encoded as a RESP array of bulk strings at pos, or `None` if cut short
+fn resp_command(
+    bytes: &[u8],
+    pos: &mut usize,
+) -> std::result::Result<Option<Vec<Vec<u8>>>, String> {
+    // Reads a line ended by CRLF
+    let line = |pos: &mut usize| -> Option<&[u8]> {
+        let rest = bytes.get(*pos..)?;
+        let end = rest.windows(2).position(|w| w == b"\r\n")?;
+        *pos += end + 2;
+        Some(&rest[..end])
+    };
+    let number = |line: &[u8], prefix: u8, at: usize| {
+        line.strip_prefix(&[prefix])
+            .and_then(|n| std::str::from_utf8(n).ok())
+            .and_then(|n| n.parse::<usize>().ok())
+            .ok_or_else(|| format!("malformed AOF command at byte {at}"))
+    };
+
+    let start = *pos;
+    let Some(header) = line(pos) else {
+        return Ok(None);
+    };
+    let count = number(header, b'*', start)?;
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let at = *pos;
+        let Some(header) = line(pos) else {
+            return Ok(None);
+        };
+        let len = number(header, b'$', at)?;
+        let end = pos
+            .checked_add(len)
+            .and_then(|end| end.checked_add(2))
+            .ok_or_else(|| format!("malformed AOF command at byte {at}"))?;
+        let Some(arg) = bytes.get(*pos..end - 2) else {
+            return Ok(None);
+        };
+        if bytes.get(end - 2..end).is_none() {
+            return Ok(None);
+        }
+        args.push(arg.to_vec());
+        *pos = end;
+    }
+    Ok(Some(args))
+}
+
+/// Applies an AOF command to dataset
+///
+/// Commands writing string keys, removing keys, or setting their expiry are applied; commands
+/// writing keys of other types mark them as such. Unknown commands are counted as skipped.
+#[allow(clippy::too_many_lines)] // One arm per command
+fn apply(dataset: &mut Dataset, args: &[Vec<u8>]) {
+    let Some((name, args)) = args.split_first() else {
+        return;
+    };
+    let name = String::from_utf8_lossy(name).to_ascii_uppercase();
+    let now = dataset.now;
+    if name == "SELECT" {
+        if let Some(db) = args.first().and_then(|db| number(db)) {
+            dataset.selected = db;
+        }
+        return;
+    }
+    if name == "FLUSHALL" {
+        dataset.keys.clear();
+        return;
+    }
+    let Some(keys) = dataset.selected() else {
+        return;
+    };
+
+    match (name.as_str(), args) {
+        ("SET", [key, value, options @ ..]) => set_with_options(keys, key, value, options, now),
+        ("SETNX", [key, value]) => {
+            if !is_live(keys, key, now) {
+                set(keys, key, value.clone(), None);
+            }
+        }
+        ("SETEX", [key, secs, value]) => {
+            let expires_at = number(secs).map(|secs| now.saturating_add(secs.saturating_mul(1000)));
+            set(keys, key, value.clone(), expires_at);
+        }
+        ("PSETEX", [key, ms, value]) => {
+            let expires_at = number(ms).map(|ms| now.saturating_add(ms));
+            set(keys, key, value.clone(), expires_at);
+        }
+        ("MSET", pairs) if pairs.len() % 2 == 0 => {
+            for pair in pairs.chunks(2) {
+                set(keys, &pair[0], pair[1].clone(), None);
+            }
+        }
+        ("MSETNX", pairs) if pairs.len() % 2 == 0 => {
+            if pairs.chunks(2).all(|pair| !keys.contains_key(&pair[0])) {
+                for pair in pairs.chunks(2) {
+                    set(keys, &pair[0], pair[1].clone(), None);
+                }
+            }
+        }
+        ("GETSET", [key, value]) => set(keys, key, value.clone(), None),
+        ("APPEND", [key, suffix]) => {
+            let entry = keys.entry(key.clone()).or_default();
+            entry
+                .value
+                .get_or_insert_with(Vec::new)
+                .extend_from_slice(suffix);
+        }
+        ("INCR" | "DECR" | "INCRBY" | "DECRBY", [key, by @ ..]) => {
+            let by = match by {
+                [] => Some(1),
+                [by] => std::str::from_utf8(by)
+                    .ok()
+                    .and_then(|by| by.parse::<i64>().ok()),
+                _ => None,
+            };
+            let sign = if name.starts_with("DECR") { -1 } else { 1 };
+            let entry = keys.entry(key.clone()).or_default();
+            let current = entry.value.as_deref().map_or(Some(0), |value| {
+                std::str::from_utf8(value).ok()?.parse::<i64>().ok()
+            });
+            if let Some(value) = current
+                .zip(by)
+                .and_then(|(current, by)| current.checked_add(sign * by))
+            {
+                entry.value = Some(value.to_string().into_bytes());
+            }
+        }
+        ("DEL" | "UNLINK" | "GETDEL", keys_removed) => {
+            for key in keys_removed {
+                keys.remove(key);
+            }
+        }
+        ("RENAME", [from, to]) => {
+            if let Some(entry) = keys.remove(from) {
+                keys.insert(to.clone(), entry);
+            }
+        }
+        ("EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT", [key, at, ..]) => {
+            let at = number(at).map(|at| match name.as_str() {
+                "EXPIRE" => now.saturating_add(at.saturating_mul(1000)),
+                "PEXPIRE" => now.saturating_add(at),
+                "EXPIREAT" => at.saturating_mul(1000),
+                _ => at,
+            });
+            if let Some(entry) = keys.get_mut(key) {
+                entry.expires_at = at;
+            }
+        }
+        ("PERSIST", [key]) => {
+            if let Some(entry) = keys.get_mut(key) {
+                entry.expires_at = None;
+            }
+        }
+        ("FLUSHDB", _) => keys.clear(),
+        // Transactions are applied as their commands
+        ("MULTI" | "EXEC", _) => {}
+        (name, [key, ..]) if is_other_type_write(name) => {
+            keys.entry(key.clone()).or_default().value = None;
+        }
+        _ => dataset.skipped_commands += 1,
+    }
+}
+
+/// Applies a SET command with its options
+fn set_with_options(
+    keys: &mut HashMap<Vec<u8>, Entry>,
+    key: &[u8],
+    value: &[u8],
+    options: &[Vec<u8>],
+    now: u64,
+) {
+    let mut expires_at = None;
+    let mut keep_ttl = false;
+    let mut condition = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let option = String::from_utf8_lossy(option).to_ascii_uppercase();
+        let arg = || options.clone().next().and_then(|arg| number(arg));
+        match option.as_str() {
+            "EX" => expires_at = arg().map(|secs| now.saturating_add(secs.saturating_mul(1000))),
+            "PX" => expires_at = arg().map(|ms| now.saturating_add(ms)),
+            "EXAT" => expires_at = arg().map(|secs| secs.saturating_mul(1000)),
+            "PXAT" => expires_at = arg(),
+            "KEEPTTL" => keep_ttl = true,
+            "NX" | "XX" => condition = Some(option == "XX"),
+            _ => {}
+        }
+        if matches!(option.as_str(), "EX" | "PX" | "EXAT" | "PXAT") {
+            options.next();
+        }
+    }
+    if condition.is_some_and(|xx| xx != is_live(keys, key, now)) {
+        return;
+    }
+    if keep_ttl {
+        expires_at = keys.get(key).and_then(|e| e.expires_at);
+    }
+    set(keys, key, value.to_vec(), expires_at);
+}
+
+/// Returns whether a key exists and has not expired
+fn is_live(keys: &HashMap<Vec<u8>, Entry>, key: &[u8], now: u64) -> bool {
+    keys.get(key)
+        .is_some_and(|e| e.expires_at.is_none_or(|at| at > now))
+}
+
+/// Sets a string key of a database
+fn set(keys: &mut HashMap<Vec<u8>, Entry>, key: &[u8], value: Vec<u8>, expires_at: Option<u64>) {
+    keys.insert(
+        key.to_vec(),
+        Entry {
+            value: Some(value),
+            expires_at,
+        },
+    );
+}
+
+/// Returns whether the command writes a key of a type other than string, given its first
+/// argument
+fn is_other_type_write(name: &str) -> bool {
+    const PREFIXES: [&str; 6] = ["L", "R", "S", "Z", "H", "X"];
+    const COMMANDS: [&str; 3] = ["PFADD", "PFMERGE", "GEOADD"];
+    COMMANDS.contains(&name)
+        || (PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+            && !matches!(
+                name,
+                "SET" | "SETNX" | "SETEX" | "SETRANGE" | "SWAPDB" | "SCRIPT" | "SAVE" | "SHUTDOWN"
+            ))
+}
+
+/// Parses a decimal argument
+fn number(arg: &[u8]) -> Option<u64> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX))
+}
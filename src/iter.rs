@@ -0,0 +1,46 @@
+//! Iteration over the key-value pairs of a store
+
+use std::{iter::FusedIterator, vec};
+
+/// Iterator over the key-value pairs of the default key space of a [`KvStore`], in key order,
+/// returned by [`KvStore::iter`]
+///
+/// Pairs are those of a snapshot taken when the iterator was created: writes made since are
+/// not visible, and no key is skipped or repeated whatever writes happen while iterating.
+///
+/// [`KvStore`]: crate::KvStore
+/// [`KvStore::iter`]: crate::KvStore::iter
+#[derive(Debug)]
+pub struct Iter {
+    entries: vec::IntoIter<(String, String)>,
+}
+
+impl Iter {
+    pub(crate) fn new(entries: Vec<(String, String)>) -> Self {
+        Self {
+            entries: entries.into_iter(),
+        }
+    }
+}
+
+impl Iterator for Iter {
+    type Item = (String, String);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for Iter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.entries.next_back()
+    }
+}
+
+impl ExactSizeIterator for Iter {}
+
+impl FusedIterator for Iter {}
@@ -0,0 +1,409 @@
+//! Maps of keys to where their records are in the log, hashed or prefix-compressed
+//!
+//! A radix tree stores each run of bytes shared by the keys below a node once, in the label of
+//! the node, so that keys such as `user:12345:profile` and `user:12346:profile` cost little
+//! more than their distinct bytes. Its nodes are laid out by key, so keys starting with a
+//! prefix are found without visiting the others.
+
+use std::{borrow::Cow, collections::HashMap, mem};
+
+/// Approximate bytes of memory a hash map entry takes besides its key
+const ENTRY_BYTES: u64 = 64;
+
+/// How the index of the log maps keys to their records, see
+/// [`OpenOptions::key_index`](crate::OpenOptions::key_index)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyIndex {
+    /// Hash map holding each key in full
+    #[default]
+    Hashed,
+    /// Radix tree holding prefixes shared by keys once
+    Radix,
+}
+
+/// Map of keys to values, of the kind chosen by [`KeyIndex`]
+#[derive(Debug)]
+pub(crate) enum KeyMap<V> {
+    Hashed {
+        map: HashMap<String, V>,
+        /// Approximate bytes of memory taken by the entries
+        bytes: u64,
+    },
+    Radix(RadixMap<V>),
+}
+
+impl<V> KeyMap<V> {
+    pub(crate) fn new(kind: KeyIndex) -> Self {
+        match kind {
+            KeyIndex::Hashed => Self::Hashed {
+                map: HashMap::new(),
+                bytes: 0,
+            },
+            KeyIndex::Radix => Self::Radix(RadixMap::default()),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&V> {
+        match self {
+            Self::Hashed { map, .. } => map.get(key),
+            Self::Radix(map) => map.get(key),
+        }
+    }
+
+    pub(crate) fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Maps key to value, returning the value it replaces
+    pub(crate) fn insert(&mut self, key: &str, value: V) -> Option<V> {
+        match self {
+            Self::Hashed { map, bytes } => {
+                let replaced = map.insert(key.to_owned(), value);
+                if replaced.is_none() {
+                    *bytes += key.len() as u64 + ENTRY_BYTES;
+                }
+                replaced
+            }
+            Self::Radix(map) => map.insert(key, value),
+        }
+    }
+
+    /// Unmaps key, returning its value
+    pub(crate) fn remove(&mut self, key: &str) -> Option<V> {
+        match self {
+            Self::Hashed { map, bytes } => {
+                let removed = map.remove(key);
+                if removed.is_some() {
+                    *bytes -= key.len() as u64 + ENTRY_BYTES;
+                }
+                removed
+            }
+            Self::Radix(map) => map.remove(key),
+        }
+    }
+
+    /// Unmaps the keys starting with prefix, returning their values
+    pub(crate) fn remove_prefix(&mut self, prefix: &str) -> Vec<V> {
+        let keys: Vec<String> = self
+            .iter_prefix(prefix)
+            .map(|(key, _)| key.into_owned())
+            .collect();
+        keys.iter().filter_map(|key| self.remove(key)).collect()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Self::Hashed { map, .. } => map.len(),
+            Self::Radix(map) => map.len,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the approximate bytes of memory taken by the keys and values
+    pub(crate) fn bytes(&self) -> u64 {
+        match self {
+            Self::Hashed { bytes, .. } => *bytes,
+            Self::Radix(map) => map.bytes(),
+        }
+    }
+
+    /// Returns the keys starting with prefix with their values, sorted by key if a radix tree
+    pub(crate) fn iter_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> Box<dyn Iterator<Item = (Cow<'a, str>, &'a V)> + 'a> {
+        match self {
+            Self::Hashed { map, .. } => Box::new(
+                map.iter()
+                    .filter(move |(key, _)| key.starts_with(prefix))
+                    .map(|(key, value)| (Cow::Borrowed(key.as_str()), value)),
+            ),
+            Self::Radix(map) => Box::new(
+                map.iter_prefix(prefix)
+                    .map(|(key, value)| (Cow::Owned(key), value)),
+            ),
+        }
+    }
+
+    pub(crate) fn values(&self) -> Box<dyn Iterator<Item = &V> + '_> {
+        match self {
+            Self::Hashed { map, .. } => Box::new(map.values()),
+            Self::Radix(map) => Box::new(Values {
+                stack: vec![&map.root],
+            }),
+        }
+    }
+
+    pub(crate) fn values_mut(&mut self) -> Box<dyn Iterator<Item = &mut V> + '_> {
+        match self {
+            Self::Hashed { map, .. } => Box::new(map.values_mut()),
+            Self::Radix(map) => Box::new(ValuesMut {
+                stack: vec![&mut map.root],
+            }),
+        }
+    }
+}
+
+/// Radix tree mapping keys to values
+#[derive(Debug)]
+pub(crate) struct RadixMap<V> {
+    /// Node of the empty key, with an empty label
+    root: Node<V>,
+    len: usize,
+    /// Nodes besides the root
+    nodes: usize,
+    /// Bytes of the labels of the nodes
+    label_bytes: usize,
+}
+
+#[derive(Debug)]
+struct Node<V> {
+    /// Bytes of the keys below the node after those of its parent, not empty but for the root
+    label: Box<[u8]>,
+    /// Sorted by the first byte of their label, which differ
+    children: Vec<Node<V>>,
+    value: Option<V>,
+}
+
+impl<V> Default for RadixMap<V> {
+    fn default() -> Self {
+        Self {
+            root: Node::new(Box::default(), None),
+            len: 0,
+            nodes: 0,
+            label_bytes: 0,
+        }
+    }
+}
+
+impl<V> RadixMap<V> {
+    fn get(&self, key: &str) -> Option<&V> {
+        let mut node = &self.root;
+        let mut rest = key.as_bytes();
+        while let Some(&first) = rest.first() {
+            node = node.child(first)?;
+            rest = rest.strip_prefix(&*node.label)?;
+        }
+        node.value.as_ref()
+    }
+
+    fn insert(&mut self, key: &str, value: V) -> Option<V> {
+        let mut node = &mut self.root;
+        let mut rest = key.as_bytes();
+        while let Some(&first) = rest.first() {
+            let i = match node.children.binary_search_by_key(&first, Node::first) {
+                Ok(i) => i,
+                Err(i) => {
+                    node.children.insert(i, Node::new(rest.into(), Some(value)));
+                    self.len += 1;
+                    self.nodes += 1;
+                    self.label_bytes += rest.len();
+                    return None;
+                }
+            };
+            let child = &mut node.children[i];
+            let common = child
+                .label
+                .iter()
+                .zip(rest)
+                .take_while(|(a, b)| a == b)
+                .count();
+            if common < child.label.len() {
+                // The key branches off within the label, which is split there
+                let parent = Node::new(child.label[..common].into(), None);
+                let mut split = mem::replace(child, parent);
+                split.label = split.label[common..].into();
+                child.children.push(split);
+                self.nodes += 1;
+            }
+            node = child;
+            rest = &rest[common..];
+        }
+
+        let replaced = node.value.replace(value);
+        if replaced.is_none() {
+            self.len += 1;
+        }
+        replaced
+    }
+
+    fn remove(&mut self, key: &str) -> Option<V> {
+        let (removed, nodes, label_bytes) = Self::remove_below(&mut self.root, key.as_bytes());
+        if removed.is_some() {
+            self.len -= 1;
+            self.nodes -= nodes;
+            self.label_bytes -= label_bytes;
+        }
+        removed
+    }
+
+    /// Removes the key of the given bytes after those of node, returning its value along with
+    /// the number of nodes and label bytes dropped by merging nodes left without value
+    fn remove_below(node: &mut Node<V>, rest: &[u8]) -> (Option<V>, usize, usize) {
+        let Some(&first) = rest.first() else {
+            return (node.value.take(), 0, 0);
+        };
+        let Ok(i) = node.children.binary_search_by_key(&first, Node::first) else {
+            return (None, 0, 0);
+        };
+        let child = &mut node.children[i];
+        let Some(rest) = rest.strip_prefix(&*child.label) else {
+            return (None, 0, 0);
+        };
+        let (removed, mut nodes, mut label_bytes) = Self::remove_below(child, rest);
+        if removed.is_some() && child.value.is_none() {
+            match child.children.len() {
+                0 => {
+                    label_bytes += node.children.remove(i).label.len();
+                    nodes += 1;
+                }
+                1 => {
+                    let only = child.children.remove(0);
+                    let label = [&*child.label, &*only.label].concat();
+                    *child = Node {
+                        label: label.into(),
+                        ..only
+                    };
+                    nodes += 1;
+                }
+                _ => {}
+            }
+        }
+        (removed, nodes, label_bytes)
+    }
+
+    /// Returns the keys starting with prefix with their values, sorted by key
+    fn iter_prefix(&self, prefix: &str) -> Iter<'_, V> {
+        let mut node = &self.root;
+        let mut path = Vec::new();
+        let mut rest = prefix.as_bytes();
+        while let Some(&first) = rest.first() {
+            let Some(child) = node.child(first) else {
+                return Iter::default();
+            };
+            if child.label.starts_with(rest) {
+                // The prefix ends within the label of child, all of whose keys start with it
+                return Iter {
+                    stack: vec![(path.len(), child)],
+                    key: path,
+                };
+            }
+            let Some(after) = rest.strip_prefix(&*child.label) else {
+                return Iter::default();
+            };
+            path.extend_from_slice(&child.label);
+            node = child;
+            rest = after;
+        }
+        Iter {
+            stack: vec![(path.len(), node)],
+            key: path,
+        }
+    }
+
+    /// Returns the approximate bytes of memory taken by the nodes
+    fn bytes(&self) -> u64 {
+        ((self.nodes + 1) * mem::size_of::<Node<V>>() + self.label_bytes) as u64
+    }
+}
+
+impl<V> Node<V> {
+    fn new(label: Box<[u8]>, value: Option<V>) -> Self {
+        Self {
+            label,
+            children: Vec::new(),
+            value,
+        }
+    }
+
+    fn first(&self) -> u8 {
+        self.label[0]
+    }
+
+    fn child(&self, first: u8) -> Option<&Self> {
+        let i = self
+            .children
+            .binary_search_by_key(&first, Self::first)
+            .ok()?;
+        Some(&self.children[i])
+    }
+}
+
+/// Iterator over the keys and values below a node of a radix tree, in key order
+struct Iter<'a, V> {
+    /// Nodes left to visit, last first, with the length of the key above each
+    stack: Vec<(usize, &'a Node<V>)>,
+    /// Key of the last node visited
+    key: Vec<u8>,
+}
+
+impl<V> Default for Iter<'_, V> {
+    fn default() -> Self {
+        Self {
+            stack: Vec::new(),
+            key: Vec::new(),
+        }
+    }
+}
+
+impl<'a, V> Iterator for Iter<'a, V> {
+    type Item = (String, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (depth, node) = self.stack.pop()?;
+            self.key.truncate(depth);
+            self.key.extend_from_slice(&node.label);
+            let depth = self.key.len();
+            self.stack
+                .extend(node.children.iter().rev().map(|child| (depth, child)));
+            if let Some(value) = &node.value {
+                // Keys are inserted whole, so those of nodes with a value are valid UTF-8
+                return Some((String::from_utf8_lossy(&self.key).into_owned(), value));
+            }
+        }
+    }
+}
+
+/// Iterator over the values of a radix tree
+struct Values<'a, V> {
+    stack: Vec<&'a Node<V>>,
+}
+
+impl<'a, V> Iterator for Values<'a, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let node = self.stack.pop()?;
+            self.stack.extend(&node.children);
+            if let Some(value) = &node.value {
+                return Some(value);
+            }
+        }
+    }
+}
+
+/// Iterator over the values of a radix tree, mutably
+struct ValuesMut<'a, V> {
+    stack: Vec<&'a mut Node<V>>,
+}
+
+impl<'a, V> Iterator for ValuesMut<'a, V> {
+    type Item = &'a mut V;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Node {
+                children, value, ..
+            } = self.stack.pop()?;
+            self.stack.extend(children);
+            if let Some(value) = value {
+                return Some(value);
+            }
+        }
+    }
+}
@@ -3,29 +3,343 @@
 
 //! Library code for key-value (KV) store implementation
 
+extern crate alloc;
+
+use cache::ValueCache;
 use clap::Subcommand;
 use dashmap::DashMap;
+use eviction::Evictor;
+use lock::DirLock;
+use manifest::Manifest;
+use segment::Dirs;
 use serde::{
     de::{self, Deserializer, SeqAccess, Visitor},
     Deserialize, Serialize,
 };
 use std::{
+    any::Any,
+    collections::{BTreeSet, VecDeque},
     fmt,
-    fs::{self, File, OpenOptions},
+    fs::{self, File},
     io::{self, prelude::*},
+    net::SocketAddr,
+    ops::Bound,
+    panic::{self, AssertUnwindSafe},
     path::{Path, PathBuf},
     result,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, OnceLock, PoisonError, RwLock, RwLockWriteGuard,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 use strum::{Display, EnumString};
 use thiserror::Error;
+use timeseries::TimeSeries;
+use tracing::{debug, error, info, instrument, trace, warn};
+use wal::{Pending, Record, Wal};
+use watch::Watchers;
+
+#[cfg(feature = "archive")]
+pub mod archive;
+pub mod auth;
+pub mod backup;
+mod bloom;
+mod bucket;
+mod cache;
+pub mod client;
+mod clock;
+mod coalesce;
+mod codec;
+mod compaction;
+pub mod config;
+pub mod doctor;
+mod document;
+pub mod dump;
+pub mod engine;
+mod entry;
+mod eviction;
+mod facade;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod freeze;
+mod glob;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod hint;
+mod history;
+mod hook;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod import;
+mod iter;
+mod keymap;
+mod lock;
+mod manifest;
+mod memory;
+#[cfg(feature = "metrics")]
+mod metrics;
+pub mod migrate;
+mod options;
+pub mod pipe;
+pub mod protocol;
+#[cfg(feature = "raft")]
+pub mod raft;
+mod rate_limit;
+pub mod replication;
+mod runtime;
+mod secondary;
+mod segment;
+pub mod server;
+mod slowlog;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+mod stats;
+pub mod thread_pool;
+mod timeseries;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transport;
+pub mod trigger;
+mod value;
+mod wal;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+mod watch;
+
+pub use bucket::Bucket;
+pub use cache::CacheConfig;
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use codec::WalFormat;
+pub use compaction::{CompactionPolicy, SegmentInfo, SizeTiered, TimeBased};
+pub use engine::{KvsEngine, MemEngine};
+pub use entry::Entry;
+pub use eviction::{EvictionConfig, EvictionPolicy};
+pub use facade::MapFacade;
+pub use history::Revision;
+pub use hook::Hook;
+pub use iter::Iter;
+pub use keymap::KeyIndex;
+#[cfg(feature = "metrics")]
+pub use metrics::Metrics;
+pub use options::{MissHook, OpenOptions, ProgressHook, ScanOptions, SetOptions};
+pub use runtime::{KvsRuntime, RuntimeOptions};
+pub use secondary::IndexCommand;
+pub use slowlog::SlowQuery;
+pub use stats::{CompactionProgress, Health, OpenProgress, StoreStats};
+pub use timeseries::{Aggregation, Sample};
+pub use value::ValueRef;
+pub use wal::{as_client, Shipment};
+pub use watch::WatchEvent;
 
 /// Write-ahead log file name
 const WAL: &str = "wa.log";
 
+/// Number of IDs reserved per WAL record by [`KvStore::next_id`]
+const ID_BATCH: u64 = 100;
+
+/// Records replayed between progress reports within a log file, see
+/// [`OpenOptions::on_progress`]
+const PROGRESS_RECORDS: u64 = 10_000;
+
 /// Key-value (KV) store wrapper
+///
+/// # Consistency
+/// Reads are served from memory and never wait for the WAL. Writes are applied in memory and
+/// queued to a dedicated WAL writer thread while holding the lock of the key's shard, so the
+/// records of a key are logged in the order its writes became visible. A write returns once its
+/// record is written (but not necessarily synced), while concurrent readers may already observe
+/// it before. If writing the record of an applied write fails, the store is poisoned.
+///
+/// # Handles
+/// A `KvStore` is a handle to the state of the store, which it shares with its clones. Cloning
+/// is cheap, so that a server can hand a handle to each connection, and the store is closed once
+/// its last handle is dropped.
+#[derive(Clone)]
 pub struct KvStore {
+    inner: Arc<Inner>,
+}
+
+/// State of a [`KvStore`], shared by its handles
+struct Inner {
     store: DashMap<String, String>,
-    wal_handle: File,
+    /// Held shared by writes while applied to `store`, and exclusively while copying it for
+    /// [`KvStore::iter`], so that the copy is a snapshot
+    snapshot: RwLock<()>,
+    /// Version of each key, see [`KvStore::get_versioned`]
+    versions: DashMap<String, u64>,
+    /// Expiry of the keys set with a TTL, in milliseconds since the Unix epoch, see
+    /// [`SetOptions::ttl`]
+    expiries: DashMap<String, u64>,
+    /// Keys of the default key space in order, so that paged scans go through the keys of a page
+    /// alone, see [`KvStore::scan_opts`]
+    ordered: RwLock<BTreeSet<String>>,
+    /// Held shared while the version of a key is locked for a write, and exclusively by writes
+    /// of several keys, whose versions may share a lock
+    multi_key: RwLock<()>,
+    buckets: DashMap<(String, String), String>,
+    sequences: DashMap<String, IdRange>,
+    series: DashMap<String, TimeSeries>,
+    /// Lists, front first, see [`KvStore::lpush`]
+    lists: DashMap<String, VecDeque<String>>,
+    /// Sets, see [`KvStore::sadd`]
+    sets: DashMap<String, BTreeSet<String>>,
+    /// Leases of locks, expired ones included until taken again, see [`KvStore::lock`]
+    leases: DashMap<String, Lease>,
+    /// Token of the last lease taken, starting from the sequence number of the last record on
+    /// open, which no token taken before exceeds
+    last_lease: AtomicU64,
+    wal: Arc<Wal>,
+    cache: Option<ValueCache>,
+    /// Picks keys to evict in cache mode, see [`OpenOptions::eviction`]
+    evictor: Option<Evictor>,
+    /// Secondary indexes of the JSON values of the default key space, see
+    /// [`KvStore::create_index`]
+    indexes: secondary::Indexes,
+    watchers: Watchers,
+    options: OpenOptions,
+    poisoned: OnceLock<String>,
+    opened: SystemTime,
+    /// Whether writes are frozen, see [`KvStore::freeze_writes`]
+    freeze: freeze::Freeze,
+    /// Directories of the files of the store
+    dirs: Dirs,
+    /// Primary whose records the store applies, if a replica
+    replica_of: RwLock<Option<SocketAddr>>,
+    applied: replication::Applied,
+    /// Raft member, if the store belongs to a cluster
+    #[cfg(feature = "raft")]
+    cluster: Option<raft::Node>,
+    #[cfg(feature = "metrics")]
+    metrics: Arc<Metrics>,
+    /// Lock of the store directory, released last
+    _lock: DirLock,
+}
+
+impl KvsEngine for KvStore {
+    type Error = KvStoreError;
+    type Iter = Iter;
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Self::get(self, key)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        Self::set(self, key, value)
+    }
+
+    fn remove(&mut self, key: String) -> Result<Option<String>> {
+        match Self::remove(self, key) {
+            Ok(value) => Ok(Some(value)),
+            Err(KvStoreError::FailedRm(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn len(&self) -> usize {
+        Self::len(self)
+    }
+
+    fn is_empty(&self) -> bool {
+        Self::is_empty(self)
+    }
+
+    fn iter(&self) -> Result<Iter> {
+        Self::iter(self)
+    }
+}
+
+/// Progress of replaying the logs of a store on open
+#[derive(Debug)]
+struct Replay {
+    /// Sequence number of the last record replayed
+    last: u64,
+    /// Sequence number up to which records missing from the logs were dropped by compaction
+    compacted: u64,
+    progress: OpenProgress,
+    /// Bytes of the log files loaded in full
+    loaded_bytes: u64,
+    started: Instant,
+}
+
+impl Replay {
+    /// Starts replaying the log files at paths
+    fn new<'a>(paths: impl IntoIterator<Item = &'a Path>) -> Self {
+        let sizes: Vec<_> = paths
+            .into_iter()
+            .map(|path| fs::metadata(path).map_or(0, |m| m.len()))
+            .collect();
+        Self {
+            last: 0,
+            compacted: 0,
+            progress: OpenProgress {
+                segments_done: 0,
+                segments_total: sizes.len(),
+                records: 0,
+                bytes_done: 0,
+                bytes_total: sizes.iter().sum(),
+                eta: None,
+            },
+            loaded_bytes: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Counts a record frame of len bytes as replayed, returning whether progress is due to be
+    /// reported
+    fn record(&mut self, len: usize) -> bool {
+        self.progress.records += 1;
+        self.progress.bytes_done += len as u64 + 1;
+        self.progress.records.is_multiple_of(PROGRESS_RECORDS)
+    }
+
+    /// Counts the log file at path as loaded
+    fn loaded(&mut self, path: &Path) {
+        self.loaded_bytes += fs::metadata(path).map_or(0, |m| m.len());
+        self.progress.segments_done += 1;
+        self.progress.bytes_done = self.loaded_bytes;
+    }
+
+    /// Returns the progress so far, with the time left estimated
+    fn progress(&self) -> OpenProgress {
+        let OpenProgress {
+            bytes_done,
+            bytes_total,
+            ..
+        } = self.progress;
+        #[allow(clippy::cast_precision_loss)] // Byte counts far below 2^52
+        let eta = (bytes_done > 0).then(|| {
+            let left = bytes_total.saturating_sub(bytes_done) as f64 / bytes_done as f64;
+            self.started.elapsed().mul_f64(left)
+        });
+        OpenProgress {
+            eta,
+            ..self.progress
+        }
+    }
+}
+
+/// Block of reserved IDs for a named sequence
+///
+/// IDs in `next..end` have been durably reserved in the WAL but not handed out yet.
+#[derive(Debug)]
+struct IdRange {
+    next: u64,
+    end: u64,
+}
+
+impl Default for IdRange {
+    fn default() -> Self {
+        Self { next: 1, end: 1 }
+    }
+}
+
+/// Lease of a lock, see [`KvStore::lock`]
+#[derive(Debug)]
+struct Lease {
+    token: u64,
+    /// Milliseconds since the Unix epoch
+    expires: u64,
 }
 
 /// Result wrapper type for KV store methods
@@ -35,12 +349,47 @@ pub type Result<T> = result::Result<T, KvStoreError>;
 impl KvStore {
     /// Constructs a new in-memory KV store by parsing on-disk write-ahead log (WAL)
     ///
+    /// Uses default [`OpenOptions`].
+    ///
     /// # Errors
     /// Returns `Err` if WAL move, open, or read fails
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
-        let wal_path = path.into().join(WAL);
+        OpenOptions::new().open(path)
+    }
+
+    /// Opens the KV store at path like [`KvStore::open`], waiting up to timeout for another
+    /// store to close it
+    ///
+    /// # Errors
+    /// Returns `Err` if the store directory is still locked after timeout, or WAL move, open, or
+    /// read fails
+    pub fn try_open_timeout(path: impl Into<PathBuf>, timeout: Duration) -> Result<Self> {
+        OpenOptions::new().lock_timeout(timeout).open(path)
+    }
+
+    #[instrument(level = "debug", skip(options))]
+    #[allow(clippy::too_many_lines)] // One field per part of the store
+    fn open_with(path: &Path, options: OpenOptions) -> Result<Self> {
+        let lock = DirLock::acquire(path, options.lock_timeout)?;
+        // Segments are opened as recorded in the manifest rather than by listing the directory
+        // New stores take the requested record format, existing ones keep theirs
+        let fresh = manifest::check(path)?;
+        let mut tracked = if fresh {
+            Manifest {
+                format: options.wal_format,
+                ..Manifest::default()
+            }
+        } else {
+            manifest::read(path)?
+        };
+        let dirs = Self::dirs(path, &options, (!fresh).then_some(&tracked))?;
+        let indexes = secondary::declared(path)?;
+        let wal_path = dirs.active_path();
         let old_wal_exists = wal_path.exists() && wal_path.is_file();
         let mut wal_path_moved = PathBuf::new();
+        let mut old_segments = tracked.segment_files(&dirs);
+        Self::set_aside_invalid_bases(&mut old_segments)?;
+        let first_segment = tracked.next_segment;
 
         // Move existing WAL if it exists
         if old_wal_exists {
@@ -48,207 +397,2998 @@ impl KvStore {
         }
 
         // Instantiate KV store with new WAL file handle
+        #[cfg(feature = "metrics")]
+        let metrics = Arc::new(Metrics::default());
+        let wal = Arc::new(Wal::new(
+            dirs.clone(),
+            Self::wal_new_open(&wal_path, &options)?,
+            first_segment,
+            tracked.format,
+            &options,
+            #[cfg(feature = "metrics")]
+            Arc::clone(&metrics),
+        )?);
+        if let Some(runtime) = &options.runtime {
+            runtime.register(&wal);
+        }
+        #[cfg(feature = "raft")]
+        let cluster = options
+            .cluster
+            .as_ref()
+            .map(|(node, members)| raft::Node::open(path, *node, members))
+            .transpose()?;
         let store = Self {
-            store: DashMap::new(),
-            wal_handle: Self::wal_new_open(&wal_path)?,
+            inner: Arc::new(Inner {
+                store: DashMap::new(),
+                snapshot: RwLock::new(()),
+                versions: DashMap::new(),
+                expiries: DashMap::new(),
+                ordered: RwLock::new(BTreeSet::new()),
+                multi_key: RwLock::new(()),
+                buckets: DashMap::new(),
+                sequences: DashMap::new(),
+                series: DashMap::new(),
+                lists: DashMap::new(),
+                sets: DashMap::new(),
+                leases: DashMap::new(),
+                last_lease: AtomicU64::new(0),
+                wal,
+                cache: options
+                    .cache
+                    .map(ValueCache::new)
+                    .or_else(|| options.runtime.as_ref().and_then(KvsRuntime::cache)),
+                evictor: options.eviction.map(Evictor::new),
+                indexes: secondary::Indexes::default(),
+                watchers: Watchers::default(),
+                opened: options.clock.now(),
+                freeze: freeze::Freeze::default(),
+                dirs,
+                replica_of: RwLock::new(None),
+                applied: replication::Applied::default(),
+                #[cfg(feature = "raft")]
+                cluster,
+                options,
+                poisoned: OnceLock::new(),
+                #[cfg(feature = "metrics")]
+                metrics,
+                _lock: lock,
+            }),
         };
 
-        // Load old WAL if it exists
-        if old_wal_exists {
-            if let Err(e) = store.wal_old_load(&wal_path_moved) {
-                // Undo old WAL move if load fails
-                eprintln!("Failed to load old WAL: {e}");
-                fs::rename(wal_path_moved, wal_path).map_err(KvStoreError::FailedWalRestore)?;
-                return Err(e);
+        // Load old segments, then old WAL if it exists; a base segment with hints stays in place
+        let live = segment::live(&old_segments);
+        let old_wal = old_wal_exists.then_some(wal_path_moved.as_path());
+        let mut replay = Replay::new(live.iter().map(|s| s.path.as_path()).chain(old_wal));
+        let mut adopted = None;
+        let loaded = live
+            .iter()
+            .try_for_each(|s| {
+                if !s.base {
+                    store.wal_old_load(&s.path, &mut replay)?;
+                } else if let Some(hints) = store.wal_base_hints(s) {
+                    adopted = Some(s.id);
+                    store.wal_base_adopt(s, hints, &mut replay)?;
+                } else {
+                    store.wal_base_load(&s.path, &mut replay)?;
+                }
+                store.loaded(&s.path, &mut replay);
+                Ok(())
+            })
+            .and_then(|()| {
+                if let Some(old_wal) = old_wal {
+                    store.wal_active_load(old_wal, tracked.end, &mut replay)?;
+                    store.loaded(old_wal, &mut replay);
+                }
+                Ok(())
+            })
+            .and_then(|()| store.inner.wal.track())
+            .map(|()| {
+                // Leases released and compacted away had tokens up to their sequence numbers
+                let sequence = store.inner.wal.sequence();
+                store
+                    .inner
+                    .last_lease
+                    .fetch_max(sequence, Ordering::Relaxed);
+            })
+            // Built from the values loaded, some of which are adopted without being replayed
+            .and_then(|()| store.build_indexes(indexes));
+        if let Err(e) = loaded {
+            error!("Failed to load old WAL: {e}");
+            let dirs = store.inner.dirs.clone();
+            drop(store);
+            Self::wal_restore(
+                &dirs,
+                first_segment,
+                old_wal_exists.then_some(&wal_path_moved),
+            )?;
+            return Err(e);
+        }
+
+        // Replay applied writes as a primary would
+        *store
+            .inner
+            .replica_of
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = store.inner.options.replica_of;
+
+        // Delete old segments and WAL once the manifest tracks the new segments
+        let old_segments: Vec<_> = old_segments
+            .into_iter()
+            .filter(|s| Some(s.id) != adopted || !s.base)
+            .collect();
+        for s in old_segments.iter().filter(|s| Some(s.id) != adopted) {
+            if let Err(e) = segment::remove_hint(&store.inner.dirs.data, s.id) {
+                warn!(
+                    segment = s.id,
+                    "Failed to remove hint file of old segment: {e}"
+                );
+            }
+        }
+        let old_paths = old_segments.into_iter().map(|s| s.path);
+        for old_path in old_paths.chain(old_wal_exists.then_some(wal_path_moved)) {
+            if let Err(e) = segment::remove_bloom(&old_path) {
+                warn!(path = %old_path.display(), "Failed to remove filter of old segment: {e}");
+            }
+            if let Err(e) = fs::remove_file(&old_path) {
+                warn!(path = %old_path.display(), "Failed to remove moved old WAL: {e}");
             }
         }
 
         Ok(store)
     }
 
-    /// Moves existing WAL and returns its new path in an output parameter
-    fn wal_old_move(wal_path: &Path) -> Result<PathBuf> {
-        let wal_path_moved = {
-            let mut ext = wal_path
-                .extension()
-                .ok_or(KvStoreError::InvalidWalFileName)?
-                .to_os_string();
-            ext.push(".old");
-            let mut tmp = PathBuf::from(wal_path);
-            tmp.set_extension(ext);
-            tmp
+    /// Returns the directories of the store at path set by options, creating them if missing
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::WrongLayout`] if the manifest of an existing store records other
+    /// directories, or `Err` if a directory cannot be created
+    fn dirs(path: &Path, options: &OpenOptions, tracked: Option<&Manifest>) -> Result<Dirs> {
+        let resolve = |dir: Option<&PathBuf>| {
+            let dir = dir.map_or(path, PathBuf::as_path);
+            fs::create_dir_all(dir)
+                .and_then(|()| dir.canonicalize())
+                .map_err(KvStoreError::FailedWalOpen)
+        };
+        let dirs = Dirs {
+            store: resolve(None)?,
+            wal: resolve(options.wal_dir.as_ref())?,
+            data: resolve(options.data_dir.as_ref())?,
         };
+        let Some(tracked) = tracked else {
+            return Ok(dirs);
+        };
+        let recorded = tracked.dirs(&dirs.store);
+        for (kind, recorded, set) in [
+            ("WAL", &recorded.wal, &dirs.wal),
+            ("data", &recorded.data, &dirs.data),
+        ] {
+            if recorded != set {
+                return Err(KvStoreError::WrongLayout(format!(
+                    "{kind} directory is {}, not {}",
+                    recorded.display(),
+                    set.display()
+                )));
+            }
+        }
+        Ok(dirs)
+    }
+
+    /// Undoes the move of the old WAL, if moved, and drops the new segments after a failed load
+    fn wal_restore(dirs: &Dirs, first_segment: u64, moved: Option<&PathBuf>) -> Result<()> {
+        for s in dirs
+            .segment_files()
+            .map_err(KvStoreError::FailedWalRestore)?
+        {
+            if s.id >= first_segment {
+                fs::remove_file(s.path).map_err(KvStoreError::FailedWalRestore)?;
+            }
+        }
+        if let Some(moved) = moved {
+            fs::rename(moved, dirs.active_path()).map_err(KvStoreError::FailedWalRestore)?;
+        }
+        Ok(())
+    }
 
+    /// Moves existing WAL and returns its new path
+    fn wal_old_move(wal_path: &Path) -> Result<PathBuf> {
+        let wal_path_moved = segment::with_suffix(wal_path, ".old");
         fs::rename(wal_path, &wal_path_moved).map_err(KvStoreError::FailedWalRename)?;
 
         Ok(wal_path_moved)
     }
 
-    fn wal_new_open(wal_path: &Path) -> Result<File> {
-        OpenOptions::new()
-            .truncate(true)
-            .create(true)
-            .write(true)
-            .open(wal_path)
+    fn wal_new_open(wal_path: &Path, options: &OpenOptions) -> Result<File> {
+        let preallocate = options.preallocate_segments.then_some(options.segment_size);
+        segment::create_active(wal_path, options.write_through, preallocate)
             .map_err(KvStoreError::FailedWalOpen)
     }
 
-    fn wal_old_load(&self, wal_path: &Path) -> Result<()> {
+    /// Sets aside base segments that fail verification, so that replay falls back to the
+    /// segments before them
+    ///
+    /// Without those segments, e.g. once deleted after a completed compaction, the records
+    /// only held by a set-aside base segment are lost; the file is kept for manual recovery.
+    fn set_aside_invalid_bases(segments: &mut Vec<segment::SegmentFile>) -> Result<()> {
+        while let Some(base) = segment::live(segments).first().filter(|s| s.base) {
+            let bytes = fs::read(&base.path).map_err(KvStoreError::FailedOldWalOpen)?;
+            let Err(reason) = segment::base_records(&bytes) else {
+                break;
+            };
+
+            let aside = segment::set_aside(&base.path).map_err(KvStoreError::FailedOldWalOpen)?;
+            warn!(
+                path = %aside.display(),
+                "Set aside invalid base segment, replaying earlier segments instead: {reason}"
+            );
+            let id = base.id;
+            segments.retain(|s| !(s.id == id && s.base));
+        }
+
+        Ok(())
+    }
+
+    fn wal_old_load(&self, wal_path: &Path, replay: &mut Replay) -> Result<()> {
+        let wal = File::open(wal_path).map_err(KvStoreError::FailedOldWalOpen)?;
+        self.wal_read(io::BufReader::new(wal), false, replay)
+    }
+
+    /// Loads the records of the active segment left by the store last opened, up to the end
+    /// recorded when it was closed, if any
+    ///
+    /// Checked records failing their check end the records of the segment, see
+    /// [`OpenOptions::checksum_records`].
+    fn wal_active_load(
+        &self,
+        wal_path: &Path,
+        end: Option<u64>,
+        replay: &mut Replay,
+    ) -> Result<()> {
         let wal = File::open(wal_path).map_err(KvStoreError::FailedOldWalOpen)?;
-        self.wal_read(wal)?;
+        let wal = wal.take(end.unwrap_or(u64::MAX));
+        self.wal_read(io::BufReader::new(wal), true, replay)
+    }
+
+    /// Loads the records of a base segment, verified by [`Self::set_aside_invalid_bases`]
+    fn wal_base_load(&self, path: &Path, replay: &mut Replay) -> Result<()> {
+        let bytes = fs::read(path).map_err(KvStoreError::FailedOldWalOpen)?;
+        let records = segment::base_records(&bytes).map_err(|reason| {
+            KvStoreError::FailedOldWalOpen(io::Error::new(io::ErrorKind::InvalidData, reason))
+        })?;
+        self.wal_read(records, false, replay)
+    }
+
+    /// Returns the hints of a base segment, verified by [`Self::set_aside_invalid_bases`], if it
+    /// can be adopted in place
+    ///
+    /// Base segments are replayed instead if their hint file is missing or unusable, or to
+    /// retain the history of their keys.
+    fn wal_base_hints(&self, base: &segment::SegmentFile) -> Option<hint::Hints> {
+        if self.inner.options.history_retention.is_some() {
+            return None;
+        }
+        let hint_path = self.inner.dirs.hint_path(base.id);
+        if !hint_path.exists() {
+            return None;
+        }
+        hint::read(&hint_path, &base.path)
+            .inspect_err(|reason| {
+                warn!(path = %hint_path.display(), "Ignored hint file, replaying base segment: {reason}");
+            })
+            .ok()
+    }
 
-        // Delete old WAL if load succeeds
-        if let Err(e) = fs::remove_file(wal_path) {
-            eprintln!("Failed to remove moved old WAL: {e}");
+    /// Adopts a base segment in place as the first segment of the log, loading the state its
+    /// records hold as located by hints rather than replaying them
+    ///
+    /// Versions, ID sequences, timeseries, lists, sets and leases come from the hints alone;
+    /// values are read from the base segment unless the store keeps them on disk.
+    fn wal_base_adopt(
+        &self,
+        base: &segment::SegmentFile,
+        hints: hint::Hints,
+        replay: &mut Replay,
+    ) -> Result<()> {
+        for (record, ..) in &hints.records {
+            match record {
+                Record::Set {
+                    key,
+                    version,
+                    expires,
+                    ..
+                } => {
+                    self.inner
+                        .versions
+                        .insert(key.clone(), version.unwrap_or(1));
+                    if let Some(expires) = expires {
+                        self.inner.expiries.insert(key.clone(), *expires);
+                    }
+                }
+                Record::Append { key, .. } => {
+                    *self.inner.versions.entry(key.clone()).or_default() += 1;
+                }
+                Record::Rename { to, .. } => {
+                    self.inner.versions.insert(to.clone(), 1);
+                }
+                Record::ReserveIds { sequence, end } => self.reserve(sequence.clone(), *end),
+                Record::Sample {
+                    key,
+                    timestamp,
+                    value,
+                } => self
+                    .inner
+                    .series
+                    .entry(key.clone())
+                    .or_default()
+                    .push(Sample {
+                        timestamp: *timestamp,
+                        value: *value,
+                    }),
+                Record::ListPush { key, value } => self
+                    .inner
+                    .lists
+                    .entry(key.clone())
+                    .or_default()
+                    .push_front(value.clone()),
+                Record::SetAdd { key, member } => {
+                    self.inner
+                        .sets
+                        .entry(key.clone())
+                        .or_default()
+                        .insert(member.clone());
+                }
+                Record::Lease {
+                    key,
+                    token,
+                    expires,
+                } => {
+                    self.inner.last_lease.fetch_max(*token, Ordering::Relaxed);
+                    self.inner.leases.insert(
+                        key.clone(),
+                        Lease {
+                            token: *token,
+                            expires: *expires,
+                        },
+                    );
+                }
+                _ => {}
+            }
         }
+        replay.last = hints.last_sequence;
+        replay.compacted = hints.through;
+        self.inner.wal.adopt(base.id, base.path.clone(), hints)?;
+        self.order().extend(self.inner.wal.keys(None, ""));
 
+        if !self.inner.options.offset_index {
+            for (key, value) in self.inner.wal.scan(None, "")? {
+                self.inner.store.insert(key, value);
+            }
+            for bucket in self.inner.wal.bucket_names() {
+                for (key, value) in self.inner.wal.scan(Some(&bucket), "")? {
+                    self.inner.buckets.insert((bucket.clone(), key), value);
+                }
+            }
+        }
+        info!(path = %base.path.display(), "Adopted base segment using its hints");
         Ok(())
     }
 
-    fn wal_read(&self, wal: File) -> Result<()> {
-        for line_result in io::BufReader::new(wal).lines() {
+    /// Replays the records read from wal, the active segment left by the store last opened if
+    /// active is set
+    fn wal_read(&self, wal: impl BufRead, active: bool, replay: &mut Replay) -> Result<()> {
+        for line_result in wal.split(b'\n') {
+            let len = line_result.as_ref().map_or(0, Vec::len);
+            // Preallocated space an active segment was not written up to
+            let unwritten = |line: &Vec<u8>| !line.is_empty() && line.iter().all(|&b| b == 0);
+            if line_result.as_ref().is_ok_and(unwritten) {
+                continue;
+            }
+            // Torn by a crash, or left by a recycled segment, as are the bytes after it
+            let torn = |line: &Vec<u8>| {
+                codec::unchecked(line.strip_suffix(b"\r").unwrap_or(line)).is_err()
+            };
+            if active && line_result.as_ref().is_ok_and(torn) {
+                warn!("Dropped the end of the WAL from a record failing its check");
+                break;
+            }
             // TODO: actually load WAL contents in memory?
-            println!("{}", self.wal_line_read(line_result)?);
+            let output = self.wal_line_read(line_result, replay)?;
+            trace!(output, "Replayed WAL record");
+            if replay.record(len) {
+                self.report(replay);
+            }
         }
 
         Ok(())
     }
 
-    fn wal_line_read(&self, line_result: result::Result<String, io::Error>) -> Result<String> {
+    /// Counts the log file at path as loaded, and reports the progress of replay
+    fn loaded(&self, path: &Path, replay: &mut Replay) {
+        replay.loaded(path);
+        self.report(replay);
+    }
+
+    /// Reports the progress of replay to the progress callback, if any
+    fn report(&self, replay: &Replay) {
+        if let Some(on_progress) = &self.inner.options.on_progress {
+            on_progress(&replay.progress());
+        }
+    }
+
+    fn wal_line_read(
+        &self,
+        line_result: result::Result<Vec<u8>, io::Error>,
+        replay: &mut Replay,
+    ) -> Result<String> {
         match line_result {
             Err(e) => Err(KvStoreError::FailedWalLineRead(e)),
-            Ok(line) => Ok(self.wal_line_deserialize(&line)?),
+            Ok(line) => Ok(self.wal_line_deserialize(&line, replay)?),
+        }
+    }
+
+    /// Replays a record frame, in the format of the log, logging it again with the same
+    /// stamp
+    ///
+    /// Records numbered up to the last one replayed are duplicates and skipped. Records skipped
+    /// over by the sequence numbers, unless dropped by compaction, are reported as missing.
+    /// Records written before records were numbered get the next sequence number, and are not
+    /// checked for duplicates.
+    fn wal_line_deserialize(&self, line: &[u8], replay: &mut Replay) -> Result<String> {
+        let (stamp, cmd) =
+            wal::decode(self.inner.wal.format(), line).map_err(KvStoreError::DeserializeCommand)?;
+        let Some(sequence) = stamp.sequence else {
+            if let Command::Compacted { through } = cmd {
+                replay.compacted = replay.compacted.max(through);
+                self.inner.wal.write(Record::Compacted { through })?;
+                return Ok(String::new());
+            }
+            self.inner.wal.resume(stamp);
+            return self.replay(cmd);
+        };
+
+        if sequence <= replay.last {
+            debug!(sequence, "Skipped duplicate WAL record");
+            return Ok(String::new());
         }
+        let first_missing = replay.last.max(replay.compacted) + 1;
+        if sequence > first_missing {
+            warn!(
+                from = first_missing,
+                to = sequence - 1,
+                "WAL records missing before sequence number {sequence}"
+            );
+        }
+
+        self.inner.wal.resume(stamp);
+        let output = self.replay(cmd)?;
+        replay.last = sequence;
+        Ok(output)
     }
 
-    fn wal_line_deserialize(&self, line: &str) -> Result<String> {
-        match serde_json::from_str(&format!("[\"{}\"]", line.replace(' ', "\",\""))) {
-            Err(e) => Err(KvStoreError::DeserializeCommand(e)),
-            Ok(cmd) => Ok(self.execute(cmd)?),
+    /// Executes a command replayed from a log, skipping records whose effect is already applied
+    ///
+    /// Removals of missing keys, as logged by earlier versions, samples not after the last one
+    /// of their timeseries, and unlocks of leases not held are no-ops, so that replaying
+    /// records again leaves the store as is. Skipped removals are not logged again.
+    pub(crate) fn replay(&self, cmd: Command) -> Result<String> {
+        match memory::exempt(|| self.run(cmd)) {
+            Err(KvStoreError::FailedRm(key)) => {
+                debug!(key, "Skipped replayed removal of missing key");
+                Ok(String::new())
+            }
+            Err(KvStoreError::OutOfOrderSample(key, timestamp)) => {
+                debug!(key, timestamp, "Skipped replayed sample not after last one");
+                Ok(String::new())
+            }
+            Err(KvStoreError::LockNotHeld(key, token)) => {
+                debug!(key, token, "Skipped replayed unlock of lease not held");
+                Ok(String::new())
+            }
+            result => result,
         }
     }
 
-    /// Executes a command as an operation on the KV store
+    /// Executes a command as an operation on the KV store, between the [hooks](Hook) of the
+    /// store, returning its output
+    ///
+    /// The output of `set` and `rm` is the previous value of the key, so `None` for a `set` of a
+    /// new key, except that a conditional `set` outputs whether it set the key. Otherwise it is
+    /// `None` only for a `get` of a missing key, so that it differs from a key holding the empty
+    /// string.
     ///
     /// # Errors
-    /// Return `Err` if operation failed
-    pub fn execute(&self, cmd: Command) -> Result<String> {
+    /// Return `Err` if operation failed or a hook vetoed it
+    pub fn execute(&self, cmd: Command) -> Result<Option<String>> {
+        self.hooked(cmd, |cmd| match cmd {
+            Command::Get { key, path: None } => self.get(key),
+            Command::Get {
+                key,
+                path: Some(path),
+            } => self.get_path(&key, &path),
+            Command::Set {
+                key,
+                value,
+                path: None,
+                nx: false,
+                xx: false,
+            } => self.set(key, value),
+            Command::Rm { key, prefix: None } => self.remove(key).map(Some),
+            cmd => self.run(cmd).map(Some),
+        })
+    }
+
+    /// Returns value for given key if present like [`KvStore::get`], between the hooks of the
+    /// store as a `get` command
+    pub(crate) fn get_hooked(&self, key: String) -> Result<Option<String>> {
+        if self.inner.options.hooks.is_empty() {
+            return self.get(key);
+        }
+        let cmd = Command::Get {
+            key: key.clone(),
+            path: None,
+        };
+        self.around(&cmd, || self.get(key))
+    }
+
+    /// Runs op on cmd between the hooks of the store
+    pub(crate) fn hooked<T>(
+        &self,
+        cmd: Command,
+        op: impl FnOnce(Command) -> Result<T>,
+    ) -> Result<T> {
+        if self.inner.options.hooks.is_empty() {
+            return op(cmd);
+        }
+        let hooked = cmd.clone();
+        self.around(&hooked, || op(cmd))
+    }
+
+    /// Runs op between the hooks of the store for cmd, unless one vetoes it
+    fn around<T>(&self, cmd: &Command, op: impl FnOnce() -> Result<T>) -> Result<T> {
+        for hook in &self.inner.options.hooks {
+            hook.before_execute(cmd)?;
+        }
+        let result = op();
+        for hook in &self.inner.options.hooks {
+            hook.after_execute(cmd, result.as_ref().err());
+        }
+        result
+    }
+
+    /// Executes a command as an operation on the KV store, without hooks
+    #[allow(clippy::too_many_lines)] // One arm per command
+    fn run(&self, cmd: Command) -> Result<String> {
         match cmd {
-            Command::Get { key } => match self.get(key.clone()) {
+            Command::Get {
+                key,
+                path: Some(path),
+            } => self.get_path(&key, &path).map(Option::unwrap_or_default),
+            Command::Set {
+                key,
+                value,
+                path: Some(path),
+                nx: false,
+                xx: false,
+            } => self.set_path(key, &path, &value).map(|()| String::new()),
+            Command::Set { path: Some(_), .. } => Err(KvStoreError::InvalidCommand(
+                "set of a JSON path cannot be conditional".to_owned(),
+            )),
+            Command::Get { key, path: None } => match self.get(key.clone()) {
                 Err(e) => Err(e),
                 Ok(value) => match value {
                     Some(v) => Ok(v),
                     _ => Ok(String::new()),
                 },
             },
-            Command::Set { key, value } => match self.set(key.clone(), value) {
+            Command::Set {
+                key,
+                value,
+                path: None,
+                nx: false,
+                xx: false,
+            } => match self.set(key.clone(), value) {
+                Err(e) => Err(e),
+                _ => Ok(String::new()),
+            },
+            Command::Set {
+                key,
+                value,
+                path: None,
+                nx,
+                xx,
+            } => {
+                let options = SetOptions {
+                    if_not_exists: nx,
+                    if_exists: xx,
+                    ..SetOptions::default()
+                };
+                self.set_opts(key, value, options)
+                    .map(|(written, _)| written.to_string())
+            }
+            Command::Rm {
+                prefix: Some(prefix),
+                ..
+            } => self
+                .remove_prefix(&prefix)
+                .map(|removed| format!("Removed {removed} keys")),
+            Command::Rm { key, prefix: None } => match self.remove(key.clone()) {
+                Err(e) => Err(e),
+                _ => Ok(String::new()),
+            },
+            Command::Clear { yes: false } => Err(KvStoreError::InvalidCommand(
+                "clear removes all keys, confirm with --yes".to_owned(),
+            )),
+            Command::Clear { yes: true } => self
+                .clear()
+                .map(|removed| format!("Removed {removed} keys")),
+            Command::VersionedSet {
+                key,
+                version,
+                value,
+            } => match self.set_versioned("set", key, value, None, |_, _| Ok(version)) {
+                Err(e) => Err(e),
+                _ => Ok(String::new()),
+            },
+            Command::ExpiringSet {
+                key,
+                version,
+                expires,
+                value,
+            } => self
+                .set_versioned("set", key, value, Some(expires), |_, _| Ok(version))
+                .map(|_| String::new()),
+            Command::BucketSet { bucket, key, value } => {
+                match self.bucket(bucket).set(key, value) {
+                    Err(e) => Err(e),
+                    _ => Ok(String::new()),
+                }
+            }
+            Command::BucketRm { bucket, key } => match self.bucket(bucket).remove(key) {
+                Err(e) => Err(e),
+                _ => Ok(String::new()),
+            },
+            Command::History { key } => Ok(self
+                .history(key)?
+                .iter()
+                .map(|r| match &r.value {
+                    Some(value) => format!("{} {value}", r.sequence),
+                    None => format!("{} (removed)", r.sequence),
+                })
+                .collect::<Vec<_>>()
+                .join("\n")),
+            Command::NextId { sequence } => self.next_id(sequence).map(|id| id.to_string()),
+            Command::ReserveIds { sequence, end } => match self.reserve_ids(sequence, end) {
                 Err(e) => Err(e),
                 _ => Ok(String::new()),
             },
-            Command::Rm { key } => match self.remove(key.clone()) {
+            // Only tells replay that missing records are expected
+            Command::Compacted { .. } => Ok(String::new()),
+            Command::TsAdd {
+                key,
+                timestamp,
+                value,
+            } => match self.ts_add(key, timestamp, value) {
                 Err(e) => Err(e),
                 _ => Ok(String::new()),
             },
+            Command::TsRange {
+                key,
+                from,
+                to,
+                aggregation,
+            } => Ok(self
+                .ts_range(key, from, to, aggregation)?
+                .iter()
+                .map(|s| format!("{} {}", s.timestamp, s.value))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            Command::Lpush { key, value } => self.lpush(key, value).map(|len| len.to_string()),
+            Command::Rpop { key } => self.rpop(&key).map(Option::unwrap_or_default),
+            Command::Lrange { key, start, stop } => Ok(self.lrange(&key, start, stop).join("\n")),
+            Command::Sadd { key, member } => self.sadd(key, member).map(|added| added.to_string()),
+            Command::Srem { key, member } => {
+                self.srem(&key, &member).map(|removed| removed.to_string())
+            }
+            Command::Smembers { key } => Ok(self.smembers(&key).join("\n")),
+            Command::Lock { key, ttl } => self
+                .lock(key, Duration::from_millis(ttl))
+                .map(|token| token.to_string()),
+            Command::Leased {
+                key,
+                token,
+                expires,
+            } => self
+                .replay_lease(key, token, expires)
+                .map(|()| String::new()),
+            Command::Unlock { key, lease } => self.unlock(&key, lease).map(|()| String::new()),
+            Command::Find { index, eq } => Ok(self.query_index(&index, &eq)?.join("\n")),
+            Command::Index {
+                command: IndexCommand::Create { name, path },
+            } => self.create_index(&name, &path).map(|()| String::new()),
+            Command::Index {
+                command: IndexCommand::Drop { name },
+            } => self.drop_index(&name).map(|()| String::new()),
+            Command::Index {
+                command: IndexCommand::List,
+            } => Ok(self
+                .indexes()
+                .iter()
+                .map(|(name, path)| format!("{name} {path}"))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            Command::Rename { from, to } => self.rename(from, to).map(|()| String::new()),
+            Command::Renamed { from, to, value } => {
+                self.replay_rename(&from, to, value).map(|()| String::new())
+            }
+            Command::Copy {
+                from,
+                to,
+                overwrite,
+            } => self.copy(&from, to, overwrite).map(|()| String::new()),
+            Command::Append { key, suffix } => self.append(key, suffix).map(|len| len.to_string()),
+            Command::Exists { key } => Ok(self.contains_key(&key).to_string()),
+            Command::Count => Ok(self.len().to_string()),
+            Command::Keys {
+                prefix,
+                limit,
+                start_after,
+                reverse,
+            } => {
+                let options = ScanOptions {
+                    limit,
+                    start_after,
+                    reverse,
+                };
+                Ok(self.keys(&prefix, &options)?.join("\n"))
+            }
+            Command::Checkpoint => self.checkpoint().map(|sequence| sequence.to_string()),
+            Command::Stats { json: false } => Ok(self.stats().to_string()),
+            Command::Stats { json: true } => {
+                serde_json::to_string(&self.stats()).map_err(KvStoreError::SerializeOutput)
+            }
+            Command::Dump { format, json } => self
+                .iter()?
+                .map(|(key, value)| {
+                    if json || format == pipe::DumpFormat::Jsonl {
+                        serde_json::to_string(&serde_json::json!({ "key": key, "value": value }))
+                            .map_err(KvStoreError::SerializeOutput)
+                    } else {
+                        Ok(format!("{key} {value}"))
+                    }
+                })
+                .collect::<Result<Vec<_>>>()
+                .map(|lines| lines.join("\n")),
+            Command::Load { batch } => pipe::load(self, io::stdin().lock(), batch)
+                .map(|loaded| format!("Loaded {loaded} commands")),
+            Command::Import { paths, format, db } => {
+                import::import(self, &paths, format, db).map(|imported| imported.to_string())
+            }
+            #[cfg(feature = "sqlite")]
+            Command::Export {
+                path,
+                format: sqlite::ExportFormat::Sqlite,
+            } => sqlite::export(self, path).map(|keys| format!("Exported {keys} keys")),
+            Command::Doctor { .. } => Err(KvStoreError::InvalidCommand(
+                "doctor checks a closed store".to_owned(),
+            )),
+            Command::Migrate => Err(KvStoreError::InvalidCommand(
+                "migrate upgrades a closed store".to_owned(),
+            )),
+            Command::Log { .. } => Err(KvStoreError::InvalidCommand(
+                "log commands read the log files of a store".to_owned(),
+            )),
+            #[cfg(feature = "archive")]
+            Command::Restore { .. } => Err(KvStoreError::InvalidCommand(
+                "restore fills a directory holding no store".to_owned(),
+            )),
+            #[cfg(feature = "metrics")]
+            Command::Info => Ok(self.inner.metrics.render()),
+            Command::Admin { .. } | Command::Ping { .. } | Command::Backup { .. } => Err(
+                KvStoreError::InvalidCommand("admin commands are sent to a server".to_owned()),
+            ),
+            #[cfg(feature = "raft")]
+            Command::Cluster { .. } => Err(KvStoreError::InvalidCommand(
+                "cluster commands are sent to a server".to_owned(),
+            )),
+            Command::Completions { .. } | Command::Man { .. } => Err(KvStoreError::InvalidCommand(
+                "documentation is generated by the CLI".to_owned(),
+            )),
         }
     }
 
-    /// Records operations in write-ahead log (WAL) if WAL is provided
+    /// Waits for the record of a write already applied in memory to be logged
+    ///
+    /// Memory and log disagree if logging fails, so the store is poisoned.
     ///
     /// # Errors
-    /// Returns `Err` if `open` or `write_all` fail
-    fn wal_write(&self, s: &str) -> Result<()> {
-        let s = format!("{s}\n");
-        (&self.wal_handle)
-            .write_all(s.as_bytes())
-            .map_err(KvStoreError::FailedWalWrite)
+    /// Returns `Err` if `write_all` fails
+    fn logged(&self, pending: Pending) -> Result<()> {
+        pending
+            .wait()
+            .map_err(|e| self.poison(format!("write applied but not logged: {e}")))
     }
 
-    /// Inserts key-value pair into store
+    /// Inserts key-value pair into store, returning the previous value of key if present
     ///
     /// # Errors
-    /// Returns `Err` if on-disk WAL write fails
-    pub fn set(&self, key: String, value: String) -> Result<()> {
-        // TODO: Use serde to serialize command
+    /// Returns `Err` if key or value exceeds its size limit, the store is over its
+    /// [memory budget](OpenOptions::max_memory), or on-disk WAL write fails
+    pub fn set(&self, key: String, value: String) -> Result<Option<String>> {
+        self.set_versioned("set", key, value, None, |_, version| Ok(version + 1))
+            .map(|(_, previous)| previous)
+    }
+
+    /// Inserts key-value pair into store with options, returning whether it was written along
+    /// with the value of key before, if present
+    ///
+    /// Unlike [group commit](OpenOptions::group_commit), which makes every write durable,
+    /// [`SetOptions::fsync`] applies to this write alone, so that critical writes are durable
+    /// once this returns while bulk writes stay fast. Writes logged before it are synced along
+    /// with it.
+    ///
+    /// Like Redis `SET NX` and `SET XX`, [`SetOptions::if_not_exists`] and
+    /// [`SetOptions::if_exists`] make the write conditional on key being missing or present.
+    /// The condition is checked while key is locked against other writes, and a write not made
+    /// is not logged. Like Redis `SET PX`, [`SetOptions::ttl`] makes key expire.
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::InvalidCommand`] if both conditions are set or the TTL is zero,
+    /// or `Err` as [`Self::set`] does, or if syncing the WAL fails
+    pub fn set_opts(
+        &self,
+        key: String,
+        value: String,
+        options: SetOptions,
+    ) -> Result<(bool, Option<String>)> {
+        if options.ttl.is_some_and(|ttl| ttl.is_zero()) {
+            return Err(KvStoreError::InvalidCommand(
+                "key TTL must be positive".to_owned(),
+            ));
+        }
+        let expires = options.ttl.map(|ttl| {
+            let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+            self.inner.options.clock.unix_millis().saturating_add(ttl)
+        });
+        let name = match (options.if_not_exists, options.if_exists) {
+            (false, false) => {
+                let (_, previous) =
+                    self.set_versioned("set", key, value, expires, |_, version| Ok(version + 1))?;
+                if options.fsync {
+                    self.sync()?;
+                }
+                return Ok((true, previous));
+            }
+            (true, false) => "set-nx",
+            (false, true) => "set-xx",
+            (true, true) => {
+                return Err(KvStoreError::InvalidCommand(
+                    "set if missing and if present at once never writes".to_owned(),
+                ))
+            }
+        };
+        let (written, previous) = self
+            .guard_write(name, || {
+                self.check_len(&key, &value)?;
+                self.check_memory()?;
+                let _multi_key = self
+                    .inner
+                    .multi_key
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner);
+                let current = self.inner.versions.entry(key.clone());
+                let version = current_version(&current);
+                // Only one condition is set, so a present key fails `if_not_exists` exactly
+                // when it passes `if_exists`
+                if (version > 0 && !self.expired(&key)) == options.if_not_exists {
+                    return Ok((false, self.current_value(&key)?));
+                }
+                let previous = self.apply_set(current, key, value, version + 1, expires)?;
+                Ok((true, previous))
+            })
+            .inspect(|_| self.evict())?;
+        if written && options.fsync {
+            self.sync()?;
+        }
+        Ok((written, previous))
+    }
+
+    /// Inserts key-value pair into store if key is at the expected version, returning its new
+    /// version
+    ///
+    /// Version 0 stands for a missing key, so that the pair is only inserted if key is new.
+    /// Checking the version and writing happen atomically, for optimistic concurrency control
+    /// along with [`Self::get_versioned`].
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::VersionMismatch`] if key is at another version, or `Err` if key or
+    /// value exceeds its size limit, or on-disk WAL write fails
+    pub fn set_if_version(&self, key: String, value: String, expected_version: u64) -> Result<u64> {
+        self.set_versioned("set-if-version", key, value, None, |key, version| {
+            if version == expected_version {
+                Ok(version + 1)
+            } else {
+                Err(KvStoreError::VersionMismatch(
+                    key.to_owned(),
+                    expected_version,
+                    version,
+                ))
+            }
+        })
+        .map(|(version, _)| version)
+    }
+
+    /// Inserts key-value pair into store at the version `next` derives from the current version
+    /// of key, 0 if missing, and returns it along with the previous value of key
+    ///
+    /// The version of key stays locked until the write is applied, so writes to a key are
+    /// numbered in the order they become visible. Key expires at expires if set.
+    fn set_versioned(
+        &self,
+        name: &'static str,
+        key: String,
+        value: String,
+        expires: Option<u64>,
+        next: impl FnOnce(&str, u64) -> Result<u64>,
+    ) -> Result<(u64, Option<String>)> {
+        self.guard_write(name, || {
+            self.check_len(&key, &value)?;
+            self.check_memory()?;
+            let _multi_key = self
+                .inner
+                .multi_key
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            let current = self.inner.versions.entry(key.clone());
+            let version = next(&key, current_version(&current))?;
+            let previous = self.apply_set(current, key, value, version, expires)?;
+            Ok((version, previous))
+        })
+        .inspect(|_| self.evict())
+    }
+
+    /// Applies and logs a write of key at version, whose entry in the versions locks key until
+    /// the write is applied, returning the previous value of key
+    ///
+    /// Key expires at expires if set, and otherwise loses any TTL. Writes with a TTL are logged
+    /// as they are made rather than coalesced, after the writes held back.
+    fn apply_set(
+        &self,
+        current: dashmap::Entry<'_, String, u64>,
+        key: String,
+        value: String,
+        version: u64,
+        expires: Option<u64>,
+    ) -> Result<Option<String>> {
+        let event = self.inner.watchers.active().then(|| WatchEvent::Set {
+            key: key.clone(),
+            value: value.clone(),
+        });
+        if let Some(evictor) = &self.inner.evictor {
+            evictor.set(&key, key.len() + value.len());
+        }
+        let indexed = self.inner.indexes.active().then(|| value.clone());
+
+        // The first version of a key is implied by its `set` record
+        let logged_version = (version > 1).then_some(version);
+        let previous = if self.inner.options.offset_index {
+            // Read past the cache, which would only keep the value being overwritten
+            let previous = self.current_value(&key)?;
+            self.inner.wal.write(Record::Set {
+                key: key.clone(),
+                value,
+                version: logged_version,
+                expires,
+            })?;
+            self.inner.indexes.set(&key, indexed.as_deref());
+            self.set_expiry(&key, expires);
+            if previous.is_none() {
+                self.order().insert(key.clone());
+            }
+            current.insert(version);
+            if let Some(cache) = &self.inner.cache {
+                cache.invalidate(&key);
+            }
+            previous
+        } else if let Some(mut coalescer) = expires
+            .is_none()
+            .then(|| self.inner.wal.coalescer())
+            .flatten()
+        {
+            let applying = self
+                .inner
+                .snapshot
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            let previous = self.inner.store.insert(key.clone(), value.clone());
+            drop(applying);
+            let previous = previous.filter(|_| !self.expired(&key));
+            self.inner.indexes.set(&key, indexed.as_deref());
+            self.set_expiry(&key, None);
+            if previous.is_none() {
+                self.order().insert(key.clone());
+            }
+            current.insert(version);
+            self.inner.wal.coalesce(
+                &mut coalescer,
+                key,
+                Some((value, version)),
+                previous.is_some(),
+            )?;
+            previous
+        } else {
+            // Writes held back are logged first, so that the record follows them
+            if let Some(mut coalescer) = self.inner.wal.coalescer() {
+                self.inner.wal.write_pending(&mut coalescer)?;
+            }
+            let applying = self
+                .inner
+                .snapshot
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            let (entry, previous) = match self.inner.store.entry(key.clone()) {
+                dashmap::Entry::Occupied(mut entry) => {
+                    let previous = entry.insert(value.clone());
+                    (entry.into_ref(), Some(previous))
+                }
+                dashmap::Entry::Vacant(entry) => (entry.insert(value.clone()), None),
+            };
+            drop(applying);
+            let previous = previous.filter(|_| !self.expired(&key));
+            self.inner.indexes.set(&key, indexed.as_deref());
+            self.set_expiry(&key, expires);
+            if previous.is_none() {
+                self.order().insert(key.clone());
+            }
+            let pending = self.inner.wal.append(Record::Set {
+                key,
+                value,
+                version: logged_version,
+                expires,
+            });
+            drop(entry);
+            current.insert(version);
+            self.logged(pending)?;
+            previous
+        };
+
+        if let Some(event) = event {
+            self.inner.watchers.notify(&event);
+        }
+
+        Ok(previous)
+    }
 
-        self.wal_write(&format!("set {key} {value}"))?;
-        self.store.insert(key, value);
+    /// Rejects keys and values exceeding the configured limits
+    fn check_len(&self, key: &str, value: &str) -> Result<()> {
+        if key.len() > self.inner.options.max_key_len {
+            return Err(KvStoreError::KeyTooLarge(
+                key.len(),
+                self.inner.options.max_key_len,
+            ));
+        }
+        if value.len() > self.inner.options.max_value_len {
+            return Err(KvStoreError::ValueTooLarge(
+                value.len(),
+                self.inner.options.max_value_len,
+            ));
+        }
 
         Ok(())
     }
 
+    /// Returns whether key was set with a TTL that has run out, see [`SetOptions::ttl`]
+    ///
+    /// Expired keys read as missing until [purged](Self::purge_expired), except to writes
+    /// applying records already logged or committed, which apply as they did when made.
+    fn expired(&self, key: &str) -> bool {
+        // Checked first, as no key has a TTL in most stores
+        !self.inner.expiries.is_empty()
+            && !memory::is_exempt()
+            && self
+                .inner
+                .expiries
+                .get(key)
+                .is_some_and(|expires| *expires <= self.inner.options.clock.unix_millis())
+    }
+
+    /// Returns the expiry of key, if set with a TTL
+    fn expiry(&self, key: &str) -> Option<u64> {
+        self.inner.expiries.get(key).map(|expires| *expires)
+    }
+
+    /// Returns the keys set with a TTL that has run out
+    fn expired_keys(&self) -> Vec<String> {
+        let now = self.inner.options.clock.unix_millis();
+        self.inner
+            .expiries
+            .iter()
+            .filter(|e| *e.value() <= now)
+            .map(|e| e.key().clone())
+            .collect()
+    }
+
+    /// Sets the expiry of key, removing any TTL if `None`
+    fn set_expiry(&self, key: &str, expires: Option<u64>) {
+        match expires {
+            Some(expires) => {
+                self.inner.expiries.insert(key.to_owned(), expires);
+            }
+            None => {
+                self.inner.expiries.remove(key);
+            }
+        }
+    }
+
+    /// Rejects writes adding keys or values once the store is over its memory budget
+    fn check_memory(&self) -> Result<()> {
+        if self.inner.options.max_memory.is_none() {
+            return Ok(());
+        }
+        memory::check(self.memory_bytes(), self.inner.options.max_memory)
+    }
+
+    /// Removes the keys picked by the evictor in cache mode until back within budget, logging
+    /// their removal
+    ///
+    /// Runs after writes of the default key space, with no key locked, except for records
+    /// already logged or committed elsewhere, whose removals are left to the primary or leader
+    /// writing them.
+    fn evict(&self) {
+        let Some(evictor) = &self.inner.evictor else {
+            return;
+        };
+        if memory::is_exempt() {
+            return;
+        }
+        while let Some((key, size)) = evictor.victim() {
+            let _multi_key = self
+                .inner
+                .multi_key
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            let current = self.inner.versions.entry(key.clone());
+            match self.apply_rm(current, key.clone()) {
+                Ok(_) => {
+                    debug!(key, "Evicted key");
+                    evictor.evicted();
+                }
+                // Removed meanwhile
+                Err(KvStoreError::FailedRm(_)) => {}
+                // Still in the store, so tracked again to be evicted later
+                Err(e) => {
+                    warn!(key, "Failed to evict key: {e}");
+                    evictor.set(&key, size);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Returns the approximate bytes of memory taken by the keys and values of the store and
+    /// its index
+    ///
+    /// Values are counted by the bytes of their live log records, unless kept on disk only.
+    fn memory_bytes(&self) -> u64 {
+        let values = if self.inner.options.offset_index {
+            0
+        } else {
+            let usage = self.inner.wal.usage();
+            usage.len - usage.dead
+        };
+        self.inner.wal.index_bytes() + values
+    }
+
     /// Returns value for given key from store if present
     ///
+    /// Never prints; use [`OpenOptions::on_miss`] to observe missing keys.
+    ///
     /// # Errors
     /// Returns `Err` if KV store read fails
     pub fn get(&self, key: impl Into<String>) -> Result<Option<String>> {
         let key = key.into();
-        if let Some(v) = self.store.get(&key) {
-            Ok(Some(v.value().to_owned()))
-        } else {
-            println!("Key not found: {key}");
-            Ok(None)
-        }
+        self.guard("get", || Ok(self.lookup(&key)?.map(ValueRef::into_string)))
     }
 
-    /// Removes key-value pair from store for given key
+    /// Returns value for given key from store if present, without copying it where possible
+    ///
+    /// Like [`Self::get`], but the returned guard borrows the value held in memory. See
+    /// [`ValueRef`] for the locking implications of holding it.
     ///
     /// # Errors
-    /// Returns `Err` if on-disk WAL write fails
-    pub fn remove(&self, key: String) -> Result<()> {
-        // TODO: Use serde to serialize command
+    /// Returns `Err` if KV store read fails
+    pub fn get_ref(&self, key: impl Into<String>) -> Result<Option<ValueRef<'_>>> {
+        let key = key.into();
+        self.guard("get-ref", || self.lookup(&key))
+    }
+
+    /// Returns the value of key, running the miss hook if missing
+    fn lookup(&self, key: &str) -> Result<Option<ValueRef<'_>>> {
+        let value = if self.expired(key) {
+            None
+        } else if self.inner.options.offset_index {
+            self.disk_get(key)?
+        } else {
+            self.inner.store.get(key).map(ValueRef::entry)
+        };
 
-        self.wal_write(&format!("rm {key}"))?;
-        match self.store.remove(&key) {
-            None => Err(KvStoreError::FailedRm(key)),
-            Some(_) => Ok(()),
+        match (&value, &self.inner.evictor) {
+            (None, _) => {
+                debug!(key, "Key not found");
+                if let Some(hook) = &self.inner.options.on_miss {
+                    hook(key);
+                }
+            }
+            (Some(_), Some(evictor)) => evictor.touch(key),
+            (Some(_), None) => {}
         }
+        Ok(value)
     }
-}
 
-impl Drop for KvStore {
-    fn drop(&mut self) {
-        println!("Flushing buffers...");
-        if let Err(e) = self.wal_handle.flush() {
-            eprintln!("Failed to flush buffer to WAL: {e}");
+    /// Returns whether key is present in store, without copying its value
+    ///
+    /// Unlike [`Self::get`], never runs the miss hook.
+    #[must_use]
+    pub fn contains_key(&self, key: &str) -> bool {
+        if self.expired(key) {
+            false
+        } else if self.inner.options.offset_index {
+            self.inner.wal.contains(None, key)
+        } else {
+            self.inner.store.contains_key(key)
         }
+    }
 
-        println!("Syncing to disk...");
-        if let Err(e) = self.wal_handle.sync_all() {
-            eprintln!("Failed to sync all to WAL: {e}");
-        }
+    /// Returns the number of keys in store, not counting those of buckets or expired ones
+    #[must_use]
+    pub fn len(&self) -> usize {
+        let len = if self.inner.options.offset_index {
+            self.inner.wal.count(None)
+        } else {
+            self.inner.store.len()
+        };
+        len.saturating_sub(self.expired_keys().len())
     }
-}
 
-/// Error wrapper for KV store methods
-#[derive(Debug, Error)]
-pub enum KvStoreError {
-    /// Unknown current working directory
-    #[error("Current working directory could not be determined")]
-    UnknownCwd(io::Error),
-    /// Unexpected WAL file name
-    #[error("WAL does not have a file name extension")]
-    InvalidWalFileName,
-    /// Failed old WAL rename
-    #[error("Failed to rename old WAL: {0}")]
-    FailedWalRename(io::Error),
-    /// Failed old WAL restore
-    #[error("Failed to restore old WAL: {0}")]
-    FailedWalRestore(io::Error),
+    /// Returns whether store holds no keys, not counting those of buckets
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns value for given key from store along with its version, if present
+    ///
+    /// A key is at version 1 once created, and each write to it increments its version. Versions
+    /// are logged with the writes, so they survive restarts.
+    ///
+    /// # Errors
+    /// Returns `Err` if KV store read fails
+    pub fn get_versioned(&self, key: impl Into<String>) -> Result<Option<(String, u64)>> {
+        let key = key.into();
+        self.guard("get-versioned", || {
+            // Writers of key wait for the version to be released before changing it
+            let Some(version) = self.inner.versions.get(&key) else {
+                return Ok(None);
+            };
+            let value = if self.expired(&key) {
+                None
+            } else if self.inner.options.offset_index {
+                self.disk_get(&key)?.map(ValueRef::into_string)
+            } else {
+                self.inner.store.get(&key).map(|v| v.value().to_owned())
+            };
+
+            Ok(value.map(|value| (value, *version)))
+        })
+    }
+
+    /// Returns the value of key as of the log record with the given sequence number
+    ///
+    /// Sequence numbers are those of the local log, as returned by [`Self::sequence`] on a store
+    /// that is not a replica. Only sequence numbers within the window set by
+    /// [`OpenOptions::history_retention`] can be read.
+    ///
+    /// # Errors
+    /// Returns `Err` if history is disabled, or not retained as of sequence
+    pub fn get_at(&self, key: impl Into<String>, sequence: u64) -> Result<Option<String>> {
+        let key = key.into();
+        self.guard("get-at", || self.inner.wal.get_at(&key, sequence))
+    }
+
+    /// Returns the values key was set to or removed at, by log sequence number, within the
+    /// window set by [`OpenOptions::history_retention`]
+    ///
+    /// The first revision may predate the window, holding the value of key as of its start.
+    ///
+    /// # Errors
+    /// Returns `Err` if history is disabled
+    pub fn history(&self, key: impl Into<String>) -> Result<Vec<Revision>> {
+        let key = key.into();
+        self.guard("history", || self.inner.wal.history(&key))
+    }
+
+    /// Returns the logged value of key in offset-index mode, going through the cache if any
+    fn disk_get(&self, key: &str) -> Result<Option<ValueRef<'_>>> {
+        let Some(cache) = &self.inner.cache else {
+            return Ok(self.inner.wal.get(None, key)?.map(ValueRef::owned));
+        };
+
+        if let Some(value) = cache.get(key) {
+            #[cfg(feature = "metrics")]
+            self.inner.metrics.cache_hit();
+            return Ok(Some(ValueRef::shared(value)));
+        }
+        #[cfg(feature = "metrics")]
+        self.inner.metrics.cache_miss();
+
+        let epoch = cache.epoch();
+        let value = self.inner.wal.get(None, key)?.map(Arc::<str>::from);
+        if let Some(value) = &value {
+            cache.insert(key.to_owned(), Arc::clone(value), epoch);
+        }
+
+        Ok(value.map(ValueRef::shared))
+    }
+
+    /// Returns key-value pairs whose keys start with prefix, sorted by key
+    ///
+    /// # Errors
+    /// Returns `Err` if KV store read fails
+    pub fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.guard("scan", || {
+            if self.inner.options.offset_index {
+                let mut entries = self.inner.wal.scan(None, prefix)?;
+                entries.retain(|(key, _)| !self.expired(key));
+                return Ok(entries);
+            }
+
+            let mut entries: Vec<_> = self
+                .inner
+                .store
+                .iter()
+                .filter(|e| e.key().starts_with(prefix) && !self.expired(e.key()))
+                .map(|e| (e.key().clone(), e.value().clone()))
+                .collect();
+            entries.sort_unstable();
+            Ok(entries)
+        })
+    }
+
+    /// Returns a page of the key-value pairs whose keys start with prefix, sorted by key in the
+    /// order of options
+    ///
+    /// The store keeps its keys in order, so that a page goes through only the keys from
+    /// [`ScanOptions::start_after`] on, up to [`ScanOptions::limit`] of them, and reads their
+    /// values alone: a large key space is paged through without copying or sorting all of it
+    /// per page. Keys removed while the page is read are left out of it.
+    ///
+    /// # Errors
+    /// Returns `Err` if KV store read fails
+    pub fn scan_opts(&self, prefix: &str, options: &ScanOptions) -> Result<Vec<(String, String)>> {
+        self.guard("scan", || {
+            self.page_keys(prefix, options)
+                .into_iter()
+                .filter_map(|key| {
+                    self.current_value(&key)
+                        .map(|value| value.map(|value| (key, value)))
+                        .transpose()
+                })
+                .collect()
+        })
+    }
+
+    /// Returns a page of the keys starting with prefix, sorted in the order of options, like
+    /// [`Self::scan_opts`] without reading their values
+    ///
+    /// # Errors
+    /// Returns `Err` if KV store read fails
+    pub fn keys(&self, prefix: &str, options: &ScanOptions) -> Result<Vec<String>> {
+        self.guard("keys", || Ok(self.page_keys(prefix, options)))
+    }
+
+    /// Returns the keys matching the glob pattern among the count keys after cursor, along with
+    /// the last key gone through unless none may follow
+    ///
+    /// Only keys starting with the literal prefix of pattern are gone through, each matched as
+    /// it is reached, so that a step of a `scan_cursor` scan costs its count of keys whatever
+    /// the size of the store.
+    ///
+    /// # Errors
+    /// Returns `Err` if the store is poisoned
+    pub(crate) fn scan_pattern(
+        &self,
+        pattern: &str,
+        cursor: Option<&str>,
+        count: usize,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        self.guard("scan-cursor", || {
+            let ordered = self
+                .inner
+                .ordered
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            let prefix = glob::literal_prefix(pattern);
+            let mut keys = Vec::new();
+            let (mut last, mut examined) = (None, 0);
+            for key in keys_after(&ordered, &prefix, cursor, false)
+                .filter(|key| !self.expired(key))
+                .take(count)
+            {
+                if glob::matches(pattern, key) {
+                    keys.push(key.clone());
+                }
+                last = Some(key);
+                examined += 1;
+            }
+            // A step short of count is the last one
+            let cursor = last.filter(|_| examined == count).cloned();
+            Ok((keys, cursor))
+        })
+    }
+
+    /// Returns the keys of the default key space in order, locked for writing
+    fn order(&self) -> RwLockWriteGuard<'_, BTreeSet<String>> {
+        self.inner
+            .ordered
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the keys starting with prefix of the page of options
+    ///
+    /// Only the keys of the page are gone through, in the order of the keys of the default key
+    /// space.
+    fn page_keys(&self, prefix: &str, options: &ScanOptions) -> Vec<String> {
+        let ordered = self
+            .inner
+            .ordered
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        keys_after(
+            &ordered,
+            prefix,
+            options.start_after.as_deref(),
+            options.reverse,
+        )
+        .filter(|key| !self.expired(key))
+        .take(options.limit.unwrap_or(usize::MAX))
+        .cloned()
+        .collect()
+    }
+
+    /// Returns an iterator over the key-value pairs of the store, in key order, as of now
+    ///
+    /// The pairs are copied up front from a snapshot of the store: the iterator sees every
+    /// write completed before this call and none made after it. Writes wait while the snapshot
+    /// is taken, for as long as copying the keys and values held in memory takes, or reading
+    /// the values from the log in offset-index mode.
+    ///
+    /// # Errors
+    /// Returns `Err` if KV store read fails
+    #[allow(clippy::iter_not_returning_iterator)] // Reading values from the log may fail
+    pub fn iter(&self) -> Result<Iter> {
+        self.guard("iter", || {
+            if self.inner.options.offset_index {
+                // The writer thread waits for the index while values are read
+                let mut entries = self.inner.wal.scan(None, "")?;
+                entries.retain(|(key, _)| !self.expired(key));
+                return Ok(Iter::new(entries));
+            }
+
+            let snapshot = self
+                .inner
+                .snapshot
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            let mut entries: Vec<_> = self
+                .inner
+                .store
+                .iter()
+                .filter(|e| !self.expired(e.key()))
+                .map(|e| (e.key().clone(), e.value().clone()))
+                .collect();
+            drop(snapshot);
+            entries.sort_unstable();
+            Ok(Iter::new(entries))
+        })
+    }
+
+    /// Returns a receiver of changes to keys starting with prefix
+    ///
+    /// Changes made after this call are delivered once applied; changes by concurrent writers
+    /// may arrive in either order. Dropping the receiver unregisters the watcher.
+    pub fn watch(&self, prefix: impl Into<String>) -> mpsc::Receiver<WatchEvent> {
+        self.inner.watchers.watch(prefix.into())
+    }
+
+    /// Returns a receiver of the WAL records numbered from sequence number `from_seq` on, then
+    /// of the records committed from then on, e.g. to keep an off-site copy of the store up to
+    /// date
+    ///
+    /// The records first received are those logged so far, as a [`Shipment::Committed`]; from
+    /// `from_seq` 1 on, they hold the whole store. Once compaction may have dropped some of
+    /// them, the live records are sent instead, as a [`Shipment::Snapshot`] replacing the
+    /// copy, e.g. a [`Backup`](backup::Backup). Records are in the text format, whatever that
+    /// of the log. Dropping the receiver ends the subscription.
+    #[must_use]
+    pub fn tail(&self, from_seq: u64) -> mpsc::Receiver<Shipment> {
+        self.inner.wal.tail(from_seq)
+    }
+
+    /// Returns a handle to the named bucket, a key space separate from the store's own
+    ///
+    /// Buckets need not be created; one exists as long as it holds keys.
+    pub fn bucket(&self, name: impl Into<String>) -> Bucket<'_> {
+        Bucket::new(self, name.into())
+    }
+
+    /// Removes key-value pair from store for given key, returning the removed value
+    ///
+    /// The version of the key starts over if it is set again.
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::FailedRm`] if key is not found, or `Err` if on-disk WAL write fails
+    pub fn remove(&self, key: String) -> Result<String> {
+        self.guard_write("rm", || {
+            let _multi_key = self
+                .inner
+                .multi_key
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            let current = self.inner.versions.entry(key.clone());
+            if self.expired(&key) {
+                return Err(KvStoreError::FailedRm(key));
+            }
+            self.apply_rm(current, key)
+        })
+    }
+
+    /// Renames key from to key to, replacing any value of to, with a single log record
+    ///
+    /// Writes to the default key space wait for the rename. The new key starts over at version
+    /// 1, as if removed and set again. Readers may briefly see the value under both keys, but
+    /// never under neither.
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::KeyNotFound`] if from is not found, or `Err` if to exceeds the key
+    /// size limit, or on-disk WAL write fails
+    pub fn rename(&self, from: String, to: String) -> Result<()> {
+        self.guard_write("rename", || {
+            let _multi_key = self
+                .inner
+                .multi_key
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            let Some(value) = self.current_value(&from)? else {
+                return Err(KvStoreError::KeyNotFound(from));
+            };
+            if from == to {
+                return Ok(());
+            }
+            self.check_len(&to, &value)?;
+            self.apply_rename(&from, to, value)
+        })
+    }
+
+    /// Applies a logged rename of key from to key to holding value, whether or not from is
+    /// still present
+    ///
+    /// Compaction may have dropped the records setting from.
+    fn replay_rename(&self, from: &str, to: String, value: String) -> Result<()> {
+        self.guard_write("rename", || {
+            let _multi_key = self
+                .inner
+                .multi_key
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            self.apply_rename(from, to, value)
+        })
+    }
+
+    /// Applies and logs the rename of key from to key to holding value, while writes of several
+    /// keys are locked exclusively
+    fn apply_rename(&self, from: &str, to: String, value: String) -> Result<()> {
+        let events = self.inner.watchers.active().then(|| {
+            [
+                WatchEvent::Removed {
+                    key: from.to_owned(),
+                },
+                WatchEvent::Set {
+                    key: to.clone(),
+                    value: value.clone(),
+                },
+            ]
+        });
+
+        if let Some(evictor) = &self.inner.evictor {
+            evictor.forget(from);
+            evictor.set(&to, to.len() + value.len());
+        }
+        let indexed = self.inner.indexes.active().then(|| value.clone());
+        let record = Record::Rename {
+            from: from.to_owned(),
+            to: to.clone(),
+            value: value.clone(),
+        };
+        if self.inner.options.offset_index {
+            self.inner.wal.write(record)?;
+            if let Some(cache) = &self.inner.cache {
+                cache.invalidate(from);
+                cache.invalidate(&to);
+            }
+        } else {
+            // Writes held back are logged first, so that the record follows them
+            let coalescer = self.inner.wal.coalescer();
+            if let Some(mut coalescer) = coalescer {
+                self.inner.wal.write_pending(&mut coalescer)?;
+            }
+            let applying = self
+                .inner
+                .snapshot
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            self.inner.store.insert(to.clone(), value);
+            self.inner.store.remove(from);
+            let pending = self.inner.wal.append(record);
+            drop(applying);
+            self.logged(pending)?;
+        }
+        self.inner.indexes.remove(from);
+        self.inner.indexes.set(&to, indexed.as_deref());
+        self.inner.expiries.remove(from);
+        self.inner.expiries.remove(&to);
+        let mut order = self.order();
+        order.remove(from);
+        order.insert(to.clone());
+        drop(order);
+        self.inner.versions.remove(from);
+        self.inner.versions.insert(to, 1);
+
+        for event in events.into_iter().flatten() {
+            self.inner.watchers.notify(&event);
+        }
+
+        Ok(())
+    }
+
+    /// Copies the value of key from to key to, replacing any value of to if overwrite is set
+    ///
+    /// The copy is logged as a single `set` of to, while to is locked against other writes.
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::KeyNotFound`] if from is not found,
+    /// [`KvStoreError::KeyExists`] if to is present and overwrite is not set, or `Err` if to
+    /// exceeds the key size limit, or on-disk WAL write fails
+    pub fn copy(&self, from: &str, to: String, overwrite: bool) -> Result<()> {
+        let Some(value) = self.current_value(from)? else {
+            return Err(KvStoreError::KeyNotFound(from.to_owned()));
+        };
+        self.set_versioned("copy", to, value, None, |to, version| {
+            if version > 0 && !overwrite && !self.expired(to) {
+                Err(KvStoreError::KeyExists(to.to_owned()))
+            } else {
+                Ok(version + 1)
+            }
+        })
+        .map(drop)
+    }
+
+    /// Appends suffix to the value of key, setting key to suffix if missing, and returns the
+    /// length of the new value
+    ///
+    /// Appending is atomic, and logged as a record of the suffix alone rather than of the whole
+    /// value, unless writes are coalesced.
+    ///
+    /// # Errors
+    /// Returns `Err` if key or the new value exceeds its size limit, or on-disk WAL write fails
+    pub fn append(&self, key: String, suffix: String) -> Result<usize> {
+        self.guard_write("append", || {
+            self.check_memory()?;
+            let _multi_key = self
+                .inner
+                .multi_key
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            let current = self.inner.versions.entry(key.clone());
+            let version = current_version(&current) + 1;
+            let Some(mut value) = self.current_value(&key)? else {
+                self.check_len(&key, &suffix)?;
+                let len = suffix.len();
+                self.apply_set(current, key, suffix, version, None)?;
+                return Ok(len);
+            };
+            value.push_str(&suffix);
+            self.check_len(&key, &value)?;
+            let len = value.len();
+            // Coalesced writes are logged as whole values
+            if self.inner.wal.coalescer().is_some() {
+                let expires = self.expiry(&key);
+                self.apply_set(current, key, value, version, expires)?;
+                return Ok(len);
+            }
+
+            let event = self.inner.watchers.active().then(|| WatchEvent::Set {
+                key: key.clone(),
+                value: value.clone(),
+            });
+            if let Some(evictor) = &self.inner.evictor {
+                evictor.set(&key, len + key.len());
+            }
+            let indexed = self.inner.indexes.active().then(|| value.clone());
+            let record = Record::Append {
+                key: key.clone(),
+                suffix,
+            };
+            if self.inner.options.offset_index {
+                self.inner.wal.write(record)?;
+                self.inner.indexes.set(&key, indexed.as_deref());
+                current.insert(version);
+                if let Some(cache) = &self.inner.cache {
+                    cache.invalidate(&key);
+                }
+            } else {
+                let applying = self
+                    .inner
+                    .snapshot
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner);
+                let entry = self.inner.store.entry(key).insert(value);
+                drop(applying);
+                self.inner.indexes.set(entry.key(), indexed.as_deref());
+                let pending = self.inner.wal.append(record);
+                drop(entry);
+                current.insert(version);
+                self.logged(pending)?;
+            }
+            if let Some(event) = event {
+                self.inner.watchers.notify(&event);
+            }
+
+            Ok(len)
+        })
+        .inspect(|_| self.evict())
+    }
+
+    /// Returns the field at path of the JSON value of key as JSON, or `None` if the key or the
+    /// field is missing
+    ///
+    /// The path is a JSON pointer such as `/a/b`, or the equivalent `$.a.b`; `$` alone is the
+    /// whole value.
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::InvalidPath`] if path does not parse,
+    /// [`KvStoreError::InvalidJson`] if the value is not JSON, or `Err` if KV store read fails
+    pub fn get_path(&self, key: &str, path: &str) -> Result<Option<String>> {
+        let pointer =
+            document::pointer(path).ok_or_else(|| KvStoreError::InvalidPath(path.to_owned()))?;
+        self.get(key)?
+            .map(|value| document::get(key, &value, &pointer))
+            .transpose()
+            .map(Option::flatten)
+    }
+
+    /// Sets the field at path of the JSON value of key to value, parsed as JSON, logging the
+    /// modified value as a single record
+    ///
+    /// The value is read and written while key is locked against other writes, as by
+    /// [`KvStore::entry`]. Objects missing along the path are created, as is the value of key
+    /// if missing; an array element is set by its index, or appended if the index is the length
+    /// of the array or `-`. See [`KvStore::get_path`] for the syntax of path.
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::InvalidPath`] if path does not parse,
+    /// [`KvStoreError::InvalidJson`] if value or that of key is not JSON or path does not lead
+    /// into objects and arrays, or `Err` if the modified value exceeds its size limit, the store
+    /// is over its [memory budget](OpenOptions::max_memory), or on-disk WAL write fails
+    pub fn set_path(&self, key: String, path: &str, value: &str) -> Result<()> {
+        let pointer =
+            document::pointer(path).ok_or_else(|| KvStoreError::InvalidPath(path.to_owned()))?;
+        let field = serde_json::from_str(value)
+            .map_err(|e| KvStoreError::InvalidJson(format!("{value}: {e}")))?;
+        self.guard_write("set-path", || {
+            self.check_memory()?;
+            let _multi_key = self
+                .inner
+                .multi_key
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            let current = self.inner.versions.entry(key.clone());
+            let value = self.current_value(&key)?;
+            let value = document::set(&key, value.as_deref(), &pointer, field)?;
+            self.check_len(&key, &value)?;
+            let version = current_version(&current) + 1;
+            let expires = self.expiry(&key);
+            self.apply_set(current, key, value, version, expires)
+                .map(drop)
+        })
+        .inspect(|()| self.evict())
+    }
+
+    /// Removes the keys of store starting with prefix, returning how many were removed
+    ///
+    /// The removal is logged as a single record, however many keys it removes, and none if it
+    /// removes no key. Writes to the default key space wait for it; buckets are left as is.
+    ///
+    /// # Errors
+    /// Returns `Err` if on-disk WAL write fails
+    pub fn remove_prefix(&self, prefix: &str) -> Result<usize> {
+        self.guard_write("rm-prefix", || {
+            let _multi_key = self
+                .inner
+                .multi_key
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            self.apply_rm_prefix(prefix)
+        })
+    }
+
+    /// Removes all keys of store, returning how many were removed, like [`Self::remove_prefix`]
+    /// with an empty prefix
+    ///
+    /// # Errors
+    /// Returns `Err` if on-disk WAL write fails
+    pub fn clear(&self) -> Result<usize> {
+        self.remove_prefix("")
+    }
+
+    /// Applies and logs the removal of the keys starting with prefix, while writes of several
+    /// keys are locked exclusively
+    fn apply_rm_prefix(&self, prefix: &str) -> Result<usize> {
+        let now = self.inner.options.clock.unix_millis();
+        let keys: Vec<String> = if self.inner.options.offset_index {
+            self.inner.wal.keys(None, prefix)
+        } else {
+            self.inner
+                .store
+                .iter()
+                .filter(|e| e.key().starts_with(prefix))
+                .map(|e| e.key().clone())
+                .collect()
+        };
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        if let Some(evictor) = &self.inner.evictor {
+            for key in &keys {
+                evictor.forget(key);
+            }
+        }
+
+        let record = Record::RmPrefix {
+            prefix: prefix.to_owned(),
+        };
+        if self.inner.options.offset_index {
+            self.inner.wal.write(record)?;
+            if let Some(cache) = &self.inner.cache {
+                for key in &keys {
+                    cache.invalidate(key);
+                }
+            }
+        } else {
+            // Writes held back are logged first, so that the record follows them
+            let coalescer = self.inner.wal.coalescer();
+            if let Some(mut coalescer) = coalescer {
+                self.inner.wal.write_pending(&mut coalescer)?;
+            }
+            let applying = self
+                .inner
+                .snapshot
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            for key in &keys {
+                self.inner.store.remove(key);
+            }
+            let pending = self.inner.wal.append(record);
+            drop(applying);
+            self.logged(pending)?;
+        }
+        let expired = keys
+            .iter()
+            .filter(|key| {
+                self.inner
+                    .expiries
+                    .remove(key.as_str())
+                    .is_some_and(|(_, expires)| expires <= now)
+            })
+            .count();
+        let mut order = self.order();
+        for key in &keys {
+            order.remove(key);
+        }
+        drop(order);
+        for key in &keys {
+            self.inner.indexes.remove(key);
+            self.inner.versions.remove(key);
+        }
+
+        if self.inner.watchers.active() {
+            for key in &keys {
+                self.inner
+                    .watchers
+                    .notify(&WatchEvent::Removed { key: key.clone() });
+            }
+        }
+
+        Ok(keys.len() - expired)
+    }
+
+    /// Returns the value of key, without running the miss hook or filling the value cache
+    fn current_value(&self, key: &str) -> Result<Option<String>> {
+        if self.expired(key) {
+            return Ok(None);
+        }
+        self.stored_value(key)
+    }
+
+    /// Returns the value of key held by the store, expired or not
+    fn stored_value(&self, key: &str) -> Result<Option<String>> {
+        if self.inner.options.offset_index {
+            self.inner.wal.get(None, key)
+        } else {
+            Ok(self.inner.store.get(key).map(|v| v.value().clone()))
+        }
+    }
+
+    /// Applies and logs the removal of key, whose entry in the versions locks key, returning the
+    /// removed value
+    fn apply_rm(&self, current: dashmap::Entry<'_, String, u64>, key: String) -> Result<String> {
+        let event = self
+            .inner
+            .watchers
+            .active()
+            .then(|| WatchEvent::Removed { key: key.clone() });
+        if let Some(evictor) = &self.inner.evictor {
+            evictor.forget(&key);
+        }
+
+        let removed = if self.inner.options.offset_index {
+            let Some(removed) = self.stored_value(&key)? else {
+                return Err(KvStoreError::FailedRm(key));
+            };
+            self.inner.wal.write(Record::Rm { key: key.clone() })?;
+            self.inner.indexes.remove(&key);
+            self.inner.expiries.remove(&key);
+            self.order().remove(&key);
+            forget_version(current);
+            if let Some(cache) = &self.inner.cache {
+                cache.invalidate(&key);
+            }
+            removed
+        } else if let Some(mut coalescer) = self.inner.wal.coalescer() {
+            let applying = self
+                .inner
+                .snapshot
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            let Some((_, removed)) = self.inner.store.remove(&key) else {
+                return Err(KvStoreError::FailedRm(key));
+            };
+            drop(applying);
+            self.inner.indexes.remove(&key);
+            self.inner.expiries.remove(&key);
+            self.order().remove(&key);
+            forget_version(current);
+            self.inner.wal.coalesce(&mut coalescer, key, None, true)?;
+            removed
+        } else {
+            // Taken before the entry, as by the snapshot
+            let applying = self
+                .inner
+                .snapshot
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            let dashmap::Entry::Occupied(entry) = self.inner.store.entry(key.clone()) else {
+                return Err(KvStoreError::FailedRm(key));
+            };
+            self.inner.indexes.remove(entry.key());
+            self.inner.expiries.remove(entry.key());
+            self.order().remove(entry.key());
+            let pending = self.inner.wal.append(Record::Rm { key });
+            let removed = entry.remove();
+            drop(applying);
+            forget_version(current);
+            self.logged(pending)?;
+            removed
+        };
+
+        if let Some(event) = event {
+            self.inner.watchers.notify(&event);
+        }
+
+        Ok(removed)
+    }
+
+    /// Removes the keys whose [TTL](SetOptions::ttl) has run out, logging their removal, and
+    /// returns how many were removed
+    ///
+    /// Expired keys read as missing already, but their records stay in the log until removed.
+    /// [`Self::compact`] and [`Self::snapshot`] purge them first, unless the store is a replica
+    /// or Raft cluster member, whose removals come from the primary or leader; automatic
+    /// compactions copy them along with their expiry.
+    ///
+    /// # Errors
+    /// Returns `Err` if on-disk WAL write fails
+    pub fn purge_expired(&self) -> Result<usize> {
+        self.guard_write("purge-expired", || {
+            let mut purged = 0;
+            for key in self.expired_keys() {
+                let _multi_key = self
+                    .inner
+                    .multi_key
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner);
+                let current = self.inner.versions.entry(key.clone());
+                // Unless set again since
+                if !self.expired(&key) {
+                    continue;
+                }
+                match self.apply_rm(current, key) {
+                    Ok(_) => purged += 1,
+                    Err(KvStoreError::FailedRm(_)) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(purged)
+        })
+    }
+
+    /// Returns the entry of key, for atomic read-modify-write of its value
+    ///
+    /// The key is locked until the entry writes it or is dropped, see [`Entry`].
+    pub fn entry(&self, key: impl Into<String>) -> Entry<'_> {
+        Entry::new(self, key.into())
+    }
+
+    /// Declares a secondary index of name on a field of the JSON values of the default key
+    /// space, replacing any index of that name, and builds it from the current values
+    ///
+    /// The field is given by path, a JSON pointer such as `/email` or the equivalent
+    /// `$.email`. Writes keep the index up to date along with the values, and it is rebuilt
+    /// whenever the store is opened. String fields are indexed as is, numbers and booleans by
+    /// their JSON text; see [`KvStore::query_index`].
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::InvalidIndex`] if path does not parse, or `Err` if reading the
+    /// values or writing the declarations of the indexes fails
+    pub fn create_index(&self, name: &str, path: &str) -> Result<()> {
+        self.guard("index-create", || {
+            // Writes wait for the index to be built, so that it misses none
+            let _multi_key = self
+                .inner
+                .multi_key
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            self.build_indexes(vec![(name.to_owned(), path.to_owned())])?;
+            self.inner.indexes.save(&self.inner.dirs.store)
+        })
+    }
+
+    /// Drops the secondary index of name
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::IndexNotFound`] if there is no index of that name, or `Err` if
+    /// writing the declarations of the indexes fails
+    pub fn drop_index(&self, name: &str) -> Result<()> {
+        if !self.inner.indexes.drop(name) {
+            return Err(KvStoreError::IndexNotFound(name.to_owned()));
+        }
+        self.inner.indexes.save(&self.inner.dirs.store)
+    }
+
+    /// Returns the name and path of each secondary index, sorted by name
+    #[must_use]
+    pub fn indexes(&self) -> Vec<(String, String)> {
+        self.inner.indexes.list()
+    }
+
+    /// Returns the keys whose field indexed by the secondary index of name holds value, sorted
+    ///
+    /// Numbers and booleans are looked up by their JSON text, e.g. `42` or `true`.
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::IndexNotFound`] if there is no index of that name
+    pub fn query_index(&self, name: &str, value: &str) -> Result<Vec<String>> {
+        self.inner.indexes.query(name, value)
+    }
+
+    /// Builds indexes of the given names and paths from the current values in a single pass,
+    /// replacing those of the same names
+    fn build_indexes(&self, declared: Vec<(String, String)>) -> Result<()> {
+        if declared.is_empty() {
+            return Ok(());
+        }
+        let mut built = declared
+            .into_iter()
+            .map(|(name, path)| Ok((name, secondary::Index::new(&path)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let mut index = |key: &str, value: &str| {
+            if let Ok(value) = serde_json::from_str(value) {
+                for (_, index) in &mut built {
+                    index.insert(key, &value);
+                }
+            }
+        };
+        if self.inner.options.offset_index {
+            for (key, value) in self.inner.wal.scan(None, "")? {
+                index(&key, &value);
+            }
+        } else {
+            for entry in &self.inner.store {
+                index(entry.key(), entry.value());
+            }
+        }
+        for (name, index) in built {
+            self.inner.indexes.insert(name, index);
+        }
+        Ok(())
+    }
+
+    /// Returns the next unique ID for the named sequence
+    ///
+    /// IDs start at 1 and increase monotonically per sequence. They are reserved in batches,
+    /// each batch costing one synced WAL record, so IDs left unissued in a batch are skipped
+    /// after a restart rather than ever being handed out twice.
+    ///
+    /// # Errors
+    /// Returns `Err` if on-disk WAL write or sync fails
+    pub fn next_id(&self, sequence: impl Into<String>) -> Result<u64> {
+        let sequence = sequence.into();
+        self.guard_write("next-id", || {
+            let mut range = self.inner.sequences.entry(sequence.clone()).or_default();
+
+            if range.next >= range.end {
+                let end = range
+                    .end
+                    .checked_add(ID_BATCH)
+                    .ok_or_else(|| KvStoreError::IdsExhausted(sequence.clone()))?;
+                self.inner.wal.write(Record::ReserveIds {
+                    sequence: sequence.clone(),
+                    end,
+                })?;
+                self.inner.wal.sync_data()?;
+                range.end = end;
+            }
+
+            let id = range.next;
+            range.next += 1;
+
+            Ok(id)
+        })
+    }
+
+    /// Restores a sequence reservation replayed from the WAL
+    ///
+    /// Unissued IDs of the reserved batch are not known to be unused, so the sequence resumes
+    /// after the end of the batch.
+    ///
+    /// # Errors
+    /// Returns `Err` if on-disk WAL write fails
+    fn reserve_ids(&self, sequence: String, end: u64) -> Result<()> {
+        self.inner.wal.write(Record::ReserveIds {
+            sequence: sequence.clone(),
+            end,
+        })?;
+        self.reserve(sequence, end);
+
+        Ok(())
+    }
+
+    /// Resumes sequence after the end of a reserved batch, unless already past it
+    fn reserve(&self, sequence: String, end: u64) {
+        let mut range = self.inner.sequences.entry(sequence).or_default();
+        if end > range.end {
+            range.next = end;
+            range.end = end;
+        }
+    }
+
+    /// Appends a sample to the timeseries stored under key
+    ///
+    /// Timeseries live in their own key space, separate from string values.
+    ///
+    /// # Errors
+    /// Returns `Err` if timestamp is not after the last sample's, or on-disk WAL write fails
+    pub fn ts_add(&self, key: impl Into<String>, timestamp: i64, value: f64) -> Result<()> {
+        let key = key.into();
+        self.guard_write("ts", || {
+            self.check_memory()?;
+            let mut series = self.inner.series.entry(key.clone()).or_default();
+
+            if let Some(last) = series.last() {
+                if timestamp <= last.timestamp {
+                    return Err(KvStoreError::OutOfOrderSample(key, timestamp));
+                }
+            }
+
+            series.push(Sample { timestamp, value });
+            let pending = self.inner.wal.append(Record::Sample {
+                key,
+                timestamp,
+                value,
+            });
+            drop(series);
+
+            self.logged(pending)
+        })
+    }
+
+    /// Returns samples of the timeseries under key with timestamps in `from..=to`
+    ///
+    /// With an aggregation other than [`Aggregation::None`], returns at most one sample
+    /// stamped with `from` holding the aggregate value.
+    ///
+    /// # Errors
+    /// Returns `Err` if KV store read fails
+    pub fn ts_range(
+        &self,
+        key: impl Into<String>,
+        from: i64,
+        to: i64,
+        aggregation: Aggregation,
+    ) -> Result<Vec<Sample>> {
+        let key = key.into();
+        self.guard("ts-range", || match self.inner.series.get(&key) {
+            None => Ok(Vec::new()),
+            Some(series) => series
+                .range(from, to, aggregation)
+                .ok_or_else(|| self.poison(format!("corrupt timeseries chunk: {key}"))),
+        })
+    }
+
+    /// Pushes value to the front of the list stored under key, creating the list if missing,
+    /// and returns its new length
+    ///
+    /// Lists live in their own key space, separate from string values and sets. Values pushed
+    /// are popped in the same order by [`KvStore::rpop`], as from a queue.
+    ///
+    /// # Errors
+    /// Returns `Err` if key or value exceeds its size limit, the store is over its
+    /// [memory budget](OpenOptions::max_memory), or on-disk WAL write fails
+    pub fn lpush(&self, key: impl Into<String>, value: impl Into<String>) -> Result<usize> {
+        let key = key.into();
+        let value = value.into();
+        self.guard_write("lpush", || {
+            self.check_len(&key, &value)?;
+            self.check_memory()?;
+            let mut list = self.inner.lists.entry(key.clone()).or_default();
+            list.push_front(value.clone());
+            let len = list.len();
+            let pending = self.inner.wal.append(Record::ListPush { key, value });
+            drop(list);
+
+            self.logged(pending).map(|()| len)
+        })
+    }
+
+    /// Pops the value at the back of the list stored under key, the one pushed first, or
+    /// returns `None` if the list is empty; a list left empty is removed
+    ///
+    /// # Errors
+    /// Returns `Err` if on-disk WAL write fails
+    pub fn rpop(&self, key: &str) -> Result<Option<String>> {
+        self.guard_write("rpop", || {
+            let dashmap::Entry::Occupied(mut list) = self.inner.lists.entry(key.to_owned()) else {
+                return Ok(None);
+            };
+            let value = list.get_mut().pop_back();
+            let pending = self.inner.wal.append(Record::ListPop {
+                key: key.to_owned(),
+            });
+            if list.get().is_empty() {
+                list.remove();
+            } else {
+                drop(list);
+            }
+
+            self.logged(pending).map(|()| value)
+        })
+    }
+
+    /// Returns the values of the list stored under key from index start to stop, both
+    /// included, front first
+    ///
+    /// Negative indexes count from the back, -1 being the last value. Indexes past either end
+    /// of the list are clamped to it, so `lrange(key, 0, -1)` returns the whole list.
+    #[must_use]
+    pub fn lrange(&self, key: &str, start: i64, stop: i64) -> Vec<String> {
+        let Some(list) = self.inner.lists.get(key) else {
+            return Vec::new();
+        };
+        let len = i64::try_from(list.len()).unwrap_or(i64::MAX);
+        let index = |i: i64| if i < 0 { len + i } else { i };
+        let start = usize::try_from(index(start).max(0)).unwrap_or(usize::MAX);
+        let Ok(stop) = usize::try_from(index(stop).min(len - 1)) else {
+            return Vec::new();
+        };
+        if start > stop {
+            return Vec::new();
+        }
+        list.range(start..=stop).cloned().collect()
+    }
+
+    /// Adds member to the set stored under key, creating the set if missing, and returns
+    /// whether it was missing from the set
+    ///
+    /// Sets live in their own key space, separate from string values and lists. Adding a
+    /// member already present logs nothing.
+    ///
+    /// # Errors
+    /// Returns `Err` if key or member exceeds its size limit, the store is over its
+    /// [memory budget](OpenOptions::max_memory), or on-disk WAL write fails
+    pub fn sadd(&self, key: impl Into<String>, member: impl Into<String>) -> Result<bool> {
+        let key = key.into();
+        let member = member.into();
+        self.guard_write("sadd", || {
+            self.check_len(&key, &member)?;
+            self.check_memory()?;
+            let mut set = self.inner.sets.entry(key.clone()).or_default();
+            if !set.insert(member.clone()) {
+                return Ok(false);
+            }
+            let pending = self.inner.wal.append(Record::SetAdd { key, member });
+            drop(set);
+
+            self.logged(pending).map(|()| true)
+        })
+    }
+
+    /// Removes member from the set stored under key and returns whether it was present; a set
+    /// left empty is removed
+    ///
+    /// # Errors
+    /// Returns `Err` if on-disk WAL write fails
+    pub fn srem(&self, key: &str, member: &str) -> Result<bool> {
+        self.guard_write("srem", || {
+            let dashmap::Entry::Occupied(mut set) = self.inner.sets.entry(key.to_owned()) else {
+                return Ok(false);
+            };
+            if !set.get_mut().remove(member) {
+                return Ok(false);
+            }
+            let pending = self.inner.wal.append(Record::SetRemove {
+                key: key.to_owned(),
+                member: member.to_owned(),
+            });
+            if set.get().is_empty() {
+                set.remove();
+            } else {
+                drop(set);
+            }
+
+            self.logged(pending).map(|()| true)
+        })
+    }
+
+    /// Returns the members of the set stored under key, sorted
+    #[must_use]
+    pub fn smembers(&self, key: &str) -> Vec<String> {
+        self.inner
+            .sets
+            .get(key)
+            .map(|set| set.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Takes the lock under key for ttl unless another lease of it is unexpired, returning the
+    /// token of the new lease to [unlock](KvStore::unlock) it with
+    ///
+    /// Locks live in their own key space, separate from string values, and their leases are
+    /// logged, so that processes opening the store in turn, or clients of a server, can exclude
+    /// each other. Tokens increase with each lease taken, so that a resource guarded by the
+    /// lock can reject writes fenced with the token of a lease taken over since.
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::LockHeld`] if another lease is unexpired, or `Err` if ttl is zero,
+    /// key exceeds its size limit, or on-disk WAL write fails
+    pub fn lock(&self, key: impl Into<String>, ttl: Duration) -> Result<u64> {
+        let key = key.into();
+        if ttl.is_zero() {
+            return Err(KvStoreError::InvalidCommand(
+                "lock TTL must be positive".to_owned(),
+            ));
+        }
+        self.guard_write("lock", || {
+            self.check_len(&key, "")?;
+            let now = self.inner.options.clock.unix_millis();
+            let entry = self.inner.leases.entry(key.clone());
+            if let dashmap::Entry::Occupied(lease) = &entry {
+                if lease.get().expires > now {
+                    return Err(KvStoreError::LockHeld(key));
+                }
+            }
+            let token = self.inner.last_lease.fetch_add(1, Ordering::Relaxed) + 1;
+            let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+            let expires = now.saturating_add(ttl);
+            let lease = entry.insert(Lease { token, expires });
+            let pending = self.inner.wal.append(Record::Lease {
+                key,
+                token,
+                expires,
+            });
+            drop(lease);
+
+            self.logged(pending).map(|()| token)
+        })
+    }
+
+    /// Releases the lock under key held with the lease of token
+    ///
+    /// A lease that expired is released all the same unless taken over since.
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::LockNotHeld`] if the lock is not held with token, or `Err` if
+    /// on-disk WAL write fails
+    pub fn unlock(&self, key: &str, token: u64) -> Result<()> {
+        self.guard_write("unlock", || {
+            let dashmap::Entry::Occupied(lease) = self.inner.leases.entry(key.to_owned()) else {
+                return Err(KvStoreError::LockNotHeld(key.to_owned(), token));
+            };
+            if lease.get().token != token {
+                return Err(KvStoreError::LockNotHeld(key.to_owned(), token));
+            }
+            let pending = self.inner.wal.append(Record::Unlock {
+                key: key.to_owned(),
+                token,
+            });
+            lease.remove();
+
+            self.logged(pending)
+        })
+    }
+
+    /// Applies and logs a replayed lease of the lock under key, taken or not expired
+    fn replay_lease(&self, key: String, token: u64, expires: u64) -> Result<()> {
+        self.guard_write("lock", || {
+            self.inner.last_lease.fetch_max(token, Ordering::Relaxed);
+            let lease = self
+                .inner
+                .leases
+                .entry(key.clone())
+                .insert(Lease { token, expires });
+            let pending = self.inner.wal.append(Record::Lease {
+                key,
+                token,
+                expires,
+            });
+            drop(lease);
+
+            self.logged(pending)
+        })
+    }
+
+    /// Logs writes held back by coalescing, without syncing the WAL to disk
+    ///
+    /// The writes then survive the process crashing, though not the machine; see
+    /// [`KvStore::sync`] for that.
+    ///
+    /// # Errors
+    /// Returns `Err` if on-disk WAL write fails
+    pub fn flush(&self) -> Result<()> {
+        self.guard("flush", || self.inner.wal.write_coalesced())
+    }
+
+    /// Logs writes held back by coalescing and syncs the WAL to disk
+    ///
+    /// Dropping the store does the same; call this to make writes durable while the store is
+    /// still shared, e.g. at the end of a transaction or before exiting.
+    ///
+    /// # Errors
+    /// Returns `Err` if on-disk WAL write or sync fails
+    pub fn sync(&self) -> Result<()> {
+        self.guard("sync", || self.inner.wal.flush())
+    }
+
+    /// Compacts the WAL now if it holds superseded records, regardless of
+    /// [`OpenOptions::compaction_threshold`], and waits for it
+    ///
+    /// Writes held back by coalescing are logged first, and expired keys are
+    /// [purged](Self::purge_expired) so that their records are dropped. Like automatic
+    /// compactions, it runs in the background of the WAL writer, which goes on committing writes
+    /// meanwhile; it runs even while automatic compactions are stopped.
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::ReadOnly`] if writes are frozen, or `Err` if logging or
+    /// compaction fails, leaving the log as it was
+    pub fn compact(&self) -> Result<()> {
+        self.inner.freeze.check()?;
+        if self.check_writable().is_ok() {
+            self.purge_expired()?;
+        }
+        self.guard("compact", || {
+            self.inner.wal.flush()?;
+            self.inner.wal.compact(false).map(|_| ())
+        })
+    }
+
+    /// Rewrites the live records of the WAL as a new base segment, even if none are
+    /// superseded, so that opening the store replays from it
+    ///
+    /// Writes held back by coalescing are logged first, and expired keys are
+    /// [purged](Self::purge_expired) as by [`Self::compact`]. A Raft cluster member also drops
+    /// the entries of its Raft log applied to the store.
+    ///
+    /// # Errors
+    /// Returns `Err` if logging, compaction, or rewriting the Raft log fails
+    pub fn snapshot(&self) -> Result<()> {
+        self.write_snapshot().map(|_| ())
+    }
+
+    /// Writes the state of the store as a new base segment, then deletes the log segments it
+    /// replaces, returning the sequence number of the last record it covers
+    ///
+    /// Opening the store then loads the base segment rather than replaying the records up to
+    /// that sequence number, giving a manual bound on recovery time. This is
+    /// [`KvStore::snapshot`], covering every record logged when called; writes made meanwhile go
+    /// to the segments after the base one.
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::ReadOnly`] if writes are frozen, or `Err` if logging or
+    /// compaction fails, leaving the log as it was
+    pub fn checkpoint(&self) -> Result<u64> {
+        self.write_snapshot()
+    }
+
+    /// Writes the state of the store as a new base segment, returning the last sequence number
+    /// it covers
+    fn write_snapshot(&self) -> Result<u64> {
+        self.inner.freeze.check()?;
+        if self.check_writable().is_ok() {
+            self.purge_expired()?;
+        }
+        self.guard("snapshot", || {
+            self.inner.wal.flush()?;
+            // A forced compaction always writes a base segment
+            let through = self.inner.wal.compact(true)?.unwrap_or_default();
+            #[cfg(feature = "raft")]
+            if let Some(node) = self.cluster() {
+                node.snapshot()?;
+            }
+            Ok(through)
+        })
+    }
+
+    /// Lets automatic compactions run again after [`KvStore::stop_compaction`], and starts
+    /// compacting the WAL in the background if it holds superseded records, without waiting
+    ///
+    /// Progress shows in [`StoreStats::compaction`].
+    pub fn start_compaction(&self) {
+        self.inner.wal.start_compaction();
+    }
+
+    /// Stops automatic compactions until [`KvStore::start_compaction`], abandoning the running
+    /// one if any and leaving the WAL as it was
+    ///
+    /// # Errors
+    /// Returns `Err` if the WAL writer is gone
+    pub fn stop_compaction(&self) -> Result<()> {
+        self.inner.wal.abandon_compaction(true)
+    }
+
+    /// Sets the size in bytes of the log below which it is never compacted, while open
+    ///
+    /// See [`OpenOptions::compaction_threshold`].
+    pub fn set_compaction_threshold(&self, bytes: u64) {
+        self.inner.wal.set_compaction_threshold(bytes);
+    }
+
+    /// Sets the capacity of the value cache, evicting the least recently used values beyond it
+    ///
+    /// Returns `false` if the store was opened without a cache, see [`OpenOptions::cache`].
+    #[must_use]
+    pub fn set_cache_capacity(&self, bytes: usize) -> bool {
+        let Some(cache) = &self.inner.cache else {
+            return false;
+        };
+        cache.resize(bytes);
+        true
+    }
+
+    /// Returns the primary whose records the store applies, if it is a replica
+    ///
+    /// See [`replication`].
+    pub fn replica_of(&self) -> Option<SocketAddr> {
+        *self
+            .inner
+            .replica_of
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Stops being a replica, so that servers accept writes from clients
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::NotReplica`] if the store is not a replica
+    pub fn promote(&self) -> Result<()> {
+        let primary = self
+            .inner
+            .replica_of
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+            .ok_or(KvStoreError::NotReplica)?;
+        info!(%primary, "Promoted replica");
+        Ok(())
+    }
+
+    /// Returns the sequence number of the last write, to pass as session token to
+    /// [`KvStore::wait_for`] on a replica
+    ///
+    /// This is the last record logged, whose sequence number is kept across reopens, or if the
+    /// store is a replica, the last record of its primary it applied. Writes held back by coalescing are only covered
+    /// once logged.
+    #[must_use]
+    pub fn sequence(&self) -> u64 {
+        if self.replica_of().is_some() {
+            self.inner.applied.get()
+        } else {
+            self.inner.wal.sequence()
+        }
+    }
+
+    /// Waits up to timeout until the write with a session token from the primary's
+    /// [`KvStore::sequence`] is applied, returning whether it was
+    ///
+    /// Returns `true` right away unless the store is a replica.
+    #[must_use]
+    pub fn wait_for(&self, sequence: u64, timeout: Duration) -> bool {
+        self.replica_of().is_none() || self.inner.applied.wait_for(sequence, timeout)
+    }
+
+    /// Returns the Raft member of the store, if it belongs to a cluster
+    #[cfg(feature = "raft")]
+    pub(crate) fn cluster(&self) -> Option<&raft::Node> {
+        self.inner.cluster.as_ref()
+    }
+
+    /// Fails if the store is a replica, which only applies writes from its primary
+    ///
+    /// Servers check this before executing writes of clients; the store itself accepts them.
+    /// Writes to a cluster member only go through the Raft log, by its TCP server.
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::ReadOnly`] if writes are frozen,
+    /// [`KvStoreError::ReadOnlyReplica`] if the store is a replica, or
+    /// [`KvStoreError::ClusterWrite`] if it belongs to a cluster
+    pub fn check_writable(&self) -> Result<()> {
+        self.inner.freeze.check()?;
+        #[cfg(feature = "raft")]
+        if self.inner.cluster.is_some() {
+            return Err(KvStoreError::ClusterWrite);
+        }
+        match self.replica_of() {
+            Some(primary) => Err(KvStoreError::ReadOnlyReplica(primary)),
+            None => Ok(()),
+        }
+    }
+
+    /// Rejects writes with [`KvStoreError::ReadOnly`] until [`KvStore::thaw`], while still
+    /// serving reads
+    ///
+    /// Waits for the writes in progress, abandons any compaction running in the background,
+    /// then logs writes held back by coalescing and syncs the WAL, so that the store directory holds the whole store and stays as is: copy it for a
+    /// consistent backup, or migrate it. Compaction is rejected alike. A replica stops applying
+    /// the records of its primary until thawed, then catches up; a Raft cluster member rejects
+    /// writes of clients but keeps applying the entries its leader commits.
+    ///
+    /// # Errors
+    /// Returns `Err` if logging or syncing fails, leaving writes frozen
+    pub fn freeze_writes(&self) -> Result<()> {
+        self.inner.freeze.freeze();
+        info!("Writes frozen");
+        self.inner.wal.abandon_compaction(false)?;
+        self.sync()
+    }
+
+    /// Accepts writes again after [`KvStore::freeze_writes`]
+    pub fn thaw(&self) {
+        self.inner.freeze.thaw();
+        info!("Writes thawed");
+    }
+
+    /// Returns whether writes are frozen, see [`KvStore::freeze_writes`]
+    #[must_use]
+    pub fn is_frozen(&self) -> bool {
+        self.inner.freeze.is_frozen()
+    }
+
+    /// Returns why the store was poisoned, if it was
+    ///
+    /// A poisoned store has detected a violated internal invariant and only serves reads;
+    /// reopen it from disk to recover.
+    pub fn poisoned(&self) -> Option<&str> {
+        self.inner.poisoned.get().map(String::as_str)
+    }
+
+    /// Marks the store as poisoned, keeping the first reason, and returns the matching error
+    fn poison(&self, reason: String) -> KvStoreError {
+        let reason = self.inner.poisoned.get_or_init(|| reason);
+        error!(reason, "KV store poisoned");
+        KvStoreError::Poisoned(reason.clone())
+    }
+
+    /// Runs a mutating operation, rejecting it if the store is poisoned
+    fn guard_write<T>(&self, name: &'static str, op: impl FnOnce() -> Result<T>) -> Result<T> {
+        if let Some(reason) = self.poisoned() {
+            return Err(KvStoreError::Poisoned(reason.to_owned()));
+        }
+        let _writing = self.inner.freeze.enter()?;
+
+        self.guard(name, op)
+    }
+
+    /// Runs an operation, poisoning the store instead of unwinding if it panics in panic-free mode
+    ///
+    /// Operation name labels the metrics recorded for it.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    fn guard<T>(&self, name: &'static str, op: impl FnOnce() -> Result<T>) -> Result<T> {
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
+
+        let result = if self.inner.options.panic_free {
+            panic::catch_unwind(AssertUnwindSafe(op))
+                .unwrap_or_else(|payload| Err(self.poison(panic_message(payload.as_ref()))))
+        } else {
+            op()
+        };
+
+        #[cfg(feature = "metrics")]
+        self.inner.metrics.command(name, start.elapsed());
+
+        result
+    }
+
+    /// Returns the time source used by the store
+    #[must_use]
+    pub fn clock(&self) -> &dyn Clock {
+        self.inner.options.clock.as_ref()
+    }
+
+    /// Returns statistics of the store's size and activity
+    #[must_use]
+    pub fn stats(&self) -> StoreStats {
+        let keys = if self.inner.options.offset_index {
+            self.inner.wal.key_count()
+        } else {
+            self.inner.store.len() + self.inner.buckets.len()
+        };
+        let usage = self.inner.wal.usage();
+
+        StoreStats {
+            keys,
+            live_bytes: usage.len - usage.dead,
+            dead_bytes: usage.dead,
+            segments: self.inner.wal.segment_count(),
+            last_compaction: usage.last_compaction,
+            compaction: self.inner.wal.compaction().progress(),
+            compaction_stopped: self.inner.wal.compaction().stopped(),
+            memory_bytes: self.memory_bytes(),
+            evictions: self.inner.evictor.as_ref().map_or(0, Evictor::evictions),
+            uptime: self
+                .clock()
+                .now()
+                .duration_since(self.inner.opened)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Returns the health of the store, for liveness and readiness probes
+    ///
+    /// Checks that the store directory is writable by writing and removing a file in it, and
+    /// so the directories of its segments if elsewhere.
+    #[must_use]
+    pub fn health(&self) -> Health {
+        let mut dirs = vec![self.inner.dirs.store.as_path()];
+        dirs.extend(self.inner.dirs.segment_dirs());
+        dirs.dedup();
+        let writable = dirs.iter().try_for_each(|dir| {
+            let probe = dir.join(".health");
+            fs::write(&probe, b"ok").and_then(|()| fs::remove_file(&probe))
+        });
+        if let Err(e) = &writable {
+            warn!("Store directory is not writable: {e}");
+        }
+
+        Health {
+            writable: writable.is_ok(),
+            last_sync: self.inner.wal.usage().last_sync,
+            replication_lag: self.replica_of().map(|_| {
+                self.inner.applied.silence().unwrap_or_else(|| {
+                    self.clock()
+                        .now()
+                        .duration_since(self.inner.opened)
+                        .unwrap_or_default()
+                })
+            }),
+        }
+    }
+
+    /// Returns metrics recorded since the store was opened
+    #[cfg(feature = "metrics")]
+    #[must_use]
+    pub fn metrics(&self) -> &Metrics {
+        &self.inner.metrics
+    }
+}
+
+/// Extracts the message of a caught panic
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        format!("panicked: {s}")
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        format!("panicked: {s}")
+    } else {
+        "panicked".to_owned()
+    }
+}
+
+/// Returns the keys of ordered starting with prefix after `start_after`, in order or in
+/// reverse
+fn keys_after<'a>(
+    ordered: &'a BTreeSet<String>,
+    prefix: &'a str,
+    start_after: Option<&str>,
+    reverse: bool,
+) -> Box<dyn Iterator<Item = &'a String> + 'a> {
+    let starts = move |key: &&String| key.starts_with(prefix);
+    if !reverse {
+        let start = match start_after {
+            Some(start) if start >= prefix => Bound::Excluded(start),
+            _ => Bound::Included(prefix),
+        };
+        return Box::new(
+            ordered
+                .range::<str, _>((start, Bound::Unbounded))
+                .take_while(starts),
+        );
+    }
+
+    let prefix_end = prefix_end(prefix);
+    let end = match (start_after, prefix_end.as_deref()) {
+        (Some(start), Some(end)) if end < start => end,
+        (Some(start), _) => start,
+        (None, Some(end)) => end,
+        (None, None) => {
+            return Box::new(
+                ordered
+                    .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+                    .rev()
+                    .take_while(starts),
+            );
+        }
+    };
+    if end < prefix {
+        return Box::new(std::iter::empty());
+    }
+    Box::new(
+        ordered
+            .range::<str, _>((Bound::Included(prefix), Bound::Excluded(end)))
+            .rev()
+            .take_while(starts),
+    )
+}
+
+/// Returns the least string after every string starting with prefix, or `None` if there is
+/// none
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut end = prefix.to_owned();
+    while let Some(c) = end.pop() {
+        // Skipping the surrogates, which are not chars
+        let next = match c {
+            '\u{d7ff}' => Some('\u{e000}'),
+            c => char::from_u32(u32::from(c) + 1),
+        };
+        if let Some(next) = next {
+            end.push(next);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// Returns the version of a key held by an entry of [`KvStore::versions`], 0 if missing
+fn current_version(entry: &dashmap::Entry<'_, String, u64>) -> u64 {
+    match entry {
+        dashmap::Entry::Occupied(entry) => *entry.get(),
+        dashmap::Entry::Vacant(_) => 0,
+    }
+}
+
+/// Forgets the version of a key removed from the store
+fn forget_version(entry: dashmap::Entry<'_, String, u64>) {
+    if let dashmap::Entry::Occupied(entry) = entry {
+        entry.remove();
+    }
+}
+
+/// Error wrapper for KV store methods
+///
+/// Errors caused by the user are told from those of storage or the environment by their
+/// [kind](Self::kind), and by [`KvStoreError::split`] into a [`UserError`] or a
+/// [`StorageError`]. Errors wrapping another expose it as their
+/// [`source`](std::error::Error::source).
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum KvStoreError {
+    /// Unknown current working directory
+    #[error("Current working directory could not be determined")]
+    UnknownCwd(#[source] io::Error),
+    /// Failed old WAL rename
+    #[error("Failed to rename old WAL")]
+    FailedWalRename(#[source] io::Error),
+    /// Failed old WAL restore
+    #[error("Failed to restore old WAL")]
+    FailedWalRestore(#[source] io::Error),
     /// Failed new WAL open
-    #[error("Failed to open new WAL: {0}")]
-    FailedWalOpen(io::Error),
+    #[error("Failed to open new WAL")]
+    FailedWalOpen(#[source] io::Error),
     /// Failed old WAL open
-    #[error("Failed to open old WAL: {0}")]
-    FailedOldWalOpen(io::Error),
+    #[error("Failed to open old WAL")]
+    FailedOldWalOpen(#[source] io::Error),
     /// Failed line read from WAL
-    #[error("Failed reading line from write-ahead log: {0}")]
-    FailedWalLineRead(io::Error),
+    #[error("Failed reading line from write-ahead log")]
+    FailedWalLineRead(#[source] io::Error),
     /// Failed WAL write
-    #[error("Failed to write to WAL: {0}")]
-    FailedWalWrite(io::Error),
+    #[error("Failed to write to WAL")]
+    FailedWalWrite(#[source] io::Error),
+    /// Failed reading a value from its WAL record in offset-index mode
+    #[error("Failed to read value from WAL")]
+    FailedValueRead(#[source] io::Error),
     /// Generic command deserialization error wrapper
-    #[error("Deserialization failure: {0}")]
+    #[error("Deserialization failure")]
     DeserializeCommand(#[from] serde_json::error::Error),
+    /// Failed checking the store directory
+    #[error("Failed to check store")]
+    FailedCheck(#[source] io::Error),
+    /// Failed reading log files to list their records
+    #[error("Failed to dump log")]
+    FailedDump(#[source] io::Error),
+    /// Failed writing shell completions or man pages
+    #[error("Failed to write completions or man page")]
+    FailedDocs(#[source] io::Error),
+    /// Store check left issues unresolved
+    #[error("Store check found {0} unresolved issues")]
+    Unhealthy(usize),
+    /// Command vetoed by a [`Hook`]
+    #[error("Command vetoed: {0}")]
+    Vetoed(String),
+    /// Write rejected while writes are frozen, see [`KvStore::freeze_writes`]
+    #[error("Store is read-only: writes are frozen")]
+    ReadOnly,
+    /// Server not ready to take traffic, see [`Health::problem`]
+    #[error("Server not ready: {0}")]
+    NotReady(String),
+    /// Failed opening or locking the lock file of the store directory
+    #[error("Failed to lock store directory")]
+    FailedLock(#[source] io::Error),
+    /// Store directory locked by another open store, see [`OpenOptions::lock_timeout`]
+    #[error("Store directory {} is locked by another open store", .0.display())]
+    Locked(PathBuf),
+    /// Failed reading or writing the manifest
+    #[error("Failed to access manifest")]
+    FailedManifest(#[source] io::Error),
+    /// Manifest does not parse
+    #[error("Invalid manifest: {0}")]
+    InvalidManifest(String),
+    /// Store opened with other WAL or data directories than recorded in its manifest, see
+    /// [`OpenOptions::wal_dir`]
+    #[error("Store layout differs from its manifest: {0}")]
+    WrongLayout(String),
+    /// Store written by another storage engine
+    #[error("Store was written by engine {0}")]
+    WrongEngine(String),
+    /// Store written by an older version, upgradable by [`migrate::migrate`]
+    #[error("Store format version {0} is older than {1}, run `kvs migrate` to upgrade it")]
+    OutdatedFormat(u32, u32),
+    /// Store written by a newer version
+    #[error("Store format version {0} is newer than supported version {1}")]
+    UnsupportedFormat(u32, u32),
+    /// Failed upgrading the store directory
+    #[error("Failed to migrate store")]
+    FailedMigration(#[source] io::Error),
+    /// Failed compacting the WAL on request
+    #[error("Failed to compact WAL")]
+    FailedCompaction(#[source] io::Error),
+    /// Failed serializing command output
+    #[error("Serialization failure")]
+    SerializeOutput(#[source] serde_json::Error),
     /// Invalid/unsupported command
     #[error("Invalid command: {0}")]
     InvalidCommand(String),
@@ -264,19 +3404,390 @@ pub enum KvStoreError {
     /// Failed KV store remove
     #[error("Key not found: {0}")]
     FailedRm(String),
+    /// Key to rename or copy not found
+    #[error("Key not found: {0}")]
+    KeyNotFound(String),
+    /// Key to copy to present, without overwriting it
+    #[error("Key exists already: {0}")]
+    KeyExists(String),
+    /// History read without retained history
+    #[error("History is not retained, see OpenOptions::history_retention")]
+    HistoryDisabled,
+    /// History read as of a sequence number outside the retention window
+    #[error("History as of sequence {0} is not retained, only as of {1} to {2}")]
+    HistoryUnavailable(u64, u64, u64),
+    /// Conditional set of a key at another version than expected
+    #[error("Key {0} is at version {2}, expected version {1}")]
+    VersionMismatch(String, u64, u64),
+    /// Key exceeds the maximum key length
+    #[error("Key too large: {0} bytes, limit {1}")]
+    KeyTooLarge(usize, usize),
+    /// Value exceeds the maximum value size
+    #[error("Value too large: {0} bytes, limit {1}")]
+    ValueTooLarge(usize, usize),
+    /// Path into JSON values that does not parse
+    #[error("Invalid path: {0}")]
+    InvalidPath(String),
+    /// Value that is not JSON, or without the structure a path leads through
+    #[error("Invalid JSON: {0}")]
+    InvalidJson(String),
+    /// Path of a secondary index, or the file declaring the indexes, that does not parse
+    #[error("Invalid index: {0}")]
+    InvalidIndex(String),
+    /// No secondary index of the name
+    #[error("Index not found: {0}")]
+    IndexNotFound(String),
+    /// Failed reading or writing the file declaring the secondary indexes
+    #[error("Failed to access indexes")]
+    FailedIndexes(#[source] io::Error),
+    /// Lock taken with a lease that has not expired, see [`KvStore::lock`]
+    #[error("Lock is held: {0}")]
+    LockHeld(String),
+    /// Lock to release not held with the lease of the token
+    #[error("Lock {0} is not held with token {1}")]
+    LockNotHeld(String, u64),
+    /// Memory taken by the store exceeds its budget
+    #[error("Out of memory budget: {0} bytes used, limit {1}")]
+    OutOfMemoryBudget(u64, u64),
+    /// ID sequence reached `u64::MAX`
+    #[error("ID sequence exhausted: {0}")]
+    IdsExhausted(String),
+    /// Timeseries sample not newer than the last one
+    #[error("Sample timestamp {1} not after last sample of timeseries: {0}")]
+    OutOfOrderSample(String, i64),
+    /// Failed starting runtime background thread
+    #[error("Failed to start runtime")]
+    FailedRuntimeStart(#[source] io::Error),
+    /// Failed serving network clients
+    #[error("Failed to serve")]
+    FailedServe(#[source] io::Error),
+    /// Failed starting thread pool threads
+    #[error("Failed to start thread pool")]
+    FailedPoolStart(#[source] io::Error),
+    /// Failed connecting to a server
+    #[error("Failed to connect")]
+    FailedConnect(#[source] io::Error),
+    /// Failed sending a request or reading its response
+    #[error("Failed request")]
+    FailedRequest(#[source] io::Error),
+    /// Request not answered before its deadline, see
+    /// [`ClientOptions::request_timeout`](client::ClientOptions::request_timeout)
+    #[error("Request timed out after {0:?}")]
+    Timeout(Duration),
+    /// Failed loading TLS certificates or keys
+    #[cfg(feature = "tls")]
+    #[error("Failed to set up TLS")]
+    FailedTls(#[source] io::Error),
+    /// Server reported an error
+    #[error("Server error: {0}")]
+    Remote(String),
+    /// Server over a rate or connection limit refused a request or connection
+    #[error("Server busy: {0}")]
+    Busy(String),
+    /// Credentials rejected by a server, or request denied to the user
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    /// Failed reading the ACL config file
+    #[error("Failed to read ACL config")]
+    FailedAcl(#[source] io::Error),
+    /// ACL config file is malformed
+    #[error("Invalid ACL config: {0}")]
+    InvalidAcl(String),
+    /// Failed reading the server config file
+    #[error("Failed to read server config")]
+    FailedConfig(#[source] io::Error),
+    /// Server config is malformed or inconsistent
+    #[error("Invalid server config: {0}")]
+    InvalidConfig(String),
+    /// Write sent to a replica
+    #[error("Read-only replica of {0}")]
+    ReadOnlyReplica(SocketAddr),
+    /// Replica operation on a store that is not a replica
+    #[error("Store is not a replica")]
+    NotReplica,
+    /// Failed shipping records to a replica
+    #[error("Failed to replicate")]
+    FailedReplication(#[source] io::Error),
+    /// Failed writing the records of a backup
+    #[error("Failed to write backup")]
+    FailedBackup(#[source] io::Error),
+    /// Failed reading a file to import
+    #[error("Failed to import")]
+    FailedImport(#[source] io::Error),
+    /// File to import that is malformed or unsupported
+    #[error("Invalid import: {0}")]
+    InvalidImport(String),
+    /// Failed reading or writing a SQLite file
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite failed")]
+    Sqlite(#[from] rusqlite::Error),
+    /// Failed starting the thread of a trigger
+    #[error("Failed to start trigger")]
+    FailedTrigger(#[source] io::Error),
+    /// Failed uploading to or fetching from an archive
+    #[cfg(feature = "archive")]
+    #[error("Failed to archive")]
+    FailedArchive(#[source] io::Error),
+    /// Archive location or contents that cannot be used
+    #[cfg(feature = "archive")]
+    #[error("Invalid archive: {0}")]
+    InvalidArchive(String),
+    /// Write proposed to a Raft cluster member other than the leader
+    #[cfg(feature = "raft")]
+    #[error("Not the cluster leader, leader is {}", .0.map_or_else(|| "unknown".to_owned(), |leader| leader.to_string()))]
+    NotLeader(Option<SocketAddr>),
+    /// Raft operation on a store that is not a cluster member
+    #[cfg(feature = "raft")]
+    #[error("Store is not a cluster member")]
+    NotClusterMember,
+    /// Write bypassing the Raft log of a cluster member
+    #[cfg(feature = "raft")]
+    #[error("Writes to a cluster member go through the Raft log of its TCP server")]
+    ClusterWrite,
+    /// Failed persisting the Raft log or running its threads
+    #[cfg(feature = "raft")]
+    #[error("Failed to run Raft")]
+    FailedRaft(#[source] io::Error),
+    /// Store is read-only after an internal invariant violation
+    #[error("KV store poisoned, reopen to recover: {0}")]
+    Poisoned(String),
+}
+
+/// Category of a [`KvStoreError`], whose number the CLI exits with
+///
+/// Numbers are stable, so that scripts can tell errors apart without parsing messages.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, Serialize)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// Malformed or unsupported command or arguments, 1
+    Usage = 1,
+    /// Key to remove, rename or copy not found, or key checked by `kvs exists` missing, 2
+    KeyNotFound = 2,
+    /// Failed file, network or terminal I/O, 3
+    Io = 3,
+    /// Store files or input that are corrupted or in an unsupported format, 4
+    Corrupted = 4,
+    /// Write conflicting with the state of the store, e.g. at another version, 5
+    Conflict = 5,
+    /// Store or server not taking the command for now, e.g. read-only, busy or locked, 6
+    Unavailable = 6,
+    /// Command denied to the user or vetoed, 7
+    Denied = 7,
+    /// Error reported by a server, 8
+    Remote = 8,
+}
+
+impl ErrorKind {
+    /// Returns the exit code of the CLI for errors of this kind
+    #[must_use]
+    pub fn exit_code(self) -> u8 {
+        self as u8
+    }
+
+    /// Returns whether errors of this kind are caused by the user, i.e. by a command, its
+    /// arguments, or the state of the keys it meets, rather than by storage, I/O, or the
+    /// availability of the store or server
+    #[must_use]
+    pub fn is_user(self) -> bool {
+        match self {
+            Self::Usage | Self::KeyNotFound | Self::Conflict | Self::Denied => true,
+            Self::Io | Self::Corrupted | Self::Unavailable | Self::Remote => false,
+        }
+    }
+}
+
+/// [`KvStoreError`] caused by the user, of a kind for which [`ErrorKind::is_user`] holds
+///
+/// Converts back into the [`KvStoreError`] it was split from.
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct UserError(KvStoreError);
+
+/// [`KvStoreError`] of storage, I/O, or the availability of the store or server, of a kind for
+/// which [`ErrorKind::is_user`] does not hold
+///
+/// Converts back into the [`KvStoreError`] it was split from.
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct StorageError(KvStoreError);
+
+impl UserError {
+    /// Returns the error split off
+    #[must_use]
+    pub fn error(&self) -> &KvStoreError {
+        &self.0
+    }
+
+    /// Returns the category of the error
+    #[must_use]
+    pub fn kind(&self) -> ErrorKind {
+        self.0.kind()
+    }
+}
+
+impl StorageError {
+    /// Returns the error split off
+    #[must_use]
+    pub fn error(&self) -> &KvStoreError {
+        &self.0
+    }
+
+    /// Returns the category of the error
+    #[must_use]
+    pub fn kind(&self) -> ErrorKind {
+        self.0.kind()
+    }
+}
+
+impl From<UserError> for KvStoreError {
+    fn from(e: UserError) -> Self {
+        e.0
+    }
+}
+
+impl From<StorageError> for KvStoreError {
+    fn from(e: StorageError) -> Self {
+        e.0
+    }
+}
+
+impl KvStoreError {
+    /// Returns the error as a [`UserError`] if caused by the user, or a [`StorageError`]
+    /// otherwise
+    ///
+    /// # Errors
+    /// Returns the error as a [`StorageError`] if [`ErrorKind::is_user`] does not hold for its
+    /// kind
+    pub fn split(self) -> std::result::Result<UserError, StorageError> {
+        if self.kind().is_user() {
+            Ok(UserError(self))
+        } else {
+            Err(StorageError(self))
+        }
+    }
+
+    /// Returns the category of the error
+    #[must_use]
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::InvalidCommand(_)
+            | Self::MissingCommand
+            | Self::MissingKey(_)
+            | Self::MissingValue(_)
+            | Self::HistoryDisabled
+            | Self::HistoryUnavailable(..)
+            | Self::KeyTooLarge(..)
+            | Self::ValueTooLarge(..)
+            | Self::OutOfOrderSample(..)
+            | Self::InvalidAcl(_)
+            | Self::InvalidConfig(_)
+            | Self::InvalidPath(_)
+            | Self::InvalidJson(_)
+            | Self::InvalidIndex(_)
+            | Self::IndexNotFound(_)
+            | Self::WrongLayout(_)
+            | Self::NotReplica => ErrorKind::Usage,
+            #[cfg(feature = "archive")]
+            Self::InvalidArchive(_) => ErrorKind::Usage,
+            #[cfg(feature = "raft")]
+            Self::NotClusterMember | Self::ClusterWrite => ErrorKind::Usage,
+            Self::FailedRm(_) | Self::KeyNotFound(_) => ErrorKind::KeyNotFound,
+            Self::UnknownCwd(_)
+            | Self::FailedWalRename(_)
+            | Self::FailedWalRestore(_)
+            | Self::FailedWalOpen(_)
+            | Self::FailedOldWalOpen(_)
+            | Self::FailedWalLineRead(_)
+            | Self::FailedWalWrite(_)
+            | Self::FailedValueRead(_)
+            | Self::FailedCheck(_)
+            | Self::FailedDump(_)
+            | Self::FailedDocs(_)
+            | Self::FailedLock(_)
+            | Self::FailedManifest(_)
+            | Self::FailedMigration(_)
+            | Self::FailedCompaction(_)
+            | Self::SerializeOutput(_)
+            | Self::FailedRuntimeStart(_)
+            | Self::FailedServe(_)
+            | Self::FailedPoolStart(_)
+            | Self::FailedConnect(_)
+            | Self::FailedRequest(_)
+            | Self::FailedAcl(_)
+            | Self::FailedConfig(_)
+            | Self::FailedReplication(_)
+            | Self::FailedBackup(_)
+            | Self::FailedImport(_)
+            | Self::FailedIndexes(_)
+            | Self::FailedTrigger(_) => ErrorKind::Io,
+            #[cfg(feature = "tls")]
+            Self::FailedTls(_) => ErrorKind::Io,
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(_) => ErrorKind::Io,
+            #[cfg(feature = "archive")]
+            Self::FailedArchive(_) => ErrorKind::Io,
+            #[cfg(feature = "raft")]
+            Self::FailedRaft(_) => ErrorKind::Io,
+            Self::DeserializeCommand(_)
+            | Self::Unhealthy(_)
+            | Self::InvalidManifest(_)
+            | Self::WrongEngine(_)
+            | Self::OutdatedFormat(..)
+            | Self::UnsupportedFormat(..)
+            | Self::InvalidImport(_)
+            | Self::Poisoned(_) => ErrorKind::Corrupted,
+            Self::VersionMismatch(..)
+            | Self::IdsExhausted(_)
+            | Self::KeyExists(_)
+            | Self::LockHeld(_)
+            | Self::LockNotHeld(..) => ErrorKind::Conflict,
+            Self::ReadOnly
+            | Self::NotReady(_)
+            | Self::Locked(_)
+            | Self::Timeout(_)
+            | Self::Busy(_)
+            | Self::OutOfMemoryBudget(..)
+            | Self::ReadOnlyReplica(_) => ErrorKind::Unavailable,
+            #[cfg(feature = "raft")]
+            Self::NotLeader(_) => ErrorKind::Unavailable,
+            Self::Vetoed(_) | Self::PermissionDenied(_) => ErrorKind::Denied,
+            Self::Remote(_) => ErrorKind::Remote,
+        }
+    }
+
+    /// Returns the message of the error followed by those of its sources, each after a colon
+    ///
+    /// The message of an error wrapping another leaves the cause to its
+    /// [`source`](std::error::Error::source), so this is what is shown to users.
+    #[must_use]
+    pub fn report(&self) -> String {
+        let mut report = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(cause) = source {
+            report.push_str(": ");
+            report.push_str(&cause.to_string());
+            source = cause.source();
+        }
+        report
+    }
 }
 
 /// Supported operations on KV store
 /// - Source of truth for CLI subcommands
 /// - Specifies serde format for WAL read/write
-#[derive(Debug, Display, EnumString, PartialEq, Subcommand)]
+#[derive(Clone, Debug, Display, EnumString, PartialEq, Subcommand)]
 #[strum(serialize_all = "lowercase")]
 pub enum Command {
-    /// Get value by key
+    /// Get value by key, printing `Key not found` if missing
     Get {
         #[arg(required = true)]
         /// Key string
         key: String,
+        /// Get the field of the JSON value at this path as JSON instead, a JSON pointer such as
+        /// `/a/b` or `$.a.b`
+        #[arg(long)]
+        path: Option<String>,
     },
     /// Set key-value pair by key
     Set {
@@ -286,29 +3797,635 @@ pub enum Command {
         /// Value string
         #[arg(required = true)]
         value: String,
+        /// Set the field of the JSON value at this path to the value parsed as JSON instead,
+        /// creating missing objects along the path
+        #[arg(long)]
+        path: Option<String>,
+        /// Only set the key if missing, printing whether it was set
+        #[arg(long, conflicts_with_all = ["path", "xx"])]
+        nx: bool,
+        /// Only set the key if present, printing whether it was set
+        #[arg(long, conflicts_with = "path")]
+        xx: bool,
     },
-    /// Remove key-value pair by key
+    /// Remove key-value pair by key, or all keys starting with a prefix
     Rm {
+        /// Key string
+        #[arg(
+            required_unless_present = "prefix",
+            conflicts_with = "prefix",
+            default_value_t
+        )]
+        key: String,
+        /// Remove all keys starting with this prefix instead, with a single log record
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+    /// Set key-value pair by key at a version; WAL-only
+    #[command(skip)]
+    #[strum(serialize = "vset")]
+    VersionedSet {
+        /// Key string
+        key: String,
+        /// Version of the key after the write
+        version: u64,
+        /// Value string
+        value: String,
+    },
+    /// Set key-value pair by key at a version, expiring at a time in milliseconds since the
+    /// Unix epoch; WAL-only
+    #[command(skip)]
+    #[strum(serialize = "xset")]
+    ExpiringSet {
+        /// Key string
+        key: String,
+        /// Version of the key after the write
+        version: u64,
+        /// Expiry of the key
+        expires: u64,
+        /// Value string
+        value: String,
+    },
+    /// Set key-value pair by key in a named bucket; WAL-only
+    #[command(skip)]
+    #[strum(serialize = "bset")]
+    BucketSet {
+        /// Bucket name
+        bucket: String,
+        /// Key string
+        key: String,
+        /// Value string
+        value: String,
+    },
+    /// Remove key-value pair by key in a named bucket; WAL-only
+    #[command(skip)]
+    #[strum(serialize = "brm")]
+    BucketRm {
+        /// Bucket name
+        bucket: String,
+        /// Key string
+        key: String,
+    },
+    /// List the retained values of a key by log sequence number
+    History {
+        /// Key string
+        #[arg(required = true)]
+        key: String,
+    },
+    /// Get next unique ID from a named sequence
+    #[strum(serialize = "next-id")]
+    NextId {
+        /// Sequence name
+        #[arg(required = true)]
+        sequence: String,
+    },
+    /// Reserve IDs of a named sequence up to (excluding) an end ID; WAL-only
+    #[command(skip)]
+    #[strum(serialize = "id")]
+    ReserveIds {
+        /// Sequence name
+        sequence: String,
+        /// First ID not covered by the reservation
+        end: u64,
+    },
+    /// Mark records up to a sequence number as dropped by compaction; WAL-only
+    #[command(skip)]
+    #[strum(serialize = "compacted")]
+    Compacted {
+        /// Sequence number of the last record the compaction covered
+        through: u64,
+    },
+    /// Append sample to timeseries by key
+    #[strum(serialize = "ts")]
+    TsAdd {
+        /// Key string
+        #[arg(required = true)]
+        key: String,
+        /// Sample timestamp
+        #[arg(required = true, allow_negative_numbers = true)]
+        timestamp: i64,
+        /// Sample value
+        #[arg(required = true, allow_negative_numbers = true)]
+        value: f64,
+    },
+    /// Get timeseries samples by key within an inclusive timestamp range
+    #[strum(serialize = "ts-range")]
+    TsRange {
+        /// Key string
+        #[arg(required = true)]
+        key: String,
+        /// Range start timestamp
+        #[arg(required = true, allow_negative_numbers = true)]
+        from: i64,
+        /// Range end timestamp
+        #[arg(required = true, allow_negative_numbers = true)]
+        to: i64,
+        /// Aggregation over samples in range
+        #[arg(long, value_enum, default_value_t)]
+        aggregation: Aggregation,
+    },
+    /// Push a value to the front of a list by key, printing the new length of the list
+    Lpush {
+        /// Key string
+        #[arg(required = true)]
+        key: String,
+        /// Value string
+        #[arg(required = true)]
+        value: String,
+    },
+    /// Pop the value at the back of a list by key, the one pushed first, printing it
+    Rpop {
+        /// Key string
+        #[arg(required = true)]
+        key: String,
+    },
+    /// Print the values of a list by key within an inclusive index range, front first
+    Lrange {
+        /// Key string
+        #[arg(required = true)]
+        key: String,
+        /// Index of the first value, negative counting from the back
+        #[arg(required = true, allow_negative_numbers = true)]
+        start: i64,
+        /// Index of the last value, negative counting from the back, e.g. -1 for the last
+        #[arg(required = true, allow_negative_numbers = true)]
+        stop: i64,
+    },
+    /// Add a member to a set by key, printing whether it was missing
+    Sadd {
+        /// Key string
+        #[arg(required = true)]
+        key: String,
+        /// Member string
+        #[arg(required = true)]
+        member: String,
+    },
+    /// Remove a member from a set by key, printing whether it was present
+    Srem {
+        /// Key string
+        #[arg(required = true)]
+        key: String,
+        /// Member string
+        #[arg(required = true)]
+        member: String,
+    },
+    /// Print the members of a set by key, in order
+    Smembers {
+        /// Key string
+        #[arg(required = true)]
+        key: String,
+    },
+    /// Take a lock by key unless held, and print the token of its lease
+    Lock {
+        /// Key string
+        #[arg(required = true)]
+        key: String,
+        /// Time to live of the lease in milliseconds
+        #[arg(long, value_name = "MS")]
+        ttl: u64,
+    },
+    /// Take a lock by key with a lease until a time in milliseconds since the Unix epoch;
+    /// WAL-only
+    #[command(skip)]
+    #[strum(serialize = "lease")]
+    Leased {
+        /// Key string
+        key: String,
+        /// Token of the lease
+        token: u64,
+        /// Expiry of the lease
+        expires: u64,
+    },
+    /// Release a lock by key held with the lease of a token
+    Unlock {
+        /// Key string
+        #[arg(required = true)]
+        key: String,
+        /// Token of the lease, as printed by `kvs lock`
+        #[arg(required = true, value_name = "TOKEN")]
+        lease: u64,
+    },
+    /// Rename a key, replacing any value of the new key
+    Rename {
+        /// Key string
+        #[arg(required = true)]
+        from: String,
+        /// New key string
+        #[arg(required = true)]
+        to: String,
+    },
+    /// Rename a key, setting the new key to the value of the old one; WAL-only
+    #[command(skip)]
+    #[strum(serialize = "mv")]
+    Renamed {
+        /// Key string
+        from: String,
+        /// New key string
+        to: String,
+        /// Value string
+        value: String,
+    },
+    /// Append a suffix to the value of a key, setting it if missing, and print the new length
+    Append {
+        /// Key string
+        #[arg(required = true)]
+        key: String,
+        /// Suffix string
+        #[arg(required = true)]
+        suffix: String,
+    },
+    /// Copy the value of a key to another key
+    Copy {
+        /// Key string
+        #[arg(required = true)]
+        from: String,
+        /// Key string to copy to
+        #[arg(required = true)]
+        to: String,
+        /// Replace the value of the key copied to if present
+        #[arg(long)]
+        overwrite: bool,
+    },
+    /// Remove all keys, not counting those of buckets
+    Clear {
+        /// Confirm removing all keys
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Check whether a key exists, printing `true` or `false` and exiting with 2 if not
+    Exists {
         /// Key string
         #[arg(required = true)]
         key: String,
     },
+    /// Print the number of keys, not counting those of buckets
+    Count,
+    /// Print the keys starting with a prefix, one per line in key order, a page at a time
+    Keys {
+        /// Key prefix, default all keys
+        #[arg(default_value_t)]
+        prefix: String,
+        /// Print at most this many keys
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Print the keys after this one, e.g. the last key of the previous page
+        #[arg(long, value_name = "KEY")]
+        start_after: Option<String>,
+        /// Print the keys in reverse order, those before the key of `--start-after`
+        #[arg(long)]
+        reverse: bool,
+    },
+    /// Print the keys whose JSON value holds a value in the field of a secondary index, one per
+    /// line in key order
+    Find {
+        /// Index name
+        #[arg(long)]
+        index: String,
+        /// Field value, numbers and booleans written as in JSON
+        #[arg(long, value_name = "VALUE")]
+        eq: String,
+    },
+    /// Manage the secondary indexes on fields of JSON values
+    Index {
+        /// Index command
+        #[command(subcommand)]
+        command: IndexCommand,
+    },
+    /// Rewrite the log as a new base segment replacing the segments before, and print the
+    /// sequence number of the last record it covers
+    Checkpoint,
+    /// Print store statistics
+    Stats {
+        /// Print as JSON instead of human-readable lines
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print all key-value pairs as of one point in time, in key order
+    Dump {
+        /// Format of the lines printed
+        #[arg(long, value_enum, default_value_t)]
+        format: pipe::DumpFormat,
+        /// Print a JSON object per pair, same as `--format jsonl`
+        #[arg(long)]
+        json: bool,
+    },
+    /// Apply the commands of the lines of standard input, `set KEY VALUE`, `rm KEY`, or JSON
+    /// objects as printed by `dump --format jsonl`, syncing writes in batches
+    Load {
+        /// Commands written between syncs of the WAL to disk
+        #[arg(long, value_name = "N", default_value_t = 1000)]
+        batch: usize,
+    },
+    /// Import the keys of files, in order, over those of the store; of Redis RDB or AOF files,
+    /// string keys are imported without their TTL, and keys already expired skipped
+    Import {
+        /// Files to import, e.g. the base and incremental files of a multi-part AOF
+        #[arg(required = true, value_name = "FILE")]
+        paths: Vec<PathBuf>,
+        /// Format of the files
+        #[arg(long, value_enum, default_value_t)]
+        format: import::ImportFormat,
+        /// Redis database whose keys are imported
+        #[arg(long, default_value_t = 0)]
+        db: u64,
+    },
+    /// Write all key-value pairs as of one point in time to a new file
+    #[cfg(feature = "sqlite")]
+    Export {
+        /// File to create
+        #[arg(value_name = "FILE")]
+        path: PathBuf,
+        /// Format of the file
+        #[arg(long, value_enum, default_value_t)]
+        format: sqlite::ExportFormat,
+    },
+    /// Check log files for corrupted or orphaned records and leftover segments
+    #[command(alias = "fsck")]
+    Doctor {
+        /// Truncate logs before invalid records and remove obsolete files
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Upgrade the store to the current format version
+    Migrate,
+    /// Inspect the log files of the store
+    Log {
+        /// Log command
+        #[command(subcommand)]
+        command: dump::LogCommand,
+    },
+    /// Fetch the segments archived to S3-compatible object storage into the store directory,
+    /// which must not hold a store yet; credentials are taken from the `AWS_*` environment
+    /// variables
+    #[cfg(feature = "archive")]
+    Restore {
+        /// Archive location, `s3://BUCKET[/PREFIX]`
+        #[arg(long, value_name = "URL")]
+        from: String,
+        /// Restore the store as of this time in milliseconds since the Unix epoch, as printed by
+        /// `kvs log dump`, instead of as of the last archived segment
+        #[arg(long, value_name = "MS")]
+        until: Option<u64>,
+        /// URL of the object storage, default `AWS_ENDPOINT_URL` or the AWS endpoint of the
+        /// region
+        #[arg(long, value_name = "URL")]
+        endpoint: Option<String>,
+    },
+    /// Print store metrics in Prometheus text format
+    #[cfg(feature = "metrics")]
+    Info,
+    /// Send an admin command to a running server
+    Admin {
+        /// Admin command
+        #[command(subcommand)]
+        command: server::AdminCommand,
+        /// Address of the server, or `unix://PATH` of its Unix domain socket
+        #[arg(long, global = true, default_value = "127.0.0.1:4000")]
+        server: String,
+    },
+    /// Check that a running server is alive and print the health of its store, failing if it
+    /// is not ready to take traffic
+    Ping {
+        /// Address of the server, or `unix://PATH` of its Unix domain socket
+        #[arg(long, default_value = "127.0.0.1:4000")]
+        server: String,
+        /// Print as JSON instead of human-readable lines
+        #[arg(long)]
+        json: bool,
+        /// Replication lag in milliseconds beyond which a replica is not ready
+        #[arg(long, value_name = "MS")]
+        max_lag_ms: Option<u64>,
+    },
+    /// Copy the WAL records of a running server numbered from a sequence number on, e.g. to
+    /// keep an off-site copy of its store up to date
+    Backup {
+        /// Address of the server, or `unix://PATH` of its Unix domain socket
+        #[arg(long, default_value = "127.0.0.1:4000")]
+        server: String,
+        /// Sequence number of the first record, default 1, from which on the records hold the
+        /// whole store
+        #[arg(long, value_name = "S", conflicts_with = "dir")]
+        from_seq: Option<u64>,
+        /// Keep copying records as the server commits them, until interrupted
+        #[arg(long)]
+        follow: bool,
+        /// Backup directory to copy the records to instead of printing them, resuming after
+        /// the last record copied; a store can be opened from it once copying stops. Also
+        /// given as `--output DIR`, its former name
+        #[arg(long, value_name = "DIR")]
+        dir: Option<PathBuf>,
+    },
+    /// Manage the Raft cluster of a running server
+    #[cfg(feature = "raft")]
+    Cluster {
+        /// Cluster command
+        #[command(subcommand)]
+        command: raft::ClusterCommand,
+        /// Address of the server, or `unix://PATH` of its Unix domain socket
+        #[arg(long, global = true, default_value = "127.0.0.1:4000")]
+        server: String,
+    },
+    /// Print the completion script of the CLI for a shell
+    // Shells have no default to parse the command name into
+    #[strum(disabled)]
+    Completions {
+        /// Shell to complete in
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Print the man page of the CLI
+    Man {
+        /// Directory to write the man pages of the CLI and each of its subcommands to instead.
+        /// Also given as `--output DIR`, its former name
+        #[arg(long, value_name = "DIR")]
+        dir: Option<PathBuf>,
+    },
 }
 
 /// Simple serializer for generating space-separated command representation for the WAL, mirroring the CLI input format
 /// TODO: Reconcile serializer with deserializer
 impl Serialize for Command {
+    #[allow(clippy::too_many_lines)] // One arm per command
     fn serialize<S>(&self, serializer: S) -> result::Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
         match self {
-            cmd @ Self::Set { key, value } => {
+            cmd @ Self::Set {
+                key,
+                value,
+                path: None,
+                nx,
+                xx,
+            } => {
+                let flag = match (nx, xx) {
+                    (true, _) => " --nx",
+                    (_, true) => " --xx",
+                    _ => "",
+                };
+                serializer.serialize_str(format!("{cmd} {key} {value}{flag}").as_str())
+            }
+            cmd @ (Self::Append { key, suffix: value }
+            | Self::Lpush { key, value }
+            | Self::Sadd { key, member: value }
+            | Self::Srem { key, member: value }) => {
                 serializer.serialize_str(format!("{cmd} {key} {value}").as_str())
             }
-            cmd @ (Self::Rm { key } | Self::Get { key }) => {
-                serializer.serialize_str(format!("{cmd} {key}").as_str())
+            cmd @ Self::Set {
+                key,
+                value,
+                path: Some(path),
+                ..
+            } => serializer.serialize_str(format!("{cmd} --path {path} {key} {value}").as_str()),
+            cmd @ Self::Get {
+                key,
+                path: Some(path),
+            } => serializer.serialize_str(format!("{cmd} --path {path} {key}").as_str()),
+            cmd @ Self::Rm {
+                prefix: Some(prefix),
+                ..
+            } => serializer.serialize_str(format!("{cmd} --prefix {prefix}").as_str()),
+            cmd @ (Self::Rm { key, prefix: None }
+            | Self::Get { key, path: None }
+            | Self::Rpop { key }
+            | Self::Smembers { key }
+            | Self::History { key }
+            | Self::Exists { key }) => serializer.serialize_str(format!("{cmd} {key}").as_str()),
+            cmd @ Self::VersionedSet {
+                key,
+                version,
+                value,
+            } => serializer.serialize_str(format!("{cmd} {key} {version} {value}").as_str()),
+            cmd @ Self::ExpiringSet {
+                key,
+                version,
+                expires,
+                value,
+            } => serializer
+                .serialize_str(format!("{cmd} {key} {version} {expires} {value}").as_str()),
+            cmd @ Self::BucketSet { bucket, key, value } => {
+                serializer.serialize_str(format!("{cmd} {bucket} {key} {value}").as_str())
+            }
+            cmd @ Self::BucketRm { bucket, key } => {
+                serializer.serialize_str(format!("{cmd} {bucket} {key}").as_str())
+            }
+            cmd @ Self::Lock { key, ttl } => {
+                serializer.serialize_str(format!("{cmd} {key} --ttl {ttl}").as_str())
+            }
+            cmd @ Self::Leased {
+                key,
+                token,
+                expires,
+            } => serializer.serialize_str(format!("{cmd} {key} {token} {expires}").as_str()),
+            cmd @ Self::Unlock { key, lease } => {
+                serializer.serialize_str(format!("{cmd} {key} {lease}").as_str())
+            }
+            cmd @ Self::Rename { from, to } => {
+                serializer.serialize_str(format!("{cmd} {from} {to}").as_str())
+            }
+            cmd @ Self::Renamed { from, to, value } => {
+                serializer.serialize_str(format!("{cmd} {from} {to} {value}").as_str())
             }
+            cmd @ Self::Copy {
+                from,
+                to,
+                overwrite,
+            } => {
+                let flag = if *overwrite { " --overwrite" } else { "" };
+                serializer.serialize_str(format!("{cmd} {from} {to}{flag}").as_str())
+            }
+            cmd @ Self::NextId { sequence } => {
+                serializer.serialize_str(format!("{cmd} {sequence}").as_str())
+            }
+            cmd @ Self::ReserveIds { sequence, end } => {
+                serializer.serialize_str(format!("{cmd} {sequence} {end}").as_str())
+            }
+            cmd @ Self::Compacted { through } => {
+                serializer.serialize_str(format!("{cmd} {through}").as_str())
+            }
+            cmd @ Self::TsAdd {
+                key,
+                timestamp,
+                value,
+            } => serializer.serialize_str(format!("{cmd} {key} {timestamp} {value}").as_str()),
+            cmd @ Self::TsRange {
+                key,
+                from,
+                to,
+                aggregation,
+            } => {
+                serializer.serialize_str(format!("{cmd} {key} {from} {to} {aggregation}").as_str())
+            }
+            cmd @ Self::Clear { yes } => {
+                let flag = if *yes { " --yes" } else { "" };
+                serializer.serialize_str(format!("{cmd}{flag}").as_str())
+            }
+            cmd @ Self::Stats { json } => {
+                let flag = if *json { " --json" } else { "" };
+                serializer.serialize_str(format!("{cmd}{flag}").as_str())
+            }
+            cmd @ Self::Dump { format, json } => {
+                let flag = if *json { " --json" } else { "" };
+                serializer.serialize_str(format!("{cmd} --format {format}{flag}").as_str())
+            }
+            cmd @ Self::Load { batch } => {
+                serializer.serialize_str(format!("{cmd} --batch {batch}").as_str())
+            }
+            cmd @ Self::Import { paths, format, db } => {
+                let paths: Vec<_> = paths.iter().map(|p| p.display().to_string()).collect();
+                let paths = paths.join(" ");
+                serializer
+                    .serialize_str(format!("{cmd} --format {format} --db {db} {paths}").as_str())
+            }
+            #[cfg(feature = "sqlite")]
+            cmd @ Self::Export { path, format } => serializer
+                .serialize_str(format!("{cmd} --format {format} {}", path.display()).as_str()),
+            cmd @ Self::Doctor { repair } => {
+                let flag = if *repair { " --repair" } else { "" };
+                serializer.serialize_str(format!("{cmd}{flag}").as_str())
+            }
+            cmd @ Self::Lrange { key, start, stop } => {
+                serializer.serialize_str(format!("{cmd} {key} {start} {stop}").as_str())
+            }
+            cmd @ Self::Keys {
+                prefix,
+                limit,
+                start_after,
+                reverse,
+            } => {
+                let limit = limit.map(|limit| format!(" --limit {limit}"));
+                let start_after = start_after
+                    .as_ref()
+                    .map(|key| format!(" --start-after {key}"));
+                let flag = if *reverse { " --reverse" } else { "" };
+                serializer.serialize_str(
+                    format!(
+                        "{cmd} {prefix}{}{}{flag}",
+                        limit.unwrap_or_default(),
+                        start_after.unwrap_or_default()
+                    )
+                    .as_str(),
+                )
+            }
+            cmd @ Self::Find { index, eq } => {
+                serializer.serialize_str(format!("{cmd} --index {index} --eq {eq}").as_str())
+            }
+            cmd @ (Self::Count
+            | Self::Checkpoint
+            | Self::Migrate
+            | Self::Log { .. }
+            | Self::Index { .. }
+            | Self::Admin { .. }
+            | Self::Ping { .. }
+            | Self::Backup { .. }
+            | Self::Completions { .. }
+            | Self::Man { .. }) => serializer.serialize_str(cmd.to_string().as_str()),
+            #[cfg(feature = "archive")]
+            cmd @ Self::Restore { .. } => serializer.serialize_str(cmd.to_string().as_str()),
+            #[cfg(feature = "metrics")]
+            cmd @ Self::Info => serializer.serialize_str(cmd.to_string().as_str()),
+            #[cfg(feature = "raft")]
+            cmd @ Self::Cluster { .. } => serializer.serialize_str(cmd.to_string().as_str()),
         }
     }
 }
@@ -322,6 +4439,7 @@ impl<'de> Visitor<'de> for CommandVisitor {
         formatter.write_str("space separated string with subcommand and arguments")
     }
 
+    #[allow(clippy::too_many_lines)] // One arm per record tag
     fn visit_seq<V>(self, mut seq: V) -> result::Result<Self::Value, V::Error>
     where
         V: SeqAccess<'de>,
@@ -338,15 +4456,222 @@ impl<'de> Visitor<'de> for CommandVisitor {
                 let value = seq
                     .next_element()?
                     .ok_or_else(|| de::Error::invalid_length(2, &self))?;
-                Ok(Command::Set { key, value })
+                Ok(Command::Set {
+                    key,
+                    value,
+                    path: None,
+                    nx: false,
+                    xx: false,
+                })
+            }
+            "append" => {
+                let key = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                let suffix = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                Ok(Command::Append { key, suffix })
             }
             "rm" => {
                 let key = seq
                     .next_element()?
                     .ok_or_else(|| de::Error::invalid_length(1, &self))?;
-                Ok(Command::Rm { key })
+                Ok(Command::Rm { key, prefix: None })
+            }
+            "rmp" => {
+                let prefix = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                Ok(Command::Rm {
+                    key: String::new(),
+                    prefix: Some(prefix),
+                })
+            }
+            "vset" => {
+                let key = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                let version: String = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                let value = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(3, &self))?;
+                Ok(Command::VersionedSet {
+                    key,
+                    version: version.parse().map_err(de::Error::custom)?,
+                    value,
+                })
+            }
+            "xset" => {
+                let key = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                let version: String = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                let expires: String = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(3, &self))?;
+                let value = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(4, &self))?;
+                Ok(Command::ExpiringSet {
+                    key,
+                    version: version.parse().map_err(de::Error::custom)?,
+                    expires: expires.parse().map_err(de::Error::custom)?,
+                    value,
+                })
+            }
+            "bset" => {
+                let bucket = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                let key = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                let value = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(3, &self))?;
+                Ok(Command::BucketSet { bucket, key, value })
+            }
+            "brm" => {
+                let bucket = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                let key = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                Ok(Command::BucketRm { bucket, key })
+            }
+            "mv" => {
+                let from = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                let to = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                let value = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(3, &self))?;
+                Ok(Command::Renamed { from, to, value })
+            }
+            "id" => {
+                let sequence = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                let end: String = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                let end = end.parse().map_err(de::Error::custom)?;
+                Ok(Command::ReserveIds { sequence, end })
+            }
+            "compacted" => {
+                let through: String = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                let through = through.parse().map_err(de::Error::custom)?;
+                Ok(Command::Compacted { through })
+            }
+            "ts" => {
+                let key = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                let timestamp: String = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                let value: String = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(3, &self))?;
+                Ok(Command::TsAdd {
+                    key,
+                    timestamp: timestamp.parse().map_err(de::Error::custom)?,
+                    value: value.parse().map_err(de::Error::custom)?,
+                })
+            }
+            "lpush" => {
+                let key = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                let value = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                Ok(Command::Lpush { key, value })
+            }
+            "rpop" => {
+                let key = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                Ok(Command::Rpop { key })
+            }
+            "sadd" => {
+                let key = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                let member = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                Ok(Command::Sadd { key, member })
+            }
+            "srem" => {
+                let key = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                let member = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                Ok(Command::Srem { key, member })
+            }
+            "lease" => {
+                let key = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                let token: String = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                let expires: String = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(3, &self))?;
+                Ok(Command::Leased {
+                    key,
+                    token: token.parse().map_err(de::Error::custom)?,
+                    expires: expires.parse().map_err(de::Error::custom)?,
+                })
+            }
+            "unlock" => {
+                let key = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                let token: String = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                let lease = token.parse().map_err(de::Error::custom)?;
+                Ok(Command::Unlock { key, lease })
             }
-            _ => Err(de::Error::unknown_variant(&command, &["set", "rm"])),
+            _ => Err(de::Error::unknown_variant(
+                &command,
+                &[
+                    "set",
+                    "append",
+                    "rm",
+                    "rmp",
+                    "vset",
+                    "xset",
+                    "bset",
+                    "brm",
+                    "mv",
+                    "id",
+                    "compacted",
+                    "ts",
+                    "lpush",
+                    "rpop",
+                    "sadd",
+                    "srem",
+                    "lease",
+                    "unlock",
+                ],
+            )),
         }
     }
 }
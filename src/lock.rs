@@ -0,0 +1,55 @@
+//! `LOCK` file keeping a store directory from being opened by two stores at once
+//!
+//! The lock is an exclusive advisory lock on the file, held by the store for as long as it is
+//! open, and released by the operating system when its process exits, even after a crash.
+
+use crate::{KvStoreError, Result};
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Lock file name
+pub(crate) const LOCK: &str = "LOCK";
+
+/// Interval between attempts to take a lock held by another store
+const RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Exclusive lock of a store directory, released when dropped
+#[derive(Debug)]
+pub(crate) struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Locks the store directory at dir, waiting up to timeout for another store to release it
+    ///
+    /// # Errors
+    /// Returns `Err` if the lock file cannot be opened, or the directory is still locked after
+    /// timeout
+    pub(crate) fn acquire(dir: &Path, timeout: Duration) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(LOCK))
+            .map_err(KvStoreError::FailedLock)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            match file.try_lock() {
+                Ok(()) => return Ok(Self { _file: file }),
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                    thread::sleep(
+                        RETRY_INTERVAL.min(deadline.saturating_duration_since(Instant::now())),
+                    );
+                }
+                Err(TryLockError::WouldBlock) => {
+                    return Err(KvStoreError::Locked(dir.to_owned()));
+                }
+                Err(TryLockError::Error(e)) => return Err(KvStoreError::FailedLock(e)),
+            }
+        }
+    }
+}
@@ -0,0 +1,256 @@
+//! `MANIFEST` file recording the format version and segments of a store directory
+//!
+//! Segments are opened from the manifest instead of by listing the directory. The manifest is
+//! rewritten whenever the writer seals a segment or compacts the log, each time after the new
+//! segment file is in place; segments found from the next segment ID on are the outcome of such
+//! an update interrupted by a crash, and are tracked when opening.
+//!
+//! A store placing its segments in directories other than the store directory records them
+//! too, so that it is only opened with the same layout.
+
+use crate::{
+    codec::WalFormat,
+    segment::{self, Dirs, SegmentFile},
+    KvStoreError, Result, WAL,
+};
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// Manifest file name
+pub(crate) const MANIFEST: &str = "MANIFEST";
+
+/// Format version of store directories written by this version
+///
+/// Bump it along with a step in [`crate::migrate`] whenever the layout of log files changes.
+pub(crate) const FORMAT_VERSION: u32 = 4;
+
+/// Storage engine recorded in the manifest
+pub(crate) const ENGINE: &str = "kvs";
+
+/// Segments of a store directory
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    /// ID of the active segment, taken by the next segment sealed or compacted
+    pub(crate) next_segment: u64,
+    /// IDs of sealed segments and whether each is a base segment, in log order
+    ///
+    /// The latest base segment supersedes the segments before it.
+    pub(crate) segments: Vec<(u64, bool)>,
+    /// Format of the records of all segments, text unless recorded
+    pub(crate) format: WalFormat,
+    /// Directory of the active and sealed segments, if not the store directory
+    pub(crate) wal_dir: Option<PathBuf>,
+    /// Directory of base segments and their hint files, if not the store directory
+    pub(crate) data_dir: Option<PathBuf>,
+    /// Bytes of records in the active segment, recorded when closed with records checked and
+    /// cleared once it is written again; bytes past them are not records
+    pub(crate) end: Option<u64>,
+}
+
+impl Manifest {
+    /// Parses the manifest of a store with the current format version
+    fn parse(contents: &str) -> Result<Self> {
+        let invalid = |reason: String| KvStoreError::InvalidManifest(reason);
+        let mut manifest = Self::default();
+        let mut engine = None;
+        for line in contents.lines() {
+            let (field, value) = line
+                .split_once(' ')
+                .ok_or_else(|| invalid(format!("malformed line: {line}")))?;
+            let id = || {
+                value
+                    .parse()
+                    .map_err(|_| invalid(format!("malformed segment ID: {line}")))
+            };
+            match field {
+                // The snapshot is the last base segment
+                "version" | "snapshot" => {}
+                "engine" => engine = Some(value),
+                "next_segment" => manifest.next_segment = id()?,
+                "segment" => manifest.segments.push((id()?, false)),
+                "base" => manifest.segments.push((id()?, true)),
+                "wal_dir" => manifest.wal_dir = Some(value.into()),
+                "data_dir" => manifest.data_dir = Some(value.into()),
+                "end" => {
+                    manifest.end = Some(
+                        value
+                            .parse()
+                            .map_err(|_| invalid(format!("malformed end: {line}")))?,
+                    );
+                }
+                "codec" => {
+                    manifest.format = value
+                        .parse()
+                        .map_err(|_| invalid(format!("unknown codec: {value}")))?;
+                }
+                _ => return Err(invalid(format!("unknown field: {field}"))),
+            }
+        }
+
+        match engine {
+            Some(ENGINE) => Ok(manifest),
+            Some(engine) => Err(KvStoreError::WrongEngine(engine.to_owned())),
+            None => Err(invalid("missing engine".to_owned())),
+        }
+    }
+
+    /// Returns the ID of the latest base segment
+    pub(crate) fn snapshot(&self) -> Option<u64> {
+        self.segments
+            .iter()
+            .rev()
+            .find_map(|&(id, base)| base.then_some(id))
+    }
+
+    /// Returns the directories of the store in dir, as recorded
+    pub(crate) fn dirs(&self, dir: &Path) -> Dirs {
+        Dirs {
+            store: dir.to_owned(),
+            wal: self.wal_dir.clone().unwrap_or_else(|| dir.to_owned()),
+            data: self.data_dir.clone().unwrap_or_else(|| dir.to_owned()),
+        }
+    }
+
+    /// Returns the tracked segment files in dirs, in log order
+    ///
+    /// Segment files from the next segment ID on are tracked first, advancing it.
+    pub(crate) fn segment_files(&mut self, dirs: &Dirs) -> Vec<SegmentFile> {
+        loop {
+            let id = self.next_segment;
+            let base = dirs.base_path(id).is_file();
+            if !base && !dirs.sealed_path(id).is_file() {
+                break;
+            }
+            self.segments.push((id, base));
+            self.next_segment += 1;
+        }
+
+        self.segments
+            .iter()
+            .map(|&(id, base)| {
+                let path = if base {
+                    dirs.base_path(id)
+                } else {
+                    dirs.sealed_path(id)
+                };
+                SegmentFile { id, path, base }
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "engine {ENGINE}")?;
+        writeln!(f, "next_segment {}", self.next_segment)?;
+        writeln!(f, "codec {}", self.format)?;
+        if let Some(dir) = &self.wal_dir {
+            writeln!(f, "wal_dir {}", dir.display())?;
+        }
+        if let Some(dir) = &self.data_dir {
+            writeln!(f, "data_dir {}", dir.display())?;
+        }
+        if let Some(end) = self.end {
+            writeln!(f, "end {end}")?;
+        }
+        if let Some(snapshot) = self.snapshot() {
+            writeln!(f, "snapshot {snapshot}")?;
+        }
+        self.segments.iter().try_for_each(|&(id, base)| {
+            let kind = if base { "base" } else { "segment" };
+            writeln!(f, "{kind} {id}")
+        })
+    }
+}
+
+/// Returns the format version of the store in dir, or `None` if dir holds no store yet
+///
+/// Directories written before the manifest was introduced have version 0.
+///
+/// # Errors
+/// Returns `Err` if the manifest cannot be read or is malformed
+pub(crate) fn version(dir: &Path) -> Result<Option<u32>> {
+    let contents = match fs::read_to_string(dir.join(MANIFEST)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let logs = dir.join(WAL).is_file()
+                || !segment::segment_files(dir)
+                    .map_err(KvStoreError::FailedManifest)?
+                    .is_empty();
+            return Ok(logs.then_some(0));
+        }
+        Err(e) => return Err(KvStoreError::FailedManifest(e)),
+    };
+
+    contents
+        .lines()
+        .find_map(|line| line.strip_prefix("version "))
+        .and_then(|version| version.parse().ok())
+        .map(Some)
+        .ok_or_else(|| KvStoreError::InvalidManifest("missing format version".to_owned()))
+}
+
+/// Fails unless the store in dir has the current format version, returning whether dir holds
+/// no store yet
+///
+/// # Errors
+/// Returns `Err` if the manifest cannot be read, or the format version is older or newer
+pub(crate) fn check(dir: &Path) -> Result<bool> {
+    match version(dir)? {
+        None => Ok(true),
+        Some(FORMAT_VERSION) => Ok(false),
+        Some(version) if version < FORMAT_VERSION => {
+            Err(KvStoreError::OutdatedFormat(version, FORMAT_VERSION))
+        }
+        Some(version) => Err(KvStoreError::UnsupportedFormat(version, FORMAT_VERSION)),
+    }
+}
+
+/// Reads the manifest of the store in dir, which must have the current format version
+///
+/// # Errors
+/// Returns `Err` if the manifest cannot be read, is malformed, or records another engine
+pub(crate) fn read(dir: &Path) -> Result<Manifest> {
+    let contents = fs::read_to_string(dir.join(MANIFEST)).map_err(KvStoreError::FailedManifest)?;
+    Manifest::parse(&contents)
+}
+
+/// Writes the manifest of the store in dir, with the given format version
+///
+/// # Errors
+/// Returns `Err` if the manifest cannot be written
+pub(crate) fn write(dir: &Path, version: u32, manifest: &Manifest) -> io::Result<()> {
+    replace(dir, &format!("version {version}\n{manifest}"))
+}
+
+/// Records a format version in the manifest of the store in dir, keeping its other fields
+///
+/// # Errors
+/// Returns `Err` if the manifest cannot be read or written
+pub(crate) fn set_version(dir: &Path, version: u32) -> io::Result<()> {
+    let contents = match fs::read_to_string(dir.join(MANIFEST)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let fields = contents
+        .lines()
+        .filter(|line| !line.starts_with("version "));
+    let contents = std::iter::once(format!("version {version}"))
+        .chain(fields.map(str::to_owned))
+        .fold(String::new(), |contents, line| contents + &line + "\n");
+    replace(dir, &contents)
+}
+
+/// Replaces the manifest atomically, so an interrupted write leaves the previous one
+fn replace(dir: &Path, contents: &str) -> io::Result<()> {
+    let tmp = dir.join(format!("{MANIFEST}.tmp"));
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(MANIFEST))
+}
@@ -0,0 +1,41 @@
+//! Memory budget of a KV store, rejecting writes once its keys, values and index take more
+//! memory than allowed
+
+use crate::{KvStoreError, Result};
+use std::cell::Cell;
+
+thread_local! {
+    /// Whether the writes of this thread apply records already logged or committed, see
+    /// [`exempt`]
+    static EXEMPT: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs f with the writes it makes on this thread exempt from the memory budget
+///
+/// For records replayed on open, shipped by a primary or committed by a Raft cluster, which
+/// must not be skipped.
+pub(crate) fn exempt<T>(f: impl FnOnce() -> T) -> T {
+    let previous = EXEMPT.with(|e| e.replace(true));
+    let result = f();
+    EXEMPT.with(|e| e.set(previous));
+    result
+}
+
+/// Returns whether the writes of this thread are [exempt](exempt)
+///
+/// Such writes do not evict keys in cache mode either.
+pub(crate) fn is_exempt() -> bool {
+    EXEMPT.with(Cell::get)
+}
+
+/// Fails if used bytes of memory exceed the budget of max bytes
+///
+/// # Errors
+/// Returns [`KvStoreError::OutOfMemoryBudget`] if over budget, unless the writes of the thread
+/// are [exempt](exempt)
+pub(crate) fn check(used: u64, max: Option<u64>) -> Result<()> {
+    match max {
+        Some(max) if used > max && !is_exempt() => Err(KvStoreError::OutOfMemoryBudget(used, max)),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,165 @@
+//! Operational metrics with Prometheus text exposition
+
+use dashmap::DashMap;
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Upper bounds of latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 8] = [1e-6, 1e-5, 1e-4, 1e-3, 1e-2, 1e-1, 1.0, 10.0];
+
+/// Counters and histograms describing KV store activity
+#[derive(Debug, Default)]
+pub struct Metrics {
+    commands: DashMap<&'static str, Histogram>,
+    wal_bytes_written: AtomicU64,
+    compactions: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+impl Metrics {
+    /// Records one execution of a command and how long it took
+    pub(crate) fn command(&self, name: &'static str, elapsed: Duration) {
+        self.commands.entry(name).or_default().observe(elapsed);
+    }
+
+    /// Records bytes appended to the WAL
+    pub(crate) fn wal_write(&self, bytes: usize) {
+        self.wal_bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Records a completed WAL compaction
+    pub(crate) fn compaction(&self) {
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a `get` served from the value cache
+    pub(crate) fn cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a `get` that missed the value cache
+    pub(crate) fn cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns number of executions recorded for a command
+    #[must_use]
+    pub fn command_count(&self, name: &str) -> u64 {
+        self.commands
+            .get(name)
+            .map_or(0, |h| h.count.load(Ordering::Relaxed))
+    }
+
+    /// Returns total bytes appended to the WAL
+    #[must_use]
+    pub fn wal_bytes_written(&self) -> u64 {
+        self.wal_bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Returns number of completed WAL compactions
+    #[must_use]
+    pub fn compactions(&self) -> u64 {
+        self.compactions.load(Ordering::Relaxed)
+    }
+
+    /// Returns number of `get`s served from the value cache
+    #[must_use]
+    pub fn cache_hits(&self) -> u64 {
+        self.cache_hits.load(Ordering::Relaxed)
+    }
+
+    /// Returns number of `get`s that missed the value cache
+    #[must_use]
+    pub fn cache_misses(&self) -> u64 {
+        self.cache_misses.load(Ordering::Relaxed)
+    }
+
+    /// Renders all metrics in Prometheus text exposition format
+    #[must_use]
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP kvs_command_duration_seconds Latency of KV store commands\n");
+        out.push_str("# TYPE kvs_command_duration_seconds histogram\n");
+        let mut names: Vec<_> = self.commands.iter().map(|e| *e.key()).collect();
+        names.sort_unstable();
+        for name in names {
+            if let Some(histogram) = self.commands.get(name) {
+                histogram.render(&mut out, "kvs_command_duration_seconds", name);
+            }
+        }
+
+        out.push_str("# HELP kvs_wal_bytes_written_total Bytes appended to the write-ahead log\n");
+        out.push_str("# TYPE kvs_wal_bytes_written_total counter\n");
+        let _ = writeln!(
+            out,
+            "kvs_wal_bytes_written_total {}",
+            self.wal_bytes_written()
+        );
+
+        out.push_str("# HELP kvs_compactions_total Completed write-ahead log compactions\n");
+        out.push_str("# TYPE kvs_compactions_total counter\n");
+        let _ = writeln!(out, "kvs_compactions_total {}", self.compactions());
+
+        out.push_str("# HELP kvs_cache_hits_total Value reads served from the cache\n");
+        out.push_str("# TYPE kvs_cache_hits_total counter\n");
+        let _ = writeln!(out, "kvs_cache_hits_total {}", self.cache_hits());
+
+        out.push_str("# HELP kvs_cache_misses_total Value reads that missed the cache\n");
+        out.push_str("# TYPE kvs_cache_misses_total counter\n");
+        let _ = writeln!(out, "kvs_cache_misses_total {}", self.cache_misses());
+
+        out
+    }
+}
+
+/// Cumulative latency histogram with fixed buckets
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            if secs <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(
+            u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn render(&self, out: &mut String, metric: &str, command: &str) {
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "{metric}_bucket{{command=\"{command}\",le=\"{bound}\"}} {}",
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "{metric}_bucket{{command=\"{command}\",le=\"+Inf\"}} {count}"
+        );
+        let _ = writeln!(
+            out,
+            "{metric}_sum{{command=\"{command}\"}} {}",
+            self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9
+        );
+        let _ = writeln!(out, "{metric}_count{{command=\"{command}\"}} {count}");
+    }
+}
@@ -0,0 +1,140 @@
+//! In-place upgrade of store directories written by older versions
+//!
+//! Each format version has a step upgrading directories to the next one. Steps run in order,
+//! recording the new version in the manifest after each, so an interrupted migration resumes
+//! from the last completed step.
+
+use crate::{
+    lock::DirLock,
+    manifest::{self, Manifest, FORMAT_VERSION},
+    segment, KvStoreError, Result,
+};
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::info;
+
+/// Upgrade from a format version to the next, indexed by the version it upgrades from
+type Step = fn(&Path) -> io::Result<()>;
+
+/// Migration steps, one per format version before [`FORMAT_VERSION`]
+const STEPS: [Step; FORMAT_VERSION as usize] = [
+    add_base_headers,
+    track_segments,
+    number_records,
+    stamp_records,
+];
+
+/// Outcome of [`migrate`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Migration {
+    /// Format version before migrating
+    pub from: u32,
+    /// Format version after migrating
+    pub to: u32,
+}
+
+impl fmt::Display for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.from == self.to {
+            write!(f, "Already at format version {}", self.to)
+        } else {
+            write!(
+                f,
+                "Migrated from format version {} to {}",
+                self.from, self.to
+            )
+        }
+    }
+}
+
+/// Upgrades the store in path to the current format version
+///
+/// The store must not be open, which the lock of its directory enforces. A directory holding no
+/// store yet is left untouched.
+///
+/// # Errors
+/// Returns `Err` if the directory has a newer format version, is locked by an open store, or a
+/// step fails
+pub fn migrate(path: impl AsRef<Path>) -> Result<Migration> {
+    let dir = path.as_ref();
+    let Some(from) = manifest::version(dir)? else {
+        return Ok(Migration {
+            from: FORMAT_VERSION,
+            to: FORMAT_VERSION,
+        });
+    };
+    if from > FORMAT_VERSION {
+        return Err(KvStoreError::UnsupportedFormat(from, FORMAT_VERSION));
+    }
+    let _lock = DirLock::acquire(dir, Duration::ZERO)?;
+
+    for version in from..FORMAT_VERSION {
+        STEPS[version as usize](dir).map_err(KvStoreError::FailedMigration)?;
+        manifest::set_version(dir, version + 1).map_err(KvStoreError::FailedManifest)?;
+        info!(
+            path = %dir.display(),
+            "Migrated store to format version {}",
+            version + 1
+        );
+    }
+
+    Ok(Migration {
+        from,
+        to: FORMAT_VERSION,
+    })
+}
+
+/// Version 0 to 1: prepends the versioned checksum header to base segments
+fn add_base_headers(dir: &Path) -> io::Result<()> {
+    for base in segment::segment_files(dir)?.into_iter().filter(|s| s.base) {
+        let records = fs::read(&base.path)?;
+        if segment::has_base_header(&records) {
+            continue;
+        }
+
+        let mut tmp = base.path.as_os_str().to_owned();
+        tmp.push(".migrate");
+        let tmp = PathBuf::from(tmp);
+        let mut file = File::create(&tmp)?;
+        file.write_all(
+            segment::base_header(crc32fast::hash(&records), records.len() as u64).as_bytes(),
+        )?;
+        file.write_all(&records)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &base.path)?;
+    }
+
+    Ok(())
+}
+
+/// Version 1 to 2: lists the segments in the directory in the manifest
+fn track_segments(dir: &Path) -> io::Result<()> {
+    let files = segment::segment_files(dir)?;
+    let manifest = Manifest {
+        next_segment: files.last().map_or(0, |s| s.id + 1),
+        segments: files.iter().map(|s| (s.id, s.base)).collect(),
+        ..Manifest::default()
+    };
+    manifest::write(dir, 2, &manifest)
+}
+
+/// Version 2 to 3: nothing to rewrite, as unnumbered records are numbered when replayed
+///
+/// The version only keeps older versions from misreading numbered records.
+#[allow(clippy::unnecessary_wraps)] // Signature of a step
+fn number_records(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Version 3 to 4: nothing to rewrite, as records without timestamp are replayed as such
+///
+/// The version only keeps older versions from misreading timestamped records.
+#[allow(clippy::unnecessary_wraps)] // Signature of a step
+fn stamp_records(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
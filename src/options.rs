@@ -0,0 +1,495 @@
+//! Options for opening a KV store, and for single writes and scans of it
+
+use crate::{
+    CacheConfig, Clock, CompactionPolicy, EvictionConfig, Hook, KeyIndex, KvStore, KvsRuntime,
+    OpenProgress, Result, SizeTiered, SystemClock, WalFormat,
+};
+use std::{fmt, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+/// Callback invoked with the key of a `get` that found no value
+pub type MissHook = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Callback invoked with the progress of loading the log files of a store being opened
+pub type ProgressHook = Arc<dyn Fn(&OpenProgress) + Send + Sync>;
+
+/// Options and flags to configure how a KV store is opened
+///
+/// Mirrors [`std::fs::OpenOptions`]: chain setters on [`OpenOptions::new`], then call
+/// [`OpenOptions::open`].
+#[derive(Clone)]
+#[allow(clippy::struct_excessive_bools)] // One field per setter
+pub struct OpenOptions {
+    pub(crate) panic_free: bool,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) on_miss: Option<MissHook>,
+    pub(crate) on_progress: Option<ProgressHook>,
+    pub(crate) hooks: Vec<Arc<dyn Hook>>,
+    pub(crate) coalesce_window: Option<Duration>,
+    pub(crate) group_commit: Option<Duration>,
+    pub(crate) write_through: bool,
+    pub(crate) preallocate_segments: bool,
+    pub(crate) checksum_records: bool,
+    pub(crate) segment_size: u64,
+    pub(crate) wal_dir: Option<PathBuf>,
+    pub(crate) data_dir: Option<PathBuf>,
+    pub(crate) compaction_threshold: u64,
+    pub(crate) compaction_throttle: Option<u64>,
+    pub(crate) compaction_policy: Arc<dyn CompactionPolicy>,
+    pub(crate) offset_index: bool,
+    pub(crate) key_index: KeyIndex,
+    pub(crate) cache: Option<CacheConfig>,
+    pub(crate) eviction: Option<EvictionConfig>,
+    pub(crate) history_retention: Option<u64>,
+    pub(crate) wal_format: WalFormat,
+    pub(crate) lock_timeout: Duration,
+    pub(crate) max_key_len: usize,
+    pub(crate) max_value_len: usize,
+    pub(crate) max_memory: Option<u64>,
+    pub(crate) runtime: Option<KvsRuntime>,
+    pub(crate) replica_of: Option<SocketAddr>,
+    #[cfg(feature = "raft")]
+    pub(crate) cluster: Option<(SocketAddr, Vec<SocketAddr>)>,
+    #[cfg(feature = "archive")]
+    pub(crate) archive_segments: bool,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self {
+            panic_free: true,
+            clock: Arc::new(SystemClock),
+            on_miss: None,
+            on_progress: None,
+            hooks: Vec::new(),
+            coalesce_window: None,
+            group_commit: None,
+            write_through: false,
+            preallocate_segments: false,
+            checksum_records: false,
+            segment_size: 4 * 1024 * 1024,
+            wal_dir: None,
+            data_dir: None,
+            compaction_threshold: 1024 * 1024,
+            compaction_throttle: None,
+            compaction_policy: Arc::new(SizeTiered),
+            offset_index: false,
+            key_index: KeyIndex::default(),
+            cache: None,
+            eviction: None,
+            history_retention: None,
+            wal_format: WalFormat::default(),
+            lock_timeout: Duration::ZERO,
+            max_key_len: 4 * 1024,
+            max_value_len: 16 * 1024 * 1024,
+            max_memory: None,
+            runtime: None,
+            replica_of: None,
+            #[cfg(feature = "raft")]
+            cluster: None,
+            #[cfg(feature = "archive")]
+            archive_segments: false,
+        }
+    }
+}
+
+impl fmt::Debug for OpenOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("OpenOptions");
+        debug
+            .field("panic_free", &self.panic_free)
+            .field("clock", &self.clock)
+            .field("on_miss", &self.on_miss.is_some())
+            .field("on_progress", &self.on_progress.is_some())
+            .field("hooks", &self.hooks.len())
+            .field("coalesce_window", &self.coalesce_window)
+            .field("group_commit", &self.group_commit)
+            .field("write_through", &self.write_through)
+            .field("preallocate_segments", &self.preallocate_segments)
+            .field("checksum_records", &self.checksum_records)
+            .field("segment_size", &self.segment_size)
+            .field("wal_dir", &self.wal_dir)
+            .field("data_dir", &self.data_dir)
+            .field("compaction_threshold", &self.compaction_threshold)
+            .field("compaction_throttle", &self.compaction_throttle)
+            .field("compaction_policy", &self.compaction_policy)
+            .field("offset_index", &self.offset_index)
+            .field("key_index", &self.key_index)
+            .field("cache", &self.cache)
+            .field("eviction", &self.eviction)
+            .field("history_retention", &self.history_retention)
+            .field("wal_format", &self.wal_format)
+            .field("lock_timeout", &self.lock_timeout)
+            .field("max_key_len", &self.max_key_len)
+            .field("max_value_len", &self.max_value_len)
+            .field("max_memory", &self.max_memory)
+            .field("runtime", &self.runtime)
+            .field("replica_of", &self.replica_of);
+        #[cfg(feature = "raft")]
+        debug.field("cluster", &self.cluster);
+        #[cfg(feature = "archive")]
+        debug.field("archive_segments", &self.archive_segments);
+        debug.finish()
+    }
+}
+
+impl OpenOptions {
+    /// Returns default options
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether panics inside store operations are caught, default `true`
+    ///
+    /// A caught panic poisons the store: it turns read-only and every write returns
+    /// [`KvStoreError::Poisoned`](crate::KvStoreError::Poisoned) until it is reopened.
+    /// Disable to let panics propagate, e.g. to get backtraces while debugging. Panics are only
+    /// caught when they unwind, so a binary built with `panic = "abort"` exits on them instead.
+    pub fn panic_free(&mut self, panic_free: bool) -> &mut Self {
+        self.panic_free = panic_free;
+        self
+    }
+
+    /// Sets the time source for time-dependent features, default [`SystemClock`]
+    pub fn clock(&mut self, clock: Arc<dyn Clock>) -> &mut Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Sets a callback run whenever `get` finds no value for a key
+    ///
+    /// The store itself never prints; misses are otherwise only visible as `debug` log events.
+    pub fn on_miss(&mut self, hook: impl Fn(&str) + Send + Sync + 'static) -> &mut Self {
+        self.on_miss = Some(Arc::new(hook));
+        self
+    }
+
+    /// Sets a callback run with the progress of loading the log files while opening the store
+    ///
+    /// It runs on the opening thread once each log file is loaded, and every 10 000 records
+    /// replayed within one, so that opening a store with a large log is not silent. A new
+    /// store has no log files to load, and reports none.
+    pub fn on_progress(
+        &mut self,
+        hook: impl Fn(&OpenProgress) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.on_progress = Some(Arc::new(hook));
+        self
+    }
+
+    /// Adds a hook run around each command the store executes, after those added before
+    pub fn hook(&mut self, hook: impl Hook + 'static) -> &mut Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Enables write coalescing with the given window, default disabled
+    ///
+    /// Writes to a key are held back in memory and only the last one per key is logged once
+    /// the window opened by the oldest held-back write has elapsed, by the WAL writer thread or
+    /// the [`KvsRuntime`] if any, or earlier when the store is dropped. Held-back writes are
+    /// visible to reads but not durable: a crash loses up to one window of writes, plus one
+    /// tick with a runtime, on top of the usual WAL guarantees.
+    pub fn coalesce_window(&mut self, window: Duration) -> &mut Self {
+        self.coalesce_window = Some(window);
+        self
+    }
+
+    /// Makes writes durable using group commit with the given window, default disabled
+    ///
+    /// Each write returns only once its record is synced to disk. Records arriving at the WAL
+    /// writer within the window of the first one are committed together with a single write
+    /// and sync, trading up to one window of latency for throughput under concurrent writers.
+    /// Without group commit, records already queued are still written together, but not synced.
+    pub fn group_commit(&mut self, window: Duration) -> &mut Self {
+        self.group_commit = Some(window);
+        self
+    }
+
+    /// Opens WAL segments with `O_DSYNC`, or `FILE_FLAG_WRITE_THROUGH` on Windows, default false
+    ///
+    /// Every batch of records is then durable once written, with the single write of the batch
+    /// instead of a write followed by `fsync`. Combined with [group commit](Self::group_commit),
+    /// records queued within the window still share one write.
+    pub fn write_through(&mut self, enabled: bool) -> &mut Self {
+        self.write_through = enabled;
+        self
+    }
+
+    /// Sets the size in bytes past which the active WAL segment is sealed, default 4 MiB
+    pub fn segment_size(&mut self, bytes: u64) -> &mut Self {
+        self.segment_size = bytes;
+        self
+    }
+
+    /// Allocates the [segment size](Self::segment_size) of disk space to each active segment up
+    /// front, and recycles segment files compacted away, default false
+    ///
+    /// Appends then neither grow the file nor allocate blocks, sparing `fsync` the metadata
+    /// updates behind its latency spikes on filesystems such as ext4 and XFS. Up to a few
+    /// compacted segment files are zeroed and renamed `wa.free.<id>` instead of being deleted,
+    /// and reused as active segments rather than created. Segments are truncated to their
+    /// records when sealed, and the zeros past the records of an active segment left by a crash
+    /// are skipped on replay.
+    pub fn preallocate_segments(&mut self, enabled: bool) -> &mut Self {
+        self.preallocate_segments = enabled;
+        self
+    }
+
+    /// Writes each record framed by a marker, its length and its CRC, default false
+    ///
+    /// Replay then tells the records of the active segment left by a crash from bytes a torn
+    /// write or a recycled segment left after them, and drops everything from the first record
+    /// failing its check on, instead of failing to open. The bytes of records in the active
+    /// segment are also recorded in the manifest on close, past which nothing is replayed.
+    /// Stale records that still pass their check are skipped as duplicates, being numbered
+    /// before those replayed.
+    ///
+    /// Stores written with and without checked records can be opened either way.
+    pub fn checksum_records(&mut self, enabled: bool) -> &mut Self {
+        self.checksum_records = enabled;
+        self
+    }
+
+    /// Puts the active and sealed WAL segments in dir, default the store directory
+    ///
+    /// Keeping the WAL on its own disk separates its appends from the reads and rewrites of
+    /// compaction. The directories are recorded in the manifest, and opening the store with
+    /// others fails with [`KvStoreError::WrongLayout`](crate::KvStoreError::WrongLayout).
+    pub fn wal_dir(&mut self, dir: impl Into<PathBuf>) -> &mut Self {
+        self.wal_dir = Some(dir.into());
+        self
+    }
+
+    /// Puts the base segments written by compaction and their hint files in dir, default the
+    /// store directory
+    ///
+    /// Recorded in the manifest along with the [WAL directory](Self::wal_dir).
+    pub fn data_dir(&mut self, dir: impl Into<PathBuf>) -> &mut Self {
+        self.data_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets the size in bytes of the log below which it is never compacted, default 1 MiB
+    ///
+    /// Above it, the [compaction policy](Self::compaction_policy) decides when to compact.
+    pub fn compaction_threshold(&mut self, bytes: u64) -> &mut Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Sets the policy deciding when the WAL is compacted, and which of its segments, default
+    /// [`SizeTiered`]
+    ///
+    /// See [`TimeBased`](crate::TimeBased) for workloads whose records expire.
+    pub fn compaction_policy(&mut self, policy: impl CompactionPolicy + 'static) -> &mut Self {
+        self.compaction_policy = Arc::new(policy);
+        self
+    }
+
+    /// Limits how fast compaction copies live records, in bytes per second, default unlimited
+    ///
+    /// Compaction runs in the background either way, and pauses while writes queue up, so that
+    /// it leaves the disk to foreground writes.
+    pub fn compaction_throttle(&mut self, bytes_per_sec: u64) -> &mut Self {
+        self.compaction_throttle = Some(bytes_per_sec);
+        self
+    }
+
+    /// Sets whether values are kept on disk only, default `false`
+    ///
+    /// In offset-index mode, the store keeps in memory only where the WAL record of each key's
+    /// value is, and reads values from the log, trading read latency for memory. Missing keys
+    /// are answered from memory without any disk read. Reads of sealed segments go through a
+    /// memory mapping under the `mmap` feature. Writes are logged before they become visible,
+    /// and write coalescing is not applied.
+    pub fn offset_index(&mut self, offset_index: bool) -> &mut Self {
+        self.offset_index = offset_index;
+        self
+    }
+
+    /// Sets how the index of the log maps keys to their records, default [`KeyIndex::Hashed`]
+    ///
+    /// [`KeyIndex::Radix`] holds prefixes shared by keys once, cutting the memory taken by the
+    /// index for long keys sharing prefixes such as `user:12345:profile`, at the cost of
+    /// slower lookups. The index holds every key in both modes, and is all the store keeps of
+    /// keys in memory in [offset-index mode](Self::offset_index), where the savings matter
+    /// most.
+    pub fn key_index(&mut self, key_index: KeyIndex) -> &mut Self {
+        self.key_index = key_index;
+        self
+    }
+
+    /// Enables an LRU cache of values read from disk in offset-index mode, default disabled
+    ///
+    /// Values are cached on `get` and invalidated by `set` and `remove` of their key. Hits and
+    /// misses are recorded under the `metrics` feature. Without a cache of its own, a store
+    /// opened with a [`KvsRuntime`] draws from the cache of the runtime, if any.
+    pub fn cache(&mut self, config: CacheConfig) -> &mut Self {
+        self.cache = Some(config);
+        self
+    }
+
+    /// Turns the store into a persistent cache evicting keys beyond a key count or byte budget,
+    /// default disabled
+    ///
+    /// After each write of the default key space, the least recently or least frequently used
+    /// keys, as of the [policy](crate::EvictionPolicy), are removed until the store is back
+    /// within budget. Evictions are logged as removals, so that they survive restarts and are
+    /// shipped to replicas. Writes replayed on open, shipped by a primary or committed by a Raft
+    /// cluster evict no key, though they count as uses. Buckets, ID sequences and timeseries
+    /// are never evicted.
+    pub fn eviction(&mut self, config: EvictionConfig) -> &mut Self {
+        self.eviction = Some(config);
+        self
+    }
+
+    /// Retains the values of keys written by the given number of latest log records, default
+    /// disabled
+    ///
+    /// Values of the default key space are kept in memory as of every log sequence number in the
+    /// window, for [`KvStore::get_at`] and [`KvStore::history`], along with one value per key as
+    /// of the start of the window. The history starts over from the records replayed on open,
+    /// which no longer include those superseded before the last compaction. With history
+    /// retained, base segments are replayed on open rather than adopted using their hint files.
+    pub fn history_retention(&mut self, records: u64) -> &mut Self {
+        self.history_retention = Some(records);
+        self
+    }
+
+    /// Sets the format of log records of a new store, default [`WalFormat::Text`]
+    ///
+    /// The format is recorded in the manifest, so that records are decoded right on replay.
+    /// Existing stores keep the format they were created with.
+    pub fn wal_format(&mut self, format: WalFormat) -> &mut Self {
+        self.wal_format = format;
+        self
+    }
+
+    /// Sets how long to wait for another store holding the store directory to close it, default
+    /// zero
+    ///
+    /// A store directory is locked by the store that has it open, including in other processes.
+    /// Opening it fails with [`KvStoreError::Locked`](crate::KvStoreError::Locked) if it is still
+    /// locked after the timeout.
+    pub fn lock_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Sets the maximum key length in bytes accepted by `set`, default 4 KiB
+    ///
+    /// Longer keys are rejected with [`KvStoreError::KeyTooLarge`](crate::KvStoreError::KeyTooLarge). The
+    /// limit also applies to replay, so lowering it below keys already logged fails the open.
+    pub fn max_key_len(&mut self, bytes: usize) -> &mut Self {
+        self.max_key_len = bytes;
+        self
+    }
+
+    /// Sets the maximum value size in bytes accepted by `set`, default 16 MiB
+    ///
+    /// Larger values are rejected with [`KvStoreError::ValueTooLarge`](crate::KvStoreError::ValueTooLarge).
+    /// The limit also applies to replay, so lowering it below values already logged fails the
+    /// open.
+    pub fn max_value_len(&mut self, bytes: usize) -> &mut Self {
+        self.max_value_len = bytes;
+        self
+    }
+
+    /// Sets the memory budget in bytes of the keys, values and index of the store, default
+    /// unlimited
+    ///
+    /// Once the approximate memory taken, as returned in
+    /// [`StoreStats::memory_bytes`](crate::StoreStats::memory_bytes), exceeds the budget, writes
+    /// adding keys or values are rejected with
+    /// [`KvStoreError::OutOfMemoryBudget`](crate::KvStoreError::OutOfMemoryBudget), rather than
+    /// the process being killed out of memory. Removals are still accepted to free memory, and
+    /// records replayed on open or applied from a primary or cluster are not rejected. Stores
+    /// in [offset-index mode](Self::offset_index) keep only keys in memory.
+    pub fn max_memory(&mut self, bytes: u64) -> &mut Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    /// Shares background resources of a runtime with other stores, default none
+    ///
+    /// See [`KvsRuntime`] for the resources shared.
+    pub fn runtime(&mut self, runtime: &KvsRuntime) -> &mut Self {
+        self.runtime = Some(runtime.clone());
+        self
+    }
+
+    /// Opens the store as a replica of the primary at address, default none
+    ///
+    /// Servers reject writes of clients to a replica, which only applies the records its
+    /// primary ships, until [promoted](KvStore::promote). See [`replication`](crate::replication).
+    pub fn replica_of(&mut self, primary: SocketAddr) -> &mut Self {
+        self.replica_of = Some(primary);
+        self
+    }
+
+    /// Opens the store as the member of a Raft cluster served at node address, default none
+    ///
+    /// Members are those of a new cluster, including node when bootstrapping it, and are only
+    /// recorded if the store has no Raft log yet. Run the member with
+    /// [`raft::run`](crate::raft::run).
+    #[cfg(feature = "raft")]
+    pub fn cluster(&mut self, node: SocketAddr, members: &[SocketAddr]) -> &mut Self {
+        self.cluster = Some((node, members.to_vec()));
+        self
+    }
+
+    /// Sets whether each segment sealed or compacted is kept in the `archive` subdirectory of
+    /// the store until uploaded by [`archive::spawn`](crate::archive::spawn), default `false`
+    ///
+    /// Segments are kept as hard links where the file system supports them, so that neither
+    /// compaction nor reopening the store drops them before they are archived.
+    #[cfg(feature = "archive")]
+    pub fn archive_segments(&mut self, archive: bool) -> &mut Self {
+        self.archive_segments = archive;
+        self
+    }
+
+    /// Opens the KV store at path with these options
+    ///
+    /// # Errors
+    /// Returns `Err` if WAL move, open, or read fails
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(&path.into(), self.clone())
+    }
+}
+
+/// Options of a single write, see [`KvStore::set_opts`]
+///
+/// The default options make a write like [`KvStore::set`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SetOptions {
+    /// Whether to sync the WAL to disk before returning, as [`KvStore::sync`] does, even without
+    /// [group commit](OpenOptions::group_commit)
+    pub fsync: bool,
+    /// Whether to only set the key if missing, like Redis `SET NX`
+    pub if_not_exists: bool,
+    /// Whether to only set the key if present, like Redis `SET XX`
+    pub if_exists: bool,
+    /// Time after which the key expires, like Redis `SET PX`, or `None` to keep it until removed
+    ///
+    /// The expiry is logged with the write, so it survives restarts and compaction. Expired keys
+    /// read as missing, and are removed by [`KvStore::purge_expired`]. Writing the key again
+    /// without a TTL, or renaming it, drops its TTL, while appending to it keeps it.
+    pub ttl: Option<Duration>,
+}
+
+/// Options of a page of a scan, see [`KvStore::scan_opts`]
+///
+/// The default options scan every matching key, like [`KvStore::scan`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScanOptions {
+    /// Maximum number of keys in the page, or all of them if `None`
+    pub limit: Option<usize>,
+    /// Key the page starts after, e.g. the last key of the previous page, so that pages are
+    /// fetched one after the other
+    pub start_after: Option<String>,
+    /// Whether to scan in reverse key order, so that [`Self::start_after`] skips the keys from
+    /// it on
+    pub reverse: bool,
+}
@@ -0,0 +1,82 @@
+//! Bulk loading of commands piped in by `kvs load`, and the formats `kvs dump` pipes pairs out
+//! in, so that the store composes with shell pipelines
+//!
+//! Each input line is one command, either as text, `set KEY VALUE` with the value running to
+//! the end of the line or `rm KEY`, or as a JSON object as printed by `kvs dump --format jsonl`,
+//! `{"key": KEY, "value": VALUE}`, with a null value removing the key.
+
+use crate::{KvStore, KvStoreError, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::io::BufRead;
+use strum::Display;
+use tracing::info;
+
+/// Format `kvs dump` prints pairs in
+#[derive(Clone, Copy, Debug, Default, Display, PartialEq, Eq, ValueEnum)]
+#[strum(serialize_all = "kebab-case")]
+pub enum DumpFormat {
+    /// Space-separated key and value per line
+    #[default]
+    Text,
+    /// JSON object per line, which `kvs load` reads back
+    Jsonl,
+}
+
+/// Command of a JSON input line
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Line {
+    key: String,
+    value: Option<String>,
+}
+
+/// Applies the commands of the lines of input to store, returning how many were applied
+///
+/// Writes are synced to disk in batches of batch commands, and at the end of input. Blank
+/// lines are skipped, and removing a missing key is not an error.
+///
+/// # Errors
+/// Returns `Err` if reading input fails, a line is malformed, or a write fails; the commands
+/// of previous lines stay applied
+pub fn load(store: &KvStore, input: impl BufRead, batch: usize) -> Result<u64> {
+    let mut loaded = 0;
+    for (n, line) in input.lines().enumerate() {
+        let line = line.map_err(KvStoreError::FailedImport)?;
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid =
+            |reason: &str| KvStoreError::InvalidImport(format!("line {}: {reason}", n + 1));
+        let (key, value) = if line.starts_with('{') {
+            let line: Line = serde_json::from_str(line).map_err(|e| invalid(&e.to_string()))?;
+            (line.key, line.value)
+        } else {
+            match line.split_once(' ') {
+                Some(("set", pair)) => {
+                    let (key, value) = pair
+                        .split_once(' ')
+                        .ok_or_else(|| invalid("expected set KEY VALUE"))?;
+                    (key.to_owned(), Some(value.to_owned()))
+                }
+                Some(("rm", key)) => (key.to_owned(), None),
+                _ => return Err(invalid("expected set KEY VALUE, rm KEY or a JSON object")),
+            }
+        };
+        match value {
+            Some(value) => drop(store.set(key, value)?),
+            None => match store.remove(key) {
+                Ok(_) | Err(KvStoreError::FailedRm(_)) => {}
+                Err(e) => return Err(e),
+            },
+        }
+        loaded += 1;
+        if loaded % batch.max(1) as u64 == 0 {
+            store.sync()?;
+        }
+    }
+    store.sync()?;
+    info!(commands = loaded, "Loaded commands");
+    Ok(loaded)
+}
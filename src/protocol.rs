@@ -0,0 +1,296 @@
+//! Wire protocol spoken between [`client`](crate::client) and [`server`](crate::server)
+//!
+//! Each message is a frame: its length in bytes as a 4-byte big-endian integer, followed by
+//! that many bytes of JSON. Requests and their responses travel in an [`Envelope`] holding the
+//! ID the client gave the request. A client may write many requests before reading any
+//! response (pipelining); the server executes the requests of a connection concurrently and
+//! answers each as soon as it completes, so responses may arrive in any order, and are matched
+//! to their requests by ID.
+//!
+//! A server refusing a connection, e.g. at its connection limit, answers with a single `busy`
+//! response with ID [`REFUSED`] before closing it.
+//!
+//! Frames longer than [`MAX_FRAME`] are refused on either side; records shipped to replicas
+//! and tailing clients are split across frames to fit.
+
+use crate::{auth::Credentials, Health, Shipment, SlowQuery, StoreStats};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    io::{self, prelude::*},
+    net::SocketAddr,
+};
+use strum::IntoStaticStr;
+
+/// ID of the response of a server refusing a connection
+pub const REFUSED: u64 = u64::MAX;
+
+/// Maximum length in bytes of the body of a frame
+///
+/// Leaves room for a value of the default maximum length, see
+/// [`OpenOptions::max_value_len`](crate::OpenOptions::max_value_len), escaped as JSON along
+/// with its key.
+pub const MAX_FRAME: u32 = 64 * 1024 * 1024;
+
+/// Bytes of records shipped per frame, leaving room for their escaping as JSON
+pub(crate) const SHIPMENT_BUDGET: usize = MAX_FRAME as usize / 4;
+
+/// Request or response, with the ID of the request
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Envelope<T> {
+    /// ID of the request, chosen by the client and unique among its requests in flight on the
+    /// connection
+    pub id: u64,
+    /// Request or response
+    pub body: T,
+}
+
+/// Operation sent by a client
+#[derive(Clone, Debug, Deserialize, IntoStaticStr, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Request {
+    /// Authenticate as a user for the following requests on this connection
+    ///
+    /// Servers with an [ACL](crate::auth::Acl) deny all other requests until authenticated.
+    Auth(Credentials),
+    /// Get value by key, or the field at a path of its JSON value
+    Get {
+        /// Key string
+        key: String,
+        /// Path of the field, see [`KvStore::get_path`](crate::KvStore::get_path), or `None`
+        /// for the whole value
+        path: Option<String>,
+    },
+    /// Set key-value pair by key, or the field at a path of its JSON value
+    Set {
+        /// Key string
+        key: String,
+        /// Value string, the JSON of the field if setting one
+        value: String,
+        /// Path of the field, see [`KvStore::set_path`](crate::KvStore::set_path), or `None`
+        /// for the whole value
+        path: Option<String>,
+    },
+    /// Remove key-value pair by key
+    Rm {
+        /// Key string
+        key: String,
+    },
+    /// List key-value pairs whose keys start with prefix
+    Scan {
+        /// Key prefix
+        prefix: String,
+    },
+    /// List a page of the keys matching a pattern, after a cursor, like Redis `SCAN cursor
+    /// [MATCH pattern] [COUNT n]`, answered with a `page`
+    ///
+    /// The server goes through up to `count` keys in key order after the cursor, and answers
+    /// with those matching the pattern, possibly none, and the cursor to send for the next
+    /// page. A scan started without a cursor and continued until the answer has none returns
+    /// every key present for the whole scan, whatever writes happen in the meantime, and no
+    /// key twice.
+    ScanCursor {
+        /// Cursor of the page, from the previous page, or `None` to start a scan
+        cursor: Option<String>,
+        /// Glob-style pattern, e.g. `user:*`, of `*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\`
+        /// escapes, or `None` to match all keys
+        pattern: Option<String>,
+        /// Number of keys to go through, default 10
+        count: Option<usize>,
+    },
+    /// Take a lock by key unless held, answered with the token of its lease, see
+    /// [`KvStore::lock`](crate::KvStore::lock)
+    Lock {
+        /// Key string
+        key: String,
+        /// Time to live of the lease in milliseconds
+        ttl_ms: u64,
+    },
+    /// Release a lock by key held with the lease of a token
+    Unlock {
+        /// Key string
+        key: String,
+        /// Token of the lease
+        token: u64,
+    },
+    /// Stream [`Replication`] messages on this connection, sent by a primary to a replica
+    ///
+    /// Once answered, the connection carries no further requests, only replication messages
+    /// outside of envelopes.
+    Replicate,
+    /// Stream [`Tailing`] messages on this connection: the WAL records numbered from a sequence
+    /// number on, then those committed, see [`KvStore::tail`](crate::KvStore::tail)
+    ///
+    /// Once answered, the connection carries no further requests, only tailing messages
+    /// outside of envelopes.
+    Tail {
+        /// Sequence number of the first record
+        from_seq: u64,
+    },
+    /// Stop being a replica and accept writes from clients
+    Promote,
+    /// Reload the settings of the server that can change while it runs, see
+    /// [`ServerOptions::on_reload`](crate::server::ServerOptions::on_reload)
+    Reload,
+    /// Log writes held back by coalescing and sync the WAL to disk, see
+    /// [`KvStore::flush`](crate::KvStore::flush)
+    Flush,
+    /// Compact the WAL now, see [`KvStore::compact`](crate::KvStore::compact)
+    Compact,
+    /// Rewrite the WAL as a new base segment, see [`KvStore::snapshot`](crate::KvStore::snapshot)
+    Snapshot,
+    /// Reject writes until thawed, see [`KvStore::freeze_writes`](crate::KvStore::freeze_writes)
+    Freeze,
+    /// Accept writes again after `freeze`
+    Thaw,
+    /// Get statistics of the store
+    Stats,
+    /// Check that the server is alive and get the health of its store, see
+    /// [`KvStore::health`](crate::KvStore::health)
+    ///
+    /// Needs no authentication, so that probes need no credentials.
+    Ping,
+    /// Get the requests the server recently took long to answer, see
+    /// [`ServerOptions::slow_log`](crate::server::ServerOptions::slow_log)
+    SlowLog,
+    /// Get the sequence number of the last write logged, or applied from the primary by a
+    /// replica, to use as a session token
+    Sequence,
+    /// Wait until the write with a session token is applied, so that the following reads
+    /// observe it
+    ///
+    /// A replica that does not catch up in time answers with a redirect to its primary.
+    WaitFor {
+        /// Session token from `sequence`
+        sequence: u64,
+    },
+    /// Message between members of a Raft cluster
+    #[cfg(feature = "raft")]
+    Raft(crate::raft::Message),
+    /// Add a member to the Raft cluster, sent to its leader
+    #[cfg(feature = "raft")]
+    ClusterAddNode {
+        /// Address of the TCP server of the new member
+        node: SocketAddr,
+    },
+    /// Get the Raft cluster state seen by the server
+    #[cfg(feature = "raft")]
+    ClusterStatus,
+}
+
+/// Outcome of a request, sent by the server
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    /// Request succeeded, with the value found by a `get`
+    Ok(Option<String>),
+    /// Key-value pairs found by a `scan`, sorted by key
+    Entries(Vec<(String, String)>),
+    /// Page of keys found by a `scan_cursor`
+    Page {
+        /// Keys matching the pattern, sorted by key
+        keys: Vec<String>,
+        /// Cursor of the next page, or `None` if the scan is complete
+        cursor: Option<String>,
+    },
+    /// Sequence number requested by `sequence`
+    Sequence(u64),
+    /// Statistics requested by `stats`
+    Stats(StoreStats),
+    /// Health requested by `ping`
+    Health(Health),
+    /// Slow queries requested by `slow_log`, oldest first
+    SlowQueries(Vec<SlowQuery>),
+    /// Send the request to the server at the address instead: the primary of a lagging
+    /// replica, or the leader of a Raft cluster
+    Redirect(SocketAddr),
+    /// Answer of a Raft cluster member to a `raft` message
+    #[cfg(feature = "raft")]
+    Raft(crate::raft::Message),
+    /// Raft cluster state requested by `cluster_status`
+    #[cfg(feature = "raft")]
+    Cluster(crate::raft::Status),
+    /// Key to remove was not found
+    KeyNotFound(String),
+    /// Credentials were rejected, or the user lacks the access required by the request
+    PermissionDenied(String),
+    /// Server is over a rate or connection limit; try again later
+    Busy(String),
+    /// Write rejected while writes are frozen on the server
+    ReadOnly,
+    /// Request failed on the server
+    Err(String),
+}
+
+/// Message streamed by a primary to a replica that accepted a `replicate` request
+///
+/// The stream starts with the live records of the primary, followed by `synced`, then carries
+/// the records the primary commits, with heartbeats while there are none.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Replication {
+    /// Newline-terminated WAL records, in log order
+    Records {
+        /// Records
+        records: String,
+        /// Sequence number of the last record on the primary
+        sequence: u64,
+    },
+    /// Every live record of the primary was sent
+    Synced,
+    /// Primary is still there, sent when it has had no records to send for a while
+    Heartbeat,
+}
+
+/// Message streamed by a server to a client that sent a `tail` request
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tailing {
+    /// WAL records
+    Shipment(Shipment),
+    /// Server is still there, sent when it has had no records to send for a while
+    Heartbeat,
+}
+
+/// Writes a message as a frame, without flushing writer
+///
+/// # Errors
+/// Returns `Err` if the message is longer than [`MAX_FRAME`] or writing fails
+pub fn write_frame(writer: &mut impl Write, message: &impl Serialize) -> io::Result<()> {
+    let body = serde_json::to_vec(message)?;
+    let len = u32::try_from(body.len())
+        .ok()
+        .filter(|&len| len <= MAX_FRAME)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "frame too long"))?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(&body)
+}
+
+/// Reads a message from a frame, or `None` if reader is at its end
+///
+/// # Errors
+/// Returns `Err` if reading fails, the frame is longer than [`MAX_FRAME`], the stream ends
+/// within a frame, or the frame does not hold a message
+pub fn read_frame<T: DeserializeOwned>(reader: &mut impl Read) -> io::Result<Option<T>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    // The body grows as it is read, so a bogus length cannot exhaust memory up front
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {len} bytes exceeds limit of {MAX_FRAME}"),
+        ));
+    }
+    let mut body = Vec::new();
+    reader.take(u64::from(len)).read_to_end(&mut body)?;
+    if body.len() < len as usize {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(serde_json::from_slice(&body)?))
+}
@@ -0,0 +1,1260 @@
+//! Raft consensus among a cluster of servers
+//!
+//! Writes of clients to a cluster member are appended to a Raft log replicated by the elected
+//! leader, and applied to the store of every member once a majority holds them. Members other
+//! than the leader redirect writes to it; reads are served from the local store, so they may
+//! lag behind the leader. Members are identified by the address of their TCP
+//! [`server`](crate::server), which also carries the [`Message`]s between them.
+//!
+//! The Raft log is kept in `raft.log` next to the WAL, and the current term and vote in
+//! `raft.state`. Applied entries are compacted away once numerous, the store itself serving as
+//! snapshot: a member lagging behind them is sent the live records of the leader instead.
+//!
+//! Members are added one at a time by the leader (see [`ClusterCommand::AddNode`]), the new
+//! membership taking effect as soon as its entry is appended. A node joining an existing cluster
+//! is started with the current members, excluding itself: it only takes part in elections once
+//! the leader added it.
+
+use crate::{
+    freeze, memory,
+    protocol::{read_frame, write_frame, Envelope, Request, Response},
+    replication,
+    server::Shutdown,
+    wal::Shipment,
+    Command, KvStore, KvStoreError, Result,
+};
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    fmt,
+    fs::{self, File},
+    hash::BuildHasher,
+    io::{self, prelude::*, BufReader, BufWriter},
+    net::{SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex, MutexGuard, PoisonError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use strum::Display;
+use tracing::{debug, info, warn};
+
+/// Raft log file name
+const LOG: &str = "raft.log";
+
+/// File name of the current term and vote
+const STATE: &str = "raft.state";
+
+/// Interval at which the leader sends entries, or heartbeats if there are none, to each member
+const HEARTBEAT: Duration = Duration::from_millis(50);
+
+/// Minimum time without hearing from a leader before starting an election
+const MIN_ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Upper bound in milliseconds of the random time added to the election timeout, so that
+/// members rarely time out together
+const ELECTION_JITTER_MS: u64 = 300;
+
+/// Connect, read, and write timeout of connections to members
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Time a write waits for its entry to be applied
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum entries sent in one message
+const MAX_BATCH: usize = 256;
+
+/// Applied entries kept in the log before compacting them away
+const COMPACT_THRESHOLD: u64 = 1024;
+
+/// Operation replicated through the Raft log
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Op {
+    /// Set key-value pair by key
+    Set {
+        /// Key string
+        key: String,
+        /// Value string
+        value: String,
+    },
+    /// Remove key-value pair by key
+    Rm {
+        /// Key string
+        key: String,
+    },
+    /// Add a member to the cluster
+    AddNode(SocketAddr),
+    /// Appended by a new leader to commit the entries of earlier terms
+    Noop,
+}
+
+impl TryFrom<Command> for Op {
+    type Error = KvStoreError;
+
+    fn try_from(cmd: Command) -> Result<Self> {
+        match cmd {
+            Command::Set {
+                key,
+                value,
+                path: None,
+                nx: false,
+                xx: false,
+            } => Ok(Self::Set { key, value }),
+            Command::Rm { key, prefix: None } => Ok(Self::Rm { key }),
+            cmd => Err(KvStoreError::InvalidCommand(format!(
+                "{cmd} is not replicated through Raft"
+            ))),
+        }
+    }
+}
+
+/// Entry of the Raft log
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Entry {
+    /// Term of the leader that appended the entry
+    pub term: u64,
+    /// Operation
+    pub op: Op,
+}
+
+/// Message between cluster members, carried by [`Request::Raft`] and answered with
+/// [`Response::Raft`]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Message {
+    /// Sent by a candidate to collect votes, answered with `vote`
+    RequestVote {
+        /// Term of the candidate
+        term: u64,
+        /// Candidate
+        candidate: SocketAddr,
+        /// Index of the last entry of the candidate
+        last_index: u64,
+        /// Term of the last entry of the candidate
+        last_term: u64,
+    },
+    /// Answer to `request_vote`
+    Vote {
+        /// Current term of the member, for a candidate behind to step down
+        term: u64,
+        /// Whether the member voted for the candidate
+        granted: bool,
+    },
+    /// Sent by the leader to replicate entries, or as heartbeat without entries, answered with
+    /// `appended`
+    AppendEntries {
+        /// Term of the leader
+        term: u64,
+        /// Leader
+        leader: SocketAddr,
+        /// Index of the entry before the entries
+        prev_index: u64,
+        /// Term of the entry before the entries
+        prev_term: u64,
+        /// Entries following `prev_index`
+        entries: Vec<Entry>,
+        /// Index of the last entry the leader committed
+        commit: u64,
+    },
+    /// Sent by the leader to a member lagging behind its compacted entries, answered with
+    /// `appended`
+    InstallSnapshot {
+        /// Term of the leader
+        term: u64,
+        /// Leader
+        leader: SocketAddr,
+        /// Index of the last entry applied to the snapshot
+        last_index: u64,
+        /// Term of the last entry applied to the snapshot
+        last_term: u64,
+        /// Members as of the last entry applied to the snapshot
+        members: Vec<SocketAddr>,
+        /// Newline-terminated live WAL records of the leader
+        records: String,
+    },
+    /// Answer to `append_entries` and `install_snapshot`
+    Appended {
+        /// Current term of the member, for a leader behind to step down
+        term: u64,
+        /// Whether the member now holds the entries
+        success: bool,
+        /// Index of the last entry the member holds if successful, otherwise a hint where its
+        /// log diverges
+        last_index: u64,
+    },
+}
+
+/// Role of a cluster member
+#[derive(Clone, Copy, Debug, Deserialize, Display, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "lowercase")]
+pub enum Role {
+    /// Applies the entries of the leader
+    Follower,
+    /// Collects votes to become leader
+    Candidate,
+    /// Accepts writes and replicates them to the other members
+    Leader,
+}
+
+/// Cluster state seen by a member, answered to [`Request::ClusterStatus`]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Status {
+    /// Address identifying the member
+    pub node: SocketAddr,
+    /// Role of the member
+    pub role: Role,
+    /// Current term
+    pub term: u64,
+    /// Leader of the current term, if known
+    pub leader: Option<SocketAddr>,
+    /// Index of the last entry known to be committed
+    pub commit: u64,
+    /// Index of the last entry applied to the store
+    pub applied: u64,
+    /// Members of the cluster
+    pub members: Vec<SocketAddr>,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Node: {}", self.node)?;
+        writeln!(f, "Role: {}", self.role)?;
+        writeln!(f, "Term: {}", self.term)?;
+        match self.leader {
+            Some(leader) => writeln!(f, "Leader: {leader}")?,
+            None => writeln!(f, "Leader: unknown")?,
+        }
+        writeln!(f, "Commit index: {}", self.commit)?;
+        writeln!(f, "Applied index: {}", self.applied)?;
+        let members: Vec<_> = self.members.iter().map(ToString::to_string).collect();
+        write!(f, "Members: {}", members.join(", "))
+    }
+}
+
+/// Cluster membership commands, sent to a running server
+#[derive(Clone, Debug, Default, PartialEq, Subcommand)]
+pub enum ClusterCommand {
+    /// Add a member to the cluster, through its leader
+    AddNode {
+        /// Address of the TCP server of the new member
+        #[arg(required = true)]
+        node: SocketAddr,
+    },
+    /// Print the cluster state seen by the server
+    #[default]
+    Status,
+}
+
+/// First line of the Raft log file: the last entry compacted away
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Header {
+    index: u64,
+    term: u64,
+    /// Members as of the entry
+    members: Vec<SocketAddr>,
+}
+
+/// Raft log, persisted as a header line followed by one JSON line per entry
+struct Log {
+    path: PathBuf,
+    base: Header,
+    entries: Vec<Entry>,
+}
+
+impl Log {
+    /// Opens the log in dir, or creates it with the given members
+    ///
+    /// Entries after a torn line, which were never acknowledged, are dropped.
+    fn open(dir: &Path, members: Vec<SocketAddr>) -> io::Result<Self> {
+        let path = dir.join(LOG);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut lines = contents.lines();
+        let base = match lines.next() {
+            Some(line) => serde_json::from_str(line)?,
+            None => Header {
+                index: 0,
+                term: 0,
+                members,
+            },
+        };
+        let mut entries = Vec::new();
+        for line in lines {
+            match serde_json::from_str(line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => {
+                    warn!("Dropping torn Raft log entries: {e}");
+                    break;
+                }
+            }
+        }
+
+        let log = Self {
+            path,
+            base,
+            entries,
+        };
+        log.rewrite()?;
+        Ok(log)
+    }
+
+    fn last_index(&self) -> u64 {
+        self.base.index + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries.last().map_or(self.base.term, |e| e.term)
+    }
+
+    /// Returns the position in `entries` of the entry with index
+    fn position(&self, index: u64) -> usize {
+        let offset = index.saturating_sub(self.base.index + 1);
+        usize::try_from(offset).map_or(self.entries.len(), |i| i.min(self.entries.len()))
+    }
+
+    fn entry(&self, index: u64) -> Option<&Entry> {
+        if index <= self.base.index {
+            return None;
+        }
+        self.entries.get(self.position(index))
+    }
+
+    /// Returns the term of the entry with index, unless compacted away or not appended yet
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.base.index {
+            Some(self.base.term)
+        } else {
+            self.entry(index).map(|e| e.term)
+        }
+    }
+
+    /// Returns the entries from index on, up to [`MAX_BATCH`]
+    fn entries_from(&self, index: u64) -> Vec<Entry> {
+        self.entries[self.position(index)..]
+            .iter()
+            .take(MAX_BATCH)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the members as of the entry with index
+    fn members_at(&self, index: u64) -> Vec<SocketAddr> {
+        let end = self.position(index + 1);
+        let mut members = self.base.members.clone();
+        for entry in &self.entries[..end] {
+            if let Op::AddNode(node) = entry.op {
+                if !members.contains(&node) {
+                    members.push(node);
+                }
+            }
+        }
+        members
+    }
+
+    fn members(&self) -> Vec<SocketAddr> {
+        self.members_at(self.last_index())
+    }
+
+    /// Appends entries and syncs them to disk
+    fn append(&mut self, entries: Vec<Entry>) -> io::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut lines = Vec::new();
+        for entry in &entries {
+            serde_json::to_writer(&mut lines, entry)?;
+            lines.push(b'\n');
+        }
+        let mut file = fs::OpenOptions::new().append(true).open(&self.path)?;
+        file.write_all(&lines)?;
+        file.sync_data()?;
+
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    /// Drops the entries from index on, which conflict with those of the leader
+    fn truncate(&mut self, index: u64) -> io::Result<()> {
+        self.entries.truncate(self.position(index));
+        self.rewrite()
+    }
+
+    /// Drops the entries up to index, which are applied to the store
+    fn compact(&mut self, index: u64) -> io::Result<()> {
+        let Some(term) = self.term_at(index) else {
+            return Ok(());
+        };
+        let members = self.members_at(index);
+        self.entries.drain(..self.position(index + 1));
+        self.base = Header {
+            index,
+            term,
+            members,
+        };
+        self.rewrite()
+    }
+
+    /// Replaces all entries by a snapshot ending at base
+    fn reset(&mut self, base: Header) -> io::Result<()> {
+        self.entries.clear();
+        self.base = base;
+        self.rewrite()
+    }
+
+    fn rewrite(&self) -> io::Result<()> {
+        let mut contents = serde_json::to_string(&self.base)? + "\n";
+        for entry in &self.entries {
+            contents += &serde_json::to_string(entry)?;
+            contents.push('\n');
+        }
+        replace(&self.path, &contents)
+    }
+}
+
+/// Current term and vote, persisted before answering any message
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Vote {
+    term: u64,
+    voted_for: Option<SocketAddr>,
+}
+
+/// Replaces the file at path atomically, so an interrupted write leaves the previous one
+fn replace(path: &Path, contents: &str) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// Returns a random election timeout
+fn election_deadline() -> Instant {
+    let jitter = RandomState::new().hash_one(Instant::now()) % ELECTION_JITTER_MS;
+    Instant::now() + MIN_ELECTION_TIMEOUT + Duration::from_millis(jitter)
+}
+
+/// Replication progress of a member, tracked by the leader
+#[derive(Clone, Copy, Debug)]
+struct Progress {
+    /// Index of the next entry to send
+    next: u64,
+    /// Index of the last entry known to be held
+    matched: u64,
+}
+
+/// Volatile and persistent state of a member
+struct State {
+    term: u64,
+    voted_for: Option<SocketAddr>,
+    log: Log,
+    commit: u64,
+    applied: u64,
+    role: Role,
+    leader: Option<SocketAddr>,
+    /// When to start an election, unless a leader is heard from before
+    election: Instant,
+    /// When a leader was last heard from
+    heard: Option<Instant>,
+    /// Members that granted their vote, while candidate
+    votes: HashSet<SocketAddr>,
+    /// Replication progress of the other members, while leader
+    progress: HashMap<SocketAddr, Progress>,
+    /// Outcome of the entries proposed by this member, once applied
+    proposals: HashMap<u64, Option<Result<Option<String>>>>,
+}
+
+/// Member of a Raft cluster, set up by [`OpenOptions::cluster`](crate::OpenOptions::cluster)
+/// and run by [`run`]
+pub(crate) struct Node {
+    id: SocketAddr,
+    dir: PathBuf,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl fmt::Debug for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Node")
+            .field("id", &self.id)
+            .field("dir", &self.dir)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Node {
+    /// Opens the Raft log and state of the member with address id in dir
+    ///
+    /// Members only set up a fresh log: they are the initial members of the cluster, including
+    /// id when bootstrapping it.
+    ///
+    /// # Errors
+    /// Returns `Err` if the log or state cannot be read or written
+    pub(crate) fn open(dir: &Path, id: SocketAddr, members: &[SocketAddr]) -> Result<Self> {
+        let mut members = members.to_vec();
+        members.sort_unstable();
+        members.dedup();
+        let log = Log::open(dir, members).map_err(KvStoreError::FailedRaft)?;
+        let vote: Vote = match fs::read_to_string(dir.join(STATE)) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vote::default(),
+            Err(e) => return Err(KvStoreError::FailedRaft(e)),
+        };
+
+        // Entries up to the compacted ones were applied before the store was closed
+        let applied = log.base.index;
+        Ok(Self {
+            id,
+            dir: dir.to_owned(),
+            state: Mutex::new(State {
+                term: vote.term,
+                voted_for: vote.voted_for,
+                log,
+                commit: applied,
+                applied,
+                role: Role::Follower,
+                leader: None,
+                election: election_deadline(),
+                heard: None,
+                votes: HashSet::new(),
+                progress: HashMap::new(),
+                proposals: HashMap::new(),
+            }),
+            changed: Condvar::new(),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Waits up to timeout for the state to change
+    fn wait(&self, timeout: Duration) {
+        let state = self.lock();
+        drop(
+            self.changed
+                .wait_timeout(state, timeout)
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+    }
+
+    /// Returns the members of the cluster
+    fn members(&self) -> Vec<SocketAddr> {
+        self.lock().log.members()
+    }
+
+    /// Returns the cluster state seen by the member
+    pub(crate) fn status(&self) -> Status {
+        let state = self.lock();
+        Status {
+            node: self.id,
+            role: state.role,
+            term: state.term,
+            leader: state.leader,
+            commit: state.commit,
+            applied: state.applied,
+            members: state.log.members(),
+        }
+    }
+
+    /// Drops the entries of the log applied to the store, which serves as their snapshot
+    ///
+    /// # Errors
+    /// Returns `Err` if the log cannot be rewritten
+    pub(crate) fn snapshot(&self) -> Result<()> {
+        let mut state = self.lock();
+        let applied = state.applied;
+        state.log.compact(applied).map_err(KvStoreError::FailedRaft)
+    }
+
+    /// Appends an operation to the log and waits until it is applied, returning its outcome, the
+    /// previous value of the key for writes
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::NotLeader`] if the member is not the leader or loses leadership
+    /// before the entry is committed, or `Err` if the entry cannot be logged, is not applied in
+    /// time, or fails when applied
+    pub(crate) fn propose(&self, store: &KvStore, op: Op) -> Result<Option<String>> {
+        let mut state = self.lock();
+        if state.role != Role::Leader {
+            return Err(KvStoreError::NotLeader(state.leader));
+        }
+
+        let term = state.term;
+        state
+            .log
+            .append(vec![Entry { term, op }])
+            .map_err(KvStoreError::FailedRaft)?;
+        let index = state.log.last_index();
+        state.proposals.insert(index, None);
+        self.advance_commit(store, &mut state);
+        self.changed.notify_all();
+
+        let deadline = Instant::now() + PROPOSE_TIMEOUT;
+        loop {
+            if let Some(outcome) = state.proposals.get_mut(&index).and_then(Option::take) {
+                state.proposals.remove(&index);
+                return outcome;
+            }
+            // Overwritten by the entries of a later leader
+            if state.log.term_at(index) != Some(term) {
+                state.proposals.remove(&index);
+                return Err(KvStoreError::NotLeader(state.leader));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                state.proposals.remove(&index);
+                return Err(KvStoreError::FailedRaft(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "entry not committed in time",
+                )));
+            }
+            state = self
+                .changed
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    /// Adds a member to the cluster, unless it already is one
+    ///
+    /// # Errors
+    /// Returns `Err` if another member is still being added, or proposing fails
+    pub(crate) fn add_node(&self, store: &KvStore, node: SocketAddr) -> Result<()> {
+        {
+            let state = self.lock();
+            if state.log.members().contains(&node) {
+                return Ok(());
+            }
+            let adding = state
+                .log
+                .entries_from(state.commit + 1)
+                .iter()
+                .any(|e| matches!(e.op, Op::AddNode(_)));
+            if adding {
+                return Err(KvStoreError::InvalidCommand(
+                    "another member is still being added".to_owned(),
+                ));
+            }
+        }
+
+        self.propose(store, Op::AddNode(node))?;
+        info!(%node, "Added cluster member");
+        Ok(())
+    }
+
+    /// Answers a message of another member
+    ///
+    /// # Errors
+    /// Returns `Err` if the message is an answer
+    pub(crate) fn handle(&self, store: &KvStore, message: Message) -> Result<Message> {
+        match message {
+            Message::RequestVote {
+                term,
+                candidate,
+                last_index,
+                last_term,
+            } => Ok(self.vote(term, candidate, last_index, last_term)),
+            Message::AppendEntries {
+                term,
+                leader,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            } => Ok(self.append_entries(
+                store,
+                term,
+                leader,
+                (prev_index, prev_term),
+                entries,
+                commit,
+            )),
+            Message::InstallSnapshot {
+                term,
+                leader,
+                last_index,
+                last_term,
+                members,
+                records,
+            } => {
+                let base = Header {
+                    index: last_index,
+                    term: last_term,
+                    members,
+                };
+                Ok(self.install_snapshot(store, term, leader, base, &records))
+            }
+            Message::Vote { .. } | Message::Appended { .. } => Err(KvStoreError::InvalidCommand(
+                "Raft answers are not requests".to_owned(),
+            )),
+        }
+    }
+
+    fn vote(&self, term: u64, candidate: SocketAddr, last_index: u64, last_term: u64) -> Message {
+        let mut state = self.lock();
+        // Members not added yet, or cut off from the leader, would otherwise disrupt the cluster
+        let leader_alive = state.role == Role::Leader
+            || state
+                .heard
+                .is_some_and(|heard| heard.elapsed() < MIN_ELECTION_TIMEOUT);
+        if term < state.term || leader_alive {
+            return Message::Vote {
+                term: state.term,
+                granted: false,
+            };
+        }
+
+        if term > state.term {
+            self.step_down(&mut state, term);
+        }
+        let up_to_date = (last_term, last_index) >= (state.log.last_term(), state.log.last_index());
+        let granted = up_to_date && state.voted_for.is_none_or(|v| v == candidate);
+        if granted {
+            state.voted_for = Some(candidate);
+            self.save_vote(&state);
+            state.election = election_deadline();
+            debug!(term, %candidate, "Voted");
+        }
+
+        Message::Vote {
+            term: state.term,
+            granted,
+        }
+    }
+
+    fn append_entries(
+        &self,
+        store: &KvStore,
+        term: u64,
+        leader: SocketAddr,
+        (mut prev_index, mut prev_term): (u64, u64),
+        entries: Vec<Entry>,
+        commit: u64,
+    ) -> Message {
+        let mut state = self.lock();
+        if term < state.term {
+            return Message::Appended {
+                term: state.term,
+                success: false,
+                last_index: state.log.last_index(),
+            };
+        }
+        self.follow(&mut state, term, leader);
+
+        let last_index = prev_index + entries.len() as u64;
+        // Entries up to the compacted ones are committed, so they match those of the leader
+        let compacted = state.log.base.index.saturating_sub(prev_index);
+        let entries: Vec<_> = entries
+            .into_iter()
+            .skip(usize::try_from(compacted).unwrap_or(usize::MAX))
+            .collect();
+        if compacted > 0 {
+            prev_index = state.log.base.index;
+            prev_term = state.log.base.term;
+        }
+
+        match state.log.term_at(prev_index) {
+            Some(t) if t == prev_term => {}
+            found => {
+                let hint = if found.is_some() {
+                    prev_index - 1
+                } else {
+                    state.log.last_index()
+                };
+                return Message::Appended {
+                    term,
+                    success: false,
+                    last_index: hint,
+                };
+            }
+        }
+
+        let mut index = prev_index;
+        let mut new = Vec::new();
+        for entry in entries {
+            index += 1;
+            if new.is_empty() {
+                match state.log.term_at(index) {
+                    Some(t) if t == entry.term => continue,
+                    Some(_) => {
+                        if let Err(e) = state.log.truncate(index) {
+                            warn!("Failed to truncate Raft log: {e}");
+                            return Message::Appended {
+                                term,
+                                success: false,
+                                last_index: prev_index,
+                            };
+                        }
+                    }
+                    None => {}
+                }
+            }
+            new.push(entry);
+        }
+        if let Err(e) = state.log.append(new) {
+            warn!("Failed to append to Raft log: {e}");
+            return Message::Appended {
+                term,
+                success: false,
+                last_index: prev_index,
+            };
+        }
+
+        let commit = commit.min(last_index);
+        if commit > state.commit {
+            state.commit = commit;
+            self.apply(store, &mut state);
+        }
+
+        Message::Appended {
+            term,
+            success: true,
+            last_index,
+        }
+    }
+
+    fn install_snapshot(
+        &self,
+        store: &KvStore,
+        term: u64,
+        leader: SocketAddr,
+        base: Header,
+        records: &str,
+    ) -> Message {
+        let mut state = self.lock();
+        if term < state.term {
+            return Message::Appended {
+                term: state.term,
+                success: false,
+                last_index: state.log.last_index(),
+            };
+        }
+        self.follow(&mut state, term, leader);
+
+        let last_index = base.index;
+        if last_index > state.applied {
+            let installed = replication::restore(store, records)
+                .map_err(|e| io::Error::other(e.to_string()))
+                .and_then(|()| state.log.reset(base));
+            if let Err(e) = installed {
+                warn!("Failed to install snapshot: {e}");
+                return Message::Appended {
+                    term,
+                    success: false,
+                    last_index: state.log.last_index(),
+                };
+            }
+            state.commit = last_index;
+            state.applied = last_index;
+            info!(last_index, "Installed snapshot from leader");
+            self.changed.notify_all();
+        }
+
+        Message::Appended {
+            term,
+            success: true,
+            last_index,
+        }
+    }
+
+    /// Follows the leader of term, which was just heard from
+    fn follow(&self, state: &mut State, term: u64, leader: SocketAddr) {
+        if term > state.term || state.role != Role::Follower {
+            self.step_down(state, term);
+        }
+        if state.leader != Some(leader) {
+            info!(term, %leader, "Following leader");
+        }
+        state.leader = Some(leader);
+        state.heard = Some(Instant::now());
+        state.election = election_deadline();
+    }
+
+    /// Becomes follower, moving to a later term if given
+    fn step_down(&self, state: &mut State, term: u64) {
+        if term > state.term {
+            state.term = term;
+            state.voted_for = None;
+            state.leader = None;
+            self.save_vote(state);
+        }
+        state.role = Role::Follower;
+        state.votes.clear();
+        state.progress.clear();
+        self.changed.notify_all();
+    }
+
+    fn save_vote(&self, state: &State) {
+        let vote = Vote {
+            term: state.term,
+            voted_for: state.voted_for,
+        };
+        let saved = serde_json::to_string(&vote)
+            .map_err(io::Error::from)
+            .and_then(|contents| replace(&self.dir.join(STATE), &contents));
+        if let Err(e) = saved {
+            warn!("Failed to save Raft state: {e}");
+        }
+    }
+
+    /// Starts an election if no leader was heard from in time
+    fn tick(&self, store: &KvStore) {
+        let mut state = self.lock();
+        if state.role == Role::Leader || Instant::now() < state.election {
+            return;
+        }
+        state.election = election_deadline();
+        let members = state.log.members();
+        if !members.contains(&self.id) {
+            return;
+        }
+
+        state.term += 1;
+        state.role = Role::Candidate;
+        state.leader = None;
+        state.voted_for = Some(self.id);
+        state.votes = HashSet::from([self.id]);
+        self.save_vote(&state);
+        info!(term = state.term, "Starting election");
+        if members.len() == 1 {
+            self.become_leader(store, &mut state);
+            return;
+        }
+
+        let term = state.term;
+        let request = Message::RequestVote {
+            term,
+            candidate: self.id,
+            last_index: state.log.last_index(),
+            last_term: state.log.last_term(),
+        };
+        drop(state);
+        for member in members.into_iter().filter(|&m| m != self.id) {
+            let store = store.clone();
+            let request = request.clone();
+            thread::spawn(move || {
+                let Some(node) = store.cluster() else {
+                    return;
+                };
+                match rpc(&mut None, member, request) {
+                    Ok(reply) => node.voted(&store, member, term, &reply),
+                    Err(e) => debug!(%member, "Vote request failed: {e}"),
+                }
+            });
+        }
+    }
+
+    /// Counts the vote of a member in the election of term
+    fn voted(&self, store: &KvStore, member: SocketAddr, election: u64, reply: &Message) {
+        let &Message::Vote { term, granted } = reply else {
+            return;
+        };
+        let mut state = self.lock();
+        if term > state.term {
+            self.step_down(&mut state, term);
+            return;
+        }
+        if state.role != Role::Candidate || state.term != election || !granted {
+            return;
+        }
+
+        state.votes.insert(member);
+        let members = state.log.members();
+        let votes = state.votes.iter().filter(|v| members.contains(v)).count();
+        if votes * 2 > members.len() {
+            self.become_leader(store, &mut state);
+        }
+    }
+
+    fn become_leader(&self, store: &KvStore, state: &mut State) {
+        info!(term = state.term, "Elected cluster leader");
+        state.role = Role::Leader;
+        state.leader = Some(self.id);
+        state.progress.clear();
+
+        let entry = Entry {
+            term: state.term,
+            op: Op::Noop,
+        };
+        if let Err(e) = state.log.append(vec![entry]) {
+            warn!("Failed to append to Raft log: {e}");
+        }
+        self.advance_commit(store, state);
+        self.changed.notify_all();
+    }
+
+    /// Returns the message to send a member next, if leader
+    fn message_for(&self, store: &KvStore, member: SocketAddr) -> Option<Message> {
+        let mut state = self.lock();
+        if state.role != Role::Leader {
+            return None;
+        }
+        let next = state.log.last_index() + 1;
+        let progress = *state
+            .progress
+            .entry(member)
+            .or_insert(Progress { next, matched: 0 });
+
+        let prev_index = progress.next - 1;
+        if let Some(prev_term) = state.log.term_at(prev_index) {
+            return Some(Message::AppendEntries {
+                term: state.term,
+                leader: self.id,
+                prev_index,
+                prev_term,
+                entries: state.log.entries_from(progress.next),
+                commit: state.commit,
+            });
+        }
+
+        // The entries the member needs were compacted away
+        let term = state.term;
+        let last_index = state.applied;
+        let last_term = state.log.term_at(last_index)?;
+        let members = state.log.members_at(last_index);
+        drop(state);
+        let Ok(Shipment::Snapshot(records, _)) = store.inner.wal.subscribe().recv() else {
+            warn!("Failed to read snapshot of the store");
+            return None;
+        };
+        Some(Message::InstallSnapshot {
+            term,
+            leader: self.id,
+            last_index,
+            last_term,
+            members,
+            records,
+        })
+    }
+
+    /// Updates the progress of a member from its answer, returning whether entries are left to
+    /// send it
+    fn replied(&self, store: &KvStore, member: SocketAddr, reply: &Message) -> bool {
+        let &Message::Appended {
+            term,
+            success,
+            last_index,
+        } = reply
+        else {
+            return false;
+        };
+        let mut state = self.lock();
+        if term > state.term {
+            self.step_down(&mut state, term);
+            return false;
+        }
+        if state.role != Role::Leader {
+            return false;
+        }
+
+        let Some(progress) = state.progress.get_mut(&member) else {
+            return false;
+        };
+        if success {
+            progress.matched = progress.matched.max(last_index);
+            progress.next = progress.matched + 1;
+        } else {
+            progress.next = (progress.next - 1).min(last_index + 1).max(1);
+        }
+        let next = progress.next;
+        if success {
+            self.advance_commit(store, &mut state);
+        }
+        next <= state.log.last_index()
+    }
+
+    /// Commits the entries of the current term held by a majority, then applies them
+    fn advance_commit(&self, store: &KvStore, state: &mut State) {
+        let members = state.log.members();
+        let held = |index: u64| {
+            members
+                .iter()
+                .filter(|&&m| {
+                    m == self.id || state.progress.get(&m).is_some_and(|p| p.matched >= index)
+                })
+                .count()
+        };
+        let commit = (state.commit + 1..=state.log.last_index())
+            .rev()
+            .take_while(|&index| state.log.term_at(index) == Some(state.term))
+            .find(|&index| held(index) * 2 > members.len());
+
+        if let Some(commit) = commit {
+            state.commit = commit;
+            self.apply(store, state);
+        }
+    }
+
+    /// Applies the committed entries to the store, then compacts the log if due
+    fn apply(&self, store: &KvStore, state: &mut State) {
+        while state.applied < state.commit {
+            let index = state.applied + 1;
+            let Some(entry) = state.log.entry(index) else {
+                break;
+            };
+            // Committed entries are applied even while writes are frozen or over budget
+            let outcome = freeze::applying(|| {
+                memory::exempt(|| match entry.op.clone() {
+                    Op::Set { key, value } => store.set(key, value),
+                    Op::Rm { key } => store.remove(key).map(Some),
+                    Op::AddNode(_) | Op::Noop => Ok(None),
+                })
+            });
+            state.applied = index;
+
+            match state.proposals.get_mut(&index) {
+                Some(proposal) => *proposal = Some(outcome),
+                // Entries applied before a restart are applied again
+                None => {
+                    if let Err(e) = outcome {
+                        debug!(index, "Replicated entry failed: {e}");
+                    }
+                }
+            }
+        }
+
+        if state.applied - state.log.base.index >= COMPACT_THRESHOLD {
+            let applied = state.applied;
+            if let Err(e) = state.log.compact(applied) {
+                warn!("Failed to compact Raft log: {e}");
+            }
+        }
+        self.changed.notify_all();
+    }
+}
+
+/// Runs the cluster member of store until shut down
+///
+/// Runs on a new thread, which replicates the log to each other member on threads of its own
+/// while leader, and starts elections when no leader is heard from.
+///
+/// # Errors
+/// Returns [`KvStoreError::NotClusterMember`] if the store was not opened with
+/// [`OpenOptions::cluster`](crate::OpenOptions::cluster), or `Err` if the thread cannot be
+/// spawned
+pub fn run(store: &KvStore, shutdown: &Shutdown) -> Result<JoinHandle<()>> {
+    let node = store.cluster().ok_or(KvStoreError::NotClusterMember)?;
+    info!(node = %node.id, members = ?node.members(), "Running cluster member");
+
+    let store = store.clone();
+    let shutdown = shutdown.clone();
+    thread::Builder::new()
+        .name("kvs-raft".to_owned())
+        .spawn(move || {
+            let Some(node) = store.cluster() else {
+                return;
+            };
+            let mut peers = HashMap::new();
+            while !shutdown.is_triggered() {
+                for member in node.members() {
+                    if member == node.id || peers.contains_key(&member) {
+                        continue;
+                    }
+                    let (store, shutdown) = (store.clone(), shutdown.clone());
+                    match thread::Builder::new()
+                        .name(format!("kvs-raft-{member}"))
+                        .spawn(move || replicate(&store, member, &shutdown))
+                    {
+                        Ok(peer) => {
+                            peers.insert(member, peer);
+                        }
+                        Err(e) => warn!(%member, "Failed to start replicating: {e}"),
+                    }
+                }
+
+                node.tick(&store);
+                node.wait(HEARTBEAT);
+            }
+
+            node.changed.notify_all();
+            for peer in peers.into_values() {
+                let _ = peer.join();
+            }
+        })
+        .map_err(KvStoreError::FailedRaft)
+}
+
+/// Sends the log to a member whenever leader, until shut down
+fn replicate(store: &KvStore, member: SocketAddr, shutdown: &Shutdown) {
+    let Some(node) = store.cluster() else {
+        return;
+    };
+    let mut connection = None;
+    while !shutdown.is_triggered() {
+        if let Some(message) = node.message_for(store, member) {
+            match rpc(&mut connection, member, message) {
+                Ok(reply) if node.replied(store, member, &reply) => continue,
+                Ok(_) => {}
+                Err(e) => debug!(%member, "Raft message failed: {e}"),
+            }
+        }
+        node.wait(HEARTBEAT);
+    }
+}
+
+/// Buffered connection to a member
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl Connection {
+    fn open(member: SocketAddr) -> io::Result<Self> {
+        let stream = TcpStream::connect_timeout(&member, TIMEOUT)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    fn exchange(&mut self, request: &Request) -> io::Result<Response> {
+        write_frame(
+            &mut self.writer,
+            &Envelope {
+                id: 0,
+                body: request,
+            },
+        )?;
+        self.writer.flush()?;
+        let response: Envelope<Response> = read_frame(&mut self.reader)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        Ok(response.body)
+    }
+}
+
+/// Sends a message to a member and returns its answer, reusing or opening the connection
+///
+/// The connection is dropped if the exchange fails.
+fn rpc(
+    connection: &mut Option<Connection>,
+    member: SocketAddr,
+    message: Message,
+) -> io::Result<Message> {
+    let mut open = connection
+        .take()
+        .map_or_else(|| Connection::open(member), Ok)?;
+    let response = open.exchange(&Request::Raft(message))?;
+    *connection = Some(open);
+
+    match response {
+        Response::Raft(reply) => Ok(reply),
+        Response::Err(e) => Err(io::Error::other(e)),
+        response => Err(io::Error::other(format!(
+            "Unexpected response: {response:?}"
+        ))),
+    }
+}
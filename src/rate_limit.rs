@@ -0,0 +1,77 @@
+//! Token bucket limiting the rate of requests to a server, or of bytes copied by compactions
+
+use std::{
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Allows a number of requests, or bytes, per second on average, in bursts of up to as many
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    per_sec: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Requests allowed right away
+    tokens: f64,
+    /// When tokens was last refilled
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    /// Returns a limiter allowing `per_sec` requests per second, starting with a full bucket
+    pub(crate) fn new(per_sec: u32) -> Self {
+        Self::with_rate(f64::from(per_sec))
+    }
+
+    /// Returns a limiter allowing `per_sec` bytes per second, starting with a full bucket
+    pub(crate) fn for_bytes(per_sec: u64) -> Self {
+        #[allow(clippy::cast_precision_loss)] // Rates far below 2^52
+        Self::with_rate(per_sec as f64)
+    }
+
+    fn with_rate(per_sec: f64) -> Self {
+        Self {
+            per_sec,
+            bucket: Mutex::new(Bucket {
+                tokens: per_sec,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token for a request, or returns `false` if there is none left
+    pub(crate) fn try_acquire(&self) -> bool {
+        let mut bucket = self.refill();
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Takes tokens for bytes already copied, going into debt if there are too few, and returns
+    /// how long to wait until the debt is paid off
+    pub(crate) fn charge(&self, bytes: u64) -> Duration {
+        let mut bucket = self.refill();
+        #[allow(clippy::cast_precision_loss)] // Byte counts far below 2^52
+        let bytes = bytes as f64;
+        bucket.tokens -= bytes;
+        if bucket.tokens >= 0.0 || self.per_sec <= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-bucket.tokens / self.per_sec)
+    }
+
+    /// Adds the tokens due since the last refill, up to a full bucket
+    fn refill(&self) -> MutexGuard<'_, Bucket> {
+        let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_sec).min(self.per_sec);
+        bucket.refilled = now;
+        bucket
+    }
+}
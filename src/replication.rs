@@ -0,0 +1,335 @@
+//! Primary/replica replication by shipping WAL records
+//!
+//! A primary connects to the TCP [`server`](crate::server) of each replica and sends a
+//! [`Request::Replicate`], then streams its live records followed by every record it commits
+//! (see [`Replication`]). Replicas apply the records like WAL replay, logging them in turn, and
+//! reject writes of clients until [promoted](KvStore::promote).
+//!
+//! Replication is asynchronous: a write returns once logged on the primary, before replicas
+//! apply it. A primary losing a replica reconnects, sending its live records again; keys the
+//! replica holds beyond them are removed.
+
+use crate::{
+    protocol::{
+        read_frame, write_frame, Envelope, Replication, Request, Response, SHIPMENT_BUDGET,
+    },
+    server::Shutdown,
+    wal::{self, Shipment},
+    Command, KvStore, KvStoreError, Result,
+};
+use std::{
+    collections::HashSet,
+    io::{self, prelude::*, BufReader, BufWriter},
+    net::{SocketAddr, TcpStream},
+    sync::{mpsc::RecvTimeoutError, Condvar, Mutex, MutexGuard, PoisonError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+
+/// Connect, read, and write timeout of connections to replicas
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Delay before reconnecting to a lost replica
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Interval at which an idle replication stream checks for shutdown
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Interval at which an idle replication stream sends a heartbeat, so that replicas can tell
+/// an idle primary from a lost one
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keys set by the live records of a primary
+#[derive(Debug, Default)]
+struct Keys {
+    /// Keys per bucket, or `None` for the default key space
+    values: HashSet<(Option<String>, String)>,
+    /// Lists, emptied before the first of their values is applied
+    lists: HashSet<String>,
+    /// Sets, emptied before the first of their members is applied
+    sets: HashSet<String>,
+    /// Locks held with a lease
+    locks: HashSet<String>,
+}
+
+/// Sequence number on the primary of the last record a replica applied
+#[derive(Debug, Default)]
+pub(crate) struct Applied {
+    sequence: Mutex<u64>,
+    advanced: Condvar,
+    /// When the last message from the primary was received, if any
+    heard: Mutex<Option<Instant>>,
+}
+
+impl Applied {
+    fn lock(&self) -> MutexGuard<'_, u64> {
+        self.sequence.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the sequence number of the last record applied
+    pub(crate) fn get(&self) -> u64 {
+        *self.lock()
+    }
+
+    /// Records that the records up to sequence were applied
+    ///
+    /// The sequence number only goes back if the primary lost logged records.
+    fn advance(&self, sequence: u64) {
+        *self.lock() = sequence;
+        self.advanced.notify_all();
+    }
+
+    /// Records that a message from the primary was received
+    fn hear(&self) {
+        *self.heard.lock().unwrap_or_else(PoisonError::into_inner) = Some(Instant::now());
+    }
+
+    /// Returns the time since the last message from the primary, if any was received
+    pub(crate) fn silence(&self) -> Option<Duration> {
+        self.heard
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .map(|heard| heard.elapsed())
+    }
+
+    /// Waits up to timeout for the record with sequence to be applied, returning whether it was
+    pub(crate) fn wait_for(&self, sequence: u64, timeout: Duration) -> bool {
+        let (applied, _) = self
+            .advanced
+            .wait_timeout_while(self.lock(), timeout, |applied| *applied < sequence)
+            .unwrap_or_else(PoisonError::into_inner);
+        *applied >= sequence
+    }
+}
+
+/// Ships the records of store to the replica serving at address, until shut down
+///
+/// Runs on a new thread, reconnecting whenever the replica is lost.
+///
+/// # Errors
+/// Returns `Err` if the thread cannot be spawned
+pub fn replicate_to(
+    store: &KvStore,
+    replica: SocketAddr,
+    shutdown: &Shutdown,
+) -> Result<JoinHandle<()>> {
+    let store = store.clone();
+    let shutdown = shutdown.clone();
+    thread::Builder::new()
+        .name(format!("kvs-replicate-{replica}"))
+        .spawn(move || {
+            while !shutdown.is_triggered() {
+                match ship(&store, replica, &shutdown) {
+                    Ok(()) => break,
+                    Err(e) => {
+                        warn!(%replica, "Replication failed, retrying: {e}");
+                        thread::sleep(RETRY_INTERVAL);
+                    }
+                }
+            }
+        })
+        .map_err(KvStoreError::FailedReplication)
+}
+
+/// Streams records to a replica until shut down
+fn ship(store: &KvStore, replica: SocketAddr, shutdown: &Shutdown) -> io::Result<()> {
+    let stream = TcpStream::connect_timeout(&replica, TIMEOUT)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    send(
+        &mut writer,
+        &Envelope {
+            id: 0,
+            body: Request::Replicate,
+        },
+    )?;
+    let response: Option<Envelope<Response>> = read_frame(&mut reader)?;
+    match response.map(|envelope| envelope.body) {
+        None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        Some(Response::Ok(_)) => info!(%replica, "Replicating"),
+        Some(Response::Err(e)) => return Err(io::Error::other(e)),
+        Some(response) => {
+            return Err(io::Error::other(format!(
+                "Unexpected response: {response:?}"
+            )))
+        }
+    }
+
+    let shipments = store.inner.wal.subscribe();
+    let mut sent = Instant::now();
+    loop {
+        match shipments.recv_timeout(POLL_INTERVAL) {
+            Ok(shipment) => {
+                let synced = matches!(shipment, Shipment::Snapshot(..));
+                for part in shipment.split(SHIPMENT_BUDGET) {
+                    let (Shipment::Snapshot(records, sequence)
+                    | Shipment::Committed(records, sequence)) = part;
+                    send(&mut writer, &Replication::Records { records, sequence })?;
+                }
+                if synced {
+                    send(&mut writer, &Replication::Synced)?;
+                }
+                sent = Instant::now();
+            }
+            Err(RecvTimeoutError::Timeout) if shutdown.is_triggered() => return Ok(()),
+            Err(RecvTimeoutError::Timeout) if sent.elapsed() >= HEARTBEAT_INTERVAL => {
+                send(&mut writer, &Replication::Heartbeat)?;
+                sent = Instant::now();
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(io::Error::other("WAL writer stopped"));
+            }
+        }
+    }
+}
+
+/// Writes a message as a frame
+fn send(writer: &mut impl Write, message: &impl serde::Serialize) -> io::Result<()> {
+    write_frame(writer, message)?;
+    writer.flush()
+}
+
+/// Applies the replication messages read from a primary to store, until the primary
+/// disconnects or the store is promoted
+pub(crate) fn follow(store: &KvStore, mut reader: impl Read) -> io::Result<()> {
+    // Keys set by the live records of the primary, until all were received
+    let mut snapshot = Some(Keys::default());
+    while let Some(message) = read_frame(&mut reader)? {
+        if store.replica_of().is_none() {
+            break;
+        }
+        store.inner.applied.hear();
+
+        match message {
+            Replication::Records { records, sequence } => {
+                for record in records.lines() {
+                    apply(store, record, snapshot.as_mut()).map_err(io::Error::other)?;
+                }
+                store.inner.applied.advance(sequence);
+            }
+            Replication::Synced => {
+                if let Some(keys) = snapshot.take() {
+                    prune(store, &keys).map_err(io::Error::other)?;
+                    info!("Replica synced with primary");
+                }
+            }
+            Replication::Heartbeat => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces the contents of store by the live records of another store
+#[cfg(feature = "raft")]
+pub(crate) fn restore(store: &KvStore, records: &str) -> Result<()> {
+    let mut keys = Keys::default();
+    for record in records.lines() {
+        apply(store, record, Some(&mut keys))?;
+    }
+    prune(store, &keys)
+}
+
+/// Applies a record, adding the key it sets to snapshot keys if given
+fn apply(store: &KvStore, record: &str, snapshot: Option<&mut Keys>) -> Result<()> {
+    let cmd = wal::parse(record)?;
+    if let Some(keys) = snapshot {
+        match &cmd {
+            Command::Set { key, .. }
+            | Command::VersionedSet { key, .. }
+            | Command::ExpiringSet { key, .. }
+            | Command::Renamed { to: key, .. } => {
+                keys.values.insert((None, key.clone()));
+            }
+            Command::BucketSet { bucket, key, .. } => {
+                keys.values.insert((Some(bucket.clone()), key.clone()));
+            }
+            // Values and members applied before are sent again with the live records
+            Command::Lpush { key, .. } if keys.lists.insert(key.clone()) => {
+                empty_list(store, key)?;
+            }
+            Command::Sadd { key, .. } if keys.sets.insert(key.clone()) => {
+                empty_set(store, key)?;
+            }
+            Command::Leased { key, .. } => {
+                keys.locks.insert(key.clone());
+            }
+            _ => {}
+        }
+    }
+
+    // Samples of an earlier stream are sent again with the live records
+    store.replay(cmd).map(drop)
+}
+
+/// Pops every value of the list under key
+fn empty_list(store: &KvStore, key: &str) -> Result<()> {
+    while store.rpop(key)?.is_some() {}
+    Ok(())
+}
+
+/// Removes every member of the set under key
+fn empty_set(store: &KvStore, key: &str) -> Result<()> {
+    for member in store.smembers(key) {
+        store.srem(key, &member)?;
+    }
+    Ok(())
+}
+
+/// Removes the keys of store missing from the live records of the primary, empties its lists
+/// and sets missing from them, and releases its locks missing from them
+fn prune(store: &KvStore, keys: &Keys) -> Result<()> {
+    for (key, _) in store.scan("")? {
+        if !keys.values.contains(&(None, key.clone())) {
+            debug!(key, "Removing key missing from primary");
+            store.remove(key)?;
+        }
+    }
+    for name in store.inner.wal.bucket_names() {
+        let bucket = store.bucket(name.clone());
+        for (key, _) in bucket.scan("")? {
+            if !keys.values.contains(&(Some(name.clone()), key.clone())) {
+                debug!(bucket = name, key, "Removing key missing from primary");
+                bucket.remove(key)?;
+            }
+        }
+    }
+    let lists: Vec<String> = store
+        .inner
+        .lists
+        .iter()
+        .map(|list| list.key().clone())
+        .collect();
+    for key in lists.iter().filter(|key| !keys.lists.contains(*key)) {
+        debug!(key, "Emptying list missing from primary");
+        empty_list(store, key)?;
+    }
+    let sets: Vec<String> = store
+        .inner
+        .sets
+        .iter()
+        .map(|set| set.key().clone())
+        .collect();
+    for key in sets.iter().filter(|key| !keys.sets.contains(*key)) {
+        debug!(key, "Emptying set missing from primary");
+        empty_set(store, key)?;
+    }
+    let leases: Vec<(String, u64)> = store
+        .inner
+        .leases
+        .iter()
+        .map(|lease| (lease.key().clone(), lease.token))
+        .collect();
+    for (key, token) in leases.iter().filter(|(key, _)| !keys.locks.contains(key)) {
+        debug!(key, "Releasing lock missing from primary");
+        store.unlock(key, *token)?;
+    }
+
+    Ok(())
+}
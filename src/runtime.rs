@@ -0,0 +1,228 @@
+//! Runtime sharing background resources across KV stores of one process
+
+use crate::{
+    cache::ValueCache,
+    rate_limit::RateLimiter,
+    thread_pool::{SharedQueueThreadPool, ThreadPool},
+    wal::Wal,
+    CacheConfig, KvStoreError, Result,
+};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, Weak,
+    },
+    thread,
+    time::Duration,
+};
+use tracing::error;
+
+/// Default interval between background ticks
+const TICK: Duration = Duration::from_millis(100);
+
+/// Default number of threads running the compactions of the stores of a runtime
+const COMPACTION_THREADS: usize = 2;
+
+/// Background resources shared by all KV stores opened with it
+///
+/// Stores opened with a runtime via [`OpenOptions::runtime`](crate::OpenOptions::runtime)
+/// share, instead of each having their own:
+/// - a background thread running periodic work at each tick, e.g. logging coalesced writes
+///   once their window elapses, so that idle stores do not each wake up;
+/// - a thread pool running their compactions, rather than a thread per compaction;
+/// - a value cache, if set, for stores opened without a cache of their own;
+/// - a limit on the bytes per second copied by their compactions together, if set.
+///
+/// Each store keeps a WAL writer thread of its own, as it owns the active segment and orders
+/// the writes of the store.
+///
+/// Cheap to clone; the background threads exit once the runtime and every store opened with
+/// it are dropped.
+#[derive(Clone, Debug)]
+pub struct KvsRuntime {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    wals: Mutex<Vec<Weak<Wal>>>,
+    compactions: SharedQueueThreadPool,
+    cache: Option<ValueCache>,
+    compaction_throttle: Option<Arc<RateLimiter>>,
+    /// ID of the next store drawing from the cache
+    next_store: AtomicU64,
+}
+
+impl fmt::Debug for Inner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Inner")
+            .field("stores", &self.wals().len())
+            .field("cache", &self.cache.is_some())
+            .field("compaction_throttle", &self.compaction_throttle.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Options and flags to configure the resources of a runtime
+///
+/// Chain setters on [`RuntimeOptions::new`], then call [`RuntimeOptions::start`].
+#[derive(Clone, Debug)]
+pub struct RuntimeOptions {
+    tick: Duration,
+    compaction_threads: usize,
+    cache: Option<CacheConfig>,
+    compaction_throttle: Option<u64>,
+}
+
+impl Default for RuntimeOptions {
+    fn default() -> Self {
+        Self {
+            tick: TICK,
+            compaction_threads: COMPACTION_THREADS,
+            cache: None,
+            compaction_throttle: None,
+        }
+    }
+}
+
+impl RuntimeOptions {
+    /// Returns default options
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the interval at which the background thread wakes up, default 100 ms
+    pub fn tick(&mut self, interval: Duration) -> &mut Self {
+        self.tick = interval;
+        self
+    }
+
+    /// Sets the number of threads running the compactions of all stores, default 2
+    ///
+    /// Compactions beyond it wait for one to finish.
+    pub fn compaction_threads(&mut self, threads: usize) -> &mut Self {
+        self.compaction_threads = threads;
+        self
+    }
+
+    /// Enables an LRU cache of values read from disk in offset-index mode, shared by the
+    /// stores opened without a cache of their own, default disabled
+    ///
+    /// See [`OpenOptions::cache`](crate::OpenOptions::cache).
+    pub fn cache(&mut self, config: CacheConfig) -> &mut Self {
+        self.cache = Some(config);
+        self
+    }
+
+    /// Limits the bytes per second copied by the compactions of all stores together, default
+    /// unlimited
+    ///
+    /// Applies on top of the limit of each store, see
+    /// [`OpenOptions::compaction_throttle`](crate::OpenOptions::compaction_throttle).
+    pub fn compaction_throttle(&mut self, bytes_per_sec: u64) -> &mut Self {
+        self.compaction_throttle = Some(bytes_per_sec);
+        self
+    }
+
+    /// Starts a runtime with these options
+    ///
+    /// # Errors
+    /// Returns `Err` if the background threads cannot be spawned
+    pub fn start(&self) -> Result<KvsRuntime> {
+        let inner = Arc::new(Inner {
+            wals: Mutex::default(),
+            compactions: SharedQueueThreadPool::new(self.compaction_threads)?,
+            cache: self.cache.map(ValueCache::new),
+            compaction_throttle: self
+                .compaction_throttle
+                .map(|per_sec| Arc::new(RateLimiter::for_bytes(per_sec))),
+            next_store: AtomicU64::new(1),
+        });
+
+        let weak = Arc::downgrade(&inner);
+        let interval = self.tick;
+        thread::Builder::new()
+            .name("kvs-runtime".to_owned())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                match weak.upgrade() {
+                    Some(inner) => inner.tick(),
+                    None => break,
+                }
+            })
+            .map_err(KvStoreError::FailedRuntimeStart)?;
+
+        Ok(KvsRuntime { inner })
+    }
+}
+
+impl KvsRuntime {
+    /// Starts a runtime with default options
+    ///
+    /// # Errors
+    /// Returns `Err` if the background threads cannot be spawned
+    pub fn new() -> Result<Self> {
+        RuntimeOptions::new().start()
+    }
+
+    /// Starts a runtime whose background thread wakes up at the given interval
+    ///
+    /// # Errors
+    /// Returns `Err` if the background threads cannot be spawned
+    pub fn with_tick(interval: Duration) -> Result<Self> {
+        RuntimeOptions::new().tick(interval).start()
+    }
+
+    /// Returns number of open stores using the runtime
+    #[must_use]
+    pub fn store_count(&self) -> usize {
+        self.inner
+            .wals()
+            .iter()
+            .filter(|w| w.strong_count() > 0)
+            .count()
+    }
+
+    pub(crate) fn register(&self, wal: &Arc<Wal>) {
+        self.inner.wals().push(Arc::downgrade(wal));
+    }
+
+    /// Returns a view of the shared cache for a newly opened store, if the runtime has one
+    pub(crate) fn cache(&self) -> Option<ValueCache> {
+        let cache = self.inner.cache.as_ref()?;
+        Some(cache.view(self.inner.next_store.fetch_add(1, Ordering::Relaxed)))
+    }
+
+    /// Returns the limiter of the bytes copied by the compactions of all stores, if any
+    pub(crate) fn compaction_throttle(&self) -> Option<Arc<RateLimiter>> {
+        self.inner.compaction_throttle.clone()
+    }
+
+    /// Runs compaction work on the shared thread pool, i.e. copying records or zeroing
+    /// compacted segment files for recycling
+    pub(crate) fn compact(&self, job: impl FnOnce() + Send + 'static) {
+        self.inner.compactions.spawn(job);
+    }
+}
+
+impl Inner {
+    fn wals(&self) -> MutexGuard<'_, Vec<Weak<Wal>>> {
+        self.wals.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Runs periodic work for every live store, forgetting dropped ones
+    fn tick(&self) {
+        let wals: Vec<_> = {
+            let mut wals = self.wals();
+            wals.retain(|w| w.strong_count() > 0);
+            wals.iter().filter_map(Weak::upgrade).collect()
+        };
+
+        for wal in wals {
+            if let Err(e) = wal.flush_expired() {
+                error!("Failed to write coalesced writes to WAL: {e}");
+            }
+        }
+    }
+}
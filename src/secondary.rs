@@ -0,0 +1,216 @@
+//! Secondary indexes mapping a field of the JSON values of the default key space to the keys
+//! holding each value of the field
+//!
+//! The field of an index is given by a path into values, either a JSON pointer such as
+//! `/address/city` or the equivalent `$.address.city`. String fields are indexed as is, numbers
+//! and booleans by their JSON text; keys whose value is not JSON, lacks the field, or holds
+//! `null`, an array or an object there are left out of the index.
+//!
+//! Indexes are declared per store directory, in a file listing their paths, and built from the
+//! values of the store on open. Buckets are not indexed, and declarations are not replicated.
+
+use crate::{document, KvStoreError, Result};
+use clap::Subcommand;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+    sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+/// File declaring the indexes of a store, a JSON object of their paths by name
+const INDEXES: &str = "INDEXES";
+
+/// Commands managing the secondary indexes of a store
+#[derive(Clone, Debug, Default, PartialEq, Subcommand)]
+pub enum IndexCommand {
+    /// Declare an index on a field of JSON values, built from the current values
+    Create {
+        /// Index name
+        name: String,
+        /// Path of the field, a JSON pointer such as `/email` or `$.email`
+        path: String,
+    },
+    /// Drop an index
+    Drop {
+        /// Index name
+        name: String,
+    },
+    /// Print the name and path of each index
+    #[default]
+    List,
+}
+
+/// Thread-safe secondary indexes of a store, by name
+#[derive(Debug, Default)]
+pub(crate) struct Indexes {
+    inner: RwLock<BTreeMap<String, Index>>,
+}
+
+/// Index of the keys holding each value of a field
+#[derive(Debug)]
+pub(crate) struct Index {
+    /// Path as declared
+    path: String,
+    /// JSON pointer to the field
+    pointer: String,
+    /// Keys by field value
+    keys: BTreeMap<String, BTreeSet<String>>,
+    /// Field value by key
+    fields: HashMap<String, String>,
+}
+
+impl Index {
+    /// Returns an empty index on the field at path
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::InvalidIndex`] if path is neither a JSON pointer nor a `$.` path
+    pub(crate) fn new(path: &str) -> Result<Self> {
+        let pointer = document::pointer(path).ok_or_else(|| {
+            KvStoreError::InvalidIndex(format!("{path} is neither a JSON pointer nor a `$.` path"))
+        })?;
+        Ok(Self {
+            path: path.to_owned(),
+            pointer,
+            keys: BTreeMap::new(),
+            fields: HashMap::new(),
+        })
+    }
+
+    /// Indexes key under the field of value, if any
+    pub(crate) fn insert(&mut self, key: &str, value: &Value) {
+        let field = match value.pointer(&self.pointer) {
+            Some(Value::String(field)) => field.clone(),
+            Some(field @ (Value::Number(_) | Value::Bool(_))) => field.to_string(),
+            _ => return,
+        };
+        self.keys
+            .entry(field.clone())
+            .or_default()
+            .insert(key.to_owned());
+        self.fields.insert(key.to_owned(), field);
+    }
+
+    fn remove(&mut self, key: &str) {
+        let Some(field) = self.fields.remove(key) else {
+            return;
+        };
+        if let Some(keys) = self.keys.get_mut(&field) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.keys.remove(&field);
+            }
+        }
+    }
+}
+
+impl Indexes {
+    fn read(&self) -> RwLockReadGuard<'_, BTreeMap<String, Index>> {
+        self.inner.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, BTreeMap<String, Index>> {
+        self.inner.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns whether any index is declared, so that writes need indexing
+    pub(crate) fn active(&self) -> bool {
+        !self.read().is_empty()
+    }
+
+    /// Reindexes key, now holding value if `Some`
+    pub(crate) fn set(&self, key: &str, value: Option<&str>) {
+        let Some(value) = value else {
+            return;
+        };
+        let parsed = serde_json::from_str::<Value>(value).ok();
+        for index in self.write().values_mut() {
+            index.remove(key);
+            if let Some(parsed) = &parsed {
+                index.insert(key, parsed);
+            }
+        }
+    }
+
+    /// Unindexes key, removed from the store
+    pub(crate) fn remove(&self, key: &str) {
+        if !self.active() {
+            return;
+        }
+        for index in self.write().values_mut() {
+            index.remove(key);
+        }
+    }
+
+    /// Adds index under name, replacing any index of that name
+    pub(crate) fn insert(&self, name: String, index: Index) {
+        self.write().insert(name, index);
+    }
+
+    /// Drops the index of name, returning whether it existed
+    pub(crate) fn drop(&self, name: &str) -> bool {
+        self.write().remove(name).is_some()
+    }
+
+    /// Returns the keys whose field indexed by the index of name holds field, sorted
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::IndexNotFound`] if there is no index of that name
+    pub(crate) fn query(&self, name: &str, field: &str) -> Result<Vec<String>> {
+        let indexes = self.read();
+        let index = indexes
+            .get(name)
+            .ok_or_else(|| KvStoreError::IndexNotFound(name.to_owned()))?;
+        Ok(index
+            .keys
+            .get(field)
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    /// Returns the name and path of each index, sorted by name
+    pub(crate) fn list(&self) -> Vec<(String, String)> {
+        self.read()
+            .iter()
+            .map(|(name, index)| (name.clone(), index.path.clone()))
+            .collect()
+    }
+
+    /// Writes the declarations of the indexes to the store in dir, replacing the previous ones
+    ///
+    /// # Errors
+    /// Returns [`KvStoreError::FailedIndexes`] if the file cannot be written
+    pub(crate) fn save(&self, dir: &Path) -> Result<()> {
+        let paths: BTreeMap<String, String> = self.list().into_iter().collect();
+        // Maps of strings always serialize
+        let contents = serde_json::to_string_pretty(&paths).unwrap_or_default();
+        replace(&dir.join(INDEXES), &contents).map_err(KvStoreError::FailedIndexes)
+    }
+}
+
+/// Returns the name and path of each index declared for the store in dir
+///
+/// # Errors
+/// Returns [`KvStoreError::FailedIndexes`] if the file of declarations cannot be read, or
+/// [`KvStoreError::InvalidIndex`] if it does not parse
+pub(crate) fn declared(dir: &Path) -> Result<Vec<(String, String)>> {
+    let contents = match fs::read_to_string(dir.join(INDEXES)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(KvStoreError::FailedIndexes(e)),
+    };
+    let paths: BTreeMap<String, String> = serde_json::from_str(&contents)
+        .map_err(|e| KvStoreError::InvalidIndex(format!("{INDEXES}: {e}")))?;
+    Ok(paths.into_iter().collect())
+}
+
+/// Replaces the file at path atomically, so an interrupted write leaves the previous one
+fn replace(path: &Path, contents: &str) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
@@ -0,0 +1,504 @@
+//! Log segments: the files the WAL is split into, and positional reads from them
+
+use crate::{bloom::Bloom, WAL};
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+#[cfg(feature = "mmap")]
+use tracing::warn;
+
+/// Prefix of the names of segment files zeroed for recycling, followed by their former ID
+const FREE_PREFIX: &str = "wa.free.";
+
+/// Magic starting the header of base segments
+const BASE_MAGIC: &str = "kvs-base";
+
+/// Format version of base segments written by this version
+const BASE_VERSION: u32 = 1;
+
+/// Location of a record in a segment
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Extent {
+    pub(crate) segment: u64,
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+}
+
+/// Segment file found on disk
+#[derive(Debug)]
+pub(crate) struct SegmentFile {
+    pub(crate) id: u64,
+    pub(crate) path: PathBuf,
+    /// Whether the segment was written by compaction, holding all live records of the segments
+    /// before it
+    pub(crate) base: bool,
+}
+
+/// Directories holding the files of a store, see
+/// [`OpenOptions::wal_dir`](crate::OpenOptions::wal_dir)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Dirs {
+    /// Store directory, holding the manifest and the lock file
+    pub(crate) store: PathBuf,
+    /// Directory of the active and sealed segments
+    pub(crate) wal: PathBuf,
+    /// Directory of base segments and their hint files
+    pub(crate) data: PathBuf,
+}
+
+impl Dirs {
+    /// Returns the path of the active segment
+    pub(crate) fn active_path(&self) -> PathBuf {
+        self.wal.join(WAL)
+    }
+
+    /// Returns the path base segments are written to by compaction before taking their name
+    pub(crate) fn compact_path(&self) -> PathBuf {
+        with_suffix(&self.data.join(WAL), ".compact")
+    }
+
+    pub(crate) fn sealed_path(&self, id: u64) -> PathBuf {
+        sealed_path(&self.wal, id)
+    }
+
+    pub(crate) fn base_path(&self, id: u64) -> PathBuf {
+        base_path(&self.data, id)
+    }
+
+    pub(crate) fn hint_path(&self, id: u64) -> PathBuf {
+        hint_path(&self.data, id)
+    }
+
+    /// Returns the path of the segment with the given ID once zeroed for recycling
+    pub(crate) fn free_path(&self, id: u64) -> PathBuf {
+        self.wal.join(format!("{FREE_PREFIX}{id}"))
+    }
+
+    /// Returns the segment files zeroed for recycling in the WAL directory
+    ///
+    /// # Errors
+    /// Returns `Err` if the directory cannot be listed
+    pub(crate) fn free_files(&self) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.wal)? {
+            let path = entry?.path();
+            let free = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(FREE_PREFIX));
+            if free {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    /// Returns the WAL and data directories, once if the same
+    pub(crate) fn segment_dirs(&self) -> Vec<&Path> {
+        if self.wal == self.data {
+            vec![&self.wal]
+        } else {
+            vec![&self.wal, &self.data]
+        }
+    }
+
+    /// Returns the sealed and base segments in the WAL and data directories, in log order
+    ///
+    /// # Errors
+    /// Returns `Err` if a directory cannot be listed
+    pub(crate) fn segment_files(&self) -> io::Result<Vec<SegmentFile>> {
+        let mut files = Vec::new();
+        for dir in self.segment_dirs() {
+            files.extend(segment_files(dir)?);
+        }
+        files.sort_by_key(|f| f.id);
+        Ok(files)
+    }
+}
+
+/// Returns the path of the sealed segment with the given ID in dir
+pub(crate) fn sealed_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("wa.{id}.log"))
+}
+
+/// Creates the active segment at path, emptied if it exists, and allocates preallocate bytes
+/// of disk space to it if `Some`
+///
+/// With write-through, the file is opened with `O_DSYNC`, or `FILE_FLAG_WRITE_THROUGH` on
+/// Windows, so that each write returns once its data is on disk.
+///
+/// # Errors
+/// Returns `Err` if the file cannot be created or the space cannot be allocated
+pub(crate) fn create_active(
+    path: &Path,
+    write_through: bool,
+    preallocate: Option<u64>,
+) -> io::Result<File> {
+    let file = open_active(path, write_through, true)?;
+    if let Some(len) = preallocate {
+        allocate(&file, len)?;
+    }
+    Ok(file)
+}
+
+/// Takes the segment file zeroed for recycling at free as the active segment at path, holding
+/// at least len bytes of disk space
+///
+/// # Errors
+/// Returns `Err` if the file cannot be renamed, opened or extended
+pub(crate) fn recycle_active(
+    free: &Path,
+    path: &Path,
+    write_through: bool,
+    len: u64,
+) -> io::Result<File> {
+    fs::rename(free, path)?;
+    let file = open_active(path, write_through, false)?;
+    allocate(&file, len)?;
+    Ok(file)
+}
+
+/// Opens the active segment at path for writing from its start, emptied if truncate is set
+fn open_active(path: &Path, write_through: bool, truncate: bool) -> io::Result<File> {
+    let mut options = fs::OpenOptions::new();
+    options.truncate(truncate).create(true).write(true);
+    if write_through {
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::custom_flags(&mut options, libc::O_DSYNC);
+        #[cfg(windows)]
+        std::os::windows::fs::OpenOptionsExt::custom_flags(&mut options, FILE_FLAG_WRITE_THROUGH);
+    }
+    options.open(path)
+}
+
+/// Allocates len bytes of disk space to file, reading as zeros, unless it is already as long
+///
+/// Filesystems without `fallocate`, and other platforms, only extend the file.
+fn allocate(file: &File, len: u64) -> io::Result<()> {
+    if file.metadata()?.len() >= len {
+        return Ok(());
+    }
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        let len = libc::off_t::try_from(len).map_err(io::Error::other)?;
+        // SAFETY: the descriptor stays open for the duration of the call, as file is borrowed
+        if unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len) } == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EOPNOTSUPP) {
+            return Err(e);
+        }
+    }
+    file.set_len(len)
+}
+
+/// Overwrites the segment file at path with len zero bytes and syncs it, so that appending to
+/// it as an active segment only writes data already allocated
+///
+/// # Errors
+/// Returns `Err` if the file cannot be written
+pub(crate) fn zero(path: &Path, len: u64) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new().write(true).open(path)?;
+    file.set_len(len)?;
+    let zeros = vec![0; 64 * 1024];
+    let mut left = len;
+    while left > 0 {
+        let n = usize::try_from(left).map_or(zeros.len(), |left| left.min(zeros.len()));
+        file.write_all(&zeros[..n])?;
+        left -= n as u64;
+    }
+    file.sync_all()
+}
+
+/// Returns the length of the records of a segment, before the zeros of preallocated space left
+/// unwritten in an active segment, e.g. by a crash
+///
+/// Every record ends with a newline, so trailing zeros are never part of one.
+pub(crate) fn written_len(bytes: &[u8]) -> usize {
+    bytes.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1)
+}
+
+/// Flag of `CreateFileW` making writes go through the cache to disk
+#[cfg(windows)]
+const FILE_FLAG_WRITE_THROUGH: u32 = 0x8000_0000;
+
+/// Returns the path of the base segment with the given ID in dir
+pub(crate) fn base_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("wa.{id}.base.log"))
+}
+
+/// Returns the path of the hint file of the base segment with the given ID in dir
+pub(crate) fn hint_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("wa.{id}.base.hint"))
+}
+
+/// Returns the path of the filter file of the sealed or base segment at path
+pub(crate) fn bloom_path(path: &Path) -> PathBuf {
+    path.with_extension("bloom")
+}
+
+/// Removes the filter file of the segment at path, if any
+///
+/// # Errors
+/// Returns `Err` if the filter file exists but cannot be removed
+pub(crate) fn remove_bloom(path: &Path) -> io::Result<()> {
+    match fs::remove_file(bloom_path(path)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Removes the hint file of the base segment with the given ID in dir, if any
+///
+/// # Errors
+/// Returns `Err` if the hint file exists but cannot be removed
+pub(crate) fn remove_hint(dir: &Path, id: u64) -> io::Result<()> {
+    match fs::remove_file(hint_path(dir, id)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the header line of a base segment whose records have the given checksum and length
+///
+/// Records follow the header. Its length only depends on the format version, so it can be
+/// written as a placeholder before the records and overwritten once they are known.
+pub(crate) fn base_header(checksum: u32, len: u64) -> String {
+    format!("{BASE_MAGIC} {BASE_VERSION} {checksum:08x} {len:016x}\n")
+}
+
+/// Returns whether bytes start with a base segment header, valid or not
+pub(crate) fn has_base_header(bytes: &[u8]) -> bool {
+    bytes.starts_with(format!("{BASE_MAGIC} ").as_bytes())
+}
+
+/// Verifies the header of a base segment and returns the records after it
+///
+/// # Errors
+/// Returns why the segment cannot be loaded: no or malformed header, unsupported format
+/// version, truncated records, or checksum mismatch
+pub(crate) fn base_records(bytes: &[u8]) -> Result<&[u8], String> {
+    let end = bytes
+        .iter()
+        .take(64)
+        .position(|&b| b == b'\n')
+        .ok_or("missing header")?;
+    let header = std::str::from_utf8(&bytes[..end]).map_err(|_| "malformed header")?;
+    let records = &bytes[end + 1..];
+
+    let [magic, version, checksum, len] = header.split(' ').collect::<Vec<_>>()[..] else {
+        return Err("malformed header".to_owned());
+    };
+    if magic != BASE_MAGIC {
+        return Err("missing header".to_owned());
+    }
+    match version.parse::<u32>() {
+        Ok(BASE_VERSION) => {}
+        Ok(version) => return Err(format!("unsupported format version {version}")),
+        Err(_) => return Err("malformed header".to_owned()),
+    }
+    let (Ok(checksum), Ok(len)) = (
+        u32::from_str_radix(checksum, 16),
+        u64::from_str_radix(len, 16),
+    ) else {
+        return Err("malformed header".to_owned());
+    };
+
+    if records.len() as u64 != len {
+        return Err(format!(
+            "expected {len} bytes of records, found {}",
+            records.len()
+        ));
+    }
+    if crc32fast::hash(records) != checksum {
+        return Err("checksum mismatch".to_owned());
+    }
+
+    Ok(records)
+}
+
+/// Renames an unusable segment file so it is neither loaded nor deleted, and returns its
+/// new path
+///
+/// # Errors
+/// Returns `Err` if the rename fails
+pub(crate) fn set_aside(path: &Path) -> io::Result<PathBuf> {
+    let aside = with_suffix(path, ".corrupt");
+    fs::rename(path, &aside)?;
+    Ok(aside)
+}
+
+/// Returns path with suffix appended to its file name
+///
+/// Unlike replacing the extension, this keeps the whole file name, whatever dots it holds, and
+/// yields the same name on every platform.
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Returns the sealed and base segments in dir, in log order
+///
+/// # Errors
+/// Returns `Err` if dir cannot be listed
+pub(crate) fn segment_files(dir: &Path) -> io::Result<Vec<SegmentFile>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(stem) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("wa.")?.strip_suffix(".log"))
+        else {
+            continue;
+        };
+        let (id, base) = match stem.strip_suffix(".base") {
+            Some(id) => (id, true),
+            None => (stem, false),
+        };
+        if let Ok(id) = id.parse() {
+            files.push(SegmentFile { id, path, base });
+        }
+    }
+    files.sort_unstable_by_key(|f| f.id);
+
+    Ok(files)
+}
+
+/// Returns the segments still needed to rebuild the current state, from the last base onward
+///
+/// Segments before a base segment are obsolete; they are left behind if compaction is
+/// interrupted before deleting them.
+pub(crate) fn live(files: &[SegmentFile]) -> &[SegmentFile] {
+    let start = files.iter().rposition(|f| f.base).unwrap_or(0);
+    &files[start..]
+}
+
+/// Read handle to a segment
+///
+/// Sealed segments are never written again. Under the `mmap` feature they are memory-mapped
+/// and read from the mapping, falling back to positional reads if mapping fails.
+#[derive(Debug)]
+pub(crate) struct Segment {
+    path: PathBuf,
+    /// Handle read from, only kept on platforms with positional reads or to map the segment
+    #[cfg(any(unix, windows, feature = "mmap"))]
+    file: File,
+    /// Filter of the keys whose values the segment holds, if sealed with one
+    filter: Option<Bloom>,
+    #[cfg(feature = "mmap")]
+    map: Option<memmap2::Mmap>,
+}
+
+impl Segment {
+    /// Opens the segment at path for reading
+    ///
+    /// # Errors
+    /// Returns `Err` if the file cannot be opened
+    pub(crate) fn open(path: PathBuf) -> io::Result<Self> {
+        #[cfg(any(unix, windows, feature = "mmap"))]
+        let file = File::open(&path)?;
+        #[cfg(not(any(unix, windows, feature = "mmap")))]
+        File::open(&path)?;
+        Ok(Self {
+            path,
+            #[cfg(any(unix, windows, feature = "mmap"))]
+            file,
+            filter: None,
+            #[cfg(feature = "mmap")]
+            map: None,
+        })
+    }
+
+    /// Marks the segment as sealed after it was renamed to path
+    pub(crate) fn seal(&mut self, path: PathBuf) {
+        self.path = path;
+
+        // SAFETY: sealed segments are never written or truncated again; they are only deleted,
+        // after compaction has dropped the mapping.
+        #[cfg(feature = "mmap")]
+        match unsafe { memmap2::Mmap::map(&self.file) } {
+            Ok(map) => self.map = Some(map),
+            Err(e) => {
+                warn!(path = %self.path.display(), "Failed to map segment, reading from file: {e}");
+            }
+        }
+    }
+
+    /// Sets the filter of the keys whose values the segment holds
+    pub(crate) fn set_filter(&mut self, filter: Bloom) {
+        self.filter = Some(filter);
+    }
+
+    /// Returns whether the segment may hold a value of key, always `true` without a filter
+    pub(crate) fn may_contain(&self, key: &str) -> bool {
+        self.filter
+            .as_ref()
+            .is_none_or(|filter| filter.may_contain(key))
+    }
+
+    /// Returns the path of the segment file
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the bytes of a record
+    ///
+    /// # Errors
+    /// Returns `Err` if the read fails or falls outside the segment
+    pub(crate) fn read(&self, extent: Extent) -> io::Result<Vec<u8>> {
+        let len = usize::try_from(extent.len).map_err(io::Error::other)?;
+
+        #[cfg(feature = "mmap")]
+        if let Some(map) = &self.map {
+            let start = usize::try_from(extent.offset).map_err(io::Error::other)?;
+            return map
+                .get(start..start + len)
+                .map(<[u8]>::to_vec)
+                .ok_or_else(|| io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let mut buf = vec![0; len];
+        self.read_at(&mut buf, extent.offset)?;
+        Ok(buf)
+    }
+
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(&self.file, buf, offset)
+    }
+
+    /// Reads with `seek_read`, which moves the cursor of the shared handle but reads from the
+    /// given offset whatever other readers do
+    #[cfg(windows)]
+    fn read_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match std::os::windows::fs::FileExt::seek_read(&self.file, buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads through a fresh handle, since seeking the shared one would race other readers
+    #[cfg(not(any(unix, windows)))]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+    }
+}
@@ -0,0 +1,1007 @@
+//! Server exposing a KV store over the [`protocol`](crate::protocol), on TCP or a Unix domain
+//! socket
+
+use crate::{
+    auth::{Acl, Credentials, User},
+    protocol::{
+        read_frame, write_frame, Envelope, Request, Response, Tailing, REFUSED, SHIPMENT_BUDGET,
+    },
+    rate_limit::RateLimiter,
+    replication,
+    slowlog::SlowLog,
+    thread_pool::ThreadPool,
+    transport::{Listener, ServerAddr, Stream},
+    Command, KvStore, KvStoreError, Result,
+};
+use clap::Subcommand;
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, prelude::*, BufReader, BufWriter},
+    net::{self, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::{fs, os::unix::net::UnixListener, path::Path};
+use tracing::{debug, info, warn};
+
+/// Time a replica waits to apply a session token before redirecting the client to its primary
+const WAIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Requests of a connection executed at once
+const MAX_IN_FLIGHT: usize = 16;
+
+/// Interval at which an idle tailing connection checks whether it was closed
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Interval at which an idle tailing connection sends a heartbeat, so that clients can tell an
+/// idle server from a lost one
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keys a `scan_cursor` request goes through unless it gives a count, as in Redis
+const SCAN_COUNT: usize = 10;
+
+/// Serves store on address until shut down, with default [`ServerOptions`]
+///
+/// # Errors
+/// Returns `Err` if binding or accepting connections fails
+pub fn serve(
+    store: &KvStore,
+    addr: impl ToSocketAddrs,
+    pool: &impl ThreadPool,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    ServerOptions::new().serve(store, addr, pool, shutdown)
+}
+
+/// Serves store on an already bound listener until shut down, with default [`ServerOptions`]
+///
+/// # Errors
+/// Returns `Err` if accepting connections fails
+pub fn run(
+    store: &KvStore,
+    listener: &TcpListener,
+    pool: &impl ThreadPool,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    ServerOptions::new().run(store, listener, pool, shutdown)
+}
+
+/// Callback reloading the settings of a server, given the options it serves with
+pub type ReloadHook = Arc<dyn Fn(&ServerOptions) -> Result<()> + Send + Sync>;
+
+/// Requests a server answers, to serve admin requests on an address of their own
+///
+/// Admin requests manage the server and its store: `promote`, `reload`, `flush`, `compact`,
+/// `snapshot`, `freeze`, `thaw`, `stats`, `slow_log` and `tail`. Raft cluster requests are not among them, as members
+/// redirect them to the address of the leader. Servers of any scope answer `ping`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Scope {
+    /// Every request
+    #[default]
+    All,
+    /// Every request but admin requests
+    Data,
+    /// Admin requests only
+    Admin,
+}
+
+impl Scope {
+    /// Fails unless servers with this scope answer request
+    fn check(self, request: &Request) -> Result<()> {
+        if *request == Request::Ping {
+            return Ok(());
+        }
+        let admin = matches!(
+            request,
+            Request::Promote
+                | Request::Reload
+                | Request::Flush
+                | Request::Compact
+                | Request::Snapshot
+                | Request::Freeze
+                | Request::Thaw
+                | Request::Stats
+                | Request::SlowLog
+                | Request::Tail { .. }
+        );
+        match (self, admin) {
+            (Self::Data, true) => Err(KvStoreError::InvalidCommand(
+                "admin requests are served on the admin address".to_owned(),
+            )),
+            (Self::Admin, false) => Err(KvStoreError::InvalidCommand(
+                "only admin requests are served on this address".to_owned(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Admin commands, sent to a running server
+#[derive(Clone, Debug, Default, PartialEq, Subcommand)]
+pub enum AdminCommand {
+    /// Log writes held back by coalescing and sync the WAL to disk
+    Flush,
+    /// Compact the WAL if it holds superseded records
+    Compact,
+    /// Rewrite the WAL as a new base segment to replay from
+    Snapshot,
+    /// Reject writes, e.g. while backing up the store directory, until thawed
+    Freeze,
+    /// Accept writes again
+    Thaw,
+    /// Print store statistics
+    Stats {
+        /// Print as JSON instead of human-readable lines
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the requests the server recently took long to answer, oldest first
+    #[command(name = "slowlog")]
+    SlowLog {
+        /// Print a JSON object per request instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Reload the settings of the server, e.g. the config file of `kvs-server`
+    #[default]
+    Reload,
+}
+
+/// Options to configure how the server accepts clients
+///
+/// Mirrors [`ClientOptions`](crate::client::ClientOptions): chain setters on
+/// [`ServerOptions::new`], then call [`ServerOptions::serve`] or [`ServerOptions::run`], or
+/// their Unix domain socket counterparts.
+#[derive(Clone, Default)]
+pub struct ServerOptions {
+    /// ACL shared with clones, replaced by [`ServerOptions::set_acl`]
+    acl: Arc<RwLock<Option<Arc<Acl>>>>,
+    on_reload: Option<ReloadHook>,
+    scope: Scope,
+    slow_log: Option<Arc<SlowLog>>,
+    max_connections: Option<usize>,
+    /// Limiter shared with clones
+    rate_limit: Option<Arc<RateLimiter>>,
+    connection_rate_limit: Option<u32>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl fmt::Debug for ServerOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("ServerOptions");
+        debug
+            .field("acl", &self.current_acl())
+            .field("on_reload", &self.on_reload.is_some())
+            .field("scope", &self.scope)
+            .field("slow_log", &self.slow_log)
+            .field("max_connections", &self.max_connections)
+            .field("rate_limit", &self.rate_limit)
+            .field("connection_rate_limit", &self.connection_rate_limit);
+        #[cfg(feature = "tls")]
+        debug.field("tls", &self.tls);
+        debug.finish()
+    }
+}
+
+impl ServerOptions {
+    /// Returns default options: plain TCP, without authentication
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requires clients to authenticate as a user of acl, and checks their permissions
+    ///
+    /// Replicas and Raft cluster members cannot require authentication, as servers connect to
+    /// them without credentials.
+    pub fn acl(&mut self, acl: Acl) -> &mut Self {
+        self.acl = Arc::new(RwLock::new(Some(Arc::new(acl))));
+        self
+    }
+
+    /// Replaces the ACL of servers running with these options or their clones, or stops
+    /// requiring authentication without one
+    ///
+    /// Open connections are not dropped: their following requests are checked against the
+    /// permissions their user has in the new ACL, and denied if it has no user of that name.
+    pub fn set_acl(&self, acl: Option<Acl>) {
+        *self.acl.write().unwrap_or_else(PoisonError::into_inner) = acl.map(Arc::new);
+    }
+
+    /// Sets a callback run on [`Request::Reload`] from an admin, answered with its outcome
+    ///
+    /// Without one, servers answer reload requests with an error.
+    pub fn on_reload(
+        &mut self,
+        hook: impl Fn(&Self) -> Result<()> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.on_reload = Some(Arc::new(hook));
+        self
+    }
+
+    /// Restricts the requests answered, e.g. to serve admin requests on a separate address
+    /// with [`Scope::Admin`], and the others with [`Scope::Data`]
+    ///
+    /// Other requests are answered with an error.
+    pub fn scope(&mut self, scope: Scope) -> &mut Self {
+        self.scope = scope;
+        self
+    }
+
+    /// Logs requests taking at least threshold to answer as warnings, keeping the last
+    /// capacity of them for [`Request::SlowLog`]
+    ///
+    /// Servers running with clones of these options share the slow queries kept.
+    pub fn slow_log(&mut self, threshold: Duration, capacity: usize) -> &mut Self {
+        self.slow_log = Some(Arc::new(SlowLog::new(threshold, capacity)));
+        self
+    }
+
+    /// Refuses connections beyond `max` open at once on a listener, answering them with
+    /// [`Response::Busy`] before closing them
+    ///
+    /// Over TLS, refused connections are closed right away, since answering would take a
+    /// handshake.
+    pub fn max_connections(&mut self, max: usize) -> &mut Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Limits the requests answered to `per_sec` per second across all connections, in bursts
+    /// of up to as many, answering the others with [`Response::Busy`]
+    ///
+    /// Servers running with clones of these options share the limit.
+    pub fn rate_limit(&mut self, per_sec: u32) -> &mut Self {
+        self.rate_limit = Some(Arc::new(RateLimiter::new(per_sec)));
+        self
+    }
+
+    /// Limits the requests answered to `per_sec` per second on each connection, like
+    /// [`ServerOptions::rate_limit`]
+    pub fn connection_rate_limit(&mut self, per_sec: u32) -> &mut Self {
+        self.connection_rate_limit = Some(per_sec);
+        self
+    }
+
+    /// Runs the reload callback, if any
+    ///
+    /// # Errors
+    /// Returns `Err` if there is no callback or it fails
+    pub fn reload(&self) -> Result<()> {
+        match &self.on_reload {
+            Some(hook) => hook(self),
+            None => Err(KvStoreError::InvalidCommand(
+                "reload is not supported by this server".to_owned(),
+            )),
+        }
+    }
+
+    /// Returns the ACL currently in force
+    fn current_acl(&self) -> Option<Arc<Acl>> {
+        self.acl
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Serves over TLS with config, see [`tls::server_config`](crate::tls::server_config)
+    ///
+    /// Clients failing the handshake are disconnected.
+    #[cfg(feature = "tls")]
+    pub fn tls(&mut self, config: Arc<rustls::ServerConfig>) -> &mut Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Serves store on address until shut down
+    ///
+    /// # Errors
+    /// Returns `Err` if binding or accepting connections fails
+    pub fn serve(
+        &self,
+        store: &KvStore,
+        addr: impl ToSocketAddrs,
+        pool: &impl ThreadPool,
+        shutdown: &Shutdown,
+    ) -> Result<()> {
+        let listener = TcpListener::bind(addr).map_err(KvStoreError::FailedServe)?;
+        self.run(store, &listener, pool, shutdown)
+    }
+
+    /// Serves store on an already bound listener until shut down
+    ///
+    /// Each connection is handled by a job on the pool, occupying one of its threads until the
+    /// client disconnects: with a fixed-size pool, connections beyond its thread count wait
+    /// for an earlier one to close. Once shut down, returns after every connection is closed.
+    ///
+    /// # Errors
+    /// Returns `Err` if accepting connections fails
+    pub fn run(
+        &self,
+        store: &KvStore,
+        listener: &TcpListener,
+        pool: &impl ThreadPool,
+        shutdown: &Shutdown,
+    ) -> Result<()> {
+        self.accept(store, Listener::Tcp(listener), pool, shutdown)
+    }
+
+    /// Serves store on a Unix domain socket created at path until shut down, removing it
+    /// afterwards
+    ///
+    /// A stale socket left at path by a server that is gone is replaced. The socket is
+    /// created with the permissions allowed by the umask; restrict who may connect with those
+    /// of its directory.
+    ///
+    /// # Errors
+    /// Returns `Err` if another server is listening at path, or binding or accepting
+    /// connections fails
+    #[cfg(unix)]
+    pub fn serve_unix(
+        &self,
+        store: &KvStore,
+        path: impl AsRef<Path>,
+        pool: &impl ThreadPool,
+        shutdown: &Shutdown,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let listener = match UnixListener::bind(path) {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && is_stale(path) => {
+                info!(path = %path.display(), "Replacing stale socket");
+                fs::remove_file(path).map_err(KvStoreError::FailedServe)?;
+                UnixListener::bind(path)
+            }
+            listener => listener,
+        }
+        .map_err(KvStoreError::FailedServe)?;
+
+        let result = self.run_unix(store, &listener, pool, shutdown);
+        let _ = fs::remove_file(path);
+        result
+    }
+
+    /// Serves store on an already bound Unix domain socket until shut down, like
+    /// [`ServerOptions::run`]
+    ///
+    /// # Errors
+    /// Returns `Err` if accepting connections fails
+    #[cfg(unix)]
+    pub fn run_unix(
+        &self,
+        store: &KvStore,
+        listener: &UnixListener,
+        pool: &impl ThreadPool,
+        shutdown: &Shutdown,
+    ) -> Result<()> {
+        self.accept(store, Listener::Unix(listener), pool, shutdown)
+    }
+
+    /// Accepts connections on listener until shut down, see [`ServerOptions::run`]
+    fn accept(
+        &self,
+        store: &KvStore,
+        listener: Listener<'_>,
+        pool: &impl ThreadPool,
+        shutdown: &Shutdown,
+    ) -> Result<()> {
+        let addr = listener.local_addr().map_err(KvStoreError::FailedServe)?;
+        info!(%addr, "Server listening");
+        shutdown.listening(&addr);
+        let open = Arc::new(AtomicUsize::new(0));
+
+        loop {
+            let stream = listener.accept();
+            if shutdown.is_triggered() {
+                break;
+            }
+            let stream = stream.map_err(KvStoreError::FailedServe)?;
+            if self
+                .max_connections
+                .is_some_and(|max| open.load(Ordering::SeqCst) >= max)
+            {
+                self.refuse(&stream);
+                continue;
+            }
+            let Some(connection) = shutdown.register(&stream) else {
+                continue;
+            };
+            let store = store.clone();
+            let options = self.clone();
+            let open = Arc::clone(&open);
+            open.fetch_add(1, Ordering::SeqCst);
+            pool.spawn(move || {
+                let peer = stream.peer().ok();
+                if let Err(e) = handle(&store, stream, &options) {
+                    warn!(?peer, "Connection failed: {e}");
+                }
+                drop(connection);
+                open.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+
+        info!(%addr, "Server draining connections");
+        shutdown.drained();
+
+        Ok(())
+    }
+
+    /// Answers a connection over the connection limit with [`Response::Busy`] and closes it
+    ///
+    /// The frame fits in the empty send buffer of the new connection, so writing it does not
+    /// hold up the accept loop.
+    #[cfg_attr(not(feature = "tls"), allow(clippy::unused_self))] // Plain streams need no options
+    fn refuse(&self, mut stream: &Stream) {
+        let peer = stream.peer().ok();
+        warn!(?peer, "Refusing connection over the limit");
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            return;
+        }
+        let busy = Response::Busy("too many connections".to_owned());
+        let _ = write_frame(
+            &mut stream,
+            &Envelope {
+                id: REFUSED,
+                body: busy,
+            },
+        );
+        let _ = stream.shutdown(net::Shutdown::Both);
+    }
+
+    /// Splits a connection into its reading and writing halves, starting TLS if enabled
+    #[cfg_attr(not(feature = "tls"), allow(clippy::unused_self))] // Plain streams need no options
+    fn open(&self, stream: Stream) -> io::Result<Halves> {
+        #[cfg(feature = "tls")]
+        if let Some(config) = &self.tls {
+            let (reader, writer) = crate::tls::accept(config, stream)?;
+            return Ok((Box::new(reader), Box::new(writer)));
+        }
+        Ok((Box::new(stream.try_clone()?), Box::new(stream)))
+    }
+}
+
+/// Returns whether the Unix domain socket at path refuses connections, its server being gone
+#[cfg(unix)]
+fn is_stale(path: &Path) -> bool {
+    use std::os::unix::{fs::FileTypeExt, net::UnixStream};
+
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket())
+        && UnixStream::connect(path).is_err_and(|e| e.kind() == io::ErrorKind::ConnectionRefused)
+}
+
+/// Reading and writing halves of a connection
+type Halves = (Box<dyn Read + Send>, Box<dyn Write + Send>);
+
+/// Handle to stop servers gracefully
+///
+/// Once triggered, servers stop accepting connections and stop reading from open ones, so
+/// that each connection closes after answering the requests already received. Cheap to clone,
+/// e.g. into a signal handler.
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown {
+    inner: Arc<ShutdownInner>,
+}
+
+#[derive(Debug, Default)]
+struct ShutdownInner {
+    triggered: AtomicBool,
+    state: Mutex<ShutdownState>,
+    drained: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct ShutdownState {
+    /// Addresses of listening servers, connected to in order to wake them up
+    listeners: Vec<ServerAddr>,
+    /// Open connections by ID
+    connections: HashMap<u64, Stream>,
+    next_id: u64,
+}
+
+/// Registration of an open connection, removed on drop
+#[derive(Debug)]
+struct Connection {
+    shutdown: Shutdown,
+    id: u64,
+}
+
+impl Shutdown {
+    /// Returns a handle that is not yet triggered
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stops servers using this handle
+    pub fn trigger(&self) {
+        if self.inner.triggered.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        info!("Shutting down");
+
+        let state = self.lock();
+        for stream in state.connections.values() {
+            let _ = stream.shutdown(net::Shutdown::Read);
+        }
+        let listeners = state.listeners.clone();
+        drop(state);
+
+        for addr in &listeners {
+            wake(addr);
+        }
+    }
+
+    /// Returns whether the handle was triggered
+    #[must_use]
+    pub fn is_triggered(&self) -> bool {
+        self.inner.triggered.load(Ordering::SeqCst)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ShutdownState> {
+        self.inner
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Records a listening address to wake up on trigger, waking it right away if already
+    /// triggered
+    fn listening(&self, addr: &ServerAddr) {
+        self.lock().listeners.push(addr.clone());
+        if self.is_triggered() {
+            wake(addr);
+        }
+    }
+
+    /// Registers an accepted connection, stopping reads right away if already triggered
+    fn register(&self, stream: &Stream) -> Option<Connection> {
+        let stream = match stream.try_clone() {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to register connection: {e}");
+                return None;
+            }
+        };
+
+        let mut state = self.lock();
+        if self.is_triggered() {
+            let _ = stream.shutdown(net::Shutdown::Read);
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.connections.insert(id, stream);
+
+        Some(Connection {
+            shutdown: self.clone(),
+            id,
+        })
+    }
+
+    /// Blocks until every registered connection is closed
+    fn drained(&self) {
+        let mut state = self.lock();
+        while !state.connections.is_empty() {
+            state = self
+                .inner
+                .drained
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+/// Connects to a listening server so that it notices the shutdown
+fn wake(addr: &ServerAddr) {
+    let addr = match addr {
+        ServerAddr::Tcp(addrs) => ServerAddr::Tcp(
+            addrs
+                .iter()
+                .map(|&addr| match addr {
+                    SocketAddr::V4(_) if addr.ip().is_unspecified() => {
+                        (Ipv4Addr::LOCALHOST, addr.port()).into()
+                    }
+                    SocketAddr::V6(_) if addr.ip().is_unspecified() => {
+                        (Ipv6Addr::LOCALHOST, addr.port()).into()
+                    }
+                    addr => addr,
+                })
+                .collect(),
+        ),
+        #[cfg(unix)]
+        ServerAddr::Unix(_) => addr.clone(),
+    };
+    let _ = Stream::connect(&addr, None);
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut state = self.shutdown.lock();
+        state.connections.remove(&self.id);
+        if state.connections.is_empty() {
+            self.shutdown.inner.drained.notify_all();
+        }
+    }
+}
+
+/// Answers requests read from stream until the client disconnects
+///
+/// Requests are executed concurrently by up to [`MAX_IN_FLIGHT`] workers of the connection,
+/// started as needed, each answering its request once done; reading further requests waits
+/// while all are busy. Writes are attributed to the address of the client in the log.
+fn handle(store: &KvStore, stream: Stream, options: &ServerOptions) -> io::Result<()> {
+    let peer = stream.peer()?;
+    let (reader, writer) = options.open(stream.try_clone()?)?;
+    let mut reader = BufReader::new(reader);
+    let responses = Responses {
+        writer: Mutex::new(BufWriter::new(writer)),
+        stream,
+        failed: OnceLock::new(),
+    };
+    let (jobs, queue) = mpsc::sync_channel(0);
+    let queue = Mutex::new(queue);
+    let idle = AtomicUsize::new(0);
+
+    let mut session = None;
+    let slow_log = options.slow_log.as_deref();
+    let rate_limit = options.connection_rate_limit.map(RateLimiter::new);
+
+    thread::scope(|s| {
+        let mut workers = 0;
+        while let Some(Envelope { id, body }) = read_frame(&mut reader)? {
+            let limits = [rate_limit.as_ref(), options.rate_limit.as_deref()];
+            if !limits.into_iter().flatten().all(RateLimiter::try_acquire) {
+                responses.send(id, &Response::Busy("rate limit exceeded".to_owned()));
+                continue;
+            }
+            // Authentication applies to the requests read after it
+            let acl = options.current_acl();
+            let request = match serde_json::from_value(body) {
+                Ok(Request::Auth(credentials)) => {
+                    responses.send(id, &authenticate(acl, &credentials, &mut session));
+                    continue;
+                }
+                Ok(request) => request,
+                Err(e) => {
+                    responses.send(id, &Response::Err(format!("Invalid request: {e}")));
+                    continue;
+                }
+            };
+            if let Err(e) = options.scope.check(&request) {
+                responses.send(id, &Response::Err(e.to_string()));
+                continue;
+            }
+            if let Err(KvStoreError::PermissionDenied(e)) = authorize(acl, &mut session, &request) {
+                responses.send(id, &Response::PermissionDenied(e));
+                continue;
+            }
+            if request == Request::Reload {
+                let response = match options.reload() {
+                    Ok(()) => Response::Ok(None),
+                    Err(e) => Response::Err(e.report()),
+                };
+                responses.send(id, &response);
+                continue;
+            }
+            if request == Request::SlowLog {
+                let response = match &options.slow_log {
+                    Some(slow_log) => Response::SlowQueries(slow_log.queries()),
+                    None => Response::Err(
+                        KvStoreError::InvalidCommand(
+                            "slow query log is not enabled on this server".to_owned(),
+                        )
+                        .to_string(),
+                    ),
+                };
+                responses.send(id, &response);
+                continue;
+            }
+            if request == Request::Replicate && store.replica_of().is_some() {
+                responses.send(id, &Response::Ok(None));
+                info!(peer, "Following primary");
+                return replication::follow(store, &mut reader);
+            }
+            if let Request::Tail { from_seq } = request {
+                responses.send(id, &Response::Ok(None));
+                info!(peer, from_seq, "Tailing WAL");
+                return tail(store, from_seq, &mut reader, &responses);
+            }
+
+            if idle.load(Ordering::SeqCst) == 0 && workers < MAX_IN_FLIGHT {
+                s.spawn(|| work(store, &peer, slow_log, &queue, &idle, &responses));
+                workers += 1;
+            }
+            // Workers only stop once jobs is dropped
+            let _ = jobs.send(Envelope { id, body: request });
+        }
+        drop(jobs);
+        Ok(())
+    })?;
+
+    responses.failed.into_inner().map_or(Ok(()), Err)
+}
+
+/// Streams the WAL records of store numbered from `from_seq` on to a client, until it closes
+/// the connection or the server shuts down, which both end reads from the client
+fn tail(
+    store: &KvStore,
+    from_seq: u64,
+    reader: &mut (impl Read + Send),
+    responses: &Responses,
+) -> io::Result<()> {
+    let shipments = store.tail(from_seq);
+    let closed = AtomicBool::new(false);
+    thread::scope(|s| {
+        s.spawn(|| {
+            // Clients send nothing more
+            let _ = io::copy(reader, &mut io::sink());
+            closed.store(true, Ordering::SeqCst);
+        });
+
+        let mut sent = Instant::now();
+        let result = loop {
+            let messages = match shipments.recv_timeout(POLL_INTERVAL) {
+                Ok(shipment) => shipment
+                    .split(SHIPMENT_BUDGET)
+                    .into_iter()
+                    .map(Tailing::Shipment)
+                    .collect(),
+                Err(RecvTimeoutError::Timeout) if closed.load(Ordering::SeqCst) => break Ok(()),
+                Err(RecvTimeoutError::Timeout) if sent.elapsed() >= HEARTBEAT_INTERVAL => {
+                    vec![Tailing::Heartbeat]
+                }
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => {
+                    break Err(io::Error::other("WAL writer stopped"));
+                }
+            };
+            if let Err(e) = messages.iter().try_for_each(|m| responses.stream(m)) {
+                break Err(e);
+            }
+            sent = Instant::now();
+        };
+        // Unblocks the reading thread
+        let _ = responses.stream.shutdown(net::Shutdown::Both);
+        result
+    })
+}
+
+/// Executes the requests of a connection taken from queue, answering each, until the
+/// connection stops reading requests
+fn work(
+    store: &KvStore,
+    peer: &str,
+    slow_log: Option<&SlowLog>,
+    queue: &Mutex<mpsc::Receiver<Envelope<Request>>>,
+    idle: &AtomicUsize,
+    responses: &Responses,
+) {
+    loop {
+        idle.fetch_add(1, Ordering::SeqCst);
+        let job = queue.lock().unwrap_or_else(PoisonError::into_inner).recv();
+        idle.fetch_sub(1, Ordering::SeqCst);
+        let Ok(Envelope { id, body }) = job else {
+            return;
+        };
+
+        let response = crate::as_client(peer, || match slow_log {
+            Some(slow_log) => {
+                slow_log.time(peer, store.clock().now(), body, |body| respond(store, body))
+            }
+            None => respond(store, body),
+        });
+        debug!(id, ?response, "Answered request");
+        responses.send(id, &response);
+    }
+}
+
+/// Responses of a connection, written by its workers
+struct Responses {
+    writer: Mutex<BufWriter<Box<dyn Write + Send>>>,
+    stream: Stream,
+    /// First failure to write a response, after which the connection is shut down
+    failed: OnceLock<io::Error>,
+}
+
+impl Responses {
+    /// Writes the response to the request with id
+    fn send(&self, id: u64, response: &Response) {
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        let result = write_frame(&mut *writer, &Envelope { id, body: response })
+            .and_then(|()| writer.flush());
+        if let Err(e) = result {
+            let _ = self.stream.shutdown(net::Shutdown::Both);
+            let _ = self.failed.set(e);
+        }
+    }
+
+    /// Writes a message outside of an envelope, once the connection streams messages
+    fn stream(&self, message: &Tailing) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        write_frame(&mut *writer, message)?;
+        writer.flush()
+    }
+}
+
+/// User a connection authenticated as, and the ACL it was found in
+struct Session {
+    acl: Arc<Acl>,
+    user: Arc<User>,
+}
+
+/// Authenticates the connection as the user with credentials, if the server has an ACL
+fn authenticate(
+    acl: Option<Arc<Acl>>,
+    credentials: &Credentials,
+    session: &mut Option<Session>,
+) -> Response {
+    let Some(acl) = acl else {
+        return Response::Ok(None);
+    };
+    *session = acl
+        .authenticate(credentials)
+        .map(|user| Session { acl, user });
+    match session {
+        Some(session) => {
+            debug!(user = session.user.name, "Authenticated");
+            Response::Ok(None)
+        }
+        None => Response::PermissionDenied("invalid credentials".to_owned()),
+    }
+}
+
+/// Fails unless the connection may make request, if the server has an ACL
+///
+/// A session from before the ACL was replaced moves to the user of the same name in the new
+/// one, if any.
+///
+/// # Errors
+/// Returns [`KvStoreError::PermissionDenied`] if the connection is not authenticated, or its
+/// user lacks the access required
+fn authorize(
+    acl: Option<Arc<Acl>>,
+    session: &mut Option<Session>,
+    request: &Request,
+) -> Result<()> {
+    let Some(acl) = acl else {
+        return Ok(());
+    };
+    if *request == Request::Ping {
+        return Ok(());
+    }
+    if let Some(stale) = session.take_if(|session| !Arc::ptr_eq(&session.acl, &acl)) {
+        *session = acl.user(&stale.user.name).map(|user| Session { acl, user });
+    }
+    match session {
+        Some(session) => session.user.authorize(request),
+        None => Err(KvStoreError::PermissionDenied(
+            "not authenticated".to_owned(),
+        )),
+    }
+}
+
+/// Executes a write of a client, through the Raft log if the store belongs to a cluster
+fn write(store: &KvStore, cmd: Command) -> Result<Response> {
+    store.inner.freeze.check()?;
+    #[cfg(feature = "raft")]
+    if let Some(node) = store.cluster() {
+        return store
+            .hooked(cmd, |cmd| node.propose(store, cmd.try_into()?))
+            .map(Response::Ok);
+    }
+
+    store.check_writable()?;
+    store.execute(cmd).map(Response::Ok)
+}
+
+/// Returns the Raft member of store
+#[cfg(feature = "raft")]
+fn member(store: &KvStore) -> Result<&crate::raft::Node> {
+    store.cluster().ok_or(KvStoreError::NotClusterMember)
+}
+
+/// Answers a `scan_cursor` request with the keys matching pattern among the count keys after
+/// cursor
+///
+/// The cursor is the last key gone through, so keys present for the whole scan are each returned
+/// once; see [`KvStore::scan_pattern`].
+fn scan_page(
+    store: &KvStore,
+    cursor: Option<&str>,
+    pattern: Option<&str>,
+    count: Option<usize>,
+) -> Result<Response> {
+    let count = count.unwrap_or(SCAN_COUNT);
+    if count == 0 {
+        return Err(KvStoreError::InvalidCommand(
+            "scan count must be positive".to_owned(),
+        ));
+    }
+    let (keys, cursor) = store.scan_pattern(pattern.unwrap_or("*"), cursor, count)?;
+    Ok(Response::Page { keys, cursor })
+}
+
+/// Executes request on store
+fn respond(store: &KvStore, request: Request) -> Response {
+    let result = match request {
+        // Handled as read, see `handle`
+        Request::Auth(_) | Request::Reload | Request::SlowLog | Request::Tail { .. } => {
+            Ok(Response::Ok(None))
+        }
+        Request::Get { key, path: None } => store.get_hooked(key).map(Response::Ok),
+        Request::Get {
+            key,
+            path: Some(path),
+        } => store.get_path(&key, &path).map(Response::Ok),
+        Request::Set { key, value, path } => write(
+            store,
+            Command::Set {
+                key,
+                value,
+                path,
+                nx: false,
+                xx: false,
+            },
+        ),
+        Request::Rm { key } => write(store, Command::Rm { key, prefix: None }),
+        Request::Scan { prefix } => store.scan(&prefix).map(Response::Entries),
+        Request::ScanCursor {
+            cursor,
+            pattern,
+            count,
+        } => scan_page(store, cursor.as_deref(), pattern.as_deref(), count),
+        Request::Lock { key, ttl_ms } => write(store, Command::Lock { key, ttl: ttl_ms }),
+        Request::Unlock { key, token } => write(store, Command::Unlock { key, lease: token }),
+        Request::Replicate => Err(KvStoreError::NotReplica),
+        Request::Promote => store.promote().map(|()| Response::Ok(None)),
+        Request::Flush => store.sync().map(|()| Response::Ok(None)),
+        Request::Compact => store.compact().map(|()| Response::Ok(None)),
+        Request::Snapshot => store.snapshot().map(|()| Response::Ok(None)),
+        Request::Freeze => store.freeze_writes().map(|()| Response::Ok(None)),
+        Request::Thaw => {
+            store.thaw();
+            Ok(Response::Ok(None))
+        }
+        Request::Stats => Ok(Response::Stats(store.stats())),
+        Request::Ping => Ok(Response::Health(store.health())),
+        Request::Sequence => Ok(Response::Sequence(store.sequence())),
+        Request::WaitFor { sequence } => Ok(match store.replica_of() {
+            Some(primary) if !store.wait_for(sequence, WAIT_TIMEOUT) => Response::Redirect(primary),
+            _ => Response::Ok(None),
+        }),
+        #[cfg(feature = "raft")]
+        Request::Raft(message) => member(store)
+            .and_then(|node| node.handle(store, message))
+            .map(Response::Raft),
+        #[cfg(feature = "raft")]
+        Request::ClusterAddNode { node } => member(store)
+            .and_then(|member| member.add_node(store, node))
+            .map(|()| Response::Ok(None)),
+        #[cfg(feature = "raft")]
+        Request::ClusterStatus => member(store).map(|node| Response::Cluster(node.status())),
+    };
+
+    match result {
+        Ok(response) => response,
+        Err(KvStoreError::FailedRm(key)) => Response::KeyNotFound(key),
+        Err(KvStoreError::PermissionDenied(e)) => Response::PermissionDenied(e),
+        Err(KvStoreError::ReadOnly) => Response::ReadOnly,
+        #[cfg(feature = "raft")]
+        Err(KvStoreError::NotLeader(Some(leader))) => Response::Redirect(leader),
+        Err(e) => match e.split() {
+            Ok(e) => Response::Err(e.error().report()),
+            Err(e) => {
+                let report = e.error().report();
+                warn!("Request failed: {report}");
+                Response::Err(report)
+            }
+        },
+    }
+}
@@ -0,0 +1,125 @@
+//! Log of the requests a server took long to answer, to find pathological keys
+
+use crate::protocol::Request;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+/// Request a server took at least the slow query threshold to answer
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct SlowQuery {
+    /// When the server started executing the request
+    pub at: SystemTime,
+    /// Client that sent the request
+    pub client: String,
+    /// Request name, e.g. `get`
+    pub command: String,
+    /// Key or prefix of the request, if any
+    pub key: Option<String>,
+    /// Time taken to answer the request
+    pub duration: Duration,
+}
+
+impl SlowQuery {
+    /// Returns the header line of the table [`SlowQuery`] formats rows of
+    #[must_use]
+    pub fn header() -> String {
+        format!(
+            "{:>17} {:>12} {:<21} {:<10} KEY",
+            "AT", "DURATION_MS", "CLIENT", "COMMAND"
+        )
+    }
+}
+
+/// Formats the query as a row of the table headed by [`SlowQuery::header`]
+impl fmt::Display for SlowQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let at = self
+            .at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64());
+        write!(
+            f,
+            "{at:>17.3} {:>12.3} {:<21} {:<10} {}",
+            self.duration.as_secs_f64() * 1000.0,
+            self.client,
+            self.command,
+            self.key.as_deref().unwrap_or("-")
+        )
+    }
+}
+
+/// Most recent slow queries of a server, oldest first
+#[derive(Debug)]
+pub(crate) struct SlowLog {
+    threshold: Duration,
+    capacity: usize,
+    queries: Mutex<VecDeque<SlowQuery>>,
+}
+
+impl SlowLog {
+    /// Returns an empty log keeping the last capacity requests taking at least threshold
+    pub(crate) fn new(threshold: Duration, capacity: usize) -> Self {
+        Self {
+            threshold,
+            capacity,
+            queries: Mutex::default(),
+        }
+    }
+
+    /// Answers request of client with respond, started at time at, recording it if slow
+    pub(crate) fn time<T>(
+        &self,
+        client: &str,
+        at: SystemTime,
+        request: Request,
+        respond: impl FnOnce(Request) -> T,
+    ) -> T {
+        let command: &'static str = (&request).into();
+        let key = match &request {
+            Request::Get { key, .. } | Request::Set { key, .. } | Request::Rm { key } => {
+                Some(key.clone())
+            }
+            Request::Scan { prefix } => Some(prefix.clone()),
+            Request::ScanCursor { pattern, .. } => pattern.clone(),
+            _ => None,
+        };
+        let start = Instant::now();
+        let response = respond(request);
+        let duration = start.elapsed();
+        if duration < self.threshold {
+            return response;
+        }
+
+        warn!(client, command, ?key, ?duration, "Slow query");
+        let mut queries = self.queries.lock().unwrap_or_else(PoisonError::into_inner);
+        if queries.len() == self.capacity {
+            queries.pop_front();
+        }
+        if self.capacity > 0 {
+            queries.push_back(SlowQuery {
+                at,
+                client: client.to_owned(),
+                command: command.to_owned(),
+                key,
+                duration,
+            });
+        }
+        response
+    }
+
+    /// Returns the recorded queries, oldest first
+    pub(crate) fn queries(&self) -> Vec<SlowQuery> {
+        self.queries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,88 @@
+//! Export of the store to, and import from, a SQLite file, which many tools can read directly
+//!
+//! The file holds a single table, `kv (key TEXT PRIMARY KEY, value BLOB)`, with values as
+//! their UTF-8 bytes.
+
+use crate::{import::Imported, KvStore, KvStoreError, Result};
+use clap::ValueEnum;
+use rusqlite::{types::ValueRef, Connection, OpenFlags};
+use std::path::Path;
+use strum::Display;
+use tracing::info;
+
+/// Table holding the key-value pairs
+const TABLE: &str = "kv";
+
+/// Format of a file to export to
+#[derive(Clone, Copy, Debug, Default, Display, PartialEq, Eq, ValueEnum)]
+#[strum(serialize_all = "kebab-case")]
+pub enum ExportFormat {
+    /// SQLite database with a single `kv` table
+    #[default]
+    Sqlite,
+}
+
+/// Writes all key-value pairs of store, as of one point in time, to a new SQLite file at path,
+/// returning how many were written
+///
+/// # Errors
+/// Returns `Err` if path exists already, or reading the store or writing the file fails
+pub fn export(store: &KvStore, path: impl AsRef<Path>) -> Result<u64> {
+    let path = path.as_ref();
+    if path.exists() {
+        return Err(KvStoreError::InvalidCommand(format!(
+            "{} exists already",
+            path.display()
+        )));
+    }
+    let mut connection = Connection::open(path)?;
+    let transaction = connection.transaction()?;
+    transaction.execute(
+        &format!("CREATE TABLE {TABLE} (key TEXT PRIMARY KEY, value BLOB)"),
+        (),
+    )?;
+    let mut exported = 0;
+    {
+        let mut insert =
+            transaction.prepare(&format!("INSERT INTO {TABLE} (key, value) VALUES (?1, ?2)"))?;
+        for (key, value) in store.iter()? {
+            insert.execute((key, value.as_bytes()))?;
+            exported += 1;
+        }
+    }
+    transaction.commit()?;
+    info!(path = %path.display(), keys = exported, "Exported store to SQLite");
+    Ok(exported)
+}
+
+/// Sets the key-value pairs of the SQLite file at path, as written by [`export`], over those of
+/// store
+///
+/// Values may be blobs or text; pairs with a null key or value, or one not in UTF-8, are
+/// skipped.
+///
+/// # Errors
+/// Returns `Err` if the file cannot be read or has no `kv` table, or writing a key fails
+pub fn import(store: &KvStore, path: impl AsRef<Path>) -> Result<Imported> {
+    let path = path.as_ref();
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut select = connection.prepare(&format!("SELECT key, value FROM {TABLE}"))?;
+    let mut rows = select.query(())?;
+    let mut imported = Imported::default();
+    while let Some(row) = rows.next()? {
+        let text = |value: ValueRef<'_>| match value {
+            ValueRef::Text(bytes) | ValueRef::Blob(bytes) => String::from_utf8(bytes.to_vec()).ok(),
+            ValueRef::Integer(n) => Some(n.to_string()),
+            ValueRef::Real(x) => Some(x.to_string()),
+            ValueRef::Null => None,
+        };
+        let (Some(key), Some(value)) = (text(row.get_ref(0)?), text(row.get_ref(1)?)) else {
+            imported.not_utf8 += 1;
+            continue;
+        };
+        store.set(key, value)?;
+        imported.keys += 1;
+    }
+    info!(path = %path.display(), keys = imported.keys, "Imported SQLite file");
+    Ok(imported)
+}
@@ -0,0 +1,204 @@
+//! Point-in-time statistics and health of a KV store
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Snapshot of store size and activity, as returned by [`KvStore::stats`](crate::KvStore::stats)
+///
+/// Displays as human-readable lines; serializes with times as seconds (since the Unix epoch
+/// for points in time).
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct StoreStats {
+    /// Keys with a value, across the default key space and buckets
+    pub keys: usize,
+    /// Bytes of WAL records still needed to rebuild the current state
+    pub live_bytes: u64,
+    /// Bytes of WAL records superseded by later ones, reclaimed by compaction
+    pub dead_bytes: u64,
+    /// WAL segments, including the active one
+    pub segments: usize,
+    /// When the WAL was last compacted, if it was since the store was opened
+    #[serde(
+        serialize_with = "serialize_time",
+        deserialize_with = "deserialize_time"
+    )]
+    pub last_compaction: Option<SystemTime>,
+    /// Progress of the compaction running in the background, if any
+    #[serde(default)]
+    pub compaction: Option<CompactionProgress>,
+    /// Whether automatic compactions are stopped, see
+    /// [`KvStore::stop_compaction`](crate::KvStore::stop_compaction)
+    #[serde(default)]
+    pub compaction_stopped: bool,
+    /// Approximate bytes of memory taken by the keys and values of the store and its index,
+    /// see [`OpenOptions::max_memory`](crate::OpenOptions::max_memory)
+    #[serde(default)]
+    pub memory_bytes: u64,
+    /// Keys evicted in cache mode since the store was opened, see
+    /// [`OpenOptions::eviction`](crate::OpenOptions::eviction)
+    #[serde(default)]
+    pub evictions: u64,
+    /// Time since the store was opened
+    #[serde(
+        serialize_with = "serialize_duration",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub uptime: Duration,
+}
+
+impl fmt::Display for StoreStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Keys: {}", self.keys)?;
+        writeln!(f, "Live bytes: {}", self.live_bytes)?;
+        writeln!(f, "Dead bytes: {}", self.dead_bytes)?;
+        writeln!(f, "Segments: {}", self.segments)?;
+        match self.last_compaction.map(unix_secs) {
+            Some(secs) => writeln!(f, "Last compaction: {secs:.3} (Unix time)")?,
+            None => writeln!(f, "Last compaction: never")?,
+        }
+        match (self.compaction, self.compaction_stopped) {
+            (Some(progress), _) => writeln!(
+                f,
+                "Compaction: {} of {} bytes copied",
+                progress.copied_bytes, progress.total_bytes
+            )?,
+            (None, true) => writeln!(f, "Compaction: stopped")?,
+            (None, false) => writeln!(f, "Compaction: idle")?,
+        }
+        writeln!(f, "Memory: {} bytes", self.memory_bytes)?;
+        writeln!(f, "Evictions: {}", self.evictions)?;
+        write!(f, "Uptime: {:.3}s", self.uptime.as_secs_f64())
+    }
+}
+
+/// Progress of a compaction running in the background, as returned in
+/// [`StoreStats::compaction`]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct CompactionProgress {
+    /// Bytes of live records copied to the new base segment so far
+    pub copied_bytes: u64,
+    /// Bytes of live records to copy
+    pub total_bytes: u64,
+}
+
+/// Progress of loading the log files of a store while opening it, as reported to
+/// [`OpenOptions::on_progress`](crate::OpenOptions::on_progress)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OpenProgress {
+    /// Log files loaded so far
+    pub segments_done: usize,
+    /// Log files to load, sealed and base segments along with the previous active segment
+    pub segments_total: usize,
+    /// Records replayed so far
+    pub records: u64,
+    /// Bytes of log files loaded so far
+    pub bytes_done: u64,
+    /// Bytes of log files to load
+    pub bytes_total: u64,
+    /// Estimated time left, from the rate bytes were loaded at so far
+    pub eta: Option<Duration>,
+}
+
+/// Liveness and readiness of a store, as returned by [`KvStore::health`](crate::KvStore::health)
+///
+/// Displays and serializes like [`StoreStats`].
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Health {
+    /// Whether a file could be written to the store directory
+    pub writable: bool,
+    /// When the WAL was last synced to disk, if it was since the store was opened
+    #[serde(
+        serialize_with = "serialize_time",
+        deserialize_with = "deserialize_time"
+    )]
+    pub last_sync: Option<SystemTime>,
+    /// Time since a replica last heard from its primary, or since it was opened if it never
+    /// did; `None` if the store is not a replica
+    #[serde(serialize_with = "serialize_lag", deserialize_with = "deserialize_lag")]
+    pub replication_lag: Option<Duration>,
+}
+
+impl Health {
+    /// Returns why the store should not take traffic, if any: its directory is not writable,
+    /// or it is a replica lagging more than `max_lag`
+    #[must_use]
+    pub fn problem(&self, max_lag: Option<Duration>) -> Option<String> {
+        if !self.writable {
+            return Some("store directory is not writable".to_owned());
+        }
+        match self.replication_lag.zip(max_lag) {
+            Some((lag, max_lag)) if lag > max_lag => Some(format!(
+                "replication lag of {:.3}s exceeds {:.3}s",
+                lag.as_secs_f64(),
+                max_lag.as_secs_f64()
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Writable: {}", if self.writable { "yes" } else { "no" })?;
+        match self.last_sync.map(unix_secs) {
+            Some(secs) => writeln!(f, "Last sync: {secs:.3} (Unix time)")?,
+            None => writeln!(f, "Last sync: never")?,
+        }
+        match self.replication_lag {
+            Some(lag) => write!(f, "Replication lag: {:.3}s", lag.as_secs_f64()),
+            None => write!(f, "Replication lag: not a replica"),
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64())
+}
+
+#[allow(clippy::ref_option)] // Signature required by `serialize_with`
+fn serialize_time<S: Serializer>(
+    time: &Option<SystemTime>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    time.map(unix_secs).serialize(serializer)
+}
+
+fn serialize_duration<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    duration.as_secs_f64().serialize(serializer)
+}
+
+#[allow(clippy::ref_option)] // Signature required by `serialize_with`
+fn serialize_lag<S: Serializer>(lag: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+    lag.map(|lag| lag.as_secs_f64()).serialize(serializer)
+}
+
+fn deserialize_time<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<SystemTime>, D::Error> {
+    Option::<f64>::deserialize(deserializer)?
+        .map(|secs| {
+            Duration::try_from_secs_f64(secs)
+                .map(|since_epoch| UNIX_EPOCH + since_epoch)
+                .map_err(de::Error::custom)
+        })
+        .transpose()
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    Duration::try_from_secs_f64(f64::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+fn deserialize_lag<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    Option::<f64>::deserialize(deserializer)?
+        .map(|secs| Duration::try_from_secs_f64(secs).map_err(de::Error::custom))
+        .transpose()
+}
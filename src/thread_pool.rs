@@ -0,0 +1,118 @@
+//! Thread pools running server connection handlers
+
+use crate::{KvStoreError, Result};
+use std::{
+    io,
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex, PoisonError},
+    thread,
+};
+use tracing::{error, warn};
+
+/// Job run on a pool thread
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Pool of threads running spawned jobs
+pub trait ThreadPool {
+    /// Starts a pool with the given number of threads
+    ///
+    /// # Errors
+    /// Returns `Err` if the threads cannot be spawned
+    fn new(threads: usize) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Runs job on a pool thread
+    ///
+    /// A panicking job does not take down the pool.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
+
+/// Pool spawning a new thread per job, ignoring the thread count
+#[derive(Debug)]
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(_threads: usize) -> Result<Self> {
+        Ok(Self)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Err(e) = thread::Builder::new().spawn(job) {
+            error!("Failed to spawn thread: {e}");
+        }
+    }
+}
+
+/// Pool of a fixed number of threads taking jobs from a shared queue
+///
+/// Threads exit once the pool is dropped and the queue is drained.
+#[derive(Debug)]
+pub struct SharedQueueThreadPool {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: usize) -> Result<Self> {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+
+        for i in 0..threads.max(1) {
+            let queue = Arc::clone(&queue);
+            thread::Builder::new()
+                .name(format!("kvs-pool-{i}"))
+                .spawn(move || loop {
+                    let job = queue.lock().unwrap_or_else(PoisonError::into_inner).recv();
+                    let Ok(job) = job else {
+                        break;
+                    };
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        warn!("Thread pool job panicked");
+                    }
+                })
+                .map_err(KvStoreError::FailedPoolStart)?;
+        }
+
+        Ok(Self { jobs })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.jobs.send(Box::new(job)).is_err() {
+            error!("Thread pool queue closed");
+        }
+    }
+}
+
+/// Work-stealing pool backed by [`rayon`]
+#[derive(Debug)]
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+}
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: usize) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("kvs-rayon-{i}"))
+            .panic_handler(|_| warn!("Thread pool job panicked"))
+            .build()
+            .map_err(|e| KvStoreError::FailedPoolStart(io::Error::other(e)))?;
+
+        Ok(Self { pool })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.pool.spawn(job);
+    }
+}
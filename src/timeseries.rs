@@ -0,0 +1,227 @@
+//! Append-oriented timeseries values stored in compressed chunks
+
+use clap::ValueEnum;
+use strum::{Display, EnumString};
+
+/// Maximum number of samples per chunk before a new chunk is started
+const CHUNK_SAMPLES: usize = 256;
+
+/// Single timeseries data point
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    /// Sample timestamp, e.g. milliseconds since the Unix epoch
+    pub timestamp: i64,
+    /// Sample value
+    pub value: f64,
+}
+
+/// Aggregation applied to samples of a range query
+#[derive(Clone, Copy, Debug, Default, Display, EnumString, PartialEq, ValueEnum)]
+#[strum(serialize_all = "lowercase")]
+pub enum Aggregation {
+    /// Return raw samples
+    #[default]
+    None,
+    /// Number of samples
+    Count,
+    /// Sum of sample values
+    Sum,
+    /// Mean of sample values
+    Avg,
+    /// Minimum sample value
+    Min,
+    /// Maximum sample value
+    Max,
+}
+
+impl Aggregation {
+    /// Aggregates samples into a single sample stamped with `timestamp`
+    ///
+    /// Returns the samples unchanged for [`Aggregation::None`], and nothing for an empty range.
+    #[allow(clippy::cast_precision_loss)]
+    fn apply(self, samples: Vec<Sample>, timestamp: i64) -> Vec<Sample> {
+        if samples.is_empty() {
+            return samples;
+        }
+
+        let values = samples.iter().map(|s| s.value);
+        let value = match self {
+            Self::None => return samples,
+            Self::Count => samples.len() as f64,
+            Self::Sum => values.sum(),
+            Self::Avg => values.sum::<f64>() / samples.len() as f64,
+            Self::Min => values.fold(f64::INFINITY, f64::min),
+            Self::Max => values.fold(f64::NEG_INFINITY, f64::max),
+        };
+
+        vec![Sample { timestamp, value }]
+    }
+}
+
+/// Timeseries made of compressed chunks in timestamp order
+#[derive(Debug, Default)]
+pub(crate) struct TimeSeries {
+    chunks: Vec<Chunk>,
+}
+
+impl TimeSeries {
+    /// Last appended sample, if any
+    pub(crate) fn last(&self) -> Option<Sample> {
+        self.chunks.last().map(|c| c.last)
+    }
+
+    /// Appends a sample; caller guarantees timestamps are strictly increasing
+    pub(crate) fn push(&mut self, sample: Sample) {
+        match self.chunks.last_mut() {
+            Some(chunk) if chunk.len < CHUNK_SAMPLES => chunk.push(sample),
+            _ => self.chunks.push(Chunk::new(sample)),
+        }
+    }
+
+    /// Returns samples with timestamps in `from..=to`, aggregated as requested
+    ///
+    /// Returns `None` if a chunk fails to decode.
+    pub(crate) fn range(
+        &self,
+        from: i64,
+        to: i64,
+        aggregation: Aggregation,
+    ) -> Option<Vec<Sample>> {
+        let mut samples = Vec::new();
+        for chunk in &self.chunks {
+            if chunk.first.timestamp <= to && chunk.last.timestamp >= from {
+                samples.extend(
+                    chunk
+                        .samples()?
+                        .into_iter()
+                        .filter(|s| (from..=to).contains(&s.timestamp)),
+                );
+            }
+        }
+
+        Some(aggregation.apply(samples, from))
+    }
+}
+
+/// Run of samples compressed with timestamp delta-of-delta and value XOR encoding
+///
+/// The first sample is stored verbatim. Each following sample is encoded as a zigzag varint
+/// of its timestamp delta-of-delta, then the XOR of its value bits with the previous value's:
+/// a zero byte if unchanged, else a byte holding the leading and trailing zero byte counts
+/// followed by the remaining significant bytes.
+#[derive(Debug)]
+struct Chunk {
+    first: Sample,
+    last: Sample,
+    last_delta: i64,
+    len: usize,
+    data: Vec<u8>,
+}
+
+impl Chunk {
+    fn new(sample: Sample) -> Self {
+        Self {
+            first: sample,
+            last: sample,
+            last_delta: 0,
+            len: 1,
+            data: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, sample: Sample) {
+        let delta = sample.timestamp.wrapping_sub(self.last.timestamp);
+        write_varint(&mut self.data, zigzag(delta.wrapping_sub(self.last_delta)));
+
+        let xor = sample.value.to_bits() ^ self.last.value.to_bits();
+        if xor == 0 {
+            self.data.push(0);
+        } else {
+            let leading = xor.leading_zeros() / 8;
+            let trailing = xor.trailing_zeros() / 8;
+            #[allow(clippy::cast_possible_truncation)]
+            self.data.push(((leading << 4) | trailing) as u8);
+            let bytes = xor.to_be_bytes();
+            self.data
+                .extend_from_slice(&bytes[leading as usize..8 - trailing as usize]);
+        }
+
+        self.last = sample;
+        self.last_delta = delta;
+        self.len += 1;
+    }
+
+    /// Decompresses all samples of the chunk, or returns `None` if its data is malformed
+    fn samples(&self) -> Option<Vec<Sample>> {
+        let mut samples = Vec::with_capacity(self.len);
+        samples.push(self.first);
+
+        let mut prev = self.first;
+        let mut delta = 0i64;
+        let mut pos = 0;
+        while pos < self.data.len() {
+            delta = delta.wrapping_add(unzigzag(read_varint(&self.data, &mut pos)?));
+            let timestamp = prev.timestamp.wrapping_add(delta);
+
+            let header = *self.data.get(pos)?;
+            pos += 1;
+            let mut bits = prev.value.to_bits();
+            if header != 0 {
+                let leading = usize::from(header >> 4);
+                let width = 8usize.checked_sub(leading + usize::from(header & 0x0f))?;
+                let mut bytes = [0u8; 8];
+                bytes
+                    .get_mut(leading..leading + width)?
+                    .copy_from_slice(self.data.get(pos..pos + width)?);
+                pos += width;
+                bits ^= u64::from_be_bytes(bytes);
+            }
+
+            prev = Sample {
+                timestamp,
+                value: f64::from_bits(bits),
+            };
+            samples.push(prev);
+        }
+
+        Some(samples)
+    }
+}
+
+fn zigzag(n: i64) -> u64 {
+    #[allow(clippy::cast_sign_loss)]
+    {
+        ((n << 1) ^ (n >> 63)) as u64
+    }
+}
+
+fn unzigzag(n: u64) -> i64 {
+    #[allow(clippy::cast_possible_wrap)]
+    {
+        (n >> 1) as i64 ^ -((n & 1) as i64)
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        #[allow(clippy::cast_possible_truncation)]
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    buf.push(n as u8);
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut n = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *buf.get(*pos)?;
+        *pos += 1;
+        n |= u64::from(byte & 0x7f).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(n);
+        }
+        shift += 7;
+    }
+}
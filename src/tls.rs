@@ -0,0 +1,254 @@
+//! TLS for the [`server`](crate::server) and [`client`](crate::client), using rustls
+//!
+//! Certificates and private keys are read from PEM files. A server given the certificate
+//! authority of its clients only accepts clients presenting a certificate it signed, so that
+//! both ends are authenticated. Replication and Raft connections between servers are not
+//! encrypted.
+
+use crate::{transport::Stream, KvStoreError, Result};
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection,
+};
+use std::{
+    fmt,
+    io::{self, prelude::*},
+    path::Path,
+    result,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+/// Size of the buffer ciphertext is read into, that of the largest TLS record
+const RECORD_SIZE: usize = 16 * 1024 + 256;
+
+/// Returns the configuration of a server presenting the certificate chain at cert with the
+/// private key at key, only accepting clients whose certificate was signed by the authority
+/// at `client_ca` if given
+///
+/// # Errors
+/// Returns `Err` if a file cannot be read or does not hold a valid certificate or key
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> Result<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid)?;
+    let builder = match client_ca {
+        Some(ca) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(roots(ca)?, provider())
+                .build()
+                .map_err(invalid)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(certs(cert)?, private_key(key)?)
+        .map_err(invalid)?;
+    Ok(Arc::new(config))
+}
+
+/// Returns the configuration of a client only trusting servers whose certificate was signed
+/// by the authority at ca, presenting the certificate chain and private key at identity if
+/// given
+///
+/// # Errors
+/// Returns `Err` if a file cannot be read or does not hold a valid certificate or key
+pub fn client_config(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid)?
+        .with_root_certificates(roots(ca)?);
+    let config = match identity {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(certs(cert)?, private_key(key)?)
+            .map_err(invalid)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Reads the certificates in the PEM file at path
+fn certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(Iterator::collect::<result::Result<Vec<_>, _>>)
+        .map_err(|e| unreadable(path, e))?;
+    if certs.is_empty() {
+        return Err(unreadable(path, "no certificate found"));
+    }
+    Ok(certs)
+}
+
+/// Reads the first private key in the PEM file at path
+fn private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| unreadable(path, e))
+}
+
+/// Reads the certificate authorities in the PEM file at path
+fn roots(path: &Path) -> Result<Arc<RootCertStore>> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(path)? {
+        roots.add(cert).map_err(invalid)?;
+    }
+    Ok(Arc::new(roots))
+}
+
+fn unreadable(path: &Path, e: impl fmt::Display) -> KvStoreError {
+    let message = format!("{}: {e}", path.display());
+    KvStoreError::FailedTls(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+fn invalid(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> KvStoreError {
+    KvStoreError::FailedTls(io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Starts the server side of a TLS session over stream, returning its reading and writing
+/// halves
+///
+/// The handshake completes as the halves are used.
+///
+/// # Errors
+/// Returns `Err` if the session cannot be started or the stream cannot be cloned
+pub(crate) fn accept(
+    config: &Arc<ServerConfig>,
+    stream: Stream,
+) -> io::Result<(TlsReader, TlsWriter)> {
+    let conn = ServerConnection::new(Arc::clone(config)).map_err(io::Error::other)?;
+    split(conn.into(), stream)
+}
+
+/// Starts the client side of a TLS session over stream, returning its reading and writing
+/// halves
+///
+/// The certificate of the server must be valid for `server_name`, or else for the IP address
+/// the stream is connected to.
+///
+/// # Errors
+/// Returns `Err` if the server name is invalid, the session cannot be started or the stream
+/// cannot be cloned
+pub(crate) fn connect(
+    config: &Arc<ClientConfig>,
+    server_name: Option<&str>,
+    stream: Stream,
+) -> io::Result<(TlsReader, TlsWriter)> {
+    let name = match server_name {
+        Some(name) => ServerName::try_from(name.to_owned())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+        None => stream
+            .peer_addr()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no server name"))?
+            .ip()
+            .into(),
+    };
+    let conn = ClientConnection::new(Arc::clone(config), name).map_err(io::Error::other)?;
+    split(conn.into(), stream)
+}
+
+fn split(conn: Connection, stream: Stream) -> io::Result<(TlsReader, TlsWriter)> {
+    let session = Arc::new(Session {
+        conn: Mutex::new(conn),
+        stream: stream.try_clone()?,
+    });
+    let reader = TlsReader {
+        session: Arc::clone(&session),
+        stream,
+        incoming: vec![0; RECORD_SIZE].into_boxed_slice(),
+    };
+    Ok((reader, TlsWriter { session }))
+}
+
+/// TLS session shared by its reading and writing halves
+///
+/// Ciphertext is read from the stream without holding the session, so that one thread can
+/// wait for requests while others write responses. The peer is notified once both halves are
+/// dropped.
+struct Session {
+    conn: Mutex<Connection>,
+    stream: Stream,
+}
+
+impl Session {
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Writes the ciphertext queued by conn to the stream
+    fn send(&self, conn: &mut Connection) -> io::Result<()> {
+        while conn.wants_write() {
+            conn.write_tls(&mut &self.stream)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let conn = self.conn.get_mut().unwrap_or_else(PoisonError::into_inner);
+        conn.send_close_notify();
+        while conn.wants_write() {
+            if conn.write_tls(&mut &self.stream).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Reading half of a TLS session
+pub(crate) struct TlsReader {
+    session: Arc<Session>,
+    stream: Stream,
+    incoming: Box<[u8]>,
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.session.lock().reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+
+            let n = self.stream.read(&mut self.incoming)?;
+            let mut received = &self.incoming[..n];
+            let mut conn = self.session.lock();
+            loop {
+                conn.read_tls(&mut received)?;
+                let state = conn.process_new_packets();
+                // Handshake messages and alerts are sent right away
+                self.session.send(&mut conn)?;
+                state.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                if received.is_empty() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Writing half of a TLS session
+pub(crate) struct TlsWriter {
+    session: Arc<Session>,
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.session.lock();
+        let n = conn.writer().write(buf)?;
+        self.session.send(&mut conn)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut conn = self.session.lock();
+        conn.writer().flush()?;
+        self.session.send(&mut conn)
+    }
+}
@@ -0,0 +1,243 @@
+//! Transports carrying the [`protocol`](crate::protocol): TCP, or Unix domain sockets for
+//! clients on the same host
+//!
+//! A Unix domain socket avoids the overhead of TCP, and lets the permissions of the socket
+//! file and its directory decide which local users may connect.
+
+use crate::KvStoreError;
+use std::{
+    fmt,
+    io::{self, prelude::*},
+    net::{self, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    str::FromStr,
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+};
+
+/// Prefix of the addresses of Unix domain sockets
+const UNIX_PREFIX: &str = "unix:";
+
+/// Address of a server
+///
+/// Parses from a TCP address such as `127.0.0.1:4000` or `localhost:4000`, resolved right
+/// away, or from `unix:` followed by the path of a Unix domain socket, as in
+/// `unix:/run/kvs.sock` or `unix:///run/kvs.sock`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerAddr {
+    /// TCP socket addresses, tried in order
+    Tcp(Vec<SocketAddr>),
+    /// Path of a Unix domain socket
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for ServerAddr {
+    type Err = KvStoreError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix(UNIX_PREFIX) {
+            // The authority of `unix://` URLs is empty
+            let path = path.strip_prefix("//").unwrap_or(path);
+            #[cfg(unix)]
+            return Ok(Self::Unix(path.into()));
+            #[cfg(not(unix))]
+            return Err(KvStoreError::FailedConnect(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Unix domain sockets are not supported: {path}"),
+            )));
+        }
+
+        let addrs = s
+            .to_socket_addrs()
+            .map_err(KvStoreError::FailedConnect)?
+            .collect();
+        Ok(Self::Tcp(addrs))
+    }
+}
+
+impl From<SocketAddr> for ServerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Tcp(vec![addr])
+    }
+}
+
+impl fmt::Display for ServerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addrs) => match addrs.as_slice() {
+                [addr] => write!(f, "{addr}"),
+                addrs => write!(f, "{addrs:?}"),
+            },
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "{UNIX_PREFIX}{}", path.display()),
+        }
+    }
+}
+
+/// Connected stream of either transport
+#[derive(Debug)]
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Connects to the first address of the server accepting a connection within timeout
+    pub(crate) fn connect(addr: &ServerAddr, timeout: Option<Duration>) -> io::Result<Self> {
+        match addr {
+            ServerAddr::Tcp(addrs) => {
+                let mut last_error = io::ErrorKind::AddrNotAvailable.into();
+                for addr in addrs {
+                    let stream = match timeout {
+                        None => TcpStream::connect(addr),
+                        Some(timeout) => TcpStream::connect_timeout(addr, timeout),
+                    };
+                    match stream {
+                        Ok(stream) => return Ok(Self::Tcp(stream)),
+                        Err(e) => last_error = e,
+                    }
+                }
+                Err(last_error)
+            }
+            #[cfg(unix)]
+            ServerAddr::Unix(path) => UnixStream::connect(path).map(Self::Unix),
+        }
+    }
+
+    /// Sets the read and write timeouts, and disables Nagle's algorithm on TCP
+    pub(crate) fn configure(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => {
+                stream.set_nodelay(true)?;
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+            #[cfg(unix)]
+            Self::Unix(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Self::Tcp(stream) => stream.try_clone().map(Self::Tcp),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.try_clone().map(Self::Unix),
+        }
+    }
+
+    pub(crate) fn shutdown(&self, how: net::Shutdown) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    /// Returns the address of the peer, if connected over TCP
+    #[cfg(feature = "tls")]
+    pub(crate) fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            Self::Unix(_) => None,
+        }
+    }
+
+    /// Describes the peer, for logs: its address over TCP, or the socket connected to
+    pub(crate) fn peer(&self) -> io::Result<String> {
+        match self {
+            Self::Tcp(stream) => stream.peer_addr().map(|addr| addr.to_string()),
+            #[cfg(unix)]
+            Self::Unix(stream) => {
+                let addr = stream.local_addr()?;
+                let path = addr.as_pathname().map(|path| path.display().to_string());
+                Ok(format!("{UNIX_PREFIX}{}", path.unwrap_or_default()))
+            }
+        }
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+/// Bound listener of either transport
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Listener<'a> {
+    Tcp(&'a TcpListener),
+    #[cfg(unix)]
+    Unix(&'a UnixListener),
+}
+
+impl Listener<'_> {
+    pub(crate) fn accept(self) -> io::Result<Stream> {
+        match self {
+            Self::Tcp(listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+            #[cfg(unix)]
+            Self::Unix(listener) => listener.accept().map(|(stream, _)| Stream::Unix(stream)),
+        }
+    }
+
+    /// Returns the address clients connect to
+    pub(crate) fn local_addr(self) -> io::Result<ServerAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr().map(ServerAddr::from),
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let addr = listener.local_addr()?;
+                let path = addr
+                    .as_pathname()
+                    .ok_or_else(|| io::Error::other("unnamed Unix domain socket"))?;
+                Ok(ServerAddr::Unix(path.to_owned()))
+            }
+        }
+    }
+}
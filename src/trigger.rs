@@ -0,0 +1,145 @@
+//! Triggers reacting to changes of keys with a prefix, e.g. to invalidate caches or call
+//! webhooks
+//!
+//! A trigger either calls a function or runs a command for each change. Commands get the
+//! change as a JSON object on stdin, e.g.
+//!
+//! ```json
+//! {"event": "set", "key": "users/ada", "value": "lovelace"}
+//! {"event": "removed", "key": "users/ada"}
+//! ```
+//!
+//! `kvs-server` runs the triggers of its [config file](crate::config).
+
+use crate::{server::Shutdown, KvStore, KvStoreError, Result, WatchEvent};
+use std::{
+    fmt,
+    io::Write,
+    process::{self, Stdio},
+    sync::{mpsc::RecvTimeoutError, Arc},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use tracing::{debug, warn};
+
+/// Interval at which an idle trigger checks for shutdown
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What a trigger does for each change
+#[derive(Clone)]
+pub enum Action {
+    /// Call a function with the change
+    Callback(Arc<dyn Fn(&WatchEvent) + Send + Sync>),
+    /// Run a program with arguments, writing the change to its stdin
+    Command(Vec<String>),
+}
+
+impl fmt::Debug for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Callback(_) => f.write_str("Callback(..)"),
+            Self::Command(argv) => f.debug_tuple("Command").field(argv).finish(),
+        }
+    }
+}
+
+/// Action run for each change of the keys starting with a prefix
+#[derive(Clone, Debug)]
+pub struct Trigger {
+    /// Key prefix
+    pub prefix: String,
+    /// Action run for each change
+    pub action: Action,
+}
+
+impl Trigger {
+    /// Returns a trigger calling f with each change of the keys starting with prefix
+    pub fn callback(
+        prefix: impl Into<String>,
+        f: impl Fn(&WatchEvent) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            prefix: prefix.into(),
+            action: Action::Callback(Arc::new(f)),
+        }
+    }
+
+    /// Returns a trigger running the program of argv with its arguments for each change of the
+    /// keys starting with prefix
+    pub fn command(prefix: impl Into<String>, argv: Vec<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            action: Action::Command(argv),
+        }
+    }
+
+    /// Runs the action for event, logging failures
+    fn fire(&self, event: &WatchEvent) {
+        match &self.action {
+            Action::Callback(f) => f(event),
+            Action::Command(argv) => {
+                if let Err(e) = run(argv, event) {
+                    warn!(
+                        prefix = self.prefix,
+                        key = event.key(),
+                        "Trigger failed: {e}"
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Runs trigger for each change of store from now on, until shut down
+///
+/// Runs on a new thread, one change at a time in the order they were received, so a slow
+/// command holds up the changes after it. Changes made while shutting down may be missed.
+///
+/// # Errors
+/// Returns `Err` if the thread cannot be spawned, or the trigger has an empty command
+pub fn spawn(store: &KvStore, trigger: Trigger, shutdown: &Shutdown) -> Result<JoinHandle<()>> {
+    if matches!(&trigger.action, Action::Command(argv) if argv.is_empty()) {
+        return Err(KvStoreError::InvalidConfig(format!(
+            "trigger on {:?} has an empty command",
+            trigger.prefix
+        )));
+    }
+
+    let events = store.watch(trigger.prefix.clone());
+    let shutdown = shutdown.clone();
+    thread::Builder::new()
+        .name(format!("kvs-trigger-{}", trigger.prefix))
+        .spawn(move || loop {
+            match events.recv_timeout(POLL_INTERVAL) {
+                Ok(event) => trigger.fire(&event),
+                Err(RecvTimeoutError::Timeout) if shutdown.is_triggered() => return,
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        })
+        .map_err(KvStoreError::FailedTrigger)
+}
+
+/// Runs the program of argv with event as JSON on stdin, and waits for it to succeed
+fn run(argv: &[String], event: &WatchEvent) -> std::io::Result<()> {
+    let mut child = process::Command::new(&argv[0])
+        .args(&argv[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let written = child.stdin.take().map_or(Ok(()), |mut stdin| {
+        serde_json::to_writer(&mut stdin, event)?;
+        stdin.write_all(b"\n")
+    });
+    let status = child.wait()?;
+    written?;
+    debug!(?argv, %status, "Ran trigger");
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::other(format!(
+            "{} exited with {status}",
+            argv[0]
+        )))
+    }
+}
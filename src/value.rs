@@ -0,0 +1,94 @@
+//! Borrowed values returned by [`KvStore::get_ref`](crate::KvStore::get_ref)
+
+use dashmap::mapref::one::Ref;
+use std::{fmt, ops::Deref, sync::Arc};
+
+/// Value of a key, borrowed from the store where possible
+///
+/// Derefs to `str`, and converts to `&[u8]` with [`AsRef`]. When the value is held in memory,
+/// the guard borrows it from the map of the store, holding a read lock of the shard of the key:
+/// writes to any key of that shard wait until the guard is dropped, so a thread writing while
+/// holding a guard may deadlock. In offset-index mode, the value is read from the log or shared
+/// with the value cache, and no lock is held.
+pub struct ValueRef<'a>(Inner<'a>);
+
+enum Inner<'a> {
+    /// Entry of the in-memory map
+    Entry(Ref<'a, String, String>),
+    /// Value shared with the value cache
+    Shared(Arc<str>),
+    /// Value read from the log
+    Owned(String),
+}
+
+impl<'a> ValueRef<'a> {
+    pub(crate) fn entry(entry: Ref<'a, String, String>) -> Self {
+        Self(Inner::Entry(entry))
+    }
+
+    pub(crate) fn shared(value: Arc<str>) -> Self {
+        Self(Inner::Shared(value))
+    }
+
+    pub(crate) fn owned(value: String) -> Self {
+        Self(Inner::Owned(value))
+    }
+
+    /// Returns the value as an owned string, releasing any lock held
+    #[must_use]
+    pub fn into_string(self) -> String {
+        match self.0 {
+            Inner::Entry(entry) => entry.value().clone(),
+            Inner::Shared(value) => value.as_ref().to_owned(),
+            Inner::Owned(value) => value,
+        }
+    }
+}
+
+impl Deref for ValueRef<'_> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match &self.0 {
+            Inner::Entry(entry) => entry.value(),
+            Inner::Shared(value) => value,
+            Inner::Owned(value) => value,
+        }
+    }
+}
+
+impl AsRef<str> for ValueRef<'_> {
+    fn as_ref(&self) -> &str {
+        self
+    }
+}
+
+impl AsRef<[u8]> for ValueRef<'_> {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl PartialEq<str> for ValueRef<'_> {
+    fn eq(&self, other: &str) -> bool {
+        **self == *other
+    }
+}
+
+impl PartialEq<&str> for ValueRef<'_> {
+    fn eq(&self, other: &&str) -> bool {
+        **self == **other
+    }
+}
+
+impl fmt::Debug for ValueRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl fmt::Display for ValueRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self)
+    }
+}
@@ -0,0 +1,2473 @@
+//! Write-ahead log (WAL) appended by a dedicated writer thread
+//!
+//! Callers queue records to the writer over a channel instead of sharing the file handle, and
+//! the writer commits records queued together with a single write (and sync). The log is split
+//! into segments: once the active segment `wa.log` grows past the segment size it is sealed,
+//! i.e. renamed to `wa.<id>.log` and never written again, and a new active segment is started.
+//! Once the store is opened, the writer records the segments in the manifest as they change.
+//!
+//! Records are encoded in the [format](WalFormat) recorded in the manifest. They are numbered
+//! with monotonically increasing sequence numbers and timestamped when committed, along with the
+//! client that made the write if known (see [`as_client`]); replayed into a new log, they keep
+//! their [`Stamp`]. Superseded records dropped by compaction leave gaps, marked by a `compacted`
+//! record giving the last sequence number the compaction covered.
+//!
+//! The writer keeps an index of where the live record of each key sits in the log, which also
+//! serves value reads in offset-index mode. Once most of the log is dead records it compacts it
+//! online: live records are copied to a new base segment `wa.<id>.base.log`, which supersedes
+//! all previous segments, by a thread of its own or of the thread pool of its runtime while the
+//! writer goes on appending (see
+//! [`compaction`](crate::compaction)).
+//!
+//! Along with each base segment, compaction writes a hint file locating its records (see
+//! [`hint`](crate::hint)). Opening a store adopts a base segment with a valid hint file in
+//! place, indexing its records from the hints, and only replays the segments after it.
+
+#[cfg(feature = "metrics")]
+use crate::Metrics;
+use crate::{
+    bloom::{self, Bloom},
+    coalesce::Coalescer,
+    codec::{self, Stamp, WalFormat},
+    compaction::{CompactionPolicy, Control, SegmentInfo},
+    hint::{self, Hints},
+    history::{History, Revision},
+    keymap::{KeyIndex, KeyMap},
+    manifest::{self, Manifest, FORMAT_VERSION},
+    segment::{self, Dirs, Extent, Segment},
+    Clock, Command, KvStoreError, KvsRuntime, OpenOptions, Result,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs::{self, File},
+    io::{self, prelude::*, BufWriter, SeekFrom},
+    mem,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, TryLockError,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, error, info, warn};
+
+thread_local! {
+    /// Client the writes of this thread are attributed to, see [`as_client`]
+    static CLIENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Runs f with the writes it makes on this thread attributed to client in the log
+///
+/// Writes coalesced with [`OpenOptions::coalesce_window`] are logged without client.
+pub fn as_client<T>(client: &str, f: impl FnOnce() -> T) -> T {
+    let previous = CLIENT.with(|c| c.replace(Some(client.to_owned())));
+    let result = f();
+    CLIENT.with(|c| *c.borrow_mut() = previous);
+    result
+}
+
+/// Operation recorded in the WAL
+#[derive(Debug)]
+pub(crate) enum Record {
+    Set {
+        key: String,
+        value: String,
+        /// Version of the key after the write, if not one past its previous version (or 1)
+        version: Option<u64>,
+        /// Expiry of the key in milliseconds since the Unix epoch, if set with a TTL
+        expires: Option<u64>,
+    },
+    /// Appends suffix to the value of a present key
+    Append {
+        key: String,
+        suffix: String,
+    },
+    Rm {
+        key: String,
+    },
+    /// Removes every key of the default key space starting with prefix
+    RmPrefix {
+        prefix: String,
+    },
+    /// Logs the value, so that the record alone sets the new key once the old one's are
+    /// compacted away
+    Rename {
+        from: String,
+        to: String,
+        value: String,
+    },
+    BucketSet {
+        bucket: String,
+        key: String,
+        value: String,
+    },
+    BucketRm {
+        bucket: String,
+        key: String,
+    },
+    ReserveIds {
+        sequence: String,
+        end: u64,
+    },
+    Sample {
+        key: String,
+        timestamp: i64,
+        value: f64,
+    },
+    /// Pushes value to the front of the list under key
+    ListPush {
+        key: String,
+        value: String,
+    },
+    /// Pops the value at the back of the list under key, the one pushed first
+    ListPop {
+        key: String,
+    },
+    SetAdd {
+        key: String,
+        member: String,
+    },
+    SetRemove {
+        key: String,
+        member: String,
+    },
+    /// Takes the lock under key until expires, in milliseconds since the Unix epoch
+    Lease {
+        key: String,
+        token: u64,
+        expires: u64,
+    },
+    /// Releases the lock under key held with token
+    Unlock {
+        key: String,
+        token: u64,
+    },
+    /// Marks missing records up to a sequence number as dropped by compaction; not numbered
+    Compacted {
+        through: u64,
+    },
+}
+
+impl Record {
+    /// Returns the key whose value the record writes, if any
+    pub(crate) fn valued_key(&self) -> Option<&str> {
+        match self {
+            Self::Set { key, .. }
+            | Self::Append { key, .. }
+            | Self::Rename { to: key, .. }
+            | Self::BucketSet { key, .. } => Some(key),
+            _ => None,
+        }
+    }
+
+    /// Returns the fields of the record, starting with its tag
+    pub(crate) fn fields(&self) -> Vec<Cow<'_, str>> {
+        match self {
+            Self::Set {
+                key,
+                value,
+                version: None,
+                expires: None,
+            } => vec!["set".into(), key.into(), value.into()],
+            Self::Set {
+                key,
+                value,
+                version: Some(version),
+                expires: None,
+            } => vec![
+                "vset".into(),
+                key.into(),
+                version.to_string().into(),
+                value.into(),
+            ],
+            Self::Set {
+                key,
+                value,
+                version,
+                expires: Some(expires),
+            } => vec![
+                "xset".into(),
+                key.into(),
+                version.unwrap_or(1).to_string().into(),
+                expires.to_string().into(),
+                value.into(),
+            ],
+            Self::Append { key, suffix } => vec!["append".into(), key.into(), suffix.into()],
+            Self::Rm { key } => vec!["rm".into(), key.into()],
+            Self::RmPrefix { prefix } => vec!["rmp".into(), prefix.into()],
+            Self::Rename { from, to, value } => {
+                vec!["mv".into(), from.into(), to.into(), value.into()]
+            }
+            Self::BucketSet { bucket, key, value } => {
+                vec!["bset".into(), bucket.into(), key.into(), value.into()]
+            }
+            Self::BucketRm { bucket, key } => vec!["brm".into(), bucket.into(), key.into()],
+            Self::ReserveIds { sequence, end } => {
+                vec!["id".into(), sequence.into(), end.to_string().into()]
+            }
+            Self::Sample {
+                key,
+                timestamp,
+                value,
+            } => vec![
+                "ts".into(),
+                key.into(),
+                timestamp.to_string().into(),
+                value.to_string().into(),
+            ],
+            Self::ListPush { key, value } => vec!["lpush".into(), key.into(), value.into()],
+            Self::ListPop { key } => vec!["rpop".into(), key.into()],
+            Self::SetAdd { key, member } => vec!["sadd".into(), key.into(), member.into()],
+            Self::SetRemove { key, member } => vec!["srem".into(), key.into(), member.into()],
+            Self::Lease {
+                key,
+                token,
+                expires,
+            } => vec![
+                "lease".into(),
+                key.into(),
+                token.to_string().into(),
+                expires.to_string().into(),
+            ],
+            Self::Unlock { key, token } => {
+                vec!["unlock".into(), key.into(), token.to_string().into()]
+            }
+            Self::Compacted { through } => vec!["compacted".into(), through.to_string().into()],
+        }
+    }
+}
+
+/// Parses the fields of a record into the command replaying it
+fn command(fields: Vec<String>) -> serde_json::Result<Command> {
+    let fields = fields.into_iter().map(serde_json::Value::String).collect();
+    serde_json::from_value(serde_json::Value::Array(fields))
+}
+
+/// Parses a text record line into the command replaying it
+///
+/// # Errors
+/// Returns `Err` if the line is not a valid record
+pub(crate) fn parse(line: &str) -> serde_json::Result<Command> {
+    decode(WalFormat::Text, line.as_bytes()).map(|(_, cmd)| cmd)
+}
+
+/// Decodes a record frame, without the newline ending it, into its stamp and the command
+/// replaying it
+///
+/// A carriage return before the newline, as left by tools converting line endings to those of
+/// Windows, is ignored: frames of any format never end with one.
+///
+/// # Errors
+/// Returns `Err` if the frame is not a valid record
+pub(crate) fn decode(format: WalFormat, frame: &[u8]) -> serde_json::Result<(Stamp, Command)> {
+    let frame = frame.strip_suffix(b"\r").unwrap_or(frame);
+    let (stamp, fields) = format.codec().decode_checked(frame)?;
+    Ok((stamp, command(fields)?))
+}
+
+/// Encodes a record into its frame, ended by a newline
+pub(crate) fn encode(format: WalFormat, stamp: &Stamp, record: &Record) -> Vec<u8> {
+    let mut frame = Vec::new();
+    format.codec().encode(stamp, &record.fields(), &mut frame);
+    frame.push(b'\n');
+    frame
+}
+
+/// Request to the writer thread, acknowledged once carried out
+enum Job {
+    Append(Record, Option<String>, mpsc::SyncSender<Result<()>>),
+    Resume(Stamp),
+    Sync(mpsc::SyncSender<Result<()>>),
+    Track(mpsc::SyncSender<Result<()>>),
+    /// Ship records from those numbered from a sequence number on if given, or else from a
+    /// snapshot of the live records
+    Subscribe(Option<u64>, mpsc::Sender<Shipment>),
+    /// Compact, or abandon the running compaction, once the batch is committed
+    Compact(Request, CompactionAck),
+    /// Take the base segment with the given ID at path as the first segment of the log
+    Adopt(u64, PathBuf, Hints, mpsc::SyncSender<Result<()>>),
+    /// Check on coalesced writes once the window just opened elapses
+    Wake,
+}
+
+/// Acknowledgement of a [`Job::Compact`], with the last sequence number covered by the base
+/// segment written if any
+type CompactionAck = mpsc::SyncSender<Result<Option<u64>>>;
+
+/// What a [`Job::Compact`] asks of compaction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Request {
+    /// Compact if the log holds dead records
+    Compact,
+    /// Compact even without dead records
+    Force,
+    /// Abandon the running compaction, if any
+    Abandon,
+}
+
+/// Newline-terminated WAL records in the text format, shipped to a subscriber of the log, see
+/// [`KvStore::tail`](crate::KvStore::tail)
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Shipment {
+    /// Live records of the log when subscribing, in log order, and the sequence number of the
+    /// last record written then; they replace any records received before
+    Snapshot(String, u64),
+    /// Records committed since, in log order, and the sequence number of the last one
+    Committed(String, u64),
+}
+
+impl Shipment {
+    /// Splits the shipment into shipments of whole records of at most `budget` bytes each,
+    /// unless a single record is longer, so that each fits in a protocol frame
+    ///
+    /// A snapshot splits into a snapshot followed by committed records. Each part is numbered
+    /// by its last record, so that copying can resume after any of them.
+    pub(crate) fn split(self, budget: usize) -> Vec<Self> {
+        let (records, sequence) = match &self {
+            Self::Snapshot(records, sequence) | Self::Committed(records, sequence) => {
+                (records, *sequence)
+            }
+        };
+        if records.len() <= budget {
+            return vec![self];
+        }
+
+        let mut parts = Vec::new();
+        let mut rest = records.as_str();
+        while !rest.is_empty() {
+            let end = if rest.len() <= budget {
+                rest.len()
+            } else {
+                // Records end at newlines, which are char boundaries
+                let bytes = rest.as_bytes();
+                bytes[..budget]
+                    .iter()
+                    .rposition(|&b| b == b'\n')
+                    .or_else(|| bytes.iter().position(|&b| b == b'\n'))
+                    .map_or(rest.len(), |newline| newline + 1)
+            };
+            let (part, next) = rest.split_at(end);
+            let numbered = if next.is_empty() {
+                sequence
+            } else {
+                part.lines()
+                    .next_back()
+                    .and_then(|frame| decode(WalFormat::Text, frame.as_bytes()).ok())
+                    .and_then(|(stamp, _)| stamp.sequence)
+                    .unwrap_or(sequence)
+            };
+            parts.push(match (&self, parts.is_empty()) {
+                (Self::Snapshot(..), true) => Self::Snapshot(part.to_owned(), numbered),
+                _ => Self::Committed(part.to_owned(), numbered),
+            });
+            rest = next;
+        }
+        parts
+    }
+}
+
+/// Acknowledgement of a queued record, received once it is written
+pub(crate) struct Pending(mpsc::Receiver<Result<()>>);
+
+impl Pending {
+    /// Blocks until the record is written
+    ///
+    /// # Errors
+    /// Returns `Err` if `write_all` fails, the record removes a key without logged value, or
+    /// the writer thread is gone
+    pub(crate) fn wait(self) -> Result<()> {
+        self.0.recv().unwrap_or_else(|_| Err(writer_gone()))
+    }
+}
+
+/// Handle to the WAL writer thread, holding back coalesced writes
+pub(crate) struct Wal {
+    jobs: Option<mpsc::Sender<Job>>,
+    writer: Option<JoinHandle<()>>,
+    log: Arc<RwLock<Log>>,
+    /// Writes held back, shared with the writer logging them once their window elapses
+    coalescer: Arc<Mutex<Coalescer>>,
+    coalesce_window: Option<Duration>,
+    /// Whether the writer logs coalesced writes, and so is woken when a window opens
+    writer_coalesces: bool,
+    /// Whether records are being replayed into the log, which are never coalesced
+    replaying: AtomicBool,
+    clock: Arc<dyn Clock>,
+    /// Bytes of records below which compaction is never attempted, shared with the writer
+    compaction_threshold: Arc<AtomicU64>,
+    /// Controls of background compaction, shared with the writer
+    compaction: Arc<Control>,
+}
+
+impl Wal {
+    /// Starts the writer thread appending to the freshly created active segment in dirs
+    ///
+    /// The active segment gets the given ID, which must be above those of existing segments.
+    /// Records are written in the given format, which must be that of existing segments.
+    ///
+    /// # Errors
+    /// Returns `Err` if the log cannot be opened for reading or the writer thread cannot be
+    /// spawned
+    pub(crate) fn new(
+        dirs: Dirs,
+        handle: File,
+        active: u64,
+        format: WalFormat,
+        options: &OpenOptions,
+        #[cfg(feature = "metrics")] metrics: Arc<Metrics>,
+    ) -> Result<Self> {
+        let path = dirs.active_path();
+        let segment = Segment::open(path.clone()).map_err(KvStoreError::FailedWalOpen)?;
+        let mut free = dirs.free_files().map_err(KvStoreError::FailedWalOpen)?;
+        if !options.preallocate_segments {
+            for path in free.drain(..) {
+                fs::remove_file(path).map_err(KvStoreError::FailedWalOpen)?;
+            }
+        }
+        let log = Arc::new(RwLock::new(Log {
+            index: Index::new(options.key_index),
+            segments: BTreeMap::from([(active, segment)]),
+            usage: Usage::default(),
+            sequence: 0,
+            history: History::new(options.history_retention),
+            format,
+        }));
+
+        let (jobs, queue) = mpsc::channel();
+        let coalescer = Arc::default();
+        let compaction_threshold = Arc::new(AtomicU64::new(options.compaction_threshold));
+        let compaction = Arc::new(Control::new(
+            options.compaction_throttle,
+            options
+                .runtime
+                .as_ref()
+                .and_then(KvsRuntime::compaction_throttle),
+        ));
+        let writer = Writer {
+            path,
+            dirs,
+            handle,
+            active,
+            active_len: 0,
+            active_keys: Vec::new(),
+            len: 0,
+            dead: 0,
+            segment_size: options.segment_size,
+            compaction_threshold: Arc::clone(&compaction_threshold),
+            compaction: Arc::clone(&compaction),
+            policy: Arc::clone(&options.compaction_policy),
+            segments: BTreeMap::from([(active, SegmentUsage::new(options.clock.now()))]),
+            running: None,
+            waiting: Vec::new(),
+            snapshot: None,
+            tracking: false,
+            subscribers: Vec::new(),
+            // A runtime logs coalesced writes from its own thread
+            coalescing: options
+                .coalesce_window
+                .filter(|_| options.runtime.is_none())
+                .map(|window| (Arc::clone(&coalescer), window)),
+            runtime: options.runtime.clone(),
+            next_sequence: 1,
+            resumed: None,
+            log: Arc::clone(&log),
+            group_commit: options.group_commit,
+            write_through: options.write_through,
+            preallocate: options.preallocate_segments,
+            checksum_records: options.checksum_records,
+            free: Arc::new(Free::new(free)),
+            clock: Arc::clone(&options.clock),
+            #[cfg(feature = "archive")]
+            archive_segments: options.archive_segments,
+            #[cfg(feature = "metrics")]
+            metrics,
+        };
+        let writer = thread::Builder::new()
+            .name("kvs-wal".to_owned())
+            .spawn(move || writer.run(&queue))
+            .map_err(KvStoreError::FailedWalOpen)?;
+
+        Ok(Self {
+            jobs: Some(jobs),
+            writer: Some(writer),
+            log,
+            coalescer,
+            coalesce_window: options.coalesce_window,
+            writer_coalesces: options.runtime.is_none(),
+            replaying: AtomicBool::new(true),
+            clock: Arc::clone(&options.clock),
+            compaction_threshold,
+            compaction,
+        })
+    }
+
+    /// Returns the controls of background compaction
+    pub(crate) fn compaction(&self) -> &Control {
+        &self.compaction
+    }
+
+    /// Sets the size of the log below which it is never compacted, from the next commit on
+    pub(crate) fn set_compaction_threshold(&self, bytes: u64) {
+        self.compaction_threshold.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Queues a record for the writer thread without waiting for it to be written
+    ///
+    /// The record is attributed to the client of the calling thread, if any.
+    pub(crate) fn append(&self, record: Record) -> Pending {
+        let (ack, pending) = mpsc::sync_channel(1);
+        let client = CLIENT.with(|c| c.borrow().clone());
+        self.send(Job::Append(record, client, ack));
+        Pending(pending)
+    }
+
+    /// Appends a record and waits until it is written
+    ///
+    /// # Errors
+    /// Returns `Err` if `write_all` fails
+    pub(crate) fn write(&self, record: Record) -> Result<()> {
+        self.append(record).wait()
+    }
+
+    /// Stamps the next numbered record appended with the timestamp and client of stamp, and
+    /// its sequence number if any, unless records were numbered past it
+    ///
+    /// Replayed records keep their stamps this way, until the log is tracked.
+    pub(crate) fn resume(&self, stamp: Stamp) {
+        self.send(Job::Resume(stamp));
+    }
+
+    /// Syncs written records to disk
+    ///
+    /// # Errors
+    /// Returns `Err` if `sync_data` fails
+    pub(crate) fn sync_data(&self) -> Result<()> {
+        let (ack, pending) = mpsc::sync_channel(1);
+        self.send(Job::Sync(ack));
+        Pending(pending).wait()
+    }
+
+    /// Takes the base segment with the given ID at path, left by an earlier compaction, as the
+    /// first segment of the log in place, indexing its records as located by hints instead of
+    /// having them replayed
+    ///
+    /// Only called before any record is replayed.
+    ///
+    /// # Errors
+    /// Returns `Err` if the base segment cannot be opened
+    pub(crate) fn adopt(&self, id: u64, path: PathBuf, hints: Hints) -> Result<()> {
+        let (ack, pending) = mpsc::sync_channel(1);
+        self.send(Job::Adopt(id, path, hints, ack));
+        Pending(pending).wait()
+    }
+
+    /// Starts recording the segments in the manifest, once the log holds the whole store
+    ///
+    /// Writes are coalesced from then on, if enabled.
+    ///
+    /// # Errors
+    /// Returns `Err` if the manifest cannot be written
+    pub(crate) fn track(&self) -> Result<()> {
+        self.replaying.store(false, Ordering::Release);
+        let (ack, pending) = mpsc::sync_channel(1);
+        self.send(Job::Track(ack));
+        Pending(pending).wait()
+    }
+
+    /// Subscribes to the records written to the log, starting with a snapshot of its live
+    /// records
+    ///
+    /// The subscription ends when the receiver is dropped, or if the snapshot cannot be read.
+    pub(crate) fn subscribe(&self) -> mpsc::Receiver<Shipment> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Job::Subscribe(None, tx));
+        rx
+    }
+
+    /// Subscribes to the records written to the log, starting with those numbered from
+    /// sequence number from on, or a snapshot of its live records if compaction dropped some
+    /// of them
+    ///
+    /// The subscription ends when the receiver is dropped, or if the log cannot be read.
+    pub(crate) fn tail(&self, from: u64) -> mpsc::Receiver<Shipment> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Job::Subscribe(Some(from), tx));
+        rx
+    }
+
+    /// Logs all writes held back by the coalescer, then syncs written records to disk
+    ///
+    /// # Errors
+    /// Returns `Err` if `write_all` or `sync_data` fails
+    pub(crate) fn flush(&self) -> Result<()> {
+        self.write_coalesced()?;
+        self.sync_data()
+    }
+
+    /// Writes the writes held back by coalescing, if enabled, without syncing them
+    ///
+    /// # Errors
+    /// Returns `Err` if writing fails
+    pub(crate) fn write_coalesced(&self) -> Result<()> {
+        match self.coalescer() {
+            Some(mut coalescer) => self.write_pending(&mut coalescer),
+            None => Ok(()),
+        }
+    }
+
+    /// Compacts the log now regardless of its size, if it holds dead records or forced,
+    /// returning the last sequence number covered by the base segment written if any
+    ///
+    /// A forced compaction rewrites the live records as a new base segment even if none are
+    /// dead, so that replay starts from it.
+    ///
+    /// # Errors
+    /// Returns `Err` if compaction fails, leaving the log as it was
+    pub(crate) fn compact(&self, force: bool) -> Result<Option<u64>> {
+        let request = if force {
+            Request::Force
+        } else {
+            Request::Compact
+        };
+        let (ack, compacted) = mpsc::sync_channel(1);
+        self.send(Job::Compact(request, ack));
+        compacted.recv().unwrap_or_else(|_| Err(writer_gone()))
+    }
+
+    /// Lets automatic compactions run again, and starts compacting the log in the background
+    /// if it holds dead records, without waiting
+    pub(crate) fn start_compaction(&self) {
+        self.compaction.start();
+        let (ack, _) = mpsc::sync_channel(1);
+        self.send(Job::Compact(Request::Compact, ack));
+    }
+
+    /// Stops automatic compactions if stop is set, and abandons the running compaction, if
+    /// any, leaving the log as it was
+    ///
+    /// # Errors
+    /// Returns `Err` if the writer thread is gone
+    pub(crate) fn abandon_compaction(&self, stop: bool) -> Result<()> {
+        if stop {
+            self.compaction.stop();
+        }
+        let (ack, abandoned) = mpsc::sync_channel(1);
+        self.send(Job::Compact(Request::Abandon, ack));
+        abandoned
+            .recv()
+            .unwrap_or_else(|_| Err(writer_gone()))
+            .map(|_| ())
+    }
+
+    /// Sends a job, dropping it if the writer is gone so that its acknowledgement fails
+    fn send(&self, job: Job) {
+        if let Some(jobs) = &self.jobs {
+            let _ = jobs.send(job);
+        }
+    }
+
+    /// Reads the logged value of a key in the given bucket, or the default key space
+    ///
+    /// The index covers every segment and lives in memory, so a key without value is answered
+    /// without touching any segment; no per-segment filter is needed to skip reads.
+    ///
+    /// # Errors
+    /// Returns `Err` if reading or parsing the record fails
+    pub(crate) fn get(&self, bucket: Option<&str>, key: &str) -> Result<Option<String>> {
+        let log = read(&self.log);
+        log.index
+            .keys(bucket)
+            .and_then(|keys| keys.get(key))
+            .filter(|extent| log.may_contain(extent.segment, key))
+            .map(|extent| log.read_value(bucket, key, *extent))
+            .transpose()
+    }
+
+    /// Returns the keys with a logged value of the given bucket, or the default key space, that
+    /// start with prefix
+    pub(crate) fn keys(&self, bucket: Option<&str>, prefix: &str) -> Vec<String> {
+        read(&self.log)
+            .index
+            .keys(bucket)
+            .into_iter()
+            .flat_map(|keys| keys.iter_prefix(prefix))
+            .map(|(key, _)| key.into_owned())
+            .collect()
+    }
+
+    /// Returns whether key of the given bucket, or the default key space, has a logged value
+    pub(crate) fn contains(&self, bucket: Option<&str>, key: &str) -> bool {
+        read(&self.log)
+            .index
+            .keys(bucket)
+            .is_some_and(|keys| keys.contains_key(key))
+    }
+
+    /// Returns the number of keys with a logged value in the given bucket, or the default key
+    /// space
+    pub(crate) fn count(&self, bucket: Option<&str>) -> usize {
+        read(&self.log).index.keys(bucket).map_or(0, KeyMap::len)
+    }
+
+    /// Reads logged key-value pairs of the given bucket, or the default key space, whose keys
+    /// start with prefix, sorted by key
+    ///
+    /// # Errors
+    /// Returns `Err` if reading or parsing a record fails
+    pub(crate) fn scan(&self, bucket: Option<&str>, prefix: &str) -> Result<Vec<(String, String)>> {
+        let log = read(&self.log);
+        let mut entries = log
+            .index
+            .keys(bucket)
+            .into_iter()
+            .flat_map(|keys| keys.iter_prefix(prefix))
+            .map(|(key, extent)| {
+                let value = log.read_value(bucket, &key, *extent)?;
+                Ok((key.into_owned(), value))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        entries.sort_unstable();
+
+        Ok(entries)
+    }
+
+    /// Returns the log size accounting
+    pub(crate) fn usage(&self) -> Usage {
+        read(&self.log).usage
+    }
+
+    /// Returns the sequence number of the last record written, 0 if none
+    pub(crate) fn sequence(&self) -> u64 {
+        read(&self.log).sequence
+    }
+
+    /// Returns the format records are written in
+    pub(crate) fn format(&self) -> WalFormat {
+        read(&self.log).format
+    }
+
+    /// Returns the value of key in the default key space as of the record with sequence number
+    ///
+    /// # Errors
+    /// Returns `Err` if history is disabled or not retained as of sequence
+    pub(crate) fn get_at(&self, key: &str, sequence: u64) -> Result<Option<String>> {
+        let log = read(&self.log);
+        log.history.get_at(key, sequence, log.sequence)
+    }
+
+    /// Returns the retained revisions of key in the default key space, in log order
+    ///
+    /// # Errors
+    /// Returns `Err` if history is disabled
+    pub(crate) fn history(&self, key: &str) -> Result<Vec<Revision>> {
+        let log = read(&self.log);
+        log.history.revisions(key, log.sequence)
+    }
+
+    /// Returns the names of buckets holding keys
+    pub(crate) fn bucket_names(&self) -> Vec<String> {
+        read(&self.log).index.buckets.keys().cloned().collect()
+    }
+
+    /// Returns the number of segments, including the active one
+    pub(crate) fn segment_count(&self) -> usize {
+        read(&self.log).segments.len()
+    }
+
+    /// Returns the approximate bytes of memory taken by the index of live records
+    pub(crate) fn index_bytes(&self) -> u64 {
+        read(&self.log).index.bytes()
+    }
+
+    /// Returns the number of keys with a logged value, across the default key space and
+    /// buckets
+    pub(crate) fn key_count(&self) -> usize {
+        let index = &read(&self.log).index;
+        index.keys.len() + index.buckets.values().map(KeyMap::len).sum::<usize>()
+    }
+
+    /// Locks the coalescer if write coalescing is enabled and records are not being replayed
+    ///
+    /// Holding the lock while applying a write in memory keeps memory and log order in step.
+    pub(crate) fn coalescer(&self) -> Option<MutexGuard<'_, Coalescer>> {
+        if self.replaying.load(Ordering::Acquire) {
+            return None;
+        }
+        self.coalesce_window.map(|_| {
+            self.coalescer
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+        })
+    }
+
+    /// Holds back a write in the coalescer, logging all pending writes once the window elapses
+    ///
+    /// `value` holds the new value of key and its version, or `None` for a removal. `existed`
+    /// tells whether the key had a value before this write.
+    pub(crate) fn coalesce(
+        &self,
+        coalescer: &mut Coalescer,
+        key: String,
+        value: Option<(String, u64)>,
+        existed: bool,
+    ) -> Result<()> {
+        let now = self.clock.now();
+        let opening = self.writer_coalesces && !coalescer.is_open();
+        coalescer.push(key, value, existed, now);
+        if opening {
+            self.send(Job::Wake);
+        }
+        self.write_expired(coalescer)
+    }
+
+    /// Logs pending writes if their coalescing window has elapsed
+    ///
+    /// # Errors
+    /// Returns `Err` if `write_all` fails
+    pub(crate) fn flush_expired(&self) -> Result<()> {
+        match self.coalescer() {
+            Some(mut coalescer) => self.write_expired(&mut coalescer),
+            None => Ok(()),
+        }
+    }
+
+    fn write_expired(&self, coalescer: &mut Coalescer) -> Result<()> {
+        match self.coalesce_window {
+            Some(window) if coalescer.expired(window, self.clock.now()) => {
+                self.write_pending(coalescer)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes all writes held back by the coalescer
+    pub(crate) fn write_pending(&self, coalescer: &mut Coalescer) -> Result<()> {
+        let pending: Vec<_> = coalescer.take().map(|record| self.append(record)).collect();
+
+        pending.into_iter().try_for_each(Pending::wait)
+    }
+}
+
+impl Drop for Wal {
+    fn drop(&mut self) {
+        debug!("Flushing coalesced writes...");
+        let mut coalescer = mem::take(
+            &mut *self
+                .coalescer
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        if let Err(e) = self.write_pending(&mut coalescer) {
+            error!("Failed to write coalesced writes to WAL: {e}");
+        }
+
+        // Closing the queue lets the writer drain it, sync, and exit, abandoning any compaction
+        self.compaction.cancel();
+        drop(self.jobs.take());
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                error!("WAL writer thread panicked");
+            }
+        }
+    }
+}
+
+/// Error for a job whose writer thread has exited
+fn writer_gone() -> KvStoreError {
+    KvStoreError::FailedWalWrite(io::Error::other("WAL writer thread stopped"))
+}
+
+/// Interval at which an idle writer checks on a running compaction
+const COMPACTION_POLL: Duration = Duration::from_millis(10);
+
+/// Records copied by compaction between checks of its pace
+const COMPACTION_CHUNK: usize = 256;
+
+/// Segment files kept zeroed for recycling as active segments, when preallocating
+const FREE_SEGMENTS: usize = 4;
+
+/// Approximate bytes of memory an index entry takes besides its key
+const ENTRY_BYTES: u64 = 64;
+
+/// Approximate bytes of memory an extent of a list takes
+const EXTENT_BYTES: u64 = 24;
+
+/// Where each record copied by a compaction moved to in the base segment, by segment and
+/// offset, and the bytes of records in the base segment
+type Copied = (HashMap<(u64, u64), u64>, u64, Bloom);
+
+/// Size and age of a segment, as tracked by the writer
+#[derive(Clone, Copy, Debug)]
+struct SegmentUsage {
+    /// Bytes of records
+    len: u64,
+    /// Bytes of records superseded by later ones
+    dead: u64,
+    created: SystemTime,
+    /// Sequence number of the last record written when the segment was sealed
+    last_sequence: u64,
+    base: bool,
+}
+
+impl SegmentUsage {
+    /// Returns the usage of an empty segment started at created
+    fn new(created: SystemTime) -> Self {
+        Self {
+            len: 0,
+            dead: 0,
+            created,
+            last_sequence: 0,
+            base: false,
+        }
+    }
+
+    /// Returns the information a compaction policy gets about the segment with the given ID
+    fn info(&self, id: u64) -> SegmentInfo {
+        SegmentInfo {
+            id,
+            live_bytes: self.len - self.dead,
+            dead_bytes: self.dead,
+            created: self.created,
+            base: self.base,
+        }
+    }
+}
+
+/// Compaction copying live records to a new base segment in the background
+#[derive(Debug)]
+struct Running {
+    /// ID of the base segment, that of the last segment it replaces
+    base: u64,
+    /// Bytes of records of the segments it replaces, when it started
+    len: u64,
+    /// Bytes of dead records of the segments it replaces, when it started
+    dead: u64,
+    /// Last sequence number of the segments it replaces
+    through: u64,
+    /// Acknowledgements of the compactions requested from it
+    acks: Vec<CompactionAck>,
+    copying: Copying,
+}
+
+/// Records being copied to a base segment on another thread, and the outcome once over
+#[derive(Debug)]
+struct Copying {
+    done: mpsc::Receiver<io::Result<Copied>>,
+    copied: Option<io::Result<Copied>>,
+}
+
+impl Copying {
+    /// Returns whether copying is over, keeping its outcome
+    fn is_finished(&mut self) -> bool {
+        if self.copied.is_none() {
+            self.copied = match self.done.try_recv() {
+                Ok(copied) => Some(copied),
+                Err(mpsc::TryRecvError::Empty) => None,
+                Err(mpsc::TryRecvError::Disconnected) => Some(Err(copying_panicked())),
+            };
+        }
+        self.copied.is_some()
+    }
+
+    /// Waits for copying to be over, returning its outcome
+    fn join(self) -> io::Result<Copied> {
+        match self.copied {
+            Some(copied) => copied,
+            None => self.done.recv().unwrap_or_else(|_| Err(copying_panicked())),
+        }
+    }
+}
+
+/// Error of a compaction whose copying thread panicked
+fn copying_panicked() -> io::Error {
+    io::Error::other("compaction thread panicked")
+}
+
+/// Compacted segment files zeroed for recycling as active segments, shared with the threads
+/// zeroing them
+#[derive(Debug, Default)]
+struct Free {
+    files: Mutex<FreeFiles>,
+    /// Notified whenever a file is done zeroing
+    zeroed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct FreeFiles {
+    /// Files zeroed, ready for recycling
+    zeroed: Vec<PathBuf>,
+    /// Number of files being zeroed
+    zeroing: usize,
+}
+
+impl Free {
+    fn new(zeroed: Vec<PathBuf>) -> Self {
+        Self {
+            files: Mutex::new(FreeFiles { zeroed, zeroing: 0 }),
+            zeroed: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, FreeFiles> {
+        self.files.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Takes a zeroed file, if any
+    fn pop(&self) -> Option<PathBuf> {
+        self.lock().zeroed.pop()
+    }
+
+    /// Reserves room for a file to zero, unless [`FREE_SEGMENTS`] are kept or being zeroed
+    fn reserve(&self) -> bool {
+        let mut files = self.lock();
+        let room = files.zeroed.len() + files.zeroing < FREE_SEGMENTS;
+        files.zeroing += usize::from(room);
+        room
+    }
+
+    /// Zeroes the file at path to len bytes, then renames it to free for recycling, into room
+    /// reserved for it
+    ///
+    /// A file that cannot be zeroed is deleted instead.
+    fn zero(&self, path: &Path, free: PathBuf, len: u64) {
+        match segment::zero(path, len).and_then(|()| fs::rename(path, &free)) {
+            Ok(()) => self.lock().zeroed.push(free),
+            Err(e) => {
+                error!(path = %path.display(), "Failed to zero compacted segment: {e}");
+                let _ = fs::remove_file(path);
+            }
+        }
+        self.release();
+    }
+
+    /// Releases room reserved for a file, once zeroed or not
+    fn release(&self) {
+        self.lock().zeroing -= 1;
+        self.zeroed.notify_all();
+    }
+
+    /// Blocks until no file is being zeroed
+    fn wait(&self) {
+        let files = self.lock();
+        drop(
+            self.zeroed
+                .wait_while(files, |files| files.zeroing > 0)
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+    }
+}
+
+/// Copies the records at extents, in log order, to a base segment at path after a `compacted`
+/// marker of the last sequence number through, paced by control, then writes its hint file at
+/// `hint_path` and its filter at `bloom_path`
+///
+/// # Errors
+/// Returns `Err` if reading or writing fails, or with [`io::ErrorKind::Interrupted`] if the
+/// compaction was abandoned
+fn write_base(
+    log: &RwLock<Log>,
+    extents: &[Extent],
+    through: u64,
+    (path, hint_path, bloom_path): (&Path, &Path, &Path),
+    control: &Control,
+) -> io::Result<Copied> {
+    let started = Instant::now();
+    let mut moved = HashMap::with_capacity(extents.len());
+    let header_len = segment::base_header(0, 0).len() as u64;
+    let format = read(log).format;
+    let marker = encode(format, &Stamp::default(), &Record::Compacted { through });
+
+    // The header is written once the checksum of the records is known
+    let mut compacted = BufWriter::new(File::create(path)?);
+    compacted.write_all(segment::base_header(0, 0).as_bytes())?;
+    let mut checksum = crc32fast::Hasher::new();
+    checksum.update(&marker);
+    compacted.write_all(&marker)?;
+    let mut offset = header_len + marker.len() as u64;
+    let mut copied = 0;
+    let mut hints = Vec::new();
+    let mut keys = Vec::new();
+    let mut last_sequence = 0;
+    for chunk in extents.chunks(COMPACTION_CHUNK) {
+        if control.cancelled() {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "compaction abandoned",
+            ));
+        }
+        // The segments before the base one stay in place until it is swapped in
+        let log = read(log);
+        for extent in chunk {
+            let record = log.read(*extent)?;
+            let frame = record.strip_suffix(b"\n").unwrap_or(&record);
+            let (stamp, fields) = format
+                .codec()
+                .decode_checked(frame)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            last_sequence = stamp.sequence.unwrap_or(last_sequence);
+            hint::encode(&fields, offset, extent.len, &mut hints);
+            keys.extend(bloom::valued_key(&fields).map(str::to_owned));
+            checksum.update(&record);
+            compacted.write_all(&record)?;
+            moved.insert((extent.segment, extent.offset), offset);
+            offset += extent.len;
+            copied += extent.len;
+        }
+        drop(log);
+        control.pace(copied, started);
+    }
+    let mut compacted = compacted
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?;
+    compacted.seek(SeekFrom::Start(0))?;
+    let checksum = checksum.finalize();
+    let header = segment::base_header(checksum, offset - header_len);
+    compacted.write_all(header.as_bytes())?;
+    compacted.sync_all()?;
+    hint::write(
+        hint_path,
+        &hints,
+        (checksum, offset - header_len),
+        (last_sequence, through),
+    )?;
+    let filter = Bloom::of(&keys);
+    filter.write(bloom_path)?;
+
+    Ok((moved, offset - header_len, filter))
+}
+
+fn read(log: &RwLock<Log>) -> RwLockReadGuard<'_, Log> {
+    log.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Locations of the records needed to rebuild the current state
+#[derive(Debug)]
+struct Index {
+    /// Latest `set` per key
+    keys: KeyMap<Extent>,
+    /// Appends since the latest `set` per key, in log order
+    appends: HashMap<String, Vec<Extent>>,
+    /// Latest `bset` per key, per bucket
+    buckets: HashMap<String, KeyMap<Extent>>,
+    /// Latest reservation per ID sequence
+    sequences: HashMap<String, Extent>,
+    /// Every timeseries sample, in log order
+    samples: Vec<Extent>,
+    /// Pushes of the values of each list, in log order, so the back of the list first
+    lists: HashMap<String, VecDeque<Extent>>,
+    /// Latest addition of each member, per set
+    sets: HashMap<String, HashMap<String, Extent>>,
+    /// Latest lease per lock, until released
+    locks: HashMap<String, Extent>,
+    /// Approximate bytes of memory taken by the above but the maps of keys
+    bytes: u64,
+    /// Kind of the maps of keys
+    kind: KeyIndex,
+}
+
+/// Returns the approximate bytes of memory an index entry for key takes
+fn entry_bytes(key: &str) -> u64 {
+    key.len() as u64 + ENTRY_BYTES
+}
+
+impl Index {
+    fn new(key_index: KeyIndex) -> Self {
+        Self {
+            keys: KeyMap::new(key_index),
+            appends: HashMap::new(),
+            buckets: HashMap::new(),
+            sequences: HashMap::new(),
+            samples: Vec::new(),
+            lists: HashMap::new(),
+            sets: HashMap::new(),
+            locks: HashMap::new(),
+            bytes: 0,
+            kind: key_index,
+        }
+    }
+
+    /// Returns the approximate bytes of memory taken by the index
+    fn bytes(&self) -> u64 {
+        self.bytes + self.keys.bytes() + self.buckets.values().map(KeyMap::bytes).sum::<u64>()
+    }
+
+    /// Returns the latest `set` per key of the given bucket, or the default key space
+    fn keys(&self, bucket: Option<&str>) -> Option<&KeyMap<Extent>> {
+        match bucket {
+            None => Some(&self.keys),
+            Some(bucket) => self.buckets.get(bucket),
+        }
+    }
+
+    fn extents(&self) -> impl Iterator<Item = &Extent> {
+        self.keys
+            .values()
+            .chain(self.appends.values().flatten())
+            .chain(self.buckets.values().flat_map(KeyMap::values))
+            .chain(self.sequences.values())
+            .chain(self.samples.iter())
+            .chain(self.lists.values().flatten())
+            .chain(self.sets.values().flat_map(HashMap::values))
+            .chain(self.locks.values())
+    }
+
+    fn extents_mut(&mut self) -> impl Iterator<Item = &mut Extent> {
+        self.keys
+            .values_mut()
+            .chain(self.appends.values_mut().flatten())
+            .chain(self.buckets.values_mut().flat_map(KeyMap::values_mut))
+            .chain(self.sequences.values_mut())
+            .chain(self.samples.iter_mut())
+            .chain(self.lists.values_mut().flatten())
+            .chain(self.sets.values_mut().flat_map(HashMap::values_mut))
+            .chain(self.locks.values_mut())
+    }
+}
+
+/// Index of live records, and read handles to the segments holding them
+///
+/// Shared between the writer, which alone modifies it, and readers.
+#[derive(Debug)]
+struct Log {
+    index: Index,
+    segments: BTreeMap<u64, Segment>,
+    usage: Usage,
+    /// Sequence number of the last record written, as of the last commit
+    sequence: u64,
+    history: History,
+    format: WalFormat,
+}
+
+/// Log size accounting, as of the last commit or compaction
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Usage {
+    /// Bytes in all segments
+    pub(crate) len: u64,
+    /// Bytes of records superseded by later ones
+    pub(crate) dead: u64,
+    /// When the log was last compacted, if it was since it was opened
+    pub(crate) last_compaction: Option<SystemTime>,
+    /// When written records were last synced to disk, if they were since the log was opened
+    pub(crate) last_sync: Option<SystemTime>,
+}
+
+impl Log {
+    /// Reads the `set`, `vset`, `xset`, `mv` or `bset` record of key in bucket at extent, along
+    /// with the appends to it since, and returns its value
+    fn read_value(&self, bucket: Option<&str>, key: &str, extent: Extent) -> Result<String> {
+        let mut value = self.read_field(extent, key, |fields| match (bucket, fields) {
+            (None, [tag, k]) => tag == "set" && k == key,
+            (None, [tag, k, version_or_to]) => {
+                (tag == "vset" && k == key) || (tag == "mv" && version_or_to == key)
+            }
+            (None, [tag, k, _, _]) => tag == "xset" && k == key,
+            (Some(bucket), [tag, b, k]) => tag == "bset" && b == bucket && k == key,
+            _ => false,
+        })?;
+        if bucket.is_none() {
+            for extent in self.index.appends.get(key).into_iter().flatten() {
+                value.push_str(&self.read_field(
+                    *extent,
+                    key,
+                    |fields| matches!(fields, [tag, k] if tag == "append" && k == key),
+                )?);
+            }
+        }
+        Ok(value)
+    }
+
+    /// Reads the record of key at extent and returns its last field, if matches accepts the
+    /// others
+    fn read_field(
+        &self,
+        extent: Extent,
+        key: &str,
+        matches: impl FnOnce(&[String]) -> bool,
+    ) -> Result<String> {
+        let invalid = || {
+            KvStoreError::FailedValueRead(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("record at {extent:?} is not a write of {key}"),
+            ))
+        };
+        let bytes = self.read(extent).map_err(KvStoreError::FailedValueRead)?;
+        let frame = bytes.strip_suffix(b"\n").ok_or_else(invalid)?;
+        let (_, mut fields) = (self.format.codec())
+            .decode_checked(frame)
+            .map_err(|_| invalid())?;
+        let value = fields.pop().ok_or_else(invalid)?;
+        if matches(&fields) {
+            Ok(value)
+        } else {
+            Err(invalid())
+        }
+    }
+
+    /// Returns the live records in the text format, in log order
+    fn live_records(&self) -> io::Result<String> {
+        let mut extents: Vec<_> = self.index.extents().copied().collect();
+        extents.sort_unstable_by_key(|e| (e.segment, e.offset));
+        let codec = self.format.codec();
+        let mut records = Vec::new();
+        for extent in extents {
+            let bytes = self.read(extent)?;
+            let frame = bytes.strip_suffix(b"\n").unwrap_or(&bytes);
+            let (stamp, fields) = codec
+                .decode_checked(frame)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let fields: Vec<_> = fields.into_iter().map(Cow::Owned).collect();
+            WalFormat::Text
+                .codec()
+                .encode(&stamp, &fields, &mut records);
+            records.push(b'\n');
+        }
+        String::from_utf8(records).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Returns the records numbered from sequence number from on in the text format, in log
+    /// order, or `None` if compaction may have dropped some of them
+    ///
+    /// Only the writer may call this, so that segments are not deleted while read.
+    fn records_since(&self, from: u64) -> io::Result<Option<String>> {
+        let codec = self.format.codec();
+        let mut records = Vec::new();
+        for segment in self.segments.values() {
+            let bytes = fs::read(segment.path())?;
+            // The header of a base segment is a line of its own
+            let frames = if segment::has_base_header(&bytes) {
+                bytes.splitn(2, |&b| b == b'\n').nth(1).unwrap_or_default()
+            } else {
+                &bytes[..segment::written_len(&bytes)]
+            };
+            for frame in frames.split(|&b| b == b'\n').filter(|f| !f.is_empty()) {
+                let (stamp, fields) = codec
+                    .decode_checked(frame)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                match stamp.sequence {
+                    Some(sequence) if sequence >= from => {
+                        let fields: Vec<_> = fields.into_iter().map(Cow::Owned).collect();
+                        WalFormat::Text
+                            .codec()
+                            .encode(&stamp, &fields, &mut records);
+                        records.push(b'\n');
+                    }
+                    Some(_) => {}
+                    // Starting from the first record, the live records make up for those
+                    // dropped
+                    None => {
+                        if let Ok(Command::Compacted { through }) = command(fields) {
+                            if from > 1 && through >= from {
+                                return Ok(None);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        String::from_utf8(records)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Returns whether the segment with the given ID may hold a value of key, as told by its
+    /// filter, so that a segment that cannot is not read
+    fn may_contain(&self, segment: u64, key: &str) -> bool {
+        self.segments
+            .get(&segment)
+            .is_none_or(|segment| segment.may_contain(key))
+    }
+
+    fn read(&self, extent: Extent) -> io::Result<Vec<u8>> {
+        self.segments
+            .get(&extent.segment)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "segment not found"))?
+            .read(extent)
+    }
+}
+
+/// Owner of the active segment, running on the writer thread
+#[allow(clippy::struct_excessive_bools)] // Independent settings of the writer
+struct Writer {
+    /// Path of the active segment
+    path: PathBuf,
+    /// Directories of the files of the store
+    dirs: Dirs,
+    handle: File,
+    /// ID of the active segment
+    active: u64,
+    /// Bytes in the active segment
+    active_len: u64,
+    /// Keys whose values the active segment holds, filtered once it is sealed
+    active_keys: Vec<String>,
+    /// Bytes of records in all segments
+    len: u64,
+    /// Bytes of records superseded by later ones
+    dead: u64,
+    segment_size: u64,
+    /// Bytes of records below which compaction is never attempted
+    compaction_threshold: Arc<AtomicU64>,
+    compaction: Arc<Control>,
+    policy: Arc<dyn CompactionPolicy>,
+    /// Size and age of every segment, including the active one
+    segments: BTreeMap<u64, SegmentUsage>,
+    /// Compaction running in the background, if any
+    running: Option<Running>,
+    /// Requested compactions waiting for the running one to finish
+    waiting: Vec<(Request, CompactionAck)>,
+    /// ID of the base segment, if the log was compacted
+    snapshot: Option<u64>,
+    /// Whether segment changes are recorded in the manifest
+    tracking: bool,
+    subscribers: Vec<mpsc::Sender<Shipment>>,
+    /// Writes held back by write coalescing, and its window, if logged by the writer
+    coalescing: Option<(Arc<Mutex<Coalescer>>, Duration)>,
+    /// Runtime running compactions on its thread pool, if any
+    runtime: Option<KvsRuntime>,
+    /// Sequence number of the next record
+    next_sequence: u64,
+    /// Timestamp and client of the next numbered record, if replayed
+    resumed: Option<Stamp>,
+    log: Arc<RwLock<Log>>,
+    group_commit: Option<Duration>,
+    /// Whether the active segment is opened with write-through, so written records are synced
+    write_through: bool,
+    /// Whether active segments get the segment size of disk space up front
+    preallocate: bool,
+    /// Compacted segment files zeroed for recycling as active segments, by compaction threads
+    free: Arc<Free>,
+    /// Whether records are written checked, and the end of the active segment recorded on close
+    checksum_records: bool,
+    clock: Arc<dyn Clock>,
+    /// Whether sealed and base segments are kept for the archiver
+    #[cfg(feature = "archive")]
+    archive_segments: bool,
+    #[cfg(feature = "metrics")]
+    metrics: Arc<Metrics>,
+}
+
+impl Writer {
+    /// Carries out jobs until every sender is dropped, then syncs the log
+    ///
+    /// Jobs are committed in batches: the first job waiting, every job queued behind it, and
+    /// with group commit every job arriving within the commit window.
+    fn run(mut self, queue: &mpsc::Receiver<Job>) {
+        loop {
+            // A running compaction and coalesced writes are checked on in between jobs
+            let first = if let Some(timeout) = self.idle_timeout() {
+                match queue.recv_timeout(timeout) {
+                    Ok(job) => job,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        self.compaction.set_load(0);
+                        self.flush_coalesced();
+                        self.schedule_compaction();
+                        continue;
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            } else {
+                match queue.recv() {
+                    Ok(job) => job,
+                    Err(_) => break,
+                }
+            };
+            let mut batch = vec![first];
+            let deadline = self.group_commit.map(|window| Instant::now() + window);
+            loop {
+                let next = match deadline {
+                    Some(deadline) => queue
+                        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                        .ok(),
+                    None => queue.try_recv().ok(),
+                };
+                match next {
+                    Some(job) => batch.push(job),
+                    None => break,
+                }
+            }
+            self.compaction.set_load(batch.len());
+            let requested = self.commit(batch);
+
+            if self.active_len >= self.segment_size {
+                if let Err(e) = self.seal() {
+                    error!("Failed to seal WAL segment: {e}");
+                }
+            }
+
+            self.waiting.extend(requested);
+            self.schedule_compaction();
+        }
+
+        if let Some(running) = self.running.take() {
+            self.compaction.cancel();
+            self.finish_compaction(running);
+        }
+        self.free.wait();
+        for (_, ack) in self.waiting.drain(..) {
+            let _ = ack.send(Err(writer_gone()));
+        }
+
+        debug!("Syncing to disk...");
+        if let Err(e) = self.trim().and_then(|()| self.handle.sync_all()) {
+            error!("Failed to sync all to WAL: {e}");
+        } else if self.checksum_records {
+            if let Err(e) = self.record_manifest(Some(self.active_len)) {
+                error!("Failed to record the end of the WAL: {e}");
+            }
+        }
+    }
+
+    /// Writes the records of a batch with a single `write_all`, then acknowledges every job
+    ///
+    /// Records are numbered in order. Removals of keys without a logged value are rejected
+    /// instead of written, since they would only pollute the log. The log is synced first if
+    /// group commit is enabled or the batch requests a sync, unless written through already.
+    ///
+    /// Returns the compactions requested, acknowledged once carried out.
+    #[allow(clippy::too_many_lines)] // One pass over the batch
+    fn commit(&mut self, batch: Vec<Job>) -> Vec<(Request, CompactionAck)> {
+        let mut buf = Vec::new();
+        let mut records = Vec::new();
+        let mut acks = Vec::with_capacity(batch.len());
+        let mut sync = self.group_commit.is_some();
+        let mut track = false;
+        let mut subscribers = Vec::new();
+        let mut compactions = Vec::new();
+        let mut adoptions = Vec::new();
+        let now = self.now_millis();
+
+        let log = Arc::clone(&self.log);
+        {
+            let log = read(&log);
+            let codec = log.format.codec();
+            // Whether keys, per bucket, have a value after the records of the batch so far
+            let mut live = HashMap::new();
+            for job in batch {
+                match job {
+                    Job::Append(record, client, ack) => {
+                        let removed = match &record {
+                            Record::Set { key, .. } | Record::Append { key, .. } => {
+                                live.insert((None, key.clone()), true);
+                                None
+                            }
+                            Record::BucketSet { bucket, key, .. } => {
+                                live.insert((Some(bucket.clone()), key.clone()), true);
+                                None
+                            }
+                            Record::Rm { key } => Some((None, key.clone())),
+                            Record::RmPrefix { prefix } => {
+                                for ((bucket, key), live) in &mut live {
+                                    if bucket.is_none() && key.starts_with(prefix.as_str()) {
+                                        *live = false;
+                                    }
+                                }
+                                None
+                            }
+                            // The old key may be compacted away already when replayed
+                            Record::Rename { from, to, .. } => {
+                                live.insert((None, from.clone()), false);
+                                live.insert((None, to.clone()), true);
+                                None
+                            }
+                            Record::BucketRm { bucket, key } => {
+                                Some((Some(bucket.clone()), key.clone()))
+                            }
+                            Record::ReserveIds { .. }
+                            | Record::Sample { .. }
+                            | Record::ListPush { .. }
+                            | Record::ListPop { .. }
+                            | Record::SetAdd { .. }
+                            | Record::SetRemove { .. }
+                            | Record::Lease { .. }
+                            | Record::Unlock { .. }
+                            | Record::Compacted { .. } => None,
+                        };
+                        if let Some(slot) = removed {
+                            let exists = live.get(&slot).copied().unwrap_or_else(|| {
+                                log.index
+                                    .keys(slot.0.as_deref())
+                                    .is_some_and(|keys| keys.contains_key(&slot.1))
+                            });
+                            if !exists {
+                                acks.push((ack, Some(KvStoreError::FailedRm(slot.1))));
+                                continue;
+                            }
+                            live.insert(slot, false);
+                        }
+
+                        let start = buf.len();
+                        let stamp = self.stamp(&record, now, client);
+                        if self.checksum_records {
+                            codec::encode_checked(codec, &stamp, &record.fields(), &mut buf);
+                        } else {
+                            codec.encode(&stamp, &record.fields(), &mut buf);
+                        }
+                        buf.push(b'\n');
+                        records.push((record, stamp, (buf.len() - start) as u64));
+                        acks.push((ack, None));
+                    }
+                    Job::Resume(stamp) => self.resume(stamp),
+                    Job::Sync(ack) => {
+                        sync = true;
+                        acks.push((ack, None));
+                    }
+                    Job::Track(ack) => {
+                        track = true;
+                        self.resumed = None;
+                        acks.push((ack, None));
+                    }
+                    Job::Subscribe(from, subscriber) => subscribers.push((from, subscriber)),
+                    Job::Compact(request, ack) => compactions.push((request, ack)),
+                    Job::Adopt(id, path, hints, ack) => adoptions.push((id, path, hints, ack)),
+                    Job::Wake => {}
+                }
+            }
+        }
+
+        let mut result = self.handle.write_all(&buf);
+        if result.is_ok() {
+            #[cfg(feature = "metrics")]
+            self.metrics.wal_write(buf.len());
+
+            let mut log = log.write().unwrap_or_else(PoisonError::into_inner);
+            for (record, stamp, len) in &records {
+                self.index(&mut log.index, record, *len);
+                if let Some(sequence) = stamp.sequence {
+                    log.sequence = sequence;
+                    log.history.record(stamp, record);
+                }
+            }
+            log.usage.len = self.len;
+            log.usage.dead = self.dead;
+            drop(log);
+
+            if sync || self.write_through {
+                if !self.write_through {
+                    result = self.handle.sync_data();
+                }
+                if result.is_ok() {
+                    let mut log = self.log.write().unwrap_or_else(PoisonError::into_inner);
+                    log.usage.last_sync = Some(self.clock.now());
+                }
+            }
+        }
+        if result.is_ok() && track {
+            self.tracking = true;
+            result = self.write_manifest();
+        }
+        if result.is_ok() {
+            self.ship(&records, subscribers);
+        }
+        for (id, path, hints, ack) in adoptions {
+            let _ = ack.send(
+                self.adopt(id, path, hints)
+                    .map_err(KvStoreError::FailedOldWalOpen),
+            );
+        }
+
+        for (ack, rejected) in acks {
+            let _ = ack.send(match rejected {
+                Some(e) => Err(e),
+                None => result.as_ref().copied().map_err(|e| {
+                    KvStoreError::FailedWalWrite(io::Error::new(e.kind(), e.to_string()))
+                }),
+            });
+        }
+        compactions
+    }
+
+    /// Returns how long to wait for a job before checking on a running compaction or on
+    /// coalesced writes, or `None` to wait for the next job
+    ///
+    /// The writer is woken by a [`Job::Wake`] when a coalescing window opens, so an empty
+    /// coalescer is not checked on.
+    fn idle_timeout(&self) -> Option<Duration> {
+        let coalesced = self.coalescing.as_ref().and_then(|(coalescer, window)| {
+            match coalescer.try_lock() {
+                Ok(coalescer) => coalescer.remaining(*window, self.clock.now()),
+                Err(TryLockError::Poisoned(e)) => {
+                    e.into_inner().remaining(*window, self.clock.now())
+                }
+                // Checked on again once the other thread is likely done with it
+                Err(TryLockError::WouldBlock) => Some(*window),
+            }
+        });
+        let compaction = self.running.as_ref().map(|_| COMPACTION_POLL);
+        coalesced.into_iter().chain(compaction).min()
+    }
+
+    /// Logs the writes held back by write coalescing if their window has elapsed
+    ///
+    /// A coalescer locked by another thread is left alone: that thread logs its writes through
+    /// this one, and waits for them while holding it.
+    fn flush_coalesced(&mut self) {
+        let Some((coalescer, window)) = &self.coalescing else {
+            return;
+        };
+        let mut coalescer = match coalescer.try_lock() {
+            Ok(coalescer) => coalescer,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => return,
+        };
+        if !coalescer.expired(*window, self.clock.now()) {
+            return;
+        }
+        let (jobs, written): (Vec<_>, Vec<_>) = coalescer
+            .take()
+            .map(|record| {
+                let (ack, written) = mpsc::sync_channel(1);
+                (Job::Append(record, None, ack), written)
+            })
+            .unzip();
+        drop(coalescer);
+        let requested = self.commit(jobs);
+        self.waiting.extend(requested);
+        let failed = written
+            .iter()
+            .filter_map(|written| written.try_recv().ok())
+            .find_map(Result::err);
+        if let Some(e) = failed {
+            error!("Failed to write coalesced writes to WAL: {e}");
+        }
+        if self.active_len >= self.segment_size {
+            if let Err(e) = self.seal() {
+                error!("Failed to seal WAL segment: {e}");
+            }
+        }
+    }
+
+    /// Returns the current time in milliseconds since the Unix epoch
+    fn now_millis(&self) -> u64 {
+        self.clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX))
+    }
+
+    /// Stamps the next numbered record with a replayed stamp, see [`Wal::resume`]
+    fn resume(&mut self, stamp: Stamp) {
+        if let Some(sequence) = stamp.sequence {
+            self.next_sequence = self.next_sequence.max(sequence);
+        }
+        self.resumed = Some(stamp);
+    }
+
+    /// Returns the stamp of a record committed at timestamp now by client, numbering it unless
+    /// it is a marker
+    fn stamp(&mut self, record: &Record, now: u64, client: Option<String>) -> Stamp {
+        if let Record::Compacted { .. } = record {
+            return Stamp::default();
+        }
+        let sequence = Some(self.next_sequence);
+        self.next_sequence += 1;
+        match self.resumed.take() {
+            Some(resumed) => Stamp {
+                sequence,
+                ..resumed
+            },
+            None => Stamp {
+                sequence,
+                timestamp: Some(now),
+                client,
+            },
+        }
+    }
+
+    /// Ships committed records in the text format to subscribers, then the records asked for
+    /// or a snapshot of the log to new subscribers
+    fn ship(
+        &mut self,
+        records: &[(Record, Stamp, u64)],
+        subscribers: Vec<(Option<u64>, mpsc::Sender<Shipment>)>,
+    ) {
+        let log = read(&self.log);
+        if !records.is_empty() && !self.subscribers.is_empty() {
+            let text = records
+                .iter()
+                .flat_map(|(record, stamp, _)| encode(WalFormat::Text, stamp, record))
+                .collect();
+            // Text records are valid UTF-8
+            let text = String::from_utf8(text).unwrap_or_default();
+            self.subscribers.retain(|tx| {
+                tx.send(Shipment::Committed(text.clone(), log.sequence))
+                    .is_ok()
+            });
+        }
+
+        // Read once for all new subscribers needing it
+        let mut snapshot = None;
+        for (from, tx) in subscribers {
+            let shipment = match from.map(|from| log.records_since(from)) {
+                Some(Ok(Some(records))) => Shipment::Committed(records, log.sequence),
+                Some(Err(e)) => {
+                    error!("Failed to read WAL for subscriber: {e}");
+                    continue;
+                }
+                Some(Ok(None)) | None => match snapshot.get_or_insert_with(|| log.live_records()) {
+                    Ok(records) => Shipment::Snapshot(records.clone(), log.sequence),
+                    Err(e) => {
+                        error!("Failed to snapshot WAL for subscriber: {e}");
+                        continue;
+                    }
+                },
+            };
+            if tx.send(shipment).is_ok() {
+                self.subscribers.push(tx);
+            }
+        }
+    }
+
+    /// Indexes a record of the given length just appended to the active segment
+    fn index(&mut self, index: &mut Index, record: &Record, len: u64) {
+        let extent = Extent {
+            segment: self.active,
+            offset: self.active_len,
+            len,
+        };
+        self.active_len += len;
+        self.len += len;
+        if let Some(segment) = self.segments.get_mut(&self.active) {
+            segment.len += len;
+        }
+        if let Some(key) = record.valued_key() {
+            self.active_keys.push(key.to_owned());
+        }
+        self.index_at(index, record, extent);
+    }
+
+    /// Takes the base segment with the given ID at path as the first segment of the log,
+    /// indexing its records as located by hints, see [`Wal::adopt`]
+    fn adopt(&mut self, id: u64, path: PathBuf, hints: Hints) -> io::Result<()> {
+        let mut base = Segment::open(path.clone())?;
+        let bloom_path = segment::bloom_path(&path);
+        let filter = Bloom::read(&bloom_path).unwrap_or_else(|reason| {
+            debug!(path = %bloom_path.display(), "Rebuilding filter of base segment: {reason}");
+            let keys: Vec<_> = hints
+                .records
+                .iter()
+                .filter_map(|(r, ..)| r.valued_key())
+                .collect();
+            let filter = Bloom::of(&keys);
+            if let Err(e) = filter.write(&bloom_path) {
+                warn!(path = %bloom_path.display(), "Failed to write segment filter: {e}");
+            }
+            filter
+        });
+        base.set_filter(filter);
+        base.seal(path);
+        self.segments.insert(
+            id,
+            SegmentUsage {
+                len: hints.len,
+                dead: 0,
+                created: self.clock.now(),
+                last_sequence: hints.through,
+                base: true,
+            },
+        );
+        self.len += hints.len;
+        self.snapshot = Some(id);
+        self.next_sequence = self.next_sequence.max(hints.last_sequence + 1);
+
+        let log = Arc::clone(&self.log);
+        let mut log = log.write().unwrap_or_else(PoisonError::into_inner);
+        log.segments.insert(id, base);
+        log.sequence = log.sequence.max(hints.last_sequence);
+        let records = hints.records.len();
+        for (record, offset, len) in hints.records {
+            let extent = Extent {
+                segment: id,
+                offset,
+                len,
+            };
+            self.index_at(&mut log.index, &record, extent);
+        }
+        log.usage.len = self.len;
+        log.usage.dead = self.dead;
+        debug!(segment = id, records, "Adopted base segment");
+        Ok(())
+    }
+
+    /// Indexes a record at extent, counting the records it supersedes as dead
+    fn index_at(&mut self, index: &mut Index, record: &Record, extent: Extent) {
+        let superseded = match record {
+            Record::Set { key, .. } => {
+                self.drop_appends(index, key);
+                index.keys.insert(key, extent)
+            }
+            Record::Append { key, .. } => {
+                let appends = index.appends.entry(key.clone()).or_insert_with(|| {
+                    index.bytes += entry_bytes(key);
+                    Vec::new()
+                });
+                appends.push(extent);
+                index.bytes += EXTENT_BYTES;
+                None
+            }
+            Record::Rm { key } => {
+                // A removal only cancels out earlier records, so it is dead right away
+                self.kill(extent);
+                self.drop_appends(index, key);
+                index.keys.remove(key)
+            }
+            Record::RmPrefix { prefix } => {
+                self.kill(extent);
+                for removed in index.keys.remove_prefix(prefix) {
+                    self.kill(removed);
+                }
+                let bytes = &mut index.bytes;
+                index.appends.retain(|key, extents| {
+                    let removed = key.starts_with(prefix.as_str());
+                    if removed {
+                        for &extent in extents.iter() {
+                            self.kill(extent);
+                        }
+                        *bytes -= entry_bytes(key) + extents.len() as u64 * EXTENT_BYTES;
+                    }
+                    !removed
+                });
+                None
+            }
+            Record::Rename { from, to, .. } => {
+                self.drop_appends(index, from);
+                self.drop_appends(index, to);
+                if let Some(renamed) = index.keys.remove(from) {
+                    self.kill(renamed);
+                }
+                index.keys.insert(to, extent)
+            }
+            Record::Compacted { .. } => {
+                // Compaction writes a new marker
+                self.kill(extent);
+                None
+            }
+            Record::BucketSet { bucket, key, .. } => {
+                let keys = index.buckets.entry(bucket.clone()).or_insert_with(|| {
+                    index.bytes += entry_bytes(bucket);
+                    KeyMap::new(index.kind)
+                });
+                keys.insert(key, extent)
+            }
+            Record::BucketRm { bucket, key } => {
+                self.kill(extent);
+                let keys = index.buckets.get_mut(bucket);
+                let superseded = keys.and_then(|keys| keys.remove(key));
+                if index.buckets.get(bucket).is_some_and(KeyMap::is_empty) {
+                    index.buckets.remove(bucket);
+                    index.bytes -= entry_bytes(bucket);
+                }
+                superseded
+            }
+            Record::ReserveIds { sequence, .. } => {
+                let superseded = index.sequences.insert(sequence.clone(), extent);
+                if superseded.is_none() {
+                    index.bytes += entry_bytes(sequence);
+                }
+                superseded
+            }
+            Record::Sample { .. } => {
+                index.samples.push(extent);
+                index.bytes += EXTENT_BYTES;
+                None
+            }
+            Record::ListPush { .. }
+            | Record::ListPop { .. }
+            | Record::SetAdd { .. }
+            | Record::SetRemove { .. }
+            | Record::Lease { .. }
+            | Record::Unlock { .. } => self.index_collection(index, record, extent),
+        };
+        if let Some(superseded) = superseded {
+            self.kill(superseded);
+        }
+    }
+
+    /// Indexes a record of a list, set or lock at extent, returning the record it supersedes
+    ///
+    /// A pop, removal or unlock cancels out the push, addition or lease it undoes, both being
+    /// dead then.
+    fn index_collection(
+        &mut self,
+        index: &mut Index,
+        record: &Record,
+        extent: Extent,
+    ) -> Option<Extent> {
+        match record {
+            Record::ListPush { key, .. } => {
+                let pushes = index.lists.entry(key.clone()).or_insert_with(|| {
+                    index.bytes += entry_bytes(key);
+                    VecDeque::new()
+                });
+                pushes.push_back(extent);
+                index.bytes += EXTENT_BYTES;
+                None
+            }
+            Record::ListPop { key } => {
+                self.kill(extent);
+                let pushes = index.lists.get_mut(key)?;
+                let popped = pushes.pop_front()?;
+                index.bytes -= EXTENT_BYTES;
+                if pushes.is_empty() {
+                    index.lists.remove(key);
+                    index.bytes -= entry_bytes(key);
+                }
+                Some(popped)
+            }
+            Record::SetAdd { key, member } => {
+                let members = index.sets.entry(key.clone()).or_insert_with(|| {
+                    index.bytes += entry_bytes(key);
+                    HashMap::new()
+                });
+                let superseded = members.insert(member.clone(), extent);
+                if superseded.is_none() {
+                    index.bytes += entry_bytes(member) + EXTENT_BYTES;
+                }
+                superseded
+            }
+            Record::SetRemove { key, member } => {
+                self.kill(extent);
+                let members = index.sets.get_mut(key)?;
+                let removed = members.remove(member)?;
+                index.bytes -= entry_bytes(member) + EXTENT_BYTES;
+                if members.is_empty() {
+                    index.sets.remove(key);
+                    index.bytes -= entry_bytes(key);
+                }
+                Some(removed)
+            }
+            Record::Lease { key, .. } => {
+                let superseded = index.locks.insert(key.clone(), extent);
+                if superseded.is_none() {
+                    index.bytes += entry_bytes(key) + EXTENT_BYTES;
+                }
+                superseded
+            }
+            Record::Unlock { key, .. } => {
+                self.kill(extent);
+                let released = index.locks.remove(key)?;
+                index.bytes -= entry_bytes(key) + EXTENT_BYTES;
+                Some(released)
+            }
+            _ => None,
+        }
+    }
+
+    /// Counts the record at extent as dead
+    fn kill(&mut self, extent: Extent) {
+        self.dead += extent.len;
+        if let Some(segment) = self.segments.get_mut(&extent.segment) {
+            segment.dead += extent.len;
+        }
+    }
+
+    /// Drops the appends to the value of key, superseded by a write of the whole key
+    fn drop_appends(&mut self, index: &mut Index, key: &str) {
+        let Some(extents) = index.appends.remove(key) else {
+            return;
+        };
+        index.bytes -= entry_bytes(key) + extents.len() as u64 * EXTENT_BYTES;
+        for extent in extents {
+            self.kill(extent);
+        }
+    }
+
+    /// Seals the active segment and starts a new one
+    fn seal(&mut self) -> io::Result<()> {
+        self.trim()?;
+        self.handle.sync_all()?;
+        let sealed = self.dirs.sealed_path(self.active);
+        fs::rename(&self.path, &sealed)?;
+        #[cfg(feature = "archive")]
+        self.keep_for_archive(&sealed);
+        let handle = self.create_active()?;
+        let segment = Segment::open(self.path.clone())?;
+        let filter = Bloom::of(&mem::take(&mut self.active_keys));
+        // Reads are only slower without the filter file
+        if let Err(e) = filter.write(&segment::bloom_path(&sealed)) {
+            warn!(segment = self.active, "Failed to write segment filter: {e}");
+        }
+
+        let mut log = self.log.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(previous) = log.segments.get_mut(&self.active) {
+            previous.set_filter(filter);
+            previous.seal(sealed);
+        }
+        if let Some(previous) = self.segments.get_mut(&self.active) {
+            previous.last_sequence = log.sequence;
+        }
+        self.active += 1;
+        log.segments.insert(self.active, segment);
+        drop(log);
+        self.segments
+            .insert(self.active, SegmentUsage::new(self.clock.now()));
+
+        self.handle = handle;
+        self.active_len = 0;
+        debug!(segment = self.active - 1, "Sealed WAL segment");
+
+        self.write_manifest()
+    }
+
+    /// Truncates the active segment to its records, if preallocated
+    fn trim(&self) -> io::Result<()> {
+        if self.preallocate {
+            self.handle.set_len(self.active_len)?;
+        }
+        Ok(())
+    }
+
+    /// Creates the next active segment, preallocated if enabled, recycling a zeroed segment
+    /// file if any
+    fn create_active(&mut self) -> io::Result<File> {
+        if let Some(free) = self.free.pop() {
+            let file =
+                segment::recycle_active(&free, &self.path, self.write_through, self.segment_size)?;
+            debug!(path = %free.display(), "Recycled segment file");
+            return Ok(file);
+        }
+        let preallocate = self.preallocate.then_some(self.segment_size);
+        segment::create_active(&self.path, self.write_through, preallocate)
+    }
+
+    /// Deletes the compacted segment with the given ID at path, or keeps it zeroed for
+    /// recycling if preallocating and fewer than [`FREE_SEGMENTS`] are kept
+    ///
+    /// Segments are zeroed on a thread of their own, or of the thread pool of the runtime, so
+    /// that writes go on meanwhile. Base segments live in the data directory and are always
+    /// deleted, as are segments kept for the archiver, which may be hard links to them.
+    fn discard(&mut self, id: u64, path: &Path) -> io::Result<()> {
+        #[cfg(feature = "archive")]
+        let recycle = self.preallocate && !self.archive_segments;
+        #[cfg(not(feature = "archive"))]
+        let recycle = self.preallocate;
+        segment::remove_bloom(path)?;
+        if !recycle || path != self.dirs.sealed_path(id) || !self.free.reserve() {
+            return fs::remove_file(path);
+        }
+
+        let free = Arc::clone(&self.free);
+        let (zeroed, free_path, len) =
+            (path.to_owned(), self.dirs.free_path(id), self.segment_size);
+        let zero = move || free.zero(&zeroed, free_path, len);
+        match &self.runtime {
+            Some(runtime) => runtime.compact(zero),
+            None => {
+                if let Err(e) = thread::Builder::new()
+                    .name("kvs-recycle".to_owned())
+                    .spawn(zero)
+                {
+                    self.free.release();
+                    fs::remove_file(path)?;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Links the segment at path into the directory the archiver uploads from, if archiving,
+    /// or copies it where hard links are not supported
+    ///
+    /// A segment that cannot be kept is logged and left out of the archive.
+    #[cfg(feature = "archive")]
+    fn keep_for_archive(&self, path: &Path) {
+        if !self.archive_segments {
+            return;
+        }
+        let dir = self.dirs.store.join(crate::archive::PENDING);
+        let kept = fs::create_dir_all(&dir).and_then(|()| {
+            let link = dir.join(path.file_name().unwrap_or_default());
+            fs::hard_link(path, &link).or_else(|_| fs::copy(path, &link).map(drop))
+        });
+        if let Err(e) = kept {
+            error!(path = %path.display(), "Failed to keep segment for archive: {e}");
+        }
+    }
+
+    /// Records the segments in the manifest, if tracking them
+    fn write_manifest(&self) -> io::Result<()> {
+        self.record_manifest(None)
+    }
+
+    /// Records the segments in the manifest, if tracking them, along with the bytes of records
+    /// in the active segment if it is no longer written
+    fn record_manifest(&self, end: Option<u64>) -> io::Result<()> {
+        if !self.tracking {
+            return Ok(());
+        }
+
+        let log = read(&self.log);
+        let manifest = Manifest {
+            next_segment: self.active,
+            segments: log
+                .segments
+                .keys()
+                .filter(|&&id| id != self.active)
+                .map(|&id| (id, Some(id) == self.snapshot))
+                .collect(),
+            format: log.format,
+            wal_dir: (self.dirs.wal != self.dirs.store).then(|| self.dirs.wal.clone()),
+            data_dir: (self.dirs.data != self.dirs.store).then(|| self.dirs.data.clone()),
+            end,
+        };
+        drop(log);
+        manifest::write(&self.dirs.store, FORMAT_VERSION, &manifest)
+    }
+
+    /// Finishes the running compaction if done or abandoned, then starts another if due or
+    /// requested
+    ///
+    /// Requested compactions that find nothing to do are acknowledged right away.
+    fn schedule_compaction(&mut self) {
+        let (abandons, waiting): (Vec<_>, Vec<_>) = mem::take(&mut self.waiting)
+            .into_iter()
+            .partition(|(request, _)| *request == Request::Abandon);
+        self.waiting = waiting;
+        if !abandons.is_empty() {
+            self.compaction.cancel();
+        }
+        if self
+            .running
+            .as_mut()
+            .is_some_and(|r| r.copying.is_finished())
+            || !abandons.is_empty()
+        {
+            if let Some(running) = self.running.take() {
+                self.finish_compaction(running);
+            }
+        }
+        for (_, ack) in abandons {
+            let _ = ack.send(Ok(None));
+        }
+        if self.running.is_some() {
+            return;
+        }
+
+        let requested = !self.waiting.is_empty();
+        let forced = self
+            .waiting
+            .iter()
+            .any(|(request, _)| *request == Request::Force)
+            || (requested && self.dead > 0);
+        let acks: Vec<_> = self.waiting.drain(..).map(|(_, ack)| ack).collect();
+        let count = if forced {
+            self.segments.len()
+        } else if self.compaction.stopped()
+            || self.len < self.compaction_threshold.load(Ordering::Relaxed)
+        {
+            0
+        } else {
+            let segments: Vec<_> = self
+                .segments
+                .iter()
+                .map(|(&id, usage)| usage.info(id))
+                .collect();
+            self.policy.select(&segments, self.clock.now())
+        };
+        if count == 0 {
+            for ack in acks {
+                let _ = ack.send(Ok(None));
+            }
+            return;
+        }
+        match self.start_compaction(count) {
+            Ok(running) => self.running = Some(Running { acks, ..running }),
+            Err(e) => {
+                error!("Failed to start compacting WAL: {e}");
+                for ack in acks {
+                    let _ = ack.send(Err(KvStoreError::FailedCompaction(io::Error::new(
+                        e.kind(),
+                        e.to_string(),
+                    ))));
+                }
+            }
+        }
+    }
+
+    /// Starts rewriting the live records of the count oldest segments, in their original
+    /// order, as a base segment on a thread of its own, or of the thread pool of the runtime
+    ///
+    /// The base segment takes the ID of the last segment it replaces, sealing it first if
+    /// active, and starts with a `compacted` marker of the last sequence number of that
+    /// segment. Records go on being appended to the segments after it meanwhile.
+    fn start_compaction(&mut self, count: usize) -> io::Result<Running> {
+        let mut ids: Vec<_> = self.segments.keys().copied().take(count.max(1)).collect();
+        // A base segment keeps its ID, so the segment after it comes along; the active segment
+        // is never a base segment
+        if ids.last().is_some_and(|id| self.segments[id].base) {
+            ids.extend(self.segments.keys().nth(ids.len()));
+        }
+        let Some(&base) = ids.last() else {
+            return Err(io::Error::other("no segment to compact"));
+        };
+        if base == self.active {
+            self.seal()?;
+        }
+        let compacted = ids.iter().map(|id| self.segments[id]);
+        let len = compacted.clone().map(|s| s.len).sum();
+        let dead = compacted.map(|s| s.dead).sum();
+
+        let log = read(&self.log);
+        let mut extents: Vec<_> = log
+            .index
+            .extents()
+            .filter(|e| e.segment <= base)
+            .copied()
+            .collect();
+        drop(log);
+        let through = self.segments[&base].last_sequence;
+
+        extents.sort_unstable_by_key(|e| (e.segment, e.offset));
+        self.compaction.begin(extents.iter().map(|e| e.len).sum());
+        let log = Arc::clone(&self.log);
+        let control = Arc::clone(&self.compaction);
+        let path = self.dirs.compact_path();
+        let hint_path = self.dirs.hint_path(base);
+        let bloom_path = segment::bloom_path(&self.dirs.base_path(base));
+        let (finished, done) = mpsc::sync_channel(1);
+        let copy = move || {
+            let paths = (path.as_path(), hint_path.as_path(), bloom_path.as_path());
+            let _ = finished.send(write_base(&log, &extents, through, paths, &control));
+        };
+        match &self.runtime {
+            Some(runtime) => runtime.compact(copy),
+            None => {
+                thread::Builder::new()
+                    .name("kvs-compaction".to_owned())
+                    .spawn(copy)
+                    .inspect_err(|_| self.compaction.end())?;
+            }
+        }
+        Ok(Running {
+            base,
+            len,
+            dead,
+            through,
+            acks: Vec::new(),
+            copying: Copying { done, copied: None },
+        })
+    }
+
+    /// Waits for the running compaction, then swaps its base segment in for the segments
+    /// before it and acknowledges the compactions requested from it
+    ///
+    /// On failure the old segments stay in place untouched; if interrupted after the base
+    /// segment is complete, replay ignores the segments before it.
+    fn finish_compaction(&mut self, running: Running) {
+        let compact_path = self.dirs.compact_path();
+        let copied = running.copying.join();
+        self.compaction.end();
+        let result = copied.and_then(|copied| {
+            self.swap_base(
+                running.base,
+                running.len,
+                running.dead,
+                &compact_path,
+                copied,
+            )
+        });
+        if let Err(e) = &result {
+            if e.kind() == io::ErrorKind::Interrupted {
+                info!("Abandoned WAL compaction");
+            } else {
+                error!("Failed to compact WAL: {e}");
+            }
+            let _ = fs::remove_file(&compact_path);
+            let _ = fs::remove_file(self.dirs.hint_path(running.base));
+            let _ = segment::remove_bloom(&self.dirs.base_path(running.base));
+        }
+        for ack in running.acks {
+            let _ = ack.send(
+                result
+                    .as_ref()
+                    .map(|()| Some(running.through))
+                    .map_err(|e| {
+                        KvStoreError::FailedCompaction(io::Error::new(e.kind(), e.to_string()))
+                    }),
+            );
+        }
+    }
+
+    /// Replaces the segments before base by the base segment copied to `compact_path`, given the
+    /// bytes of records, and of dead ones, when the compaction started
+    fn swap_base(
+        &mut self,
+        base: u64,
+        len_before: u64,
+        dead_before: u64,
+        compact_path: &Path,
+        (moved, base_len, filter): Copied,
+    ) -> io::Result<()> {
+        let base_path = self.dirs.base_path(base);
+        fs::rename(compact_path, &base_path)?;
+        #[cfg(feature = "archive")]
+        self.keep_for_archive(&base_path);
+        let mut base_segment = Segment::open(base_path.clone())?;
+        base_segment.set_filter(filter);
+        base_segment.seal(base_path);
+
+        let mut log = self.log.write().unwrap_or_else(PoisonError::into_inner);
+        // Records written since the compaction started sit in the segments after the base one
+        for extent in log.index.extents_mut().filter(|e| e.segment <= base) {
+            extent.offset = moved[&(extent.segment, extent.offset)];
+            extent.segment = base;
+        }
+        let newer = log.segments.split_off(&(base + 1));
+        let old = mem::replace(&mut log.segments, newer);
+        log.segments.insert(base, base_segment);
+        // Records superseded since the compaction started were copied, and stay dead
+        let newer = self.segments.split_off(&(base + 1));
+        let compacted = mem::replace(&mut self.segments, newer);
+        let dead = compacted.values().map(|s| s.dead).sum::<u64>() - dead_before;
+        self.segments.insert(
+            base,
+            SegmentUsage {
+                len: base_len,
+                dead,
+                created: self.clock.now(),
+                last_sequence: compacted[&base].last_sequence,
+                base: true,
+            },
+        );
+        self.len = self.len - len_before + base_len;
+        self.dead -= dead_before;
+        log.usage = Usage {
+            len: self.len,
+            dead: self.dead,
+            last_compaction: Some(self.clock.now()),
+            last_sync: log.usage.last_sync,
+        };
+        drop(log);
+        self.snapshot = Some(base);
+        self.write_manifest()?;
+
+        // Segments are deleted or recycled once unmapped, along with the hint files of base
+        // segments
+        for (id, segment) in old {
+            let path = segment.path().to_owned();
+            drop(segment);
+            if let Err(e) = self.discard(id, &path) {
+                error!(path = %path.display(), "Failed to remove compacted segment: {e}");
+            }
+            if id != base {
+                if let Err(e) = segment::remove_hint(&self.dirs.data, id) {
+                    error!(
+                        segment = id,
+                        "Failed to remove hint file of compacted segment: {e}"
+                    );
+                }
+            }
+        }
+
+        #[cfg(feature = "metrics")]
+        self.metrics.compaction();
+
+        info!(before = len_before, after = self.len, "Compacted WAL");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+//! JavaScript bindings of a KV store for web apps, built for `wasm32-unknown-unknown` with
+//! `cargo rustc --lib --target wasm32-unknown-unknown --features wasm --crate-type cdylib`
+//!
+//! Browsers have no file system for the write-ahead log, so the store keeps its pairs in a
+//! [`MemEngine`] for as long as it is referenced from JavaScript.
+
+use crate::engine::{KvsEngine, MemEngine};
+use wasm_bindgen::prelude::*;
+
+/// KV store for web apps, keeping its pairs in memory
+#[wasm_bindgen]
+#[derive(Debug, Default)]
+pub struct WasmStore {
+    engine: MemEngine,
+}
+
+#[wasm_bindgen]
+impl WasmStore {
+    /// Returns an empty store
+    #[wasm_bindgen(constructor)]
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the value of key, or `undefined` if missing
+    #[must_use]
+    pub fn get(&self, key: String) -> Option<String> {
+        let Ok(value) = self.engine.get(key);
+        value
+    }
+
+    /// Sets the value of key, returning its previous value, or `undefined` if missing
+    pub fn set(&mut self, key: String, value: String) -> Option<String> {
+        let Ok(previous) = self.engine.set(key, value);
+        previous
+    }
+
+    /// Removes key, returning its value, or `undefined` if missing
+    pub fn remove(&mut self, key: String) -> Option<String> {
+        let Ok(value) = self.engine.remove(key);
+        value
+    }
+
+    /// Returns the number of keys
+    #[wasm_bindgen(getter)]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.engine.len()
+    }
+
+    /// Returns whether there are no keys
+    #[wasm_bindgen(js_name = isEmpty)]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.engine.is_empty()
+    }
+}
@@ -0,0 +1,68 @@
+//! Change notifications for watched key prefixes
+
+use serde::Serialize;
+use std::sync::{
+    mpsc::{self, Receiver, Sender},
+    Mutex, PoisonError,
+};
+
+/// Change of a key observed by a watcher
+///
+/// Serializes with the kind of change as `event`, e.g. `{"event": "removed", "key": "a"}`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WatchEvent {
+    /// Key was set to value
+    Set {
+        /// Key string
+        key: String,
+        /// Value string
+        value: String,
+    },
+    /// Key was removed
+    Removed {
+        /// Key string
+        key: String,
+    },
+}
+
+impl WatchEvent {
+    /// Returns the changed key
+    #[must_use]
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Set { key, .. } | Self::Removed { key } => key,
+        }
+    }
+}
+
+/// Registered watchers with the key prefixes they watch
+#[derive(Debug, Default)]
+pub(crate) struct Watchers {
+    watchers: Mutex<Vec<(String, Sender<WatchEvent>)>>,
+}
+
+impl Watchers {
+    /// Registers a watcher of keys starting with prefix
+    pub(crate) fn watch(&self, prefix: String) -> Receiver<WatchEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.lock().push((prefix, tx));
+        rx
+    }
+
+    /// Returns whether any watcher is registered
+    pub(crate) fn active(&self) -> bool {
+        !self.lock().is_empty()
+    }
+
+    /// Sends event to matching watchers, forgetting those whose receiver was dropped
+    pub(crate) fn notify(&self, event: &WatchEvent) {
+        self.lock().retain(|(prefix, tx)| {
+            !event.key().starts_with(prefix.as_str()) || tx.send(event.clone()).is_ok()
+        });
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<(String, Sender<WatchEvent>)>> {
+        self.watchers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -1,10 +1,24 @@
 #![warn(clippy::all, clippy::pedantic, future_incompatible)]
 
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::doctor::{self, Issue};
+use kvs::dump;
+use kvs::migrate::{self, Migration};
+use kvs::{
+    Aggregation, CacheConfig, CompactionPolicy, ErrorKind, EvictionConfig, EvictionPolicy,
+    KeyIndex, KvStore, KvStoreError, KvsEngine, KvsRuntime, ManualClock, MapFacade, MemEngine,
+    OpenOptions, OpenProgress, Result, Revision, RuntimeOptions, Sample, ScanOptions, SegmentInfo,
+    SetOptions, StoreStats, TimeBased, ValueRef, WalFormat, WatchEvent,
+};
 use predicates::ord::eq;
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty, PredicateStrExt};
-use std::process::Command;
+use proptest::prelude::*;
+use std::collections::HashMap;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -24,6 +38,57 @@ fn cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// `kvs migrate` should upgrade a store written by an older version, which fails to open until then.
+#[test]
+fn cli_migrate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = |name: &str| temp_dir.path().join(name);
+
+    // Written before the manifest and base segment headers
+    std::fs::write(path("wa.1.base.log"), "set key1 value1\n").unwrap();
+    std::fs::write(path("wa.log"), "set key2 value2\n").unwrap();
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvStoreError::OutdatedFormat(0, 4))
+    ));
+    assert!(matches!(
+        doctor::check(temp_dir.path(), true),
+        Err(KvStoreError::OutdatedFormat(0, 4))
+    ));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Migrated from format version 0 to 4").trim());
+    assert_eq!(
+        migrate::migrate(temp_dir.path())?,
+        Migration { from: 4, to: 4 }
+    );
+    assert_eq!(doctor::check(temp_dir.path(), false)?.issues, vec![]);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    std::fs::write(path("MANIFEST"), "version 5\n").unwrap();
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvStoreError::UnsupportedFormat(5, 4))
+    ));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
 // `kvs get <KEY>` should print "Key not found" for a non-existent key and exit with zero.
 #[test]
 fn cli_get_non_existent_key() {
@@ -63,6 +128,28 @@ fn cli_set() {
         .stdout(is_empty().trim());
 }
 
+// `kvs --log-format json` should write JSON log events to stderr only.
+#[test]
+fn cli_log_json() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args([
+            "set",
+            "key1",
+            "value1",
+            "--log-level",
+            "debug",
+            "--log-format",
+            "json",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty().trim())
+        .stderr(contains("{\"timestamp\":").and(contains("\"level\":\"DEBUG\"")));
+}
+
 #[test]
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -119,6 +206,133 @@ fn cli_rm_stored() -> Result<()> {
     Ok(())
 }
 
+// `kvs --bucket <BUCKET>` should run key-value commands in that bucket only, with the previous
+// value of keys written in JSON results.
+#[test]
+fn cli_bucket() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--bucket", "users", "set", "key1", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty().trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--bucket", "users"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value2").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    // The JSON result holds the previous value
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--bucket", "users", "--output", "json", "rm", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(r#"{"ok":true,"value":"value2"}"#).trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--bucket", "users", "next-id", "seq"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+// `kvs stats --json` should print store statistics as JSON.
+#[test]
+fn cli_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Keys: 2").and(contains("Last compaction: never")));
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats", "--json"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    let stats: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(stats["keys"], 2);
+    assert_eq!(stats["dead_bytes"], 0);
+    assert_eq!(stats["segments"], 1);
+    assert!(stats["last_compaction"].is_null());
+
+    Ok(())
+}
+
+// `kvs doctor` should fail on unresolved issues and succeed once repaired.
+#[test]
+fn cli_doctor() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let mut wal = std::fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("wa.log"))
+        .unwrap();
+    std::io::Write::write_all(&mut wal, b"set key2").unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["doctor"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("truncated record"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["fsck", "--repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("(repaired)"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Ok(())
+}
+
 #[test]
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
@@ -256,6 +470,208 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// Removals of missing keys should be rejected without being logged, and replaying tombstones
+// of missing keys or records applied twice should leave the store as is.
+#[test]
+fn replay_idempotent() -> Result<()> {
+    for offset_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            OpenOptions::new()
+                .offset_index(offset_index)
+                .open(temp_dir.path())
+        };
+        let wal_path = temp_dir.path().join("wa.log");
+
+        let store = open()?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let wal = std::fs::read_to_string(&wal_path).unwrap();
+        assert!(store.remove("missing".to_owned()).is_err());
+        assert_eq!(std::fs::read_to_string(&wal_path).unwrap(), wal);
+        drop(store);
+
+        let wal = std::fs::read_to_string(&wal_path).unwrap()
+            + "rm missing\nrm key1\nrm key1\nset key2 value2\nts temp 1 20\nts temp 1 20\n";
+        std::fs::write(&wal_path, wal).unwrap();
+
+        let store = open()?;
+        assert_eq!(store.get("key1")?, None);
+        assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+        assert_eq!(store.ts_range("temp", 0, 10, Aggregation::None)?.len(), 1);
+        drop(store);
+        assert_eq!(doctor::check(temp_dir.path(), false)?.issues, vec![]);
+    }
+
+    Ok(())
+}
+
+// Logs should replay after their line endings were converted to those of Windows, including
+// values holding carriage returns, from directories with spaces, dots and non-ASCII characters
+// in their names and paths longer than Windows' legacy limit of 260 characters.
+#[test]
+fn windows_line_endings_and_paths() -> Result<()> {
+    // Length 13 encodes to a carriage return in binary frames
+    let values = [
+        "carriage\rreturn",
+        "crlf\r\nline",
+        "0123456789abc",
+        "trailing\r",
+    ];
+    for format in [
+        WalFormat::Text,
+        WalFormat::Json,
+        WalFormat::Bincode,
+        WalFormat::MessagePack,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut dir = temp_dir
+            .path()
+            .join("Program Files (x86)")
+            .join("kvs data.d");
+        while dir.as_os_str().len() <= 260 {
+            dir.push("Données du magasin de clés");
+        }
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let store = OpenOptions::new().wal_format(format).open(&dir)?;
+        for (i, value) in values.iter().enumerate() {
+            store.set(format!("key{i}"), (*value).to_owned())?;
+        }
+        drop(store);
+        let wal_path = dir.join("wa.log");
+        let wal = std::fs::read(&wal_path).unwrap();
+        assert!(!wal.contains(&b'\r'));
+        let crlf: Vec<_> = wal
+            .iter()
+            .flat_map(|&b| if b == b'\n' { vec![b'\r', b] } else { vec![b] })
+            .collect();
+        std::fs::write(&wal_path, crlf).unwrap();
+        assert_eq!(doctor::check(&dir, false)?.issues, vec![]);
+
+        for offset_index in [false, true] {
+            let store = OpenOptions::new().offset_index(offset_index).open(&dir)?;
+            for (i, value) in values.iter().enumerate() {
+                assert_eq!(store.get(format!("key{i}"))?, Some((*value).to_owned()));
+            }
+            assert_eq!(store.sequence(), 4);
+        }
+    }
+
+    Ok(())
+}
+
+// Records should keep their sequence numbers across reopens and compaction, replay should skip
+// duplicate records, and records after a gap should still be replayed.
+#[test]
+fn wal_sequence_numbers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let wal_path = temp_dir.path().join("wa.log");
+
+    let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1)));
+    let store = OpenOptions::new().clock(clock).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.sequence(), 3);
+    drop(store);
+    assert_eq!(
+        std::fs::read_to_string(&wal_path).unwrap(),
+        "1 1000 set key1 value1\n2 1000 set key2 value2\n3 1000 rm key1\n"
+    );
+
+    // Records written before they were timestamped
+
+    let wal = std::fs::read_to_string(&wal_path).unwrap() + "2 set key2 stale\n";
+    std::fs::write(&wal_path, wal).unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.sequence(), 3);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.sequence(), 4);
+    drop(store);
+
+    let wal = std::fs::read_to_string(&wal_path).unwrap() + "10 set key4 value4\n";
+    std::fs::write(&wal_path, wal).unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.sequence(), 10);
+    assert_eq!(store.get("key4")?, Some("value4".to_owned()));
+
+    // Churn the log until it is compacted
+    for i in 0..20_000 {
+        store.set("churn".to_owned(), format!("{i:0>64}"))?;
+    }
+    assert_eq!(store.sequence(), 20_010);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.sequence(), 20_010);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    assert_eq!(store.sequence(), 20_011);
+
+    Ok(())
+}
+
+// Records should be written in the format chosen when creating the store, which should be
+// recorded in the manifest and kept when reopening with another format.
+#[test]
+fn wal_formats() -> Result<()> {
+    // Length 10 and the escape byte of binary frames, as in U+06DB, encode to special bytes
+    let values = ["0123456789", "line\nbreak", "\u{6db}", ""];
+    for format in [
+        WalFormat::Text,
+        WalFormat::Json,
+        WalFormat::Bincode,
+        WalFormat::MessagePack,
+    ] {
+        for offset_index in [false, true] {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let store = OpenOptions::new()
+                .wal_format(format)
+                .offset_index(offset_index)
+                .open(temp_dir.path())?;
+            for (i, value) in values.iter().enumerate() {
+                store.set(format!("key{i}"), (*value).to_owned())?;
+            }
+            store.remove("key0".to_owned())?;
+            store
+                .bucket("b")
+                .set("key".to_owned(), "value".to_owned())?;
+            store.ts_add("temp", 1, 20.5)?;
+            drop(store);
+
+            let manifest = std::fs::read_to_string(temp_dir.path().join("MANIFEST")).unwrap();
+            assert!(manifest.contains(&format!("\ncodec {format}\n")));
+            if format == WalFormat::Json {
+                let wal = std::fs::read_to_string(temp_dir.path().join("wa.log")).unwrap();
+                assert!(wal.starts_with(
+                    "{\"sequence\":1,\"fields\":[\"set\",\"key0\",\"0123456789\"],\"timestamp\":"
+                ));
+            }
+
+            let store = OpenOptions::new()
+                .offset_index(offset_index)
+                .open(temp_dir.path())?;
+            assert_eq!(store.get("key0")?, None);
+            for (i, value) in values.iter().enumerate().skip(1) {
+                assert_eq!(store.get(format!("key{i}"))?, Some((*value).to_owned()));
+            }
+            assert_eq!(store.bucket("b").get("key")?, Some("value".to_owned()));
+            assert_eq!(store.ts_range("temp", 0, 10, Aggregation::None)?.len(), 1);
+            assert_eq!(store.sequence(), 7);
+            drop(store);
+
+            let manifest = std::fs::read_to_string(temp_dir.path().join("MANIFEST")).unwrap();
+            assert!(manifest.contains(&format!("\ncodec {format}\n")));
+            let report = doctor::check(temp_dir.path(), false)?;
+            assert_eq!((report.records, report.issues), (7, vec![]));
+        }
+    }
+
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -301,3 +717,6046 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// IDs should be unique and increasing per sequence.
+#[test]
+fn next_id_sequences() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.next_id("users")?, 1);
+    assert_eq!(store.next_id("users")?, 2);
+    assert_eq!(store.next_id("orders")?, 1);
+    assert_eq!(store.next_id("users")?, 3);
+
+    Ok(())
+}
+
+// IDs should never be reissued after reopening the store.
+#[test]
+fn next_id_after_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut last = 0;
+    for _ in 0..250 {
+        last = store.next_id("users")?;
+    }
+    assert_eq!(last, 250);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.next_id("users")? > last);
+    assert_eq!(store.next_id("orders")?, 1);
+
+    Ok(())
+}
+
+// Timeseries samples should round-trip through compressed chunks and persist.
+#[test]
+fn timeseries_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let samples: Vec<Sample> = (0..1000)
+        .map(|i| Sample {
+            timestamp: 1_700_000_000_000 + i * 1000 + i % 7,
+            value: f64::from(i32::try_from(i % 50).unwrap()) * 0.5 - 3.25,
+        })
+        .collect();
+    for s in &samples {
+        store.ts_add("cpu", s.timestamp, s.value)?;
+    }
+
+    let (from, to) = (samples[100].timestamp, samples[899].timestamp);
+    assert_eq!(
+        store.ts_range("cpu", from, to, Aggregation::None)?,
+        samples[100..900]
+    );
+    assert_eq!(
+        store.ts_range("cpu", from, to, Aggregation::Count)?,
+        vec![Sample {
+            timestamp: from,
+            value: 800.0
+        }]
+    );
+    assert!(store.ts_add("cpu", from, 1.0).is_err());
+    assert!(store
+        .ts_range("mem", 0, i64::MAX, Aggregation::None)?
+        .is_empty());
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.ts_range("cpu", i64::MIN, i64::MAX, Aggregation::None)?,
+        samples
+    );
+    assert_eq!(
+        store.ts_range("cpu", i64::MIN, i64::MAX, Aggregation::Max)?,
+        vec![Sample {
+            timestamp: i64::MIN,
+            value: 21.25
+        }]
+    );
+
+    Ok(())
+}
+
+// Opening a store over arbitrary WAL contents should return `Ok` or `Err`, never panic.
+#[test]
+fn open_fuzzed_wal() {
+    const TOKENS: [&str; 12] = [
+        "set", "rm", "id", "ts", "get", " ", "\n", "\"", "\\", "-1", "1e309", "ключ",
+    ];
+
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    for _ in 0..200 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut wal = Vec::new();
+        for _ in 0..next() % 64 {
+            let n = usize::try_from(next() % 16).unwrap();
+            match TOKENS.get(n) {
+                Some(token) => wal.extend_from_slice(token.as_bytes()),
+                None => wal.push(next().to_le_bytes()[0]),
+            }
+        }
+        std::fs::write(temp_dir.path().join("wa.log"), &wal).unwrap();
+
+        if let Ok(store) = OpenOptions::new().panic_free(false).open(temp_dir.path()) {
+            assert_eq!(store.poisoned(), None);
+            store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        }
+    }
+}
+
+// Metrics should count commands and WAL bytes and render as Prometheus text.
+#[cfg(feature = "metrics")]
+#[test]
+fn metrics_exposition() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let store = OpenOptions::new().clock(clock).open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.get("key1")?;
+
+    let metrics = store.metrics();
+    assert_eq!(metrics.command_count("set"), 2);
+    assert_eq!(metrics.command_count("get"), 1);
+    assert_eq!(metrics.command_count("rm"), 0);
+    assert_eq!(metrics.wal_bytes_written(), 40);
+
+    let text = metrics.render();
+    assert!(text.contains("# TYPE kvs_command_duration_seconds histogram"));
+    assert!(text.contains("kvs_command_duration_seconds_count{command=\"set\"} 2"));
+    assert!(text.contains("kvs_command_duration_seconds_bucket{command=\"get\",le=\"+Inf\"} 1"));
+    assert!(text.contains("kvs_wal_bytes_written_total 40"));
+
+    Ok(())
+}
+
+// The store should read time from the configured clock.
+#[test]
+fn manual_clock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1)));
+    let store = OpenOptions::new()
+        .clock(clock.clone())
+        .open(temp_dir.path())?;
+
+    assert_eq!(store.clock().unix_millis(), 1000);
+    clock.advance(Duration::from_millis(500));
+    assert_eq!(store.clock().unix_millis(), 1500);
+    clock.set(UNIX_EPOCH);
+    assert_eq!(store.clock().now(), UNIX_EPOCH);
+
+    Ok(())
+}
+
+// Missing keys should be reported to the miss hook instead of printed.
+#[test]
+fn get_miss_hook() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let misses = Arc::new(Mutex::new(Vec::new()));
+    let store = {
+        let misses = Arc::clone(&misses);
+        OpenOptions::new()
+            .on_miss(move |key| misses.lock().unwrap().push(key.to_owned()))
+            .open(temp_dir.path())?
+    };
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(*misses.lock().unwrap(), ["key2"]);
+
+    Ok(())
+}
+
+// The HTTP API should serve keys as JSON resources.
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn http_api() -> Result<()> {
+    use axum::body::{to_bytes, Body};
+    use axum::http::{Request, StatusCode};
+    use kvs::http::{router, Entry};
+    use tower::ServiceExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let app = router(KvStore::open(temp_dir.path())?);
+    let send = |method: &str, uri: &str, body: &str| {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body.to_owned()))
+            .unwrap();
+        let app = app.clone();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            (status, body)
+        }
+    };
+
+    assert_eq!(
+        send("PUT", "/keys/user1", "ada").await.0,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(
+        send("PUT", "/keys/user2", "grace").await.0,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(
+        send("PUT", "/keys/other", "x").await.0,
+        StatusCode::NO_CONTENT
+    );
+
+    let (status, body) = send("GET", "/keys/user1", "").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        serde_json::from_slice::<Entry>(&body).unwrap(),
+        Entry {
+            key: "user1".to_owned(),
+            value: "ada".to_owned()
+        }
+    );
+
+    let (status, body) = send("GET", "/keys?prefix=user", "").await;
+    assert_eq!(status, StatusCode::OK);
+    let keys: Vec<_> = serde_json::from_slice::<Vec<Entry>>(&body)
+        .unwrap()
+        .into_iter()
+        .map(|e| e.key)
+        .collect();
+    assert_eq!(keys, ["user1", "user2"]);
+
+    assert_eq!(
+        send("DELETE", "/keys/user1", "").await.0,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(
+        send("GET", "/keys/user1", "").await.0,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        send("DELETE", "/keys/user1", "").await.0,
+        StatusCode::NOT_FOUND
+    );
+
+    let (status, body) = send("GET", "/healthz", "").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(
+        serde_json::from_slice::<kvs::Health>(&body)
+            .unwrap()
+            .writable
+    );
+
+    Ok(())
+}
+
+// Overwrites within the coalescing window should be logged as a single record.
+#[test]
+fn coalesce_overwrites() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let wal_lines = || {
+        std::fs::read_to_string(temp_dir.path().join("wa.log"))
+            .unwrap()
+            .lines()
+            .count()
+    };
+    let clock = Arc::new(ManualClock::default());
+    let store = OpenOptions::new()
+        .clock(clock.clone())
+        .coalesce_window(Duration::from_secs(1))
+        .open(temp_dir.path())?;
+
+    for i in 0..100 {
+        store.set("counter".to_owned(), i.to_string())?;
+    }
+    store.set("gone".to_owned(), "value".to_owned())?;
+    store.remove("gone".to_owned())?;
+    assert_eq!(store.get("counter")?, Some("99".to_owned()));
+    assert_eq!(wal_lines(), 0);
+
+    // Next write after the window logs the latest write per key; the key created and removed
+    // within the window cancels out.
+    clock.advance(Duration::from_secs(1));
+    store.set("counter".to_owned(), "100".to_owned())?;
+    assert_eq!(wal_lines(), 1);
+
+    store.set("counter".to_owned(), "101".to_owned())?;
+    drop(store);
+    assert_eq!(wal_lines(), 2);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("counter")?, Some("101".to_owned()));
+    assert_eq!(store.get("gone")?, None);
+
+    Ok(())
+}
+
+// A shared runtime should log coalesced writes of all its stores in the background.
+#[test]
+fn runtime_flushes_coalesced() -> Result<()> {
+    let runtime = KvsRuntime::with_tick(Duration::from_millis(10))?;
+    let dirs: Vec<_> = (0..3)
+        .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+        .collect();
+    let stores = dirs
+        .iter()
+        .map(|dir| {
+            OpenOptions::new()
+                .coalesce_window(Duration::from_millis(10))
+                .runtime(&runtime)
+                .open(dir.path())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(runtime.store_count(), 3);
+
+    for store in &stores {
+        store.set("key1".to_owned(), "value1".to_owned())?;
+    }
+    std::thread::sleep(Duration::from_millis(200));
+    for dir in &dirs {
+        let wal = std::fs::read_to_string(dir.path().join("wa.log")).unwrap();
+        let (sequence, stamped) = wal.split_once(' ').unwrap();
+        assert_eq!(
+            (sequence, stamped.split_once(' ').unwrap().1),
+            ("1", "set key1 value1\n")
+        );
+    }
+
+    drop(stores);
+    assert_eq!(runtime.store_count(), 0);
+
+    Ok(())
+}
+
+// Stores of a runtime should compact on its thread pool under its throttle, and share its value
+// cache without reading each other's values.
+#[test]
+fn runtime_shares_resources() -> Result<()> {
+    let runtime = RuntimeOptions::new()
+        .compaction_threads(1)
+        .compaction_throttle(1 << 20)
+        .cache(CacheConfig {
+            capacity_bytes: 1024,
+        })
+        .start()?;
+    let dirs: Vec<_> = (0..3)
+        .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+        .collect();
+    let stores = dirs
+        .iter()
+        .map(|dir| {
+            OpenOptions::new()
+                .offset_index(true)
+                .runtime(&runtime)
+                .open(dir.path())
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    for (i, store) in stores.iter().enumerate() {
+        for round in 0..10 {
+            store.set("key1".to_owned(), format!("value{i}-{round}"))?;
+        }
+    }
+    thread::scope(|s| {
+        let compactions: Vec<_> = stores
+            .iter()
+            .map(|store| s.spawn(|| store.compact()))
+            .collect();
+        compactions
+            .into_iter()
+            .try_for_each(|compaction| compaction.join().unwrap())
+    })?;
+    for (i, store) in stores.iter().enumerate() {
+        assert_eq!(store.stats().dead_bytes, 0);
+        for _ in 0..2 {
+            assert_eq!(store.get("key1")?, Some(format!("value{i}-9")));
+        }
+        #[cfg(feature = "metrics")]
+        assert_eq!(store.metrics().cache_hits(), 1);
+    }
+
+    Ok(())
+}
+
+// Without a runtime, the WAL writer should log coalesced writes once their window elapses,
+// without waiting for another write.
+#[test]
+fn writer_flushes_coalesced() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .coalesce_window(Duration::from_millis(10))
+        .open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    std::thread::sleep(Duration::from_millis(200));
+    let wal = std::fs::read_to_string(temp_dir.path().join("wa.log")).unwrap();
+    assert_eq!(wal.lines().count(), 1);
+    assert!(wal.ends_with(" value2\n"));
+
+    Ok(())
+}
+
+// The gRPC interface should serve unary calls, scans, and change streams.
+#[cfg(feature = "grpc")]
+#[tokio::test]
+async fn grpc_api() -> Result<()> {
+    use kvs::grpc::proto::{GetRequest, RemoveRequest, ScanRequest, SetRequest, WatchRequest};
+    use kvs::grpc::{GrpcClient, KvsService};
+    use tokio_stream::{wrappers::TcpListenerStream, StreamExt};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(KvsService::new(store))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+
+    let mut client = GrpcClient::connect(format!("http://{addr}")).await.unwrap();
+    let mut changes = client
+        .watch(WatchRequest {
+            prefix: "user".to_owned(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    for (key, value) in [("user1", "ada"), ("user2", "grace"), ("other", "x")] {
+        client
+            .set(SetRequest {
+                key: key.to_owned(),
+                value: value.to_owned(),
+            })
+            .await
+            .unwrap();
+    }
+    let get = |key: &str| GetRequest {
+        key: key.to_owned(),
+    };
+    assert_eq!(
+        client.get(get("user1")).await.unwrap().into_inner().value,
+        Some("ada".to_owned())
+    );
+
+    let keys: Vec<_> = client
+        .scan(ScanRequest {
+            prefix: "user".to_owned(),
+        })
+        .await
+        .unwrap()
+        .into_inner()
+        .map(|entry| entry.unwrap().key)
+        .collect()
+        .await;
+    assert_eq!(keys, ["user1", "user2"]);
+
+    let remove = || RemoveRequest {
+        key: "user1".to_owned(),
+    };
+    client.remove(remove()).await.unwrap();
+    assert_eq!(
+        client.get(get("user1")).await.unwrap().into_inner().value,
+        None
+    );
+    assert_eq!(
+        client.remove(remove()).await.unwrap_err().code(),
+        tonic::Code::NotFound
+    );
+
+    let mut seen = Vec::new();
+    for _ in 0..3 {
+        let change = changes.next().await.unwrap().unwrap();
+        seen.push((change.key, change.value));
+    }
+    assert_eq!(
+        seen,
+        [
+            ("user1".to_owned(), Some("ada".to_owned())),
+            ("user2".to_owned(), Some("grace".to_owned())),
+            ("user1".to_owned(), None),
+        ]
+    );
+
+    Ok(())
+}
+
+// Watchers should receive changes to keys under their prefix.
+#[test]
+fn watch_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let changes = store.watch("user");
+
+    store.set("user1".to_owned(), "ada".to_owned())?;
+    store.set("other".to_owned(), "x".to_owned())?;
+    store.remove("user1".to_owned())?;
+    assert!(store.remove("user1".to_owned()).is_err());
+
+    assert_eq!(
+        changes.try_iter().collect::<Vec<_>>(),
+        [
+            WatchEvent::Set {
+                key: "user1".to_owned(),
+                value: "ada".to_owned()
+            },
+            WatchEvent::Removed {
+                key: "user1".to_owned()
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+// The TCP client should pool connections, pipeline requests, get and set fields of JSON values,
+// and report server errors.
+#[test]
+fn tcp_client_server() -> Result<()> {
+    use kvs::client::{ClientOptions, KvsClient};
+    use kvs::protocol::{Request, Response};
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use std::net::TcpListener;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Arc::new(KvStore::open(temp_dir.path())?);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let pool = SharedQueueThreadPool::new(16)?;
+    let shutdown = kvs::server::Shutdown::new();
+    thread::spawn(move || kvs::server::run(&store, &listener, &pool, &shutdown));
+
+    let client = KvsClient::connect(addr)?;
+    client.set("user1", "ada")?;
+    client.set("user2", "grace")?;
+    assert_eq!(client.get("user1")?, Some("ada".to_owned()));
+    assert_eq!(client.get("missing")?, None);
+    client.set_path("doc", "/a/b", "1")?;
+    assert_eq!(
+        client.get_path("doc", "$.a")?,
+        Some(r#"{"b":1}"#.to_owned())
+    );
+    assert_eq!(client.get_path("doc", "/c")?, None);
+    assert!(client.get_path("doc", "bogus").is_err());
+    assert_eq!(
+        client.scan("user")?,
+        [
+            ("user1".to_owned(), "ada".to_owned()),
+            ("user2".to_owned(), "grace".to_owned()),
+        ]
+    );
+    client.remove("user1")?;
+    assert!(matches!(
+        client.remove("user1"),
+        Err(KvStoreError::FailedRm(key)) if key == "user1"
+    ));
+
+    let responses = client.pipeline(&[
+        Request::Set {
+            key: "a".to_owned(),
+            value: "1".to_owned(),
+            path: None,
+        },
+        Request::Rm {
+            key: "b".to_owned(),
+        },
+        Request::Get {
+            key: "user2".to_owned(),
+            path: None,
+        },
+    ])?;
+    assert_eq!(
+        responses,
+        [
+            Response::Ok(None),
+            Response::KeyNotFound("b".to_owned()),
+            Response::Ok(Some("grace".to_owned())),
+        ]
+    );
+
+    // Concurrent callers share the pool
+    let client = Arc::new(client);
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let client = Arc::clone(&client);
+            thread::spawn(move || client.set(format!("key{i}"), i.to_string()))
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(client.scan("key")?.len(), 8);
+
+    // Closed port fails after retries
+    let closed = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    assert!(matches!(
+        ClientOptions::new()
+            .retries(1)
+            .backoff(Duration::from_millis(1))
+            .connect(closed),
+        Err(KvStoreError::FailedConnect(_))
+    ));
+
+    Ok(())
+}
+
+// The server should answer pipelined requests as each completes, tagged with their IDs.
+#[test]
+fn tcp_out_of_order() -> Result<()> {
+    use kvs::protocol::{read_frame, write_frame, Envelope, Request, Response};
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use std::net::{TcpListener, TcpStream};
+
+    // A replica whose primary never connects waits for session tokens before redirecting
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = "127.0.0.1:4000".parse().unwrap();
+    let store = Arc::new(
+        OpenOptions::new()
+            .replica_of(primary)
+            .open(temp_dir.path())?,
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let pool = SharedQueueThreadPool::new(1)?;
+    let shutdown = kvs::server::Shutdown::new();
+    thread::spawn(move || kvs::server::run(&store, &listener, &pool, &shutdown));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let wait = Envelope {
+        id: 7,
+        body: Request::WaitFor { sequence: 1 },
+    };
+    let get = Envelope {
+        id: 8,
+        body: Request::Get {
+            key: "a".to_owned(),
+            path: None,
+        },
+    };
+    write_frame(&mut stream, &wait).unwrap();
+    write_frame(&mut stream, &get).unwrap();
+    let response: Option<Envelope<Response>> = read_frame(&mut stream).unwrap();
+    assert_eq!(
+        response,
+        Some(Envelope {
+            id: 8,
+            body: Response::Ok(None)
+        })
+    );
+    let response: Option<Envelope<Response>> = read_frame(&mut stream).unwrap();
+    assert_eq!(
+        response,
+        Some(Envelope {
+            id: 7,
+            body: Response::Redirect(primary)
+        })
+    );
+
+    let bogus = Envelope {
+        id: 9,
+        body: "bogus",
+    };
+    write_frame(&mut stream, &bogus).unwrap();
+    let response: Option<Envelope<Response>> = read_frame(&mut stream).unwrap();
+    assert!(matches!(
+        response,
+        Some(Envelope { id: 9, body: Response::Err(e) }) if e.starts_with("Invalid request")
+    ));
+
+    Ok(())
+}
+
+// Frames longer than the limit should be refused before their body is read or written.
+#[test]
+fn frame_limit() {
+    use kvs::protocol::{read_frame, write_frame, MAX_FRAME};
+
+    let mut frame = (MAX_FRAME + 1).to_be_bytes().to_vec();
+    frame.extend_from_slice(b"\"body\"");
+    let e = read_frame::<String>(&mut frame.as_slice()).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+
+    let mut frame = Vec::new();
+    let e = write_frame(&mut frame, &"x".repeat(MAX_FRAME as usize)).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(frame.is_empty());
+
+    write_frame(&mut frame, &"body").unwrap();
+    assert_eq!(
+        read_frame::<String>(&mut frame.as_slice()).unwrap(),
+        Some("body".to_owned())
+    );
+}
+
+// The TCP server should serve clients over TLS, only accepting those with a trusted certificate.
+#[cfg(feature = "tls")]
+#[test]
+fn tcp_tls() -> Result<()> {
+    use kvs::client::ClientOptions;
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use std::fs;
+    use std::net::TcpListener;
+
+    let certs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pem = |name: &str, contents: String| {
+        let path = certs_dir.path().join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    };
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+    let issue = |name: &str| {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec![name.to_owned()])
+            .unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+        (
+            pem(&format!("{name}.pem"), cert.pem()),
+            pem(&format!("{name}.key"), key.serialize_pem()),
+        )
+    };
+    let ca_path = pem("ca.pem", ca.pem());
+    let (server_cert, server_key) = issue("127.0.0.1");
+    let (client_cert, client_key) = issue("client");
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Arc::new(KvStore::open(temp_dir.path())?);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let pool = SharedQueueThreadPool::new(4)?;
+    let shutdown = kvs::server::Shutdown::new();
+    let mut options = kvs::server::ServerOptions::new();
+    options.tls(kvs::tls::server_config(
+        &server_cert,
+        &server_key,
+        Some(&ca_path),
+    )?);
+    thread::spawn(move || options.run(&store, &listener, &pool, &shutdown));
+
+    let identity = Some((client_cert.as_path(), client_key.as_path()));
+    let client = ClientOptions::new()
+        .tls(kvs::tls::client_config(&ca_path, identity)?)
+        .connect(addr)?;
+    client.set("key", "value")?;
+    assert_eq!(client.get("key")?, Some("value".to_owned()));
+    let gets = vec![
+        kvs::protocol::Request::Get {
+            key: "key".to_owned(),
+            path: None,
+        };
+        100
+    ];
+    assert!(client
+        .pipeline(&gets)?
+        .iter()
+        .all(|response| *response == kvs::protocol::Response::Ok(Some("value".to_owned()))));
+
+    // Clients without a certificate, or not speaking TLS, are turned away
+    let anonymous = ClientOptions::new()
+        .retries(0)
+        .tls(kvs::tls::client_config(&ca_path, None)?)
+        .connect(addr)?;
+    assert!(anonymous.get("key").is_err());
+    let plain = ClientOptions::new().retries(0).connect(addr)?;
+    assert!(plain.get("key").is_err());
+
+    // Servers must present a certificate valid for the name connected to
+    let misnamed = ClientOptions::new()
+        .retries(0)
+        .tls(kvs::tls::client_config(&ca_path, identity)?)
+        .tls_server_name("kvs.example.com")
+        .connect(addr)?;
+    assert!(misnamed.get("key").is_err());
+
+    assert!(matches!(
+        kvs::tls::client_config(&certs_dir.path().join("missing.pem"), None),
+        Err(KvStoreError::FailedTls(_))
+    ));
+
+    Ok(())
+}
+
+// The TCP server should authenticate clients against its ACL and deny requests beyond their
+// permissions.
+#[test]
+fn tcp_auth() -> Result<()> {
+    use kvs::auth::{Acl, Credentials};
+    use kvs::client::{ClientOptions, KvsClient};
+    use kvs::protocol::{Request, Response};
+    use kvs::server::ServerOptions;
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use std::net::TcpListener;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let acl_path = temp_dir.path().join("acl.json");
+    std::fs::write(
+        &acl_path,
+        r#"{"users": [
+            {"name": "ada", "password": "secret", "permissions": {"": "read", "ada/": "write"}},
+            {"name": "ops", "token": "t0k3n", "permissions": {"": "admin"}}
+        ]}"#,
+    )
+    .unwrap();
+    let store = Arc::new(KvStore::open(temp_dir.path())?);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let pool = SharedQueueThreadPool::new(4)?;
+    let shutdown = kvs::server::Shutdown::new();
+    let mut options = ServerOptions::new();
+    options.acl(Acl::load(&acl_path)?);
+    thread::spawn(move || options.run(&store, &listener, &pool, &shutdown));
+
+    let denied = |result: Result<_>, reason: &str| {
+        assert!(matches!(result, Err(KvStoreError::PermissionDenied(e)) if e.contains(reason)));
+    };
+    denied(
+        KvsClient::connect(addr)?.get("key").map(drop),
+        "not authenticated",
+    );
+    let password = |password: &str| Credentials::Password {
+        user: "ada".to_owned(),
+        password: password.to_owned(),
+    };
+    denied(
+        ClientOptions::new()
+            .credentials(password("wrong"))
+            .connect(addr)
+            .map(drop),
+        "invalid credentials",
+    );
+
+    let ada = ClientOptions::new()
+        .credentials(password("secret"))
+        .connect(addr)?;
+    ada.set("ada/key", "value")?;
+    assert_eq!(ada.get("ada/key")?, Some("value".to_owned()));
+    assert_eq!(ada.scan("")?.len(), 1);
+    denied(ada.set("key", "value").map(drop), "lacks write access");
+    denied(ada.promote(), "lacks admin access");
+    let set = Request::Set {
+        key: "key".to_owned(),
+        value: "value".to_owned(),
+        path: None,
+    };
+    assert!(matches!(
+        ada.pipeline(&[set])?.as_slice(),
+        [Response::PermissionDenied(_)]
+    ));
+
+    let ops = ClientOptions::new()
+        .credentials(Credentials::Token("t0k3n".to_owned()))
+        .connect(addr)?;
+    ops.set("key", "value")?;
+    assert!(matches!(ops.promote(), Err(KvStoreError::Remote(_))));
+
+    assert!(matches!(
+        "{\"users\": {}}".parse::<Acl>(),
+        Err(KvStoreError::InvalidAcl(_))
+    ));
+
+    Ok(())
+}
+
+// The server should serve `unix:` addresses, replacing a stale socket but not a live one, and
+// remove its socket once shut down.
+#[cfg(unix)]
+#[test]
+fn unix_socket() -> Result<()> {
+    use kvs::client::ClientOptions;
+    use kvs::server::{ServerOptions, Shutdown};
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use kvs::transport::ServerAddr;
+    use std::os::unix::net::UnixListener;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.sock");
+    drop(UnixListener::bind(&path).unwrap());
+    let store = Arc::new(KvStore::open(temp_dir.path())?);
+    let shutdown = Shutdown::new();
+    let server = {
+        let (store, path, shutdown) = (Arc::clone(&store), path.clone(), shutdown.clone());
+        thread::spawn(move || {
+            let pool = SharedQueueThreadPool::new(2)?;
+            ServerOptions::new().serve_unix(&store, &path, &pool, &shutdown)
+        })
+    };
+
+    let addr: ServerAddr = format!("unix://{}", path.display()).parse()?;
+    assert_eq!(addr, ServerAddr::Unix(path.clone()));
+    let client = ClientOptions::new().retries(8).connect_to(addr.clone())?;
+    client.set("key", "value")?;
+    assert_eq!(client.get("key")?, Some("value".to_owned()));
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    let pool = SharedQueueThreadPool::new(1)?;
+    assert!(matches!(
+        ServerOptions::new().serve_unix(&store, &path, &pool, &shutdown),
+        Err(KvStoreError::FailedServe(_))
+    ));
+
+    drop(client);
+    shutdown.trigger();
+    server.join().unwrap()?;
+    assert!(!path.exists());
+    assert!(matches!(
+        ClientOptions::new().retries(0).connect_to(addr),
+        Err(KvStoreError::FailedConnect(_))
+    ));
+    assert!(matches!(
+        "127.0.0.1:4000".parse::<ServerAddr>()?,
+        ServerAddr::Tcp(addrs) if addrs == ["127.0.0.1:4000".parse().unwrap()]
+    ));
+
+    Ok(())
+}
+
+// Every pool should run all spawned jobs and survive panicking ones.
+#[test]
+fn thread_pools() -> Result<()> {
+    use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+    use std::sync::mpsc;
+
+    fn run_jobs<P: ThreadPool>() -> Result<()> {
+        let pool = P::new(4)?;
+        let (done, finished) = mpsc::channel();
+        for i in 0..4 {
+            pool.spawn(move || assert_ne!(i, i, "job panicked on purpose"));
+        }
+        for i in 0..64 {
+            let done = done.clone();
+            pool.spawn(move || done.send(i).unwrap());
+        }
+
+        let mut ids: Vec<_> = finished.iter().take(64).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, (0..64).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    run_jobs::<NaiveThreadPool>()?;
+    run_jobs::<SharedQueueThreadPool>()?;
+    run_jobs::<RayonThreadPool>()
+}
+
+// Concurrent writers should leave a log that replays to the state they left in memory.
+#[test]
+fn concurrent_writes_replay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Arc::new(KvStore::open(temp_dir.path())?);
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = Arc::clone(&store);
+            thread::spawn(move || -> Result<()> {
+                for i in 0..200 {
+                    let key = format!("key{}", i % 20);
+                    store.set(key.clone(), format!("{t}-{i}"))?;
+                    store.get(key.clone())?;
+                    if i % 7 == 0 {
+                        let _ = store.remove(key);
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let expected = store.scan("")?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan("")?, expected);
+
+    Ok(())
+}
+
+// Writes committed in groups should all be acknowledged and survive a reopen.
+#[test]
+fn group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Arc::new(
+        OpenOptions::new()
+            .group_commit(Duration::from_millis(2))
+            .open(temp_dir.path())?,
+    );
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = Arc::clone(&store);
+            thread::spawn(move || -> Result<()> {
+                for i in 0..25 {
+                    store.set(format!("key{t}-{i}"), i.to_string())?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan("key")?.len(), 200);
+    assert_eq!(store.get("key7-24")?, Some("24".to_owned()));
+
+    Ok(())
+}
+
+// Offset-index mode should read values back from sealed and active segments, across reopens.
+#[test]
+fn offset_index_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        OpenOptions::new()
+            .offset_index(true)
+            .segment_size(256)
+            .open(temp_dir.path())
+    };
+
+    let store = open()?;
+    for i in 0..100 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    store.remove("key0".to_owned())?;
+    assert!(store.remove("key0".to_owned()).is_err());
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key99")?, Some("value99".to_owned()));
+    assert_eq!(store.scan("key9")?.len(), 11);
+
+    let sealed = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter(|e| {
+            let name = e
+                .as_ref()
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .into_owned();
+            name.starts_with("wa.") && name != "wa.log"
+        })
+        .count();
+    assert!(sealed > 1, "expected sealed segments, found {sealed}");
+
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.get("key50")?, Some("value50".to_owned()));
+    assert_eq!(store.scan("")?.len(), 99);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key99")?, Some("value99".to_owned()));
+
+    Ok(())
+}
+
+// The value cache should serve repeated reads and never serve values overwritten or removed.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .offset_index(true)
+        .cache(CacheConfig { capacity_bytes: 64 })
+        .open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1")?, None);
+
+    // Values beyond capacity evict the least recently used ones
+    for i in 0..10 {
+        store.set(format!("key{i}"), "x".repeat(20))?;
+        store.get(format!("key{i}"))?;
+    }
+    assert_eq!(store.get("key0")?, Some("x".repeat(20)));
+
+    #[cfg(feature = "metrics")]
+    {
+        assert_eq!(store.metrics().cache_hits(), 1);
+        assert_eq!(store.metrics().cache_misses(), 14);
+    }
+
+    Ok(())
+}
+
+// `get_ref` should return the values `get` does, borrowed from memory or shared with the
+// value cache, and run the miss hook for missing keys.
+#[test]
+fn get_ref() -> Result<()> {
+    for offset_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let misses = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&misses);
+        let store = OpenOptions::new()
+            .offset_index(offset_index)
+            .cache(CacheConfig { capacity_bytes: 64 })
+            .on_miss(move |key| recorded.lock().unwrap().push(key.to_owned()))
+            .open(temp_dir.path())?;
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        for _ in 0..2 {
+            let value = store.get_ref("key1")?.expect("key1 is set");
+            assert_eq!(value, "value1");
+            assert_eq!(value.len(), 6);
+            assert_eq!(AsRef::<[u8]>::as_ref(&value), b"value1");
+            assert_eq!(value.to_string(), "value1");
+        }
+        assert!(store.get_ref("missing")?.is_none());
+        assert_eq!(*misses.lock().unwrap(), vec!["missing".to_owned()]);
+
+        // Guards are released once dropped or converted
+        let value = store.get_ref("key1")?.map(ValueRef::into_string);
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!(value, Some("value1".to_owned()));
+        assert_eq!(store.get_ref("key1")?.as_deref(), Some("value2"));
+    }
+
+    Ok(())
+}
+
+// Entries should read, modify and write keys atomically with one log record per write, so
+// concurrent increments are never lost.
+#[test]
+fn entry_read_modify_write() -> Result<()> {
+    for offset_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = OpenOptions::new()
+            .offset_index(offset_index)
+            .max_value_len(8)
+            .open(temp_dir.path())?;
+
+        let increment = |value: &mut String| {
+            *value = (value.parse::<u64>().unwrap() + 1).to_string();
+        };
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        store
+                            .entry("counter")
+                            .and_modify(increment)
+                            .and_then(|entry| entry.or_insert_with(|| "1".to_owned()))
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(store.get("counter")?, Some("800".to_owned()));
+        assert_eq!(store.sequence(), 800);
+
+        let entry = store.entry("counter");
+        assert_eq!(entry.key(), "counter");
+        assert_eq!(entry.get()?, Some("800".to_owned()));
+        assert!(matches!(
+            entry.and_modify(|value| value.push_str("000000")),
+            Err(KvStoreError::ValueTooLarge(9, 8))
+        ));
+        assert_eq!(
+            store.entry("counter").or_insert_with(|| "0".to_owned())?,
+            "800"
+        );
+        assert_eq!(store.entry("missing").remove()?, None);
+        assert_eq!(store.sequence(), 800);
+        assert_eq!(store.entry("counter").remove()?, Some("800".to_owned()));
+        assert_eq!(store.get("counter")?, None);
+        assert_eq!(store.sequence(), 801);
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("counter")?, None);
+        assert_eq!(store.sequence(), 801);
+    }
+
+    Ok(())
+}
+
+// `iter` should list every key once, as of one point in time even while a writer sweeps the
+// keys in order, and `kvs dump` should print the pairs.
+#[test]
+fn iter_snapshot() -> Result<()> {
+    for offset_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        // Coalesced writes are applied in memory only, so the writer sweeps fast
+        let store = OpenOptions::new()
+            .offset_index(offset_index)
+            .coalesce_window(Duration::from_mins(1))
+            .open(temp_dir.path())?;
+        let keys: Vec<_> = (0..1000).map(|i| format!("key{i:03}")).collect();
+        for key in &keys {
+            store.set(key.clone(), "0".to_owned())?;
+        }
+
+        let done = std::sync::atomic::AtomicBool::new(false);
+        thread::scope(|s| {
+            // Sets every key to the round number in key order, round after round
+            s.spawn(|| {
+                for round in 1.. {
+                    if done.load(std::sync::atomic::Ordering::Relaxed) {
+                        break;
+                    }
+                    for key in &keys {
+                        store.set(key.clone(), round.to_string()).unwrap();
+                    }
+                }
+            });
+
+            for _ in 0..20 {
+                let entries: Vec<_> = store.iter().unwrap().collect();
+                let listed: Vec<_> = entries.iter().map(|(key, _)| key).collect();
+                assert!(listed.iter().copied().eq(keys.iter()));
+                // Keys before the one being set are a round ahead of those after it
+                let rounds: Vec<u64> = entries.iter().map(|(_, v)| v.parse().unwrap()).collect();
+                assert!(rounds.windows(2).all(|w| w[0] == w[1] || w[0] == w[1] + 1));
+                assert!(rounds[0] <= rounds[rounds.len() - 1] + 1);
+                thread::sleep(Duration::from_millis(1));
+            }
+            done.store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.set("a".to_owned(), "1 one".to_owned())?;
+    let mut iter = store.iter()?;
+    store.remove("a".to_owned())?;
+    assert_eq!(iter.len(), 2);
+    assert_eq!(iter.next(), Some(("a".to_owned(), "1 one".to_owned())));
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("b 2\n"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump", "--json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("{\"key\":\"b\",\"value\":\"2\"}\n"));
+
+    Ok(())
+}
+
+/// Operation applied to both the store and its model in `wal_round_trip`
+#[derive(Clone, Debug)]
+enum Op {
+    Set(usize, String),
+    Rm(usize),
+    Reopen,
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => (any::<usize>(), any::<String>()).prop_map(|(key, value)| Op::Set(key, value)),
+        2 => any::<usize>().prop_map(Op::Rm),
+        1 => Just(Op::Reopen),
+    ]
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    // Arbitrary keys and values, including spaces, newlines and quotes, should survive replay.
+    #[test]
+    fn wal_round_trip(
+        keys in prop::collection::vec(any::<String>(), 1..8),
+        ops in prop::collection::vec(op(), 0..32),
+        offset_index in any::<bool>(),
+    ) {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || OpenOptions::new().offset_index(offset_index).open(temp_dir.path());
+        let mut store = open()?;
+        let mut model = HashMap::new();
+
+        for op in ops {
+            match op {
+                Op::Set(key, value) => {
+                    let key = &keys[key % keys.len()];
+                    store.set(key.clone(), value.clone())?;
+                    model.insert(key.clone(), value);
+                }
+                Op::Rm(key) => {
+                    let key = &keys[key % keys.len()];
+                    prop_assert_eq!(
+                        store.remove(key.clone()).is_ok(),
+                        model.remove(key).is_some()
+                    );
+                }
+                Op::Reopen => {
+                    drop(store);
+                    store = open()?;
+                }
+            }
+        }
+
+        drop(store);
+        let store = open()?;
+        for key in &keys {
+            prop_assert_eq!(store.get(key.clone())?, model.get(key).cloned());
+        }
+    }
+
+    // A radix tree index should find the same keys as a model, as keys sharing prefixes split
+    // and merge its nodes.
+    #[test]
+    fn radix_index(
+        ops in prop::collection::vec(("[ab]{0,5}", any::<u8>()), 0..64),
+    ) {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = OpenOptions::new()
+            .offset_index(true)
+            .key_index(KeyIndex::Radix)
+            .open(temp_dir.path())?;
+        let mut model = std::collections::BTreeMap::new();
+
+        for (key, op) in ops {
+            match op % 4 {
+                0 => {
+                    prop_assert_eq!(store.remove(key.clone()).is_ok(), model.remove(&key).is_some());
+                }
+                1 => {
+                    let removed = store.remove_prefix(&key)?;
+                    let before = model.len();
+                    model.retain(|k: &String, _| !k.starts_with(key.as_str()));
+                    prop_assert_eq!(removed, before - model.len());
+                }
+                _ => {
+                    store.set(key.clone(), op.to_string())?;
+                    model.insert(key, op.to_string());
+                }
+            }
+        }
+
+        for prefix in ["", "a", "ab", "b", "bb", "aba"] {
+            let expected: Vec<_> = model
+                .iter()
+                .filter(|(k, _)| k.starts_with(prefix))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            prop_assert_eq!(store.scan(prefix)?, expected);
+        }
+        for key in model.keys() {
+            prop_assert!(store.contains_key(key));
+        }
+    }
+}
+
+// Buckets should hold separate key spaces that survive replay and compaction.
+#[test]
+fn buckets() -> Result<()> {
+    for offset_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            OpenOptions::new()
+                .offset_index(offset_index)
+                .open(temp_dir.path())
+        };
+        let store = open()?;
+        let users = store.bucket("users");
+        let orders = store.bucket("orders");
+
+        store.set("key1".to_owned(), "default".to_owned())?;
+        assert_eq!(users.set("key1".to_owned(), "user".to_owned())?, None);
+        users.set("key2".to_owned(), "first".to_owned())?;
+        assert_eq!(
+            users.set("key2".to_owned(), "gone".to_owned())?,
+            Some("first".to_owned())
+        );
+        orders.set("key1".to_owned(), "order".to_owned())?;
+        assert_eq!(users.remove("key2".to_owned())?, Some("gone".to_owned()));
+        assert!(matches!(
+            orders.remove("key2".to_owned()),
+            Err(KvStoreError::FailedRm(_))
+        ));
+
+        assert_eq!(store.get("key1")?, Some("default".to_owned()));
+        assert_eq!(users.get("key1")?, Some("user".to_owned()));
+        assert_eq!(users.get("key2")?, None);
+        assert_eq!(
+            store.scan("")?,
+            vec![("key1".to_owned(), "default".to_owned())]
+        );
+        assert_eq!(
+            users.scan("key")?,
+            vec![("key1".to_owned(), "user".to_owned())]
+        );
+
+        // Churn the default key space until the WAL is compacted
+        for i in 0..20_000 {
+            store.set("churn".to_owned(), format!("{i:0>64}"))?;
+        }
+        drop(store);
+
+        let store = open()?;
+        assert_eq!(store.get("key1")?, Some("default".to_owned()));
+        assert_eq!(store.bucket("users").get("key1")?, Some("user".to_owned()));
+        assert_eq!(store.bucket("users").get("key2")?, None);
+        assert_eq!(
+            store.bucket("orders").get("key1")?,
+            Some("order".to_owned())
+        );
+        assert_eq!(store.bucket("missing").scan("")?, vec![]);
+    }
+
+    Ok(())
+}
+
+// Keys should carry versions that guard conditional sets and survive replay and compaction.
+#[test]
+fn key_versions() -> Result<()> {
+    for mode in ["memory", "offset-index", "coalesce"] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            let mut options = OpenOptions::new();
+            options.offset_index(mode == "offset-index");
+            if mode == "coalesce" {
+                options.coalesce_window(Duration::from_mins(1));
+            }
+            options.open(temp_dir.path())
+        };
+        let versioned = |value: &str, version| Some((value.to_owned(), version));
+
+        let store = open()?;
+        assert_eq!(store.get_versioned("key1")?, None);
+        assert_eq!(
+            store.set_if_version("key1".to_owned(), "a".to_owned(), 0)?,
+            1
+        );
+        store.set("key1".to_owned(), "b".to_owned())?;
+        assert_eq!(store.get_versioned("key1")?, versioned("b", 2));
+        assert!(matches!(
+            store.set_if_version("key1".to_owned(), "c".to_owned(), 1),
+            Err(KvStoreError::VersionMismatch(key, 1, 2)) if key == "key1"
+        ));
+        assert!(matches!(
+            store.set_if_version("key1".to_owned(), "c".to_owned(), 0),
+            Err(KvStoreError::VersionMismatch(_, 0, 2))
+        ));
+        assert_eq!(
+            store.set_if_version("key1".to_owned(), "c".to_owned(), 2)?,
+            3
+        );
+        assert_eq!(store.get("key1")?, Some("c".to_owned()));
+
+        // Versions of removed keys start over
+        store.set("key2".to_owned(), "old".to_owned())?;
+        store.set("key2".to_owned(), "old".to_owned())?;
+        store.remove("key2".to_owned())?;
+        assert_eq!(store.get_versioned("key2")?, None);
+        store.set("key2".to_owned(), "new".to_owned())?;
+        drop(store);
+
+        let store = open()?;
+        assert_eq!(store.get_versioned("key1")?, versioned("c", 3));
+        assert_eq!(store.get_versioned("key2")?, versioned("new", 1));
+        store.remove("key2".to_owned())?;
+        assert_eq!(
+            store.set_if_version("key2".to_owned(), "again".to_owned(), 0)?,
+            1
+        );
+
+        // Churn the log until it is compacted
+        for i in 0..20_000 {
+            store.set("churn".to_owned(), format!("{i:0>64}"))?;
+        }
+        drop(store);
+
+        let store = open()?;
+        assert_eq!(store.get_versioned("key1")?, versioned("c", 3));
+        assert_eq!(store.get_versioned("key2")?, versioned("again", 1));
+        assert_eq!(
+            store.get_versioned("churn")?,
+            versioned(&format!("{:0>64}", 19_999), 20_000)
+        );
+    }
+
+    Ok(())
+}
+
+// Values should be readable as of past sequence numbers within the retention window, along
+// with when and by which client they were written, and `kvs history <KEY>` should list them.
+#[test]
+fn history_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1)));
+    let store = OpenOptions::new()
+        .history_retention(3)
+        .clock(clock.clone())
+        .open(temp_dir.path())?;
+
+    let second = Duration::from_secs(1);
+    store.set("key1".to_owned(), "a".to_owned())?;
+    clock.advance(second);
+    store.set("key1".to_owned(), "b".to_owned())?;
+    clock.advance(second);
+    store.set("key2".to_owned(), "x".to_owned())?;
+    clock.advance(second);
+    store.remove("key1".to_owned())?;
+    clock.advance(second);
+    kvs::as_client("10.0.0.1:4000", || {
+        store.set("key1".to_owned(), "c".to_owned())
+    })?;
+    assert_eq!(store.sequence(), 5);
+
+    assert_eq!(store.get_at("key1", 5)?, Some("c".to_owned()));
+    assert_eq!(store.get_at("key1", 4)?, None);
+    assert_eq!(store.get_at("key1", 2)?, Some("b".to_owned()));
+    assert_eq!(store.get_at("key2", 2)?, None);
+    assert_eq!(store.get_at("key2", 3)?, Some("x".to_owned()));
+    assert!(matches!(
+        store.get_at("key1", 1),
+        Err(KvStoreError::HistoryUnavailable(1, 2, 5))
+    ));
+    assert!(matches!(
+        store.get_at("key1", 6),
+        Err(KvStoreError::HistoryUnavailable(6, 2, 5))
+    ));
+
+    // Revisions before the window are dropped but the one holding at its start
+    let revision = |sequence, value: Option<&str>, client: Option<&str>| Revision {
+        sequence,
+        value: value.map(str::to_owned),
+        timestamp: Some(UNIX_EPOCH + Duration::from_secs(sequence)),
+        client: client.map(str::to_owned),
+    };
+    assert_eq!(
+        store.history("key1")?,
+        vec![
+            revision(2, Some("b"), None),
+            revision(4, None, None),
+            revision(5, Some("c"), Some("10.0.0.1:4000"))
+        ]
+    );
+    drop(store);
+
+    // Stamps are replayed as written
+    let store = OpenOptions::new()
+        .history_retention(3)
+        .open(temp_dir.path())?;
+    assert_eq!(
+        store.history("key1")?.last(),
+        Some(&revision(5, Some("c"), Some("10.0.0.1:4000")))
+    );
+    assert_eq!(store.history("missing")?, vec![]);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        store.get_at("key1", 0),
+        Err(KvStoreError::HistoryDisabled)
+    ));
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["history", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("1 a\n2 b\n4 (removed)\n5 c").trim());
+
+    Ok(())
+}
+
+// `kvs log dump` should list the records of the log with their timestamp and client,
+// including those that do not parse, filtered by segment and sequence number.
+#[test]
+fn log_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1)));
+    let store = OpenOptions::new().clock(clock).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    kvs::as_client("10.0.0.1:4000", || {
+        store
+            .bucket("b")
+            .set("key2".to_owned(), "value2".to_owned())
+    })?;
+    store.ts_add("temp", 7, 20.5)?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+    let wal_path = temp_dir.path().join("wa.log");
+    let wal = std::fs::read_to_string(&wal_path).unwrap() + "bogus\n";
+    std::fs::write(&wal_path, wal).unwrap();
+
+    let entries = dump::dump(temp_dir.path(), None, None)?;
+    let summary: Vec<_> = entries
+        .iter()
+        .map(|e| (e.sequence, e.op.as_str(), e.key.as_deref(), e.value_size))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (Some(1), "set", Some("key1"), Some(6)),
+            (Some(2), "bset", Some("key2"), Some(6)),
+            (Some(3), "ts", Some("temp"), None),
+            (Some(4), "rm", Some("key1"), None),
+            (None, "invalid", None, None),
+        ]
+    );
+    assert_eq!(entries[1].bucket.as_deref(), Some("b"));
+    assert_eq!(entries[2].timestamp, Some(1000));
+    assert_eq!(entries[1].client.as_deref(), Some("10.0.0.1:4000"));
+    assert_eq!(entries[2].client, None);
+    assert_eq!(entries[1].offset, 23);
+    assert!(entries[4].error.is_some());
+    assert_eq!(dump::dump(temp_dir.path(), None, Some(3))?.len(), 2);
+    assert_eq!(dump::dump(temp_dir.path(), Some(0), None)?.len(), 5);
+    assert_eq!(dump::dump(temp_dir.path(), Some(1), None)?, vec![]);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["log", "dump", "--since-seq", "4"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("SEGMENT")
+                .and(contains("rm").and(contains("key1")))
+                .and(contains("bset").not()),
+        );
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["log", "dump", "--json", "--since-seq", "2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(
+            "{\"segment\":0,\"offset\":23,\"sequence\":2,\"timestamp\":1000,\
+             \"client\":\"10.0.0.1:4000\",\"op\":\"bset\",\"bucket\":\"b\",\"key\":\"key2\",\
+             \"value_size\":6}\n",
+        ));
+
+    Ok(())
+}
+
+// Only one store should have a directory open at a time, whether the others race to open it
+// from threads or processes, and `try_open_timeout` should wait for it to be closed.
+#[test]
+fn concurrent_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let barrier = Barrier::new(8);
+    let (mut opened, locked): (Vec<_>, Vec<_>) = thread::scope(|s| {
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                s.spawn(|| {
+                    barrier.wait();
+                    let store = KvStore::open(temp_dir.path());
+                    // Keep the winner open until every thread has tried
+                    barrier.wait();
+                    store
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .partition(Result::is_ok)
+    });
+    assert_eq!(opened.len(), 1);
+    assert!(locked
+        .iter()
+        .all(|store| matches!(store, Err(KvStoreError::Locked(_)))));
+
+    let processes: Vec<_> = (0..4)
+        .map(|i| {
+            Command::cargo_bin("kvs")
+                .unwrap()
+                .args(["set", &format!("key{i}"), "value"])
+                .current_dir(&temp_dir)
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    for process in processes {
+        let output = process.wait_with_output().unwrap();
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Locked"));
+    }
+    assert!(matches!(
+        KvStore::try_open_timeout(temp_dir.path(), Duration::from_millis(50)),
+        Err(KvStoreError::Locked(_))
+    ));
+
+    let store = opened.pop().unwrap()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let closer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        drop(store);
+    });
+    let store = KvStore::try_open_timeout(temp_dir.path(), Duration::from_secs(10))?;
+    closer.join().unwrap();
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Ok(())
+}
+
+// Keys and values over the configured limits should be rejected without being logged.
+#[test]
+fn size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .max_key_len(8)
+        .max_value_len(16)
+        .open(temp_dir.path())?;
+
+    store.set("k".repeat(8), "v".repeat(16))?;
+    assert!(matches!(
+        store.set("k".repeat(9), "value".to_owned()),
+        Err(KvStoreError::KeyTooLarge(9, 8))
+    ));
+    assert!(matches!(
+        store.bucket("users").set("key".to_owned(), "v".repeat(17)),
+        Err(KvStoreError::ValueTooLarge(17, 16))
+    ));
+    assert_eq!(store.get("k".repeat(9))?, None);
+    assert_eq!(store.poisoned(), None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan("")?, vec![("k".repeat(8), "v".repeat(16))]);
+    assert_eq!(store.bucket("users").get("key")?, None);
+
+    Ok(())
+}
+
+// Stats should track keys, log usage, segments, compactions and uptime.
+#[test]
+fn store_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let store = OpenOptions::new()
+        .clock(clock.clone())
+        .segment_size(1024)
+        .open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store
+        .bucket("users")
+        .set("key1".to_owned(), "value3".to_owned())?;
+    clock.advance(Duration::from_secs(5));
+
+    let stats = store.stats();
+    assert_eq!(stats.keys, 2);
+    assert_eq!(stats.live_bytes, 50);
+    assert_eq!(stats.dead_bytes, 20);
+    assert_eq!(stats.segments, 1);
+    assert_eq!(stats.last_compaction, None);
+    assert_eq!(stats.uptime, Duration::from_secs(5));
+
+    // Overwrites pile up dead bytes across segments until the log is compacted
+    for i in 0..20_000 {
+        store.set("churn".to_owned(), format!("{i:0>64}"))?;
+    }
+    let mut stats = store.stats();
+    for _ in 0..100 {
+        if stats.last_compaction.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+        stats = store.stats();
+    }
+    assert_eq!(stats.keys, 3);
+    assert_eq!(stats.last_compaction, Some(store.clock().now()));
+
+    Ok(())
+}
+
+// Doctor should report invalid records and leftover files, and repair them by truncation.
+#[test]
+fn doctor_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = |name: &str| temp_dir.path().join(name);
+
+    let clock = Arc::new(ManualClock::default());
+    let store = OpenOptions::new().clock(clock).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    // Segments from the next segment ID on are tracked, as left by an interrupted seal
+    std::fs::write(path("wa.0.log"), "set stale value\n").unwrap();
+    std::fs::write(path("wa.1.base.log"), base_segment(b"set key0 value0\n")).unwrap();
+    std::fs::write(path("wa.2.log"), "set key3 value3\nset \"key\n").unwrap();
+    std::fs::write(path("wa.log.compact"), "set key0").unwrap();
+    let mut wal = std::fs::read_to_string(path("wa.log")).unwrap();
+    wal.push_str("rm missing\nset key2 value2\n");
+    std::fs::write(path("wa.log"), wal).unwrap();
+
+    let report = doctor::check(temp_dir.path(), false)?;
+    assert_eq!(report.files, 3);
+    assert_eq!(report.records, 3);
+    assert_eq!(report.unresolved(), 4);
+    assert_eq!(
+        report.issues[0],
+        Issue::ObsoleteSegment {
+            path: path("wa.0.log")
+        }
+    );
+    assert_eq!(
+        report.issues[1],
+        Issue::Leftover {
+            path: path("wa.log.compact")
+        }
+    );
+    assert!(matches!(
+        &report.issues[2],
+        Issue::Corrupted { path: p, offset: 16, .. } if *p == path("wa.2.log")
+    ));
+    assert_eq!(
+        report.issues[3],
+        Issue::Orphaned {
+            path: path("wa.log"),
+            offset: 20,
+            key: "missing".to_owned()
+        }
+    );
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let report = doctor::check(temp_dir.path(), true)?;
+    assert_eq!(report.unresolved(), 0);
+    assert_eq!(doctor::check(temp_dir.path(), false)?.issues, vec![]);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.scan("")?,
+        vec![
+            ("key0".to_owned(), "value0".to_owned()),
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key3".to_owned(), "value3".to_owned()),
+        ]
+    );
+
+    Ok(())
+}
+
+// Open should load the segments recorded in the manifest, ignoring untracked segment files.
+#[test]
+fn manifest_tracks_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = |name: &str| temp_dir.path().join(name);
+    let manifest = || std::fs::read_to_string(path("MANIFEST")).unwrap();
+    let next_segment = || -> u64 {
+        manifest()
+            .lines()
+            .find_map(|line| line.strip_prefix("next_segment "))
+            .unwrap()
+            .parse()
+            .unwrap()
+    };
+    let open = || OpenOptions::new().segment_size(64).open(temp_dir.path());
+
+    let store = open()?;
+    for i in 0..10 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    drop(store);
+    assert!(manifest().starts_with("version 4\nengine kvs\nnext_segment "));
+    assert!(manifest().contains("\nsegment 0\n"));
+
+    // Sealed before the manifest was updated
+    let next = next_segment();
+    std::fs::write(path(&format!("wa.{next}.log")), "set key0 recovered\n").unwrap();
+    let store = open()?;
+    assert_eq!(store.get("key0".to_owned())?, Some("recovered".to_owned()));
+    assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+    drop(store);
+    assert!(next_segment() > next);
+    assert!(!path("wa.0.log").exists());
+
+    std::fs::write(path("wa.0.log"), "set stray value\n").unwrap();
+    let store = open()?;
+    assert_eq!(store.get("stray".to_owned())?, None);
+    assert_eq!(store.scan("")?.len(), 10);
+    drop(store);
+    assert_eq!(
+        doctor::check(temp_dir.path(), false)?.issues,
+        vec![Issue::ObsoleteSegment {
+            path: path("wa.0.log")
+        }]
+    );
+
+    std::fs::write(
+        path("MANIFEST"),
+        manifest().replace("engine kvs", "engine sled"),
+    )
+    .unwrap();
+    assert!(matches!(open(), Err(KvStoreError::WrongEngine(e)) if e == "sled"));
+
+    Ok(())
+}
+
+/// Returns a base segment holding records, as written by compaction
+fn base_segment(records: &[u8]) -> Vec<u8> {
+    let mut bytes = format!(
+        "kvs-base 1 {:08x} {:016x}\n",
+        crc32fast::hash(records),
+        records.len()
+    )
+    .into_bytes();
+    bytes.extend_from_slice(records);
+    bytes
+}
+
+#[test]
+fn base_segment_checksum() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = |name: &str| temp_dir.path().join(name);
+
+    std::fs::write(path("wa.1.log"), "set key1 old\nset key2 value2\n").unwrap();
+    let mut base = base_segment(b"set key1 new\n");
+    std::fs::write(path("wa.2.base.log"), &base).unwrap();
+    migrate::migrate(temp_dir.path())?;
+    let report = doctor::check(temp_dir.path(), false)?;
+    assert_eq!(
+        report.issues,
+        vec![Issue::ObsoleteSegment {
+            path: path("wa.1.log")
+        }]
+    );
+    assert_eq!(report.records, 1);
+
+    // A corrupted base is set aside, falling back to the segments it replaced
+    *base.last_mut().unwrap() = b' ';
+    std::fs::write(path("wa.2.base.log"), &base).unwrap();
+    let report = doctor::check(temp_dir.path(), false)?;
+    assert!(matches!(
+        &report.issues[..],
+        [Issue::Corrupted { offset: 0, reason, .. }] if reason == "checksum mismatch"
+    ));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("old".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(path("wa.2.base.log.corrupt").is_file());
+    assert!(!path("wa.2.base.log").exists());
+
+    Ok(())
+}
+
+// Shutting down the server should close idle connections and return once they are drained.
+#[test]
+fn server_shutdown() -> Result<()> {
+    use kvs::client::KvsClient;
+    use kvs::server::Shutdown;
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use std::net::TcpListener;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Arc::new(KvStore::open(temp_dir.path())?);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let pool = SharedQueueThreadPool::new(2)?;
+    let shutdown = Shutdown::new();
+    let server = {
+        let store = Arc::clone(&store);
+        let shutdown = shutdown.clone();
+        thread::spawn(move || kvs::server::run(&store, &listener, &pool, &shutdown))
+    };
+
+    // Both pool threads are kept busy by idle connections, and a third one waits in the queue
+    let clients = (0..3)
+        .map(|_| KvsClient::connect(addr))
+        .collect::<Result<Vec<_>>>()?;
+    clients[0].set("key1", "value1")?;
+    clients[1].set("key2", "value2")?;
+
+    shutdown.trigger();
+    server.join().unwrap()?;
+    assert!(clients[0].get("key1").is_err());
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A replica should follow its primary and reject writes of clients until promoted.
+#[test]
+fn replication() -> Result<()> {
+    use kvs::client::KvsClient;
+    use kvs::server::Shutdown;
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use std::net::TcpListener;
+
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = Arc::new(KvStore::open(primary_dir.path())?);
+    primary.set("key1".to_owned(), "value1".to_owned())?;
+    primary
+        .bucket("bucket")
+        .set("key2".to_owned(), "value2".to_owned())?;
+    let store = KvStore::open(replica_dir.path())?;
+    store.set("stale".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let replica = Arc::new(
+        OpenOptions::new()
+            .replica_of("127.0.0.1:4000".parse().unwrap())
+            .open(replica_dir.path())?,
+    );
+    let shutdown = Shutdown::new();
+    let server = {
+        let replica = Arc::clone(&replica);
+        let pool = SharedQueueThreadPool::new(2)?;
+        let shutdown = shutdown.clone();
+        thread::spawn(move || kvs::server::run(&replica, &listener, &pool, &shutdown))
+    };
+    let replicator = kvs::replication::replicate_to(&primary, addr, &shutdown)?;
+
+    // Replication is asynchronous
+    let eventually = |check: &dyn Fn() -> Result<bool>| -> Result<()> {
+        for _ in 0..100 {
+            if check()? {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("replica did not catch up");
+    };
+    eventually(&|| Ok(replica.get("stale")?.is_none()))?;
+    assert_eq!(replica.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(
+        replica.bucket("bucket").get("key2")?,
+        Some("value2".to_owned())
+    );
+    primary.set("key3".to_owned(), "value3".to_owned())?;
+    primary.remove("key1".to_owned())?;
+    eventually(&|| Ok(replica.get("key1")?.is_none()))?;
+    assert_eq!(replica.get("key3")?, Some("value3".to_owned()));
+
+    let client = KvsClient::connect(addr)?;
+    assert!(matches!(
+        client.set("key4", "value4"),
+        Err(KvStoreError::Remote(e)) if e.starts_with("Read-only replica")
+    ));
+    client.promote()?;
+    assert_eq!(replica.replica_of(), None);
+    client.set("key4", "value4")?;
+    assert!(client.promote().is_err());
+
+    shutdown.trigger();
+    replicator.join().unwrap();
+    server.join().unwrap()?;
+    assert_eq!(replica.get("key4")?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Reads with a session token should observe the write it came from, on a replica that
+// caught up or by redirecting to the primary.
+#[test]
+fn session_tokens() -> Result<()> {
+    use kvs::client::KvsClient;
+    use kvs::server::Shutdown;
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use std::net::TcpListener;
+
+    let shutdown = Shutdown::new();
+    let serve = |store: &Arc<KvStore>| {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let store = Arc::clone(store);
+        let shutdown = shutdown.clone();
+        let server = thread::spawn(move || {
+            let pool = SharedQueueThreadPool::new(2)?;
+            kvs::server::run(&store, &listener, &pool, &shutdown)
+        });
+        (addr, server)
+    };
+
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let lagging_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = Arc::new(KvStore::open(primary_dir.path())?);
+    let (primary_addr, primary_server) = serve(&primary);
+    let replica = Arc::new(
+        OpenOptions::new()
+            .replica_of(primary_addr)
+            .open(replica_dir.path())?,
+    );
+    let (replica_addr, replica_server) = serve(&replica);
+    // Never receives records
+    let lagging = Arc::new(
+        OpenOptions::new()
+            .replica_of(primary_addr)
+            .open(lagging_dir.path())?,
+    );
+    let (lagging_addr, lagging_server) = serve(&lagging);
+    let replicator = kvs::replication::replicate_to(&primary, replica_addr, &shutdown)?;
+
+    let client = KvsClient::connect(primary_addr)?;
+    let token = client.set_with_token("key1", "value1")?;
+    assert!(token > 0);
+    assert_eq!(
+        KvsClient::connect(replica_addr)?.get_after("key1", token)?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(replica.sequence(), token);
+    assert_eq!(
+        KvsClient::connect(lagging_addr)?.get_after("key1", token)?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(lagging.sequence(), 0);
+
+    let token = client.remove_with_token("key1")?;
+    assert_eq!(
+        KvsClient::connect(replica_addr)?.get_after("key1", token)?,
+        None
+    );
+
+    shutdown.trigger();
+    replicator.join().unwrap();
+    for server in [primary_server, replica_server, lagging_server] {
+        server.join().unwrap()?;
+    }
+
+    Ok(())
+}
+
+// A sharded client should spread keys across servers, keep serving the keys of healthy
+// servers when one is down, and move keys when resharding.
+#[test]
+fn sharded_client() -> Result<()> {
+    use kvs::client::{ClientOptions, ShardedClient};
+    use kvs::server::Shutdown;
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use std::net::TcpListener;
+
+    let shutdown = Shutdown::new();
+    let dirs: Vec<_> = (0..3)
+        .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+        .collect();
+    let mut stores = Vec::new();
+    let mut addrs = Vec::new();
+    let mut servers = Vec::new();
+    for dir in &dirs {
+        let store = Arc::new(KvStore::open(dir.path())?);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        addrs.push(listener.local_addr().unwrap());
+        let (server_store, shutdown) = (Arc::clone(&store), shutdown.clone());
+        servers.push(thread::spawn(move || {
+            let pool = SharedQueueThreadPool::new(2)?;
+            kvs::server::run(&server_store, &listener, &pool, &shutdown)
+        }));
+        stores.push(store);
+    }
+
+    let mut client = ShardedClient::new(&addrs[..2]);
+    for i in 0..100 {
+        client.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    assert_eq!(client.get("key42")?, Some("value42".to_owned()));
+    assert_eq!(client.scan("key")?.len(), 100);
+    let counts = |stores: &[Arc<KvStore>]| -> Result<Vec<usize>> {
+        stores.iter().map(|s| Ok(s.scan("")?.len())).collect()
+    };
+    let before = counts(&stores)?;
+    assert!(before[0] > 0 && before[1] > 0 && before[2] == 0);
+    assert_eq!(before.iter().sum::<usize>(), 100);
+
+    // Only the keys of the new server move
+    let previous = client.clone();
+    client.add_shard(addrs[2]);
+    let moved = client.reshard_from(&previous)?;
+    let after = counts(&stores)?;
+    assert_eq!(after[2], moved);
+    assert!(moved > 0 && after[0] <= before[0] && after[1] <= before[1]);
+    for (store, &addr) in stores.iter().zip(&addrs) {
+        for (key, _) in store.scan("")? {
+            assert_eq!(client.shard_for(&key), Some(addr));
+        }
+    }
+    assert_eq!(client.scan("")?.len(), 100);
+
+    // Keys of an unreachable server fail without affecting the others
+    let down = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let client = ClientOptions::new()
+        .retries(0)
+        .timeout(Some(Duration::from_secs(1)))
+        .sharded(&[addrs[0], down]);
+    let key_on = |addr| {
+        (0..1000)
+            .map(|i| format!("key{i}"))
+            .find(|key| client.shard_for(key) == Some(addr))
+            .unwrap()
+    };
+    client.set(key_on(addrs[0]), "value")?;
+    assert!(matches!(
+        client.set(key_on(down), "value"),
+        Err(KvStoreError::FailedConnect(_))
+    ));
+
+    shutdown.trigger();
+    for server in servers {
+        server.join().unwrap()?;
+    }
+
+    Ok(())
+}
+
+// A Raft cluster should elect a leader, replicate writes sent to any member, and catch up a
+// member added later.
+#[cfg(feature = "raft")]
+#[test]
+fn raft_cluster() -> Result<()> {
+    use kvs::client::KvsClient;
+    use kvs::raft::Role;
+    use kvs::server::Shutdown;
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use std::net::{SocketAddr, TcpListener};
+
+    let eventually = |check: &dyn Fn() -> Result<bool>| -> Result<()> {
+        for _ in 0..200 {
+            if check()? {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("cluster did not converge");
+    };
+
+    let shutdown = Shutdown::new();
+    let mut threads = Vec::new();
+    let mut start = |dir: &TempDir, listener: TcpListener, members: &[SocketAddr]| {
+        let addr = listener.local_addr().unwrap();
+        let store = Arc::new(OpenOptions::new().cluster(addr, members).open(dir.path())?);
+        threads.push(kvs::raft::run(&store, &shutdown)?);
+        let (server_store, shutdown) = (Arc::clone(&store), shutdown.clone());
+        threads.push(thread::spawn(move || {
+            let pool = SharedQueueThreadPool::new(4).unwrap();
+            kvs::server::run(&server_store, &listener, &pool, &shutdown).unwrap();
+        }));
+        Result::Ok(store)
+    };
+
+    let dirs: Vec<_> = (0..4)
+        .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+        .collect();
+    let listeners: Vec<_> = (0..4)
+        .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+        .collect();
+    let addrs: Vec<_> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+    let mut listeners = listeners.into_iter();
+    let mut stores = Vec::new();
+    for dir in &dirs[..3] {
+        stores.push(start(dir, listeners.next().unwrap(), &addrs[..3])?);
+    }
+
+    let clients = addrs[..3]
+        .iter()
+        .map(KvsClient::connect)
+        .collect::<Result<Vec<_>>>()?;
+    eventually(&|| {
+        let mut leaders = 0;
+        let mut known = std::collections::HashSet::new();
+        for client in &clients {
+            let status = client.cluster_status()?;
+            leaders += usize::from(status.role == Role::Leader);
+            known.insert(status.leader);
+        }
+        Ok(leaders == 1 && known.len() == 1 && !known.contains(&None))
+    })?;
+    let leader = clients[0].cluster_status()?.leader.unwrap();
+    let follower = *addrs[..3].iter().find(|&&addr| addr != leader).unwrap();
+
+    // Followers redirect writes to the leader
+    let client = KvsClient::connect(follower)?;
+    client.set("key1", "value1")?;
+    client.set("key2", "value2")?;
+    client.remove("key2")?;
+    assert!(matches!(
+        stores[0].check_writable(),
+        Err(KvStoreError::ClusterWrite)
+    ));
+    eventually(&|| {
+        Ok(stores.iter().all(|store| {
+            store.get("key1").unwrap() == Some("value1".to_owned())
+                && store.get("key2").unwrap().is_none()
+        }))
+    })?;
+
+    // A new member joins with the current members and catches up once added
+    stores.push(start(&dirs[3], listeners.next().unwrap(), &addrs[..3])?);
+    client.cluster_add_node(addrs[3])?;
+    client.set("key3", "value3")?;
+    eventually(&|| Ok(stores[3].get("key3")? == Some("value3".to_owned())))?;
+    assert_eq!(stores[3].get("key1")?, Some("value1".to_owned()));
+    let status = KvsClient::connect(addrs[3])?.cluster_status()?;
+    assert_eq!(status.members.len(), 4);
+    assert_eq!(status.leader, Some(leader));
+
+    shutdown.trigger();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    Ok(())
+}
+
+// `kvs-server` should exit cleanly with its WAL flushed on SIGTERM.
+#[cfg(unix)]
+#[test]
+fn server_sigterm() -> Result<()> {
+    use kvs::client::KvsClient;
+    use std::net::TcpListener;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr.to_string()])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+
+    let client = (0..100)
+        .find_map(|_| {
+            thread::sleep(Duration::from_millis(20));
+            KvsClient::connect(addr).ok()
+        })
+        .expect("server did not start");
+    client.set("key1", "value1").unwrap();
+
+    Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(server.wait().unwrap().success());
+    drop(client);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// `kvs-server check-config` should validate the config file as overridden by flags, and the
+// server should serve with its settings.
+#[test]
+fn server_config() -> Result<()> {
+    use kvs::client::KvsClient;
+    use std::net::TcpListener;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = temp_dir.path().join("kvs.toml");
+    let write_config = |extra: &str| {
+        let settings = format!(
+            "data_dir = {:?}\naddr = \"{addr}\"\nsync = \"always\"\n{extra}",
+            data_dir.display().to_string()
+        );
+        std::fs::write(&config, settings).unwrap();
+    };
+    let check = |args: &[&str]| {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--config", config.to_str().unwrap()])
+            .args(args)
+            .arg("check-config")
+            .current_dir(&temp_dir)
+            .assert()
+    };
+
+    write_config("");
+    check(&[]).failure().stderr(contains("is not a directory"));
+    std::fs::create_dir(&data_dir).unwrap();
+    check(&[]).success().stdout(contains("Config OK"));
+    write_config("cache_size = 1024\n");
+    check(&[])
+        .failure()
+        .stderr(contains("cache_size requires engine offset-index"));
+    check(&["--engine", "offset-index"]).success();
+    write_config("threshold = 1\n");
+    check(&[])
+        .failure()
+        .stderr(contains("unknown field `threshold`"));
+    write_config("[tls]\ncert = \"missing.pem\"\nkey = \"missing.pem\"\n");
+    check(&[]).failure();
+    write_config("[eviction]\npolicy = \"lfu\"\nmax_keys = 1000\n");
+    check(&[]).success();
+    write_config("[[triggers]]\nprefix = \"a\"\ncommand = []\n");
+    check(&[])
+        .failure()
+        .stderr(contains("has an empty command"));
+
+    write_config("engine = \"offset-index\"\ncache_size = 1024\n");
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", config.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let client = (0..100)
+        .find_map(|_| {
+            thread::sleep(Duration::from_millis(20));
+            KvsClient::connect(addr).ok()
+        })
+        .expect("server did not start");
+    client.set("key1", "value1").unwrap();
+    Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(server.wait().unwrap().success());
+    drop(client);
+
+    let store = KvStore::open(&data_dir)?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// `kvs-server` should reload its ACL on SIGHUP and on a reload request from an admin, applying
+// it to open connections, and resize the value cache of a running store.
+#[cfg(unix)]
+#[test]
+fn server_reload() -> Result<()> {
+    use kvs::auth::Credentials;
+    use kvs::client::ClientOptions;
+    use std::net::TcpListener;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let acl = temp_dir.path().join("acl.json");
+    let write_acl = |access: &str| {
+        let users = format!(
+            r#"{{"users": [
+                {{"name": "ada", "password": "secret", "permissions": {{"": "{access}"}}}},
+                {{"name": "ops", "token": "t0k3n", "permissions": {{"": "admin"}}}}
+            ]}}"#
+        );
+        std::fs::write(&acl, users).unwrap();
+    };
+    write_acl("read");
+    let config = temp_dir.path().join("kvs.toml");
+    std::fs::write(
+        &config,
+        format!("addr = \"{addr}\"\nauth_config = \"acl.json\"\n"),
+    )
+    .unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", config.to_str().unwrap(), "--threads", "4"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+
+    let connect = |credentials: Credentials| {
+        (0..100)
+            .find_map(|_| {
+                thread::sleep(Duration::from_millis(20));
+                ClientOptions::new()
+                    .pool_size(1)
+                    .credentials(credentials.clone())
+                    .connect(addr)
+                    .ok()
+            })
+            .expect("server did not start")
+    };
+    let ada = connect(Credentials::Password {
+        user: "ada".to_owned(),
+        password: "secret".to_owned(),
+    });
+    let ops = connect(Credentials::Token("t0k3n".to_owned()));
+    assert!(matches!(
+        ada.set("key", "value"),
+        Err(KvStoreError::PermissionDenied(_))
+    ));
+    assert!(matches!(
+        ada.reload(),
+        Err(KvStoreError::PermissionDenied(_))
+    ));
+
+    write_acl("write");
+    Command::new("kill")
+        .args(["-HUP", &server.id().to_string()])
+        .status()
+        .unwrap();
+    (0..100)
+        .find(|_| {
+            thread::sleep(Duration::from_millis(20));
+            ada.set("key", "value").is_ok()
+        })
+        .expect("ACL was not reloaded");
+
+    write_acl("read");
+    ops.reload().unwrap();
+    assert!(matches!(
+        ada.set("key", "value"),
+        Err(KvStoreError::PermissionDenied(_))
+    ));
+    std::fs::write(&acl, "{").unwrap();
+    assert!(matches!(ops.reload(), Err(KvStoreError::Remote(e)) if e.contains("Invalid ACL")));
+    assert_eq!(ada.get("key").unwrap(), Some("value".to_owned()));
+
+    Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(server.wait().unwrap().success());
+
+    let store = OpenOptions::new()
+        .offset_index(true)
+        .cache(CacheConfig {
+            capacity_bytes: 1024,
+        })
+        .open(temp_dir.path())?;
+    assert!(store.set_cache_capacity(0));
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    drop(store);
+    assert!(!KvStore::open(temp_dir.path())?.set_cache_capacity(0));
+
+    Ok(())
+}
+
+// `kvs-server` should serve admin commands on its admin address only, and `kvs admin` should
+// send them: compaction drops superseded records and a snapshot rewrites the log as a base
+// segment.
+#[cfg(unix)]
+#[test]
+fn admin_commands() -> Result<()> {
+    use kvs::client::KvsClient;
+    use std::net::TcpListener;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let port = || {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    };
+    let (addr, admin_addr) = (port(), port());
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr.to_string()])
+        .args(["--admin-listen", &admin_addr.to_string()])
+        .args(["--threads", "4"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+
+    let connect = |addr| {
+        (0..100)
+            .find_map(|_| {
+                thread::sleep(Duration::from_millis(20));
+                KvsClient::connect(addr).ok()
+            })
+            .expect("server did not start")
+    };
+    let client = connect(addr);
+    let admin = connect(admin_addr);
+    for i in 0..10 {
+        client.set("key", format!("value{i}")).unwrap();
+    }
+    assert!(
+        matches!(client.compact(), Err(KvStoreError::Remote(e)) if e.contains("admin address"))
+    );
+    assert!(matches!(admin.get("key"), Err(KvStoreError::Remote(e)) if e.contains("only admin")));
+
+    let stats = admin.stats().unwrap();
+    assert_eq!(stats.keys, 1);
+    assert!(stats.dead_bytes > 0);
+    assert_eq!(stats.last_compaction, None);
+    admin.compact().unwrap();
+    let stats = admin.stats().unwrap();
+    assert_eq!(stats.dead_bytes, 0);
+    assert!(stats.last_compaction.is_some());
+    admin.flush().unwrap();
+
+    client.set("other", "value").unwrap();
+    admin.snapshot().unwrap();
+    assert_eq!(admin.stats().unwrap().segments, 2);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args([
+            "admin",
+            "stats",
+            "--json",
+            "--server",
+            &admin_addr.to_string(),
+        ])
+        .assert()
+        .success()
+        .stdout(contains(r#""keys":2"#));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["admin", "compact", "--server", &addr.to_string()])
+        .assert()
+        .failure()
+        .stderr(contains("admin address"));
+
+    Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(server.wait().unwrap().success());
+    drop((client, admin));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key")?, Some("value9".to_owned()));
+    assert_eq!(store.get("other")?, Some("value".to_owned()));
+    store.compact()?;
+    store.snapshot()?;
+    assert_eq!(store.stats().dead_bytes, 0);
+
+    Ok(())
+}
+
+// The server should keep the last requests exceeding the slow query threshold, with their
+// command and key, for `kvs admin slowlog`.
+#[test]
+fn slow_query_log() -> Result<()> {
+    use kvs::client::KvsClient;
+    use kvs::server::ServerOptions;
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use std::net::TcpListener;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Arc::new(KvStore::open(temp_dir.path())?);
+    let serve = |options: ServerOptions| {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (store, pool) = (Arc::clone(&store), SharedQueueThreadPool::new(2)?);
+        let shutdown = kvs::server::Shutdown::new();
+        thread::spawn(move || options.run(&store, &listener, &pool, &shutdown));
+        KvsClient::connect(addr)
+    };
+
+    let client = serve(ServerOptions::new())?;
+    assert!(matches!(client.slow_log(), Err(KvStoreError::Remote(e)) if e.contains("not enabled")));
+
+    let client = serve(ServerOptions::new().slow_log(Duration::ZERO, 2).clone())?;
+    client.set("a", "1")?;
+    client.get("a")?;
+    client.scan("prefix")?;
+    let queries = client.slow_log()?;
+    let described: Vec<_> = queries
+        .iter()
+        .map(|query| (query.command.as_str(), query.key.as_deref()))
+        .collect();
+    assert_eq!(described, [("get", Some("a")), ("scan", Some("prefix"))]);
+    assert!(queries[0].client.starts_with("127.0.0.1:"));
+
+    let client = serve(
+        ServerOptions::new()
+            .slow_log(Duration::from_secs(10), 2)
+            .clone(),
+    )?;
+    client.set("a", "1")?;
+    assert!(client.slow_log()?.is_empty());
+
+    Ok(())
+}
+
+// Server refuses connections over the limit and requests over the rate limits as busy
+#[test]
+fn server_limits() -> Result<()> {
+    use kvs::client::KvsClient;
+    use kvs::server::ServerOptions;
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use std::net::TcpListener;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Arc::new(KvStore::open(temp_dir.path())?);
+    let serve = |options: ServerOptions| {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (store, pool) = (Arc::clone(&store), SharedQueueThreadPool::new(4)?);
+        let shutdown = kvs::server::Shutdown::new();
+        thread::spawn(move || options.run(&store, &listener, &pool, &shutdown));
+        Ok::<_, KvStoreError>(addr)
+    };
+    let busy = |result: Result<Option<String>>, reason: &str| matches!(result, Err(KvStoreError::Busy(e)) if e.contains(reason));
+
+    let addr = serve(ServerOptions::new().max_connections(1).clone())?;
+    let first = KvsClient::connect(addr)?;
+    first.set("a", "1")?;
+    let second = KvsClient::connect(addr)?;
+    assert!(busy(second.get("a"), "too many connections"));
+    assert_eq!(first.get("a")?, Some("1".to_owned()));
+    drop(first);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(KvsClient::connect(addr)?.get("a")?, Some("1".to_owned()));
+
+    let addr = serve(ServerOptions::new().connection_rate_limit(2).clone())?;
+    let client = KvsClient::connect(addr)?;
+    client.get("a")?;
+    client.get("a")?;
+    assert!(busy(client.get("a"), "rate limit exceeded"));
+    assert_eq!(KvsClient::connect(addr)?.get("a")?, Some("1".to_owned()));
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(client.get("a")?, Some("1".to_owned()));
+
+    let addr = serve(ServerOptions::new().rate_limit(2).clone())?;
+    let (a, b) = (KvsClient::connect(addr)?, KvsClient::connect(addr)?);
+    a.get("a")?;
+    b.get("a")?;
+    assert!(busy(a.get("a"), "rate limit exceeded"));
+    assert!(busy(b.get("a"), "rate limit exceeded"));
+
+    Ok(())
+}
+
+// Client gives up on requests after their deadline and does not mix up late responses
+#[test]
+fn client_request_timeout() -> Result<()> {
+    use kvs::client::ClientOptions;
+    use kvs::protocol::{read_frame, write_frame, Envelope, Request, Response};
+    use std::net::TcpListener;
+
+    // Answers the first request on the first connection late, and the others at once
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for (n, stream) in listener.incoming().enumerate() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let mut reader = stream.try_clone().unwrap();
+                while let Ok(Some(Envelope { id, body })) =
+                    read_frame::<Envelope<Request>>(&mut reader)
+                {
+                    let value = match body {
+                        Request::Get { key, .. } if key == "slow" && n == 0 => {
+                            thread::sleep(Duration::from_millis(500));
+                            "late"
+                        }
+                        _ => "fresh",
+                    };
+                    let body = Response::Ok(Some(value.to_owned()));
+                    if write_frame(&mut stream, &Envelope { id, body }).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+
+    let client = ClientOptions::new()
+        .request_timeout(Some(Duration::from_millis(100)))
+        .connect(addr)?;
+    let start = Instant::now();
+    assert!(matches!(client.get("slow"), Err(KvStoreError::Timeout(_))));
+    assert!(start.elapsed() < Duration::from_millis(400));
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(client.get("key")?, Some("fresh".to_owned()));
+
+    let responses = client.pipeline_timeout(
+        &[Request::Get {
+            key: "key".to_owned(),
+            path: None,
+        }],
+        Duration::from_secs(1),
+    )?;
+    assert_eq!(responses, [Response::Ok(Some("fresh".to_owned()))]);
+
+    Ok(())
+}
+
+// Ping should report the health of the store of a server, and fail for a lagging replica
+#[test]
+fn ping_health() -> Result<()> {
+    use kvs::client::KvsClient;
+    use kvs::server::Shutdown;
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use std::net::TcpListener;
+
+    let shutdown = Shutdown::new();
+    let serve = |store: &Arc<KvStore>| {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let store = Arc::clone(store);
+        let shutdown = shutdown.clone();
+        let server = thread::spawn(move || {
+            let pool = SharedQueueThreadPool::new(2)?;
+            kvs::server::run(&store, &listener, &pool, &shutdown)
+        });
+        (addr, server)
+    };
+    let ping = |addr: std::net::SocketAddr, args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(["ping", "--server", &addr.to_string()]).args(args);
+        cmd.assert()
+    };
+
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = Arc::new(KvStore::open(primary_dir.path())?);
+    let (primary_addr, primary_server) = serve(&primary);
+    let replica = Arc::new(
+        OpenOptions::new()
+            .replica_of(primary_addr)
+            .open(replica_dir.path())?,
+    );
+    let (replica_addr, replica_server) = serve(&replica);
+
+    let client = KvsClient::connect(primary_addr)?;
+    let health = client.ping()?;
+    assert!(health.writable);
+    assert_eq!(health.replication_lag, None);
+    client.set("key", "value")?;
+    client.flush()?;
+    assert!(client.ping()?.last_sync.is_some());
+    ping(primary_addr, &[])
+        .success()
+        .stdout(contains("Writable: yes").and(contains("not a replica")));
+
+    // Unreplicated for now, so the replica lags since it was opened
+    thread::sleep(Duration::from_millis(200));
+    ping(replica_addr, &["--max-lag-ms", "100"])
+        .failure()
+        .stderr(contains("NotReady"));
+
+    let replicator = kvs::replication::replicate_to(&primary, replica_addr, &shutdown)?;
+    // Heartbeats keep the lag of an idle primary short
+    thread::sleep(Duration::from_millis(2500));
+    let lag = KvsClient::connect(replica_addr)?.ping()?.replication_lag;
+    assert!(
+        lag.is_some_and(|lag| lag < Duration::from_millis(1500)),
+        "{lag:?}"
+    );
+    ping(replica_addr, &["--json", "--max-lag-ms", "1500"])
+        .success()
+        .stdout(contains(r#""writable":true"#));
+
+    shutdown.trigger();
+    replicator.join().unwrap();
+    for server in [primary_server, replica_server] {
+        server.join().unwrap()?;
+    }
+
+    Ok(())
+}
+
+// Frozen stores should reject writes and compaction but serve reads until thawed, also through
+// admin commands to a server
+#[test]
+fn freeze_writes() -> Result<()> {
+    use kvs::client::KvsClient;
+    use kvs::server::ServerOptions;
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use std::net::TcpListener;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Arc::new(KvStore::open(temp_dir.path())?);
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.freeze_writes()?;
+    assert!(store.is_frozen());
+    assert!(matches!(
+        store.set("key".to_owned(), "other".to_owned()),
+        Err(KvStoreError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key".to_owned()),
+        Err(KvStoreError::ReadOnly)
+    ));
+    assert!(matches!(store.compact(), Err(KvStoreError::ReadOnly)));
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    store.thaw();
+    store.set("key".to_owned(), "other".to_owned())?;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let pool = SharedQueueThreadPool::new(2)?;
+    let server_store = Arc::clone(&store);
+    thread::spawn(move || {
+        let shutdown = kvs::server::Shutdown::new();
+        ServerOptions::new().run(&server_store, &listener, &pool, &shutdown)
+    });
+    let client = KvsClient::connect(addr)?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["admin", "freeze", "--server", &addr.to_string()])
+        .assert()
+        .success();
+    assert!(matches!(
+        client.set("key", "value"),
+        Err(KvStoreError::ReadOnly)
+    ));
+    assert_eq!(client.get("key")?, Some("other".to_owned()));
+    client.thaw()?;
+    client.set("key", "value")?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Hooks should see executed commands and their outcome, and veto commands, but not see
+// replayed records
+#[test]
+fn command_hooks() -> Result<()> {
+    use kvs::Hook;
+
+    #[derive(Clone, Default)]
+    struct Audit(Arc<Mutex<Vec<String>>>);
+
+    impl Hook for Audit {
+        fn before_execute(&self, cmd: &kvs::Command) -> Result<()> {
+            match cmd {
+                kvs::Command::Set { key, .. } if key.starts_with("locked/") => {
+                    Err(KvStoreError::Vetoed(format!("{key} is locked")))
+                }
+                _ => Ok(()),
+            }
+        }
+
+        fn after_execute(&self, cmd: &kvs::Command, error: Option<&KvStoreError>) {
+            let outcome = if error.is_some() { "failed" } else { "ok" };
+            self.0.lock().unwrap().push(format!("{cmd} {outcome}"));
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let audit = Audit::default();
+    let store = OpenOptions::new()
+        .hook(audit.clone())
+        .open(temp_dir.path())?;
+    store.execute(kvs::Command::Set {
+        key: "key".to_owned(),
+        value: "value".to_owned(),
+        path: None,
+        nx: false,
+        xx: false,
+    })?;
+    assert!(store
+        .execute(kvs::Command::Rm {
+            key: "missing".to_owned(),
+            prefix: None,
+        })
+        .is_err());
+    assert!(matches!(
+        store.execute(kvs::Command::Set {
+            key: "locked/key".to_owned(),
+            value: "value".to_owned(),
+            path: None,
+            nx: false,
+            xx: false,
+        }),
+        Err(KvStoreError::Vetoed(e)) if e.contains("locked/key")
+    ));
+    assert_eq!(store.get("locked/key".to_owned())?, None);
+    assert_eq!(*audit.0.lock().unwrap(), ["set ok", "rm failed"]);
+    drop(store);
+
+    let audit = Audit::default();
+    let store = OpenOptions::new()
+        .hook(audit.clone())
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    assert!(audit.0.lock().unwrap().is_empty());
+
+    Ok(())
+}
+
+// Triggers should call their function or run their command with each change of the keys with
+// their prefix, until shut down.
+#[test]
+fn key_triggers() -> Result<()> {
+    use kvs::server::Shutdown;
+    use kvs::trigger::{self, Trigger};
+    use std::sync::{Arc, Mutex};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let shutdown = Shutdown::new();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&events);
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut handles = vec![trigger::spawn(
+        &store,
+        Trigger::callback("users/", move |event| {
+            seen.lock().unwrap().push(event.clone());
+        }),
+        &shutdown,
+    )?];
+    assert!(matches!(
+        trigger::spawn(&store, Trigger::command("users/", Vec::new()), &shutdown),
+        Err(KvStoreError::InvalidConfig(_))
+    ));
+    #[cfg(unix)]
+    let log = temp_dir.path().join("triggered.log");
+    #[cfg(unix)]
+    handles.push(trigger::spawn(
+        &store,
+        Trigger::command(
+            "users/",
+            vec![
+                "sh".to_owned(),
+                "-c".to_owned(),
+                format!("cat >> {:?}", log.display().to_string()),
+            ],
+        ),
+        &shutdown,
+    )?);
+
+    store.set("users/ada".to_owned(), "lovelace".to_owned())?;
+    store.set("groups/admins".to_owned(), "ada".to_owned())?;
+    store.remove("users/ada".to_owned())?;
+    let expected = vec![
+        WatchEvent::Set {
+            key: "users/ada".to_owned(),
+            value: "lovelace".to_owned(),
+        },
+        WatchEvent::Removed {
+            key: "users/ada".to_owned(),
+        },
+    ];
+    for _ in 0..100 {
+        if events.lock().unwrap().len() == expected.len() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(*events.lock().unwrap(), expected);
+
+    #[cfg(unix)]
+    {
+        let mut lines = Vec::new();
+        for _ in 0..100 {
+            lines = std::fs::read_to_string(&log)
+                .unwrap_or_default()
+                .lines()
+                .map(str::to_owned)
+                .collect();
+            if lines.len() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(
+            lines,
+            [
+                r#"{"event":"set","key":"users/ada","value":"lovelace"}"#,
+                r#"{"event":"removed","key":"users/ada"}"#,
+            ]
+        );
+    }
+
+    shutdown.trigger();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    Ok(())
+}
+
+// Segments should be archived to object storage as they are sealed, and restored into a new
+// store directory as of the last archived segment or an earlier point in time.
+#[cfg(feature = "archive")]
+#[test]
+#[allow(clippy::too_many_lines)] // Serves fake object storage
+fn archive_restore() -> Result<()> {
+    use axum::{
+        body::Bytes,
+        extract::{Path as UriPath, State},
+        http::{HeaderMap, Method, StatusCode},
+        Router,
+    };
+    use kvs::archive::{self, Archive, Restored};
+    use kvs::server::Shutdown;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    type Objects = Arc<Mutex<HashMap<String, Bytes>>>;
+    let objects = Objects::default();
+    let s3 = |State(objects): State<Objects>,
+              method: Method,
+              UriPath(path): UriPath<String>,
+              headers: HeaderMap,
+              body: Bytes| async move {
+        let signed = headers
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("AWS4-HMAC-SHA256 Credential=test/"));
+        if !signed {
+            return (StatusCode::FORBIDDEN, Bytes::new());
+        }
+        let mut objects = objects.lock().unwrap();
+        match method {
+            Method::PUT => {
+                objects.insert(path, body);
+                (StatusCode::OK, Bytes::new())
+            }
+            _ => objects
+                .get(&path)
+                .map_or((StatusCode::NOT_FOUND, Bytes::new()), |object| {
+                    (StatusCode::OK, object.clone())
+                }),
+        }
+    };
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let listener = runtime
+        .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+        .unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let app = Router::new()
+        .route("/{*path}", axum::routing::any(s3))
+        .with_state(Arc::clone(&objects));
+    runtime.spawn(async move { axum::serve(listener, app).await });
+
+    assert!(matches!(
+        Archive::new("http://backups"),
+        Err(KvStoreError::InvalidArchive(_))
+    ));
+    let mut archive = Archive::new("s3://backups/node1")?;
+    archive
+        .endpoint(endpoint.as_str())
+        .credentials("test", "secret");
+    let now = || {
+        thread::sleep(Duration::from_millis(20));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        u64::try_from(now.as_millis()).unwrap()
+    };
+
+    // Each record fills a segment
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let shutdown = Shutdown::new();
+    assert!(matches!(
+        archive::spawn(&KvStore::open(temp_dir.path())?, archive.clone(), &shutdown),
+        Err(KvStoreError::InvalidConfig(_))
+    ));
+    let store = OpenOptions::new()
+        .segment_size(64)
+        .archive_segments(true)
+        .open(temp_dir.path())?;
+    let archiver = archive::spawn(&store, archive.clone(), &shutdown)?;
+    let padded = |value: &str| format!("{value:<64}");
+    store.set("a".to_owned(), padded("1"))?;
+    store.set("b".to_owned(), padded("1"))?;
+    let before_snapshot = now();
+    store.set("a".to_owned(), padded("2"))?;
+    store.remove("b".to_owned())?;
+    store.set("c".to_owned(), padded("3"))?;
+    store.snapshot()?;
+    let after_snapshot = now();
+    store.set("d".to_owned(), padded("4"))?;
+    store.flush()?;
+    shutdown.trigger();
+    archiver.join().unwrap();
+    let pending = std::fs::read_dir(temp_dir.path().join("archive")).unwrap();
+    assert_eq!(pending.count(), 0);
+    assert!(objects
+        .lock()
+        .unwrap()
+        .contains_key("backups/node1/CATALOG"));
+
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore = |name: &str, until: Option<u64>| -> Result<(Restored, KvStore)> {
+        let dir = restore_dir.path().join(name);
+        let restored = archive::restore(&dir, &archive, until)?;
+        Ok((restored, KvStore::open(dir)?))
+    };
+    let values = |store: &KvStore| -> Result<Vec<Option<String>>> {
+        ["a", "b", "c", "d"]
+            .into_iter()
+            .map(|key| Ok(store.get(key.to_owned())?.map(|v| v.trim_end().to_owned())))
+            .collect()
+    };
+    let some = |value: &str| Some(value.to_owned());
+
+    let (restored, latest) = restore("latest", None)?;
+    assert_eq!(restored.records, 4);
+    assert_eq!(values(&latest)?, [some("2"), None, some("3"), some("4")]);
+    let (_, snapshot) = restore("snapshot", Some(after_snapshot))?;
+    assert_eq!(values(&snapshot)?, [some("2"), None, some("3"), None]);
+    let (_, early) = restore("early", Some(before_snapshot))?;
+    assert_eq!(values(&early)?, [some("1"), some("1"), None, None]);
+
+    let cli_dir = restore_dir.path().join("cli");
+    std::fs::create_dir(&cli_dir).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args([
+            "restore",
+            "--from",
+            "s3://backups/node1",
+            "--endpoint",
+            &endpoint,
+        ])
+        .env("AWS_ACCESS_KEY_ID", "test")
+        .env("AWS_SECRET_ACCESS_KEY", "secret")
+        .current_dir(&cli_dir)
+        .assert()
+        .success()
+        .stdout(contains("Restored 4 records"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "d"])
+        .current_dir(&cli_dir)
+        .assert()
+        .success()
+        .stdout(contains("4"));
+
+    drop(latest);
+    assert!(matches!(
+        archive::restore(restore_dir.path().join("latest"), &archive, None),
+        Err(KvStoreError::FailedArchive(_))
+    ));
+    Ok(())
+}
+
+// Tailing the WAL should send the records logged from a sequence number on, or the live records
+// once compaction dropped some of them, then each record committed; `kvs backup` should copy
+// them into a directory a store can be opened from, and keep it up to date when following.
+#[test]
+#[allow(clippy::too_many_lines)] // Copies through the store, a client and the CLI
+fn wal_tail() -> Result<()> {
+    use kvs::client::KvsClient;
+    use kvs::server::Shutdown;
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use kvs::Shipment;
+    use std::net::TcpListener;
+
+    // Records without their sequence number, timestamp and client
+    let ops = |records: &str| -> Vec<String> {
+        records
+            .lines()
+            .map(|line| {
+                let fields = line.split(' ').skip(2);
+                let fields: Vec<_> = fields.filter(|f| !f.starts_with('@')).collect();
+                fields.join(" ")
+            })
+            .collect()
+    };
+    let recv = |tail: &std::sync::mpsc::Receiver<Shipment>| {
+        tail.recv_timeout(Duration::from_secs(5)).unwrap()
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Arc::new(KvStore::open(temp_dir.path())?);
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.remove("a".to_owned())?;
+
+    let tail = store.tail(1);
+    let Shipment::Committed(records, 3) = recv(&tail) else {
+        panic!("expected the records logged so far");
+    };
+    assert_eq!(ops(&records), ["set a 1", "set b 2", "rm a"]);
+    store.set("c".to_owned(), "3".to_owned())?;
+    let Shipment::Committed(records, 4) = recv(&tail) else {
+        panic!("expected the record committed");
+    };
+    assert_eq!(ops(&records), ["set c 3"]);
+    let Shipment::Committed(records, 4) = recv(&store.tail(3)) else {
+        panic!("expected the records logged from sequence number 3 on");
+    };
+    assert_eq!(ops(&records), ["rm a", "set c 3"]);
+
+    store.compact()?;
+    let Shipment::Snapshot(records, 4) = recv(&store.tail(3)) else {
+        panic!("expected a snapshot once compacted");
+    };
+    assert_eq!(ops(&records), ["set b 2", "set c 3"]);
+    let Shipment::Committed(records, 4) = recv(&store.tail(1)) else {
+        panic!("expected the live records from the first one on");
+    };
+    assert_eq!(ops(&records), ["set b 2", "set c 3"]);
+    assert_eq!(recv(&store.tail(5)), Shipment::Committed(String::new(), 4));
+
+    let shutdown = Shutdown::new();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = {
+        let store = Arc::clone(&store);
+        let shutdown = shutdown.clone();
+        thread::spawn(move || {
+            let pool = SharedQueueThreadPool::new(4)?;
+            kvs::server::run(&store, &listener, &pool, &shutdown)
+        })
+    };
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["backup", "--server", &addr.to_string(), "--from-seq", "3"])
+        .assert()
+        .success()
+        .stdout(contains("set b 2").and(contains("set c 3")));
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup = backup_dir.path().join("wa.log");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["backup", "--server", &addr.to_string(), "--dir"])
+        .arg(backup_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("Copied records through sequence number 4"));
+
+    // Resumes after the last record copied, also given the directory by its former flag
+    let mut follower = Command::cargo_bin("kvs")
+        .unwrap()
+        .args([
+            "backup",
+            "--server",
+            &addr.to_string(),
+            "--follow",
+            "--output",
+        ])
+        .arg(backup_dir.path())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let client = KvsClient::connect(addr).unwrap();
+    let mut remote = client.tail(5).unwrap();
+    assert_eq!(
+        remote.next().unwrap().unwrap(),
+        Shipment::Committed(String::new(), 4)
+    );
+    client.set("d", "4").unwrap();
+    client.remove("b").unwrap();
+    let Shipment::Committed(records, 5) = remote.next().unwrap().unwrap() else {
+        panic!("expected the record committed");
+    };
+    assert_eq!(ops(&records), ["set d 4"]);
+
+    for _ in 0..100 {
+        if ops(&std::fs::read_to_string(&backup).unwrap()).len() == 4 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    follower.kill().unwrap();
+    follower.wait().unwrap();
+    drop(remote);
+    let copy = KvStore::open(backup_dir.path())?;
+    assert_eq!(copy.get("b".to_owned())?, None);
+    assert_eq!(copy.get("c".to_owned())?, Some("3".to_owned()));
+    assert_eq!(copy.get("d".to_owned())?, Some("4".to_owned()));
+
+    shutdown.trigger();
+    server.join().unwrap()?;
+    Ok(())
+}
+
+// Importing Redis dumps should set the string keys of the chosen database, skipping expired keys
+// and keys of other types, from RDB files and AOF files with or without an RDB preamble.
+#[test]
+#[allow(clippy::too_many_lines)] // Goes through RDB files, AOF files and the CLI
+fn redis_import() -> Result<()> {
+    use kvs::import::{self, ImportFormat, Imported};
+    use std::fmt::Write;
+
+    let mut rdb = b"REDIS0011\xfa\x09redis-ver\x057.2.0\xfe\x00\xfb\x06\x02".to_vec();
+    rdb.extend_from_slice(b"\x00\x01a\x011");
+    // Strings with 14-bit and 32-bit lengths
+    rdb.extend_from_slice(b"\x00\x01m\x40\x64");
+    rdb.extend_from_slice("m".repeat(100).as_bytes());
+    rdb.extend_from_slice(b"\x00\x03big\x80");
+    rdb.extend_from_slice(&20_000u32.to_be_bytes());
+    rdb.extend_from_slice("b".repeat(20_000).as_bytes());
+    // Integer-encoded and LZF-compressed strings
+    rdb.extend_from_slice(b"\x00\x01n\xc0\x2a");
+    rdb.extend_from_slice(b"\x00\x01z\xc3\x05\x0a\x00a\xe0\x00\x00");
+    rdb.extend_from_slice(b"\xfc");
+    rdb.extend_from_slice(&1000u64.to_le_bytes());
+    rdb.extend_from_slice(b"\x00\x01x\x01v");
+    rdb.extend_from_slice(b"\xfc");
+    rdb.extend_from_slice(&2_000_000_000_000u64.to_le_bytes());
+    rdb.extend_from_slice(b"\x00\x01t\x01w");
+    rdb.extend_from_slice(b"\x01\x01l\x02\x01p\x01q");
+    rdb.extend_from_slice(b"\xfe\x01\x00\x05other\x01o");
+    rdb.extend_from_slice(b"\xff\0\0\0\0\0\0\0\0");
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dump = temp_dir.path().join("dump.rdb");
+    std::fs::write(&dump, &rdb).unwrap();
+    let clock = Arc::new(ManualClock::new(
+        UNIX_EPOCH + Duration::from_secs(1_000_000),
+    ));
+    let store_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new().clock(clock).open(store_dir.path())?;
+    let imported = import::import(&store, &[&dump], ImportFormat::RedisRdb, 0)?;
+    assert_eq!(
+        imported,
+        Imported {
+            keys: 6,
+            ttls_dropped: 1,
+            expired: 1,
+            other_types: 1,
+            ..Imported::default()
+        }
+    );
+    for (key, value) in [
+        ("a", "1".to_owned()),
+        ("m", "m".repeat(100)),
+        ("big", "b".repeat(20_000)),
+        ("n", "42".to_owned()),
+        ("z", "a".repeat(10)),
+        ("t", "w".to_owned()),
+    ] {
+        assert_eq!(store.get(key.to_owned())?, Some(value));
+    }
+    assert_eq!(store.get("x".to_owned())?, None);
+    assert_eq!(store.get("l".to_owned())?, None);
+    assert_eq!(store.get("other".to_owned())?, None);
+    import::import(&store, &[&dump], ImportFormat::RedisRdb, 1)?;
+    assert_eq!(store.get("other".to_owned())?, Some("o".to_owned()));
+    assert!(matches!(
+        import::import(&store, &[temp_dir.path()], ImportFormat::RedisRdb, 0),
+        Err(KvStoreError::FailedImport(_))
+    ));
+    std::fs::write(&dump, &rdb[..rdb.len() - 20]).unwrap();
+    assert!(matches!(
+        import::import(&store, &[&dump], ImportFormat::RedisRdb, 0),
+        Err(KvStoreError::InvalidImport(_))
+    ));
+    drop(store);
+
+    // AOF with an RDB preamble and a command cut short by a crash
+    let resp = |args: &[&str]| {
+        let mut command = format!("*{}\r\n", args.len());
+        for arg in args {
+            write!(command, "${}\r\n{arg}\r\n", arg.len()).unwrap();
+        }
+        command
+    };
+    let mut aof = rdb.clone();
+    for args in [
+        &["SELECT", "0"][..],
+        &["MULTI"],
+        &["SET", "a", "2"],
+        &["set", "b", "x", "EX", "10"],
+        &["EXEC"],
+        &["DEL", "n"],
+        &["RPUSH", "l2", "x"],
+        &["INCRBY", "c", "5"],
+        &["APPEND", "c", "0"],
+        &["SETNX", "a", "3"],
+        &["SELECT", "1"],
+        &["SET", "c", "1"],
+    ] {
+        aof.extend_from_slice(resp(args).as_bytes());
+    }
+    aof.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\nd");
+    let aof_path = temp_dir.path().join("appendonly.aof");
+    std::fs::write(&aof_path, aof).unwrap();
+    let cli_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "--format", "redis-aof"])
+        .arg(&aof_path)
+        .current_dir(&cli_dir)
+        .assert()
+        .success()
+        .stdout(eq(
+            "Imported 7 keys, dropping 2 TTLs; skipped 1 expired keys, 2 keys of other types\n",
+        ));
+    let store = KvStore::open(cli_dir.path())?;
+    for (key, value) in [("a", "2"), ("b", "x"), ("c", "50"), ("t", "w")] {
+        assert_eq!(store.get(key.to_owned())?, Some(value.to_owned()));
+    }
+    assert_eq!(store.get("n".to_owned())?, None);
+    assert_eq!(store.get("d".to_owned())?, None);
+
+    Ok(())
+}
+
+// Importing Redis dumps with lengths too large for their contents, or for memory, should fail
+// without allocating for them.
+#[test]
+fn redis_import_malformed() -> Result<()> {
+    use kvs::import::{self, ImportFormat};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    // An LZF string claiming a length its input cannot decompress to
+    let mut rdb = b"REDIS0011\x00\x01z\xc3\x05\x80".to_vec();
+    rdb.extend_from_slice(&u32::MAX.to_be_bytes());
+    rdb.extend_from_slice(b"\x00a\xe0\x00\x00\xff\0\0\0\0\0\0\0\0");
+    let dump = temp_dir.path().join("dump.rdb");
+    std::fs::write(&dump, &rdb).unwrap();
+    assert!(matches!(
+        import::import(&store, &[&dump], ImportFormat::RedisRdb, 0),
+        Err(KvStoreError::InvalidImport(_))
+    ));
+
+    // A bulk string length overflowing the position in the file
+    let aof = temp_dir.path().join("appendonly.aof");
+    std::fs::write(&aof, format!("*1\r\n${}\r\nx\r\n", usize::MAX)).unwrap();
+    assert!(matches!(
+        import::import(&store, &[&aof], ImportFormat::RedisAof, 0),
+        Err(KvStoreError::InvalidImport(_))
+    ));
+    assert_eq!(store.len(), 0);
+
+    Ok(())
+}
+
+// `kvs export` should write the store to a new SQLite file with a single `kv` table, which
+// `kvs import --format sqlite` reads back into another store.
+#[cfg(feature = "sqlite")]
+#[test]
+fn sqlite_export_import() -> Result<()> {
+    use kvs::import::{self, ImportFormat, Imported};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+    drop(store);
+
+    let out_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = out_dir.path().join("out.db");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "sqlite"])
+        .arg(&db)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Exported 2 keys\n"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("export")
+        .arg(&db)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let connection = rusqlite::Connection::open(&db).unwrap();
+    let value: Vec<u8> = connection
+        .query_row("SELECT value FROM kv WHERE key = 'key2'", (), |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(value, b"value2");
+    connection
+        .execute(
+            "INSERT INTO kv (key, value) VALUES ('text', 'plain'), ('binary', x'ff')",
+            (),
+        )
+        .unwrap();
+    drop(connection);
+
+    let import_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(import_dir.path())?;
+    assert_eq!(
+        import::import(&store, &[&db], ImportFormat::Sqlite, 0)?,
+        Imported {
+            keys: 3,
+            not_utf8: 1,
+            ..Imported::default()
+        }
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("text".to_owned())?, Some("plain".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert!(matches!(
+        import::import(
+            &store,
+            &[out_dir.path().join("missing.db")],
+            ImportFormat::Sqlite,
+            0
+        ),
+        Err(KvStoreError::Sqlite(_))
+    ));
+
+    Ok(())
+}
+
+// `kvs load` should apply `set` and `rm` lines and JSON lines from stdin, so that the output of
+// `kvs dump --format jsonl` loads into another store, and stop at a malformed line.
+#[test]
+fn cli_load_pipe() {
+    // Unlike `std::process::Command`, feeds stdin
+    use assert_cmd::Command;
+
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["load", "--batch", "2"])
+        .write_stdin(
+            "set key1 value one\r\n\nset key2 value2\nset key3 value3\nrm key3\nrm missing\n",
+        )
+        .current_dir(&source_dir)
+        .assert()
+        .success()
+        .stdout(eq("Loaded 5 commands\n"));
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump", "--format", "jsonl"])
+        .current_dir(&source_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "{\"key\":\"key1\",\"value\":\"value one\"}\n{\"key\":\"key2\",\"value\":\"value2\"}\n"
+    );
+
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut input = output.stdout;
+    input.extend_from_slice(b"{\"key\":\"key2\",\"value\":null}\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("load")
+        .write_stdin(input)
+        .current_dir(&target_dir)
+        .assert()
+        .success()
+        .stdout(eq("Loaded 3 commands\n"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("load")
+        .write_stdin("set key3 value3\nget key1\nset key4 value4\n")
+        .current_dir(&target_dir)
+        .assert()
+        .failure()
+        .stdout(contains("line 2"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump"])
+        .current_dir(&target_dir)
+        .assert()
+        .success()
+        .stdout(eq("key1 value one\nkey3 value3\n"));
+}
+
+// `kvs completions` should print a completion script for each shell, and `kvs man` the man page
+// of the CLI, or write those of its subcommands to a directory given by `--dir` or its former
+// name `--output`.
+#[test]
+fn cli_completions_man() {
+    for (shell, start) in [
+        ("bash", "_kvs()"),
+        ("zsh", "#compdef kvs"),
+        ("fish", ""),
+        ("powershell", ""),
+    ] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["completions", shell])
+            .assert()
+            .success()
+            .stdout(contains(start).and(contains("dump")));
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["completions", "ksh"])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("man")
+        .assert()
+        .success()
+        .stdout(contains(".TH kvs 1").and(contains("kvs\\-dump")));
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["man", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success();
+    assert!(temp_dir.path().join("kvs.1").exists());
+    assert!(temp_dir.path().join("kvs-dump.1").exists());
+    assert!(temp_dir.path().join("kvs-admin-flush.1").exists());
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--output", "json", "man", "--output"])
+        .arg(temp_dir.path())
+        .assert()
+        .success();
+    assert!(temp_dir.path().join("kvs.1").exists());
+}
+
+// The CLI should exit with the stable code of the kind of error, and with `--output json` print
+// a JSON result with the value or the error of any command.
+#[test]
+fn cli_exit_codes_json() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+
+    kvs(&["set", "key1", "value1", "--output", "json"])
+        .code(0)
+        .stdout(eq("{\"ok\":true,\"value\":null}\n"));
+    kvs(&["get", "key1", "--output", "json"])
+        .code(0)
+        .stdout(eq("{\"ok\":true,\"value\":\"value1\"}\n"));
+    kvs(&["get", "key2", "--output=json"])
+        .code(0)
+        .stdout(eq("{\"ok\":true,\"value\":null}\n"));
+    kvs(&["rm", "key2"])
+        .code(2)
+        .stdout(contains("Key not found"));
+    kvs(&["--output", "json", "rm", "key2"]).code(2).stdout(eq(
+        "{\"ok\":false,\"code\":\"key_not_found\",\"exit_code\":2,\"message\":\"Key not found: key2\"}\n",
+    ));
+    kvs(&["stats", "--output", "json"])
+        .code(0)
+        .stdout(contains("{\"ok\":true,\"value\":\"Keys: 1\\n"));
+    kvs(&["log", "dump", "--json", "--output", "json"])
+        .code(0)
+        .stdout(contains("{\"ok\":true,\"value\":\"{\\\"segment\\\":0"));
+    kvs(&["unknown"]).code(1);
+    kvs(&["unknown", "--output", "json"])
+        .code(1)
+        .stdout(contains(
+            "{\"ok\":false,\"code\":\"usage\",\"exit_code\":1,",
+        ));
+    kvs(&["--help"]).code(0);
+    kvs(&[
+        "admin",
+        "flush",
+        "--server",
+        "127.0.0.1:1",
+        "--output",
+        "json",
+    ])
+    .code(3)
+    .stdout(contains("{\"ok\":false,\"code\":\"io\",\"exit_code\":3,"));
+
+    let _lock = KvStore::open(temp_dir.path()).unwrap();
+    kvs(&["get", "key1"]).code(6);
+}
+
+// `kvs get` should tell a key holding the empty string from a missing key, which prints
+// "Key not found" and exits with zero, as `KvStore::execute` returns `None` for it.
+#[test]
+fn cli_get_empty_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("empty".to_owned(), String::new())?;
+    assert_eq!(
+        store.execute(kvs::Command::Get {
+            key: "empty".to_owned(),
+            path: None,
+        })?,
+        Some(String::new())
+    );
+    assert_eq!(
+        store.execute(kvs::Command::Get {
+            key: "missing".to_owned(),
+            path: None,
+        })?,
+        None
+    );
+    drop(store);
+
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    kvs(&["get", "empty"]).success().stdout(eq("\n"));
+    kvs(&["get", "missing"])
+        .success()
+        .stdout(eq("Key not found\n"));
+    kvs(&["get", "empty", "--output", "json"])
+        .success()
+        .stdout(eq("{\"ok\":true,\"value\":\"\"}\n"));
+    kvs(&["get", "missing", "--output", "json"])
+        .success()
+        .stdout(eq("{\"ok\":true,\"value\":null}\n"));
+
+    Ok(())
+}
+
+// `set` should return the previous value of the key and `rm` the removed one, in each write
+// mode of the store, through `KvStore::execute` and over the wire.
+#[test]
+fn set_remove_previous_value() -> Result<()> {
+    use kvs::client::KvsClient;
+    use kvs::server::Shutdown;
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use std::net::TcpListener;
+
+    for offset_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = OpenOptions::new()
+            .offset_index(offset_index)
+            .open(temp_dir.path())?;
+        assert_eq!(store.set("key1".to_owned(), "value1".to_owned())?, None);
+        assert_eq!(
+            store.set("key1".to_owned(), "value2".to_owned())?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(store.remove("key1".to_owned())?, "value2");
+        assert!(matches!(
+            store.remove("key1".to_owned()),
+            Err(KvStoreError::FailedRm(_))
+        ));
+        assert_eq!(
+            store.execute(kvs::Command::Set {
+                key: "key1".to_owned(),
+                value: "value3".to_owned(),
+                path: None,
+                nx: false,
+                xx: false,
+            })?,
+            None
+        );
+        assert_eq!(
+            store.execute(kvs::Command::Rm {
+                key: "key1".to_owned(),
+                prefix: None,
+            })?,
+            Some("value3".to_owned())
+        );
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Arc::new(KvStore::open(temp_dir.path())?);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let pool = SharedQueueThreadPool::new(2)?;
+    let shutdown = Shutdown::new();
+    let server = {
+        let store = Arc::clone(&store);
+        let shutdown = shutdown.clone();
+        thread::spawn(move || kvs::server::run(&store, &listener, &pool, &shutdown))
+    };
+
+    let client = KvsClient::connect(addr)?;
+    assert_eq!(client.set("key1", "value1")?, None);
+    assert_eq!(client.set("key1", "value2")?, Some("value1".to_owned()));
+    assert_eq!(client.remove("key1")?, Some("value2".to_owned()));
+    assert!(matches!(
+        client.remove("key1"),
+        Err(KvStoreError::FailedRm(_))
+    ));
+
+    drop(client);
+    shutdown.trigger();
+    server.join().unwrap()?;
+
+    Ok(())
+}
+
+// `contains_key` and `len` should count the keys of the store without buckets, in each read
+// mode, and `kvs exists` should signal presence by its exit code while `kvs count` prints `len`.
+#[test]
+fn exists_count() -> Result<()> {
+    for offset_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = OpenOptions::new()
+            .offset_index(offset_index)
+            .open(temp_dir.path())?;
+        assert!(store.is_empty());
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), String::new())?;
+        store
+            .bucket("bucket")
+            .set("key3".to_owned(), "value3".to_owned())?;
+        store.remove("key1".to_owned())?;
+        assert!(!store.contains_key("key1"));
+        assert!(store.contains_key("key2"));
+        assert!(!store.contains_key("key3"));
+        assert_eq!(store.len(), 1);
+        assert!(!store.is_empty());
+        drop(store);
+
+        let kvs = |args: &[&str]| {
+            Command::cargo_bin("kvs")
+                .unwrap()
+                .args(args)
+                .current_dir(&temp_dir)
+                .assert()
+        };
+        kvs(&["exists", "key2"]).code(0).stdout(eq("true\n"));
+        kvs(&["exists", "key1"]).code(2).stdout(eq("false\n"));
+        kvs(&["exists", "key1", "--output", "json"])
+            .code(2)
+            .stdout(eq("{\"ok\":true,\"value\":\"false\"}\n"));
+        kvs(&["count"]).success().stdout(eq("1\n"));
+    }
+
+    Ok(())
+}
+
+// `rename` and `copy` should move and copy values with single records, surviving compaction
+// and reopening in each write mode, and be available as `kvs rename` and `kvs copy`.
+#[test]
+fn rename_copy() -> Result<()> {
+    for mode in 0..3 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            let mut options = OpenOptions::new();
+            match mode {
+                1 => options.offset_index(true),
+                2 => options.coalesce_window(Duration::from_secs(10)),
+                _ => &mut options,
+            };
+            options.open(temp_dir.path())
+        };
+
+        let store = open()?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.rename("key1".to_owned(), "key3".to_owned())?;
+        store.rename("key3".to_owned(), "key2".to_owned())?;
+        assert!(matches!(
+            store.rename("key1".to_owned(), "key4".to_owned()),
+            Err(KvStoreError::KeyNotFound(_))
+        ));
+        store.copy("key2", "key4".to_owned(), false)?;
+        assert!(matches!(
+            store.copy("key2", "key4".to_owned(), false),
+            Err(KvStoreError::KeyExists(_))
+        ));
+        store.set("key2".to_owned(), "value3".to_owned())?;
+        store.copy("key2", "key4".to_owned(), true)?;
+        assert_eq!(store.get("key1")?, None);
+        assert_eq!(store.get("key2")?, Some("value3".to_owned()));
+        assert_eq!(store.get("key3")?, None);
+        assert_eq!(store.get_versioned("key4")?, Some(("value3".to_owned(), 2)));
+        store.rename("key4".to_owned(), "key1".to_owned())?;
+        store.compact()?;
+        drop(store);
+
+        let store = open()?;
+        assert_eq!(
+            store.scan("")?,
+            vec![
+                ("key1".to_owned(), "value3".to_owned()),
+                ("key2".to_owned(), "value3".to_owned()),
+            ]
+        );
+        assert_eq!(store.get_versioned("key1")?, Some(("value3".to_owned(), 1)));
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    kvs(&["set", "key1", "value1"]).success();
+    kvs(&["rename", "key1", "key2"]).success();
+    kvs(&["copy", "key2", "key3"]).success();
+    kvs(&["copy", "key2", "key3"]).code(5);
+    kvs(&["rename", "key1", "key4"]).code(2);
+    kvs(&["get", "key1"]).stdout(eq("Key not found\n"));
+    kvs(&["get", "key3"]).stdout(eq("value1\n"));
+    kvs(&["log", "dump"]).stdout(contains("mv"));
+
+    Ok(())
+}
+
+// `remove_prefix` and `clear` should remove keys with a single record, surviving compaction
+// and reopening in each write mode and leaving buckets as is, and be available as
+// `kvs rm --prefix` and `kvs clear --yes`.
+#[test]
+fn remove_prefix_clear() -> Result<()> {
+    for mode in 0..3 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            let mut options = OpenOptions::new();
+            match mode {
+                1 => options.offset_index(true),
+                2 => options.coalesce_window(Duration::from_secs(10)),
+                _ => &mut options,
+            };
+            options.history_retention(100).open(temp_dir.path())
+        };
+
+        let store = open()?;
+        for key in ["user:1", "user:2", "user:3", "team:1"] {
+            store.set(key.to_owned(), "value".to_owned())?;
+        }
+        store
+            .bucket("bucket")
+            .set("user:4".to_owned(), "value".to_owned())?;
+        store.flush()?;
+        let sequence = store.sequence();
+        assert_eq!(store.remove_prefix("user:")?, 3);
+        assert_eq!(store.sequence(), sequence + 1);
+        assert_eq!(store.remove_prefix("user:")?, 0);
+        assert_eq!(store.sequence(), sequence + 1);
+        assert_eq!(store.get_at("user:1", sequence)?, Some("value".to_owned()));
+        assert_eq!(store.get_at("user:1", sequence + 1)?, None);
+        store.set("user:5".to_owned(), "value".to_owned())?;
+        store.compact()?;
+        drop(store);
+
+        let store = open()?;
+        assert_eq!(store.len(), 2);
+        assert!(store.contains_key("user:5"));
+        assert_eq!(
+            store.bucket("bucket").get("user:4")?,
+            Some("value".to_owned())
+        );
+        assert_eq!(store.clear()?, 2);
+        drop(store);
+
+        let store = open()?;
+        assert!(store.is_empty());
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    kvs(&["set", "user:1", "value"]).success();
+    kvs(&["set", "user:2", "value"]).success();
+    kvs(&["set", "team:1", "value"]).success();
+    kvs(&["rm", "--prefix", "user:"])
+        .success()
+        .stdout(eq("Removed 2 keys\n"));
+    kvs(&["rm", "key", "--prefix", "user:"]).code(1);
+    kvs(&["clear"]).code(1);
+    kvs(&["clear", "--yes"])
+        .success()
+        .stdout(eq("Removed 1 keys\n"));
+    kvs(&["count"]).stdout(eq("0\n"));
+
+    Ok(())
+}
+
+// `append` should concatenate suffixes to values, creating missing keys, surviving compaction
+// and reopening in each write mode, and be available as `kvs append`.
+#[test]
+fn append() -> Result<()> {
+    for mode in 0..3 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            let mut options = OpenOptions::new();
+            match mode {
+                1 => options.offset_index(true),
+                2 => options.coalesce_window(Duration::from_secs(10)),
+                _ => &mut options,
+            };
+            options
+                .history_retention(100)
+                .max_value_len(8)
+                .open(temp_dir.path())
+        };
+
+        let store = open()?;
+        assert_eq!(store.append("key1".to_owned(), "a".to_owned())?, 1);
+        assert_eq!(store.append("key1".to_owned(), "bc".to_owned())?, 3);
+        store.set("key2".to_owned(), "x".to_owned())?;
+        store.append("key2".to_owned(), "y".to_owned())?;
+        store.set("key2".to_owned(), "z".to_owned())?;
+        store.append("key2".to_owned(), "1".to_owned())?;
+        assert!(matches!(
+            store.append("key2".to_owned(), "2345678".to_owned()),
+            Err(KvStoreError::ValueTooLarge(9, 8))
+        ));
+        store.append("key3".to_owned(), "d".to_owned())?;
+        store.append("key3".to_owned(), "e".to_owned())?;
+        store.rename("key3".to_owned(), "key4".to_owned())?;
+        store.append("key4".to_owned(), "f".to_owned())?;
+        assert_eq!(store.get_versioned("key1")?, Some(("abc".to_owned(), 2)));
+        assert_eq!(
+            store.history("key1")?.pop().and_then(|r| r.value),
+            Some("abc".to_owned())
+        );
+        store.compact()?;
+        store.append("key1".to_owned(), "d".to_owned())?;
+        drop(store);
+
+        let store = open()?;
+        assert_eq!(
+            store.scan("")?,
+            vec![
+                ("key1".to_owned(), "abcd".to_owned()),
+                ("key2".to_owned(), "z1".to_owned()),
+                ("key4".to_owned(), "def".to_owned()),
+            ]
+        );
+        assert_eq!(store.get_versioned("key1")?, Some(("abcd".to_owned(), 3)));
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    kvs(&["append", "key1", "value"]).stdout(eq("5\n"));
+    kvs(&["append", "key1", "1"]).stdout(eq("6\n"));
+    kvs(&["get", "key1"]).stdout(eq("value1\n"));
+    kvs(&["log", "dump"]).stdout(contains("append"));
+
+    Ok(())
+}
+
+// `flush` should log coalesced writes without syncing them, and `sync` should also sync the WAL
+// to disk, both leaving the store open.
+#[test]
+fn flush_sync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .coalesce_window(Duration::from_secs(10))
+        .open(temp_dir.path())?;
+    let synced = store.health().last_sync;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let live_bytes = store.stats().live_bytes;
+    store.flush()?;
+    assert!(store.stats().live_bytes > live_bytes);
+    assert_eq!(store.health().last_sync, synced);
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.sync()?;
+    assert!(store.health().last_sync > synced);
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Compaction should copy at the throttled rate in the background while writes go on, report
+// its progress in the stats, and stop and start again on request.
+#[test]
+fn background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        OpenOptions::new()
+            .compaction_threshold(u64::MAX)
+            .compaction_throttle(256 * 1024)
+            .open(temp_dir.path())
+    };
+    let wait_for = |store: &KvStore, done: &dyn Fn(&StoreStats) -> bool| {
+        for _ in 0..1000 {
+            let stats = store.stats();
+            if done(&stats) {
+                return stats;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("timed out waiting for compaction");
+    };
+
+    let store = open()?;
+    for i in 0..4000 {
+        store.set(format!("key{}", i % 2000), format!("{i:0>100}"))?;
+    }
+    store.start_compaction();
+    let progress = wait_for(&store, &|stats| stats.compaction.is_some())
+        .compaction
+        .unwrap();
+    assert!(progress.copied_bytes < progress.total_bytes);
+    store.set("key0".to_owned(), "during".to_owned())?;
+    assert!(store.stats().compaction.is_some());
+
+    store.stop_compaction()?;
+    store.set_compaction_threshold(0);
+    store.set("key1".to_owned(), "stopped".to_owned())?;
+    let stats = store.stats();
+    assert_eq!(stats.compaction, None);
+    assert!(stats.compaction_stopped);
+    assert_eq!(stats.last_compaction, None);
+    assert!(stats.dead_bytes > 0);
+
+    store.start_compaction();
+    wait_for(&store, &|stats| stats.compaction.is_some());
+    store.set("key2".to_owned(), "during".to_owned())?;
+    let stats = wait_for(&store, &|stats| stats.last_compaction.is_some());
+    assert_eq!(stats.compaction, None);
+    assert!(!stats.compaction_stopped);
+    assert_eq!(stats.keys, 2000);
+    store.compact()?;
+    assert_eq!(store.stats().dead_bytes, 0);
+    drop(store);
+
+    let store = open()?;
+    assert_eq!(store.get("key0")?, Some("during".to_owned()));
+    assert_eq!(store.get("key1")?, Some("stopped".to_owned()));
+    assert_eq!(store.get("key2")?, Some("during".to_owned()));
+    assert_eq!(store.get("key3")?, Some(format!("{:0>100}", 2003)));
+
+    Ok(())
+}
+
+// Compaction policies should choose which of the oldest segments automatic compactions cover,
+// leaving newer segments as they are, while `compact` still covers every segment.
+#[test]
+fn compaction_policy() -> Result<()> {
+    #[derive(Debug, Default)]
+    struct Never(Arc<Mutex<Vec<Vec<SegmentInfo>>>>);
+    impl CompactionPolicy for Never {
+        fn select(&self, segments: &[SegmentInfo], _now: std::time::SystemTime) -> usize {
+            self.0.lock().unwrap().push(segments.to_vec());
+            0
+        }
+    }
+
+    let wait_for_compaction = |store: &KvStore| {
+        for _ in 0..1000 {
+            let stats = store.stats();
+            if stats.last_compaction.is_some() {
+                return stats;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("timed out waiting for compaction");
+    };
+
+    // A policy selecting nothing leaves dead records be
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let calls = Arc::default();
+    let store = OpenOptions::new()
+        .compaction_threshold(0)
+        .segment_size(256)
+        .compaction_policy(Never(Arc::clone(&calls)))
+        .open(temp_dir.path())?;
+    for i in 0..40 {
+        store.set(format!("key{}", i % 4), format!("value{i}"))?;
+    }
+    let stats = store.stats();
+    assert!(stats.segments > 1);
+    assert!(stats.dead_bytes > 0);
+    assert_eq!(stats.last_compaction, None);
+    let calls = calls.lock().unwrap();
+    let last = calls.last().unwrap();
+    assert!(last.len() > 1);
+    assert!(last.windows(2).all(|w| w[0].id < w[1].id));
+    assert!(last.iter().map(|s| s.dead_bytes).sum::<u64>() > 0);
+    assert!(!last.iter().any(|s| s.base));
+    drop(calls);
+    store.compact()?;
+    assert_eq!(store.stats().dead_bytes, 0);
+    drop(store);
+
+    // A time-based policy compacts the segments once old enough, and only those
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::new(
+        UNIX_EPOCH + Duration::from_secs(1_000_000),
+    ));
+    let open = || {
+        OpenOptions::new()
+            .compaction_threshold(0)
+            .segment_size(256)
+            .compaction_policy(TimeBased {
+                max_age: Duration::from_hours(1),
+            })
+            .clock(clock.clone())
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+    for i in 0..40 {
+        store.set(format!("key{}", i % 4), format!("value{i}"))?;
+    }
+    let old = store.stats();
+    assert!(old.segments > 2);
+    assert_eq!(old.last_compaction, None);
+
+    clock.advance(Duration::from_hours(2));
+    for i in 40..80 {
+        store.set(format!("new{}", i % 4), format!("value{i}"))?;
+    }
+    let stats = wait_for_compaction(&store);
+    assert!(stats.segments > 1);
+    assert!(stats.segments < old.segments + 2);
+    assert!(stats.dead_bytes > 0);
+    drop(store);
+
+    let store = open()?;
+    for i in 0..4 {
+        assert_eq!(
+            store.get(format!("key{i}"))?,
+            Some(format!("value{}", 36 + i))
+        );
+        assert_eq!(
+            store.get(format!("new{i}"))?,
+            Some(format!("value{}", 76 + i))
+        );
+    }
+
+    Ok(())
+}
+
+// Compaction should write a hint file along with the base segment, from which opening the store
+// rebuilds its state while keeping the base segment in place, in each read mode; an unusable
+// hint file should only make opening replay the base segment.
+#[test]
+fn hint_files() -> Result<()> {
+    for offset_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            OpenOptions::new()
+                .offset_index(offset_index)
+                .open(temp_dir.path())
+        };
+        let files = |suffix: &str| {
+            let mut files: Vec<_> = std::fs::read_dir(temp_dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .filter(|name| name.ends_with(suffix))
+                .collect();
+            files.sort();
+            files
+        };
+        let check = |store: &KvStore| -> Result<()> {
+            assert_eq!(
+                store.get_versioned("key 1")?,
+                Some(("value 3".to_owned(), 3))
+            );
+            assert_eq!(
+                store.get_versioned("key2")?,
+                Some(("value2-suffix".to_owned(), 2))
+            );
+            assert_eq!(store.get_versioned("key4")?, Some(("moved".to_owned(), 1)));
+            assert_eq!(store.get("key3")?, None);
+            assert_eq!(store.len(), 3);
+            assert_eq!(
+                store.bucket("bucket").get("key".to_owned())?,
+                Some("in bucket".to_owned())
+            );
+            assert!(store.next_id("ids")? > 1);
+            assert_eq!(
+                store.ts_range("series", 0, 10, Aggregation::None)?,
+                vec![
+                    Sample {
+                        timestamp: 1,
+                        value: 1.5
+                    },
+                    Sample {
+                        timestamp: 2,
+                        value: 2.5
+                    },
+                ]
+            );
+            Ok(())
+        };
+
+        let store = open()?;
+        for i in 1..=3 {
+            store.set("key 1".to_owned(), format!("value {i}"))?;
+        }
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.append("key2".to_owned(), "-suffix".to_owned())?;
+        store.set("key3".to_owned(), "moved".to_owned())?;
+        store.rename("key3".to_owned(), "key4".to_owned())?;
+        store
+            .bucket("bucket")
+            .set("key".to_owned(), "in bucket".to_owned())?;
+        store.next_id("ids")?;
+        store.ts_add("series", 1, 1.5)?;
+        store.ts_add("series", 2, 2.5)?;
+        store.compact()?;
+        let sequence = store.sequence();
+        drop(store);
+        let bases = files(".base.log");
+        assert_eq!(bases.len(), 1);
+        assert_eq!(files(".base.hint").len(), 1);
+
+        // The base segment is adopted as is, and superseded by later records
+        let store = open()?;
+        assert_eq!(files(".base.log"), bases);
+        assert_eq!(store.stats().segments, 2);
+        assert_eq!(store.sequence(), sequence);
+        check(&store)?;
+        store.set("key5".to_owned(), "value5".to_owned())?;
+        store.remove("key5".to_owned())?;
+        drop(store);
+
+        let store = open()?;
+        assert_eq!(files(".base.log"), bases);
+        assert!(store.stats().dead_bytes > 0);
+        check(&store)?;
+        drop(store);
+
+        // Without usable hints the base segment is replayed into the new log
+        let hint = temp_dir.path().join(&files(".base.hint")[0]);
+        let mut lines = std::fs::read_to_string(&hint).unwrap();
+        lines.push_str("set extra 0 1\n");
+        std::fs::write(&hint, lines).unwrap();
+        let store = open()?;
+        assert!(files(".base.log").is_empty());
+        assert!(files(".base.hint").is_empty());
+        assert_eq!(store.stats().segments, 1);
+        check(&store)?;
+    }
+
+    Ok(())
+}
+
+// Sealing a segment and taking a snapshot should write a filter file next to the segment, which opening
+// the store adopts along with the segment, rebuilds if unusable and removes along with it.
+#[test]
+fn segment_filters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        OpenOptions::new()
+            .offset_index(true)
+            .segment_size(256)
+            .compaction_threshold(u64::MAX)
+            .open(temp_dir.path())
+    };
+    let files = |suffix: &str| {
+        let mut files: Vec<_> = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(suffix))
+            .collect();
+        files.sort();
+        files
+    };
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..40 {
+            assert_eq!(store.get(format!("key{i}"))?, Some(format!("value{i}")));
+        }
+        assert_eq!(store.get("missing".to_owned())?, None);
+        Ok(())
+    };
+
+    let store = open()?;
+    for i in 0..40 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    check(&store)?;
+    let sealed = files(".bloom");
+    assert!(!sealed.is_empty());
+    assert!(sealed
+        .iter()
+        .all(|name| files(".log").contains(&name.replace(".bloom", ".log"))));
+
+    store.snapshot()?;
+    drop(store);
+    assert_eq!(files(".base.bloom").len(), 1);
+    assert_eq!(files(".bloom"), files(".base.bloom"));
+
+    let store = open()?;
+    check(&store)?;
+    drop(store);
+
+    // An unusable filter is rebuilt from the hints of its base segment
+    let bloom = temp_dir.path().join(&files(".base.bloom")[0]);
+    std::fs::write(&bloom, "kvs-bloom 1 7 00000000 0000000000000008\n12345678").unwrap();
+    let store = open()?;
+    check(&store)?;
+    assert_ne!(
+        std::fs::read(&bloom).unwrap(),
+        b"kvs-bloom 1 7 00000000 0000000000000008\n12345678"
+    );
+
+    Ok(())
+}
+
+// Opening a store should report the progress of loading its log files to the progress callback,
+// every 10 000 records and once per file, ending with every file loaded.
+#[test]
+fn open_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let reports = Arc::new(Mutex::new(Vec::<OpenProgress>::new()));
+    let open = || {
+        let reports = Arc::clone(&reports);
+        OpenOptions::new()
+            .segment_size(64 * 1024)
+            .compaction_threshold(u64::MAX)
+            .on_progress(move |progress| reports.lock().unwrap().push(*progress))
+            .open(temp_dir.path())
+    };
+
+    let store = open()?;
+    for i in 0..25_000 {
+        store.set(format!("key{i}"), "value".to_owned())?;
+    }
+    drop(store);
+    assert!(reports.lock().unwrap().is_empty());
+
+    let store = open()?;
+    let reports = reports.lock().unwrap();
+    let last = reports.last().unwrap();
+    assert!(last.segments_total > 2);
+    assert_eq!(last.segments_done, last.segments_total);
+    assert_eq!(last.bytes_done, last.bytes_total);
+    assert_eq!(last.records, 25_000);
+    assert_eq!(last.eta, Some(Duration::ZERO));
+    assert_eq!(
+        reports
+            .iter()
+            .filter(|p| p.records.is_multiple_of(10_000))
+            .count(),
+        2
+    );
+    assert_eq!(reports.len(), last.segments_total + 2);
+    assert!(reports
+        .windows(2)
+        .all(|w| w[0].records <= w[1].records && w[0].bytes_done <= w[1].bytes_done));
+    assert_eq!(store.len(), 25_000);
+
+    Ok(())
+}
+
+// Stats should report the approximate memory taken by the store, and writes adding keys or
+// values should be rejected once it exceeds the memory budget, while removals and replay
+// on open are still accepted.
+#[test]
+fn memory_budget() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().memory_bytes, 0);
+    for i in 0..100 {
+        store.set(format!("key{i}"), "v".repeat(100))?;
+    }
+    let used = store.stats().memory_bytes;
+    assert!(used > 100 * 100);
+    store.remove("key0".to_owned())?;
+    assert!(store.stats().memory_bytes < used);
+    drop(store);
+
+    let budget = used / 2;
+    let store = OpenOptions::new()
+        .max_memory(budget)
+        .open(temp_dir.path())?;
+    assert_eq!(store.len(), 99);
+    let over = store.stats().memory_bytes;
+    assert!(matches!(
+        store.set("key".to_owned(), "value".to_owned()),
+        Err(KvStoreError::OutOfMemoryBudget(used, max)) if used == over && max == budget
+    ));
+    assert!(matches!(
+        store.append("key1".to_owned(), "suffix".to_owned()),
+        Err(KvStoreError::OutOfMemoryBudget(..))
+    ));
+    assert!(matches!(
+        store
+            .bucket("users")
+            .set("key".to_owned(), "value".to_owned()),
+        Err(KvStoreError::OutOfMemoryBudget(..))
+    ));
+    assert_eq!(
+        store
+            .set("key".to_owned(), "value".to_owned())
+            .unwrap_err()
+            .kind(),
+        ErrorKind::Unavailable
+    );
+    assert_eq!(store.get("key".to_owned())?, None);
+
+    for i in 1..60 {
+        store.remove(format!("key{i}"))?;
+    }
+    assert!(store.stats().memory_bytes <= budget);
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// In cache mode, writes should evict the least recently or least frequently used keys beyond
+// the key count or byte budget, logging their removal so that they stay evicted on reopen.
+#[test]
+fn eviction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |policy, max_keys, max_bytes| {
+        OpenOptions::new()
+            .eviction(EvictionConfig {
+                policy,
+                max_keys,
+                max_bytes,
+            })
+            .open(temp_dir.path())
+    };
+
+    let store = open(EvictionPolicy::Lru, Some(3), None)?;
+    for key in ["a", "b", "c"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    assert_eq!(store.get("a")?, Some("value".to_owned()));
+    store.set("d".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("b")?, None);
+    assert_eq!(store.len(), 3);
+    store.append("e".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("c")?, None);
+    assert_eq!(store.stats().evictions, 2);
+    drop(store);
+
+    let store = open(EvictionPolicy::Lfu, Some(3), None)?;
+    assert_eq!(store.scan("")?.len(), 3);
+    assert_eq!(store.get("b")?, None);
+    for _ in 0..3 {
+        store.get("a")?;
+        store.get("e")?;
+    }
+    store.set("f".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("d")?, None);
+    store.set("g".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("f")?, None);
+    assert!(store.contains_key("a") && store.contains_key("e"));
+    store
+        .bucket("users")
+        .set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.len(), 3);
+    drop(store);
+
+    let store = open(EvictionPolicy::Lru, None, Some(30))?;
+    store.set("h".to_owned(), "v".repeat(20))?;
+    assert_eq!(store.scan("")?.len(), 2);
+    assert!(store.contains_key("g"));
+    assert_eq!(store.get("h")?, Some("v".repeat(20)));
+    assert_eq!(store.bucket("users").get("key")?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// A key failing to be evicted, e.g. as its record is damaged, should stay tracked and be evicted
+// once it can be.
+#[test]
+fn eviction_failure() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let wal_path = temp_dir.path().join("wa.log");
+    let store = OpenOptions::new()
+        .offset_index(true)
+        .checksum_records(true)
+        .eviction(EvictionConfig {
+            policy: EvictionPolicy::Lru,
+            max_keys: Some(2),
+            max_bytes: None,
+        })
+        .open(temp_dir.path())?;
+    store.set("a".to_owned(), "value".to_owned())?;
+    store.set("b".to_owned(), "value".to_owned())?;
+
+    let log = std::fs::read(&wal_path).unwrap();
+    let at = log.windows(8).position(|w| w == b" a value").unwrap() + 3;
+    let mut damaged = log.clone();
+    damaged[at] = b'x';
+    std::fs::write(&wal_path, &damaged).unwrap();
+    store.set("c".to_owned(), "value".to_owned())?;
+    assert!(store.contains_key("a"));
+    assert_eq!(store.stats().evictions, 0);
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&wal_path)
+        .and_then(|mut file| std::io::Write::write_all(&mut file, &log))
+        .unwrap();
+    store.set("d".to_owned(), "value".to_owned())?;
+    store.set("e".to_owned(), "value".to_owned())?;
+    assert_eq!(store.len(), 2);
+    assert!(!store.contains_key("a"));
+    assert_eq!(store.stats().evictions, 3);
+
+    Ok(())
+}
+
+// A radix tree index should hold keys sharing long prefixes in less memory than a hashed one,
+// through writes, removals, renames, buckets, compaction and replay.
+#[test]
+fn key_index() -> Result<()> {
+    let mut memory = Vec::new();
+    for key_index in [KeyIndex::Hashed, KeyIndex::Radix] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            OpenOptions::new()
+                .offset_index(true)
+                .key_index(key_index)
+                .open(temp_dir.path())
+        };
+        let store = open()?;
+        for i in 0..1000 {
+            let key = format!("tenant/acme-corporation/users/{i:08}/profile");
+            store.set(key, format!("value{i}"))?;
+        }
+        store.remove("tenant/acme-corporation/users/00000000/profile".to_owned())?;
+        store.remove_prefix("tenant/acme-corporation/users/000009")?;
+        store.rename(
+            "tenant/acme-corporation/users/00000001/profile".to_owned(),
+            "tenant/acme-corporation/users/00000001/settings".to_owned(),
+        )?;
+        store
+            .bucket("tenant/acme-corporation")
+            .set("users/00000001".to_owned(), "bucketed".to_owned())?;
+        store.compact()?;
+        drop(store);
+
+        let store = open()?;
+        assert_eq!(store.len(), 899);
+        assert_eq!(
+            store.get("tenant/acme-corporation/users/00000001/settings")?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(
+            store.get("tenant/acme-corporation/users/00000001/profile")?,
+            None
+        );
+        assert_eq!(
+            store
+                .bucket("tenant/acme-corporation")
+                .get("users/00000001")?,
+            Some("bucketed".to_owned())
+        );
+        let scanned = store.scan("tenant/acme-corporation/users/0000001")?;
+        assert_eq!(scanned.len(), 10);
+        assert_eq!(
+            scanned[0],
+            (
+                "tenant/acme-corporation/users/00000010/profile".to_owned(),
+                "value10".to_owned()
+            )
+        );
+        memory.push(store.stats().memory_bytes);
+    }
+    assert!(memory[1] < memory[0]);
+
+    Ok(())
+}
+
+// Secondary indexes should map a field of JSON values to the keys holding each value of it
+// through writes, removals, renames and appends, in each read mode, be rebuilt on reopen, and
+// be managed and queried by `kvs index` and `kvs find`.
+#[test]
+fn secondary_index() -> Result<()> {
+    for offset_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            OpenOptions::new()
+                .offset_index(offset_index)
+                .open(temp_dir.path())
+        };
+        let store = open()?;
+        store.set(
+            "user:1".to_owned(),
+            r#"{"email":"a@x.com","age":30}"#.to_owned(),
+        )?;
+        store.set(
+            "user:2".to_owned(),
+            r#"{"email":"b@x.com","age":30}"#.to_owned(),
+        )?;
+        store.set("user:3".to_owned(), "not json".to_owned())?;
+        store.create_index("email", "$.email")?;
+        store.create_index("age", "/age")?;
+        assert!(matches!(
+            store.create_index("bad", "email"),
+            Err(KvStoreError::InvalidIndex(_))
+        ));
+        assert!(matches!(
+            store.query_index("missing", "x"),
+            Err(KvStoreError::IndexNotFound(_))
+        ));
+        assert_eq!(store.query_index("email", "a@x.com")?, vec!["user:1"]);
+        assert_eq!(store.query_index("age", "30")?, vec!["user:1", "user:2"]);
+
+        store.set(
+            "user:1".to_owned(),
+            r#"{"email":"c@x.com","age":31}"#.to_owned(),
+        )?;
+        store.set("user:3".to_owned(), r#"{"email":"a@x.com"}"#.to_owned())?;
+        store.rename("user:2".to_owned(), "user:4".to_owned())?;
+        store.set("user:5".to_owned(), r#"{"email":"d@x"#.to_owned())?;
+        store.append("user:5".to_owned(), r#".com"}"#.to_owned())?;
+        store.set("admin:1".to_owned(), r#"{"email":"e@x.com"}"#.to_owned())?;
+        store.remove_prefix("admin:")?;
+        assert_eq!(store.query_index("email", "a@x.com")?, vec!["user:3"]);
+        assert_eq!(store.query_index("email", "b@x.com")?, vec!["user:4"]);
+        assert_eq!(store.query_index("email", "d@x.com")?, vec!["user:5"]);
+        assert!(store.query_index("email", "e@x.com")?.is_empty());
+        assert_eq!(store.query_index("age", "30")?, vec!["user:4"]);
+        store.remove("user:4".to_owned())?;
+        assert!(store.query_index("age", "30")?.is_empty());
+        store.drop_index("age")?;
+        drop(store);
+
+        let store = open()?;
+        assert_eq!(
+            store.indexes(),
+            vec![("email".to_owned(), "$.email".to_owned())]
+        );
+        assert_eq!(store.query_index("email", "c@x.com")?, vec!["user:1"]);
+        assert_eq!(store.query_index("email", "d@x.com")?, vec!["user:5"]);
+        drop(store);
+
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["index", "create", "name", "$.name"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["set", "user:6", r#"{"name":"bob","email":"a@x.com"}"#])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["find", "--index", "email", "--eq", "a@x.com"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("user:3\nuser:6").trim());
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["index", "list"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("email $.email\nname $.name").trim());
+    }
+
+    Ok(())
+}
+
+// Path operations should read and write a field of a JSON value, creating missing objects and
+// appending to arrays, log the modified value as a single record, and reject paths, values and
+// documents they cannot apply to, through both the API and `kvs get/set --path`.
+#[test]
+fn json_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(
+        "user:1".to_owned(),
+        r#"{"name":"ada","tags":["a"]}"#.to_owned(),
+    )?;
+    assert_eq!(
+        store.get_path("user:1", "$.name")?,
+        Some(r#""ada""#.to_owned())
+    );
+    assert_eq!(
+        store.get_path("user:1", "/tags/0")?,
+        Some(r#""a""#.to_owned())
+    );
+    assert_eq!(store.get_path("user:1", "$.age")?, None);
+    assert_eq!(store.get_path("user:2", "$.name")?, None);
+
+    store.set_path("user:1".to_owned(), "$.address.city", r#""London""#)?;
+    store.set_path("user:1".to_owned(), "/tags/-", r#""b""#)?;
+    store.set_path("user:1".to_owned(), "$.name", "null")?;
+    store.set_path("user:2".to_owned(), "$.age", "36")?;
+    assert_eq!(
+        store.get_path("user:1", "$")?,
+        Some(r#"{"address":{"city":"London"},"name":null,"tags":["a","b"]}"#.to_owned())
+    );
+    assert_eq!(store.get("user:2")?, Some(r#"{"age":36}"#.to_owned()));
+    assert_eq!(store.get_versioned("user:1")?.map(|(_, v)| v), Some(4));
+
+    assert!(matches!(
+        store.set_path("user:1".to_owned(), "name", "1"),
+        Err(KvStoreError::InvalidPath(_))
+    ));
+    assert!(matches!(
+        store.set_path("user:1".to_owned(), "$.name", "ada"),
+        Err(KvStoreError::InvalidJson(_))
+    ));
+    assert!(matches!(
+        store.set_path("user:1".to_owned(), "$.tags.5", "1"),
+        Err(KvStoreError::InvalidJson(_))
+    ));
+    assert!(matches!(
+        store.set_path("user:1".to_owned(), "$.address.city.zip", "1"),
+        Err(KvStoreError::InvalidJson(_))
+    ));
+    store.set("plain".to_owned(), "text".to_owned())?;
+    assert!(matches!(
+        store.get_path("plain", "$.a"),
+        Err(KvStoreError::InvalidJson(_))
+    ));
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "--path", "$.address.zip", "user:1", r#""N1""#])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "user:1", "--path", "$.address"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(r#"{"city":"London","zip":"N1"}"#).trim());
+
+    Ok(())
+}
+
+// Lists should pop values in the order pushed and return index ranges, and sets hold each
+// member once, through compaction, reopening from hints or replay, and replication replacing
+// stale lists and sets of a replica, as well as through `kvs lpush/rpop/lrange/sadd/srem/
+// smembers`.
+#[test]
+#[allow(clippy::too_many_lines)] // Goes through the store, the CLI and a replica
+fn lists_and_sets() -> Result<()> {
+    use kvs::server::Shutdown;
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use std::net::TcpListener;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for value in ["a", "b", "c", "d"] {
+        store.lpush("queue", value)?;
+    }
+    assert_eq!(store.lpush("queue", "e")?, 5);
+    assert_eq!(store.lrange("queue", 0, -1), ["e", "d", "c", "b", "a"]);
+    assert_eq!(store.lrange("queue", -2, 10), ["b", "a"]);
+    assert_eq!(store.lrange("queue", 1, 2), ["d", "c"]);
+    assert!(store.lrange("queue", 3, 1).is_empty());
+    assert!(store.lrange("missing", 0, -1).is_empty());
+    assert_eq!(store.rpop("queue")?, Some("a".to_owned()));
+    assert_eq!(store.rpop("queue")?, Some("b".to_owned()));
+    assert_eq!(store.rpop("missing")?, None);
+
+    assert!(store.sadd("tags", "red")?);
+    assert!(store.sadd("tags", "blue")?);
+    assert!(!store.sadd("tags", "red")?);
+    assert!(store.sadd("tags", "green")?);
+    assert!(store.srem("tags", "blue")?);
+    assert!(!store.srem("tags", "blue")?);
+    assert_eq!(store.smembers("tags"), ["green", "red"]);
+    store.sadd("gone", "x")?;
+    store.srem("gone", "x")?;
+    // Separate key spaces
+    store.set("tags".to_owned(), "value".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.stats().dead_bytes, 0);
+    drop(store);
+
+    for history in [None, Some(u64::MAX)] {
+        // Retaining history replays the base segment instead of adopting it with its hints
+        let mut options = OpenOptions::new();
+        if let Some(retention) = history {
+            options.history_retention(retention);
+        }
+        let store = options.open(temp_dir.path())?;
+        assert_eq!(store.lrange("queue", 0, -1), ["e", "d", "c"]);
+        assert_eq!(store.smembers("tags"), ["green", "red"]);
+        assert!(store.smembers("gone").is_empty());
+        assert_eq!(store.get("tags")?, Some("value".to_owned()));
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rpop", "queue"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("c").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["lrange", "queue", "0", "-1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("e\nd").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["sadd", "tags", "blue"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("true").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["smembers", "tags"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("blue\ngreen\nred").trim());
+
+    // A replica replaces its own lists and sets by those of its primary
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let stale = KvStore::open(replica_dir.path())?;
+    stale.lpush("queue", "stale")?;
+    stale.lpush("other", "stale")?;
+    stale.sadd("tags", "stale")?;
+    drop(stale);
+    let primary = Arc::new(KvStore::open(temp_dir.path())?);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let replica = Arc::new(
+        OpenOptions::new()
+            .replica_of("127.0.0.1:4000".parse().unwrap())
+            .open(replica_dir.path())?,
+    );
+    let shutdown = Shutdown::new();
+    let server = {
+        let replica = Arc::clone(&replica);
+        let pool = SharedQueueThreadPool::new(2)?;
+        let shutdown = shutdown.clone();
+        thread::spawn(move || kvs::server::run(&replica, &listener, &pool, &shutdown))
+    };
+    let replicator = kvs::replication::replicate_to(&primary, addr, &shutdown)?;
+    for _ in 0..100 {
+        if replica.lrange("other", 0, -1).is_empty() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(replica.lrange("queue", 0, -1), ["e", "d"]);
+    assert!(replica.lrange("other", 0, -1).is_empty());
+    assert_eq!(replica.smembers("tags"), ["blue", "green", "red"]);
+    shutdown.trigger();
+    replicator.join().unwrap();
+    server.join().unwrap()?;
+
+    Ok(())
+}
+
+// Locks should be taken by one lease at a time until it expires or is released with its token,
+// with tokens increasing through reopening from hints or replay, as well as through `kvs
+// lock/unlock` and a server.
+#[test]
+#[allow(clippy::too_many_lines)] // Goes through the store, the CLI and a server
+fn locks() -> Result<()> {
+    use kvs::client::KvsClient;
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use std::net::TcpListener;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1)));
+    let store = OpenOptions::new()
+        .clock(clock.clone())
+        .open(temp_dir.path())?;
+    let ttl = Duration::from_secs(10);
+    let first = store.lock("job", ttl)?;
+    assert!(matches!(
+        store.lock("job", ttl),
+        Err(KvStoreError::LockHeld(key)) if key == "job"
+    ));
+    assert!(matches!(
+        store.unlock("job", first + 1),
+        Err(KvStoreError::LockNotHeld(..))
+    ));
+    store.unlock("job", first)?;
+    assert!(matches!(
+        store.unlock("job", first),
+        Err(KvStoreError::LockNotHeld(..))
+    ));
+    let second = store.lock("job", ttl)?;
+    assert!(second > first);
+    // An expired lease is taken over, and can no longer be released
+    clock.advance(ttl);
+    let third = store.lock("job", ttl)?;
+    assert!(third > second);
+    assert!(matches!(
+        store.unlock("job", second),
+        Err(KvStoreError::LockNotHeld(..))
+    ));
+    assert!(store.lock("job", Duration::ZERO).is_err());
+    // Separate key spaces
+    store.set("job".to_owned(), "value".to_owned())?;
+    store.lock("released", ttl)?;
+    let released = store.lock("other", ttl)?;
+    store.unlock("other", released)?;
+    store.compact()?;
+    assert_eq!(store.stats().dead_bytes, 0);
+    drop(store);
+
+    for history in [None, Some(u64::MAX)] {
+        // Retaining history replays the base segment instead of adopting it with its hints
+        let mut options = OpenOptions::new();
+        options.clock(clock.clone());
+        if let Some(retention) = history {
+            options.history_retention(retention);
+        }
+        let store = options.open(temp_dir.path())?;
+        assert!(matches!(
+            store.lock("job", ttl),
+            Err(KvStoreError::LockHeld(_))
+        ));
+        assert_eq!(store.get("job".to_owned())?, Some("value".to_owned()));
+        let other = store.lock("other", ttl)?;
+        assert!(other > released);
+        store.unlock("other", other)?;
+    }
+
+    // The CLI reads the system clock, long after the leases expired
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["lock", "job", "--ttl", "60000"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let token = String::from_utf8(output.stdout).unwrap();
+    let token = token.trim();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["lock", "job", "--ttl", "60000"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .code(5)
+        .stdout(contains("Lock is held: job"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["unlock", "job", token])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let store = Arc::new(KvStore::open(temp_dir.path())?);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let pool = SharedQueueThreadPool::new(4)?;
+    let shutdown = kvs::server::Shutdown::new();
+    thread::spawn(move || kvs::server::run(&store, &listener, &pool, &shutdown));
+    let client = KvsClient::connect(addr)?;
+    let token = client.lock("job", ttl)?;
+    assert!(client.lock("job", ttl).is_err());
+    assert!(client.unlock("job", token + 1).is_err());
+    client.unlock("job", token)?;
+    client.lock("job", ttl)?;
+
+    Ok(())
+}
+
+// A checkpoint should replace the segments of the log by a base segment covering the records up
+// to the sequence number it returns, also through `kvs checkpoint`.
+#[test]
+fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new().segment_size(256).open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    store.remove("key0".to_owned())?;
+    assert!(store.stats().segments > 2);
+    assert_eq!(store.checkpoint()?, store.sequence());
+    assert_eq!(store.stats().segments, 2);
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key50".to_owned())?, Some("value50".to_owned()));
+    assert_eq!(store.get("key100".to_owned())?, Some("value100".to_owned()));
+    let sequence = store.sequence();
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["checkpoint"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(sequence.to_string()).trim());
+
+    // Coalesced writes are logged by the checkpoint and covered by the sequence it returns
+    let store = OpenOptions::new()
+        .coalesce_window(Duration::from_mins(1))
+        .open(temp_dir.path())?;
+    store.set("key101".to_owned(), "value101".to_owned())?;
+    assert_eq!(store.checkpoint()?, sequence + 1);
+    assert_eq!(store.sequence(), sequence + 1);
+
+    Ok(())
+}
+
+// A store should keep its WAL segments and base segments in the directories set on open,
+// recorded in the manifest so that opening it with other directories fails.
+#[test]
+fn wal_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+    let wal_dir = temp_dir.path().join("wal");
+    let data_dir = temp_dir.path().join("data");
+    std::fs::create_dir(&store_dir).unwrap();
+    let open = || {
+        OpenOptions::new()
+            .segment_size(256)
+            .wal_dir(&wal_dir)
+            .data_dir(&data_dir)
+            .open(&store_dir)
+    };
+    let store = open()?;
+    for i in 0..100 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    store.checkpoint()?;
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    drop(store);
+
+    let names = |dir: &std::path::Path| -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    };
+    assert!(names(&wal_dir).contains(&"wa.log".to_owned()));
+    assert!(names(&data_dir).iter().any(|n| n.ends_with(".base.log")));
+    assert!(names(&data_dir).iter().any(|n| n.ends_with(".base.hint")));
+    assert!(!names(&store_dir).iter().any(|n| n.starts_with("wa.")));
+
+    let store = open()?;
+    assert_eq!(store.get("key50".to_owned())?, Some("value50".to_owned()));
+    assert_eq!(store.get("key100".to_owned())?, Some("value100".to_owned()));
+    drop(store);
+    assert_eq!(doctor::check(&store_dir, false)?.issues, vec![]);
+
+    assert!(matches!(
+        KvStore::open(&store_dir),
+        Err(KvStoreError::WrongLayout(_))
+    ));
+    assert!(matches!(
+        OpenOptions::new().wal_dir(&wal_dir).open(&store_dir),
+        Err(KvStoreError::WrongLayout(_))
+    ));
+
+    Ok(())
+}
+
+// Writes through a WAL opened for write-through should count as synced without explicit syncs,
+// including in segments started after sealing, and survive a reopen.
+#[test]
+fn write_through() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1)));
+    let open = || {
+        OpenOptions::new()
+            .write_through(true)
+            .segment_size(256)
+            .clock(clock.clone())
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+    for i in 0..50 {
+        clock.advance(Duration::from_secs(1));
+        store.set(format!("key{i}"), format!("value{i}"))?;
+        assert_eq!(
+            store.health().last_sync,
+            Some(UNIX_EPOCH + Duration::from_secs(i + 2))
+        );
+    }
+    assert!(store.stats().segments > 1);
+    drop(store);
+
+    let store = open()?;
+    assert_eq!(store.scan("key")?.len(), 50);
+    assert_eq!(store.get("key49")?, Some("value49".to_owned()));
+
+    Ok(())
+}
+
+// Preallocated active segments should take the segment size up front and be truncated to their
+// records once sealed, segment files compacted away should be zeroed and recycled, and the
+// zeros left past the records of the active segment by a crash should be skipped.
+#[test]
+fn preallocate_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = |name: &str| temp_dir.path().join(name);
+    let free = || {
+        std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.to_string_lossy().contains("wa.free."))
+            .collect::<Vec<_>>()
+    };
+    let open = || {
+        OpenOptions::new()
+            .segment_size(1024)
+            .preallocate_segments(true)
+            .open(temp_dir.path())
+    };
+
+    let store = open()?;
+    for i in 0..100 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    assert!(store.stats().segments > 2);
+    assert_eq!(std::fs::metadata(path("wa.log")).unwrap().len(), 1024);
+    let sealed = std::fs::read(path("wa.1.log")).unwrap();
+    assert!(sealed.len() >= 1024 && sealed.ends_with(b"\n"));
+
+    store.checkpoint()?;
+    // Compacted segment files are zeroed in the background, until the store is dropped
+    drop(store);
+    let store = open()?;
+    let recycled = free();
+    assert!(!recycled.is_empty() && recycled.len() <= 4);
+    for file in &recycled {
+        assert!(std::fs::read(file).unwrap().iter().all(|&b| b == 0));
+    }
+    for i in 100..130 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    assert!(free().len() < recycled.len());
+
+    // As left by a crash, with the rest of the active segment unwritten
+    let active = std::fs::read(path("wa.log")).unwrap();
+    assert_eq!(active.len(), 1024);
+    drop(store);
+    std::fs::write(path("wa.log"), &active).unwrap();
+    assert_eq!(doctor::check(temp_dir.path(), false)?.issues, vec![]);
+    assert!(dump::dump(temp_dir.path(), None, None)?.len() >= 30);
+
+    let store = open()?;
+    assert_eq!(store.scan("key")?.len(), 130);
+    assert_eq!(store.get("key129")?, Some("value129".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan("key")?.len(), 130);
+    assert!(free().is_empty());
+
+    Ok(())
+}
+
+// Zeroing compacted segment files for recycling should not hold up writes.
+#[test]
+fn recycle_segments_in_background() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let free = || {
+        std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.to_string_lossy().contains("wa.free."))
+            .collect::<Vec<_>>()
+    };
+    let store = OpenOptions::new()
+        .segment_size(256 * 1024 * 1024)
+        .preallocate_segments(true)
+        .open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.checkpoint()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(free().is_empty());
+
+    // Dropping the store waits for zeroing to finish
+    drop(store);
+    let recycled = free();
+    assert_eq!(recycled.len(), 1);
+    assert!(std::fs::read(&recycled[0]).unwrap().iter().all(|&b| b == 0));
+    let store = OpenOptions::new()
+        .segment_size(256 * 1024 * 1024)
+        .preallocate_segments(true)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Checked records should be replayed up to a torn record left by a crash, and not past the end
+// of the active segment recorded on close, while stores stay readable with and without them.
+#[test]
+fn checksum_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let wal_path = temp_dir.path().join("wa.log");
+    let manifest_path = temp_dir.path().join("MANIFEST");
+    let open = || {
+        OpenOptions::new()
+            .checksum_records(true)
+            .open(temp_dir.path())
+    };
+
+    let store = open()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let wal = std::fs::read(&wal_path).unwrap();
+    assert!(wal.starts_with(&[0xdb, 0xdf]));
+    drop(store);
+    let manifest = std::fs::read_to_string(&manifest_path).unwrap();
+    assert!(manifest.contains(&format!("end {}", wal.len())));
+
+    // As left by a crash in the middle of writing the second record
+    let torn = wal.len() - 5;
+    std::fs::write(&wal_path, &wal[..torn]).unwrap();
+    let manifest: Vec<_> = manifest
+        .lines()
+        .filter(|line| !line.starts_with("end "))
+        .collect();
+    std::fs::write(&manifest_path, manifest.join("\n") + "\n").unwrap();
+    let store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    // A valid record numbered after the others, past the end recorded on close
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other = OpenOptions::new()
+        .checksum_records(true)
+        .open(other_dir.path())?;
+    for i in 0..10 {
+        other.set("stale".to_owned(), i.to_string())?;
+    }
+    drop(other);
+    let stale = std::fs::read(other_dir.path().join("wa.log")).unwrap();
+    let last = stale[..stale.len() - 1]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1);
+    let mut wal = std::fs::read(&wal_path).unwrap();
+    wal.extend_from_slice(&stale[last..]);
+    std::fs::write(&wal_path, wal).unwrap();
+    assert_eq!(doctor::check(temp_dir.path(), false)?.issues, vec![]);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("stale".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Errors should split into those caused by the user and those of storage, converting back into
+// the error they were split from, with the I/O error behind a storage error as their source,
+// left out of their message but reported after it.
+#[test]
+fn error_split() -> Result<()> {
+    use std::error::Error;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let e = store.remove("key1".to_owned()).unwrap_err();
+    let message = e.to_string();
+    let e = e.split().expect("removing a missing key is a user error");
+    assert_eq!(e.kind(), ErrorKind::KeyNotFound);
+    assert!(e.kind().is_user());
+    assert_eq!(e.to_string(), message);
+    assert!(e.source().is_none());
+    let e = KvStoreError::from(e);
+    assert!(matches!(e, KvStoreError::FailedRm(ref key) if key == "key1"));
+
+    let file = temp_dir.path().join("file");
+    std::fs::write(&file, "").unwrap();
+    let Err(e) = KvStore::open(file.join("store")) else {
+        panic!("opened a store under a file");
+    };
+    let message = e.to_string();
+    let e = e
+        .split()
+        .expect_err("opening a store under a file is a storage error");
+    assert_eq!(e.kind(), ErrorKind::Io);
+    assert!(!e.kind().is_user());
+    assert_eq!(e.to_string(), message);
+    let source = e.error().source().expect("an I/O error is the source");
+    assert!(source.is::<std::io::Error>());
+    assert!(!message.contains(&source.to_string()));
+    assert_eq!(e.error().report(), format!("{message}: {source}"));
+    assert_eq!(KvStoreError::from(e).kind(), ErrorKind::Io);
+
+    Ok(())
+}
+
+// Clones of a store should be handles sharing its state across threads, and the store should
+// be closed once its last handle is dropped.
+#[test]
+fn clone_handles() -> Result<()> {
+    fn assert_handle<T: Clone + Send + Sync + 'static>() {}
+    assert_handle::<KvStore>();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || store.set(format!("key{i}"), format!("value{i}")))
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let clone = store.clone();
+    drop(store);
+    for i in 0..4 {
+        assert_eq!(clone.get(format!("key{i}"))?, Some(format!("value{i}")));
+    }
+    assert!(matches!(
+        KvStore::try_open_timeout(temp_dir.path(), Duration::from_millis(50)),
+        Err(KvStoreError::Locked(_))
+    ));
+    drop(clone);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// The in-memory engine should get, set and remove keys like a store, through the engine trait
+// both implement.
+#[test]
+fn mem_engine() -> Result<()> {
+    fn exercise<E: KvsEngine>(engine: &mut E) -> std::result::Result<(), E::Error> {
+        assert_eq!(engine.set("key1".to_owned(), "value1".to_owned())?, None);
+        assert_eq!(
+            engine.set("key1".to_owned(), "value2".to_owned())?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(engine.get("key2".to_owned())?, None);
+        assert_eq!(engine.remove("key1".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(engine.remove("key1".to_owned())?, None);
+        assert_eq!(engine.get("key1".to_owned())?, None);
+        engine.set("key3".to_owned(), "value3".to_owned())?;
+        Ok(())
+    }
+
+    let mut engine = MemEngine::new();
+    let Ok(()) = exercise(&mut engine);
+    assert_eq!(engine.len(), 1);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    exercise(&mut store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// The store for web apps should get, set and remove keys in memory.
+#[cfg(feature = "wasm")]
+#[test]
+fn wasm_store() {
+    use kvs::wasm::WasmStore;
+
+    let mut store = WasmStore::new();
+    assert!(store.is_empty());
+    assert_eq!(store.set("key1".to_owned(), "value1".to_owned()), None);
+    assert_eq!(
+        store.set("key1".to_owned(), "value2".to_owned()),
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key1".to_owned()), Some("value2".to_owned()));
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.remove("key1".to_owned()), Some("value2".to_owned()));
+    assert_eq!(store.remove("key1".to_owned()), None);
+    assert_eq!(store.get("key1".to_owned()), None);
+}
+
+// The C bindings should open, write, read and close a store, returning the exit codes of the
+// kinds of errors.
+#[cfg(feature = "ffi")]
+#[test]
+fn ffi() {
+    use kvs::ffi::{
+        kvs_close, kvs_free_string, kvs_get, kvs_open, kvs_remove, kvs_set, KVS_KEY_NOT_FOUND,
+        KVS_OK, KVS_USAGE,
+    };
+    use std::ffi::{CStr, CString};
+    use std::ptr;
+
+    assert_eq!(KVS_USAGE, i32::from(ErrorKind::Usage.exit_code()));
+    assert_eq!(
+        KVS_KEY_NOT_FOUND,
+        i32::from(ErrorKind::KeyNotFound.exit_code())
+    );
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+    let (key, value) = (c"key1", c"value1");
+    // SAFETY: the handle is used only until closed, and values are freed once
+    unsafe {
+        let mut store = ptr::null_mut();
+        assert_eq!(kvs_open(path.as_ptr(), &raw mut store), KVS_OK);
+        assert_eq!(kvs_set(store, key.as_ptr(), value.as_ptr()), KVS_OK);
+        let mut got = ptr::null_mut();
+        assert_eq!(kvs_get(store, key.as_ptr(), &raw mut got), KVS_OK);
+        assert_eq!(CStr::from_ptr(got), value);
+        kvs_free_string(got);
+        assert_eq!(kvs_remove(store, key.as_ptr()), KVS_OK);
+        assert_eq!(kvs_remove(store, key.as_ptr()), KVS_KEY_NOT_FOUND);
+        assert_eq!(kvs_get(store, key.as_ptr(), &raw mut got), KVS_OK);
+        assert!(got.is_null());
+        assert_eq!(kvs_set(store, ptr::null(), value.as_ptr()), KVS_USAGE);
+        kvs_close(store);
+    }
+}
+
+// A store wrapped in a map facade should get, insert, remove, iterate and extend like a
+// `HashMap`, returning the errors of the store from its `try_` methods, and pairs collected into
+// a facade should land in a default engine, iterated alike.
+#[test]
+fn map_facade() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut map = MapFacade::new(KvStore::open(temp_dir.path())?);
+    assert!(map.is_empty());
+    assert_eq!(map.insert("b".to_owned(), "2".to_owned()), None);
+    assert_eq!(
+        map.insert("b".to_owned(), "3".to_owned()),
+        Some("2".to_owned())
+    );
+    map.extend([
+        ("a".to_owned(), "1".to_owned()),
+        ("c".to_owned(), "4".to_owned()),
+    ]);
+    assert_eq!(map.get("b"), Some("3".to_owned()));
+    assert!(map.contains_key("a"));
+    assert_eq!(map.remove("c"), Some("4".to_owned()));
+    assert_eq!(map.remove("c"), None);
+    assert_eq!(map.len(), 2);
+    assert_eq!(map.keys().collect::<Vec<_>>(), ["a", "b"]);
+    assert_eq!(map.values().collect::<Vec<_>>(), ["1", "3"]);
+    let pairs: HashMap<_, _> = (&map).into_iter().collect();
+    assert_eq!(pairs.len(), 2);
+    assert_eq!(map.try_get("a")?, Some("1".to_owned()));
+    assert!(!map.try_contains_key("c")?);
+    assert_eq!(map.try_remove("a")?, Some("1".to_owned()));
+    assert_eq!(map.try_insert("a".to_owned(), "1".to_owned())?, None);
+    assert_eq!(map.try_iter()?.count(), 2);
+    let store = map.into_inner();
+    assert_eq!(store.get("a")?, Some("1".to_owned()));
+    drop(store);
+
+    let mut map = MapFacade::new(OpenOptions::new().max_key_len(4).open(temp_dir.path())?);
+    let e = map
+        .try_insert("long key".to_owned(), "value".to_owned())
+        .unwrap_err();
+    assert!(matches!(e, KvStoreError::KeyTooLarge(8, 4)));
+    assert!(e.kind().is_user());
+
+    let mut map: MapFacade<MemEngine> = [("k".to_owned(), "v".to_owned())].into_iter().collect();
+    assert_eq!(map.get("k"), Some("v".to_owned()));
+    assert_eq!(map.engine().len(), 1);
+    map.insert("a".to_owned(), "1".to_owned());
+    assert_eq!(map.len(), 2);
+    assert!(!map.is_empty());
+    assert_eq!(map.keys().collect::<Vec<_>>(), ["a", "k"]);
+    assert_eq!(map.values().collect::<Vec<_>>(), ["1", "v"]);
+    assert_eq!((&map).into_iter().count(), 2);
+
+    Ok(())
+}
+
+// `set_opts` should sync the WAL before returning only for writes asking for it, and only set
+// keys meeting its condition, logging only the writes made.
+#[test]
+fn set_opts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1)));
+    let store = OpenOptions::new()
+        .clock(clock.clone())
+        .open(temp_dir.path())?;
+    let synced = store.health().last_sync;
+    clock.advance(Duration::from_secs(1));
+    let bulk = SetOptions::default();
+    assert_eq!(
+        store.set_opts("key1".to_owned(), "value1".to_owned(), bulk)?,
+        (true, None)
+    );
+    assert_eq!(store.health().last_sync, synced);
+    let durable = SetOptions {
+        fsync: true,
+        ..SetOptions::default()
+    };
+    assert_eq!(
+        store.set_opts("key1".to_owned(), "value2".to_owned(), durable)?,
+        (true, Some("value1".to_owned()))
+    );
+    assert_eq!(
+        store.health().last_sync,
+        Some(UNIX_EPOCH + Duration::from_secs(2))
+    );
+
+    let nx = SetOptions {
+        if_not_exists: true,
+        ..SetOptions::default()
+    };
+    let xx = SetOptions {
+        if_exists: true,
+        ..SetOptions::default()
+    };
+    let sequence = store.sequence();
+    assert_eq!(
+        store.set_opts("key1".to_owned(), "value3".to_owned(), nx)?,
+        (false, Some("value2".to_owned()))
+    );
+    assert_eq!(
+        store.set_opts("key2".to_owned(), "value1".to_owned(), xx)?,
+        (false, None)
+    );
+    assert_eq!(store.sequence(), sequence);
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+
+    assert_eq!(
+        store.set_opts("key1".to_owned(), "value3".to_owned(), xx)?,
+        (true, Some("value2".to_owned()))
+    );
+    store.remove("key1".to_owned())?;
+    assert_eq!(
+        store.set_opts("key1".to_owned(), "value4".to_owned(), nx)?,
+        (true, None)
+    );
+    assert_eq!(store.get_versioned("key1")?, Some(("value4".to_owned(), 1)));
+    assert!(matches!(
+        store.set_opts(
+            "key1".to_owned(),
+            "value5".to_owned(),
+            SetOptions {
+                if_not_exists: true,
+                if_exists: true,
+                ..SetOptions::default()
+            }
+        ),
+        Err(KvStoreError::InvalidCommand(_))
+    ));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value4".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+
+    Ok(())
+}
+
+// Keys set with a TTL should read as missing once it runs out, in each read mode, keeping their
+// expiry across reopening and compaction, until purged and dropped by compaction.
+#[test]
+fn set_ttl() -> Result<()> {
+    for offset_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1000)));
+        let open = || {
+            OpenOptions::new()
+                .clock(clock.clone())
+                .offset_index(offset_index)
+                .open(temp_dir.path())
+        };
+        let ttl = |secs| SetOptions {
+            ttl: Some(Duration::from_secs(secs)),
+            ..SetOptions::default()
+        };
+
+        let store = open()?;
+        store.set_opts("short".to_owned(), "value".to_owned(), ttl(10))?;
+        store.set_opts("long".to_owned(), "value".to_owned(), ttl(100))?;
+        store.set("kept".to_owned(), "value".to_owned())?;
+        store.append("long".to_owned(), "-suffix".to_owned())?;
+        assert!(matches!(
+            store.set_opts("key".to_owned(), "value".to_owned(), ttl(0)),
+            Err(KvStoreError::InvalidCommand(_))
+        ));
+        assert_eq!(store.len(), 3);
+        drop(store);
+
+        // Expiry survives reopening, and appending keeps it
+        let store = open()?;
+        clock.advance(Duration::from_secs(50));
+        assert_eq!(store.get("short")?, None);
+        assert_eq!(store.get_versioned("short")?, None);
+        assert!(!store.contains_key("short"));
+        assert_eq!(store.get("long")?, Some("value-suffix".to_owned()));
+        assert_eq!(store.len(), 2);
+        assert_eq!(
+            store.keys("", &ScanOptions::default())?,
+            vec!["kept".to_owned(), "long".to_owned()]
+        );
+        assert_eq!(store.scan("")?.len(), 2);
+        assert_eq!(store.iter()?.count(), 2);
+        assert!(matches!(
+            store.remove("short".to_owned()),
+            Err(KvStoreError::FailedRm(_))
+        ));
+
+        // Conditional sets see expired keys as missing, and writes without a TTL drop it
+        let nx = SetOptions {
+            if_not_exists: true,
+            ..SetOptions::default()
+        };
+        assert_eq!(
+            store.set_opts("short".to_owned(), "again".to_owned(), nx)?,
+            (true, None)
+        );
+        store.snapshot()?;
+        drop(store);
+
+        let store = open()?;
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(store.get("short")?, Some("again".to_owned()));
+        assert_eq!(store.get("long")?, None);
+        assert_eq!(store.purge_expired()?, 1);
+        assert_eq!(store.purge_expired()?, 0);
+        store.snapshot()?;
+        drop(store);
+
+        // Purged keys stay removed even with the clock turned back
+        clock.set(UNIX_EPOCH + Duration::from_secs(1000));
+        let store = open()?;
+        assert_eq!(store.get("long")?, None);
+        assert_eq!(store.len(), 2);
+    }
+
+    Ok(())
+}
+
+// `kvs set --nx` and `kvs set --xx` should print whether they set the key.
+#[test]
+fn cli_set_conditional() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for (args, written) in [
+        (["set", "key1", "value1", "--xx"], "false"),
+        (["set", "key1", "value1", "--nx"], "true"),
+        (["set", "key1", "value2", "--nx"], "false"),
+        (["set", "key1", "value3", "--xx"], "true"),
+    ] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq(written).trim());
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value3").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value4", "--nx", "--xx"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `scan_opts` and `keys` should page through matching keys in either order, in each key index
+// mode, and be available as `kvs keys`.
+#[test]
+fn scan_pages() -> Result<()> {
+    for offset_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = OpenOptions::new()
+            .offset_index(offset_index)
+            .open(temp_dir.path())?;
+        for i in (1..=5).rev() {
+            store.set(format!("key{i}"), format!("value{i}"))?;
+        }
+        store.set("other".to_owned(), "value".to_owned())?;
+
+        let mut options = ScanOptions {
+            limit: Some(2),
+            ..ScanOptions::default()
+        };
+        let mut pages = Vec::new();
+        loop {
+            let page = store.scan_opts("key", &options)?;
+            let Some((last, _)) = page.last() else {
+                break;
+            };
+            options.start_after = Some(last.clone());
+            pages.push(page);
+        }
+        assert_eq!(
+            pages,
+            vec![
+                vec![
+                    ("key1".to_owned(), "value1".to_owned()),
+                    ("key2".to_owned(), "value2".to_owned()),
+                ],
+                vec![
+                    ("key3".to_owned(), "value3".to_owned()),
+                    ("key4".to_owned(), "value4".to_owned()),
+                ],
+                vec![("key5".to_owned(), "value5".to_owned())],
+            ]
+        );
+        assert_eq!(
+            store.scan_opts("", &ScanOptions::default())?,
+            store.scan("")?
+        );
+
+        let options = ScanOptions {
+            limit: Some(2),
+            start_after: Some("key4".to_owned()),
+            reverse: true,
+        };
+        assert_eq!(store.keys("key", &options)?, vec!["key3", "key2"]);
+        let options = ScanOptions {
+            reverse: true,
+            ..ScanOptions::default()
+        };
+        assert_eq!(
+            store.keys("", &options)?,
+            vec!["other", "key5", "key4", "key3", "key2", "key1"]
+        );
+        assert_eq!(
+            store.keys("key", &options)?,
+            vec!["key5", "key4", "key3", "key2", "key1"]
+        );
+        let before_prefix = ScanOptions {
+            start_after: Some("a".to_owned()),
+            ..options.clone()
+        };
+        assert!(store.keys("key", &before_prefix)?.is_empty());
+
+        // Renamed and removed keys move in the order, which survives compaction
+        store.rename("key1".to_owned(), "key6".to_owned())?;
+        store.remove("key3".to_owned())?;
+        store.snapshot()?;
+        drop(store);
+        let store = OpenOptions::new()
+            .offset_index(offset_index)
+            .open(temp_dir.path())?;
+        assert_eq!(
+            store.keys("key", &ScanOptions::default())?,
+            vec!["key2", "key4", "key5", "key6"]
+        );
+        store.rename("key6".to_owned(), "key1".to_owned())?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        drop(store);
+
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["keys", "key", "--limit", "2", "--start-after", "key1"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("key2\nkey3").trim());
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["keys", "--reverse", "--limit", "1"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("other").trim());
+    }
+
+    Ok(())
+}
+
+// A `scan_cursor` scan should return each key matching its pattern once, including every key
+// present for the whole scan while others are written.
+#[test]
+fn tcp_scan_cursor() -> Result<()> {
+    use kvs::client::KvsClient;
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use std::collections::BTreeSet;
+    use std::net::TcpListener;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Arc::new(KvStore::open(temp_dir.path())?);
+    for i in 0..50 {
+        store.set(format!("user:{i:02}"), i.to_string())?;
+    }
+    store.set("user:*".to_owned(), "star".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let pool = SharedQueueThreadPool::new(4)?;
+    let shutdown = kvs::server::Shutdown::new();
+    let server_store = Arc::clone(&store);
+    thread::spawn(move || kvs::server::run(&server_store, &listener, &pool, &shutdown));
+
+    let client = KvsClient::connect(addr)?;
+    let mut cursor = None;
+    let mut keys = Vec::new();
+    let mut pages = 0;
+    loop {
+        let (page, next) = client.scan_cursor(cursor, Some("user:[0-4]?"), Some(7))?;
+        keys.extend(page);
+        pages += 1;
+        // Keys removed and added mid-scan may or may not be returned
+        store.remove(format!("user:{:02}", 49 - pages))?;
+        store.set(format!("user:{pages:02}x"), String::new())?;
+        match next {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    let unique: BTreeSet<_> = keys.iter().cloned().collect();
+    assert_eq!(unique.len(), keys.len());
+    for i in (0..49 - pages).chain([49]) {
+        assert!(unique.contains(&format!("user:{i:02}")));
+    }
+    assert!(keys
+        .iter()
+        .all(|key| key.len() == 7 && key.starts_with("user:")));
+
+    let (keys, cursor) = client.scan_cursor(None, Some(r"user:\*"), None)?;
+    assert_eq!((keys, cursor), (vec!["user:*".to_owned()], None));
+    let (keys, _) = client.scan_cursor(None, None, Some(3))?;
+    assert_eq!(keys, ["other", "user:*", "user:00"]);
+    // A step goes through count keys however few of them match
+    let (keys, cursor) = client.scan_cursor(None, Some("*9"), Some(3))?;
+    assert_eq!((keys, cursor), (vec![], Some("user:00".to_owned())));
+    assert!(matches!(
+        client.scan_cursor(None, None, Some(0)),
+        Err(KvStoreError::Remote(e)) if e.contains("count")
+    ));
+
+    Ok(())
+}